@@ -0,0 +1,145 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Replays a captured SNS event through the offline forwarding stages and
+//! prints the message that would be sent, without any AWS access.
+//!
+//! ```text
+//! cargo run --example replay -- [event.json] [config.json]
+//! ```
+//!
+//! The event defaults to `tests/payload/test_event.json` and the
+//! configuration to `privatemail.toml` in the working directory, if any,
+//! or else [`PrivatEmailConfig::default`]; a configuration file holds the
+//! JSON form of `PrivatEmailConfig`, see `examples/config.json`.
+//!
+//! The Lambda handler creates its SES client itself, so sending is
+//! replaced here by a transport printing the raw message.
+use lambda_runtime::Error;
+use lib::attachments;
+use lib::config::{local, PrivatEmailConfig};
+use lib::forward;
+use lib::html;
+use lib::mime::MimeMessage;
+use lib::received;
+use lib::tracking;
+use serde_json::Value;
+use std::path::Path;
+use std::{env, fs};
+
+/// Where a built forward goes.
+trait Transport {
+    fn send(&self, destination: &str, raw: &[u8]) -> Result<String, Error>;
+}
+
+/// [`Transport`] writing messages to stdout.
+struct PrintTransport;
+
+impl Transport for PrintTransport {
+    fn send(&self, destination: &str, raw: &[u8]) -> Result<String, Error> {
+        println!("--- to {} ({} bytes)", destination, raw.len());
+        println!("{}", String::from_utf8_lossy(raw));
+        Ok("replayed".to_owned())
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let mut args = env::args().skip(1);
+    let event_path = args.next().unwrap_or_else(|| {
+        format!("{}/tests/payload/test_event.json", env!("CARGO_MANIFEST_DIR"))
+    });
+    let email_config: PrivatEmailConfig = match args.next() {
+        Some(path) => serde_json::from_str(&fs::read_to_string(path)?)?,
+        None if Path::new(local::CONFIG_FILE).is_file() => {
+            local::load(Path::new(local::CONFIG_FILE))?
+        }
+        None => PrivatEmailConfig::default(),
+    };
+
+    // The SES notification is a JSON string inside the SNS record
+    let event: Value = serde_json::from_str(&fs::read_to_string(event_path)?)?;
+    let message = event["Records"][0]["Sns"]["Message"]
+        .as_str()
+        .ok_or("Missing SNS message")?;
+    let notification: Value = serde_json::from_str(message)?;
+    let content = notification["content"].as_str().ok_or("Missing content")?;
+    let headers = &notification["mail"]["commonHeaders"];
+    let sender = headers["returnPath"].as_str().unwrap_or_default();
+    let subject = headers["subject"].as_str().unwrap_or_default();
+    let destinations: Vec<String> =
+        serde_json::from_value(notification["mail"]["destination"].clone())?;
+    let senders: Vec<String> = headers["from"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .chain(notification["mail"]["source"].as_str())
+        .map(str::to_owned)
+        .collect();
+    let email_config =
+        email_config.for_tenant(&destinations).for_sender(&senders);
+    let subject = match email_config.subject_prefix_for(&destinations) {
+        Some(prefix) => forward::prefix_subject(prefix, subject),
+        None => subject.to_owned(),
+    };
+
+    let mail = mailparse::parse_mail(content.as_bytes())?;
+    for anomaly in received::analyze(&received::chain(&mail.headers)) {
+        println!("Received chain anomaly: {}", anomaly);
+    }
+    if let Some(blocklist) = &email_config.blocked_attachments {
+        for attachment in attachments::find_blocked(&mail, blocklist) {
+            println!("Blocked attachment: {:?}", attachment);
+        }
+    }
+
+    let html_body = mail
+        .parts()
+        .find(|part| part.ctype.mimetype == "text/html")
+        .map(|part| part.get_body())
+        .transpose()?
+        .unwrap_or_default();
+    let html_body = if email_config.strip_trackers {
+        match &email_config.tracking_redirects {
+            Some(redirects) => tracking::strip(&html_body, redirects),
+            None => tracking::strip(&html_body, tracking::DEFAULT_REDIRECTS),
+        }
+    } else {
+        html_body
+    };
+    let html_body = if email_config.sanitize_html {
+        html::sanitize(&html_body)
+    } else {
+        html_body
+    };
+    let mut forward = MimeMessage::new()
+        .header("From", &email_config.from_email)
+        .header("To", email_config.to_address(&destinations))
+        .header("Reply-To", sender)
+        .header("Subject", &subject)
+        .html(&html_body);
+    if email_config.text_fallback {
+        forward = forward.text(html::to_text(&html_body));
+    }
+    if email_config.inject_headers {
+        let message_id = notification["mail"]["messageId"].as_str();
+        for (name, value) in forward::forwarding_headers(
+            &destinations,
+            sender,
+            message_id.unwrap_or_default(),
+        ) {
+            forward = forward.header(name, value);
+        }
+    }
+    let raw = forward::with_threading(forward, &mail.headers).to_bytes();
+
+    let message_id = PrintTransport.send(&email_config.to_email, &raw)?;
+    println!("--- sent as {}", message_id);
+    Ok(())
+}
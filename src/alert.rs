@@ -0,0 +1,320 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Alerts about mail the forwarder could not deliver.
+//!
+//! With `alert_topic_arn` set, every forward failing permanently publishes
+//! a [`FailureEvent`] as JSON to the SNS topic, whatever the failure
+//! policy. The failure class is also set as the `failure_class` message
+//! attribute, for subscription filter policies.
+//!
+//! With `alert_failure_threshold` set, failed sends in a row are counted
+//! in the store, or in the container without one, and the configured
+//! [`Alerter`]s are triggered once the count reaches the threshold. The
+//! next successful send resolves the alert.
+use crate::aws;
+use crate::config::PrivatEmailConfig;
+use crate::failure::FailureClass;
+use crate::notify::post_json;
+use crate::store::{MemoryRecordStore, RecordStore};
+use async_trait::async_trait;
+use lambda_runtime::Error;
+use rusoto_core::param::{Params, ServiceParams};
+use rusoto_core::signature::SignedRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::sync::OnceLock;
+
+/// Subject of the published alerts, for email subscriptions.
+pub const ALERT_SUBJECT: &str = "privatemail: mail could not be forwarded";
+
+/// Longest error description published, in characters.
+const MAX_ERROR_CHARS: usize = 2_000;
+
+/// Counter of the failed sends in a row.
+pub const STREAK_COUNTER: &str = "failure_streak";
+
+/// Deduplication key of the repeated failures alert, so that a single
+/// incident is open at a time.
+pub const DEDUP_KEY: &str = "privatemail-send-failures";
+
+/// Events API endpoint of PagerDuty.
+pub const PAGERDUTY_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// Alert API endpoint of Opsgenie.
+pub const OPSGENIE_URL: &str = "https://api.opsgenie.com/v2/alerts";
+
+/// Failure to forward a received message.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailureEvent {
+    /// SES message id of the received message
+    pub message_id: String,
+
+    /// Original sender
+    pub sender: String,
+
+    /// Original recipients
+    pub recipients: Vec<String>,
+
+    /// Subject of the message
+    pub subject: String,
+
+    /// Class of the send error
+    pub failure_class: FailureClass,
+
+    /// Description of the send error
+    pub error: String,
+
+    /// Unix time of the failure
+    pub failed_at: i64,
+}
+
+/// Publishes `event` to the SNS topic `topic_arn`.
+pub async fn publish(
+    topic_arn: &str,
+    event: &FailureEvent,
+) -> Result<(), Error> {
+    let mut event = event.clone();
+    if event.error.chars().count() > MAX_ERROR_CHARS {
+        event.error = event.error.chars().take(MAX_ERROR_CHARS).collect();
+    }
+    let mut request =
+        SignedRequest::new("POST", "sns", &aws::arn_region(topic_arn), "/");
+    let mut params = Params::new();
+    params.put("Action", "Publish");
+    params.put("Version", "2010-03-31");
+    params.put("TopicArn", topic_arn);
+    params.put("Subject", ALERT_SUBJECT);
+    params.put("Message", serde_json::to_string(&event)?);
+    params.put("MessageAttributes.entry.1.Name", "failure_class");
+    params.put("MessageAttributes.entry.1.Value.DataType", "String");
+    params.put(
+        "MessageAttributes.entry.1.Value.StringValue",
+        event.failure_class.as_str(),
+    );
+    request.set_params(params);
+    aws::dispatch(request).await?;
+    Ok(())
+}
+
+/// Repeated send failures alerted to the on-call.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Alert {
+    /// Failed sends in a row
+    pub failures: u64,
+
+    /// SES message id of the last message which failed
+    pub message_id: String,
+
+    /// Description of the last send error
+    pub error: String,
+}
+
+impl Alert {
+    /// Returns the one line summary of the alert.
+    pub fn summary(&self) -> String {
+        format!("privatemail: {} forwards failed in a row", self.failures)
+    }
+}
+
+/// Pages the on-call through an incident management service.
+#[async_trait]
+pub trait Alerter: Send + Sync {
+    /// Opens an incident for `alert`.
+    async fn trigger(&self, alert: &Alert) -> Result<(), Error>;
+
+    /// Closes the incident, sends succeeding again.
+    async fn resolve(&self) -> Result<(), Error>;
+}
+
+/// Creates the alerters configured in `config`.
+pub fn from_config(config: &PrivatEmailConfig) -> Vec<Box<dyn Alerter>> {
+    let mut alerters: Vec<Box<dyn Alerter>> = Vec::new();
+    if let Some(key) = &config.pagerduty_routing_key {
+        alerters.push(Box::new(PagerDutyAlerter::new(key)));
+    }
+    if let Some(key) = &config.opsgenie_api_key {
+        alerters.push(Box::new(OpsgenieAlerter::new(key)));
+    }
+    alerters
+}
+
+/// [`Alerter`] sending events to the PagerDuty Events API v2.
+#[derive(Clone, Debug)]
+pub struct PagerDutyAlerter {
+    routing_key: String,
+}
+
+impl PagerDutyAlerter {
+    /// Creates an alerter for the service integration `routing_key`.
+    pub fn new<K: ToString>(routing_key: K) -> Self {
+        PagerDutyAlerter { routing_key: routing_key.to_string() }
+    }
+
+    /// Builds the event of `action`, `trigger` or `resolve`, for `alert`.
+    pub fn payload(&self, action: &str, alert: Option<&Alert>) -> Value {
+        let mut event = json!({
+            "routing_key": self.routing_key,
+            "event_action": action,
+            "dedup_key": DEDUP_KEY,
+        });
+        if let Some(alert) = alert {
+            event["payload"] = json!({
+                "summary": alert.summary(),
+                "source": "privatemail",
+                "severity": "critical",
+                "custom_details": {
+                    "failures": alert.failures,
+                    "messageId": alert.message_id,
+                    "error": alert.error,
+                },
+            });
+        }
+        event
+    }
+}
+
+#[async_trait]
+impl Alerter for PagerDutyAlerter {
+    async fn trigger(&self, alert: &Alert) -> Result<(), Error> {
+        post_json(PAGERDUTY_URL, &[], &self.payload("trigger", Some(alert)))
+            .await
+    }
+
+    async fn resolve(&self) -> Result<(), Error> {
+        post_json(PAGERDUTY_URL, &[], &self.payload("resolve", None)).await
+    }
+}
+
+/// [`Alerter`] creating and closing Opsgenie alerts.
+#[derive(Clone, Debug)]
+pub struct OpsgenieAlerter {
+    api_key: String,
+}
+
+impl OpsgenieAlerter {
+    /// Creates an alerter for the API integration `api_key`.
+    pub fn new<K: ToString>(api_key: K) -> Self {
+        OpsgenieAlerter { api_key: api_key.to_string() }
+    }
+
+    /// Builds the alert creation request of `alert`.
+    pub fn payload(alert: &Alert) -> Value {
+        json!({
+            "message": alert.summary(),
+            "alias": DEDUP_KEY,
+            "description": format!(
+                "Last failed message {}: {}",
+                alert.message_id, alert.error
+            ),
+            "priority": "P1",
+            "source": "privatemail",
+        })
+    }
+
+    fn authorization(&self) -> String {
+        format!("GenieKey {}", self.api_key)
+    }
+}
+
+#[async_trait]
+impl Alerter for OpsgenieAlerter {
+    async fn trigger(&self, alert: &Alert) -> Result<(), Error> {
+        let authorization = self.authorization();
+        let headers = [("Authorization", authorization.as_str())];
+        post_json(OPSGENIE_URL, &headers, &Self::payload(alert)).await
+    }
+
+    async fn resolve(&self) -> Result<(), Error> {
+        let url = format!(
+            "{}/{}/close?identifierType=alias",
+            OPSGENIE_URL, DEDUP_KEY
+        );
+        let authorization = self.authorization();
+        let headers = [("Authorization", authorization.as_str())];
+        post_json(&url, &headers, &json!({})).await
+    }
+}
+
+/// Counter store of the container, used when no store is configured.
+pub fn local_records() -> &'static MemoryRecordStore {
+    static RECORDS: OnceLock<MemoryRecordStore> = OnceLock::new();
+    RECORDS.get_or_init(Default::default)
+}
+
+/// Counts a failed send, returning the failed sends in a row.
+pub async fn record_failure(records: &dyn RecordStore) -> Result<u64, Error> {
+    records.increment(STREAK_COUNTER, 1).await
+}
+
+/// Ends the failure streak after a successful send, returning the failed
+/// sends in a row before it.
+pub async fn record_success(records: &dyn RecordStore) -> Result<u64, Error> {
+    let streak = records.increment(STREAK_COUNTER, 0).await?;
+    if streak > 0 {
+        records.decrement(STREAK_COUNTER, streak).await?;
+    }
+    Ok(streak)
+}
+
+/** Test module for failure alerts */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failure_event() {
+        let event = FailureEvent {
+            message_id: "0100017fa0".to_owned(),
+            sender: "eru@fufu.soup".to_owned(),
+            recipients: vec!["hello@nyah.dev".to_owned()],
+            subject: "Fufu".to_owned(),
+            failure_class: FailureClass::Permanent,
+            error: "Email address is not verified.".to_owned(),
+            failed_at: 1647679576,
+        };
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["messageId"], "0100017fa0");
+        assert_eq!(json["failureClass"], "permanent");
+        assert_eq!(json["failedAt"], 1647679576);
+    }
+
+    #[tokio::test]
+    async fn test_failure_streak() {
+        let records = MemoryRecordStore::default();
+        assert_eq!(record_success(&records).await.unwrap(), 0);
+        assert_eq!(record_failure(&records).await.unwrap(), 1);
+        assert_eq!(record_failure(&records).await.unwrap(), 2);
+        assert_eq!(record_success(&records).await.unwrap(), 2);
+        assert_eq!(record_failure(&records).await.unwrap(), 1);
+    }
+
+    #[test]
+    fn test_alert_payloads() {
+        let alert = Alert {
+            failures: 5,
+            message_id: "0100017fa0".to_owned(),
+            error: "Sending paused".to_owned(),
+        };
+        let pagerduty = PagerDutyAlerter::new("R0UT1NG");
+        let event = pagerduty.payload("trigger", Some(&alert));
+        assert_eq!(event["routing_key"], "R0UT1NG");
+        assert_eq!(event["dedup_key"], DEDUP_KEY);
+        assert_eq!(
+            event["payload"]["summary"],
+            "privatemail: 5 forwards failed in a row"
+        );
+        assert!(pagerduty.payload("resolve", None).get("payload").is_none());
+
+        let alert = OpsgenieAlerter::payload(&alert);
+        assert_eq!(alert["alias"], DEDUP_KEY);
+        assert_eq!(alert["priority"], "P1");
+    }
+}
@@ -0,0 +1,137 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Archive of every received message.
+//!
+//! With `archive_bucket` set, each message is written to the bucket before
+//! it is handled, whether it is then forwarded or not: the raw MIME under
+//! `yyyy/mm/dd/<messageId>.eml` and a JSON [`Metadata`] sidecar under
+//! `yyyy/mm/dd/<messageId>.json`, the day being the one SES received it.
+//! Unlike the archive of the local store, this copy is independent of the
+//! pipeline and of any mail provider. Messages may be compressed, and
+//! encrypted by S3 or before upload, see [`S3ObjectStore`].
+use crate::config::PrivatEmailConfig;
+use crate::quiet;
+use crate::store::s3::S3ObjectStore;
+use crate::store::ObjectStore;
+use bytes::Bytes;
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Metadata of an archived message, written next to its raw MIME.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    /// SES message id
+    pub message_id: String,
+
+    /// RFC 3339 timestamp at which SES received the message
+    pub timestamp: String,
+
+    /// Envelope sender
+    pub source: String,
+
+    /// Envelope recipients
+    pub destination: Vec<String>,
+
+    /// Subject header
+    pub subject: String,
+
+    /// Verdict status by lowercase check name, e.g. `spam`
+    pub verdicts: BTreeMap<String, String>,
+
+    /// Size of the raw message, in bytes
+    pub size: usize,
+
+    /// SHA-256 digest of the raw message, in hexadecimal
+    pub sha256: String,
+
+    /// RFC 3339 timestamp at which the message was archived
+    pub archived_at: String,
+}
+
+/// Returns the store of `archive_bucket`, compressing and encrypting
+/// messages as configured, if the bucket is set.
+pub fn object_store(config: &PrivatEmailConfig) -> Option<S3ObjectStore> {
+    let bucket = config.archive_bucket.as_ref()?;
+    Some(
+        S3ObjectStore::new(bucket, Region::default())
+            .with_compression(config.compress_archive)
+            .with_sse_kms(config.sse_kms_key_id.clone())
+            .with_envelope_encryption(config.envelope_kms_key_id.clone()),
+    )
+}
+
+/// Returns the key of the message `message_id` received at `timestamp`,
+/// without extension.
+pub fn key(message_id: &str, timestamp: &str) -> String {
+    // Timestamps SES can't have sent are archived under the current day
+    let day = match timestamp.get(..10) {
+        Some(day) if day.as_bytes().get(4) == Some(&b'-') => day.to_owned(),
+        _ => quiet::timestamp(quiet::now())[..10].to_owned(),
+    };
+    format!("{}/{}", day.replace('-', "/"), message_id)
+}
+
+/// Writes `raw` and its `metadata`, completed with the size and digest of
+/// `raw`, to `objects`. Returns the key of the raw message.
+pub async fn write(
+    objects: &dyn ObjectStore,
+    mut metadata: Metadata,
+    raw: Bytes,
+) -> Result<String, Error> {
+    let key = key(&metadata.message_id, &metadata.timestamp);
+    metadata.size = raw.len();
+    metadata.sha256 = hex::encode(Sha256::digest(&raw));
+    metadata.archived_at = quiet::timestamp(quiet::now());
+
+    let raw_key = format!("{}.eml", key);
+    objects.put(&raw_key, raw).await?;
+    objects
+        .put(&format!("{}.json", key), serde_json::to_vec(&metadata)?.into())
+        .await?;
+    Ok(raw_key)
+}
+
+/** Test module for the archive of received messages */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::fs::FsObjectStore;
+
+    #[tokio::test]
+    async fn test_write() {
+        assert_eq!(key("abc", "2022-03-19T08:46:16.420Z"), "2022/03/19/abc");
+        assert!(key("abc", "").ends_with("/abc"));
+
+        let dir = std::env::temp_dir()
+            .join(format!("privatemail-archive-{}", std::process::id()));
+        let objects = FsObjectStore::new(&dir).unwrap();
+        let metadata = Metadata {
+            message_id: "abc".to_owned(),
+            timestamp: "2022-03-19T08:46:16.420Z".to_owned(),
+            subject: "Fufu".to_owned(),
+            ..Default::default()
+        };
+        let raw = Bytes::from_static(b"Subject: Fufu\r\n\r\nHello");
+        let key = write(&objects, metadata, raw.clone()).await.unwrap();
+        assert_eq!(key, "2022/03/19/abc.eml");
+        assert_eq!(objects.get(&key).await.unwrap().unwrap(), raw);
+        let sidecar = objects.get("2022/03/19/abc.json").await.unwrap();
+        let sidecar: Metadata =
+            serde_json::from_slice(&sidecar.unwrap()).unwrap();
+        assert_eq!(sidecar.subject, "Fufu");
+        assert_eq!(sidecar.size, raw.len());
+        assert_eq!(sidecar.sha256.len(), 64);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
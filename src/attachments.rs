@@ -0,0 +1,212 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Attachment blocklist for forwarded messages.
+//!
+//! Attachments are matched either by file extension (entries starting with
+//! a `.`, e.g. `.exe`) or by MIME type (entries containing a `/`, e.g.
+//! `application/x-msdownload`). Matching is case-insensitive.
+use mailparse::{DispositionType, ParsedMail};
+use serde::{Deserialize, Serialize};
+
+/// Suggested blocklist covering common malware vectors: executables,
+/// scripts and macro-enabled office documents.
+pub const DEFAULT_BLOCKLIST: &[&str] = &[
+    ".exe",
+    ".scr",
+    ".com",
+    ".bat",
+    ".cmd",
+    ".pif",
+    ".msi",
+    ".vbs",
+    ".js",
+    ".jse",
+    ".wsf",
+    ".hta",
+    ".ps1",
+    ".jar",
+    ".docm",
+    ".xlsm",
+    ".pptm",
+    ".dotm",
+    "application/x-msdownload",
+    "application/x-msdos-program",
+    "application/javascript",
+    "application/vnd.ms-word.document.macroenabled.12",
+    "application/vnd.ms-excel.sheet.macroenabled.12",
+    "application/vnd.ms-powerpoint.presentation.macroenabled.12",
+];
+
+/// Action taken on a message carrying a blocked attachment.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AttachmentAction {
+    /// Forward the message without the offending attachments
+    #[default]
+    Strip,
+
+    /// Do not forward the message at all
+    Quarantine,
+}
+
+impl std::str::FromStr for AttachmentAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "strip" => Ok(AttachmentAction::Strip),
+            "quarantine" => Ok(AttachmentAction::Quarantine),
+            other => Err(format!("Invalid attachment action: {}", other)),
+        }
+    }
+}
+
+/// An attachment that matched an entry of the blocklist.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct BlockedAttachment {
+    /// File name of the attachment, if any was given
+    pub filename: Option<String>,
+
+    /// MIME type of the attachment part
+    pub mimetype: String,
+
+    /// Blocklist entry which matched the attachment
+    pub rule: String,
+}
+
+/// Returns the file name of an attachment part, looking at the
+/// Content-Disposition `filename` and the Content-Type `name` parameters.
+pub fn attachment_filename(part: &ParsedMail) -> Option<String> {
+    let disposition = part.get_content_disposition();
+    disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned()
+}
+
+/// Returns true when `part` is an attachment rather than a body part.
+pub fn is_attachment(part: &ParsedMail) -> bool {
+    part.subparts.is_empty()
+        && (part.get_content_disposition().disposition
+            == DispositionType::Attachment
+            || attachment_filename(part).is_some())
+}
+
+/// Returns the blocklist entry matching `filename` or `mimetype`, if any.
+pub fn matching_rule(
+    filename: Option<&str>,
+    mimetype: &str,
+    blocklist: &[String],
+) -> Option<String> {
+    let filename = filename.map(|f| f.trim().to_lowercase());
+    let mimetype = mimetype.to_lowercase();
+    blocklist
+        .iter()
+        .map(|rule| rule.trim().to_lowercase())
+        .filter(|rule| !rule.is_empty())
+        .find(|rule| {
+            if rule.contains('/') {
+                mimetype == *rule
+            } else {
+                let ext = if rule.starts_with('.') {
+                    rule.clone()
+                } else {
+                    format!(".{}", rule)
+                };
+                filename.as_deref().is_some_and(|f| f.ends_with(&ext))
+            }
+        })
+}
+
+/// Walks every part of `mail` and returns the attachments matching `blocklist`.
+pub fn find_blocked(
+    mail: &ParsedMail,
+    blocklist: &[String],
+) -> Vec<BlockedAttachment> {
+    let mut blocked = Vec::new();
+    for part in mail.parts() {
+        if !is_attachment(part) {
+            continue;
+        }
+        let filename = attachment_filename(part);
+        if let Some(rule) =
+            matching_rule(filename.as_deref(), &part.ctype.mimetype, blocklist)
+        {
+            blocked.push(BlockedAttachment {
+                filename,
+                mimetype: part.ctype.mimetype.clone(),
+                rule,
+            });
+        }
+    }
+    blocked
+}
+
+/** Test module for attachment blocklist */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::parse_mail;
+
+    const MAIL: &str = "Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+        \r\n\
+        --b1\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        hello\r\n\
+        --b1\r\n\
+        Content-Type: application/octet-stream; name=\"Invoice.EXE\"\r\n\
+        Content-Disposition: attachment; filename=\"Invoice.EXE\"\r\n\
+        \r\n\
+        MZ\r\n\
+        --b1\r\n\
+        Content-Type: application/pdf\r\n\
+        Content-Disposition: attachment; filename=\"report.pdf\"\r\n\
+        \r\n\
+        %PDF\r\n\
+        --b1--\r\n";
+
+    fn blocklist(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_find_blocked_by_extension() {
+        let mail = parse_mail(MAIL.as_bytes()).unwrap();
+        let blocked = find_blocked(&mail, &blocklist(&[".exe", ".js"]));
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].filename.as_deref(), Some("Invoice.EXE"));
+        assert_eq!(blocked[0].rule, ".exe");
+    }
+
+    #[test]
+    fn test_find_blocked_by_mimetype() {
+        let mail = parse_mail(MAIL.as_bytes()).unwrap();
+        let blocked = find_blocked(&mail, &blocklist(&["application/pdf"]));
+        assert_eq!(blocked.len(), 1);
+        assert_eq!(blocked[0].filename.as_deref(), Some("report.pdf"));
+    }
+
+    #[test]
+    fn test_body_parts_are_never_blocked() {
+        let mail = parse_mail(MAIL.as_bytes()).unwrap();
+        assert!(find_blocked(&mail, &blocklist(&["text/plain"])).is_empty());
+    }
+
+    #[test]
+    fn test_attachment_action_from_str() {
+        assert_eq!("Quarantine".parse(), Ok(AttachmentAction::Quarantine));
+        assert_eq!("strip".parse(), Ok(AttachmentAction::Strip));
+        assert!("delete".parse::<AttachmentAction>().is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Policies for the SPF, DKIM and DMARC verdicts of the SES receipt.
+use serde::{Deserialize, Serialize};
+
+/// Header listing the failed checks on tagged forwards, which are sent raw.
+pub const AUTH_HEADER: &str = "X-PrivatEmail-Auth";
+
+/// Verdict status SES reports for a failed check.
+pub const FAIL: &str = "FAIL";
+
+/// Verdict status SES reports for a passed check.
+pub const PASS: &str = "PASS";
+
+/// Sender authentication check reported in the SES receipt.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuthCheck {
+    Spf,
+    Dkim,
+    Dmarc,
+}
+
+impl AuthCheck {
+    /// Returns the lowercase name of the check.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthCheck::Spf => "spf",
+            AuthCheck::Dkim => "dkim",
+            AuthCheck::Dmarc => "dmarc",
+        }
+    }
+}
+
+/// Action taken on a message failing a check.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum VerdictPolicy {
+    /// Do not forward the message at all
+    Drop,
+
+    /// Forward the message with the failure marked in the subject
+    Tag,
+
+    /// Forward the message as is
+    #[default]
+    Forward,
+}
+
+impl std::str::FromStr for VerdictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "drop" => Ok(VerdictPolicy::Drop),
+            "tag" => Ok(VerdictPolicy::Tag),
+            "forward" => Ok(VerdictPolicy::Forward),
+            other => Err(format!("Invalid verdict policy: {}", other)),
+        }
+    }
+}
+
+/// Returns the value of the [`AUTH_HEADER`] for the `failed` checks,
+/// e.g. `spf=fail dmarc=fail`.
+pub fn header_value(failed: &[AuthCheck]) -> String {
+    failed
+        .iter()
+        .map(|check| format!("{}={}", check.as_str(), FAIL.to_lowercase()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prefixes `subject` with the `failed` checks, e.g. `[SPF FAIL] Hi`.
+pub fn tag_subject(subject: &str, failed: &[AuthCheck]) -> String {
+    if failed.is_empty() {
+        return subject.to_owned();
+    }
+    let checks: Vec<String> =
+        failed.iter().map(|check| check.as_str().to_uppercase()).collect();
+    format!("[{} {}] {}", checks.join("/"), FAIL, subject)
+}
+
+/// Whether one of the DKIM `signatures`, the values of `DKIM-Signature`
+/// headers, is by the domain of `address` or a parent domain of it.
+pub fn dkim_aligned(signatures: &[String], address: &str) -> bool {
+    let Some((_, domain)) = address.trim().rsplit_once('@') else {
+        return false;
+    };
+    let domain = domain.to_lowercase();
+    signatures.iter().filter_map(|s| signing_domain(s)).any(|signer| {
+        domain == signer || domain.ends_with(&format!(".{}", signer))
+    })
+}
+
+/// Returns the `d=` tag of a DKIM signature.
+fn signing_domain(signature: &str) -> Option<String> {
+    signature.split(';').find_map(|tag| {
+        let (name, value) = tag.split_once('=')?;
+        (name.trim() == "d").then(|| value.trim().to_lowercase())
+    })
+}
+
+/** Test module for verdict policies */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verdict_policy_from_str() {
+        assert_eq!("Drop".parse(), Ok(VerdictPolicy::Drop));
+        assert_eq!(" tag ".parse(), Ok(VerdictPolicy::Tag));
+        assert!("reject".parse::<VerdictPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_tag_subject() {
+        let failed = [AuthCheck::Spf, AuthCheck::Dmarc];
+        assert_eq!(tag_subject("Hi", &failed), "[SPF/DMARC FAIL] Hi");
+        assert_eq!(tag_subject("Hi", &[]), "Hi");
+        assert_eq!(header_value(&failed), "spf=fail dmarc=fail");
+    }
+
+    #[test]
+    fn test_dkim_aligned() {
+        let signatures =
+            ["v=1; a=rsa-sha256; d=Nyah.dev; s=mail; b=abc".to_owned()];
+        assert!(dkim_aligned(&signatures, "hello@nyah.dev"));
+        assert!(dkim_aligned(&signatures, "hello@mail.nyah.dev"));
+        assert!(!dkim_aligned(&signatures, "hello@evilnyah.dev"));
+        assert!(!dkim_aligned(&signatures, "hello@achu.soup"));
+        assert!(!dkim_aligned(&[], "hello@nyah.dev"));
+    }
+}
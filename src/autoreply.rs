@@ -0,0 +1,296 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Vacation auto-replies and acknowledgments to the senders of forwarded
+//! mail.
+//!
+//! Replies are rendered from the `auto_reply_text` template, which may use
+//! the variables of [`banner::Context`], and sent from the forwarding
+//! address. Each sender gets at most one reply per `auto_reply_days`,
+//! claimed with a conditional write to the `auto_reply_table` DynamoDB
+//! table, whose `expires_at` attribute may serve as its TTL. As RFC 3834
+//! asks, lists, bulk mail, automatic messages and bounces never get one.
+//!
+//! Acknowledgments are sent for every message to an alias such as
+//! `support@`, from per-alias [`Acknowledgment`] templates which may also
+//! use `{ticket}`, the SES message id. A sender getting an acknowledgment
+//! gets no vacation reply for the same message.
+use crate::aws::{self, AwsError};
+use crate::banner;
+use crate::mime::MimeMessage;
+use lambda_runtime::Error;
+use mailparse::{MailHeader, MailHeaderMap};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Header marking messages sent automatically.
+pub const AUTO_SUBMITTED_HEADER: &str = "Auto-Submitted";
+
+/// Partition key attribute of the responder table.
+pub const KEY_ATTRIBUTE: &str = "address";
+
+/// Local parts of senders which never get a reply.
+const AUTOMATED_SENDERS: [&str; 6] = [
+    "mailer-daemon",
+    "postmaster",
+    "noreply",
+    "no-reply",
+    "do-not-reply",
+    "donotreply",
+];
+
+/// Templates of the acknowledgment of mail to an alias.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Acknowledgment {
+    /// Subject template, `Received: <subject> [#<ticket>]` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    /// Text template, e.g. `We received your message, ticket #{ticket}.`
+    pub text: String,
+}
+
+impl Acknowledgment {
+    /// Builds the acknowledgment to `to` of the message with `headers`,
+    /// `original_subject` and SES id `ticket`.
+    pub fn build(
+        &self,
+        from: &str,
+        to: &str,
+        original_subject: &str,
+        ticket: &str,
+        context: &banner::Context,
+        headers: &[MailHeader],
+    ) -> MimeMessage {
+        let render = |template: &str| {
+            context.render(template, false).replace("{ticket}", ticket)
+        };
+        let subject = match &self.subject {
+            Some(subject) => render(subject),
+            None => format!("Received: {} [#{}]", original_subject, ticket),
+        };
+        reply(from, to, &subject, &render(&self.text), headers)
+    }
+}
+
+/// Returns why the message with `headers`, sent from the envelope
+/// `sender`, gets no auto-reply, if it doesn't.
+pub fn suppressed(headers: &[MailHeader], sender: &str) -> Option<String> {
+    let sender = sender.trim().trim_matches(['<', '>']).to_lowercase();
+    let local = sender.split('@').next().unwrap_or_default();
+    if sender.is_empty() {
+        return Some("bounce".to_owned());
+    }
+    if AUTOMATED_SENDERS.iter().any(|a| local.starts_with(a))
+        || local.starts_with("owner-")
+        || local.ends_with("-request")
+    {
+        return Some(format!("automated sender {}", sender));
+    }
+    if let Some(value) = headers.get_first_value(AUTO_SUBMITTED_HEADER) {
+        if !value.trim().eq_ignore_ascii_case("no") {
+            return Some(format!(
+                "{}: {}",
+                AUTO_SUBMITTED_HEADER,
+                value.trim()
+            ));
+        }
+    }
+    if let Some(value) = headers.get_first_value("Precedence") {
+        let value = value.trim().to_lowercase();
+        if ["bulk", "list", "junk"].contains(&value.as_str()) {
+            return Some(format!("Precedence: {}", value));
+        }
+    }
+    [
+        "List-Id",
+        "List-Unsubscribe",
+        "List-Post",
+        "X-Auto-Response-Suppress",
+        "Feedback-ID",
+    ]
+    .into_iter()
+    .find(|name| headers.get_first_header(name).is_some())
+    .map(|name| format!("{} header", name))
+}
+
+/// Builds the reply to `to` for the message with `headers` and
+/// `original_subject`, rendering `subject` and `template` with `context`.
+pub fn build(
+    from: &str,
+    to: &str,
+    subject: Option<&str>,
+    original_subject: &str,
+    template: &str,
+    context: &banner::Context,
+    headers: &[MailHeader],
+) -> MimeMessage {
+    let subject = match subject {
+        Some(subject) => context.render(subject, false),
+        None => format!("Auto: {}", original_subject),
+    };
+    reply(from, to, &subject, &context.render(template, false), headers)
+}
+
+/// Builds an automatic reply to the message with `headers`.
+fn reply(
+    from: &str,
+    to: &str,
+    subject: &str,
+    text: &str,
+    headers: &[MailHeader],
+) -> MimeMessage {
+    let mut message = MimeMessage::new()
+        .header("From", from)
+        .header("To", to)
+        .header("Subject", subject)
+        .header(AUTO_SUBMITTED_HEADER, "auto-replied")
+        .header("X-Auto-Response-Suppress", "All")
+        .header("Precedence", "auto_reply");
+    if let Some(message_id) = headers.get_first_value("Message-ID") {
+        message = message
+            .header("In-Reply-To", message_id.trim())
+            .header("References", message_id.trim());
+    }
+    message.text(text)
+}
+
+/// Records a reply to `sender` at `now` in `table`, returning false when
+/// one was already sent within `days`.
+pub async fn claim(
+    table: &str,
+    sender: &str,
+    now: i64,
+    days: u32,
+) -> Result<bool, Error> {
+    let interval = days as i64 * 86_400;
+    let mut request =
+        SignedRequest::new("POST", "dynamodb", &Region::default(), "/");
+    request.add_header("x-amz-target", "DynamoDB_20120810.PutItem");
+    request.set_content_type("application/x-amz-json-1.0".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&json!({
+        "TableName": table,
+        "Item": {
+            KEY_ATTRIBUTE: { "S": sender.trim().to_lowercase() },
+            "replied_at": { "N": now.to_string() },
+            "expires_at": { "N": (now + interval).to_string() },
+        },
+        "ConditionExpression":
+            "attribute_not_exists(#address) OR replied_at <= :cutoff",
+        "ExpressionAttributeNames": { "#address": KEY_ATTRIBUTE },
+        "ExpressionAttributeValues": {
+            ":cutoff": { "N": (now - interval).to_string() }
+        },
+    }))?));
+    match aws::dispatch(request).await {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.downcast_ref::<AwsError>().is_some_and(|e| {
+                e.body.contains("ConditionalCheckFailedException")
+            }) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/** Test module for auto-replies */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suppressed() {
+        let (headers, _) =
+            mailparse::parse_headers(b"Message-ID: <a@b.io>\r\n\r\n").unwrap();
+        assert_eq!(suppressed(&headers, "fufu@achu.soup"), None);
+        assert!(suppressed(&headers, "<>").is_some());
+        assert!(suppressed(&headers, "MAILER-DAEMON@achu.soup").is_some());
+        assert!(suppressed(&headers, "news-request@achu.soup").is_some());
+
+        for header in [
+            "Auto-Submitted: auto-replied",
+            "Precedence: Bulk",
+            "List-Id: <news.achu.soup>",
+        ] {
+            let raw = format!("{}\r\n\r\n", header);
+            let (headers, _) =
+                mailparse::parse_headers(raw.as_bytes()).unwrap();
+            assert!(suppressed(&headers, "fufu@achu.soup").is_some());
+        }
+        let (headers, _) =
+            mailparse::parse_headers(b"Auto-Submitted: no\r\n\r\n").unwrap();
+        assert_eq!(suppressed(&headers, "fufu@achu.soup"), None);
+    }
+
+    #[test]
+    fn test_build() {
+        let (headers, _) =
+            mailparse::parse_headers(b"Message-ID: <a@b.io>\r\n\r\n").unwrap();
+        let context = banner::Context::new(
+            "fufu@achu.soup",
+            &["me@nyah.dev".to_owned()],
+            "2021-03-19T08:46:16.594Z",
+        );
+        let raw = build(
+            "forwarder@nyah.dev",
+            "fufu@achu.soup",
+            None,
+            "Lunch",
+            "Away until Monday, your mail to {recipient} will wait.",
+            &context,
+            &headers,
+        )
+        .to_bytes();
+        let mail = mailparse::parse_mail(&raw).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Subject").unwrap(),
+            "Auto: Lunch"
+        );
+        assert_eq!(
+            mail.headers.get_first_value("Auto-Submitted").unwrap(),
+            "auto-replied"
+        );
+        assert_eq!(
+            mail.headers.get_first_value("In-Reply-To").unwrap(),
+            "<a@b.io>"
+        );
+        assert!(mail
+            .get_body()
+            .unwrap()
+            .contains("your mail to me@nyah.dev will wait"));
+
+        let acknowledgment: Acknowledgment = serde_json::from_str(
+            r#"{"text": "Dear {sender}, this is ticket #{ticket}."}"#,
+        )
+        .unwrap();
+        let raw = acknowledgment
+            .build(
+                "forwarder@nyah.dev",
+                "fufu@achu.soup",
+                "Broken",
+                "0100abc",
+                &context,
+                &headers,
+            )
+            .to_bytes();
+        let mail = mailparse::parse_mail(&raw).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Subject").unwrap(),
+            "Received: Broken [#0100abc]"
+        );
+        assert!(mail
+            .get_body()
+            .unwrap()
+            .contains("Dear fufu@achu.soup, this is ticket #0100abc."));
+    }
+}
@@ -0,0 +1,202 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Signed request helpers for AWS services without a dedicated rusoto
+//! crate. Requests are built with [`SignedRequest`], signed with the
+//! default credentials chain and dispatched through the shared client.
+//!
+//! Like the AWS SDKs, requests go to the endpoint of
+//! `AWS_ENDPOINT_URL_<SERVICE>`, e.g. `AWS_ENDPOINT_URL_S3`, or else of
+//! `AWS_ENDPOINT_URL` when set, e.g. to LocalStack or MinIO for local
+//! development.
+use crate::{dryrun, xray};
+use lambda_runtime::Error;
+use rusoto_core::credential::{
+    DefaultCredentialsProvider, ProvideAwsCredentials,
+};
+use rusoto_core::request::BufferedHttpResponse;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{Client, HttpDispatchError, Region};
+use std::env;
+use std::time::Duration;
+
+/// Endpoint of every AWS service, overridden per service by
+/// `AWS_ENDPOINT_URL_<SERVICE>`.
+pub const ENDPOINT_URL: &str = "AWS_ENDPOINT_URL";
+
+/// Error returned by an AWS API with a non-success status code.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AwsError {
+    /// HTTP status code of the response
+    pub status: u16,
+
+    /// Raw response body, usually carrying the service error code
+    pub body: String,
+}
+
+impl std::fmt::Display for AwsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "AWS request failed with status {}: {}",
+            self.status, self.body
+        )
+    }
+}
+
+impl std::error::Error for AwsError {}
+
+/// Signs and dispatches `request`, buffering the response body, as an
+/// X-Ray subsegment of the invocation.
+///
+/// Non-success responses are returned as [`AwsError`]. During a dry run,
+/// the request is recorded and answered by the [`dryrun`] recorder instead.
+pub async fn dispatch(
+    mut request: SignedRequest,
+) -> Result<BufferedHttpResponse, Error> {
+    if let Some(recorder) = dryrun::recorder() {
+        return checked(recorder.respond(&request));
+    }
+    redirect(&mut request);
+    let service = request.service.clone();
+    let region = request.region.name().to_owned();
+    xray::traced(&service, &operation(&request), &region, |header| async {
+        if let Some(header) = header {
+            request.add_header(xray::TRACE_HEADER, &header);
+        }
+        let mut response = Client::shared()
+            .sign_and_dispatch(request)
+            .await
+            .map_err(|e| format!("Error dispatching AWS request: {:?}", e))?;
+        let response =
+            response.buffer().await.map_err(Box::<HttpDispatchError>::new)?;
+        checked(response)
+    })
+    .await
+}
+
+/// Returns `response`, or an [`AwsError`] when its status is not a success.
+fn checked(
+    response: BufferedHttpResponse,
+) -> Result<BufferedHttpResponse, Error> {
+    if !response.status.is_success() {
+        return Err(Box::new(AwsError {
+            status: response.status.as_u16(),
+            body: response.body_as_str().to_owned(),
+        }));
+    }
+    Ok(response)
+}
+
+/// Returns the name of the operation of `request`: its JSON target or
+/// query action, or the S3 object operation of its method.
+pub fn operation(request: &SignedRequest) -> String {
+    let target = request
+        .headers
+        .get("x-amz-target")
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).into_owned());
+    if let Some(target) = target {
+        return target.rsplit('.').next().unwrap_or_default().to_owned();
+    }
+    if let Some(Some(action)) = request.params.get("Action") {
+        return action.clone();
+    }
+    match (request.service.as_str(), request.method.as_str()) {
+        ("s3", "GET") => "GetObject".to_owned(),
+        ("s3", "PUT") => "PutObject".to_owned(),
+        ("s3", "DELETE") => "DeleteObject".to_owned(),
+        ("s3", "HEAD") => "HeadObject".to_owned(),
+        (_, method) => format!("{} {}", method, request.path),
+    }
+}
+
+/// Returns a request to the SESv2 API in `region`, which is served by the
+/// `email` endpoints but signed for `ses`.
+pub fn sesv2_request(
+    region: &Region,
+    method: &str,
+    path: &str,
+) -> SignedRequest {
+    let mut request = SignedRequest::new(method, "ses", region, path);
+    if !matches!(region, Region::Custom { .. }) {
+        request.set_hostname(Some(format!(
+            "email.{}.amazonaws.com",
+            region.name()
+        )));
+    }
+    request
+}
+
+/// Returns the endpoint configured for the AWS `service`, by its signing
+/// name, if any.
+pub fn endpoint(service: &str) -> Option<String> {
+    let name = format!("{}_{}", ENDPOINT_URL, service.to_uppercase());
+    env::var(name)
+        .or_else(|_| env::var(ENDPOINT_URL))
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+/// Returns `region`, at the endpoint configured for `service` if any.
+pub fn service_region(service: &str, region: Region) -> Region {
+    match endpoint(service) {
+        Some(endpoint) if !matches!(region, Region::Custom { .. }) => {
+            Region::Custom { name: region.name().to_owned(), endpoint }
+        }
+        _ => region,
+    }
+}
+
+/// Points `request` at the endpoint configured for its service, if any.
+fn redirect(request: &mut SignedRequest) {
+    if endpoint(&request.service).is_some() {
+        let region = request.region.clone();
+        request.region = service_region(&request.service, region);
+        request.set_hostname(None);
+    }
+}
+
+/// Returns the region of the resource `arn`, or the default region when
+/// it is not an ARN.
+pub fn arn_region(arn: &str) -> Region {
+    arn.split(':')
+        .nth(3)
+        .and_then(|region| region.parse().ok())
+        .unwrap_or_default()
+}
+
+/// Fetches the string value of the Secrets Manager secret `secret_id`.
+pub async fn get_secret_string(secret_id: &str) -> Result<String, Error> {
+    let mut request =
+        SignedRequest::new("POST", "secretsmanager", &Region::default(), "/");
+    request.add_header("x-amz-target", "secretsmanager.GetSecretValue");
+    request.set_content_type("application/x-amz-json-1.1".to_owned());
+    request.set_payload(Some(serde_json::to_vec(
+        &serde_json::json!({ "SecretId": secret_id }),
+    )?));
+    let response = dispatch(request).await?;
+    let value: serde_json::Value = serde_json::from_slice(&response.body)?;
+    value["SecretString"].as_str().map(str::to_owned).ok_or_else(|| {
+        format!("Secret {} has no string value", secret_id).into()
+    })
+}
+
+/// Returns a presigned URL for `request`, valid for `expires_in`.
+pub async fn presign(
+    mut request: SignedRequest,
+    expires_in: Duration,
+) -> Result<String, Error> {
+    if dryrun::recorder().is_some() {
+        return Ok(format!("dry-run://{}{}", request.service, request.path));
+    }
+    redirect(&mut request);
+    let credentials = DefaultCredentialsProvider::new()?.credentials().await?;
+    Ok(request.generate_presigned_url(&credentials, &expires_in, false))
+}
@@ -0,0 +1,177 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Templated banner prepended to forwarded bodies, like the external mail
+//! warnings of corporate mail systems.
+//!
+//! Templates may use `{sender}`, `{recipient}` and `{received}`, which are
+//! replaced by the original sender, the original recipients and the time
+//! SES received the message. Values are HTML escaped in the HTML variant.
+use crate::config::PrivatEmailConfig;
+use crate::html;
+
+/// Values substituted into banner templates.
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// Original sender, as in the `From` header
+    pub sender: String,
+
+    /// Original recipients, comma separated
+    pub recipient: String,
+
+    /// When SES received the message, e.g. `2021-03-19 08:46 UTC`
+    pub received: String,
+}
+
+impl Context {
+    /// Creates a context from the SES `timestamp`, e.g.
+    /// `2021-03-19T08:46:16.594Z`.
+    pub fn new(sender: &str, recipients: &[String], timestamp: &str) -> Self {
+        let received = match timestamp.get(..16) {
+            Some(minutes) => format!("{} UTC", minutes.replace('T', " ")),
+            None => timestamp.to_owned(),
+        };
+        Context {
+            sender: sender.to_owned(),
+            recipient: recipients.join(", "),
+            received,
+        }
+    }
+
+    /// Returns `template` with the variables replaced, HTML escaping the
+    /// values when `escape` is set.
+    pub fn render(&self, template: &str, escape: bool) -> String {
+        let value = |value: &str| {
+            if escape {
+                html::escape(value)
+            } else {
+                value.to_owned()
+            }
+        };
+        template
+            .replace("\\n", "\n")
+            .replace("{sender}", &value(&self.sender))
+            .replace("{recipient}", &value(&self.recipient))
+            .replace("{received}", &value(&self.received))
+    }
+}
+
+/// HTML and plain text banner templates.
+#[derive(Clone, Debug)]
+pub struct Banner<'a> {
+    html: Option<&'a str>,
+    text: Option<&'a str>,
+}
+
+impl<'a> Banner<'a> {
+    /// Returns the configured banner, if any.
+    pub fn from_config(email_config: &'a PrivatEmailConfig) -> Option<Self> {
+        let html = email_config.banner_html.as_deref();
+        let text = email_config.banner_text.as_deref();
+        (html.is_some() || text.is_some()).then_some(Banner { html, text })
+    }
+
+    /// Renders the HTML variant, falling back to the escaped text variant
+    /// in a paragraph.
+    pub fn html(&self, context: &Context) -> String {
+        match (self.html, self.text) {
+            (Some(html), _) => context.render(html, true),
+            (None, Some(text)) => format!(
+                "<p>{}</p>",
+                html::escape(&context.render(text, false))
+                    .replace('\n', "<br>")
+            ),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Renders the text variant, falling back to the text of the HTML
+    /// variant.
+    pub fn text(&self, context: &Context) -> String {
+        match (self.text, self.html) {
+            (Some(text), _) => context.render(text, false),
+            (None, Some(html)) => html::to_text(&context.render(html, true)),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Prepends the banner to the forwarded `html` body and its plain
+    /// `text` alternative.
+    pub fn prepend(
+        &self,
+        context: &Context,
+        html: &str,
+        text: Option<&str>,
+    ) -> (String, Option<String>) {
+        (
+            format!("{}\r\n{}", self.html(context), html),
+            text.map(|text| {
+                format!("{}\r\n\r\n{}", self.text(context).trim_end(), text)
+            }),
+        )
+    }
+}
+
+/** Test module for forwarding banners */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> Context {
+        Context::new(
+            "John Doe <john@doe.example>",
+            &["achu@fufu.soup".to_owned()],
+            "2021-03-19T08:46:16.594Z",
+        )
+    }
+
+    #[test]
+    fn test_render() {
+        let context = context();
+        assert_eq!(context.received, "2021-03-19 08:46 UTC");
+        assert_eq!(
+            context.render("<b>[EXTERNAL]</b> from {sender}", true),
+            "<b>[EXTERNAL]</b> from John Doe &lt;john@doe.example&gt;"
+        );
+        assert_eq!(
+            context.render("To {recipient} at {received} {unknown}", false),
+            "To achu@fufu.soup at 2021-03-19 08:46 UTC {unknown}"
+        );
+    }
+
+    #[test]
+    fn test_banner() {
+        let mut config = PrivatEmailConfig::default();
+        assert!(Banner::from_config(&config).is_none());
+
+        config.banner_text =
+            Some("[EXTERNAL] forwarded by privatemail\\nFrom {sender}".into());
+        let banner = Banner::from_config(&config).unwrap();
+        let (html, text) = banner.prepend(&context(), "<p>Hi</p>", Some("Hi"));
+        assert_eq!(
+            html,
+            "<p>[EXTERNAL] forwarded by privatemail<br>\
+             From John Doe &lt;john@doe.example&gt;</p>\r\n<p>Hi</p>"
+        );
+        assert_eq!(
+            text.unwrap(),
+            "[EXTERNAL] forwarded by privatemail\n\
+             From John Doe <john@doe.example>\r\n\r\nHi"
+        );
+
+        config.banner_text = None;
+        config.banner_html =
+            Some("<div><b>[EXTERNAL]</b> {sender}</div>".into());
+        let banner = Banner::from_config(&config).unwrap();
+        assert_eq!(
+            banner.text(&context()),
+            "[EXTERNAL] John Doe <john@doe.example>\n"
+        );
+    }
+}
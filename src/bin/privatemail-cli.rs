@@ -0,0 +1,143 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! privatemail-cli - One-shot runs of the handler.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! ```text
+//! privatemail-cli run --event <event.json> [--dry-run] [--config <file>]
+//! ```
+//!
+//! Handles a saved invocation payload once, as the Lambda would, and
+//! prints the rule decisions for mail and the handler response. The
+//! configuration is read from the given file, `privatemail.toml` or the
+//! environment. With `--dry-run`, AWS is never called: requests are
+//! recorded and answered as by empty resources, notifications and the
+//! local store are disabled, and the would-be SES requests, like the
+//! `SendEmailRequest` of a forward, are printed with the other AWS calls.
+use lambda_runtime::{Context, Error, LambdaEvent};
+use lib::config::{local, secrets, PrivatEmailConfig};
+use lib::dryrun::{self, Call};
+use lib::router::Route;
+use lib::{content, rules, EmailReceiptNotification, PrivatEmailService};
+use serde_json::Value;
+use std::path::Path;
+use std::{env, fs, process};
+
+const USAGE: &str = "usage: privatemail-cli run --event <event.json> \
+                     [--dry-run] [--config <file>]";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    lib::logging::init();
+    let mut args = env::args().skip(1);
+    let (mut event_path, mut config_path, mut dry_run) = (None, None, false);
+    if args.next().as_deref() != Some("run") {
+        usage();
+    }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--event" => event_path = args.next(),
+            "--config" => config_path = args.next(),
+            "--dry-run" => dry_run = true,
+            _ => usage(),
+        }
+    }
+    let Some(event_path) = event_path else { usage() };
+
+    let email_config = match config_path {
+        Some(path) => local::load(Path::new(&path))?,
+        None if Path::new(local::CONFIG_FILE).is_file() => {
+            local::load(Path::new(local::CONFIG_FILE))?
+        }
+        None => PrivatEmailConfig::new_from_env()?,
+    };
+    let email_config = match dry_run {
+        true => dryrun::sandbox(email_config),
+        false => email_config.resolve(&secrets::SecretsManager).await?,
+    };
+    let event: Value = serde_json::from_str(&fs::read_to_string(event_path)?)?;
+    if let Ok(ses_mail) = EmailReceiptNotification::from_event(&event) {
+        print_rules(&email_config, &ses_mail)?;
+    }
+
+    let mut service = PrivatEmailService::from_config(email_config)?;
+    let recorder = dry_run.then(dryrun::start);
+    if let Some(recorder) = &recorder {
+        service = service.with_sender(recorder.ses_client(Default::default()));
+    }
+    let mut context = Context::default();
+    context.request_id = format!("cli-{}", process::id());
+    context.xray_trace_id = Some(context.request_id.clone());
+    let lambda_event = LambdaEvent::new(event, context);
+    let response = match Route::of(&lambda_event.payload) {
+        Route::Http => service.handle_http(lambda_event).await?,
+        _ => service.handle(lambda_event).await?,
+    };
+    println!("Status: {}", response.status_code());
+    println!("{}", serde_json::to_string_pretty(response.body())?);
+
+    if let Some(recorder) = recorder {
+        for call in recorder.calls() {
+            print_call(&call);
+        }
+    }
+    if response.status_code() >= 300 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Prints the usage and exits.
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+/// Prints the rules matching `ses_mail` and the action they decide.
+fn print_rules(
+    email_config: &PrivatEmailConfig,
+    ses_mail: &EmailReceiptNotification,
+) -> Result<(), Error> {
+    let mail = content::parse(ses_mail.content())?;
+    let message = ses_mail.rule_message(&mail, email_config.rules_utc_offset);
+    let filter_rules = email_config.filter_rules();
+    let outcome = rules::evaluate(
+        &filter_rules,
+        email_config.default_rule_action.as_ref(),
+        &message,
+    );
+    for &index in &outcome.matched {
+        let rule = &filter_rules[index];
+        println!("Rule: {} ({})", rule.label(index), rule.action);
+    }
+    if outcome.by_default {
+        println!("Rule: none matched, default action applies");
+    }
+    println!("Action: {}", outcome.disposition);
+    Ok(())
+}
+
+/// Prints an AWS call of the dry run, in full for sends.
+fn print_call(call: &Call) {
+    println!("{} {} {}", call.service, call.operation, call.path);
+    if !call.is_send() {
+        return;
+    }
+    for (name, value) in &call.params {
+        if !matches!(name.as_str(), "Action" | "Version" | "RawMessage.Data") {
+            println!("  {}: {}", name, value);
+        }
+    }
+    if let Some(raw) = call.raw_message() {
+        println!("  RawMessage.Data:");
+        for line in raw.lines() {
+            println!("    {}", line);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! privatemail-export - Export of the archive as an mbox file.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! ```text
+//! privatemail-export <from YYYY-MM-DD> [until YYYY-MM-DD] > archive.mbox
+//! ```
+//!
+//! Writes the messages archived to `archive_bucket` on the days from
+//! `from` to `until`, both included, to stdout as an mbox file which mail
+//! clients like Thunderbird import. `until` defaults to `from`. Only the
+//! archive settings are used, compression and KMS keys included, and read
+//! access to the bucket.
+use lambda_runtime::Error;
+use lib::{archive, mbox, quiet, PrivatEmailService};
+use std::io::{self, BufWriter, Write};
+use std::{env, process};
+
+const USAGE: &str = "usage: privatemail-export <from YYYY-MM-DD> \
+    [until YYYY-MM-DD] > archive.mbox";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let days: Vec<Option<i64>> =
+        args.iter().map(|day| quiet::parse_timestamp(day)).collect();
+    let (from, until) = match days.as_slice() {
+        [Some(from)] => (*from, *from),
+        [Some(from), Some(until)] if from <= until => (*from, *until),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    };
+    let service = PrivatEmailService::new().await?;
+    let Some(objects) = archive::object_store(&service.config()) else {
+        eprintln!("No archive configured, set ARCHIVE_BUCKET");
+        process::exit(2);
+    };
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    let count = mbox::export(&objects, from, until, &mut out).await?;
+    out.flush()?;
+    eprintln!("Exported {} messages", count);
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! privatemail-redrive - Replay of dead-lettered events.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! ```text
+//! privatemail-redrive [message-id...]
+//! ```
+//!
+//! Replays the events dead-lettered under `failed_prefix` of the configured
+//! store through the handler, only those of the given SES message ids when
+//! any are given, and deletes the letters of the events now handled. The
+//! events are forwarded with the function's settings, so its sending
+//! address and SES permissions are needed. Exits with 1 when an event
+//! failed again.
+use lambda_runtime::Error;
+use lib::{deadletter, PrivatEmailService};
+use std::{env, process};
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let message_ids: Vec<String> = env::args().skip(1).collect();
+    let service = PrivatEmailService::new().await?;
+    if service.mail_store.is_none() {
+        eprintln!("No store configured, set S3_BUCKET or LOCAL_STORE_DIR");
+        process::exit(2);
+    }
+
+    let redrive = deadletter::redrive(&service, &message_ids).await?;
+    for key in &redrive.handled {
+        println!("handled  {}", key);
+    }
+    for key in &redrive.failed {
+        println!(" failed  {}", key);
+    }
+    println!(
+        "Redrove {} events, {} failed",
+        redrive.handled.len() + redrive.failed.len(),
+        redrive.failed.len()
+    );
+    if !redrive.failed.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! privatemail-replay - Replay of archived messages.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! ```text
+//! privatemail-replay [--to address] [--date YYYY-MM-DD] <key|message-id>...
+//! ```
+//!
+//! Handles the messages archived to `archive_bucket` under the given keys
+//! or SES message ids again, through the whole forwarding pipeline,
+//! forwarding them to `address` instead of `to_email` when given. Message
+//! ids are looked up under the day they were received, by default each of
+//! the last 31 days. The credentials must read the archive bucket besides
+//! sending with SES. Exits with 1 when a message failed.
+use lambda_runtime::Error;
+use lib::{archive, replay, PrivatEmailService};
+use std::{env, process};
+
+const USAGE: &str = "usage: privatemail-replay [--to address] \
+                     [--date YYYY-MM-DD] <key|message-id>...";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let to = option(&mut args, "--to");
+    let day = option(&mut args, "--date");
+    if args.is_empty() || args.iter().any(|arg| arg.starts_with("--")) {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    }
+    let service = PrivatEmailService::new().await?;
+    let Some(objects) = archive::object_store(&service.config()) else {
+        eprintln!("No archive configured, set ARCHIVE_BUCKET");
+        process::exit(2);
+    };
+
+    let (day, mut failed) = (day.as_deref(), 0);
+    for target in &args {
+        match replay::replay(&service, &objects, target, day, to.as_deref())
+            .await
+        {
+            Ok(response) if response.status_code() < 300 => {
+                println!("replayed  {}: {}", target, response.message());
+            }
+            Ok(response) => {
+                println!("  failed  {}: {}", target, response.message());
+                failed += 1;
+            }
+            Err(e) => {
+                println!("  failed  {}: {}", target, e);
+                failed += 1;
+            }
+        }
+    }
+    println!("Replayed {} messages, {} failed", args.len(), failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Removes the option `name` and its value from `args`, returning the
+/// value.
+fn option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    match args.iter().position(|arg| arg == name) {
+        Some(i) if i + 1 < args.len() => {
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Some(value)
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,119 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! privatemail-rules - Dry run of the filtering rules.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! ```text
+//! privatemail-rules <event.json> [rules.yaml]
+//! ```
+//!
+//! Evaluates the rules against a saved SNS event, or a bare SES
+//! notification, and prints which rules match and the action that would
+//! be taken; rules skipped after the evaluation ended are marked `skip`.
+//! Nothing is sent. The rules file is a YAML, JSON or TOML configuration
+//! holding `rules`, `default_rule_action`, `rules_utc_offset` and
+//! `black_list`, or a bare list of rules; without one, `privatemail.toml`
+//! or the environment is used. Schedules are checked against the current
+//! time.
+use lambda_runtime::Error;
+use lib::config::{local, PrivatEmailConfig};
+use lib::EmailReceiptNotification;
+use lib::{content, rules};
+use serde_json::Value;
+use std::path::Path;
+use std::{env, fs, process};
+
+fn main() -> Result<(), Error> {
+    let mut args = env::args().skip(1);
+    let Some(event_path) = args.next() else {
+        eprintln!("Usage: privatemail-rules <event.json> [rules.yaml]");
+        process::exit(2);
+    };
+    let email_config = match args.next() {
+        Some(path) => rules_config(Path::new(&path))?,
+        None if Path::new(local::CONFIG_FILE).is_file() => {
+            local::load(Path::new(local::CONFIG_FILE))?
+        }
+        None => PrivatEmailConfig::new_from_env()?,
+    };
+
+    let event: Value = serde_json::from_str(&fs::read_to_string(event_path)?)?;
+    let ses_mail = EmailReceiptNotification::from_event(&event)?;
+    let mail = content::parse(ses_mail.content())?;
+    let message = ses_mail.rule_message(&mail, email_config.rules_utc_offset);
+    println!("Senders: {}", message.senders.join(", "));
+    println!("Recipients: {}", message.recipients.join(", "));
+    println!("Subject: {}", message.subject);
+
+    let filter_rules = email_config.filter_rules();
+    let outcome = rules::evaluate(
+        &filter_rules,
+        email_config.default_rule_action.as_ref(),
+        &message,
+    );
+    let mut order: Vec<usize> = (0..filter_rules.len()).collect();
+    order.sort_by_key(|&index| filter_rules[index].priority);
+    for index in order {
+        let rule = &filter_rules[index];
+        let state = if outcome.matched.contains(&index) {
+            "match"
+        } else if rule.matches(&message) {
+            "skip"
+        } else {
+            "-"
+        };
+        println!("{:>5}  {} ({})", state, rule.label(index), rule.action);
+    }
+    if outcome.by_default {
+        println!("No rule matched, default action applies");
+    }
+    for tag in &outcome.tags {
+        println!("Tag: {}", tag);
+    }
+    if let Some(to) = &outcome.redirect {
+        println!("Redirect: {}", to);
+    }
+    if let Some(release_at) = outcome.defer_until {
+        println!("Deferred until: {}", release_at);
+    }
+    println!("Action: {}", outcome.disposition);
+    Ok(())
+}
+
+/// Reads the rules of the configuration file at `path`, parsed by its
+/// extension.
+fn rules_config(path: &Path) -> Result<PrivatEmailConfig, Error> {
+    let contents = fs::read_to_string(path)?;
+    let value: Value = match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(&contents)?,
+        Some("toml") => {
+            serde_json::to_value(toml::from_str::<toml::Table>(&contents)?)?
+        }
+        _ => serde_yaml::from_str(&contents)?,
+    };
+    let mut config = PrivatEmailConfig::default();
+    match value {
+        Value::Array(_) => config.rules = serde_json::from_value(value)?,
+        mut value => {
+            if !value["rules"].is_null() {
+                config.rules = serde_json::from_value(value["rules"].take())?;
+            }
+            config.black_list =
+                serde_json::from_value(value["black_list"].take())?;
+            config.default_rule_action =
+                serde_yaml::with::singleton_map::deserialize(
+                    value["default_rule_action"].take(),
+                )?;
+            if let Some(offset) = value["rules_utc_offset"].as_i64() {
+                config.rules_utc_offset = offset as i32;
+            }
+        }
+    }
+    Ok(config)
+}
@@ -0,0 +1,90 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! privatemail-suppression - Administration of the SES suppression list.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! ```text
+//! privatemail-suppression list
+//! privatemail-suppression check <address>...
+//! privatemail-suppression add <bounce|complaint> <address>...
+//! privatemail-suppression remove <address>...
+//! ```
+//!
+//! Lists, looks up, adds or removes addresses on the SES account
+//! suppression list of `SES_REGION`, or the region of the environment.
+//! `check` exits with 1 when an address is suppressed.
+use lambda_runtime::Error;
+use lib::suppression::{self, Reason};
+use rusoto_core::Region;
+use std::{env, process};
+
+const USAGE: &str = "usage: privatemail-suppression \
+    <list | check <address>... | add <bounce|complaint> <address>... \
+    | remove <address>...>";
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Some((command, args)) = args.split_first() else {
+        eprintln!("{}", USAGE);
+        process::exit(2);
+    };
+    let region: Region = match env::var("SES_REGION") {
+        Ok(region) => region.parse()?,
+        Err(_) => Region::default(),
+    };
+    match (command.as_str(), args) {
+        ("list", []) => {
+            for destination in suppression::list(&region).await? {
+                println!(
+                    "{:>9}  {}",
+                    destination.reason.as_str().to_lowercase(),
+                    destination.email_address
+                );
+            }
+        }
+        ("check", addresses) if !addresses.is_empty() => {
+            let mut suppressed = false;
+            for address in addresses {
+                match suppression::get(&region, address).await? {
+                    Some(destination) => {
+                        suppressed = true;
+                        println!(
+                            "{:>9}  {}",
+                            destination.reason.as_str().to_lowercase(),
+                            address
+                        );
+                    }
+                    None => println!("{:>9}  {}", "-", address),
+                }
+            }
+            if suppressed {
+                process::exit(1);
+            }
+        }
+        ("add", [reason, addresses @ ..]) if !addresses.is_empty() => {
+            let reason: Reason = reason.parse()?;
+            for address in addresses {
+                suppression::add(&region, address, reason).await?;
+                println!("Suppressed {}", address);
+            }
+        }
+        ("remove", addresses) if !addresses.is_empty() => {
+            for address in addresses {
+                suppression::remove(&region, address).await?;
+                println!("Removed {}", address);
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
+    Ok(())
+}
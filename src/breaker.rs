@@ -0,0 +1,127 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Circuit breaker around the SES send path.
+//!
+//! After `circuit_breaker_threshold` sends in a row fail, as when sending
+//! is paused on the account, the breaker opens and messages are
+//! quarantined instead of sent. Once `circuit_breaker_cooldown_secs` have
+//! passed a single send is let through: its success closes the breaker,
+//! its failure opens it again, and another one is let through after the
+//! next cooldown when it never finishes. The state lives in the warm
+//! Lambda container, so each container trips on its own.
+use std::sync::{Mutex, OnceLock};
+
+/// Counter and metric name of sends refused by the open breaker.
+pub const OPEN_COUNTER: &str = "circuit_open";
+
+/// Metric name logged when the breaker opens.
+pub const TRIPPED_METRIC: &str = "circuit_tripped";
+
+/// State of the breaker.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum State {
+    /// Sends go through
+    #[default]
+    Closed,
+
+    /// Sends are refused since the given time
+    Open(i64),
+
+    /// A trial send is under way since the given time
+    HalfOpen(i64),
+}
+
+/// Consecutive failures of the send path and the state they led to.
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    failures: u32,
+    state: State,
+}
+
+impl CircuitBreaker {
+    /// Returns the state of the breaker.
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Whether a send may be made at `now`, letting a trial send through
+    /// once the breaker was open for `cooldown` seconds.
+    pub fn allow(&mut self, now: i64, cooldown: i64) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::Open(since) | State::HalfOpen(since)
+                if now - since >= cooldown =>
+            {
+                self.state = State::HalfOpen(now);
+                true
+            }
+            State::Open(_) | State::HalfOpen(_) => false,
+        }
+    }
+
+    /// Records a successful send, closing the breaker.
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+        self.state = State::Closed;
+    }
+
+    /// Records a failed send at `now`, returning true when it opened the
+    /// breaker, i.e. it was the `threshold`th failure in a row or a failed
+    /// trial send.
+    pub fn record_failure(&mut self, now: i64, threshold: u32) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        let trips = match self.state {
+            State::Closed => self.failures >= threshold,
+            State::HalfOpen(_) => true,
+            State::Open(_) => false,
+        };
+        if trips {
+            self.state = State::Open(now);
+        }
+        trips
+    }
+}
+
+/// Breaker shared by the invocations of a warm Lambda container.
+pub fn shared() -> &'static Mutex<CircuitBreaker> {
+    static BREAKER: OnceLock<Mutex<CircuitBreaker>> = OnceLock::new();
+    BREAKER.get_or_init(Default::default)
+}
+
+/** Test module for the circuit breaker */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_breaker() {
+        let mut breaker = CircuitBreaker::default();
+        assert!(!breaker.record_failure(100, 3));
+        assert!(!breaker.record_failure(101, 3));
+        breaker.record_success();
+        assert!(!breaker.record_failure(102, 3));
+        assert!(!breaker.record_failure(103, 3));
+        assert!(breaker.record_failure(104, 3));
+        assert_eq!(breaker.state(), State::Open(104));
+
+        assert!(!breaker.allow(150, 60));
+        assert!(breaker.allow(164, 60));
+        assert_eq!(breaker.state(), State::HalfOpen(164));
+        assert!(!breaker.allow(165, 60));
+        assert!(breaker.record_failure(166, 3));
+        assert!(!breaker.allow(200, 60));
+
+        assert!(breaker.allow(226, 60));
+        assert!(breaker.allow(290, 60));
+        breaker.record_success();
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.allow(291, 60));
+    }
+}
@@ -0,0 +1,419 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Optional message classification.
+//!
+//! A [`Classifier`] labels each message before it is forwarded; the label
+//! can block the message (`BLOCKED_LABELS`) and is passed along as a
+//! header or SES message tag. [`BedrockClassifier`] asks an Amazon Bedrock
+//! model through the Converse API. Classification never holds up mail:
+//! calls are bounded by a timeout, answers are cached per sender and
+//! subject, and a daily cap limits spend. Only answers for senders
+//! authenticated by SPF are cached, so forged mail can neither reuse nor
+//! set the label of the sender it claims to be.
+use crate::aws;
+use crate::received::Anomaly;
+use crate::store::RecordStore;
+use async_trait::async_trait;
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tracing::warn;
+
+/// Header carrying the label on raw forwarded messages.
+pub const LABEL_HEADER: &str = "X-PrivatEmail-Label";
+
+/// SES message tag carrying the label on formatted forwarded messages.
+pub const LABEL_TAG: &str = "label";
+
+/// Default time allowed for a classification call, in milliseconds.
+pub const DEFAULT_TIMEOUT_MS: u64 = 1500;
+
+/// Body characters sent to the classifier, bounding the cost of a call.
+pub const MAX_INPUT_CHARS: usize = 4000;
+
+/// Entries kept in the warm-container cache before it is reset.
+const CACHE_CAPACITY: usize = 1024;
+
+/// Counter prefix tracking daily classification calls.
+const COUNTER_PREFIX: &str = "classifications/";
+
+/// Label attached to a classified message.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Label {
+    /// Mail written by a person to the recipient
+    Personal,
+
+    /// Bulk mail the recipient subscribed to
+    Newsletter,
+
+    /// Receipts, notifications and other automated mail
+    Transactional,
+
+    /// Mail which looks like phishing
+    PhishingSuspect,
+}
+
+impl Label {
+    /// Every label, in the order offered to the classifier.
+    pub const ALL: [Label; 4] = [
+        Label::Personal,
+        Label::Newsletter,
+        Label::Transactional,
+        Label::PhishingSuspect,
+    ];
+
+    /// Returns the label name used in headers, tags and config.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Label::Personal => "personal",
+            Label::Newsletter => "newsletter",
+            Label::Transactional => "transactional",
+            Label::PhishingSuspect => "phishing-suspect",
+        }
+    }
+}
+
+impl std::str::FromStr for Label {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        Label::ALL
+            .into_iter()
+            .find(|label| label.as_str() == s)
+            .ok_or_else(|| format!("Invalid label: {}", s))
+    }
+}
+
+/// What a classifier gets to see of a message.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ClassifierInput {
+    /// Original sender address
+    pub sender: String,
+
+    /// Message subject
+    pub subject: String,
+
+    /// Decoded body, truncated to [`MAX_INPUT_CHARS`]
+    pub body: String,
+
+    /// Anomalies found in the `Received` chain
+    pub anomalies: Vec<String>,
+
+    /// Whether SPF passed for the sender, so its answer may be cached
+    pub authenticated: bool,
+}
+
+impl ClassifierInput {
+    /// Builds the input, truncating `body` to [`MAX_INPUT_CHARS`].
+    pub fn new(sender: &str, subject: &str, body: &str) -> Self {
+        ClassifierInput {
+            sender: sender.to_owned(),
+            subject: subject.to_owned(),
+            body: body.chars().take(MAX_INPUT_CHARS).collect(),
+            anomalies: Vec::new(),
+            authenticated: false,
+        }
+    }
+
+    /// Adds the anomalies found in the `Received` chain.
+    pub fn with_anomalies(mut self, anomalies: &[Anomaly]) -> Self {
+        self.anomalies = anomalies.iter().map(Anomaly::to_string).collect();
+        self
+    }
+
+    /// Marks the sender as authenticated, or not.
+    pub fn with_authenticated(mut self, authenticated: bool) -> Self {
+        self.authenticated = authenticated;
+        self
+    }
+
+    /// Cache key; the body is left out so that repeated newsletters and
+    /// notifications from a sender share an answer.
+    fn cache_key(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.sender.to_lowercase().hash(&mut hasher);
+        self.subject.hash(&mut hasher);
+        self.anomalies.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Assigns a [`Label`] to a message.
+#[async_trait]
+pub trait Classifier: Send + Sync {
+    /// Returns the label for `input`.
+    async fn classify(&self, input: &ClassifierInput) -> Result<Label, Error>;
+}
+
+/// [`Classifier`] asking an Amazon Bedrock model through the Converse API.
+#[derive(Clone, Debug)]
+pub struct BedrockClassifier {
+    model_id: String,
+    region: Region,
+}
+
+impl BedrockClassifier {
+    /// Creates a classifier using `model_id` in `region`.
+    pub fn new<M: ToString>(model_id: M, region: Region) -> Self {
+        BedrockClassifier { model_id: model_id.to_string(), region }
+    }
+}
+
+#[async_trait]
+impl Classifier for BedrockClassifier {
+    async fn classify(&self, input: &ClassifierInput) -> Result<Label, Error> {
+        let text =
+            converse(&self.model_id, &self.region, &prompt(input), 10).await?;
+        parse_label(&text)
+    }
+}
+
+/// Sends `prompt` to a Bedrock model through the Converse API and returns
+/// the text of its answer, capped at `max_tokens`.
+pub(crate) async fn converse(
+    model_id: &str,
+    region: &Region,
+    prompt: &str,
+    max_tokens: u32,
+) -> Result<String, Error> {
+    let mut request = SignedRequest::new(
+        "POST",
+        "bedrock",
+        region,
+        &format!("/model/{}/converse", model_id),
+    );
+    if !matches!(region, Region::Custom { .. }) {
+        request.set_hostname(Some(format!(
+            "bedrock-runtime.{}.amazonaws.com",
+            region.name()
+        )));
+    }
+    request.set_content_type("application/json".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&json!({
+        "messages": [{
+            "role": "user",
+            "content": [{ "text": prompt }],
+        }],
+        "inferenceConfig": { "maxTokens": max_tokens, "temperature": 0 },
+    }))?));
+    let response = aws::dispatch(request).await?;
+    let response: Value = serde_json::from_slice(&response.body)?;
+    response["output"]["message"]["content"][0]["text"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| "Missing text in Bedrock response".into())
+}
+
+/// Builds the classification prompt for `input`.
+fn prompt(input: &ClassifierInput) -> String {
+    let labels: Vec<&str> = Label::ALL.iter().map(Label::as_str).collect();
+    let anomalies = if input.anomalies.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "Anomalies in its Received headers: {}.\n",
+            input.anomalies.join("; ")
+        )
+    };
+    format!(
+        "Classify the email below as exactly one of: {}.\n\
+         Answer with the label only.\n{}\n\
+         From: {}\nSubject: {}\n\n{}",
+        labels.join(", "),
+        anomalies,
+        input.sender,
+        input.subject,
+        input.body
+    )
+}
+
+/// Extracts the label from a model answer, tolerating surrounding text.
+fn parse_label(text: &str) -> Result<Label, Error> {
+    let text = text.to_lowercase();
+    Label::ALL
+        .into_iter()
+        .filter_map(|label| text.find(label.as_str()).map(|at| (at, label)))
+        .min_by_key(|(at, _)| *at)
+        .map(|(_, label)| label)
+        .ok_or_else(|| {
+            format!("No label in classifier answer: {}", text).into()
+        })
+}
+
+/// Labels cached across invocations of a warm Lambda container.
+fn cache() -> &'static Mutex<HashMap<u64, Label>> {
+    static CACHE: OnceLock<Mutex<HashMap<u64, Label>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Daily budget for classification calls.
+pub struct Budget<'a> {
+    /// Store keeping the daily counter
+    pub records: &'a dyn RecordStore,
+
+    /// Day being counted, e.g. `2021-03-19`
+    pub day: &'a str,
+
+    /// Largest number of calls allowed per day
+    pub cap: u64,
+}
+
+/// Classifies `input`, returning `None` instead of failing when the
+/// classifier errors, times out or the budget is spent. Answers for
+/// unauthenticated senders are neither read from nor written to the cache.
+pub async fn classify(
+    classifier: &dyn Classifier,
+    input: &ClassifierInput,
+    timeout: Duration,
+    budget: Option<Budget<'_>>,
+) -> Option<Label> {
+    let key = input.authenticated.then(|| input.cache_key());
+    if let Some(label) =
+        key.and_then(|key| cache().lock().unwrap().get(&key).copied())
+    {
+        return Some(label);
+    }
+
+    if let Some(budget) = budget {
+        let counter = format!("{}{}", COUNTER_PREFIX, budget.day);
+        match budget.records.increment(&counter, 1).await {
+            Ok(calls) if calls > budget.cap => {
+                warn!("Daily classification cap of {} reached", budget.cap);
+                return None;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Error counting classification call: {:?}", e);
+                return None;
+            }
+        }
+    }
+
+    let label =
+        match tokio::time::timeout(timeout, classifier.classify(input)).await {
+            Ok(Ok(label)) => label,
+            Ok(Err(e)) => {
+                warn!("Error classifying message: {:?}", e);
+                return None;
+            }
+            Err(_) => {
+                warn!("Classification timed out after {:?}", timeout);
+                return None;
+            }
+        };
+
+    if let Some(key) = key {
+        let mut cache = cache().lock().unwrap();
+        if cache.len() >= CACHE_CAPACITY {
+            cache.clear();
+        }
+        cache.insert(key, label);
+    }
+    Some(label)
+}
+
+/** Test module for message classification */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryRecordStore;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct Fixed(Label, Duration, AtomicUsize);
+
+    #[async_trait]
+    impl Classifier for Fixed {
+        async fn classify(&self, _: &ClassifierInput) -> Result<Label, Error> {
+            self.2.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.1).await;
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_parse_label() {
+        assert_eq!(parse_label(" Newsletter.\n").unwrap(), Label::Newsletter);
+        assert_eq!(
+            parse_label("phishing-suspect, not personal").unwrap(),
+            Label::PhishingSuspect
+        );
+        assert!(parse_label("spam").is_err());
+        assert_eq!("Phishing-Suspect".parse(), Ok(Label::PhishingSuspect));
+    }
+
+    #[tokio::test]
+    async fn test_classify_cache_and_budget() {
+        let classifier =
+            Fixed(Label::Transactional, Duration::ZERO, AtomicUsize::new(0));
+        let records = MemoryRecordStore::default();
+        let budget =
+            || Some(Budget { records: &records, day: "2021-03-19", cap: 1 });
+        let first = ClassifierInput::new("shop@cache.test", "Receipt", "1")
+            .with_authenticated(true);
+        let second = ClassifierInput::new("shop@cache.test", "Other", "2")
+            .with_authenticated(true);
+        let timeout = Duration::from_secs(1);
+
+        assert_eq!(
+            classify(&classifier, &first, timeout, budget()).await,
+            Some(Label::Transactional)
+        );
+        // Cached answers neither call the classifier nor use the budget
+        assert_eq!(
+            classify(&classifier, &first, timeout, budget()).await,
+            Some(Label::Transactional)
+        );
+        assert_eq!(
+            classify(&classifier, &second, timeout, budget()).await,
+            None
+        );
+        assert_eq!(classifier.2.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_classify_unauthenticated_is_not_cached() {
+        let classifier =
+            Fixed(Label::Transactional, Duration::ZERO, AtomicUsize::new(0));
+        let timeout = Duration::from_secs(1);
+        let genuine = ClassifierInput::new("bank@forged.test", "Alert", "1")
+            .with_authenticated(true);
+        let forged = ClassifierInput::new("bank@forged.test", "Alert", "2");
+
+        // A forged message neither sets the label of the sender...
+        classify(&classifier, &forged, timeout, None).await;
+        classify(&classifier, &genuine, timeout, None).await;
+        assert_eq!(classifier.2.load(Ordering::SeqCst), 2);
+
+        // ...nor gets the one cached for it
+        classify(&classifier, &forged, timeout, None).await;
+        assert_eq!(classifier.2.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_classify_timeout() {
+        let classifier =
+            Fixed(Label::Personal, Duration::from_secs(5), AtomicUsize::new(0));
+        let input = ClassifierInput::new("slow@timeout.test", "Hi", "");
+        assert_eq!(
+            classify(&classifier, &input, Duration::from_millis(10), None)
+                .await,
+            None
+        );
+    }
+}
@@ -6,11 +6,46 @@
 //!
 //! Authors:
 //! - Nyah Check <hello@nyah.dev>
+//!
 //! GPG signature verification.
-
+//!
 //! Configuration struct for `PrivatEmail`
+pub mod builder;
+pub mod dynamo;
+pub mod local;
+pub mod remote;
+pub mod secrets;
+pub mod tenant;
+pub mod validate;
+
+pub use builder::PrivatEmailConfigBuilder;
+pub use tenant::Tenant;
+
+use crate::attachments::{AttachmentAction, DEFAULT_BLOCKLIST};
+use crate::auth::{AuthCheck, VerdictPolicy};
+use crate::autoreply::Acknowledgment;
+use crate::classify::{Label, DEFAULT_TIMEOUT_MS};
+use crate::dnsbl::Blocklist;
+use crate::failure::{self, FailureClass, FailurePolicy};
+use crate::forward::{self, ForwardMode};
+use crate::limits::{
+    SESV2_MAX_SEND_SIZE, SES_MAX_RECEIVE_SIZE, SES_MAX_SEND_SIZE,
+};
+use crate::migration::SesBackend;
+use crate::notify;
+use crate::quiet::{self, QuietHours};
+use crate::rules::{Action, Rule};
+use crate::store;
+use crate::tracking;
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 /// Config object for `PrivatEmail`.
 ///
@@ -20,6 +55,120 @@ use std::env;
 ///  `from_email`: Original Recipient Email from Verified SES Domain
 ///  `to_email`: Recipient SES verified email address which receives the forwarded email
 ///  `black_list`: Black listed email addresses.
+///  `blocked_attachments`: Banned attachment extensions or MIME types.
+///  `attachment_action`: Strip blocked attachments or quarantine the message.
+///  `local_store_dir`: Directory holding the local archive, quarantine and database.
+///  `s3_bucket`: Bucket holding quarantined and oversized messages.
+///  `archive_bucket`: Bucket every received message is archived to.
+///  `compress_archive`: Compress archived messages with zstd.
+///  `sse_kms_key_id`: KMS key S3 encrypts stored messages with.
+///  `envelope_kms_key_id`: KMS key archived messages are encrypted under.
+///  `retention_days`: Days archived and quarantined messages are kept.
+///  `max_send_size`: Largest message size handed to SES, in bytes.
+///  `max_content_size`: Largest received message parsed, in bytes.
+///  `slack_webhook_url`: Slack incoming webhook notified of forwarded mail.
+///  `preview_renderer`: Lambda function rendering HTML previews to PNG.
+///  `discord_webhooks`: Discord webhook notified of forwarded mail, per route.
+///  `telegram_bot_token`: Token of the Telegram bot sending notifications.
+///  `telegram_chat_id`: Telegram chat notified of forwarded mail.
+///  `telegram_preview_chars`: Length of the text preview sent to Telegram.
+///  `telegram_only_aliases`: Routes sent to Telegram instead of forwarded.
+///  `alert_topic_arn`: SNS topic alerted of permanently failed forwards.
+///  `alert_failure_threshold`: Failed sends in a row paging the on-call.
+///  `pagerduty_routing_key`: PagerDuty integration paged on failed sends.
+///  `opsgenie_api_key`: Opsgenie integration paged on failed sends.
+///  `event_bus`: EventBridge bus put the lifecycle events of messages.
+///  `webhook_url`: HTTPS endpoint posted a summary of every handled message.
+///  `webhook_secret`: Secret signing the webhook payloads with HMAC-SHA256.
+///  `ses_primary`: SES client library used to send mail.
+///  `ses_dual_write`: Build requests for both SES clients and log differences.
+///  `ses_v2`: Send raw forwards through SESv2, up to 40 MB.
+///  `configuration_set`: SES configuration set mail is sent through.
+///  `ses_region`: SES region mail is sent in, the function's by default.
+///  `ses_failover_region`: SES region sends fail over to.
+///  `ses_failover_identity_arn`: Identity sends fail over as.
+///  `message_tags`: Tag forwards with their alias, tenant and sender domain.
+///  `tracking_configuration_set`: SES configuration set tracking opens/clicks.
+///  `tracked_aliases`: Whether forwards are tracked, per route.
+///  `check_suppression`: Skip sends to the account suppression list.
+///  `send_as_domains`: Domains mail may be composed from.
+///  `send_api_token`: Bearer token of HTTP send requests.
+///  `inbound_webhook_token`: Token of mail posted by inbound webhooks.
+///  `search_api_token`: Bearer token of HTTP searches of the mail index.
+///  `forward_mode`: Forward the body inline or the original as an attachment.
+///  `classifier_model`: Bedrock model labelling messages before forwarding.
+///  `classifier_timeout_ms`: Time allowed for a classification, in milliseconds.
+///  `classifier_daily_cap`: Largest number of classifications per day.
+///  `blocked_labels`: Classifier labels which are never forwarded.
+///  `digest_summaries`: Summarize long messages in digests.
+///  `summary_model`: Bedrock model writing digest summaries.
+///  `inject_headers`: Add `X-Original-*` and `X-Forwarded-By` headers to forwards.
+///  `relay_alias`: Address receiving replies relayed back to original senders.
+///  `relay_secret`: Secret signing relay reply addresses.
+///  `failure_policies`: Policy applied per class of send failure.
+///  `srs_domain`: Domain of SRS rewritten return paths.
+///  `srs_secret`: Secret hashing SRS rewritten return paths.
+///  `dedup_attachments`: Store archived attachments once, by content hash.
+///  `dkim_domain`: Domain signing raw sends with DKIM.
+///  `dkim_selector`: DKIM selector of the signing key.
+///  `dkim_private_key`: PEM encoded DKIM private key.
+///  `dkim_key_secret`: Secrets Manager secret holding the DKIM private key.
+///  `to_display_names`: Display name of the forwarded `To` header per route.
+///  `spf_policy`: Drop, tag or forward mail failing SPF.
+///  `dkim_policy`: Drop, tag or forward mail failing DKIM.
+///  `dmarc_policy`: Drop, tag or forward mail failing DMARC.
+///  `dnsbl_lists`: DNS blocklists the sending relay and domain are checked in.
+///  `dnsbl_threshold`: Blocklist score from which `dnsbl_policy` applies.
+///  `dnsbl_policy`: Drop, tag (default) or forward blocklisted mail.
+///  `text_fallback`: Add a plain text alternative to forwards (default on).
+///  `pgp_public_key`: Armored PGP public key forwards are encrypted to.
+///  `quiet_hours`: Daily window without notifications, per route.
+///  `quiet_hours_utc_offset`: UTC offset of quiet hours, in minutes.
+///  `quiet_hours_defer`: Also defer forwards until quiet hours end.
+///  `pgp_keyring`: Armored PGP public keys inbound signatures are checked with.
+///  `smime_certificate`: PEM certificate and private key signing forwards.
+///  `smime_certificate_secret`: Secrets Manager secret holding the above.
+///  `smime_trusted_certs`: PEM CA certificates inbound S/MIME is checked with.
+///  `sanitize_html`: Strip scripts, forms and active content from forwards.
+///  `strip_trackers`: Drop tracking pixels and unwrap tracking redirects.
+///  `tracking_redirects`: Redirectors unwrapped, as `host/path?param`.
+///  `banner_html`: HTML banner template prepended to forwarded bodies.
+///  `banner_text`: Plain text banner template prepended to forwarded bodies.
+///  `config_table`: DynamoDB table of per-recipient settings.
+///  `validate_resources`: Check that the S3 bucket and table exist at startup.
+///  `subject_prefix`: Prefix of forwarded subjects.
+///  `tenants`: Per-domain sender, destination, blocklist and subject prefix.
+///  `subject_prefixes`: Prefix of forwarded subjects per route.
+///  `sender_routes`: Destination of mail from a sender address or domain.
+///  `rules`: Ordered filtering rules, see [`crate::rules`].
+///  `default_rule_action`: Action taken when no filtering rule matches.
+///  `rules_utc_offset`: UTC offset of rule schedules, in minutes.
+///  `auto_reply_text`: Template of the vacation auto-reply.
+///  `auto_reply_subject`: Template of the auto-reply subject.
+///  `auto_reply_table`: DynamoDB table of senders replied to.
+///  `auto_reply_days`: Days between auto-replies to a sender.
+///  `acknowledgments`: Acknowledgment templates, per route.
+///  `loop_detection`: Mark forwards and drop them when they return.
+///  `loop_id`: Identifier of the forwarder in the loop header.
+///  `hop_limit`: Most hops of a message which is forwarded.
+///  `hop_header`: Header counted as hops instead of `Received`.
+///  `dedup_table`: DynamoDB table of claimed messages.
+///  `index_table`: DynamoDB table indexing the metadata of processed mail.
+///  `bounce_table`: DynamoDB table of bounced addresses.
+///  `bounce_notify_email`: Address bounces of sent mail are reported to.
+///  `stats_email`: Address statistics reports are sent to.
+///  `dedup_ttl_hours`: Window duplicates are skipped in, in hours.
+///  `dedup_by_header`: Deduplicate by `Message-ID` header, not SES id.
+///  `send_retries`: Retries of a send throttled by SES.
+///  `send_retry_base_ms`: First backoff between throttled sends, in ms.
+///  `circuit_breaker_threshold`: Failed sends in a row opening the breaker.
+///  `circuit_breaker_cooldown_secs`: Seconds before a trial send.
+///  `quota_defer_percent`: Share of the SES quota forwards are deferred at.
+///  `quota_defer_secs`: Seconds forwards are deferred by near the quota.
+///  `failed_prefix`: Store prefix of dead-lettered events.
+///  `dead_letter_errors`: Dead-letter failed invocations, not retry them.
+///  `record_concurrency`: Records of an event handled at the same time.
+///  `config_refresh_secs`: Seconds a remote configuration is kept for.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct PrivatEmailConfig {
     /// Original Recipient Email from Verified SES Domain
@@ -31,6 +180,570 @@ pub struct PrivatEmailConfig {
     /// Black Listed email addresses
     #[serde(skip_serializing_if = "Option::is_none")]
     pub black_list: Option<Vec<String>>,
+
+    /// Attachment extensions (`.exe`) or MIME types which are never forwarded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked_attachments: Option<Vec<String>>,
+
+    /// Action taken when a message carries a blocked attachment
+    #[serde(default)]
+    pub attachment_action: AttachmentAction,
+
+    /// Directory used to persist messages and records when running locally
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_store_dir: Option<PathBuf>,
+
+    /// S3 bucket used to store quarantined and oversized messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3_bucket: Option<String>,
+
+    /// S3 bucket every received message is archived to, with its metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_bucket: Option<String>,
+
+    /// Compress messages archived to `archive_bucket` with zstd
+    #[serde(default)]
+    pub compress_archive: bool,
+
+    /// KMS key S3 encrypts messages written to `archive_bucket` and
+    /// `s3_bucket` with (SSE-KMS)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sse_kms_key_id: Option<String>,
+
+    /// KMS key messages are envelope encrypted under before they are
+    /// written to `archive_bucket`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub envelope_kms_key_id: Option<String>,
+
+    /// Days archived and quarantined messages are kept, when the retention
+    /// schedule deletes them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u32>,
+
+    /// Messages above this size are replaced by a notification with a link
+    #[serde(default = "default_max_send_size")]
+    pub max_send_size: usize,
+
+    /// Received messages above this size are quarantined without being
+    /// parsed, with a notification linking to them
+    #[serde(default = "default_max_content_size")]
+    pub max_content_size: usize,
+
+    /// Slack incoming webhook notified of forwarded mail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slack_webhook_url: Option<String>,
+
+    /// Discord webhook notified of forwarded mail, per route
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub discord_webhooks: BTreeMap<String, String>,
+
+    /// Token of the Telegram bot notifying `telegram_chat_id`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telegram_bot_token: Option<String>,
+
+    /// Telegram chat notified of forwarded mail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telegram_chat_id: Option<String>,
+
+    /// Characters of the text body previewed in Telegram messages
+    #[serde(default = "default_telegram_preview_chars")]
+    pub telegram_preview_chars: usize,
+
+    /// Routes whose mail is sent to Telegram instead of being forwarded
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub telegram_only_aliases: Vec<String>,
+
+    /// SNS topic published a failure event for every forward failing
+    /// permanently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_topic_arn: Option<String>,
+
+    /// Failed sends in a row triggering the PagerDuty or Opsgenie alert
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alert_failure_threshold: Option<u32>,
+
+    /// Routing key of the PagerDuty Events API v2 integration paged on
+    /// repeated send failures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pagerduty_routing_key: Option<String>,
+
+    /// API key of the Opsgenie integration paged on repeated send failures
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub opsgenie_api_key: Option<String>,
+
+    /// EventBridge bus, by name or ARN, put the lifecycle events of every
+    /// message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_bus: Option<String>,
+
+    /// HTTPS endpoint posted a JSON summary of every handled message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+
+    /// Secret signing the webhook payloads with HMAC-SHA256
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webhook_secret: Option<String>,
+
+    /// Lambda function rendering forwarded HTML to PNG previews
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preview_renderer: Option<String>,
+
+    /// SES client library used to send mail
+    #[serde(default)]
+    pub ses_primary: SesBackend,
+
+    /// Build requests for both SES clients and log their differences
+    #[serde(default)]
+    pub ses_dual_write: bool,
+
+    /// Send raw forwards through SESv2, raising the size limit to 40 MB
+    #[serde(default)]
+    pub ses_v2: bool,
+
+    /// SES configuration set mail is sent through, for its event
+    /// destinations
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration_set: Option<String>,
+
+    /// SES region mail is sent in, when the sending identities are not
+    /// verified in the region of the function
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ses_region: Option<String>,
+
+    /// SES region sends are retried in when the primary region is
+    /// unavailable or sending is paused there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ses_failover_region: Option<String>,
+
+    /// ARN of the identity of the failover region sends are made as, when
+    /// the sending addresses are not verified there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ses_failover_identity_arn: Option<String>,
+
+    /// Tag forwards with the alias, tenant and original sender domain, to
+    /// slice SES metrics and events by
+    #[serde(default)]
+    pub message_tags: bool,
+
+    /// SES configuration set with open and click tracking, which forwards
+    /// of tracked aliases are sent through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracking_configuration_set: Option<String>,
+
+    /// Whether forwards are tracked, keyed by the original recipient
+    /// address, its `@domain` or `*`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tracked_aliases: BTreeMap<String, bool>,
+
+    /// Look addresses up on the SES account suppression list before
+    /// sending to them
+    #[serde(default)]
+    pub check_suppression: bool,
+
+    /// Domains whose aliases mail may be composed from through send
+    /// requests
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub send_as_domains: Vec<String>,
+
+    /// Bearer token authorizing send requests over HTTP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_api_token: Option<String>,
+
+    /// Token authorizing mail posted by inbound parse webhooks over HTTP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inbound_webhook_token: Option<String>,
+
+    /// Bearer token authorizing searches of the `index_table` over HTTP
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub search_api_token: Option<String>,
+
+    /// Forward the decoded body inline or the original as an attachment
+    #[serde(default)]
+    pub forward_mode: ForwardMode,
+
+    /// Bedrock model id labelling messages before forwarding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classifier_model: Option<String>,
+
+    /// Time allowed for a classification call, in milliseconds
+    #[serde(default = "default_classifier_timeout_ms")]
+    pub classifier_timeout_ms: u64,
+
+    /// Largest number of classification calls per day
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub classifier_daily_cap: Option<u64>,
+
+    /// Messages with one of these labels are not forwarded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blocked_labels: Option<Vec<Label>>,
+
+    /// Summarize long messages in digests instead of showing the first line
+    #[serde(default)]
+    pub digest_summaries: bool,
+
+    /// Bedrock model id writing digest summaries; extractive when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary_model: Option<String>,
+
+    /// Add `X-Original-To`, `X-Original-From` and `X-Forwarded-By` headers,
+    /// sending inline forwards through the raw send path
+    #[serde(default)]
+    pub inject_headers: bool,
+
+    /// Address receiving replies which are relayed to the original sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_alias: Option<String>,
+
+    /// Secret signing relay reply addresses
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_secret: Option<String>,
+
+    /// Policy applied per class of send failure, overriding the defaults
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub failure_policies: BTreeMap<FailureClass, FailurePolicy>,
+
+    /// Verified domain of SRS rewritten return paths
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srs_domain: Option<String>,
+
+    /// Secret hashing SRS rewritten return paths
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub srs_secret: Option<String>,
+
+    /// Store archived attachments once, addressed by their SHA-256
+    #[serde(default)]
+    pub dedup_attachments: bool,
+
+    /// Domain signing raw sends with DKIM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dkim_domain: Option<String>,
+
+    /// DKIM selector of the signing key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dkim_selector: Option<String>,
+
+    /// PEM encoded DKIM private key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dkim_private_key: Option<String>,
+
+    /// Secrets Manager secret holding the PEM encoded DKIM private key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dkim_key_secret: Option<String>,
+
+    /// Display name of the forwarded `To` header, keyed by the original
+    /// recipient address, its `@domain` or `*`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub to_display_names: BTreeMap<String, String>,
+
+    /// Action taken on mail failing SPF
+    #[serde(default)]
+    pub spf_policy: VerdictPolicy,
+
+    /// Action taken on mail failing DKIM
+    #[serde(default)]
+    pub dkim_policy: VerdictPolicy,
+
+    /// Action taken on mail failing DMARC
+    #[serde(default)]
+    pub dmarc_policy: VerdictPolicy,
+
+    /// DNS blocklists the relay handing mail to SES and the sending domain
+    /// are looked up in
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dnsbl_lists: Vec<Blocklist>,
+
+    /// Total score of the blocklists naming a message from which
+    /// `dnsbl_policy` applies
+    #[serde(default = "default_dnsbl_threshold")]
+    pub dnsbl_threshold: u32,
+
+    /// Action taken on mail reaching the blocklist threshold
+    #[serde(default = "default_dnsbl_policy")]
+    pub dnsbl_policy: VerdictPolicy,
+
+    /// Send a `text/plain` alternative with inline forwards, rendered from
+    /// the HTML when the original has none
+    #[serde(default = "default_text_fallback")]
+    pub text_fallback: bool,
+
+    /// ASCII armored PGP public key forwarded mail is encrypted to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pgp_public_key: Option<String>,
+
+    /// Daily window without notifications, keyed by the original
+    /// recipient address, its `@domain` or `*`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub quiet_hours: BTreeMap<String, QuietHours>,
+
+    /// UTC offset of the quiet hours, in minutes
+    #[serde(default)]
+    pub quiet_hours_utc_offset: i32,
+
+    /// Defer forwards, not just notifications, until quiet hours end
+    #[serde(default)]
+    pub quiet_hours_defer: bool,
+
+    /// ASCII armored PGP public keys inbound signatures are verified with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pgp_keyring: Option<String>,
+
+    /// PEM encoded S/MIME certificate followed by its private key, used to
+    /// sign forwarded mail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smime_certificate: Option<String>,
+
+    /// Secrets Manager secret holding the S/MIME certificate and private
+    /// key, used when `smime_certificate` is unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smime_certificate_secret: Option<String>,
+
+    /// PEM encoded CA certificates inbound S/MIME signatures are verified
+    /// with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smime_trusted_certs: Option<String>,
+
+    /// Strip scripts, forms and dangerous attributes from forwarded HTML
+    #[serde(default)]
+    pub sanitize_html: bool,
+
+    /// Drop tracking pixels and rewrite tracking redirects in forwarded HTML
+    #[serde(default)]
+    pub strip_trackers: bool,
+
+    /// Redirectors rewritten to their destination, as `host/path?param`;
+    /// the built-in list is used when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracking_redirects: Option<Vec<String>>,
+
+    /// HTML banner prepended to forwarded bodies, with `{sender}`,
+    /// `{recipient}` and `{received}` variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner_html: Option<String>,
+
+    /// Plain text variant of the banner, with the same variables
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banner_text: Option<String>,
+
+    /// DynamoDB table holding settings per recipient address, `@domain`
+    /// or `*`, applied over this configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub config_table: Option<String>,
+
+    /// Check at startup that the configured S3 bucket and DynamoDB table
+    /// exist
+    #[serde(default)]
+    pub validate_resources: bool,
+
+    /// Prefix of forwarded subjects, e.g. `[PrivatEmail]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_prefix: Option<String>,
+
+    /// Tenants by the domain mail is received on, replacing settings of
+    /// this configuration for their mail
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tenants: BTreeMap<String, Tenant>,
+
+    /// Prefix of forwarded subjects by original recipient, `@domain` or
+    /// `*`, taking precedence over `subject_prefix`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub subject_prefixes: BTreeMap<String, String>,
+
+    /// Destination of mail from a sender address or `@domain`, including
+    /// its subdomains, replacing `to_email`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sender_routes: BTreeMap<String, String>,
+
+    /// Filtering rules evaluated in order, after the black list
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<Rule>,
+
+    /// Action taken on messages no filtering rule matches
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map"
+    )]
+    pub default_rule_action: Option<Action>,
+
+    /// UTC offset of the schedules of filtering rules, in minutes
+    #[serde(default)]
+    pub rules_utc_offset: i32,
+
+    /// Text template of the auto-reply to senders, enabling it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_reply_text: Option<String>,
+
+    /// Subject template of the auto-reply, `Auto: <subject>` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_reply_subject: Option<String>,
+
+    /// DynamoDB table tracking the senders replied to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_reply_table: Option<String>,
+
+    /// Days before a sender gets another auto-reply
+    #[serde(default = "default_auto_reply_days")]
+    pub auto_reply_days: u32,
+
+    /// Acknowledgments sent to the senders of mail to a route
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub acknowledgments: BTreeMap<String, Acknowledgment>,
+
+    /// Mark every forward with the loop header, and drop inbound mail
+    /// carrying it, which requires raw sends
+    #[serde(default = "default_loop_detection")]
+    pub loop_detection: bool,
+
+    /// Identifier in the loop header, `from_email` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub loop_id: Option<String>,
+
+    /// Refuse to forward messages with more hops than this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hop_limit: Option<usize>,
+
+    /// Header counted as hops, `Received` by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hop_header: Option<String>,
+
+    /// DynamoDB table recording forwarded messages, skipping duplicates
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_table: Option<String>,
+
+    /// DynamoDB table, keyed by `message_id`, indexing the metadata of every
+    /// processed message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index_table: Option<String>,
+
+    /// DynamoDB table recording the bounces of sent mail per address
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounce_table: Option<String>,
+
+    /// Address bounces of sent mail are reported to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bounce_notify_email: Option<String>,
+
+    /// Address the scheduled statistics reports are sent to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats_email: Option<String>,
+
+    /// Hours duplicates of a message are skipped for
+    #[serde(default = "default_dedup_ttl_hours")]
+    pub dedup_ttl_hours: u32,
+
+    /// Deduplicate by the `Message-ID` header instead of the SES id
+    #[serde(default)]
+    pub dedup_by_header: bool,
+
+    /// Retries of a send throttled by SES within the invocation
+    #[serde(default = "default_send_retries")]
+    pub send_retries: u32,
+
+    /// Backoff before the first retry, doubled on each further one
+    #[serde(default = "default_send_retry_base_ms")]
+    pub send_retry_base_ms: u64,
+
+    /// Failed sends in a row after which mail is quarantined, not sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker_threshold: Option<u32>,
+
+    /// Seconds the open circuit breaker waits before a trial send
+    #[serde(default = "default_circuit_breaker_cooldown_secs")]
+    pub circuit_breaker_cooldown_secs: u64,
+
+    /// Percentage of the 24-hour SES sending quota from which forwards are
+    /// deferred to the store, as they are while sending is paused
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota_defer_percent: Option<u32>,
+
+    /// Seconds forwards are deferred by near the sending quota
+    #[serde(default = "default_quota_defer_secs")]
+    pub quota_defer_secs: u64,
+
+    /// Store prefix dead-lettered events are written under
+    #[serde(default = "default_failed_prefix")]
+    pub failed_prefix: String,
+
+    /// Dead-letter every failed invocation instead of failing it
+    #[serde(default)]
+    pub dead_letter_errors: bool,
+
+    /// Records of a multi-record event handled at the same time
+    #[serde(default = "default_record_concurrency")]
+    pub record_concurrency: usize,
+
+    /// Seconds after which a remote configuration is loaded again, 0 to
+    /// keep the one loaded at the cold start
+    #[serde(default = "default_config_refresh_secs")]
+    pub config_refresh_secs: u64,
+}
+
+fn default_max_send_size() -> usize {
+    SES_MAX_SEND_SIZE
+}
+
+fn default_max_content_size() -> usize {
+    SES_MAX_RECEIVE_SIZE
+}
+
+fn default_dnsbl_threshold() -> u32 {
+    1
+}
+
+fn default_dnsbl_policy() -> VerdictPolicy {
+    VerdictPolicy::Tag
+}
+
+fn default_auto_reply_days() -> u32 {
+    7
+}
+
+fn default_text_fallback() -> bool {
+    true
+}
+
+fn default_loop_detection() -> bool {
+    true
+}
+
+fn default_dedup_ttl_hours() -> u32 {
+    24
+}
+
+fn default_send_retries() -> u32 {
+    3
+}
+
+fn default_send_retry_base_ms() -> u64 {
+    200
+}
+
+fn default_circuit_breaker_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_quota_defer_secs() -> u64 {
+    3600
+}
+
+fn default_record_concurrency() -> usize {
+    4
+}
+
+fn default_config_refresh_secs() -> u64 {
+    60
+}
+
+fn default_failed_prefix() -> String {
+    store::FAILED_PREFIX.to_owned()
+}
+
+fn default_telegram_preview_chars() -> usize {
+    notify::DEFAULT_TELEGRAM_PREVIEW_CHARS
+}
+
+fn default_classifier_timeout_ms() -> u64 {
+    DEFAULT_TIMEOUT_MS
 }
 
 /// Default configuration for `PrivatEmailConfig`
@@ -40,28 +753,543 @@ impl Default for PrivatEmailConfig {
             from_email: String::from("hello@nyah.dev"),
             to_email: String::from("hello@nyah.dev"),
             black_list: None,
+            blocked_attachments: None,
+            attachment_action: AttachmentAction::default(),
+            local_store_dir: None,
+            s3_bucket: None,
+            archive_bucket: None,
+            compress_archive: false,
+            sse_kms_key_id: None,
+            envelope_kms_key_id: None,
+            retention_days: None,
+            max_send_size: SES_MAX_SEND_SIZE,
+            max_content_size: SES_MAX_RECEIVE_SIZE,
+            slack_webhook_url: None,
+            discord_webhooks: BTreeMap::new(),
+            telegram_bot_token: None,
+            telegram_chat_id: None,
+            telegram_preview_chars: default_telegram_preview_chars(),
+            telegram_only_aliases: Vec::new(),
+            alert_topic_arn: None,
+            alert_failure_threshold: None,
+            pagerduty_routing_key: None,
+            opsgenie_api_key: None,
+            event_bus: None,
+            webhook_url: None,
+            webhook_secret: None,
+            preview_renderer: None,
+            ses_primary: SesBackend::default(),
+            ses_dual_write: false,
+            ses_v2: false,
+            configuration_set: None,
+            ses_region: None,
+            ses_failover_region: None,
+            ses_failover_identity_arn: None,
+            message_tags: false,
+            tracking_configuration_set: None,
+            tracked_aliases: BTreeMap::new(),
+            check_suppression: false,
+            send_as_domains: Vec::new(),
+            send_api_token: None,
+            inbound_webhook_token: None,
+            search_api_token: None,
+            forward_mode: ForwardMode::default(),
+            classifier_model: None,
+            classifier_timeout_ms: DEFAULT_TIMEOUT_MS,
+            classifier_daily_cap: None,
+            blocked_labels: None,
+            digest_summaries: false,
+            summary_model: None,
+            inject_headers: false,
+            relay_alias: None,
+            relay_secret: None,
+            failure_policies: BTreeMap::new(),
+            srs_domain: None,
+            srs_secret: None,
+            dedup_attachments: false,
+            dkim_domain: None,
+            dkim_selector: None,
+            dkim_private_key: None,
+            dkim_key_secret: None,
+            to_display_names: BTreeMap::new(),
+            spf_policy: VerdictPolicy::default(),
+            dkim_policy: VerdictPolicy::default(),
+            dmarc_policy: VerdictPolicy::default(),
+            dnsbl_lists: Vec::new(),
+            dnsbl_threshold: default_dnsbl_threshold(),
+            dnsbl_policy: default_dnsbl_policy(),
+            text_fallback: default_text_fallback(),
+            pgp_public_key: None,
+            quiet_hours: BTreeMap::new(),
+            quiet_hours_utc_offset: 0,
+            quiet_hours_defer: false,
+            pgp_keyring: None,
+            smime_certificate: None,
+            smime_certificate_secret: None,
+            smime_trusted_certs: None,
+            sanitize_html: false,
+            strip_trackers: false,
+            tracking_redirects: None,
+            banner_html: None,
+            banner_text: None,
+            config_table: None,
+            validate_resources: false,
+            subject_prefix: None,
+            tenants: BTreeMap::new(),
+            subject_prefixes: BTreeMap::new(),
+            sender_routes: BTreeMap::new(),
+            rules: Vec::new(),
+            default_rule_action: None,
+            rules_utc_offset: 0,
+            auto_reply_text: None,
+            auto_reply_subject: None,
+            auto_reply_table: None,
+            auto_reply_days: default_auto_reply_days(),
+            acknowledgments: BTreeMap::new(),
+            loop_detection: default_loop_detection(),
+            loop_id: None,
+            hop_limit: None,
+            hop_header: None,
+            dedup_table: None,
+            index_table: None,
+            bounce_table: None,
+            bounce_notify_email: None,
+            stats_email: None,
+            dedup_ttl_hours: default_dedup_ttl_hours(),
+            dedup_by_header: false,
+            send_retries: default_send_retries(),
+            send_retry_base_ms: default_send_retry_base_ms(),
+            circuit_breaker_threshold: None,
+            circuit_breaker_cooldown_secs:
+                default_circuit_breaker_cooldown_secs(),
+            quota_defer_percent: None,
+            quota_defer_secs: default_quota_defer_secs(),
+            failed_prefix: default_failed_prefix(),
+            dead_letter_errors: false,
+            record_concurrency: default_record_concurrency(),
+            config_refresh_secs: default_config_refresh_secs(),
         }
     }
 }
 
 /// Create a new `PrivatEmailConfig` client struct from environment variables.
 impl PrivatEmailConfig {
-    /// Create new PrivatEmailConfig struct from environment variables.
-    pub fn new_from_env() -> Self {
+    /// Loads the configuration file named by `CONFIG_S3_URI`, or else
+    /// `privatemail.toml` in the working directory with environment
+    /// overrides, or else reads the configuration from environment
+    /// variables. Secret references are left unresolved, so the result is
+    /// only validated by [`PrivatEmailConfig::resolve`].
+    pub async fn load() -> Result<Self, Error> {
+        if let Some(uri) = env_string(remote::CONFIG_S3_URI) {
+            return remote::load(&uri).await;
+        }
+        let file = Path::new(local::CONFIG_FILE);
+        Ok(if file.is_file() {
+            local::load(file)?
+        } else {
+            Self::new_from_env()?
+        })
+    }
+
+    /// Returns the configuration with its secret references replaced by
+    /// their values from `provider`, and validates it, so settings held in
+    /// secrets are checked too.
+    pub async fn resolve(
+        self,
+        provider: &dyn secrets::SecretsProvider,
+    ) -> Result<Self, Error> {
+        let config = secrets::resolve(self, provider).await?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings that would otherwise only fail when mail
+    /// arrives, reporting every problem found.
+    pub fn validate(&self) -> Result<(), Error> {
+        report(validate::problems(self))
+    }
+
+    /// Checks that the S3 bucket and DynamoDB table of the configuration
+    /// exist and can be accessed.
+    pub async fn check_resources(&self) -> Result<(), Error> {
+        report(validate::missing_resources(self).await)
+    }
+
+    /// Create new PrivatEmailConfig struct from environment variables,
+    /// reporting every variable whose value is invalid.
+    pub fn new_from_env() -> Result<Self, Error> {
+        let mut problems = Vec::new();
         let b_list = env::var("BLACK_LIST").unwrap_or_default();
         let black_list =
             b_list.split(',').map(|x| x.replace(' ', "")).collect();
 
-        PrivatEmailConfig {
-            from_email: env::var("FROM_EMAIL")
-                .unwrap_or_else(|_e| panic!("Invalid FROM_EMAIL")),
-            to_email: env::var("TO_EMAIL")
-                .unwrap_or_else(|_e| panic!("Invalid TO_EMAIL")),
+        let config = PrivatEmailConfig {
+            from_email: env::var("FROM_EMAIL").unwrap_or_default(),
+            to_email: env::var("TO_EMAIL").unwrap_or_default(),
             black_list: Some(black_list),
+            blocked_attachments: env_list("BLOCKED_ATTACHMENTS").map(|list| {
+                if list == ["default"] {
+                    DEFAULT_BLOCKLIST.iter().map(|e| e.to_string()).collect()
+                } else {
+                    list
+                }
+            }),
+            attachment_action: env_parse("ATTACHMENT_ACTION", &mut problems)
+                .unwrap_or_default(),
+            local_store_dir: env::var("LOCAL_STORE_DIR")
+                .ok()
+                .map(PathBuf::from),
+            s3_bucket: env_string("S3_BUCKET"),
+            archive_bucket: env_string("ARCHIVE_BUCKET"),
+            compress_archive: env_bool("COMPRESS_ARCHIVE"),
+            sse_kms_key_id: env_string("SSE_KMS_KEY_ID"),
+            envelope_kms_key_id: env_string("ENVELOPE_KMS_KEY_ID"),
+            retention_days: env_parse("RETENTION_DAYS", &mut problems),
+            max_send_size: env_parse("MAX_SEND_SIZE", &mut problems).unwrap_or(
+                if env_bool("SES_V2") {
+                    SESV2_MAX_SEND_SIZE
+                } else {
+                    SES_MAX_SEND_SIZE
+                },
+            ),
+            max_content_size: env_parse("MAX_CONTENT_SIZE", &mut problems)
+                .unwrap_or(SES_MAX_RECEIVE_SIZE),
+            slack_webhook_url: env_string("SLACK_WEBHOOK_URL"),
+            discord_webhooks: env_list_parse(
+                "DISCORD_WEBHOOKS",
+                notify::parse_discord_webhook,
+                &mut problems,
+            ),
+            telegram_bot_token: env_string("TELEGRAM_BOT_TOKEN"),
+            telegram_chat_id: env_string("TELEGRAM_CHAT_ID"),
+            telegram_preview_chars: env_parse(
+                "TELEGRAM_PREVIEW_CHARS",
+                &mut problems,
+            )
+            .unwrap_or_else(default_telegram_preview_chars),
+            telegram_only_aliases: env_list("TELEGRAM_ONLY_ALIASES")
+                .unwrap_or_default(),
+            alert_topic_arn: env_string("ALERT_TOPIC_ARN"),
+            alert_failure_threshold: env_parse(
+                "ALERT_FAILURE_THRESHOLD",
+                &mut problems,
+            ),
+            pagerduty_routing_key: env_string("PAGERDUTY_ROUTING_KEY"),
+            opsgenie_api_key: env_string("OPSGENIE_API_KEY"),
+            event_bus: env_string("EVENT_BUS"),
+            webhook_url: env_string("WEBHOOK_URL"),
+            webhook_secret: env_string("WEBHOOK_SECRET"),
+            preview_renderer: env_string("PREVIEW_RENDERER"),
+            ses_primary: env_parse("SES_PRIMARY", &mut problems)
+                .unwrap_or_default(),
+            ses_dual_write: env_bool("SES_DUAL_WRITE"),
+            ses_v2: env_bool("SES_V2"),
+            configuration_set: env_string("CONFIGURATION_SET"),
+            ses_region: env_string("SES_REGION"),
+            ses_failover_region: env_string("SES_FAILOVER_REGION"),
+            ses_failover_identity_arn: env_string("SES_FAILOVER_IDENTITY_ARN"),
+            message_tags: env_bool("MESSAGE_TAGS"),
+            tracking_configuration_set: env_string(
+                "TRACKING_CONFIGURATION_SET",
+            ),
+            check_suppression: env_bool("CHECK_SUPPRESSION"),
+            send_as_domains: env_list("SEND_AS_DOMAINS").unwrap_or_default(),
+            send_api_token: env_string("SEND_API_TOKEN"),
+            inbound_webhook_token: env_string("INBOUND_WEBHOOK_TOKEN"),
+            search_api_token: env_string("SEARCH_API_TOKEN"),
+            tracked_aliases: env_list_parse(
+                "TRACKED_ALIASES",
+                tracking::parse_tracked_alias,
+                &mut problems,
+            ),
+            forward_mode: env_parse("FORWARD_MODE", &mut problems)
+                .unwrap_or_default(),
+            classifier_model: env_string("CLASSIFIER_MODEL"),
+            classifier_timeout_ms: env_parse(
+                "CLASSIFIER_TIMEOUT_MS",
+                &mut problems,
+            )
+            .unwrap_or(DEFAULT_TIMEOUT_MS),
+            classifier_daily_cap: env_parse(
+                "CLASSIFIER_DAILY_CAP",
+                &mut problems,
+            ),
+            blocked_labels: env_list("BLOCKED_LABELS").map(|list| {
+                list.iter()
+                    .filter_map(|l| {
+                        checked("BLOCKED_LABELS", l.parse(), &mut problems)
+                    })
+                    .collect()
+            }),
+            digest_summaries: env_bool("DIGEST_SUMMARIES"),
+            summary_model: env_string("SUMMARY_MODEL"),
+            inject_headers: env_bool("INJECT_HEADERS"),
+            relay_alias: env_string("RELAY_ALIAS"),
+            relay_secret: env_string("RELAY_SECRET"),
+            failure_policies: env_list_parse(
+                "FAILURE_POLICIES",
+                failure::parse_policy,
+                &mut problems,
+            ),
+            srs_domain: env_string("SRS_DOMAIN"),
+            srs_secret: env_string("SRS_SECRET"),
+            dedup_attachments: env_bool("DEDUP_ATTACHMENTS"),
+            dkim_domain: env_string("DKIM_DOMAIN"),
+            dkim_selector: env_string("DKIM_SELECTOR"),
+            dkim_private_key: env_string("DKIM_PRIVATE_KEY"),
+            dkim_key_secret: env_string("DKIM_KEY_SECRET"),
+            to_display_names: env_list_parse(
+                "TO_DISPLAY_NAMES",
+                forward::parse_display_name,
+                &mut problems,
+            ),
+            spf_policy: env_verdict_policy("SPF_POLICY", &mut problems),
+            dkim_policy: env_verdict_policy("DKIM_POLICY", &mut problems),
+            dmarc_policy: env_verdict_policy("DMARC_POLICY", &mut problems),
+            dnsbl_lists: env_list_parse(
+                "DNSBL_LISTS",
+                |l| l.parse(),
+                &mut problems,
+            ),
+            dnsbl_threshold: env_parse("DNSBL_THRESHOLD", &mut problems)
+                .unwrap_or_else(default_dnsbl_threshold),
+            dnsbl_policy: env_parse("DNSBL_POLICY", &mut problems)
+                .unwrap_or_else(default_dnsbl_policy),
+            text_fallback: env::var("TEXT_FALLBACK").is_err()
+                || env_bool("TEXT_FALLBACK"),
+            pgp_public_key: env_string("PGP_PUBLIC_KEY"),
+            quiet_hours: env_list_parse(
+                "QUIET_HOURS",
+                quiet::parse_route,
+                &mut problems,
+            ),
+            quiet_hours_utc_offset: env_offset(
+                "QUIET_HOURS_UTC_OFFSET",
+                &mut problems,
+            ),
+            quiet_hours_defer: env_bool("QUIET_HOURS_DEFER"),
+            pgp_keyring: env_string("PGP_KEYRING"),
+            smime_certificate: env_string("SMIME_CERTIFICATE"),
+            smime_certificate_secret: env_string("SMIME_CERTIFICATE_SECRET"),
+            smime_trusted_certs: env_string("SMIME_TRUSTED_CERTS"),
+            sanitize_html: env_bool("SANITIZE_HTML"),
+            strip_trackers: env_bool("STRIP_TRACKERS"),
+            tracking_redirects: env_list("TRACKING_REDIRECTS"),
+            banner_html: env_string("BANNER_HTML"),
+            banner_text: env_string("BANNER_TEXT"),
+            config_table: env_string("CONFIG_TABLE"),
+            validate_resources: env_bool("VALIDATE_RESOURCES"),
+            subject_prefix: env_string("SUBJECT_PREFIX"),
+            tenants: env_json("TENANTS", &mut problems).unwrap_or_default(),
+            subject_prefixes: env_list_parse(
+                "SUBJECT_PREFIXES",
+                forward::parse_subject_prefix,
+                &mut problems,
+            ),
+            sender_routes: env_list_parse(
+                "SENDER_ROUTES",
+                forward::parse_sender_route,
+                &mut problems,
+            ),
+            rules: env_json("RULES", &mut problems).unwrap_or_default(),
+            default_rule_action: env_parse(
+                "DEFAULT_RULE_ACTION",
+                &mut problems,
+            ),
+            rules_utc_offset: env_offset("RULES_UTC_OFFSET", &mut problems),
+            auto_reply_text: env_string("AUTO_REPLY_TEXT"),
+            auto_reply_subject: env_string("AUTO_REPLY_SUBJECT"),
+            auto_reply_table: env_string("AUTO_REPLY_TABLE"),
+            auto_reply_days: env_parse("AUTO_REPLY_DAYS", &mut problems)
+                .unwrap_or_else(default_auto_reply_days),
+            acknowledgments: env_json::<BTreeMap<String, Acknowledgment>>(
+                "ACKNOWLEDGMENTS",
+                &mut problems,
+            )
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(route, a)| (route.to_lowercase(), a))
+            .collect(),
+            loop_detection: env::var("LOOP_DETECTION").is_err()
+                || env_bool("LOOP_DETECTION"),
+            loop_id: env_string("LOOP_ID"),
+            hop_limit: env_parse("HOP_LIMIT", &mut problems),
+            hop_header: env_string("HOP_HEADER"),
+            dedup_table: env_string("DEDUP_TABLE"),
+            index_table: env_string("INDEX_TABLE"),
+            bounce_table: env_string("BOUNCE_TABLE"),
+            bounce_notify_email: env_string("BOUNCE_NOTIFY_EMAIL"),
+            stats_email: env_string("STATS_EMAIL"),
+            dedup_ttl_hours: env_parse("DEDUP_TTL_HOURS", &mut problems)
+                .unwrap_or_else(default_dedup_ttl_hours),
+            dedup_by_header: env_bool("DEDUP_BY_HEADER"),
+            send_retries: env_parse("SEND_RETRIES", &mut problems)
+                .unwrap_or_else(default_send_retries),
+            send_retry_base_ms: env_parse("SEND_RETRY_BASE_MS", &mut problems)
+                .unwrap_or_else(default_send_retry_base_ms),
+            circuit_breaker_threshold: env_parse(
+                "CIRCUIT_BREAKER_THRESHOLD",
+                &mut problems,
+            ),
+            circuit_breaker_cooldown_secs: env_parse(
+                "CIRCUIT_BREAKER_COOLDOWN_SECS",
+                &mut problems,
+            )
+            .unwrap_or_else(default_circuit_breaker_cooldown_secs),
+            quota_defer_percent: env_parse(
+                "QUOTA_DEFER_PERCENT",
+                &mut problems,
+            ),
+            quota_defer_secs: env_parse("QUOTA_DEFER_SECS", &mut problems)
+                .unwrap_or_else(default_quota_defer_secs),
+            failed_prefix: env_string("FAILED_PREFIX")
+                .unwrap_or_else(default_failed_prefix),
+            dead_letter_errors: env_bool("DEAD_LETTER_ERRORS"),
+            record_concurrency: env_parse("RECORD_CONCURRENCY", &mut problems)
+                .unwrap_or_else(default_record_concurrency),
+            config_refresh_secs: env_parse(
+                "CONFIG_REFRESH_SECS",
+                &mut problems,
+            )
+            .unwrap_or_else(default_config_refresh_secs),
+        };
+        report(problems)?;
+        Ok(config)
+    }
+
+    /// Returns the action taken on mail failing `check`.
+    pub fn verdict_policy(&self, check: AuthCheck) -> VerdictPolicy {
+        match check {
+            AuthCheck::Spf => self.spf_policy,
+            AuthCheck::Dkim => self.dkim_policy,
+            AuthCheck::Dmarc => self.dmarc_policy,
         }
     }
 
+    /// Returns the policy applied to send failures of `class`.
+    pub fn failure_policy(&self, class: FailureClass) -> FailurePolicy {
+        self.failure_policies
+            .get(&class)
+            .copied()
+            .unwrap_or_else(|| class.default_policy())
+    }
+
+    /// Returns the SES configuration set forwards of mail sent to
+    /// `recipients` go through: the tracking one when the first matching
+    /// route is tracked.
+    pub fn configuration_set_for(&self, recipients: &[String]) -> Option<&str> {
+        match route(&self.tracked_aliases, recipients) {
+            Some(true) => self.tracking_configuration_set.as_deref(),
+            _ => self.configuration_set.as_deref(),
+        }
+    }
+
+    /// Returns the SES region mail is sent in: `ses_region`, or the region
+    /// of the function when unset or invalid.
+    pub fn sending_region(&self) -> Region {
+        self.ses_region
+            .as_deref()
+            .and_then(|region| region.parse().ok())
+            .unwrap_or_default()
+    }
+
+    /// Returns the Discord webhook notified of mail sent to `recipients`,
+    /// from the first matching route.
+    pub fn discord_webhook_for(&self, recipients: &[String]) -> Option<&str> {
+        route(&self.discord_webhooks, recipients).map(String::as_str)
+    }
+
+    /// Returns whether mail sent to `recipients` goes to Telegram instead
+    /// of being forwarded, by the first matching route.
+    pub fn telegram_only(&self, recipients: &[String]) -> bool {
+        let routes: BTreeMap<String, ()> = self
+            .telegram_only_aliases
+            .iter()
+            .map(|r| (r.trim().to_lowercase(), ()))
+            .collect();
+        route(&routes, recipients).is_some()
+    }
+
+    /// Returns the `To` address of a forward of mail sent to `recipients`,
+    /// with the display name of the first matching route.
+    pub fn to_address(&self, recipients: &[String]) -> String {
+        match route(&self.to_display_names, recipients) {
+            Some(name) => forward::display_address(name, &self.to_email),
+            None => self.to_email.clone(),
+        }
+    }
+
+    /// Returns the subject prefix of forwards of mail sent to `recipients`,
+    /// from the first matching route or else `subject_prefix`.
+    pub fn subject_prefix_for(&self, recipients: &[String]) -> Option<&str> {
+        route(&self.subject_prefixes, recipients)
+            .or(self.subject_prefix.as_ref())
+            .map(String::as_str)
+    }
+
+    /// Returns the acknowledgment of mail sent to `recipients`, from the
+    /// first matching route.
+    pub fn acknowledgment_for(
+        &self,
+        recipients: &[String],
+    ) -> Option<&Acknowledgment> {
+        route(&self.acknowledgments, recipients)
+    }
+
+    /// Returns the identifier of this forwarder in the loop header.
+    pub fn loop_identifier(&self) -> &str {
+        self.loop_id.as_deref().unwrap_or(&self.from_email)
+    }
+
+    /// Returns the filtering rules, led by rules dropping mail from the
+    /// black listed senders.
+    pub fn filter_rules(&self) -> Vec<Rule> {
+        self.black_list
+            .iter()
+            .flatten()
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| Rule::blocked_sender(entry))
+            .chain(self.rules.iter().cloned())
+            .collect()
+    }
+
+    /// Returns the configuration with the destination of the first sender
+    /// route matching one of `senders`. Sender routes win over the
+    /// recipient routing applied before.
+    pub fn for_sender(mut self, senders: &[String]) -> Self {
+        if let Some(to_email) = sender_route(&self.sender_routes, senders) {
+            self.to_email = to_email.clone();
+        }
+        self
+    }
+
+    /// Returns the configuration of the tenant receiving mail sent to
+    /// `recipients`, by the domain of the first recipient.
+    pub fn for_tenant(mut self, recipients: &[String]) -> Self {
+        let tenant = recipients
+            .first()
+            .and_then(|recipient| tenant::find(&self.tenants, recipient))
+            .cloned();
+        if let Some(tenant) = tenant {
+            tenant.apply(&mut self);
+        }
+        self
+    }
+
+    /// Returns the time the quiet hours of the route of `recipients` end,
+    /// if `now` is within them.
+    pub fn quiet_until(&self, recipients: &[String], now: i64) -> Option<i64> {
+        route(&self.quiet_hours, recipients)?
+            .until(now, self.quiet_hours_utc_offset)
+    }
+
+    /// Returns a builder starting from the default configuration.
+    pub fn builder() -> PrivatEmailConfigBuilder {
+        PrivatEmailConfigBuilder::default()
+    }
+
     /// Create a new `PrivatEmailConfig` struct
+    #[deprecated(note = "use `PrivatEmailConfig::builder` instead")]
     pub fn new<F, T, B>(from_email: F, to_email: T, black_list: B) -> Self
     where
         F: ToString,
@@ -75,10 +1303,151 @@ impl PrivatEmailConfig {
             from_email: from_email.to_string(),
             to_email: to_email.to_string(),
             black_list: Some(b_list),
+            ..Default::default()
         }
     }
 }
 
+/// Returns the value of the first route in `routes` matching one of
+/// `recipients`, by address, then `@domain`, then `*`.
+fn route<'a, T>(
+    routes: &'a BTreeMap<String, T>,
+    recipients: &[String],
+) -> Option<&'a T> {
+    recipients
+        .iter()
+        .find_map(|r| {
+            let r = r.to_lowercase();
+            let domain = r.rsplit_once('@').map(|(_, d)| format!("@{}", d));
+            routes.get(&r).or_else(|| domain.and_then(|d| routes.get(&d)))
+        })
+        .or_else(|| routes.get("*"))
+}
+
+/// Turns the `problems` found in the configuration into one error.
+fn report(problems: Vec<String>) -> Result<(), Error> {
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Invalid configuration:\n  - {}", problems.join("\n  - "))
+            .into())
+    }
+}
+
+/// Returns the value of the first route in `routes` matching one of
+/// `senders`, by address, then `@domain`, then the parent domains.
+fn sender_route<'a>(
+    routes: &'a BTreeMap<String, String>,
+    senders: &[String],
+) -> Option<&'a String> {
+    senders.iter().find_map(|sender| {
+        let sender = sender.trim().to_lowercase();
+        let address = match sender.rsplit_once('<') {
+            Some((_, rest)) => rest.trim_end_matches('>'),
+            None => &sender,
+        };
+        let (_, mut domain) = address.rsplit_once('@')?;
+        routes.get(address).or_else(|| loop {
+            if let Some(destination) = routes.get(&format!("@{}", domain)) {
+                break Some(destination);
+            }
+            domain = domain.split_once('.')?.1;
+        })
+    })
+}
+
+/// Reads the environment variable `key`, returning `None` when it is
+/// unset or empty.
+fn env_string(key: &str) -> Option<String> {
+    env::var(key).ok().filter(|v| !v.trim().is_empty())
+}
+
+/// Reads a boolean flag from the environment variable `key`; `true`, `1`
+/// and `yes` enable it.
+fn env_bool(key: &str) -> bool {
+    env::var(key).is_ok_and(|v| {
+        matches!(v.trim().to_lowercase().as_str(), "true" | "1" | "yes")
+    })
+}
+
+/// Reads a verdict policy from the environment variable `key`, defaulting
+/// to forwarding.
+fn env_verdict_policy(key: &str, problems: &mut Vec<String>) -> VerdictPolicy {
+    env_parse(key, problems).unwrap_or_default()
+}
+
+/// Parses the environment variable `key`, returning `None` when it is
+/// unset or empty, and adding a problem to `problems` when it is invalid.
+fn env_parse<T>(key: &str, problems: &mut Vec<String>) -> Option<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    env_string(key).and_then(|v| checked(key, v.trim().parse(), problems))
+}
+
+/// Parses the JSON of the environment variable `key`, like [`env_parse`].
+fn env_json<T: DeserializeOwned>(
+    key: &str,
+    problems: &mut Vec<String>,
+) -> Option<T> {
+    env_string(key)
+        .and_then(|v| checked(key, serde_json::from_str(&v), problems))
+}
+
+/// Parses the UTC offset of the environment variable `key`, like
+/// [`env_parse`], defaulting to UTC.
+fn env_offset(key: &str, problems: &mut Vec<String>) -> i32 {
+    env_string(key)
+        .and_then(|o| checked(key, quiet::parse_offset(&o), problems))
+        .unwrap_or_default()
+}
+
+/// Parses each entry of the comma separated list of the environment
+/// variable `key` with `parse`, skipping and adding a problem to
+/// `problems` for each invalid one.
+fn env_list_parse<T, C, E>(
+    key: &str,
+    parse: impl Fn(&str) -> Result<T, E>,
+    problems: &mut Vec<String>,
+) -> C
+where
+    C: FromIterator<T>,
+    E: Display,
+{
+    env_list(key)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|entry| checked(key, parse(entry), problems))
+        .collect()
+}
+
+/// Returns the value of `result`, adding its error to `problems`, named
+/// after the environment variable `key`, when it failed.
+fn checked<T, E: Display>(
+    key: &str,
+    result: Result<T, E>,
+    problems: &mut Vec<String>,
+) -> Option<T> {
+    result.map_err(|e| problems.push(format!("{}: {}", key, e))).ok()
+}
+
+/// Reads a comma separated list from the environment variable `key`,
+/// returning `None` when the variable is unset or empty.
+fn env_list(key: &str) -> Option<Vec<String>> {
+    let value = env::var(key).ok()?;
+    let list: Vec<String> = value
+        .split(',')
+        .map(|x| x.trim().to_string())
+        .filter(|x| !x.is_empty())
+        .collect();
+    if list.is_empty() {
+        None
+    } else {
+        Some(list)
+    }
+}
+
 /** Test module for PrivatEmailConfig struct */
 #[cfg(test)]
 mod tests {
@@ -86,6 +1455,7 @@ mod tests {
     use std::env;
 
     #[test]
+    #[allow(deprecated)]
     fn test_new_privatemail_config() {
         let new_config = PrivatEmailConfig::new(
             String::from("test_from"),
@@ -105,6 +1475,7 @@ mod tests {
         assert!(new_config.from_email.contains("hello@nyah.dev"));
         assert!(new_config.to_email.contains("hello@nyah.dev"));
         assert!(new_config.black_list.is_none());
+        assert!(new_config.text_fallback);
     }
 
     #[test]
@@ -112,9 +1483,170 @@ mod tests {
         env::set_var("FROM_EMAIL", "test_from");
         env::set_var("TO_EMAIL", "test_to");
 
-        let new_config = PrivatEmailConfig::new_from_env();
+        let new_config = PrivatEmailConfig::new_from_env().unwrap();
         assert!(new_config.from_email.contains("test_from"));
         assert!(new_config.to_email.contains("test_to"));
         assert_eq!(new_config.black_list.unwrap(), [""]);
     }
+
+    #[test]
+    fn test_env_problems() {
+        env::set_var("TEST_ENV_DAYS", "ten");
+        env::set_var("TEST_ENV_ROUTES", "a@b.io=c@d.io, nowhere");
+        let mut problems = Vec::new();
+        assert_eq!(env_parse::<u32>("TEST_ENV_DAYS", &mut problems), None);
+        let routes: Vec<(String, String)> = env_list_parse(
+            "TEST_ENV_ROUTES",
+            forward::parse_sender_route,
+            &mut problems,
+        );
+        assert_eq!(routes.len(), 1);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("TEST_ENV_DAYS: "));
+        assert!(problems[1].starts_with("TEST_ENV_ROUTES: "));
+        let error = report(problems).unwrap_err().to_string();
+        assert!(error.starts_with("Invalid configuration:"));
+    }
+
+    #[test]
+    fn test_to_address() {
+        let mut config = PrivatEmailConfig::default();
+        let recipients = ["Support@Acme.com".to_owned()];
+        assert_eq!(config.to_address(&recipients), "hello@nyah.dev");
+
+        config.to_display_names = [
+            ("*", "PrivatEmail"),
+            ("@acme.com", "Acme"),
+            ("support@acme.com", "Acme Support (via PrivatEmail)"),
+        ]
+        .into_iter()
+        .map(|(route, name)| (route.to_owned(), name.to_owned()))
+        .collect();
+        assert_eq!(
+            config.to_address(&recipients),
+            "\"Acme Support (via PrivatEmail)\" <hello@nyah.dev>"
+        );
+        assert_eq!(
+            config.to_address(&["sales@acme.com".to_owned()]),
+            "\"Acme\" <hello@nyah.dev>"
+        );
+        assert_eq!(
+            config.to_address(&["me@nyah.dev".to_owned()]),
+            "\"PrivatEmail\" <hello@nyah.dev>"
+        );
+    }
+
+    #[test]
+    fn test_subject_prefix_for() {
+        let config = PrivatEmailConfig::builder()
+            .subject_prefix("[PrivatEmail]")
+            .subject_prefix_route("Jobs@fufu.soup", "[Jobs]")
+            .subject_prefix_route("@eru.soup", "[Eru]")
+            .build();
+        let prefix = |recipient: &str| {
+            config
+                .subject_prefix_for(&[recipient.to_owned()])
+                .map(str::to_owned)
+        };
+        assert_eq!(prefix("jobs@Fufu.soup").as_deref(), Some("[Jobs]"));
+        assert_eq!(prefix("hr@eru.soup").as_deref(), Some("[Eru]"));
+        assert_eq!(prefix("me@fufu.soup").as_deref(), Some("[PrivatEmail]"));
+    }
+
+    #[test]
+    fn test_configuration_set_for() {
+        let config = PrivatEmailConfig::builder()
+            .configuration_set("forwards")
+            .tracking_configuration_set("tracked")
+            .tracked_alias("@fufu.soup", true)
+            .tracked_alias("Private@fufu.soup", false)
+            .build();
+        let set = |recipient: &str| {
+            config
+                .configuration_set_for(&[recipient.to_owned()])
+                .map(str::to_owned)
+        };
+        assert_eq!(set("jobs@fufu.soup").as_deref(), Some("tracked"));
+        assert_eq!(set("private@fufu.soup").as_deref(), Some("forwards"));
+        assert_eq!(set("jobs@eru.soup").as_deref(), Some("forwards"));
+    }
+
+    #[test]
+    fn test_sending_region() {
+        let config =
+            PrivatEmailConfig::builder().ses_region("eu-west-1").build();
+        assert_eq!(config.sending_region(), Region::EuWest1);
+        let config = PrivatEmailConfig::builder().build();
+        assert_eq!(config.sending_region(), Region::default());
+    }
+
+    #[test]
+    fn test_chat_routes() {
+        let config = PrivatEmailConfig::builder()
+            .discord_webhook("@Fufu.soup", "https://discord.com/api/webhooks/1")
+            .telegram("123:abc", "-10042")
+            .telegram_only_aliases(["News@eru.soup"])
+            .build();
+        let recipients = |r: &str| vec![r.to_owned()];
+        assert_eq!(
+            config.discord_webhook_for(&recipients("jobs@fufu.soup")),
+            Some("https://discord.com/api/webhooks/1")
+        );
+        assert_eq!(
+            config.discord_webhook_for(&recipients("news@eru.soup")),
+            None
+        );
+        assert!(config.telegram_only(&recipients("news@eru.soup")));
+        assert!(!config.telegram_only(&recipients("jobs@fufu.soup")));
+    }
+
+    #[test]
+    fn test_for_sender() {
+        let config = PrivatEmailConfig::builder()
+            .sender_route("@Bank.example", "finance@nyah.dev")
+            .sender_route("boss@work.example", "work@nyah.dev")
+            .build();
+        let to_email = |senders: &[&str]| {
+            let senders: Vec<String> =
+                senders.iter().map(|s| s.to_string()).collect();
+            config.clone().for_sender(&senders).to_email
+        };
+        assert_eq!(
+            to_email(&["Bank <Alerts@mail.Bank.example>"]),
+            "finance@nyah.dev"
+        );
+        assert_eq!(
+            to_email(&["news@work.example", "boss@work.example"]),
+            "work@nyah.dev"
+        );
+        assert_eq!(to_email(&["me@fufu.soup"]), "hello@nyah.dev");
+        assert_eq!(to_email(&["bank.example"]), "hello@nyah.dev");
+    }
+
+    #[test]
+    fn test_quiet_until() {
+        let mut config = PrivatEmailConfig::default();
+        config
+            .quiet_hours
+            .insert("@news.test".to_owned(), "22:00-07:00".parse().unwrap());
+        config.quiet_hours_utc_offset = 60;
+
+        // 2021-03-19 21:30 UTC is 22:30 at UTC+01:00
+        let now = 1616189400;
+        let news = ["weekly@news.test".to_owned()];
+        assert_eq!(config.quiet_until(&news, now), Some(now + 8 * 3600 + 1800));
+        assert_eq!(config.quiet_until(&["me@nyah.dev".to_owned()], now), None);
+    }
+
+    #[test]
+    fn test_env_list() {
+        env::set_var("TEST_ENV_LIST", " .exe, ,application/javascript ");
+        assert_eq!(
+            env_list("TEST_ENV_LIST").unwrap(),
+            [".exe", "application/javascript"]
+        );
+        env::set_var("TEST_ENV_LIST", " , ");
+        assert!(env_list("TEST_ENV_LIST").is_none());
+        assert!(env_list("TEST_ENV_LIST_UNSET").is_none());
+    }
 }
@@ -0,0 +1,848 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Builder for [`PrivatEmailConfig`].
+//!
+//! Starts from [`PrivatEmailConfig::default`] and takes typed values:
+//! lists as iterators, routes one at a time and timeouts as durations.
+use super::{PrivatEmailConfig, Tenant};
+use crate::attachments::AttachmentAction;
+use crate::auth::VerdictPolicy;
+use crate::autoreply::Acknowledgment;
+use crate::classify::Label;
+use crate::dnsbl::Blocklist;
+use crate::failure::{FailureClass, FailurePolicy};
+use crate::forward::ForwardMode;
+use crate::migration::SesBackend;
+use crate::quiet::QuietHours;
+use crate::rules::{Action, Rule};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Builds a [`PrivatEmailConfig`], see [`PrivatEmailConfig::builder`].
+#[derive(Clone, Debug, Default)]
+pub struct PrivatEmailConfigBuilder {
+    config: PrivatEmailConfig,
+}
+
+/// Collects `items` into a list of strings.
+fn list<I>(items: I) -> Vec<String>
+where
+    I: IntoIterator,
+    I::Item: Into<String>,
+{
+    items.into_iter().map(Into::into).collect()
+}
+
+impl PrivatEmailConfigBuilder {
+    /// Sets the verified SES address forwards are sent from.
+    pub fn from_email(mut self, address: impl Into<String>) -> Self {
+        self.config.from_email = address.into();
+        self
+    }
+
+    /// Sets the address receiving forwards.
+    pub fn to_email(mut self, address: impl Into<String>) -> Self {
+        self.config.to_email = address.into();
+        self
+    }
+
+    /// Sets the blocked sender addresses and domains.
+    pub fn black_list<I>(mut self, senders: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.config.black_list = Some(list(senders));
+        self
+    }
+
+    /// Sets the banned attachment extensions or MIME types.
+    pub fn blocked_attachments<I>(mut self, attachments: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.config.blocked_attachments = Some(list(attachments));
+        self
+    }
+
+    /// Sets what happens to mail with blocked attachments.
+    pub fn attachment_action(mut self, action: AttachmentAction) -> Self {
+        self.config.attachment_action = action;
+        self
+    }
+
+    /// Sets the directory of the local archive, quarantine and database.
+    pub fn local_store_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.local_store_dir = Some(dir.into());
+        self
+    }
+
+    /// Sets the bucket every received message is archived to.
+    pub fn archive_bucket(mut self, bucket: impl Into<String>) -> Self {
+        self.config.archive_bucket = Some(bucket.into());
+        self
+    }
+
+    /// Compresses archived messages with zstd.
+    pub fn compress_archive(mut self, enabled: bool) -> Self {
+        self.config.compress_archive = enabled;
+        self
+    }
+
+    /// Sets the KMS key S3 encrypts stored messages with.
+    pub fn sse_kms_key_id(mut self, key_id: impl Into<String>) -> Self {
+        self.config.sse_kms_key_id = Some(key_id.into());
+        self
+    }
+
+    /// Sets the KMS key archived messages are envelope encrypted under.
+    pub fn envelope_kms_key_id(mut self, key_id: impl Into<String>) -> Self {
+        self.config.envelope_kms_key_id = Some(key_id.into());
+        self
+    }
+
+    /// Keeps archived and quarantined messages for `days`.
+    pub fn retention_days(mut self, days: u32) -> Self {
+        self.config.retention_days = Some(days);
+        self
+    }
+
+    /// Sets the largest message size handed to SES, in bytes.
+    pub fn max_send_size(mut self, bytes: usize) -> Self {
+        self.config.max_send_size = bytes;
+        self
+    }
+
+    /// Sets the largest received message parsed, in bytes.
+    pub fn max_content_size(mut self, bytes: usize) -> Self {
+        self.config.max_content_size = bytes;
+        self
+    }
+
+    /// Sets the Slack incoming webhook notified of forwarded mail.
+    pub fn slack_webhook_url(mut self, url: impl Into<String>) -> Self {
+        self.config.slack_webhook_url = Some(url.into());
+        self
+    }
+
+    /// Notifies the Discord webhook `url` of forwards of mail sent to
+    /// `route`, an address, `@domain` or `*`.
+    pub fn discord_webhook(
+        mut self,
+        route: impl Into<String>,
+        url: impl Into<String>,
+    ) -> Self {
+        self.config
+            .discord_webhooks
+            .insert(route.into().to_lowercase(), url.into());
+        self
+    }
+
+    /// Notifies the Telegram chat `chat_id` through the bot of `bot_token`.
+    pub fn telegram(
+        mut self,
+        bot_token: impl Into<String>,
+        chat_id: impl Into<String>,
+    ) -> Self {
+        self.config.telegram_bot_token = Some(bot_token.into());
+        self.config.telegram_chat_id = Some(chat_id.into());
+        self
+    }
+
+    /// Sends mail of the `routes` to Telegram instead of forwarding it.
+    pub fn telegram_only_aliases<I>(mut self, routes: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.config.telegram_only_aliases = list(routes);
+        self
+    }
+
+    /// Publishes an event of every permanently failed forward to the SNS
+    /// topic `arn`.
+    pub fn alert_topic_arn(mut self, arn: impl Into<String>) -> Self {
+        self.config.alert_topic_arn = Some(arn.into());
+        self
+    }
+
+    /// Pages the on-call through PagerDuty once `threshold` sends in a row
+    /// failed.
+    pub fn pagerduty(
+        mut self,
+        routing_key: impl Into<String>,
+        threshold: u32,
+    ) -> Self {
+        self.config.pagerduty_routing_key = Some(routing_key.into());
+        self.config.alert_failure_threshold = Some(threshold);
+        self
+    }
+
+    /// Pages the on-call through Opsgenie once `threshold` sends in a row
+    /// failed.
+    pub fn opsgenie(
+        mut self,
+        api_key: impl Into<String>,
+        threshold: u32,
+    ) -> Self {
+        self.config.opsgenie_api_key = Some(api_key.into());
+        self.config.alert_failure_threshold = Some(threshold);
+        self
+    }
+
+    /// Puts the lifecycle events of every message on the EventBridge
+    /// `bus`, a name or ARN.
+    pub fn event_bus(mut self, bus: impl Into<String>) -> Self {
+        self.config.event_bus = Some(bus.into());
+        self
+    }
+
+    /// Posts a summary of every handled message to `url`, signed with
+    /// `secret` when set.
+    pub fn webhook(
+        mut self,
+        url: impl Into<String>,
+        secret: Option<String>,
+    ) -> Self {
+        self.config.webhook_url = Some(url.into());
+        self.config.webhook_secret = secret;
+        self
+    }
+
+    /// Sets the Lambda function rendering HTML previews.
+    pub fn preview_renderer(mut self, function: impl Into<String>) -> Self {
+        self.config.preview_renderer = Some(function.into());
+        self
+    }
+
+    /// Sets the SES client library used to send mail.
+    pub fn ses_primary(mut self, backend: SesBackend) -> Self {
+        self.config.ses_primary = backend;
+        self
+    }
+
+    /// Builds requests for both SES clients and logs differences.
+    pub fn ses_dual_write(mut self, enabled: bool) -> Self {
+        self.config.ses_dual_write = enabled;
+        self
+    }
+
+    /// Sends mail through the SES configuration set `name`.
+    pub fn configuration_set(mut self, name: impl Into<String>) -> Self {
+        self.config.configuration_set = Some(name.into());
+        self
+    }
+
+    /// Sends mail through SES in `region`.
+    pub fn ses_region(mut self, region: impl Into<String>) -> Self {
+        self.config.ses_region = Some(region.into());
+        self
+    }
+
+    /// Retries sends failing in the primary region in SES `region`.
+    pub fn ses_failover_region(mut self, region: impl Into<String>) -> Self {
+        self.config.ses_failover_region = Some(region.into());
+        self
+    }
+
+    /// Makes sends failed over as the identity `arn`.
+    pub fn ses_failover_identity_arn(mut self, arn: impl Into<String>) -> Self {
+        self.config.ses_failover_identity_arn = Some(arn.into());
+        self
+    }
+
+    /// Sets whether forwards are tagged with their alias, tenant and
+    /// original sender domain.
+    pub fn message_tags(mut self, enabled: bool) -> Self {
+        self.config.message_tags = enabled;
+        self
+    }
+
+    /// Sends forwards of tracked aliases through the SES configuration set
+    /// `name`, which tracks opens and clicks.
+    pub fn tracking_configuration_set(
+        mut self,
+        name: impl Into<String>,
+    ) -> Self {
+        self.config.tracking_configuration_set = Some(name.into());
+        self
+    }
+
+    /// Sets whether forwards of mail sent to `route`, an address, `@domain`
+    /// or `*`, are tracked.
+    pub fn tracked_alias(
+        mut self,
+        route: impl Into<String>,
+        tracked: bool,
+    ) -> Self {
+        self.config
+            .tracked_aliases
+            .insert(route.into().to_lowercase(), tracked);
+        self
+    }
+
+    /// Sets whether addresses are looked up on the SES account suppression
+    /// list before sending to them.
+    pub fn check_suppression(mut self, enabled: bool) -> Self {
+        self.config.check_suppression = enabled;
+        self
+    }
+
+    /// Lets mail be composed from aliases on `domains`, over HTTP when
+    /// authorized by the bearer `token`.
+    pub fn send_as<I>(mut self, domains: I, token: Option<String>) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.config.send_as_domains = list(domains);
+        self.config.send_api_token = token;
+        self
+    }
+
+    /// Accepts mail posted by inbound parse webhooks over HTTP when
+    /// authorized by `token`.
+    pub fn inbound_webhook_token(mut self, token: impl Into<String>) -> Self {
+        self.config.inbound_webhook_token = Some(token.into());
+        self
+    }
+
+    /// Accepts searches of the mail index over HTTP when authorized by the
+    /// bearer `token`.
+    pub fn search_api_token(mut self, token: impl Into<String>) -> Self {
+        self.config.search_api_token = Some(token.into());
+        self
+    }
+
+    /// Sends raw forwards through SESv2, up to `max_send_size` bytes.
+    pub fn ses_v2(mut self, max_send_size: usize) -> Self {
+        self.config.ses_v2 = true;
+        self.config.max_send_size = max_send_size;
+        self
+    }
+
+    /// Sets how the original message is forwarded.
+    pub fn forward_mode(mut self, mode: ForwardMode) -> Self {
+        self.config.forward_mode = mode;
+        self
+    }
+
+    /// Sets the Bedrock model labelling messages before forwarding.
+    pub fn classifier_model(mut self, model: impl Into<String>) -> Self {
+        self.config.classifier_model = Some(model.into());
+        self
+    }
+
+    /// Sets the time allowed for a classification.
+    pub fn classifier_timeout(mut self, timeout: Duration) -> Self {
+        self.config.classifier_timeout_ms =
+            timeout.as_millis().try_into().unwrap_or(u64::MAX);
+        self
+    }
+
+    /// Sets the largest number of classifications per day.
+    pub fn classifier_daily_cap(mut self, cap: u64) -> Self {
+        self.config.classifier_daily_cap = Some(cap);
+        self
+    }
+
+    /// Sets the classifier labels which are never forwarded.
+    pub fn blocked_labels(
+        mut self,
+        labels: impl IntoIterator<Item = Label>,
+    ) -> Self {
+        self.config.blocked_labels = Some(labels.into_iter().collect());
+        self
+    }
+
+    /// Summarizes long messages in digests.
+    pub fn digest_summaries(mut self, enabled: bool) -> Self {
+        self.config.digest_summaries = enabled;
+        self
+    }
+
+    /// Sets the Bedrock model writing digest summaries.
+    pub fn summary_model(mut self, model: impl Into<String>) -> Self {
+        self.config.summary_model = Some(model.into());
+        self
+    }
+
+    /// Adds `X-Original-*` and `X-Forwarded-By` headers to forwards.
+    pub fn inject_headers(mut self, enabled: bool) -> Self {
+        self.config.inject_headers = enabled;
+        self
+    }
+
+    /// Relays replies sent to `alias` back to the original senders, with
+    /// reply addresses signed by `secret`.
+    pub fn relay(
+        mut self,
+        alias: impl Into<String>,
+        secret: impl Into<String>,
+    ) -> Self {
+        self.config.relay_alias = Some(alias.into());
+        self.config.relay_secret = Some(secret.into());
+        self
+    }
+
+    /// Sets the policy applied to send failures of `class`.
+    pub fn failure_policy(
+        mut self,
+        class: FailureClass,
+        policy: FailurePolicy,
+    ) -> Self {
+        self.config.failure_policies.insert(class, policy);
+        self
+    }
+
+    /// Rewrites return paths to `domain` with SRS, hashed with `secret`.
+    pub fn srs(
+        mut self,
+        domain: impl Into<String>,
+        secret: impl Into<String>,
+    ) -> Self {
+        self.config.srs_domain = Some(domain.into());
+        self.config.srs_secret = Some(secret.into());
+        self
+    }
+
+    /// Stores attachments seen before only once.
+    pub fn dedup_attachments(mut self, enabled: bool) -> Self {
+        self.config.dedup_attachments = enabled;
+        self
+    }
+
+    /// Signs forwards for `domain` with the key published at `selector`.
+    pub fn dkim(
+        mut self,
+        domain: impl Into<String>,
+        selector: impl Into<String>,
+    ) -> Self {
+        self.config.dkim_domain = Some(domain.into());
+        self.config.dkim_selector = Some(selector.into());
+        self
+    }
+
+    /// Sets the PEM DKIM private key.
+    pub fn dkim_private_key(mut self, pem: impl Into<String>) -> Self {
+        self.config.dkim_private_key = Some(pem.into());
+        self
+    }
+
+    /// Sets the Secrets Manager secret holding the DKIM private key.
+    pub fn dkim_key_secret(mut self, secret: impl Into<String>) -> Self {
+        self.config.dkim_key_secret = Some(secret.into());
+        self
+    }
+
+    /// Sets the display name of forwards of mail sent to `route`, an
+    /// address, `@domain` or `*`.
+    pub fn to_display_name(
+        mut self,
+        route: impl Into<String>,
+        name: impl Into<String>,
+    ) -> Self {
+        self.config
+            .to_display_names
+            .insert(route.into().to_lowercase(), name.into());
+        self
+    }
+
+    /// Sets the action taken on mail failing SPF.
+    pub fn spf_policy(mut self, policy: VerdictPolicy) -> Self {
+        self.config.spf_policy = policy;
+        self
+    }
+
+    /// Sets the action taken on mail failing DKIM.
+    pub fn dkim_policy(mut self, policy: VerdictPolicy) -> Self {
+        self.config.dkim_policy = policy;
+        self
+    }
+
+    /// Sets the action taken on mail failing DMARC.
+    pub fn dmarc_policy(mut self, policy: VerdictPolicy) -> Self {
+        self.config.dmarc_policy = policy;
+        self
+    }
+
+    /// Looks up the sending relay or domain in the DNS blocklist `list`.
+    pub fn dnsbl_list(mut self, list: Blocklist) -> Self {
+        self.config.dnsbl_lists.push(list);
+        self
+    }
+
+    /// Applies `policy` to mail named by blocklists scoring `threshold`.
+    pub fn dnsbl_policy(
+        mut self,
+        threshold: u32,
+        policy: VerdictPolicy,
+    ) -> Self {
+        self.config.dnsbl_threshold = threshold;
+        self.config.dnsbl_policy = policy;
+        self
+    }
+
+    /// Sends a plain text alternative with forwards.
+    pub fn text_fallback(mut self, enabled: bool) -> Self {
+        self.config.text_fallback = enabled;
+        self
+    }
+
+    /// Sets the armored PGP public key forwards are encrypted to.
+    pub fn pgp_public_key(mut self, armored: impl Into<String>) -> Self {
+        self.config.pgp_public_key = Some(armored.into());
+        self
+    }
+
+    /// Sets the quiet hours of mail sent to `route`, an address, `@domain`
+    /// or `*`.
+    pub fn quiet_hours(
+        mut self,
+        route: impl Into<String>,
+        hours: QuietHours,
+    ) -> Self {
+        self.config.quiet_hours.insert(route.into().to_lowercase(), hours);
+        self
+    }
+
+    /// Sets the UTC offset of quiet hours, in minutes.
+    pub fn quiet_hours_utc_offset(mut self, minutes: i32) -> Self {
+        self.config.quiet_hours_utc_offset = minutes;
+        self
+    }
+
+    /// Also defers forwards until quiet hours end.
+    pub fn quiet_hours_defer(mut self, enabled: bool) -> Self {
+        self.config.quiet_hours_defer = enabled;
+        self
+    }
+
+    /// Sets the armored PGP keys inbound signatures are checked with.
+    pub fn pgp_keyring(mut self, armored: impl Into<String>) -> Self {
+        self.config.pgp_keyring = Some(armored.into());
+        self
+    }
+
+    /// Sets the PEM certificate and private key signing forwards.
+    pub fn smime_certificate(mut self, pem: impl Into<String>) -> Self {
+        self.config.smime_certificate = Some(pem.into());
+        self
+    }
+
+    /// Sets the Secrets Manager secret holding the S/MIME certificate.
+    pub fn smime_certificate_secret(
+        mut self,
+        secret: impl Into<String>,
+    ) -> Self {
+        self.config.smime_certificate_secret = Some(secret.into());
+        self
+    }
+
+    /// Sets the PEM CA certificates inbound S/MIME is checked with.
+    pub fn smime_trusted_certs(mut self, pem: impl Into<String>) -> Self {
+        self.config.smime_trusted_certs = Some(pem.into());
+        self
+    }
+
+    /// Strips scripts, forms and active content from forwards.
+    pub fn sanitize_html(mut self, enabled: bool) -> Self {
+        self.config.sanitize_html = enabled;
+        self
+    }
+
+    /// Drops tracking pixels and unwraps tracking redirects.
+    pub fn strip_trackers(mut self, enabled: bool) -> Self {
+        self.config.strip_trackers = enabled;
+        self
+    }
+
+    /// Sets the redirectors unwrapped, as `host/path?param`.
+    pub fn tracking_redirects<I>(mut self, redirects: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.config.tracking_redirects = Some(list(redirects));
+        self
+    }
+
+    /// Sets the HTML banner template prepended to forwarded bodies.
+    pub fn banner_html(mut self, template: impl Into<String>) -> Self {
+        self.config.banner_html = Some(template.into());
+        self
+    }
+
+    /// Sets the plain text banner template prepended to forwarded bodies.
+    pub fn banner_text(mut self, template: impl Into<String>) -> Self {
+        self.config.banner_text = Some(template.into());
+        self
+    }
+
+    /// Sets the DynamoDB table of per-recipient settings.
+    pub fn config_table(mut self, table: impl Into<String>) -> Self {
+        self.config.config_table = Some(table.into());
+        self
+    }
+
+    /// Checks at startup that the S3 bucket and DynamoDB table exist.
+    pub fn validate_resources(mut self, enabled: bool) -> Self {
+        self.config.validate_resources = enabled;
+        self
+    }
+
+    /// Sets the prefix of forwarded subjects.
+    pub fn subject_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.subject_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Sets the subject prefix of forwards of mail sent to `route`, an
+    /// address, `@domain` or `*`.
+    pub fn subject_prefix_route(
+        mut self,
+        route: impl Into<String>,
+        prefix: impl Into<String>,
+    ) -> Self {
+        self.config
+            .subject_prefixes
+            .insert(route.into().to_lowercase(), prefix.into());
+        self
+    }
+
+    /// Sends mail from `sender`, an address or `@domain`, to
+    /// `destination`.
+    pub fn sender_route(
+        mut self,
+        sender: impl Into<String>,
+        destination: impl Into<String>,
+    ) -> Self {
+        self.config
+            .sender_routes
+            .insert(sender.into().to_lowercase(), destination.into());
+        self
+    }
+
+    /// Appends a filtering rule.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.config.rules.push(rule);
+        self
+    }
+
+    /// Sets the action taken when no rule matches.
+    pub fn default_rule_action(mut self, action: Action) -> Self {
+        self.config.default_rule_action = Some(action);
+        self
+    }
+
+    /// Sends the auto-reply rendered from `template` to senders, tracking
+    /// them in the DynamoDB `table`.
+    pub fn auto_reply(
+        mut self,
+        template: impl Into<String>,
+        table: impl Into<String>,
+    ) -> Self {
+        self.config.auto_reply_text = Some(template.into());
+        self.config.auto_reply_table = Some(table.into());
+        self
+    }
+
+    /// Sets the subject template of the auto-reply.
+    pub fn auto_reply_subject(mut self, template: impl Into<String>) -> Self {
+        self.config.auto_reply_subject = Some(template.into());
+        self
+    }
+
+    /// Sets the days before a sender gets another auto-reply.
+    pub fn auto_reply_days(mut self, days: u32) -> Self {
+        self.config.auto_reply_days = days;
+        self
+    }
+
+    /// Acknowledges mail sent to `route` with `acknowledgment`.
+    pub fn acknowledgment(
+        mut self,
+        route: impl Into<String>,
+        acknowledgment: Acknowledgment,
+    ) -> Self {
+        self.config
+            .acknowledgments
+            .insert(route.into().to_lowercase(), acknowledgment);
+        self
+    }
+
+    /// Marks forwards with the loop header and drops them when they return.
+    pub fn loop_detection(mut self, enabled: bool) -> Self {
+        self.config.loop_detection = enabled;
+        self
+    }
+
+    /// Sets the identifier of the forwarder in the loop header.
+    pub fn loop_id(mut self, id: impl Into<String>) -> Self {
+        self.config.loop_id = Some(id.into());
+        self
+    }
+
+    /// Refuses to forward messages with more than `limit` hops, counted
+    /// from `header` or else the `Received` headers.
+    pub fn hop_limit(mut self, limit: usize, header: Option<String>) -> Self {
+        self.config.hop_limit = Some(limit);
+        self.config.hop_header = header;
+        self
+    }
+
+    /// Skips duplicates of messages recorded in the DynamoDB `table`.
+    pub fn dedup_table(mut self, table: impl Into<String>) -> Self {
+        self.config.dedup_table = Some(table.into());
+        self
+    }
+
+    /// Records bounces of sent mail in the DynamoDB `table`.
+    pub fn bounce_table(mut self, table: impl Into<String>) -> Self {
+        self.config.bounce_table = Some(table.into());
+        self
+    }
+
+    /// Reports bounces of sent mail to `address`.
+    pub fn bounce_notify_email(mut self, address: impl Into<String>) -> Self {
+        self.config.bounce_notify_email = Some(address.into());
+        self
+    }
+
+    /// Sends the scheduled statistics reports to `address`.
+    pub fn stats_email(mut self, address: impl Into<String>) -> Self {
+        self.config.stats_email = Some(address.into());
+        self
+    }
+
+    /// Sets the hours duplicates of a message are skipped for.
+    pub fn dedup_ttl_hours(mut self, hours: u32) -> Self {
+        self.config.dedup_ttl_hours = hours;
+        self
+    }
+
+    /// Deduplicates by the `Message-ID` header instead of the SES id.
+    pub fn dedup_by_header(mut self, enabled: bool) -> Self {
+        self.config.dedup_by_header = enabled;
+        self
+    }
+
+    /// Retries sends throttled by SES up to `retries` times, backing off
+    /// from `base_ms`.
+    pub fn send_retries(mut self, retries: u32, base_ms: u64) -> Self {
+        self.config.send_retries = retries;
+        self.config.send_retry_base_ms = base_ms;
+        self
+    }
+
+    /// Quarantines mail once `threshold` sends in a row failed, trying
+    /// again after `cooldown_secs`.
+    pub fn circuit_breaker(
+        mut self,
+        threshold: u32,
+        cooldown_secs: u64,
+    ) -> Self {
+        self.config.circuit_breaker_threshold = Some(threshold);
+        self.config.circuit_breaker_cooldown_secs = cooldown_secs;
+        self
+    }
+
+    /// Defers forwards by `defer_secs` once `percent` of the SES sending
+    /// quota is used, or while sending is paused.
+    pub fn quota_deferral(mut self, percent: u32, defer_secs: u64) -> Self {
+        self.config.quota_defer_percent = Some(percent);
+        self.config.quota_defer_secs = defer_secs;
+        self
+    }
+
+    /// Writes dead-lettered events under the store `prefix`.
+    pub fn failed_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.config.failed_prefix = prefix.into();
+        self
+    }
+
+    /// Dead-letters failed invocations instead of failing them.
+    pub fn dead_letter_errors(mut self, enabled: bool) -> Self {
+        self.config.dead_letter_errors = enabled;
+        self
+    }
+
+    /// Handles up to `concurrency` records of an event at the same time.
+    pub fn record_concurrency(mut self, concurrency: usize) -> Self {
+        self.config.record_concurrency = concurrency;
+        self
+    }
+
+    /// Loads a remote configuration again after `secs`, never with 0.
+    pub fn config_refresh_secs(mut self, secs: u64) -> Self {
+        self.config.config_refresh_secs = secs;
+        self
+    }
+
+    /// Sets the tenant receiving mail on `domain`.
+    pub fn tenant(mut self, domain: impl Into<String>, tenant: Tenant) -> Self {
+        self.config.tenants.insert(domain.into().to_lowercase(), tenant);
+        self
+    }
+
+    /// Returns the configuration.
+    pub fn build(self) -> PrivatEmailConfig {
+        self.config
+    }
+}
+
+impl From<PrivatEmailConfig> for PrivatEmailConfigBuilder {
+    fn from(config: PrivatEmailConfig) -> Self {
+        PrivatEmailConfigBuilder { config }
+    }
+}
+
+/** Test module for the configuration builder */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let config = PrivatEmailConfig::builder()
+            .from_email("achu@fufu.soup")
+            .to_email("onions@suya.io")
+            .black_list(["spam.example", "junk@mail.test"])
+            .classifier_timeout(Duration::from_secs(2))
+            .to_display_name("@Fufu.soup", "Fufu")
+            .quiet_hours("*", "22:00-07:00".parse().unwrap())
+            .relay("relay@fufu.soup", "s3cr3t")
+            .failure_policy(FailureClass::Throttling, FailurePolicy::Alert)
+            .build();
+        assert_eq!(config.from_email, "achu@fufu.soup");
+        assert_eq!(
+            config.black_list.as_deref().unwrap(),
+            ["spam.example", "junk@mail.test"]
+        );
+        assert_eq!(config.classifier_timeout_ms, 2000);
+        assert_eq!(
+            config.to_address(&["x@fufu.soup".into()]),
+            "\"Fufu\" <onions@suya.io>"
+        );
+        assert_eq!(config.relay_secret.as_deref(), Some("s3cr3t"));
+        assert_eq!(
+            config.failure_policy(FailureClass::Throttling),
+            FailurePolicy::Alert
+        );
+        assert!(config.text_fallback);
+
+        let rebuilt = PrivatEmailConfigBuilder::from(config.clone())
+            .text_fallback(false)
+            .build();
+        assert_eq!(rebuilt.to_email, config.to_email);
+        assert!(!rebuilt.text_fallback);
+    }
+}
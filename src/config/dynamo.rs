@@ -0,0 +1,231 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Per-recipient settings stored in a DynamoDB table.
+//!
+//! Items are keyed by the `address` attribute, holding a recipient
+//! address, an `@domain` or `*`, and carry a `settings` string with a JSON
+//! object of [`PrivatEmailConfig`] fields, e.g.
+//! `{"to_email": "work@suya.io", "black_list": ["spam.example"]}`. Settings
+//! of `*`, the domain and the address are applied in that order over the
+//! base configuration. Items are cached for [`CACHE_TTL`], so changes take
+//! effect within seconds.
+//!
+//! Stores and signing keys are set up before the recipient is known, so
+//! their settings are only read from the base configuration.
+use super::PrivatEmailConfig;
+use crate::aws;
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Partition key attribute of the table.
+pub const KEY_ATTRIBUTE: &str = "address";
+
+/// Attribute holding the JSON encoded settings.
+pub const SETTINGS_ATTRIBUTE: &str = "settings";
+
+/// How long items, or their absence, are cached.
+pub const CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// Settings by table and key, with the time they were read.
+type Cache = HashMap<(String, String), (Instant, Option<Value>)>;
+
+/// Items cached across invocations of a warm Lambda container.
+fn cache() -> &'static Mutex<Cache> {
+    static CACHE: OnceLock<Mutex<Cache>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Returns `config` with the settings stored in `table` for the first of
+/// `recipients` applied.
+pub async fn apply(
+    table: &str,
+    config: PrivatEmailConfig,
+    recipients: &[String],
+) -> Result<PrivatEmailConfig, Error> {
+    let Some(recipient) = recipients.first() else {
+        return Ok(config);
+    };
+    let keys = route_keys(recipient);
+    let settings = lookup(table, &keys).await?;
+    merge(config, &settings)
+}
+
+/// Returns the keys of the items applying to `recipient`, least specific
+/// first.
+pub fn route_keys(recipient: &str) -> Vec<String> {
+    let recipient = recipient.trim().to_lowercase();
+    let mut keys = vec!["*".to_owned()];
+    if let Some((_, domain)) = recipient.rsplit_once('@') {
+        keys.push(format!("@{}", domain));
+    }
+    keys.push(recipient);
+    keys
+}
+
+/// Returns `config` with the fields of each of the `settings` objects
+/// replaced in turn.
+pub fn merge(
+    config: PrivatEmailConfig,
+    settings: &[Value],
+) -> Result<PrivatEmailConfig, Error> {
+    if settings.is_empty() {
+        return Ok(config);
+    }
+    let mut value = serde_json::to_value(config)?;
+    for object in settings {
+        let Some(fields) = object.as_object() else {
+            return Err(
+                format!("Settings are not an object: {}", object).into()
+            );
+        };
+        for (field, setting) in fields {
+            value[field] = setting.clone();
+        }
+    }
+    serde_json::from_value(value)
+        .map_err(|e| format!("Invalid recipient settings: {}", e).into())
+}
+
+/// Returns the settings stored under `keys`, in order, reading the items
+/// missing from the cache.
+async fn lookup(table: &str, keys: &[String]) -> Result<Vec<Value>, Error> {
+    let now = Instant::now();
+    let mut found: HashMap<String, Option<Value>> = HashMap::new();
+    {
+        let cache = cache().lock().unwrap();
+        for key in keys {
+            match cache.get(&(table.to_owned(), key.clone())) {
+                Some((at, settings)) if now.duration_since(*at) < CACHE_TTL => {
+                    found.insert(key.clone(), settings.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let missing: Vec<&String> =
+        keys.iter().filter(|key| !found.contains_key(*key)).collect();
+    if !missing.is_empty() {
+        let mut items = fetch(table, &missing).await?;
+        let mut cache = cache().lock().unwrap();
+        for key in missing {
+            let settings = items.remove(key);
+            cache.insert(
+                (table.to_owned(), key.clone()),
+                (now, settings.clone()),
+            );
+            found.insert(key.clone(), settings);
+        }
+    }
+    Ok(keys.iter().filter_map(|key| found.remove(key).flatten()).collect())
+}
+
+/// Reads the items stored under `keys` with a single `BatchGetItem`.
+async fn fetch(
+    table: &str,
+    keys: &[&String],
+) -> Result<HashMap<String, Value>, Error> {
+    let keys: Vec<Value> =
+        keys.iter().map(|key| json!({ KEY_ATTRIBUTE: { "S": key } })).collect();
+    let mut request =
+        SignedRequest::new("POST", "dynamodb", &Region::default(), "/");
+    request.add_header("x-amz-target", "DynamoDB_20120810.BatchGetItem");
+    request.set_content_type("application/x-amz-json-1.0".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&json!({
+        "RequestItems": { table: { "Keys": keys } }
+    }))?));
+    let response = aws::dispatch(request).await?;
+    parse_items(table, &serde_json::from_slice(&response.body)?)
+}
+
+/// Returns the settings of the items of `table` in a `BatchGetItem`
+/// `response`, by key.
+fn parse_items(
+    table: &str,
+    response: &Value,
+) -> Result<HashMap<String, Value>, Error> {
+    let mut items = HashMap::new();
+    let Some(responses) = response["Responses"][table].as_array() else {
+        return Ok(items);
+    };
+    for item in responses {
+        let (Some(key), Some(settings)) = (
+            item[KEY_ATTRIBUTE]["S"].as_str(),
+            item[SETTINGS_ATTRIBUTE]["S"].as_str(),
+        ) else {
+            continue;
+        };
+        let settings = serde_json::from_str(settings)
+            .map_err(|e| format!("Invalid settings of {}: {}", key, e))?;
+        items.insert(key.to_owned(), settings);
+    }
+    Ok(items)
+}
+
+/** Test module for the DynamoDB configuration table */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_keys() {
+        assert_eq!(
+            route_keys("Achu@Fufu.soup"),
+            ["*", "@fufu.soup", "achu@fufu.soup"]
+        );
+        assert_eq!(route_keys("postmaster"), ["*", "postmaster"]);
+    }
+
+    #[test]
+    fn test_merge() {
+        let config = merge(
+            PrivatEmailConfig::default(),
+            &[
+                json!({ "to_email": "all@suya.io", "text_fallback": false }),
+                json!({ "to_email": "achu@suya.io", "black_list": ["x.io"] }),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.to_email, "achu@suya.io");
+        assert_eq!(config.black_list.unwrap(), ["x.io"]);
+        assert!(!config.text_fallback);
+        assert_eq!(config.from_email, PrivatEmailConfig::default().from_email);
+
+        assert!(merge(PrivatEmailConfig::default(), &[json!([1])]).is_err());
+        assert!(merge(
+            PrivatEmailConfig::default(),
+            &[json!({ "forward_mode": "carrier-pigeon" })]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_items() {
+        let response = json!({
+            "Responses": { "privatemail": [
+                {
+                    "address": { "S": "@fufu.soup" },
+                    "settings": { "S": "{\"to_email\": \"fufu@suya.io\"}" }
+                },
+                { "address": { "S": "*" } }
+            ] },
+            "UnprocessedKeys": {}
+        });
+        let items = parse_items("privatemail", &response).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items["@fufu.soup"]["to_email"], "fufu@suya.io");
+        assert!(parse_items("other", &response).unwrap().is_empty());
+    }
+}
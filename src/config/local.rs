@@ -0,0 +1,100 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Local configuration file for runs outside Lambda.
+//!
+//! `privatemail.toml` in the working directory holds the settings of
+//! [`PrivatEmailConfig`] as lowercase keys. Environment variables named
+//! after a setting, e.g. `TO_EMAIL` for `to_email`, override the file.
+use super::PrivatEmailConfig;
+use lambda_runtime::Error;
+use serde_json::Value;
+use std::env;
+use std::path::Path;
+
+/// Name of the configuration file looked up in the working directory.
+pub const CONFIG_FILE: &str = "privatemail.toml";
+
+/// Reads the configuration file at `path`, with environment overrides.
+pub fn load(path: &Path) -> Result<PrivatEmailConfig, Error> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    let file = parse(&contents)
+        .map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+    merge(file, &PrivatEmailConfig::new_from_env()?, |name| {
+        env::var(name.to_uppercase()).is_ok_and(|v| !v.trim().is_empty())
+    })
+}
+
+/// Parses the TOML `contents` into a table of settings.
+fn parse(contents: &str) -> Result<Value, Error> {
+    let table: toml::Table = toml::from_str(contents)?;
+    Ok(serde_json::to_value(table)?)
+}
+
+/// Returns the default configuration with the `file` settings applied,
+/// then the settings of `env` which `is_set` in the environment.
+fn merge(
+    file: Value,
+    env: &PrivatEmailConfig,
+    is_set: impl Fn(&str) -> bool,
+) -> Result<PrivatEmailConfig, Error> {
+    let mut value = serde_json::to_value(PrivatEmailConfig::default())?;
+    if let Value::Object(settings) = file {
+        for (name, setting) in settings {
+            value[name] = setting;
+        }
+    }
+    if let Value::Object(settings) = serde_json::to_value(env)? {
+        for (name, setting) in settings {
+            if is_set(&name) {
+                value[name] = setting;
+            }
+        }
+    }
+    serde_json::from_value(value)
+        .map_err(|e| format!("Invalid {}: {}", CONFIG_FILE, e).into())
+}
+
+/** Test module for the local configuration file */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward::ForwardMode;
+
+    #[test]
+    fn test_merge() {
+        let file = parse(
+            r#"
+            from_email = "achu@fufu.soup"
+            black_list = ["spam.example"]
+            forward_mode = "attachment"
+
+            [to_display_names]
+            "@fufu.soup" = "Fufu"
+            "#,
+        )
+        .unwrap();
+        let env = PrivatEmailConfig {
+            from_email: "env@fufu.soup".into(),
+            to_email: "onions@suya.io".into(),
+            ..PrivatEmailConfig::default()
+        };
+        let config = merge(file, &env, |name| name == "to_email").unwrap();
+        assert_eq!(config.from_email, "achu@fufu.soup");
+        assert_eq!(config.to_email, "onions@suya.io");
+        assert_eq!(config.black_list.unwrap(), ["spam.example"]);
+        assert_eq!(config.forward_mode, ForwardMode::Attachment);
+        assert_eq!(config.to_display_names["@fufu.soup"], "Fufu");
+
+        let file = parse("forward_mode = \"carrier-pigeon\"").unwrap();
+        assert!(merge(file, &env, |_| false).is_err());
+        assert!(parse("from_email = ").is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Configuration file stored in S3.
+//!
+//! `CONFIG_S3_URI`, e.g. `s3://bucket/privatemail.yaml`, names a YAML or
+//! JSON document holding the serialized [`PrivatEmailConfig`]. The parsed
+//! file is cached in the warm Lambda container and only downloaded again
+//! when its ETag changes.
+use super::PrivatEmailConfig;
+use crate::aws::{self, AwsError};
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use std::sync::{Mutex, OnceLock};
+use tracing::warn;
+
+/// Environment variable naming the configuration object.
+pub const CONFIG_S3_URI: &str = "CONFIG_S3_URI";
+
+/// Configuration parsed from the object with the given ETag.
+#[derive(Clone, Debug)]
+struct Cached {
+    uri: String,
+    etag: String,
+    config: PrivatEmailConfig,
+}
+
+/// Configuration cached across invocations of a warm Lambda container.
+fn cache() -> &'static Mutex<Option<Cached>> {
+    static CACHE: OnceLock<Mutex<Option<Cached>>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Returns the configuration stored at the S3 `uri`, reusing the cached
+/// copy while the object is unchanged. The cached copy is also used when
+/// S3 can't be reached.
+pub async fn load(uri: &str) -> Result<PrivatEmailConfig, Error> {
+    let (bucket, key) = parse_uri(uri)?;
+    let cached = cache().lock().unwrap().clone().filter(|c| c.uri == uri);
+
+    let mut request = SignedRequest::new(
+        "GET",
+        "s3",
+        &Region::default(),
+        &format!("/{}/{}", bucket, key),
+    );
+    if let Some(cached) = &cached {
+        request.add_header("If-None-Match", &cached.etag);
+    }
+    let response = match (aws::dispatch(request).await, cached) {
+        (Ok(response), _) => response,
+        (Err(e), Some(cached)) if is_not_modified(&e) => {
+            return Ok(cached.config)
+        }
+        (Err(e), Some(cached)) => {
+            warn!("Using cached configuration, {} failed: {}", uri, e);
+            return Ok(cached.config);
+        }
+        (Err(e), None) => return Err(e),
+    };
+
+    let config = parse(key, &response.body)?;
+    if let Some(etag) = response.headers.get("etag") {
+        *cache().lock().unwrap() = Some(Cached {
+            uri: uri.to_owned(),
+            etag: etag.to_owned(),
+            config: config.clone(),
+        });
+    }
+    Ok(config)
+}
+
+/// Splits `s3://bucket/key` into the bucket and the key.
+pub fn parse_uri(uri: &str) -> Result<(&str, &str), Error> {
+    uri.strip_prefix("s3://")
+        .and_then(|path| path.split_once('/'))
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| format!("Invalid {}: {}", CONFIG_S3_URI, uri).into())
+}
+
+/// Parses the configuration file `body`, as YAML unless `key` names a
+/// `.json` file.
+pub fn parse(key: &str, body: &[u8]) -> Result<PrivatEmailConfig, Error> {
+    let config = if key.to_ascii_lowercase().ends_with(".json") {
+        serde_json::from_slice(body)
+            .map_err(|e| format!("Invalid configuration {}: {}", key, e))?
+    } else {
+        serde_yaml::from_slice(body)
+            .map_err(|e| format!("Invalid configuration {}: {}", key, e))?
+    };
+    Ok(config)
+}
+
+/// Returns true when `error` is a "not modified" response.
+fn is_not_modified(error: &Error) -> bool {
+    error.downcast_ref::<AwsError>().is_some_and(|e| e.status == 304)
+}
+
+/** Test module for the S3 configuration file */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::forward::ForwardMode;
+
+    #[test]
+    fn test_parse_uri() {
+        assert_eq!(
+            parse_uri("s3://bucket/conf/privatemail.yaml").unwrap(),
+            ("bucket", "conf/privatemail.yaml")
+        );
+        assert!(parse_uri("s3://bucket").is_err());
+        assert!(parse_uri("https://bucket/privatemail.yaml").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let yaml = b"from_email: achu@fufu.soup\n\
+                     to_email: onions@suya.io\n\
+                     forward_mode: attachment\n\
+                     to_display_names:\n  '@fufu.soup': Fufu\n\
+                     quiet_hours:\n  '*': 22:00-07:00\n";
+        let config = parse("privatemail.yaml", yaml).unwrap();
+        assert_eq!(config.from_email, "achu@fufu.soup");
+        assert_eq!(
+            config.to_address(&["x@fufu.soup".into()]),
+            "\"Fufu\" <onions@suya.io>"
+        );
+        assert_eq!(config.forward_mode, ForwardMode::Attachment);
+        assert_eq!(config.quiet_hours["*"].to_string(), "22:00-07:00");
+        assert!(config.validate().is_ok());
+
+        let json = serde_json::to_vec(&config).unwrap();
+        assert_eq!(parse("privatemail.json", &json).unwrap(), config);
+        assert!(parse("privatemail.json", yaml).is_err());
+    }
+}
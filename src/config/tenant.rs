@@ -0,0 +1,121 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Tenants sharing one deployment, keyed by the domain mail is received
+//! on.
+//!
+//! Each tenant section may replace the sender, destination, blocklist and
+//! subject prefix of the configuration, e.g. in `privatemail.toml`:
+//!
+//! ```toml
+//! [tenants."fufu.soup"]
+//! from_email = "forwarder@fufu.soup"
+//! to_email = "achu@suya.io"
+//! subject_prefix = "[Fufu]"
+//! ```
+use super::PrivatEmailConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Settings of the tenant receiving mail on a domain.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Tenant {
+    /// Verified SES address forwards of the tenant are sent from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_email: Option<String>,
+
+    /// Address receiving the forwards of the tenant
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_email: Option<String>,
+
+    /// Blocked senders, replacing the configured black list
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub black_list: Option<Vec<String>>,
+
+    /// Prefix of forwarded subjects, e.g. `[Fufu]`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_prefix: Option<String>,
+}
+
+impl Tenant {
+    /// Replaces the settings of `config` the tenant sets.
+    pub fn apply(&self, config: &mut PrivatEmailConfig) {
+        if let Some(from_email) = &self.from_email {
+            config.from_email = from_email.clone();
+        }
+        if let Some(to_email) = &self.to_email {
+            config.to_email = to_email.clone();
+        }
+        if let Some(black_list) = &self.black_list {
+            config.black_list = Some(black_list.clone());
+        }
+        if let Some(subject_prefix) = &self.subject_prefix {
+            config.subject_prefix = Some(subject_prefix.clone());
+        }
+    }
+}
+
+/// Returns the tenant of the domain `recipient` is on. Domains may be
+/// written with or without a leading `@`.
+pub fn find<'a>(
+    tenants: &'a BTreeMap<String, Tenant>,
+    recipient: &str,
+) -> Option<&'a Tenant> {
+    entry(tenants, recipient).map(|(_, tenant)| tenant)
+}
+
+/// Returns the domain naming the tenant `recipient` is on, without `@`.
+pub fn name<'a>(
+    tenants: &'a BTreeMap<String, Tenant>,
+    recipient: &str,
+) -> Option<&'a str> {
+    entry(tenants, recipient).map(|(key, _)| key.trim_start_matches('@'))
+}
+
+fn entry<'a>(
+    tenants: &'a BTreeMap<String, Tenant>,
+    recipient: &str,
+) -> Option<(&'a String, &'a Tenant)> {
+    let (_, domain) = recipient.trim().rsplit_once('@')?;
+    tenants.iter().find(|(key, _)| {
+        key.trim_start_matches('@').eq_ignore_ascii_case(domain)
+    })
+}
+
+/** Test module for tenants */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_and_apply() {
+        let tenants: BTreeMap<String, Tenant> = serde_json::from_str(
+            r#"{
+                "fufu.soup": {
+                    "to_email": "achu@suya.io",
+                    "black_list": ["spam.example"],
+                    "subject_prefix": "[Fufu]"
+                },
+                "@eru.soup": { "from_email": "forwarder@eru.soup" }
+            }"#,
+        )
+        .unwrap();
+        assert!(find(&tenants, "jobs@Eru.Soup").is_some());
+        assert!(find(&tenants, "jobs@suya.io").is_none());
+        assert!(find(&tenants, "fufu.soup").is_none());
+        assert_eq!(name(&tenants, "jobs@eru.soup"), Some("eru.soup"));
+
+        let mut config = PrivatEmailConfig::default();
+        find(&tenants, "jobs@fufu.soup").unwrap().apply(&mut config);
+        assert_eq!(config.from_email, PrivatEmailConfig::default().from_email);
+        assert_eq!(config.to_email, "achu@suya.io");
+        assert_eq!(config.black_list.unwrap(), ["spam.example"]);
+        assert_eq!(config.subject_prefix.as_deref(), Some("[Fufu]"));
+    }
+}
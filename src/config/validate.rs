@@ -0,0 +1,359 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Configuration checks run when the function starts, so that mistakes
+//! are reported at once instead of failing the first message.
+use super::PrivatEmailConfig;
+use crate::aws::{self, AwsError};
+use crate::{limits, rules};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+
+/// Returns the problems of `config` found without calling AWS.
+pub fn problems(config: &PrivatEmailConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (name, address) in [
+        ("from_email", Some(&config.from_email)),
+        ("to_email", Some(&config.to_email)),
+        ("relay_alias", config.relay_alias.as_ref()),
+        ("bounce_notify_email", config.bounce_notify_email.as_ref()),
+        ("stats_email", config.stats_email.as_ref()),
+    ] {
+        match address.map(|a| a.trim()) {
+            Some("") => problems.push(format!("{} is not set", name)),
+            Some(a) if !is_address(a) => {
+                problems.push(format!("{} is not an address: {}", name, a))
+            }
+            _ => {}
+        }
+    }
+    for (domain, tenant) in &config.tenants {
+        for (name, address) in
+            [("from_email", &tenant.from_email), ("to_email", &tenant.to_email)]
+        {
+            match address {
+                Some(a) if !is_address(a) => problems.push(format!(
+                    "{} of tenant {} is not an address: {}",
+                    name, domain, a
+                )),
+                _ => {}
+            }
+        }
+    }
+    for (sender, destination) in &config.sender_routes {
+        if !is_address(destination) {
+            problems.push(format!(
+                "destination of sender route {} is not an address: {}",
+                sender, destination
+            ));
+        }
+    }
+    for (index, rule) in config.rules.iter().enumerate() {
+        match &rule.body_regex {
+            Some(pattern) if rules::body_regex(pattern).is_err() => problems
+                .push(format!(
+                    "body_regex of rule {} is invalid: {}",
+                    rule.label(index),
+                    pattern
+                )),
+            _ => {}
+        }
+    }
+    if config.record_concurrency == 0 {
+        problems.push("record_concurrency is 0".to_owned());
+    }
+    if config.max_send_size == 0 {
+        problems.push("max_send_size is 0".to_owned());
+    }
+    if config.max_content_size == 0 {
+        problems.push("max_content_size is 0".to_owned());
+    }
+    let send_limit = match config.ses_v2 {
+        true => limits::SESV2_MAX_SEND_SIZE,
+        false => limits::SES_MAX_SEND_SIZE,
+    };
+    if config.max_send_size > send_limit {
+        problems.push(format!(
+            "max_send_size exceeds the SES limit of {} bytes",
+            send_limit
+        ));
+    }
+    // The URL itself is left out, it may come from a secret
+    match &config.webhook_url {
+        Some(url) if !url.starts_with("https://") => {
+            problems.push("webhook_url is not an HTTPS URL".to_owned())
+        }
+        _ => {}
+    }
+    if config.alert_failure_threshold.is_some()
+        && config.pagerduty_routing_key.is_none()
+        && config.opsgenie_api_key.is_none()
+    {
+        problems.push(
+            "alert_failure_threshold needs pagerduty_routing_key or \
+             opsgenie_api_key"
+                .to_owned(),
+        );
+    }
+    if config.alert_failure_threshold == Some(0) {
+        problems.push("alert_failure_threshold is 0".to_owned());
+    }
+    if !config.telegram_only_aliases.is_empty()
+        && config.telegram_chat_id.is_none()
+    {
+        problems
+            .push("telegram_only_aliases needs telegram_chat_id".to_owned());
+    }
+    if config.tracked_aliases.values().any(|&tracked| tracked)
+        && config.tracking_configuration_set.is_none()
+    {
+        problems.push(
+            "tracked_aliases needs tracking_configuration_set".to_owned(),
+        );
+    }
+    for (name, region) in [
+        ("ses_region", &config.ses_region),
+        ("ses_failover_region", &config.ses_failover_region),
+    ] {
+        match region {
+            Some(region) if region.parse::<Region>().is_err() => {
+                problems.push(format!("{} is not a region: {}", name, region))
+            }
+            _ => {}
+        }
+    }
+    if config.ses_failover_identity_arn.is_some()
+        && config.ses_failover_region.is_none()
+    {
+        problems.push(
+            "ses_failover_identity_arn needs ses_failover_region".to_owned(),
+        );
+    }
+    if config.ses_v2 && !cfg!(feature = "sesv2") {
+        problems.push("ses_v2 needs the sesv2 feature".to_owned());
+    }
+    if config.quiet_hours_utc_offset.abs() > 14 * 60 {
+        problems.push("quiet_hours_utc_offset exceeds 14 hours".to_owned());
+    }
+    if config.rules_utc_offset.abs() > 14 * 60 {
+        problems.push("rules_utc_offset exceeds 14 hours".to_owned());
+    }
+    if config.compress_archive && config.archive_bucket.is_none() {
+        problems.push("compress_archive needs archive_bucket".to_owned());
+    }
+    if config.envelope_kms_key_id.is_some() && config.archive_bucket.is_none() {
+        problems.push("envelope_kms_key_id needs archive_bucket".to_owned());
+    }
+    if config.sse_kms_key_id.is_some()
+        && config.archive_bucket.is_none()
+        && config.s3_bucket.is_none()
+    {
+        problems.push(
+            "sse_kms_key_id needs archive_bucket or s3_bucket".to_owned(),
+        );
+    }
+    if config.search_api_token.is_some() && config.index_table.is_none() {
+        problems.push("search_api_token needs index_table".to_owned());
+    }
+    if config.retention_days == Some(0) {
+        problems.push("retention_days is 0".to_owned());
+    }
+    if config.retention_days.is_some()
+        && config.archive_bucket.is_none()
+        && config.s3_bucket.is_none()
+        && config.local_store_dir.is_none()
+    {
+        problems.push(
+            "retention_days needs archive_bucket, s3_bucket or local_store_dir"
+                .to_owned(),
+        );
+    }
+    if config.circuit_breaker_threshold.is_some()
+        && config.s3_bucket.is_none()
+        && config.local_store_dir.is_none()
+    {
+        problems.push(
+            "circuit_breaker_threshold needs s3_bucket or local_store_dir"
+                .to_owned(),
+        );
+    }
+    if !config.dnsbl_lists.is_empty() && config.dnsbl_threshold == 0 {
+        problems.push("dnsbl_threshold is 0".to_owned());
+    }
+    if let Some(percent) = config.quota_defer_percent {
+        if !(1..=100).contains(&percent) {
+            problems.push(format!(
+                "quota_defer_percent is not between 1 and 100: {}",
+                percent
+            ));
+        }
+        if config.s3_bucket.is_none() && config.local_store_dir.is_none() {
+            problems.push(
+                "quota_defer_percent needs s3_bucket or local_store_dir"
+                    .to_owned(),
+            );
+        }
+    }
+    if config.rules.iter().any(|rule| rule.action == rules::Action::Digest)
+        && config.s3_bucket.is_none()
+        && config.local_store_dir.is_none()
+    {
+        problems
+            .push("digest rules need s3_bucket or local_store_dir".to_owned());
+    }
+    for (name, set) in [
+        (
+            "relay_alias and relay_secret",
+            [&config.relay_alias, &config.relay_secret],
+        ),
+        ("srs_domain and srs_secret", [&config.srs_domain, &config.srs_secret]),
+        (
+            "dkim_domain and dkim_selector",
+            [&config.dkim_domain, &config.dkim_selector],
+        ),
+        (
+            "auto_reply_text and auto_reply_table",
+            [&config.auto_reply_text, &config.auto_reply_table],
+        ),
+        (
+            "telegram_bot_token and telegram_chat_id",
+            [&config.telegram_bot_token, &config.telegram_chat_id],
+        ),
+    ] {
+        if set[0].is_some() != set[1].is_some() {
+            problems.push(format!("{} must be set together", name));
+        }
+    }
+    problems
+}
+
+/// Returns the S3 bucket and DynamoDB tables named by `config` which can't
+/// be reached.
+pub async fn missing_resources(config: &PrivatEmailConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (name, bucket) in [
+        ("s3_bucket", &config.s3_bucket),
+        ("archive_bucket", &config.archive_bucket),
+    ] {
+        let Some(bucket) = bucket else { continue };
+        let request = SignedRequest::new(
+            "HEAD",
+            "s3",
+            &Region::default(),
+            &format!("/{}", bucket),
+        );
+        if let Err(e) = aws::dispatch(request).await {
+            problems.push(format!("{} {}: {}", name, bucket, reason(&e)));
+        }
+    }
+    for (name, table) in [
+        ("config_table", &config.config_table),
+        ("auto_reply_table", &config.auto_reply_table),
+        ("dedup_table", &config.dedup_table),
+        ("index_table", &config.index_table),
+        ("bounce_table", &config.bounce_table),
+    ] {
+        let Some(table) = table else { continue };
+        let mut request =
+            SignedRequest::new("POST", "dynamodb", &Region::default(), "/");
+        request.add_header("x-amz-target", "DynamoDB_20120810.DescribeTable");
+        request.set_content_type("application/x-amz-json-1.0".to_owned());
+        request.set_payload(Some(
+            serde_json::json!({ "TableName": table }).to_string(),
+        ));
+        if let Err(e) = aws::dispatch(request).await {
+            problems.push(format!("{} {}: {}", name, table, reason(&e)));
+        }
+    }
+    problems
+}
+
+/// Returns true when `value` looks like `local@domain.tld`, optionally
+/// with a display name as in `Name <local@domain.tld>`.
+pub fn is_address(value: &str) -> bool {
+    let address = match value.rsplit_once('<') {
+        Some((_, rest)) => match rest.strip_suffix('>') {
+            Some(address) => address,
+            None => return false,
+        },
+        None => value,
+    };
+    let Some((local, domain)) = address.rsplit_once('@') else {
+        return false;
+    };
+    !local.is_empty()
+        && !address.chars().any(|c| c.is_whitespace() || c == '<')
+        && domain.contains('.')
+        && domain.split('.').all(|label| {
+            !label.is_empty()
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Describes why a resource check failed.
+fn reason(error: &lambda_runtime::Error) -> String {
+    match error.downcast_ref::<AwsError>() {
+        Some(e) if e.status == 404 || e.body.contains("NotFound") => {
+            "does not exist".to_owned()
+        }
+        Some(e) if e.status == 403 => "access denied".to_owned(),
+        _ => error.to_string(),
+    }
+}
+
+/** Test module for configuration checks */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_address() {
+        assert!(is_address("achu@fufu.soup"));
+        assert!(is_address("Fufu Corn <achu@fufu-corn.soup>"));
+        assert!(!is_address("achu"));
+        assert!(!is_address("achu@localhost"));
+        assert!(!is_address("achu@fufu..soup"));
+        assert!(!is_address("a chu@fufu.soup"));
+        assert!(!is_address("Fufu <achu@fufu.soup"));
+    }
+
+    #[test]
+    fn test_problems() {
+        assert!(problems(&PrivatEmailConfig::default()).is_empty());
+
+        let config = PrivatEmailConfig {
+            from_email: String::new(),
+            to_email: "nobody".into(),
+            relay_alias: Some("relay@fufu.soup".into()),
+            max_send_size: 0,
+            max_content_size: 0,
+            webhook_url: Some("http://hooks.fufu.soup".into()),
+            quota_defer_percent: Some(120),
+            rules: serde_json::from_str(
+                r#"[{"body_regex": "(", "action": "drop"}]"#,
+            )
+            .unwrap(),
+            ..PrivatEmailConfig::default()
+        };
+        assert_eq!(
+            problems(&config),
+            [
+                "from_email is not set",
+                "to_email is not an address: nobody",
+                "body_regex of rule #1 is invalid: (",
+                "max_send_size is 0",
+                "max_content_size is 0",
+                "webhook_url is not an HTTPS URL",
+                "quota_defer_percent is not between 1 and 100: 120",
+                "quota_defer_percent needs s3_bucket or local_store_dir",
+                "relay_alias and relay_secret must be set together",
+            ]
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Parsing of received mail.
+//!
+//! The content of received mail is whatever the sender made it: truncated
+//! boundaries, headers without names, invalid base64 or thousands of
+//! nested multiparts, which overflow the stack of a recursive parser. The
+//! handler parses it through [`parse`] and takes the forwarded body from
+//! [`html_body`], so every such message fails with a [`ContentError`] and
+//! is quarantined, counted under [`MALFORMED_COUNTER`], instead of
+//! crashing the function and being retried.
+use crate::html;
+use mailparse::{DispositionType, ParsedMail};
+
+/// Most header fields of a received message.
+pub const MAX_HEADERS: usize = 1000;
+
+/// Most multipart bodies of a received message, which bounds their
+/// nesting.
+pub const MAX_MULTIPARTS: usize = 256;
+
+/// Counter of messages quarantined for malformed content.
+pub const MALFORMED_COUNTER: &str = "malformed";
+
+/// Error parsing received mail.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ContentError {
+    /// The message is not MIME
+    Unparsable(String),
+
+    /// The message has more header fields than [`MAX_HEADERS`]
+    TooManyHeaders(usize),
+
+    /// The message has more multipart bodies than [`MAX_MULTIPARTS`]
+    TooManyParts(usize),
+
+    /// The forwarded body does not decode, e.g. from invalid base64
+    Undecodable(String),
+}
+
+impl std::fmt::Display for ContentError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContentError::Unparsable(e) => {
+                write!(f, "unparsable message: {}", e)
+            }
+            ContentError::TooManyHeaders(count) => write!(
+                f,
+                "{} header fields exceed the maximum of {}",
+                count, MAX_HEADERS
+            ),
+            ContentError::TooManyParts(count) => write!(
+                f,
+                "{} multipart bodies exceed the maximum of {}",
+                count, MAX_MULTIPARTS
+            ),
+            ContentError::Undecodable(e) => {
+                write!(f, "undecodable body: {}", e)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ContentError {}
+
+/// Counts the multipart `Content-Type` fields of `raw`, headers of parts
+/// and attached messages included.
+fn count_multiparts(raw: &[u8]) -> usize {
+    const FIELD: &[u8] = b"content-type:";
+    const MULTIPART: &[u8] = b"multipart/";
+    raw.split(|&b| b == b'\n')
+        .filter(|line| {
+            line.get(..FIELD.len())
+                .is_some_and(|name| name.eq_ignore_ascii_case(FIELD))
+                && line
+                    .windows(MULTIPART.len())
+                    .any(|window| window.eq_ignore_ascii_case(MULTIPART))
+        })
+        .count()
+}
+
+/// Parses the received message `raw`, bounding its headers and parts.
+pub fn parse(raw: &[u8]) -> Result<ParsedMail<'_>, ContentError> {
+    // Bound the nesting before the recursive parser descends into it
+    let multiparts = count_multiparts(raw);
+    if multiparts > MAX_MULTIPARTS {
+        return Err(ContentError::TooManyParts(multiparts));
+    }
+    let mail = mailparse::parse_mail(raw)
+        .map_err(|e| ContentError::Unparsable(e.to_string()))?;
+    if mail.headers.len() > MAX_HEADERS {
+        return Err(ContentError::TooManyHeaders(mail.headers.len()));
+    }
+    // Without its boundary, a multipart body has no part to forward
+    if mail.ctype.mimetype.starts_with("multipart/") && mail.subparts.is_empty()
+    {
+        let e = "multipart body without parts".to_owned();
+        return Err(ContentError::Unparsable(e));
+    }
+    Ok(mail)
+}
+
+/// Returns the HTML body forwarded for `mail`: its first inline
+/// `text/html` part, decoded from its charset, otherwise its text rendered
+/// as HTML.
+pub fn html_body(mail: &ParsedMail) -> Result<String, ContentError> {
+    let inline = |mimetype: &str| {
+        mail.parts().find(|part| {
+            part.ctype.mimetype == mimetype
+                && part.get_content_disposition().disposition
+                    == DispositionType::Inline
+        })
+    };
+    let decode = |part: &ParsedMail| {
+        part.get_body().map_err(|e| ContentError::Undecodable(e.to_string()))
+    };
+    if let Some(part) = inline("text/html") {
+        return decode(part);
+    }
+    let text = match inline("text/plain") {
+        Some(part) => decode(part)?,
+        None => String::new(),
+    };
+    Ok(format!("<pre>{}</pre>", html::escape(&text)))
+}
+
+/** Test module for the parsing of received mail */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_malformed() {
+        let nested = |depth| {
+            let mut raw: String = (0..depth)
+                .map(|i| {
+                    format!(
+                        "Content-Type: multipart/mixed; boundary=\"b{}\"\r\n\
+                         \r\n--b{}\r\n",
+                        i, i
+                    )
+                })
+                .collect();
+            raw.push_str("Content-Type: text/plain\r\n\r\nhi\r\n");
+            raw
+        };
+        let deepest = nested(MAX_MULTIPARTS);
+        assert!(parse(deepest.as_bytes()).is_ok());
+        let deeper = nested(MAX_MULTIPARTS + 1);
+        assert_eq!(
+            parse(deeper.as_bytes()).unwrap_err(),
+            ContentError::TooManyParts(MAX_MULTIPARTS + 1)
+        );
+
+        let headers: String =
+            (0..=MAX_HEADERS).map(|i| format!("X-H{}: v\r\n", i)).collect();
+        let raw = format!("{}\r\nbody", headers);
+        assert_eq!(
+            parse(raw.as_bytes()).unwrap_err(),
+            ContentError::TooManyHeaders(MAX_HEADERS + 1)
+        );
+
+        let empty = "Content-Type: multipart/mixed; boundary=\"x\"\r\n\r\n";
+        assert!(matches!(
+            parse(empty.as_bytes()),
+            Err(ContentError::Unparsable(_))
+        ));
+    }
+
+    #[test]
+    fn test_html_body() {
+        let truncated = "From: fufu@achu.soup\r\n\
+                         Content-Type: multipart/mixed; boundary=\"x\"\r\n\r\n\
+                         --x\r\nContent-Type: text/html\r\n\
+                         Content-Transfer-Encoding: base64\r\n\r\nPGI+aG";
+        let mail = parse(truncated.as_bytes()).unwrap();
+        assert!(matches!(html_body(&mail), Err(ContentError::Undecodable(_))));
+
+        let plain = "From: fufu@achu.soup\r\n\r\nFufu <&> eru\r\n";
+        let mail = parse(plain.as_bytes()).unwrap();
+        assert_eq!(
+            html_body(&mail).unwrap(),
+            "<pre>Fufu &lt;&amp;&gt; eru\r\n</pre>"
+        );
+
+        let attachment_only = "From: fufu@achu.soup\r\n\
+                               Content-Type: application/pdf\r\n\r\n%PDF";
+        let mail = parse(attachment_only.as_bytes()).unwrap();
+        assert_eq!(html_body(&mail).unwrap(), "<pre></pre>");
+    }
+}
@@ -0,0 +1,177 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Dead letters of events which could not be handled, and their redrive.
+//!
+//! A [`DeadLetter`] keeps the raw Lambda event with the error it failed
+//! with under `<failed_prefix><message id>.json` of the store, next to the
+//! raw message in `.eml`. Send failures under the `dead-letter` policy are
+//! written there, as is every failed invocation when `dead_letter_errors`
+//! is set, which then succeeds instead of being retried by Lambda.
+//! [`redrive`], run by `privatemail-redrive`, replays the events through
+//! a [`PrivatEmailService`] and deletes the letters of those handled.
+use crate::failure::FailureClass;
+use crate::store::ObjectStore;
+use crate::PrivatEmailService;
+use bytes::Bytes;
+use lambda_runtime::{Context, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{error, info, warn};
+
+/// Event which could not be handled and why.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetter {
+    /// SES message id of the event, or the request id without one
+    pub message_id: String,
+
+    /// Class of the failed send, if a send failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_class: Option<FailureClass>,
+
+    /// Description of the error
+    pub error: String,
+
+    /// Unix time of the failure
+    #[serde(default)]
+    pub failed_at: i64,
+
+    /// Raw Lambda event, replayed on redrive
+    #[serde(default)]
+    pub event: Value,
+}
+
+/// Letters replayed by a redrive.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Redrive {
+    /// Keys of the letters handled and deleted
+    pub handled: Vec<String>,
+
+    /// Keys of the letters which failed again, or can't be replayed
+    pub failed: Vec<String>,
+}
+
+/// Returns the key of the letter of `message_id`, without extension.
+pub fn key(prefix: &str, message_id: &str) -> String {
+    format!("{}{}", prefix, message_id)
+}
+
+impl DeadLetter {
+    /// Writes the letter, and the raw message `content` when known, under
+    /// `prefix`, returning its key.
+    pub async fn write(
+        &self,
+        objects: &dyn ObjectStore,
+        prefix: &str,
+        content: Option<Bytes>,
+    ) -> Result<String, Error> {
+        let key = key(prefix, &self.message_id);
+        if let Some(content) = content {
+            objects.put(&format!("{}.eml", key), content).await?;
+        }
+        objects
+            .put(&format!("{}.json", key), serde_json::to_vec(self)?.into())
+            .await?;
+        Ok(key)
+    }
+}
+
+/// Replays the letters in the store of `service` through it, only those of
+/// `message_ids` when any are given. Letters whose replay is answered
+/// with a success status are deleted.
+pub async fn redrive(
+    service: &PrivatEmailService,
+    message_ids: &[String],
+) -> Result<Redrive, Error> {
+    let Some(mail_store) = &service.mail_store else {
+        return Err("No store configured for dead letters".into());
+    };
+    let objects = &*mail_store.objects;
+    let email_config = service.config();
+    let prefix = email_config.failed_prefix.as_str();
+    let mut redrive = Redrive::default();
+    for object in objects.list(prefix).await? {
+        let Some(key) = object.strip_suffix(".json") else {
+            continue;
+        };
+        let message_id = &key[prefix.len()..];
+        if !message_ids.is_empty()
+            && !message_ids.iter().any(|m| m == message_id)
+        {
+            continue;
+        }
+        let letter = match objects.get(&object).await? {
+            Some(body) => serde_json::from_slice::<DeadLetter>(&body)?,
+            None => continue,
+        };
+        if letter.event.is_null() {
+            warn!("{} holds no event, skipping", object);
+            redrive.failed.push(key.to_owned());
+            continue;
+        }
+
+        let mut context = Context::default();
+        context.request_id = format!("redrive-{}", message_id);
+        context.xray_trace_id = Some(context.request_id.clone());
+        match service.handle(LambdaEvent::new(letter.event, context)).await {
+            Ok(response) if response.status_code() < 300 => {
+                info!("Redrove {}: {}", key, response);
+                objects.delete(&format!("{}.eml", key)).await?;
+                objects.delete(&object).await?;
+                redrive.handled.push(key.to_owned());
+            }
+            Ok(response) => {
+                error!("Redrive of {} failed: {}", key, response);
+                redrive.failed.push(key.to_owned());
+            }
+            Err(e) => {
+                error!("Redrive of {} failed: {:?}", key, e);
+                redrive.failed.push(key.to_owned());
+            }
+        }
+    }
+    Ok(redrive)
+}
+
+/** Test module for dead letters */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::fs::FsObjectStore;
+
+    #[tokio::test]
+    async fn test_write() {
+        let dir = std::env::temp_dir()
+            .join(format!("privatemail-deadletter-{}", std::process::id()));
+        let objects = FsObjectStore::new(&dir).unwrap();
+        let letter = DeadLetter {
+            message_id: "0100abc".to_owned(),
+            failure_class: Some(FailureClass::Size),
+            error: "Message too long".to_owned(),
+            failed_at: 1_616_143_576,
+            event: serde_json::json!({ "Records": [] }),
+        };
+        let key = letter
+            .write(&objects, "failed/", Some("Subject: Hi\r\n\r\nhello".into()))
+            .await
+            .unwrap();
+        assert_eq!(key, "failed/0100abc");
+        assert_eq!(
+            objects.list("failed/").await.unwrap(),
+            ["failed/0100abc.eml", "failed/0100abc.json"]
+        );
+        let stored: DeadLetter = serde_json::from_slice(
+            &objects.get("failed/0100abc.json").await.unwrap().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(stored, letter);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,217 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Digest entries and rendering.
+//!
+//! Messages matched by a `digest` rule are not forwarded: the raw message
+//! is kept under [`DIGEST_PREFIX`] with a [`DigestEntry`] beside it. A
+//! daily EventBridge schedule invoking the function with
+//! `{"digest": true}` sends the pending entries as a single email, linking
+//! to the raw messages, and clears them.
+use crate::store::{MailRecord, DIGEST_PREFIX};
+use crate::summary::{self, Summarizer};
+use crate::thread;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Longest wait for a model summary of a digested message.
+pub const SUMMARY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Validity of the links to the raw messages, the longest S3 allows.
+pub const LINK_EXPIRY: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Returns the key of the pending entry of `message_id`.
+pub fn entry_key(message_id: &str) -> String {
+    format!("{}{}.json", DIGEST_PREFIX, message_id)
+}
+
+/// Returns the key of the raw message of `message_id`, as tracked.
+pub fn message_key(message_id: &str) -> String {
+    format!("{}{}.eml", DIGEST_PREFIX, message_id)
+}
+
+/// Returns true when `key` is a pending entry.
+pub fn is_entry_key(key: &str) -> bool {
+    key.starts_with(DIGEST_PREFIX) && key.ends_with(".json")
+}
+
+/// Returns the subject of a digest of `count` messages.
+pub fn subject(count: usize) -> String {
+    let noun = if count == 1 { "message" } else { "messages" };
+    format!("Digest: {} new {}", count, noun)
+}
+
+/// One message listed in a digest.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DigestEntry {
+    /// SES message id
+    pub message_id: String,
+
+    /// Time the message was received
+    pub timestamp: String,
+
+    /// Original sender address
+    pub sender: String,
+
+    /// Message subject
+    pub subject: String,
+
+    /// First line or summary of the body
+    pub summary: String,
+
+    /// Conversation the message belongs to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+
+    /// Link to the raw message, added when the digest is sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+impl DigestEntry {
+    /// Builds the entry for `record` with HTML `body`, summarizing long
+    /// bodies when `summaries` is set and showing the first line otherwise.
+    pub async fn new(
+        record: &MailRecord,
+        body: &str,
+        summaries: bool,
+        summarizer: Option<&dyn Summarizer>,
+        timeout: Duration,
+    ) -> Self {
+        let text = summary::plain_text(body);
+        let summary = if summaries {
+            summary::summarize(summarizer, &text, timeout).await
+        } else {
+            summary::first_line(&text)
+        };
+        DigestEntry {
+            message_id: record.message_id.clone(),
+            timestamp: record.timestamp.clone(),
+            sender: record.source.clone(),
+            subject: record.subject.clone(),
+            summary,
+            thread_id: record.thread_id.clone(),
+            link: None,
+        }
+    }
+}
+
+/// Groups `entries` by conversation, keeping the order in which each
+/// thread first appears. Entries without a thread id stand alone.
+pub fn group(entries: &[DigestEntry]) -> Vec<Vec<&DigestEntry>> {
+    let mut groups: Vec<Vec<&DigestEntry>> = Vec::new();
+    for entry in entries {
+        let existing = entry.thread_id.as_ref().and_then(|thread_id| {
+            groups
+                .iter_mut()
+                .find(|g| g[0].thread_id.as_ref() == Some(thread_id))
+        });
+        match existing {
+            Some(group) => group.push(entry),
+            None => groups.push(vec![entry]),
+        }
+    }
+    groups
+}
+
+/// Renders `entries` as the plain text body of a digest email, listing
+/// conversations as one block.
+pub fn render_text(entries: &[DigestEntry]) -> String {
+    let noun = if entries.len() == 1 { "message" } else { "messages" };
+    let mut text = format!("{} new {}\r\n", entries.len(), noun);
+    for group in group(entries) {
+        if group.len() > 1 {
+            text.push_str(&format!(
+                "\r\n{} new messages in thread {}\r\n",
+                group.len(),
+                thread::normalize_subject(&group[0].subject)
+            ));
+            for entry in group {
+                text.push_str(&format!(
+                    "  {}: {}\r\n",
+                    entry.sender, entry.summary
+                ));
+                if let Some(link) = &entry.link {
+                    text.push_str(&format!("  {}\r\n", link));
+                }
+            }
+        } else {
+            let entry = group[0];
+            text.push_str(&format!(
+                "\r\n{} - {}\r\n{}\r\n",
+                entry.sender, entry.subject, entry.summary
+            ));
+            if let Some(link) = &entry.link {
+                text.push_str(&format!("{}\r\n", link));
+            }
+        }
+    }
+    text
+}
+
+/** Test module for digests */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MailAction;
+
+    #[tokio::test]
+    async fn test_digest_entry() {
+        let record = MailRecord {
+            message_id: "a".to_owned(),
+            timestamp: "2021-03-19T08:46:16.420Z".to_owned(),
+            source: "news@letter.test".to_owned(),
+            destination: vec!["hello@nyah.dev".to_owned()],
+            subject: "Weekly".to_owned(),
+            action: MailAction::Forwarded,
+            object_key: None,
+            thread_id: None,
+        };
+        let body = "<p>This week in Rust</p><p>More news</p>";
+        let mut entry =
+            DigestEntry::new(&record, body, true, None, Duration::ZERO).await;
+        assert_eq!(entry.summary, "This week in Rust");
+        assert_eq!(
+            render_text(&[entry.clone()]),
+            "1 new message\r\n\r\n\
+             news@letter.test - Weekly\r\nThis week in Rust\r\n"
+        );
+        entry.link = Some("https://s3.test/digest/a.eml".to_owned());
+        assert!(render_text(&[entry]).ends_with(
+            "This week in Rust\r\nhttps://s3.test/digest/a.eml\r\n"
+        ));
+        assert_eq!(subject(2), "Digest: 2 new messages");
+        assert!(is_entry_key(&entry_key("a")));
+        assert!(!is_entry_key(&message_key("a")));
+    }
+
+    #[test]
+    fn test_render_threads() {
+        let entry = |sender: &str, subject: &str, thread_id: Option<&str>| {
+            DigestEntry {
+                message_id: sender.to_owned(),
+                timestamp: "2021-03-19T08:46:16.420Z".to_owned(),
+                sender: sender.to_owned(),
+                subject: subject.to_owned(),
+                summary: "…".to_owned(),
+                thread_id: thread_id.map(str::to_owned),
+                link: None,
+            }
+        };
+        let entries = [
+            entry("a@a.test", "Lunch", Some("<1@a.test>")),
+            entry("b@b.test", "Weekly", None),
+            entry("c@c.test", "Re: Lunch", Some("<1@a.test>")),
+        ];
+        assert_eq!(group(&entries).len(), 2);
+        assert!(render_text(&entries)
+            .contains("2 new messages in thread Lunch\r\n  a@a.test: …"));
+    }
+}
@@ -0,0 +1,294 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! DKIM signing (RFC 6376) of messages sent through the raw send path.
+//!
+//! Rebuilt messages no longer carry a valid signature from the original
+//! sender, so they are signed with the forwarding domain's key using
+//! `rsa-sha256` and `relaxed/relaxed` canonicalization.
+use crate::aws;
+use crate::config::PrivatEmailConfig;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_runtime::Error;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Headers signed when present, in signing order.
+pub const SIGNED_HEADERS: [&str; 9] = [
+    "from",
+    "to",
+    "subject",
+    "reply-to",
+    "in-reply-to",
+    "references",
+    "mime-version",
+    "content-type",
+    "content-transfer-encoding",
+];
+
+/// Signs outgoing messages for one domain and selector.
+#[derive(Clone, Debug)]
+pub struct DkimSigner {
+    domain: String,
+    selector: String,
+    key: SigningKey<Sha256>,
+}
+
+impl DkimSigner {
+    /// Creates a signer from a PKCS#8 or PKCS#1 PEM encoded RSA key.
+    pub fn from_pem(
+        domain: &str,
+        selector: &str,
+        pem: &str,
+    ) -> Result<Self, Error> {
+        // Keys passed through environment variables often have escaped
+        // newlines
+        let pem = pem.trim().replace("\\n", "\n");
+        let key = RsaPrivateKey::from_pkcs8_pem(&pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(&pem))
+            .map_err(|e| format!("Invalid DKIM private key: {}", e))?;
+        Ok(DkimSigner {
+            domain: domain.to_owned(),
+            selector: selector.to_owned(),
+            key: SigningKey::new(key),
+        })
+    }
+
+    /// Returns the signer configured in `config`, reading the key from
+    /// `dkim_private_key` or else from the Secrets Manager secret
+    /// `dkim_key_secret`. Signing is off unless domain and selector are set.
+    pub async fn from_config(
+        config: &PrivatEmailConfig,
+    ) -> Result<Option<Self>, Error> {
+        let (Some(domain), Some(selector)) =
+            (&config.dkim_domain, &config.dkim_selector)
+        else {
+            return Ok(None);
+        };
+        let pem = match (&config.dkim_private_key, &config.dkim_key_secret) {
+            (Some(pem), _) => pem.clone(),
+            (None, Some(secret_id)) => {
+                aws::get_secret_string(secret_id).await?
+            }
+            (None, None) => {
+                return Err("DKIM signing needs a private key".into())
+            }
+        };
+        Self::from_pem(domain, selector, &pem).map(Some)
+    }
+
+    /// Returns `raw` with a `DKIM-Signature` header prepended.
+    pub fn sign(&self, raw: &[u8]) -> Result<Vec<u8>, Error> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.sign_at(raw, timestamp)
+    }
+
+    fn sign_at(&self, raw: &[u8], timestamp: u64) -> Result<Vec<u8>, Error> {
+        let (headers, body) = split_message(raw);
+        let headers = parse_header_fields(headers);
+
+        // The last instance of each header is signed, see RFC 6376 5.4.2
+        let mut signed = Vec::new();
+        let mut canonical = Vec::new();
+        for name in SIGNED_HEADERS {
+            if let Some((field, value)) = headers
+                .iter()
+                .rev()
+                .find(|(field, _)| field.eq_ignore_ascii_case(name))
+            {
+                signed.push(name);
+                canonical.extend(relaxed_header(field, value));
+                canonical.extend_from_slice(b"\r\n");
+            }
+        }
+        if !signed.contains(&"from") {
+            return Err("Cannot DKIM sign a message without From".into());
+        }
+
+        let body_hash = STANDARD.encode(Sha256::digest(relaxed_body(body)));
+        let unsigned = format!(
+            "v=1; a=rsa-sha256; c=relaxed/relaxed; d={}; s={}; t={}; h={}; \
+             bh={}; b=",
+            self.domain,
+            self.selector,
+            timestamp,
+            signed.join(":"),
+            body_hash
+        );
+        canonical.extend(relaxed_header("DKIM-Signature", unsigned.as_bytes()));
+        let signature = STANDARD.encode(self.key.sign(&canonical).to_bytes());
+
+        let mut out = format!("DKIM-Signature: {}{}\r\n", unsigned, signature)
+            .into_bytes();
+        out.extend_from_slice(raw);
+        Ok(out)
+    }
+}
+
+/// Splits a message into its header block and body.
+fn split_message(raw: &[u8]) -> (&[u8], &[u8]) {
+    match raw.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(at) => (&raw[..at + 2], &raw[at + 4..]),
+        None => (raw, &[]),
+    }
+}
+
+/// Parses a header block into `(name, value)` pairs, keeping the folding
+/// and the bytes of values.
+fn parse_header_fields(headers: &[u8]) -> Vec<(String, Vec<u8>)> {
+    let mut fields: Vec<(String, Vec<u8>)> = Vec::new();
+    for line in split_lines(headers).into_iter().filter(|l| !l.is_empty()) {
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            if let Some((_, value)) = fields.last_mut() {
+                value.extend_from_slice(b"\r\n");
+                value.extend_from_slice(line);
+            }
+        } else if let Some(colon) = line.iter().position(|&b| b == b':') {
+            let name = String::from_utf8_lossy(&line[..colon]).into_owned();
+            fields.push((name, line[colon + 1..].to_vec()));
+        }
+    }
+    fields
+}
+
+/// Splits `data` at each CRLF.
+fn split_lines(data: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut rest = data;
+    while let Some(at) = rest.windows(2).position(|w| w == b"\r\n") {
+        lines.push(&rest[..at]);
+        rest = &rest[at + 2..];
+    }
+    lines.push(rest);
+    lines
+}
+
+/// Reduces each run of WSP (space or tab) to a single space and drops
+/// trailing WSP, leaving every other byte as is.
+fn reduce_whitespace(value: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(value.len());
+    let mut in_space = false;
+    for &b in value {
+        if b == b' ' || b == b'\t' {
+            in_space = true;
+            continue;
+        }
+        if in_space {
+            out.push(b' ');
+            in_space = false;
+        }
+        out.push(b);
+    }
+    out
+}
+
+/// Relaxed header canonicalization, RFC 6376 3.4.2.
+fn relaxed_header(name: &str, value: &[u8]) -> Vec<u8> {
+    let unfolded: Vec<u8> =
+        value.iter().copied().filter(|&b| b != b'\r' && b != b'\n').collect();
+    let value = reduce_whitespace(&unfolded);
+    let mut out = name.trim().to_lowercase().into_bytes();
+    out.push(b':');
+    out.extend_from_slice(value.strip_prefix(b" ").unwrap_or(&value));
+    out
+}
+
+/// Relaxed body canonicalization, RFC 6376 3.4.4.
+fn relaxed_body(body: &[u8]) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> =
+        split_lines(body).into_iter().map(reduce_whitespace).collect();
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    let mut out = Vec::with_capacity(body.len());
+    for line in lines {
+        out.extend_from_slice(&line);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/** Test module for DKIM signing */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::signature::Verifier;
+
+    const KEY: &str = include_str!("../tests/keys/dkim_test.pem");
+
+    #[test]
+    fn test_relaxed_canonicalization() {
+        assert_eq!(
+            relaxed_header("Subject ", b" Hello \r\n\t world  "),
+            b"subject:Hello world"
+        );
+        assert_eq!(relaxed_body(b"a  b \t\r\nc\r\n\r\n\r\n"), b"a b\r\nc\r\n");
+        assert_eq!(relaxed_body(b""), b"");
+        assert_eq!(
+            relaxed_header("Subject", b" Caf\xe9 \x85 au lait"),
+            b"subject:Caf\xe9 \x85 au lait"
+        );
+    }
+
+    #[test]
+    fn test_dkim_sign() {
+        let signer = DkimSigner::from_pem("nyah.dev", "mail", KEY).unwrap();
+        let raw = b"From: hello@nyah.dev\r\nTo: me@gmail.test\r\n\
+                    Subject: Hi\r\n\r\nHello  there\r\n";
+        let signed = signer.sign_at(raw, 1_600_000_000).unwrap();
+        let signed = String::from_utf8(signed).unwrap();
+        let (header, rest) = signed.split_once("\r\n").unwrap();
+        assert_eq!(rest.as_bytes(), raw);
+        assert!(header.contains("d=nyah.dev; s=mail; t=1600000000;"));
+        assert!(header.contains("h=from:to:subject;"));
+
+        // Verify the signature over the canonical headers
+        let (unsigned, signature) = header.split_once("b=").unwrap();
+        let unsigned = unsigned.trim_start_matches("DKIM-Signature: ");
+        let mut canonical =
+            b"from:hello@nyah.dev\r\nto:me@gmail.test\r\nsubject:Hi\r\n"
+                .to_vec();
+        canonical.extend(relaxed_header(
+            "DKIM-Signature",
+            format!("{}b=", unsigned).as_bytes(),
+        ));
+        let key = RsaPrivateKey::from_pkcs8_pem(KEY).unwrap();
+        let verifier = VerifyingKey::<Sha256>::new(key.to_public_key());
+        let signature =
+            Signature::try_from(STANDARD.decode(signature).unwrap().as_slice())
+                .unwrap();
+        assert!(verifier.verify(&canonical, &signature).is_ok());
+
+        assert!(signer.sign_at(b"To: x@y.z\r\n\r\n", 0).is_err());
+    }
+
+    #[test]
+    fn test_dkim_sign_8bit_body() {
+        let signer = DkimSigner::from_pem("nyah.dev", "mail", KEY).unwrap();
+        let body = b"Caf\xe9  cr\xe8me \t\r\n\xff\xfe\r\n\r\n";
+        let mut raw = b"From: hello@nyah.dev\r\nContent-Type: text/plain; \
+                        charset=iso-8859-1\r\nContent-Transfer-Encoding: \
+                        8bit\r\n\r\n"
+            .to_vec();
+        raw.extend_from_slice(body);
+        let signed = signer.sign_at(&raw, 1_600_000_000).unwrap();
+        let canonical = b"Caf\xe9 cr\xe8me\r\n\xff\xfe\r\n";
+        assert_eq!(relaxed_body(body), canonical);
+        let body_hash = STANDARD.encode(Sha256::digest(canonical));
+        let header = String::from_utf8_lossy(&signed);
+        assert!(header.contains(&format!("bh={};", body_hash)));
+        assert!(signed.ends_with(body));
+    }
+}
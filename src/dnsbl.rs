@@ -0,0 +1,467 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! DNS blocklist lookups of the sending relay and domain.
+//!
+//! Each [`Blocklist`] of `dnsbl_lists` is a DNS zone answering with a
+//! `127.0.0.0/8` address for the names it lists: the reversed address of
+//! the relay which handed the message to SES, see [`received::relay`], for
+//! IP lists such as `zen.spamhaus.org`, and the host name that relay
+//! announced and the envelope sender domain for domain lists such as
+//! `dbl.spamhaus.org`. The scores of the lists naming the message add up;
+//! from `dnsbl_threshold` on, `dnsbl_policy` drops or tags it, on top of
+//! the SES verdicts.
+use crate::auth::VerdictPolicy;
+use crate::config::PrivatEmailConfig;
+use crate::received::{self, Hop};
+use crate::store::RecordStore;
+use async_trait::async_trait;
+use futures::future;
+use lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tracing::{error, warn};
+
+/// Header listing the blocklists naming a tagged forward, which is sent
+/// raw.
+pub const DNSBL_HEADER: &str = "X-PrivatEmail-DNSBL";
+
+/// Prefix of the subject of tagged forwards.
+pub const SUBJECT_TAG: &str = "[DNSBL]";
+
+/// Counter and metric name of messages reaching the threshold.
+pub const LISTED_COUNTER: &str = "dnsbl_listed";
+
+/// Longest wait for the answer of a blocklist.
+pub const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What a blocklist lists.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ListKind {
+    /// Addresses of sending hosts
+    #[default]
+    Ip,
+
+    /// Domain and host names
+    Domain,
+}
+
+/// DNS blocklist checked for received mail.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Blocklist {
+    /// DNS zone of the list, e.g. `zen.spamhaus.org`
+    pub zone: String,
+
+    /// Whether the list names addresses or domains
+    #[serde(default)]
+    pub kind: ListKind,
+
+    /// Score added when the list names the message
+    #[serde(default = "default_score")]
+    pub score: u32,
+}
+
+fn default_score() -> u32 {
+    1
+}
+
+impl std::str::FromStr for Blocklist {
+    type Err = String;
+
+    /// Parses `zone[:ip|:domain][=score]`, e.g. `dbl.spamhaus.org:domain=2`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid blocklist: {}", s);
+        let (list, score) = match s.split_once('=') {
+            Some((list, score)) => {
+                (list, score.trim().parse().map_err(|_| invalid())?)
+            }
+            None => (s, default_score()),
+        };
+        let (zone, kind) = match list.split_once(':') {
+            Some((zone, "ip")) => (zone, ListKind::Ip),
+            Some((zone, "domain")) => (zone, ListKind::Domain),
+            Some(_) => return Err(invalid()),
+            None => (list, ListKind::Ip),
+        };
+        let zone = zone.trim().trim_matches('.').to_lowercase();
+        if zone.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Blocklist { zone, kind, score })
+    }
+}
+
+/// A blocklist naming the message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Listing {
+    /// Zone of the list
+    pub zone: String,
+
+    /// Address or domain listed
+    pub listed: String,
+
+    /// Answer of the list, its return code
+    pub code: Ipv4Addr,
+
+    /// Score of the list
+    pub score: u32,
+}
+
+impl std::fmt::Display for Listing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={} ({})", self.zone, self.listed, self.code)
+    }
+}
+
+/// Resolves the names of blocklist queries.
+#[async_trait]
+pub trait Resolver: Send + Sync {
+    /// Returns the IPv4 addresses of `name`, none when it does not exist.
+    async fn lookup(&self, name: &str) -> Result<Vec<Ipv4Addr>, Error>;
+}
+
+/// [`Resolver`] of the system, as configured for the Lambda function.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+#[async_trait]
+impl Resolver for SystemResolver {
+    async fn lookup(&self, name: &str) -> Result<Vec<Ipv4Addr>, Error> {
+        // Names not listed do not exist, which the system resolver only
+        // tells as an error
+        let Ok(addrs) = tokio::net::lookup_host((name, 0)).await else {
+            return Ok(Vec::new());
+        };
+        Ok(addrs
+            .filter_map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(ip),
+                IpAddr::V6(_) => None,
+            })
+            .collect())
+    }
+}
+
+/// [`Resolver`] answering from a fixed table, for tests.
+#[derive(Clone, Debug, Default)]
+pub struct StaticResolver(BTreeMap<String, Vec<Ipv4Addr>>);
+
+impl StaticResolver {
+    /// Creates a resolver where no name exists.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the resolver answering `addr` for `name`.
+    pub fn with(mut self, name: &str, addr: Ipv4Addr) -> Self {
+        self.0.entry(name.to_lowercase()).or_default().push(addr);
+        self
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn lookup(&self, name: &str) -> Result<Vec<Ipv4Addr>, Error> {
+        Ok(self.0.get(&name.to_lowercase()).cloned().unwrap_or_default())
+    }
+}
+
+/// Returns the query of `ip` in `zone`, its octets or nibbles reversed,
+/// e.g. `2.0.0.127.zen.spamhaus.org`.
+pub fn ip_query(ip: &IpAddr, zone: &str) -> String {
+    let labels: Vec<String> = match ip {
+        IpAddr::V4(ip) => {
+            ip.octets().iter().rev().map(|o| o.to_string()).collect()
+        }
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|o| [o & 0x0F, o >> 4])
+            .map(|n| format!("{:x}", n))
+            .collect(),
+    };
+    format!("{}.{}", labels.join("."), zone)
+}
+
+/// Whether `code` lists the name queried. Lists answer within
+/// `127.0.0.0/8`, and with `127.255.255.0/24` when they refuse the query.
+fn is_listed(code: &Ipv4Addr) -> bool {
+    let [a, b, c, _] = code.octets();
+    a == 127 && (b, c) != (255, 255)
+}
+
+/// Returns the domains of the message looked up in domain lists: the host
+/// name announced by `relay` and the domain of the envelope `source`.
+pub fn domains(relay: Option<&Hop>, source: &str) -> Vec<String> {
+    let mut domains = Vec::new();
+    let candidates = [
+        relay.and_then(|hop| hop.from.as_deref()),
+        source.rsplit_once('@').map(|(_, domain)| domain),
+    ];
+    for domain in candidates.into_iter().flatten() {
+        let domain = domain.trim_matches(|c| c == '<' || c == '>' || c == '.');
+        let domain = domain.to_lowercase();
+        if domain.contains('.')
+            && domain.parse::<IpAddr>().is_err()
+            && !domains.contains(&domain)
+        {
+            domains.push(domain);
+        }
+    }
+    domains
+}
+
+/// Looks up the relay of `chain` and the domains of the message from
+/// `source` in `lists`, returning the listings found. Lists failing or
+/// not answering in time are skipped.
+pub async fn check(
+    resolver: &dyn Resolver,
+    lists: &[Blocklist],
+    chain: &[Hop],
+    source: &str,
+) -> Vec<Listing> {
+    let relay = received::relay(chain);
+    let ip = relay.and_then(|hop| hop.ip);
+    let domains = domains(relay, source);
+
+    let mut queries = Vec::new();
+    for list in lists {
+        match list.kind {
+            ListKind::Ip => {
+                queries.extend(ip.map(|ip| {
+                    (list, ip.to_string(), ip_query(&ip, &list.zone))
+                }))
+            }
+            ListKind::Domain => queries.extend(domains.iter().map(|domain| {
+                (list, domain.clone(), format!("{}.{}", domain, list.zone))
+            })),
+        }
+    }
+
+    let answers = future::join_all(queries.into_iter().map(
+        |(list, listed, query)| async move {
+            match tokio::time::timeout(LOOKUP_TIMEOUT, resolver.lookup(&query))
+                .await
+            {
+                Ok(Ok(codes)) => {
+                    codes.into_iter().find(is_listed).map(|code| Listing {
+                        zone: list.zone.clone(),
+                        listed,
+                        code,
+                        score: list.score,
+                    })
+                }
+                Ok(Err(e)) => {
+                    warn!("Error looking up {}: {:?}", query, e);
+                    None
+                }
+                Err(_) => {
+                    warn!("Lookup of {} timed out", query);
+                    None
+                }
+            }
+        },
+    ))
+    .await;
+    answers.into_iter().flatten().collect()
+}
+
+/// What `dnsbl_policy` makes of a message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    /// Forward the message as is
+    Pass,
+
+    /// Forward the message with [`SUBJECT_TAG`] and this [`DNSBL_HEADER`]
+    Tag(String),
+
+    /// Do not forward the message at all
+    Drop,
+}
+
+impl Verdict {
+    /// Returns the [`DNSBL_HEADER`] value of a tagged message.
+    pub fn header(&self) -> Option<&str> {
+        match self {
+            Verdict::Tag(header) => Some(header),
+            _ => None,
+        }
+    }
+}
+
+/// Looks the message of `message_id` up in the `dnsbl_lists` of
+/// `email_config`, see [`check`], and returns the verdict of its
+/// `dnsbl_policy`. Messages reaching `dnsbl_threshold` are logged and
+/// counted in `records`.
+pub async fn screen(
+    resolver: &dyn Resolver,
+    email_config: &PrivatEmailConfig,
+    chain: &[Hop],
+    source: &str,
+    message_id: &str,
+    records: Option<&dyn RecordStore>,
+) -> Verdict {
+    if email_config.dnsbl_lists.is_empty() {
+        return Verdict::Pass;
+    }
+    let listings =
+        check(resolver, &email_config.dnsbl_lists, chain, source).await;
+    if listings.is_empty() || score(&listings) < email_config.dnsbl_threshold {
+        return Verdict::Pass;
+    }
+
+    let header = header_value(&listings);
+    warn!(
+        message_id,
+        metric = LISTED_COUNTER,
+        "Listed in DNS blocklists: {}",
+        header
+    );
+    if let Some(records) = records {
+        if let Err(e) = records.increment(LISTED_COUNTER, 1).await {
+            error!("Error counting blocklisted mail: {:?}", e);
+        }
+    }
+    match email_config.dnsbl_policy {
+        VerdictPolicy::Drop => Verdict::Drop,
+        VerdictPolicy::Tag => Verdict::Tag(header),
+        VerdictPolicy::Forward => Verdict::Pass,
+    }
+}
+
+/// Returns the total score of `listings`.
+pub fn score(listings: &[Listing]) -> u32 {
+    listings.iter().map(|listing| listing.score).sum()
+}
+
+/// Returns the value of the [`DNSBL_HEADER`] for `listings`, e.g.
+/// `zen.spamhaus.org=203.0.113.7 (127.0.0.2)`.
+pub fn header_value(listings: &[Listing]) -> String {
+    listings
+        .iter()
+        .map(|listing| listing.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/** Test module for DNS blocklist lookups */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocklist_from_str() {
+        assert_eq!(
+            "zen.spamhaus.org".parse(),
+            Ok(Blocklist {
+                zone: "zen.spamhaus.org".to_owned(),
+                kind: ListKind::Ip,
+                score: 1,
+            })
+        );
+        assert_eq!(
+            " dbl.spamhaus.org.:domain=3".parse(),
+            Ok(Blocklist {
+                zone: "dbl.spamhaus.org".to_owned(),
+                kind: ListKind::Domain,
+                score: 3,
+            })
+        );
+        assert!("dbl.spamhaus.org:host".parse::<Blocklist>().is_err());
+        assert!("zen.spamhaus.org=high".parse::<Blocklist>().is_err());
+        assert!(":ip=1".parse::<Blocklist>().is_err());
+    }
+
+    #[test]
+    fn test_ip_query() {
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        assert_eq!(
+            ip_query(&ip, "zen.spamhaus.org"),
+            "7.113.0.203.zen.spamhaus.org"
+        );
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(
+            ip_query(&ip, "dnsbl.fufu.soup"),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.\
+             0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.dnsbl.fufu.soup"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        // Topmost header first, as relays prepend them
+        let raw = "Received: from mail.achu.soup ([203.0.113.7]) \
+                   by inbound-smtp.us-east-1.amazonaws.com\r\n\
+                   Received: from laptop ([192.168.1.20]) by mail.achu.soup\r\n\
+                   \r\n";
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        let chain = received::chain(&headers);
+        let lists = [
+            "zen.spamhaus.org=2".parse().unwrap(),
+            "dbl.spamhaus.org:domain".parse().unwrap(),
+            "bl.fufu.soup".parse().unwrap(),
+        ];
+        let resolver = StaticResolver::new()
+            .with("7.113.0.203.zen.spamhaus.org", Ipv4Addr::new(127, 0, 0, 2))
+            .with("achu.soup.dbl.spamhaus.org", Ipv4Addr::new(127, 0, 1, 2))
+            .with(
+                "7.113.0.203.bl.fufu.soup",
+                Ipv4Addr::new(127, 255, 255, 254),
+            );
+        let listings = check(&resolver, &lists, &chain, "fufu@achu.soup").await;
+        assert_eq!(listings.len(), 2, "{:?}", listings);
+        assert_eq!(score(&listings), 3);
+        assert_eq!(
+            header_value(&listings),
+            "zen.spamhaus.org=203.0.113.7 (127.0.0.2), \
+             dbl.spamhaus.org=achu.soup (127.0.1.2)"
+        );
+        assert!(check(&resolver, &lists, &[], "").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_screen() {
+        let resolver = StaticResolver::new()
+            .with("achu.soup.dbl.spamhaus.org", Ipv4Addr::new(127, 0, 1, 2));
+        let records = crate::store::MemoryRecordStore::default();
+        let screen = |threshold, policy| {
+            let email_config = PrivatEmailConfig::builder()
+                .dnsbl_list("dbl.spamhaus.org:domain".parse().unwrap())
+                .dnsbl_policy(threshold, policy)
+                .build();
+            let records = &records;
+            let resolver = &resolver;
+            async move {
+                screen(
+                    resolver,
+                    &email_config,
+                    &[],
+                    "fufu@achu.soup",
+                    "rjq1eo6jf3qqff76",
+                    Some(records),
+                )
+                .await
+            }
+        };
+
+        assert_eq!(
+            screen(1, VerdictPolicy::Tag).await,
+            Verdict::Tag("dbl.spamhaus.org=achu.soup (127.0.1.2)".to_owned())
+        );
+        assert_eq!(screen(1, VerdictPolicy::Drop).await, Verdict::Drop);
+        assert_eq!(screen(1, VerdictPolicy::Forward).await, Verdict::Pass);
+        assert_eq!(screen(2, VerdictPolicy::Drop).await, Verdict::Pass);
+        assert_eq!(records.counters().await.unwrap()[LISTED_COUNTER], 3);
+    }
+}
@@ -0,0 +1,255 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Dry runs of the handler.
+//!
+//! Once [`start`] is called, AWS requests are recorded instead of sent, and
+//! answered as by empty resources: objects and items are missing, writes
+//! succeed and sends get a fake message id. The SES client of
+//! [`Recorder::ses_client`] records its sends the same way, so a run shows
+//! what the handler would have sent.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::request::{
+    BufferedHttpResponse, DispatchSignedRequest, DispatchSignedRequestFuture,
+    HttpResponse,
+};
+use rusoto_core::signature::{SignedRequest, SignedRequestPayload};
+use rusoto_core::Region;
+use rusoto_ses::SesClient;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::config::PrivatEmailConfig;
+
+/// AWS request recorded by a dry run.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Call {
+    /// Signing name of the service, e.g. `s3`
+    pub service: String,
+
+    /// Operation, as named by [`crate::aws::operation`]
+    pub operation: String,
+
+    /// Path of the request
+    pub path: String,
+
+    /// Form parameters of query API requests, in order
+    pub params: Vec<(String, String)>,
+}
+
+impl Call {
+    /// Returns whether the call sends mail.
+    pub fn is_send(&self) -> bool {
+        matches!(self.operation.as_str(), "SendEmail" | "SendRawEmail")
+            || (self.service == "ses"
+                && self.path.ends_with("/outbound-emails"))
+    }
+
+    /// Returns the raw message of a `SendRawEmail` call, decoded when sent
+    /// in base64.
+    pub fn raw_message(&self) -> Option<String> {
+        let (_, data) =
+            self.params.iter().find(|(name, _)| name == "RawMessage.Data")?;
+        Some(match STANDARD.decode(data) {
+            Ok(raw) => String::from_utf8_lossy(&raw).into_owned(),
+            Err(_) => data.clone(),
+        })
+    }
+}
+
+/// Records the AWS requests of a dry run, answering them.
+#[derive(Clone, Debug, Default)]
+pub struct Recorder {
+    calls: Arc<Mutex<Vec<Call>>>,
+}
+
+/// Recorder of the process, once a dry run started.
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+/// Starts recording the AWS requests of the process instead of sending
+/// them. Returns the recorder.
+pub fn start() -> &'static Recorder {
+    RECORDER.get_or_init(Recorder::default)
+}
+
+/// Returns the recorder of the process, during a dry run.
+pub fn recorder() -> Option<&'static Recorder> {
+    RECORDER.get()
+}
+
+/// Returns `config` without the notifications and local store which dry
+/// runs would still reach, as they don't go through AWS.
+pub fn sandbox(mut config: PrivatEmailConfig) -> PrivatEmailConfig {
+    config.slack_webhook_url = None;
+    config.discord_webhooks.clear();
+    config.telegram_bot_token = None;
+    config.webhook_url = None;
+    config.pagerduty_routing_key = None;
+    config.opsgenie_api_key = None;
+    config.local_store_dir = None;
+    config
+}
+
+impl Recorder {
+    /// Returns the calls recorded so far, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns an SES client recording its requests here.
+    pub fn ses_client(&self, region: Region) -> SesClient {
+        let credentials = StaticProvider::new_minimal(
+            "dry-run".to_owned(),
+            "dry-run".to_owned(),
+        );
+        SesClient::new_with(self.clone(), credentials, region)
+    }
+
+    /// Records `request`, returning the response of empty resources.
+    pub fn respond(&self, request: &SignedRequest) -> BufferedHttpResponse {
+        let call = record(request);
+        let (status, body) = response(&call, self.calls().len() + 1);
+        self.calls.lock().unwrap().push(call);
+        BufferedHttpResponse {
+            status: status.try_into().unwrap(),
+            body: body.into(),
+            headers: Default::default(),
+        }
+    }
+}
+
+impl DispatchSignedRequest for Recorder {
+    fn dispatch(
+        &self,
+        request: SignedRequest,
+        _timeout: Option<Duration>,
+    ) -> DispatchSignedRequestFuture {
+        let response = self.respond(&request);
+        Box::pin(async move {
+            Ok(HttpResponse {
+                status: response.status,
+                body: response.body.to_vec().into(),
+                headers: response.headers,
+            })
+        })
+    }
+}
+
+/// Returns the call of `request`.
+fn record(request: &SignedRequest) -> Call {
+    let mut params: Vec<(String, String)> = request
+        .params
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone().unwrap_or_default()))
+        .collect();
+    let is_form = request.headers.get("content-type").is_none_or(|values| {
+        values.iter().any(|value| value.starts_with(b"application/x-www-form"))
+    });
+    if let Some(SignedRequestPayload::Buffer(payload)) = &request.payload {
+        let payload = String::from_utf8_lossy(payload);
+        let url = format!("http://localhost/?{}", payload);
+        match reqwest::Url::parse(&url) {
+            Ok(url) if is_form && payload.contains('=') => params.extend(
+                url.query_pairs()
+                    .map(|(name, value)| (name.into(), value.into())),
+            ),
+            _ => params.push(("payload".to_owned(), payload.into_owned())),
+        }
+    }
+    let operation = params
+        .iter()
+        .find(|(name, _)| name == "Action")
+        .map(|(_, action)| action.clone())
+        .unwrap_or_else(|| crate::aws::operation(request));
+    Call {
+        service: request.service.clone(),
+        operation,
+        path: request.path.clone(),
+        params,
+    }
+}
+
+/// Returns the status and body answering `call`, the `n`th of the run.
+fn response(call: &Call, n: usize) -> (u16, String) {
+    let message_id = format!("dry-run-{}", n);
+    match (call.service.as_str(), call.operation.as_str()) {
+        ("s3", "GetObject" | "HeadObject")
+            if call.params.iter().any(|(name, _)| name == "list-type") =>
+        {
+            (200, "<ListBucketResult></ListBucketResult>".to_owned())
+        }
+        ("s3", "GetObject" | "HeadObject") => (404, "NoSuchKey".to_owned()),
+        ("s3", _) => (200, String::new()),
+        ("email", action) => {
+            let result = match call.is_send() {
+                true => format!("<MessageId>{}</MessageId>", message_id),
+                false => String::new(),
+            };
+            let xml = format!(
+                "<{0}Response><{0}Result>{1}</{0}Result>\
+                 <ResponseMetadata/></{0}Response>",
+                action, result
+            );
+            (200, xml)
+        }
+        _ if call.is_send() => {
+            (200, format!("{{\"MessageId\":\"{}\"}}", message_id))
+        }
+        _ => (200, "{}".to_owned()),
+    }
+}
+
+/** Test module for dry runs */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_ses::{
+        Body, Content, Destination, Message, SendEmailRequest, Ses,
+    };
+
+    #[tokio::test]
+    async fn test_recorder() {
+        let recorder = Recorder::default();
+        let client = recorder.ses_client(Region::UsEast1);
+        let content =
+            |data: &str| Content { charset: None, data: data.to_owned() };
+        let response = client
+            .send_email(SendEmailRequest {
+                source: "fufu@achu.soup".to_owned(),
+                destination: Destination {
+                    to_addresses: Some(vec!["eru@nyah.dev".to_owned()]),
+                    ..Default::default()
+                },
+                message: Message {
+                    subject: content("Fufu & soup"),
+                    body: Body { text: Some(content("Hi")), html: None },
+                },
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(response.message_id, "dry-run-1");
+
+        let mut get = SignedRequest::new("GET", "s3", &Region::UsEast1, "/b/k");
+        assert_eq!(recorder.respond(&get).status.as_u16(), 404);
+        get.add_param("list-type", "2");
+        assert_eq!(recorder.respond(&get).status.as_u16(), 200);
+
+        let calls = recorder.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls[0].is_send());
+        let subject = calls[0]
+            .params
+            .iter()
+            .find(|(name, _)| name == "Message.Subject.Data");
+        assert_eq!(subject.unwrap().1, "Fufu & soup");
+        assert_eq!(calls[1].operation, "GetObject");
+    }
+}
@@ -0,0 +1,62 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Case-insensitive encodings for data embedded in address local parts.
+/// Lowercase RFC 4648 base32 alphabet; mail systems may lowercase local
+/// parts, so the encoding must not depend on case.
+pub const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Encodes `data` as unpadded lowercase base32.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in data {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Decodes unpadded base32, ignoring case.
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = BASE32.iter().position(|b| *b == c.to_ascii_lowercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/** Test module for encodings */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base32_round_trip() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"fooba"] {
+            let encoded = base32_encode(data);
+            assert_eq!(base32_decode(&encoded.to_uppercase()).unwrap(), data);
+        }
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+    }
+}
@@ -0,0 +1,224 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Erasure of the mail of a correspondent.
+//!
+//! Invoking the function directly with an erasure request deletes
+//! everything kept of the messages from a sender address, as privacy
+//! regulations like the GDPR require on request: their objects of the
+//! `archive_bucket`, their stored and quarantined copies with their
+//! records, and their entries of the `index_table`:
+//!
+//! ```json
+//! { "erase": { "sender": "fufu@achu.soup" } }
+//! ```
+//!
+//! The archive is found by reading the metadata of every archived message,
+//! so it works without index, and slowly on large archives.
+use crate::archive::Metadata;
+use crate::index;
+use crate::retention;
+use crate::store::{MailStore, ObjectStore};
+use lambda_runtime::Error;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Request to erase the mail of a sender.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErasureRequest {
+    /// Envelope sender address whose mail is erased
+    pub sender: String,
+}
+
+/// Report of an erasure.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Erasure {
+    /// Sender address whose mail was erased
+    pub sender: String,
+
+    /// Keys deleted from the `archive_bucket`
+    pub archived: Vec<String>,
+
+    /// Ids of the messages deleted from the store, with their records
+    pub stored: Vec<String>,
+
+    /// Ids of the messages deleted from the `index_table`
+    pub indexed: Vec<String>,
+}
+
+/// Returns the erasure request of a direct invocation `event`, routed as
+/// [`Route::Erase`](crate::router::Route::Erase).
+pub fn from_invocation(event: &Value) -> Result<ErasureRequest, String> {
+    let request: ErasureRequest =
+        serde_json::from_value(event["erase"].clone())
+            .map_err(|e| format!("Invalid erasure request: {}", e))?;
+    match request.sender.trim().split_once('@') {
+        Some((local, domain)) if !local.is_empty() && !domain.is_empty() => {
+            Ok(request)
+        }
+        _ => Err(format!("Invalid sender {}", request.sender)),
+    }
+}
+
+/// Returns whether the envelope sender `address` is `sender`, regardless of
+/// case.
+pub fn is_sender(address: &str, sender: &str) -> bool {
+    let bare = |address: &str| {
+        address.trim().trim_start_matches('<').trim_end_matches('>').to_owned()
+    };
+    bare(address).eq_ignore_ascii_case(&bare(sender))
+}
+
+/// Deletes the messages of `sender` from the archive `objects`, with their
+/// metadata. Returns the keys deleted.
+pub async fn erase_archive(
+    objects: &dyn ObjectStore,
+    sender: &str,
+) -> Result<Vec<String>, Error> {
+    let mut deleted = Vec::new();
+    for key in objects.list("").await? {
+        let Some(base) = key.strip_suffix(".json") else {
+            continue;
+        };
+        let Some(metadata) = objects.get(&key).await? else {
+            continue;
+        };
+        let metadata: Metadata = serde_json::from_slice(&metadata)?;
+        if !is_sender(&metadata.source, sender) {
+            continue;
+        }
+        let raw_key = format!("{}.eml", base);
+        objects.delete(&raw_key).await?;
+        objects.delete(&key).await?;
+        deleted.extend([raw_key, key]);
+    }
+    Ok(deleted)
+}
+
+/// Deletes the stored messages of `sender` from `mail_store`, and their
+/// records. Returns their message ids.
+pub async fn erase_store(
+    mail_store: &MailStore,
+    sender: &str,
+) -> Result<Vec<String>, Error> {
+    let records = mail_store.records.list_records("", "~").await?;
+    let mut deleted = Vec::new();
+    for record in records {
+        if !is_sender(&record.source, sender) {
+            continue;
+        }
+        retention::delete_message(mail_store, &record).await?;
+        deleted.push(record.message_id);
+    }
+    Ok(deleted)
+}
+
+/// Deletes the entries of `table` of the messages from `sender`, and those
+/// of `message_ids`. Returns the message ids deleted.
+pub async fn erase_index(
+    table: &str,
+    sender: &str,
+    message_ids: &BTreeSet<String>,
+) -> Result<Vec<String>, Error> {
+    let domain = sender.trim().rsplit('@').next().unwrap_or_default();
+    let entries = index::query(
+        table,
+        index::SENDER_INDEX,
+        "sender_domain",
+        &domain.to_lowercase(),
+        ("0000", "9999"),
+        usize::MAX,
+    )
+    .await?;
+    let mut ids = message_ids.clone();
+    ids.extend(
+        entries
+            .into_iter()
+            .filter(|entry| is_sender(&entry.sender, sender))
+            .map(|entry| entry.message_id),
+    );
+    for message_id in &ids {
+        index::delete(table, message_id).await?;
+    }
+    Ok(ids.into_iter().collect())
+}
+
+/** Test module for the erasure of a correspondent */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive;
+    use crate::store::fs::FsObjectStore;
+    use crate::store::{MailAction, MailRecord, MemoryRecordStore};
+    use serde_json::json;
+
+    #[test]
+    fn test_request() {
+        let event = json!({ "erase": { "sender": "Fufu@Achu.soup" } });
+        let request = from_invocation(&event).unwrap();
+        assert!(is_sender("<fufu@achu.soup>", &request.sender));
+        assert!(!is_sender("fufu@achu.soup.io", &request.sender));
+        for sender in ["achu.soup", "@achu.soup", "fufu@"] {
+            let event = json!({ "erase": { "sender": sender } });
+            assert!(from_invocation(&event).is_err());
+        }
+        assert!(from_invocation(&json!({ "erase": true })).is_err());
+        assert!(from_invocation(&json!({ "search": {} })).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_erase() {
+        let root = std::env::temp_dir()
+            .join(format!("privatemail-erasure-{}", std::process::id()));
+        let objects = FsObjectStore::new(root.join("archive")).unwrap();
+        for (id, source) in [("a", "fufu@achu.soup"), ("b", "eru@achu.soup")] {
+            let metadata = archive::Metadata {
+                message_id: id.to_owned(),
+                timestamp: "2022-03-19T08:46:16.420Z".to_owned(),
+                source: source.to_owned(),
+                ..Default::default()
+            };
+            archive::write(&objects, metadata, "Hi".into()).await.unwrap();
+        }
+        let deleted = erase_archive(&objects, "FUFU@achu.soup").await.unwrap();
+        assert_eq!(deleted, ["2022/03/19/a.eml", "2022/03/19/a.json"]);
+        assert_eq!(objects.list("").await.unwrap().len(), 2);
+
+        let mail_store = MailStore::new(
+            Box::new(FsObjectStore::new(root.join("store")).unwrap()),
+            Box::new(MemoryRecordStore::default()),
+        );
+        for (id, source) in [("c", "fufu@achu.soup"), ("d", "eru@achu.soup")] {
+            let record = MailRecord {
+                message_id: id.to_owned(),
+                timestamp: "2022-03-19T08:46:16.420Z".to_owned(),
+                source: source.to_owned(),
+                destination: vec!["samubu@user.earth".to_owned()],
+                subject: "Testing".to_owned(),
+                action: MailAction::Quarantined,
+                object_key: None,
+                thread_id: None,
+            };
+            mail_store
+                .track(record, "Subject: Hi\r\n\r\nHi".into())
+                .await
+                .unwrap();
+        }
+        let deleted = erase_store(&mail_store, "fufu@achu.soup").await.unwrap();
+        assert_eq!(deleted, ["c"]);
+        assert!(mail_store.records.get_record("c").await.unwrap().is_none());
+        assert!(mail_store.records.get_record("d").await.unwrap().is_some());
+        let keys = mail_store.objects.list("").await.unwrap();
+        assert!(keys.iter().all(|key| !key.contains("/c.")), "{:?}", keys);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Mail lifecycle events put on an EventBridge bus.
+//!
+//! With `event_bus` set, a `privatemail.received` event is put for every
+//! received message, then one named after its outcome, e.g.
+//! `privatemail.forwarded`, `privatemail.blocked` or `privatemail.failed`.
+//! Every event has the `privatemail` source and a [`MailEvent`] detail,
+//! whose fields are only ever added to within a [`DETAIL_VERSION`]:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "messageId": "0100017fa0",
+//!   "sender": "eru@fufu.soup",
+//!   "recipients": ["hello@nyah.dev"],
+//!   "subject": "Fufu",
+//!   "receivedAt": "2022-03-19T08:46:15.000Z"
+//! }
+//! ```
+use crate::aws;
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Source of the events.
+pub const SOURCE: &str = "privatemail";
+
+/// Name of the event of a received message.
+pub const RECEIVED: &str = "received";
+
+/// Version of the detail schema.
+pub const DETAIL_VERSION: u32 = 1;
+
+/// Detail of a lifecycle event.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MailEvent {
+    /// Version of the detail schema
+    pub version: u32,
+
+    /// SES message id of the received message
+    pub message_id: String,
+
+    /// Original sender
+    pub sender: String,
+
+    /// Original recipients
+    pub recipients: Vec<String>,
+
+    /// Subject of the message
+    pub subject: String,
+
+    /// Time SES received the message, as an ISO 8601 date
+    pub received_at: String,
+}
+
+/// Returns the detail type of the event `name`, e.g.
+/// `privatemail.forwarded`.
+pub fn detail_type(name: &str) -> String {
+    format!("{}.{}", SOURCE, name)
+}
+
+/// Returns the `PutEvents` entry of the event `name` on `bus`.
+pub fn entry(bus: &str, name: &str, event: &MailEvent) -> Result<Value, Error> {
+    Ok(json!({
+        "EventBusName": bus,
+        "Source": SOURCE,
+        "DetailType": detail_type(name),
+        "Detail": serde_json::to_string(event)?,
+    }))
+}
+
+/// Puts the event `name` on the EventBridge bus `bus`, a name or ARN.
+pub async fn put(
+    bus: &str,
+    name: &str,
+    event: &MailEvent,
+) -> Result<(), Error> {
+    let mut request =
+        SignedRequest::new("POST", "events", &aws::arn_region(bus), "/");
+    request.add_header("x-amz-target", "AWSEvents.PutEvents");
+    request.set_content_type("application/x-amz-json-1.1".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&json!({
+        "Entries": [entry(bus, name, event)?],
+    }))?));
+    let response = aws::dispatch(request).await?;
+    let body: Value = serde_json::from_slice(&response.body)?;
+    match body["FailedEntryCount"].as_u64() {
+        Some(0) | None => Ok(()),
+        Some(_) => Err(format!(
+            "Event {} was not put: {}",
+            detail_type(name),
+            body["Entries"][0]["ErrorMessage"].as_str().unwrap_or_default()
+        )
+        .into()),
+    }
+}
+
+/** Test module for lifecycle events */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::Region;
+
+    #[test]
+    fn test_entry() {
+        let event = MailEvent {
+            version: DETAIL_VERSION,
+            message_id: "0100017fa0".to_owned(),
+            sender: "eru@fufu.soup".to_owned(),
+            recipients: vec!["hello@nyah.dev".to_owned()],
+            subject: "Fufu".to_owned(),
+            received_at: "2022-03-19T08:46:15.000Z".to_owned(),
+        };
+        let entry = entry("privatemail", "forwarded", &event).unwrap();
+        assert_eq!(entry["Source"], "privatemail");
+        assert_eq!(entry["DetailType"], "privatemail.forwarded");
+        let detail: Value =
+            serde_json::from_str(entry["Detail"].as_str().unwrap()).unwrap();
+        assert_eq!(detail["version"], 1);
+        assert_eq!(detail["messageId"], "0100017fa0");
+        assert_eq!(detail["receivedAt"], "2022-03-19T08:46:15.000Z");
+
+        assert_eq!(
+            aws::arn_region(
+                "arn:aws:events:eu-west-1:123456789012:event-bus/privatemail"
+            ),
+            Region::EuWest1
+        );
+    }
+}
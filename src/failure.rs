@@ -0,0 +1,408 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Classification of outbound send failures and the policy applied to
+//! each class.
+use lambda_runtime::Error;
+use rand::Rng;
+use rusoto_core::RusotoError;
+use rusoto_ses::{SendEmailError, SendRawEmailError};
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+use tracing::warn;
+
+/// Counter prefix tracking send failures per class.
+pub const FAILURE_PREFIX: &str = "failures/";
+
+/// Longest backoff between two throttled sends, in milliseconds.
+pub const MAX_BACKOFF_MS: u64 = 5_000;
+
+/// Kind of error returned by a send.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Deserialize,
+    Eq,
+    Hash,
+    Ord,
+    PartialEq,
+    PartialOrd,
+    Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FailureClass {
+    /// SES rate limits were hit
+    Throttling,
+
+    /// The send can never succeed, e.g. an unverified identity or an
+    /// invalid address
+    Permanent,
+
+    /// The message is too large to be sent
+    Size,
+
+    /// Network and service errors which may go away on their own
+    Transient,
+}
+
+/// What to do with a message whose send failed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailurePolicy {
+    /// Fail the invocation so that it is retried by Lambda
+    Retry,
+
+    /// Store the message and error under the failed prefix
+    DeadLetter,
+
+    /// Notify the configured notifiers
+    Alert,
+
+    /// Tell the original sender that the message was not delivered
+    Bounce,
+}
+
+impl FailureClass {
+    /// Every failure class.
+    pub const ALL: [FailureClass; 4] = [
+        FailureClass::Throttling,
+        FailureClass::Permanent,
+        FailureClass::Size,
+        FailureClass::Transient,
+    ];
+
+    /// Name of the class, also used in config and counter names.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailureClass::Throttling => "throttling",
+            FailureClass::Permanent => "permanent",
+            FailureClass::Size => "size",
+            FailureClass::Transient => "transient",
+        }
+    }
+
+    /// Policy applied when none is configured for the class.
+    pub fn default_policy(&self) -> FailurePolicy {
+        match self {
+            FailureClass::Throttling | FailureClass::Transient => {
+                FailurePolicy::Retry
+            }
+            FailureClass::Permanent => FailurePolicy::Alert,
+            FailureClass::Size => FailurePolicy::DeadLetter,
+        }
+    }
+
+    /// Returns whether sends failing so can never succeed, however often
+    /// they are retried.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, FailureClass::Permanent | FailureClass::Size)
+    }
+
+    /// Returns the counter name tracking the class.
+    pub fn counter_key(&self) -> String {
+        format!("{}{}", FAILURE_PREFIX, self.as_str())
+    }
+}
+
+impl std::str::FromStr for FailureClass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase();
+        FailureClass::ALL
+            .into_iter()
+            .find(|class| class.as_str() == s)
+            .ok_or_else(|| format!("Invalid failure class: {}", s))
+    }
+}
+
+impl FailurePolicy {
+    /// Name of the policy, as used in config.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FailurePolicy::Retry => "retry",
+            FailurePolicy::DeadLetter => "dead-letter",
+            FailurePolicy::Alert => "alert",
+            FailurePolicy::Bounce => "bounce",
+        }
+    }
+}
+
+impl std::str::FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "retry" => Ok(FailurePolicy::Retry),
+            "dead-letter" | "dlq" => Ok(FailurePolicy::DeadLetter),
+            "alert" => Ok(FailurePolicy::Alert),
+            "bounce" => Ok(FailurePolicy::Bounce),
+            other => Err(format!("Invalid failure policy: {}", other)),
+        }
+    }
+}
+
+/// Parses a `class=policy` pair, e.g. `permanent=bounce`.
+pub fn parse_policy(s: &str) -> Result<(FailureClass, FailurePolicy), String> {
+    let (class, policy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid failure policy: {}", s))?;
+    Ok((class.parse()?, policy.parse()?))
+}
+
+/// Classifies an error description, returning `default` when nothing in
+/// it identifies the class.
+fn classify_text(text: &str, default: FailureClass) -> FailureClass {
+    let text = text.to_lowercase();
+    let any = |needles: &[&str]| needles.iter().any(|n| text.contains(n));
+    if any(&[
+        "throttl",
+        "sending rate",
+        "sendingrate",
+        "rate exceeded",
+        "too many requests",
+        "toomanyrequests",
+    ]) {
+        FailureClass::Throttling
+    } else if any(&["too long", "too large", "size exceeds"]) {
+        FailureClass::Size
+    } else if any(&[
+        "not verified",
+        "illegal address",
+        "invalid",
+        "paused",
+        "does not exist",
+        "blacklisted",
+        "suppression list",
+    ]) {
+        FailureClass::Permanent
+    } else {
+        default
+    }
+}
+
+fn classify_rusoto<E: Debug>(error: &RusotoError<E>) -> FailureClass {
+    match error {
+        RusotoError::Service(e) => {
+            classify_text(&format!("{:?}", e), FailureClass::Permanent)
+        }
+        RusotoError::Validation(message) => {
+            classify_text(message, FailureClass::Permanent)
+        }
+        RusotoError::Unknown(response) => {
+            let default = if response.status.is_server_error() {
+                FailureClass::Transient
+            } else {
+                FailureClass::Permanent
+            };
+            classify_text(response.body_as_str(), default)
+        }
+        RusotoError::HttpDispatch(_)
+        | RusotoError::Credentials(_)
+        | RusotoError::ParseError(_)
+        | RusotoError::Blocking => FailureClass::Transient,
+    }
+}
+
+/// Classifies a send error.
+pub fn classify(error: &(dyn std::error::Error + 'static)) -> FailureClass {
+    if let Some(e) = error.downcast_ref::<RusotoError<SendEmailError>>() {
+        classify_rusoto(e)
+    } else if let Some(e) =
+        error.downcast_ref::<RusotoError<SendRawEmailError>>()
+    {
+        classify_rusoto(e)
+    } else {
+        classify_text(
+            &format!("{} {:?}", error, error),
+            FailureClass::Transient,
+        )
+    }
+}
+
+/// Returns whether a send failing with `error` may succeed through SES in
+/// another region: the service could not be reached or failed, or sending
+/// is paused for the account.
+pub fn is_regional(error: &(dyn std::error::Error + 'static)) -> bool {
+    let text = format!("{} {:?}", error, error).to_lowercase();
+    classify(error) == FailureClass::Transient
+        || text.contains("accountsendingpaused")
+        || text.contains("sending paused for this account")
+}
+
+/// Returns the backoff before retry `attempt`, counted from 0: `base_ms`
+/// doubled per attempt up to [`MAX_BACKOFF_MS`], scaled by `jitter` in
+/// `[0, 1]`.
+pub fn backoff(attempt: u32, base_ms: u64, jitter: f64) -> Duration {
+    let ceiling =
+        base_ms.saturating_mul(1 << attempt.min(16)).min(MAX_BACKOFF_MS);
+    Duration::from_millis((ceiling as f64 * jitter.clamp(0.0, 1.0)) as u64)
+}
+
+/// Runs `send` until it succeeds or fails other than by throttling,
+/// retrying throttled sends up to `retries` times with full jitter
+/// backoff from `base_ms`.
+pub async fn retry_throttled<F, Fut>(
+    retries: u32,
+    base_ms: u64,
+    mut send: F,
+) -> Result<String, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<String, Error>>,
+{
+    let mut attempt = 0;
+    loop {
+        match send().await {
+            Err(e)
+                if attempt < retries
+                    && classify(e.as_ref()) == FailureClass::Throttling =>
+            {
+                let delay = backoff(attempt, base_ms, rand::thread_rng().gen());
+                warn!(
+                    attempt = attempt + 1,
+                    delay_ms = delay.as_millis() as u64,
+                    "Send throttled, retrying: {}",
+                    e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/** Test module for failure classification */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::request::BufferedHttpResponse;
+
+    #[test]
+    fn test_classify_rusoto() {
+        let rejected: RusotoError<SendEmailError> =
+            RusotoError::Service(SendEmailError::MessageRejected(
+                "Email address is not verified.".to_owned(),
+            ));
+        assert_eq!(classify(&rejected), FailureClass::Permanent);
+
+        let too_long: RusotoError<SendRawEmailError> = RusotoError::Service(
+            SendRawEmailError::MessageRejected("Message too long".to_owned()),
+        );
+        assert_eq!(classify(&too_long), FailureClass::Size);
+
+        let throttled: RusotoError<SendEmailError> =
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: http::StatusCode::BAD_REQUEST,
+                body: "<Code>Throttling</Code><Message>Maximum sending \
+                       rate exceeded.</Message>"
+                    .into(),
+                headers: Default::default(),
+            });
+        assert_eq!(classify(&throttled), FailureClass::Throttling);
+
+        let unavailable: RusotoError<SendEmailError> =
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: http::StatusCode::SERVICE_UNAVAILABLE,
+                body: "".into(),
+                headers: Default::default(),
+            });
+        assert_eq!(classify(&unavailable), FailureClass::Transient);
+
+        let suppressed: Error =
+            "Destination gone@suya.io is on the account suppression list"
+                .into();
+        assert_eq!(classify(suppressed.as_ref()), FailureClass::Permanent);
+    }
+
+    #[test]
+    fn test_is_regional() {
+        let paused: RusotoError<SendRawEmailError> =
+            RusotoError::Service(SendRawEmailError::AccountSendingPaused(
+                "Sending paused for this account.".to_owned(),
+            ));
+        assert_eq!(classify(&paused), FailureClass::Permanent);
+        assert!(is_regional(&paused));
+
+        let unavailable: RusotoError<SendRawEmailError> =
+            RusotoError::Unknown(BufferedHttpResponse {
+                status: http::StatusCode::SERVICE_UNAVAILABLE,
+                body: "".into(),
+                headers: Default::default(),
+            });
+        assert!(is_regional(&unavailable));
+
+        let rejected: RusotoError<SendRawEmailError> =
+            RusotoError::Service(SendRawEmailError::MessageRejected(
+                "Email address is not verified.".to_owned(),
+            ));
+        assert!(!is_regional(&rejected));
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(
+            parse_policy("permanent=bounce"),
+            Ok((FailureClass::Permanent, FailurePolicy::Bounce))
+        );
+        assert_eq!(
+            parse_policy("size=dlq"),
+            Ok((FailureClass::Size, FailurePolicy::DeadLetter))
+        );
+        assert!(parse_policy("permanent").is_err());
+        assert!(parse_policy("weird=retry").is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0, 200, 1.0), Duration::from_millis(200));
+        assert_eq!(backoff(3, 200, 1.0), Duration::from_millis(1_600));
+        assert_eq!(backoff(3, 200, 0.5), Duration::from_millis(800));
+        assert_eq!(backoff(40, 200, 1.0), Duration::from_millis(5_000));
+    }
+
+    #[tokio::test]
+    async fn test_retry_throttled() {
+        let mut calls = 0;
+        let result = retry_throttled(2, 0, || {
+            calls += 1;
+            async { Err::<String, Error>("MaxSendingRateExceeded".into()) }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let result = retry_throttled(2, 0, || {
+            calls += 1;
+            let attempt = calls;
+            async move {
+                match attempt {
+                    1 => Err("Throttling: Rate exceeded".into()),
+                    _ => Ok("0100abc".to_owned()),
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "0100abc");
+        assert_eq!(calls, 2);
+
+        let mut calls = 0;
+        let result = retry_throttled(2, 0, || {
+            calls += 1;
+            async {
+                Err::<String, Error>("Email address is not verified".into())
+            }
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+    }
+}
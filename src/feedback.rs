@@ -0,0 +1,342 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! SES feedback notifications about mail sent by the forwarder.
+//!
+//! Identities or configuration sets publishing their `Bounce` notifications
+//! to the SNS topic of the function have them logged, recorded per bounced
+//! address in `bounce_table` and, when `bounce_notify_email` is set,
+//! reported to that address. `Delivery` notifications are logged with
+//! their delivery latency, and `DeliveryDelay` events as warnings.
+use crate::aws;
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// `notificationType` of bounce notifications.
+pub const BOUNCE: &str = "Bounce";
+
+/// `notificationType` of delivery notifications.
+pub const DELIVERY: &str = "Delivery";
+
+/// `eventType` of delayed delivery events.
+pub const DELIVERY_DELAY: &str = "DeliveryDelay";
+
+/// Counter and metric name of bounced recipients.
+pub const BOUNCE_COUNTER: &str = "bounce";
+
+/// Metric name of the delivery latency of sent mail, in milliseconds.
+pub const DELIVERY_METRIC: &str = "delivery_ms";
+
+/// Counter and metric name of delayed recipients.
+pub const DELAY_COUNTER: &str = "delivery_delay";
+
+/// Partition key of the bounce table, the lowercase bounced address.
+pub const KEY_ATTRIBUTE: &str = "address";
+
+/// SES notification of a message which bounced.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BounceNotification {
+    /// Details of the bounce
+    pub bounce: Bounce,
+
+    /// Message which bounced
+    pub mail: SentMail,
+}
+
+/// Bounce of a sent message.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bounce {
+    /// `Permanent`, `Transient` or `Undetermined`
+    pub bounce_type: String,
+
+    /// Cause of the bounce, e.g. `General` or `Suppressed`
+    #[serde(default)]
+    pub bounce_sub_type: String,
+
+    /// Recipients the message bounced for
+    #[serde(default)]
+    pub bounced_recipients: Vec<BouncedRecipient>,
+
+    /// Time the bounce was reported, as an ISO 8601 date
+    #[serde(default)]
+    pub timestamp: String,
+
+    /// Unique id of the bounce
+    #[serde(default)]
+    pub feedback_id: String,
+}
+
+/// Recipient a message bounced for, or whose delivery is delayed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BouncedRecipient {
+    /// Address of the recipient
+    pub email_address: String,
+
+    /// Enhanced status code, e.g. `5.1.1`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+
+    /// Response of the receiving server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diagnostic_code: Option<String>,
+}
+
+/// SES notification of a message delivered to the receiving servers.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DeliveryNotification {
+    /// Details of the delivery
+    pub delivery: Delivery,
+
+    /// Message which was delivered
+    pub mail: SentMail,
+}
+
+/// Delivery of a sent message.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Delivery {
+    /// Milliseconds from the send request to the delivery
+    #[serde(default)]
+    pub processing_time_millis: u64,
+
+    /// Recipients the message was delivered to
+    #[serde(default)]
+    pub recipients: Vec<String>,
+
+    /// Response of the receiving server
+    #[serde(default)]
+    pub smtp_response: String,
+}
+
+/// SES event of a message whose delivery is delayed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryDelayNotification {
+    /// Details of the delay
+    pub delivery_delay: DeliveryDelay,
+
+    /// Message which is delayed
+    pub mail: SentMail,
+}
+
+/// Delay of the delivery of a sent message.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeliveryDelay {
+    /// Cause of the delay, e.g. `MailboxFull` or
+    /// `TransientCommunicationFailure`
+    #[serde(default)]
+    pub delay_type: String,
+
+    /// Recipients whose delivery is delayed
+    #[serde(default)]
+    pub delayed_recipients: Vec<BouncedRecipient>,
+
+    /// Time SES stops retrying, as an ISO 8601 date
+    #[serde(default)]
+    pub expiration_time: String,
+}
+
+/// Message sent through SES which feedback is about.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SentMail {
+    /// SES message id of the sent message
+    pub message_id: String,
+
+    /// Sender of the message
+    #[serde(default)]
+    pub source: String,
+
+    /// Recipients of the message
+    #[serde(default)]
+    pub destination: Vec<String>,
+}
+
+/// Returns the `notificationType` of the SES notification `message`, or
+/// the `eventType` of a message published by a configuration set.
+pub fn notification_type(message: &Value) -> &str {
+    message["notificationType"]
+        .as_str()
+        .or_else(|| message["eventType"].as_str())
+        .unwrap_or_default()
+}
+
+impl BounceNotification {
+    /// Returns whether the message bounced permanently.
+    pub fn is_permanent(&self) -> bool {
+        self.bounce.bounce_type == "Permanent"
+    }
+
+    /// Returns the addresses the message bounced for.
+    pub fn addresses(&self) -> impl Iterator<Item = &str> {
+        self.bounce.bounced_recipients.iter().map(|r| r.email_address.as_str())
+    }
+
+    /// Returns a plain text report of the bounce.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "A {} bounce ({}) was reported for message {} from {}.\r\n\r\n",
+            self.bounce.bounce_type.to_lowercase(),
+            self.bounce.bounce_sub_type,
+            self.mail.message_id,
+            self.mail.source,
+        );
+        for recipient in &self.bounce.bounced_recipients {
+            report.push_str(&format!(
+                "{}: {} {}\r\n",
+                recipient.email_address,
+                recipient.status.as_deref().unwrap_or("-"),
+                recipient.diagnostic_code.as_deref().unwrap_or_default(),
+            ));
+        }
+        report
+    }
+}
+
+/// Records the bounce of each recipient of `notification` at `now` in
+/// `table`, counting the bounces of the address.
+pub async fn record(
+    table: &str,
+    notification: &BounceNotification,
+    now: i64,
+) -> Result<(), Error> {
+    for recipient in &notification.bounce.bounced_recipients {
+        let mut request =
+            SignedRequest::new("POST", "dynamodb", &Region::default(), "/");
+        request.add_header("x-amz-target", "DynamoDB_20120810.UpdateItem");
+        request.set_content_type("application/x-amz-json-1.0".to_owned());
+        request.set_payload(Some(serde_json::to_vec(&json!({
+            "TableName": table,
+            "Key": {
+                KEY_ATTRIBUTE: {
+                    "S": recipient.email_address.trim().to_lowercase()
+                },
+            },
+            "UpdateExpression": "SET bounce_type = :type, \
+                bounce_sub_type = :sub_type, diagnostic_code = :code, \
+                message_id = :message_id, bounced_at = :now \
+                ADD bounces :one",
+            "ExpressionAttributeValues": {
+                ":type": { "S": notification.bounce.bounce_type },
+                ":sub_type": { "S": notification.bounce.bounce_sub_type },
+                ":code": {
+                    "S": recipient.diagnostic_code.as_deref().unwrap_or("-")
+                },
+                ":message_id": { "S": notification.mail.message_id },
+                ":now": { "N": now.to_string() },
+                ":one": { "N": "1" },
+            },
+        }))?));
+        aws::dispatch(request).await?;
+    }
+    Ok(())
+}
+
+/** Test module for feedback notifications */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounce_notification() {
+        let message: Value = serde_json::from_str(
+            r#"{
+                "notificationType": "Bounce",
+                "bounce": {
+                    "bounceType": "Permanent",
+                    "bounceSubType": "General",
+                    "bouncedRecipients": [{
+                        "emailAddress": "Gone@suya.io",
+                        "action": "failed",
+                        "status": "5.1.1",
+                        "diagnosticCode": "smtp; 550 5.1.1 user unknown"
+                    }],
+                    "timestamp": "2022-03-19T08:46:16.000Z",
+                    "feedbackId": "0100017fa1"
+                },
+                "mail": {
+                    "timestamp": "2022-03-19T08:46:15.000Z",
+                    "source": "test@nyah.dev",
+                    "messageId": "0100017fa0",
+                    "destination": ["Gone@suya.io"]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(notification_type(&message), BOUNCE);
+        let notification: BounceNotification =
+            serde_json::from_value(message).unwrap();
+        assert!(notification.is_permanent());
+        assert_eq!(
+            notification.addresses().collect::<Vec<_>>(),
+            ["Gone@suya.io"]
+        );
+        assert_eq!(
+            notification.report(),
+            "A permanent bounce (General) was reported for message \
+             0100017fa0 from test@nyah.dev.\r\n\r\n\
+             Gone@suya.io: 5.1.1 smtp; 550 5.1.1 user unknown\r\n"
+        );
+    }
+
+    #[test]
+    fn test_delivery_notifications() {
+        let message: Value = serde_json::from_str(
+            r#"{
+                "notificationType": "Delivery",
+                "mail": { "messageId": "0100017fa0" },
+                "delivery": {
+                    "timestamp": "2022-03-19T08:46:16.000Z",
+                    "processingTimeMillis": 546,
+                    "recipients": ["hello@nyah.dev"],
+                    "smtpResponse": "250 ok",
+                    "reportingMTA": "a8-70.smtp-out.amazonses.com"
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(notification_type(&message), DELIVERY);
+        let notification: DeliveryNotification =
+            serde_json::from_value(message).unwrap();
+        assert_eq!(notification.delivery.processing_time_millis, 546);
+        assert_eq!(notification.delivery.recipients, ["hello@nyah.dev"]);
+
+        let message: Value = serde_json::from_str(
+            r#"{
+                "eventType": "DeliveryDelay",
+                "mail": { "messageId": "0100017fa0" },
+                "deliveryDelay": {
+                    "timestamp": "2022-03-19T09:46:16.000Z",
+                    "delayType": "MailboxFull",
+                    "expirationTime": "2022-03-20T08:46:15.000Z",
+                    "delayedRecipients": [{
+                        "emailAddress": "hello@nyah.dev",
+                        "status": "4.2.2",
+                        "diagnosticCode": "smtp; 452 4.2.2 mailbox full"
+                    }]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(notification_type(&message), DELIVERY_DELAY);
+        let notification: DeliveryDelayNotification =
+            serde_json::from_value(message).unwrap();
+        assert_eq!(notification.delivery_delay.delay_type, "MailboxFull");
+        assert_eq!(
+            notification.delivery_delay.delayed_recipients[0].email_address,
+            "hello@nyah.dev"
+        );
+    }
+}
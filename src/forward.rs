@@ -0,0 +1,506 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Forwarding modes and the raw send path.
+use crate::dkim::DkimSigner;
+use crate::html;
+use crate::mime::{encode_header_value, Attachment, MimeMessage};
+use crate::sender::EmailSender;
+use crate::thread::message_ids;
+use bytes::Bytes;
+use lambda_runtime::Error;
+use mailparse::{DispositionType, MailHeader, MailHeaderMap, ParsedMail};
+use rusoto_ses::{
+    MessageTag, RawMessage, SendEmailRequest, SendRawEmailRequest,
+};
+use serde::{Deserialize, Serialize};
+
+/// File name of the original message in attachment mode.
+pub const ORIGINAL_FILENAME: &str = "original.eml";
+
+/// Value of the `X-Forwarded-By` header.
+pub const FORWARDED_BY: &str =
+    concat!("privatemail/", env!("CARGO_PKG_VERSION"));
+
+/// Header carrying the identifier of the forwarder, so forwards
+/// re-entering it are recognised.
+pub const LOOP_HEADER: &str = "X-PrivateMail-Loop";
+
+/// SES message tag carrying the local part of the alias mail was sent to.
+pub const ALIAS_TAG: &str = "alias";
+
+/// SES message tag carrying the tenant mail was received for.
+pub const TENANT_TAG: &str = "tenant";
+
+/// SES message tag carrying the domain of the original sender.
+pub const ORIGINAL_DOMAIN_TAG: &str = "original_domain";
+
+/// How the original message is handed to the destination inbox.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardMode {
+    /// Forward the decoded body as a new message
+    #[default]
+    Inline,
+
+    /// Attach the untouched original as `message/rfc822` to a wrapper email
+    Attachment,
+}
+
+impl std::str::FromStr for ForwardMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "inline" => Ok(ForwardMode::Inline),
+            "attachment" => Ok(ForwardMode::Attachment),
+            other => Err(format!("Invalid forward mode: {}", other)),
+        }
+    }
+}
+
+/// Builds the short wrapper email carrying `raw` as an `.eml` attachment.
+pub fn wrap_original(
+    from: &str,
+    to: &str,
+    reply_to: &str,
+    sender: &str,
+    subject: &str,
+    raw: &[u8],
+) -> MimeMessage {
+    MimeMessage::new()
+        .header("From", from)
+        .header("To", to)
+        .header("Reply-To", reply_to)
+        .header("Subject", format!("Fwd: {}", subject))
+        .text(format!(
+            "Forwarded message from {}.\r\n\r\n\
+             The original message is attached as {}.\r\n",
+            sender, ORIGINAL_FILENAME
+        ))
+        .attach(Attachment::message(ORIGINAL_FILENAME, raw))
+}
+
+/// Rebuilds a formatted send request as a raw MIME message, so that
+/// headers can be added to inline forwards.
+pub fn from_request(request: &SendEmailRequest) -> MimeMessage {
+    let mut message = MimeMessage::new().header("From", &request.source);
+    if let Some(to) = &request.destination.to_addresses {
+        message = message.header("To", to.join(", "));
+    }
+    if let Some(reply_to) = &request.reply_to_addresses {
+        message = message.header("Reply-To", reply_to.join(", "));
+    }
+    message = message.header("Subject", &request.message.subject.data);
+    if let Some(text) = &request.message.body.text {
+        message = message.text(&text.data);
+    }
+    if let Some(html) = &request.message.body.html {
+        message = message.html(&html.data);
+    }
+    message
+}
+
+/// Returns the headers recording where a forward came from, for filtering
+/// rules at the destination.
+pub fn forwarding_headers(
+    original_to: &[String],
+    original_from: &str,
+    ses_message_id: &str,
+) -> Vec<(&'static str, String)> {
+    vec![
+        ("X-Original-To", original_to.join(", ")),
+        ("X-Original-From", original_from.to_owned()),
+        ("X-PrivatEmail-SES-Id", ses_message_id.to_owned()),
+        ("X-Forwarded-By", FORWARDED_BY.to_owned()),
+    ]
+}
+
+/// Adds threading headers derived from the `original` message headers so
+/// that replies thread in the destination mailbox.
+///
+/// `In-Reply-To` is copied as is. SES assigns its own `Message-ID` to
+/// every message it sends, so the original id is appended to
+/// `References`, which is what clients thread on.
+pub fn with_threading(
+    mut message: MimeMessage,
+    original: &[MailHeader],
+) -> MimeMessage {
+    let mut references = original
+        .get_first_value("References")
+        .map(|r| message_ids(&r))
+        .unwrap_or_default();
+    if let Some(in_reply_to) = original.get_first_value("In-Reply-To") {
+        let in_reply_to = message_ids(&in_reply_to);
+        if !in_reply_to.is_empty() {
+            message = message.header("In-Reply-To", in_reply_to.join(" "));
+        }
+    }
+    if let Some(message_id) = original.get_first_value("Message-ID") {
+        for id in message_ids(&message_id) {
+            if !references.contains(&id) {
+                references.push(id);
+            }
+        }
+    }
+    if !references.is_empty() {
+        message = message.header("References", references.join(" "));
+    }
+    message
+}
+
+/// Returns true when `headers` carry the [`LOOP_HEADER`] of the
+/// forwarder identified by `id`.
+pub fn is_loop(headers: &[MailHeader], id: &str) -> bool {
+    headers
+        .get_all_values(LOOP_HEADER)
+        .iter()
+        .any(|value| value.trim().eq_ignore_ascii_case(id.trim()))
+}
+
+/// Returns the plain text alternative of the forward of `mail`: its own
+/// `text/plain` part when it has one, otherwise `html` rendered as text.
+pub fn text_alternative(mail: &ParsedMail, html: &str) -> String {
+    mail.parts()
+        .find(|part| {
+            part.ctype.mimetype == "text/plain"
+                && part.get_content_disposition().disposition
+                    == DispositionType::Inline
+        })
+        .and_then(|part| part.get_body().ok())
+        .unwrap_or_else(|| html::to_text(html))
+}
+
+/// Parses a `route=name` pair, e.g. `support@acme.com=Acme Support`. The
+/// route is an original recipient address, an `@domain` or `*`.
+pub fn parse_display_name(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((route, name)) if !route.trim().is_empty() => {
+            Ok((route.trim().to_lowercase(), name.trim().to_owned()))
+        }
+        _ => Err(format!("Invalid display name: {}", s)),
+    }
+}
+
+/// Prefixes `subject` with `prefix`, unless it already starts with it as
+/// in replies to earlier forwards.
+pub fn prefix_subject(prefix: &str, subject: &str) -> String {
+    let prefix = prefix.trim();
+    let already = subject
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix));
+    if prefix.is_empty() || already {
+        subject.to_owned()
+    } else {
+        format!("{} {}", prefix, subject)
+    }
+}
+
+/// Parses a `route=prefix` subject prefix, e.g. `jobs@fufu.soup=[Jobs]`.
+pub fn parse_subject_prefix(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((route, prefix))
+            if !route.trim().is_empty() && !prefix.trim().is_empty() =>
+        {
+            Ok((route.trim().to_lowercase(), prefix.trim().to_owned()))
+        }
+        _ => Err(format!("Invalid subject prefix: {}", s)),
+    }
+}
+
+/// Parses a `sender=destination` route, e.g.
+/// `@bank.example=finance@nyah.dev`.
+pub fn parse_sender_route(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((sender, destination))
+            if sender.contains('@') && destination.contains('@') =>
+        {
+            Ok((sender.trim().to_lowercase(), destination.trim().to_owned()))
+        }
+        _ => Err(format!("Invalid sender route: {}", s)),
+    }
+}
+
+/// Formats `address` with the display `name`, quoting ASCII names and
+/// RFC 2047 encoding the others.
+pub fn display_address(name: &str, address: &str) -> String {
+    if name.is_empty() {
+        address.to_owned()
+    } else if name.is_ascii() {
+        let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\" <{}>", encode_header_value(&name), address)
+    } else {
+        format!("{} <{}>", encode_header_value(name), address)
+    }
+}
+
+/// Returns the SES message tags of a forward of mail from `sender` to the
+/// alias `recipient`, received for `tenant`, if any.
+pub fn message_tags(
+    recipient: &str,
+    sender: &str,
+    tenant: Option<&str>,
+) -> Vec<MessageTag> {
+    let alias =
+        recipient.rsplit_once('@').map_or(recipient, |(local, _)| local);
+    let sender_domain = sender.rsplit_once('@').map(|(_, domain)| domain);
+    [
+        (ALIAS_TAG, Some(alias)),
+        (TENANT_TAG, tenant),
+        (ORIGINAL_DOMAIN_TAG, sender_domain),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| {
+        Some(MessageTag { name: name.to_owned(), value: tag_value(value?)? })
+    })
+    .collect()
+}
+
+/// Returns `value` as an SES tag value, which may only hold up to 256
+/// ASCII letters, digits, `_` and `-`.
+fn tag_value(value: &str) -> Option<String> {
+    let value: String = value
+        .trim()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .take(256)
+        .collect();
+    (!value.is_empty()).then_some(value)
+}
+
+/// Sends a raw MIME message from `source` to `destinations` through the
+/// SES `configuration_set`, if any, DKIM signing it when a signer is
+/// given, and returns the SES message id. The message is tagged with
+/// `tags`.
+pub async fn send_raw_email(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    source: &str,
+    destinations: Vec<String>,
+    raw: Bytes,
+    configuration_set: Option<&str>,
+    tags: &[MessageTag],
+) -> Result<String, Error> {
+    let raw = match dkim {
+        Some(signer) => signer.sign(&raw)?.into(),
+        None => raw,
+    };
+    let request = SendRawEmailRequest {
+        destinations: Some(destinations),
+        raw_message: RawMessage { data: raw },
+        source: Some(source.to_owned()),
+        configuration_set_name: configuration_set.map(str::to_owned),
+        tags: (!tags.is_empty()).then(|| tags.to_vec()),
+        ..Default::default()
+    };
+    email_sender.send_raw_email(request).await
+}
+
+/** Test module for forwarding modes */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::parse_mail;
+
+    #[test]
+    fn test_forward_mode_from_str() {
+        assert_eq!("attachment".parse(), Ok(ForwardMode::Attachment));
+        assert_eq!("Inline".parse(), Ok(ForwardMode::Inline));
+        assert!("bounce".parse::<ForwardMode>().is_err());
+    }
+
+    #[test]
+    fn test_message_tags() {
+        let tags = message_tags("jobs@fufu.soup", "news@mail.achu.soup", None);
+        let tags: Vec<_> =
+            tags.iter().map(|t| (t.name.as_str(), t.value.as_str())).collect();
+        assert_eq!(
+            tags,
+            [(ALIAS_TAG, "jobs"), (ORIGINAL_DOMAIN_TAG, "mail_achu_soup")]
+        );
+        let tags = message_tags("Jöbs+cv@fufu.soup", "", Some("fufu.soup"));
+        assert_eq!(tags[0].value, "J_bs_cv");
+        assert_eq!(tags[1].name, TENANT_TAG);
+        assert_eq!(tags[1].value, "fufu_soup");
+        assert_eq!(tags.len(), 2);
+    }
+
+    #[test]
+    fn test_wrap_original() {
+        let original = b"Subject: Hi\r\nFrom: fufu@achu.soup\r\n\r\nhello\r\n";
+        let raw = wrap_original(
+            "test@nyah.dev",
+            "hello@nyah.dev",
+            "fufu@achu.soup",
+            "fufu@achu.soup",
+            "Hi",
+            original,
+        )
+        .to_bytes();
+        let mail = parse_mail(&raw).unwrap();
+        let subject = mail
+            .headers
+            .iter()
+            .find(|h| h.get_key() == "Subject")
+            .unwrap()
+            .get_value();
+        assert_eq!(subject, "Fwd: Hi");
+        assert_eq!(
+            mail.subparts[1].get_content_disposition().params["filename"],
+            ORIGINAL_FILENAME
+        );
+        assert_eq!(mail.subparts[1].get_body_raw().unwrap(), original);
+    }
+
+    #[test]
+    fn test_from_request_with_forwarding_headers() {
+        use rusoto_ses::{Body, Content, Destination, Message};
+
+        let request = SendEmailRequest {
+            destination: Destination {
+                to_addresses: Some(vec!["hello@nyah.dev".to_owned()]),
+                ..Default::default()
+            },
+            message: Message {
+                body: Body {
+                    html: Some(Content {
+                        charset: None,
+                        data: "<p>hi</p>".to_owned(),
+                    }),
+                    text: None,
+                },
+                subject: Content { charset: None, data: "Hi".to_owned() },
+            },
+            reply_to_addresses: Some(vec!["fufu@achu.soup".to_owned()]),
+            source: "test@nyah.dev".to_owned(),
+            ..Default::default()
+        };
+        let mut message = from_request(&request);
+        for (name, value) in forwarding_headers(
+            &["me@nyah.dev".to_owned()],
+            "fufu@achu.soup",
+            "ses-id",
+        ) {
+            message = message.header(name, value);
+        }
+        let raw = message.to_bytes();
+        let mail = parse_mail(&raw).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("To").unwrap(),
+            "hello@nyah.dev"
+        );
+        assert_eq!(
+            mail.headers.get_first_value("X-Original-To").unwrap(),
+            "me@nyah.dev"
+        );
+        assert_eq!(
+            mail.headers.get_first_value("X-Forwarded-By").unwrap(),
+            FORWARDED_BY
+        );
+        assert_eq!(mail.get_body().unwrap().trim(), "<p>hi</p>");
+    }
+
+    #[test]
+    fn test_text_alternative() {
+        let html_only =
+            b"Content-Type: text/html\r\n\r\n<p>Hi <b>there</b></p>";
+        let mail = parse_mail(html_only).unwrap();
+        assert_eq!(
+            text_alternative(&mail, "<p>Hi <b>there</b></p>"),
+            "Hi there\n"
+        );
+
+        let alternative =
+            b"Content-Type: multipart/alternative; boundary=b\r\n\
+                            \r\n\
+                            --b\r\nContent-Type: text/plain\r\n\r\n\
+                            Hi, plain\r\n\
+                            --b\r\nContent-Type: text/html\r\n\r\n\
+                            <p>Hi</p>\r\n\
+                            --b--\r\n";
+        let mail = parse_mail(alternative).unwrap();
+        assert_eq!(text_alternative(&mail, "<p>Hi</p>"), "Hi, plain\r\n");
+    }
+
+    #[test]
+    fn test_display_address() {
+        assert_eq!(
+            parse_display_name(" Support@Acme.com = Acme Support "),
+            Ok(("support@acme.com".to_owned(), "Acme Support".to_owned()))
+        );
+        assert!(parse_display_name("=Acme").is_err());
+        assert_eq!(
+            display_address("Acme \"Support\"", "me@nyah.dev"),
+            "\"Acme \\\"Support\\\"\" <me@nyah.dev>"
+        );
+        assert_eq!(
+            display_address("Caf\u{e9}", "me@nyah.dev"),
+            "=?UTF-8?B?Q2Fmw6k=?= <me@nyah.dev>"
+        );
+        assert_eq!(display_address("", "me@nyah.dev"), "me@nyah.dev");
+    }
+
+    #[test]
+    fn test_with_threading() {
+        let original = b"Message-ID: <c@achu.soup>\r\n\
+                         In-Reply-To: <b@achu.soup>\r\n\
+                         References: <a@achu.soup>\r\n <b@achu.soup>\r\n\r\n";
+        let (headers, _) = mailparse::parse_headers(original).unwrap();
+        let message = with_threading(MimeMessage::new(), &headers);
+        assert_eq!(message.get_header("In-Reply-To"), Some("<b@achu.soup>"));
+        assert_eq!(
+            message.get_header("References"),
+            Some("<a@achu.soup> <b@achu.soup> <c@achu.soup>")
+        );
+
+        let message = with_threading(MimeMessage::new(), &[]);
+        assert!(message.get_header("References").is_none());
+    }
+
+    #[test]
+    fn test_prefix_subject() {
+        assert_eq!(prefix_subject("[Fufu]", "Hi"), "[Fufu] Hi");
+        assert_eq!(prefix_subject("[Fufu] ", "[fufu] Re: Hi"), "[fufu] Re: Hi");
+        assert_eq!(prefix_subject("", "Hi"), "Hi");
+
+        assert_eq!(
+            parse_subject_prefix(" Jobs@Fufu.soup = [Jobs] ").unwrap(),
+            ("jobs@fufu.soup".to_owned(), "[Jobs]".to_owned())
+        );
+        assert!(parse_subject_prefix("jobs@fufu.soup=").is_err());
+        assert!(parse_subject_prefix("[Jobs]").is_err());
+    }
+
+    #[test]
+    fn test_parse_sender_route() {
+        assert_eq!(
+            parse_sender_route("@Bank.example = finance@nyah.dev").unwrap(),
+            ("@bank.example".to_owned(), "finance@nyah.dev".to_owned())
+        );
+        assert!(parse_sender_route("bank.example=finance@nyah.dev").is_err());
+        assert!(parse_sender_route("@bank.example").is_err());
+    }
+
+    #[test]
+    fn test_is_loop() {
+        let (headers, _) = mailparse::parse_headers(
+            b"X-PrivateMail-Loop: other@fufu.soup\r\n\
+            X-PrivateMail-Loop: Forwarder@nyah.dev \r\n\r\n",
+        )
+        .unwrap();
+        assert!(is_loop(&headers, "forwarder@nyah.dev"));
+        assert!(!is_loop(&headers, "forwarder@suya.io"));
+        assert!(!is_loop(&[], "forwarder@nyah.dev"));
+    }
+}
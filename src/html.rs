@@ -0,0 +1,325 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Plain text rendering and sanitization of HTML bodies.
+//!
+//! [`to_text`] gives HTML-only messages a `text/plain` alternative.
+//! Paragraphs, line breaks and list items are kept, link targets are
+//! written after the link text and scripts, styles and the document head
+//! are dropped.
+//!
+//! [`sanitize`] strips scripts, forms, event handlers and other active
+//! content from forwarded bodies, keeping the layout markup mail relies on.
+use std::collections::HashSet;
+
+/// Tags whose content is never rendered.
+const SKIPPED: &[&str] = &["script", "style", "head", "title"];
+
+/// Tags separated from their surroundings by a blank line.
+const PARAGRAPHS: &[&str] = &[
+    "p",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "ul",
+    "ol",
+    "table",
+    "blockquote",
+    "pre",
+    "hr",
+];
+
+/// Tags starting a new line.
+const LINES: &[&str] = &["br", "div", "tr", "section", "article", "header"];
+
+/// Presentational tags kept by [`sanitize`] on top of ammonia's defaults.
+const LAYOUT_TAGS: &[&str] = &["center", "font"];
+
+/// Layout attributes of mail templates kept by [`sanitize`] on any tag.
+const LAYOUT_ATTRIBUTES: &[&str] = &[
+    "align",
+    "bgcolor",
+    "border",
+    "cellpadding",
+    "cellspacing",
+    "color",
+    "height",
+    "style",
+    "valign",
+    "width",
+];
+
+/// Returns `html` with scripts, forms, frames, event handler attributes and
+/// `javascript:` links removed. Inline styles and table layout attributes
+/// are kept, and `cid:` links to inline parts still resolve.
+pub fn sanitize(html: &str) -> String {
+    let mut schemes: HashSet<&str> =
+        ammonia::Builder::default().clone_url_schemes().into_iter().collect();
+    schemes.insert("cid");
+    ammonia::Builder::default()
+        .add_tags(LAYOUT_TAGS)
+        .add_generic_attributes(LAYOUT_ATTRIBUTES)
+        .url_schemes(schemes)
+        .clean(html)
+        .to_string()
+}
+
+/// Renders `html` as plain text.
+pub fn to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut link: Option<(String, usize)> = None;
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        push_text(&mut text, &rest[..open]);
+        let tag = &rest[open..];
+        if tag.starts_with("<!--") {
+            rest = tag.find("-->").map_or("", |end| &tag[end + 3..]);
+            continue;
+        }
+        let Some(end) = tag.find('>') else {
+            rest = "";
+            break;
+        };
+        let (name, closing) = tag_name(&tag[1..end]);
+        rest = &tag[end + 1..];
+        if !closing && SKIPPED.contains(&name.as_str()) {
+            let close = format!("</{}", name);
+            rest = rest
+                .to_ascii_lowercase()
+                .find(&close)
+                .and_then(|i| rest[i..].find('>').map(|e| &rest[i + e + 1..]))
+                .unwrap_or("");
+            continue;
+        }
+        match name.as_str() {
+            "a" if !closing => {
+                link = attribute(&tag[1..end], "href")
+                    .map(|href| (decode_entities(&href), text.len()));
+            }
+            "a" => {
+                if let Some((href, start)) = link.take() {
+                    let label = text[start..].trim();
+                    let target = href.trim_start_matches("mailto:");
+                    if !href.starts_with('#') && !href.is_empty() {
+                        if label.is_empty() {
+                            text.push_str(target);
+                        } else if label != target {
+                            text.push_str(&format!(" ({})", target));
+                        }
+                    }
+                }
+            }
+            "li" if !closing => {
+                new_line(&mut text);
+                text.push_str("- ");
+            }
+            "td" | "th" if closing => text.push(' '),
+            name if PARAGRAPHS.contains(&name) => blank_line(&mut text),
+            name if LINES.contains(&name) => new_line(&mut text),
+            _ => {}
+        }
+    }
+    push_text(&mut text, rest);
+
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if blank {
+            out.push('\n');
+            blank = false;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Escapes `value` for HTML text and attribute values.
+pub fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Decodes the named entities common in mail and numeric references.
+pub fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').filter(|&end| end <= 10).and_then(|end| {
+            let entity = &rest[1..end];
+            let c = match entity {
+                "nbsp" => Some(' '),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                "amp" => Some('&'),
+                _ => entity.strip_prefix('#').and_then(|n| {
+                    match n.strip_prefix(['x', 'X']) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                        None => n.parse().ok(),
+                    }
+                    .and_then(char::from_u32)
+                }),
+            };
+            c.map(|c| (c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Appends the text node `raw`, collapsing whitespace as browsers do.
+fn push_text(text: &mut String, raw: &str) {
+    let decoded = decode_entities(raw);
+    for (i, word) in decoded.split_whitespace().enumerate() {
+        let starts_with_space =
+            decoded.starts_with(char::is_whitespace) || i > 0;
+        if starts_with_space
+            && !text.is_empty()
+            && !text.ends_with(char::is_whitespace)
+        {
+            text.push(' ');
+        }
+        text.push_str(word);
+    }
+    if decoded.ends_with(char::is_whitespace) && !decoded.trim().is_empty() {
+        text.push(' ');
+    }
+}
+
+fn new_line(text: &mut String) {
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+}
+
+fn blank_line(text: &mut String) {
+    new_line(text);
+    text.push('\n');
+}
+
+/// Returns the lowercase name of the tag `inner` and whether it closes.
+pub(crate) fn tag_name(inner: &str) -> (String, bool) {
+    let closing = inner.starts_with('/');
+    let name = inner
+        .trim_start_matches('/')
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    (name, closing)
+}
+
+/// Returns the value of the attribute `name` of the tag `inner`.
+pub(crate) fn attribute(inner: &str, name: &str) -> Option<String> {
+    let lower = inner.to_ascii_lowercase();
+    let mut from = 0;
+    while let Some(i) = lower[from..].find(name) {
+        let start = from + i;
+        from = start + name.len();
+        let preceded = lower[..start].ends_with(char::is_whitespace);
+        let value = inner[from..].trim_start();
+        if !preceded || !value.starts_with('=') {
+            continue;
+        }
+        let value = value[1..].trim_start();
+        return Some(match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                value[1..].split(quote).next().unwrap_or_default().to_owned()
+            }
+            _ => value
+                .split(|c: char| c.is_whitespace() || c == '>')
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+        });
+    }
+    None
+}
+
+/** Test module for HTML rendering */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_text() {
+        let html = "<html><head><title>Sale</title>\
+                    <style>p { color: red; }</style></head><body>\
+                    <h1>Big   sale</h1><p>Save 50%&nbsp;today &amp; \
+                    <b>tomorrow</b>.<br>Ends soon.</p>\
+                    <!-- tracking -->\
+                    <ul><li>Shoes</li><li>Hats</li></ul>\
+                    <p><a href=\"https://shop.example/sale\">Shop now</a> \
+                    or <a href='mailto:help@shop.example'>help@shop.example</a>\
+                    </p><script>track()</script></body></html>";
+        assert_eq!(
+            to_text(html),
+            "Big sale\n\
+             \n\
+             Save 50% today & tomorrow.\n\
+             Ends soon.\n\
+             \n\
+             - Shoes\n\
+             - Hats\n\
+             \n\
+             Shop now (https://shop.example/sale) or help@shop.example\n"
+        );
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let html = "<table width=\"600\" bgcolor=\"#fff\"><tr>\
+                    <td style=\"color: red\" onclick=\"steal()\">Hi\
+                    <img src=\"cid:logo\" onerror=\"steal()\"></td>\
+                    </tr></table><script>steal()</script>\
+                    <form action=\"https://evil.example\">\
+                    <input name=\"password\"></form>\
+                    <a href=\"javascript:steal()\">Click</a>\
+                    <iframe src=\"https://evil.example\"></iframe>";
+        let clean = sanitize(html);
+        assert!(clean.contains("<table width=\"600\" bgcolor=\"#fff\">"));
+        assert!(clean.contains("<td style=\"color: red\">Hi"));
+        assert!(clean.contains("<img src=\"cid:logo\">"));
+        for removed in ["steal", "form", "input", "iframe", "evil"] {
+            assert!(!clean.contains(removed), "{} in {}", removed, clean);
+        }
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(
+            decode_entities("&lt;a&gt; &#233;&#x41; &bogus; &"),
+            "<a> éA &bogus; &"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! HTTP requests delivered by a Function URL or API Gateway, in the
+//! payload format of `lambda_http`.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_json::Value;
+
+/// Returns whether `event` is an HTTP request from a Function URL or API
+/// Gateway.
+pub fn is_http(event: &Value) -> bool {
+    event["requestContext"].is_object() && event.get("body").is_some()
+}
+
+/// Returns the path of the HTTP request `event`.
+pub fn path(event: &Value) -> &str {
+    event["rawPath"]
+        .as_str()
+        .or_else(|| event["path"].as_str())
+        .unwrap_or_default()
+}
+
+/// Returns the value of the header `name` of the HTTP request `event`,
+/// matched regardless of case.
+pub fn header<'a>(event: &'a Value, name: &str) -> Option<&'a str> {
+    event["headers"]
+        .as_object()?
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .and_then(|(_, value)| value.as_str())
+}
+
+/// Returns the value of the query string parameter `name` of the HTTP
+/// request `event`.
+pub fn query_param<'a>(event: &'a Value, name: &str) -> Option<&'a str> {
+    event["queryStringParameters"][name].as_str()
+}
+
+/// Returns the body of the HTTP request `event`, decoded when base64
+/// encoded.
+pub fn body(event: &Value) -> Result<Vec<u8>, String> {
+    let body = event["body"].as_str().unwrap_or_default();
+    match event["isBase64Encoded"].as_bool() {
+        Some(true) => {
+            STANDARD.decode(body).map_err(|e| format!("Invalid body: {}", e))
+        }
+        _ => Ok(body.as_bytes().to_vec()),
+    }
+}
+
+/// Returns whether the HTTP request `event` carries `token`, which may
+/// not be empty, as a bearer token or in its `token` query parameter for
+/// webhook providers which cannot set headers.
+pub fn authorized(event: &Value, token: &str) -> bool {
+    let presented = header(event, "authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| query_param(event, "token"))
+        .unwrap_or_default();
+    !token.is_empty() && constant_time_eq(presented, token)
+}
+
+/// Compares `a` and `b` in a time independent of where they differ.
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/** Test module for HTTP requests */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request() {
+        let event = json!({
+            "rawPath": "/inbound",
+            "requestContext": { "http": { "method": "POST" } },
+            "headers": { "Content-Type": "message/rfc822" },
+            "queryStringParameters": { "token": "s3cret" },
+            "body": STANDARD.encode("Subject: Hi\r\n\r\nHello"),
+            "isBase64Encoded": true,
+        });
+        assert!(is_http(&event));
+        assert!(!is_http(&json!({ "Records": [] })));
+        assert_eq!(path(&event), "/inbound");
+        assert_eq!(header(&event, "content-type"), Some("message/rfc822"));
+        assert_eq!(body(&event).unwrap(), b"Subject: Hi\r\n\r\nHello");
+        assert!(authorized(&event, "s3cret"));
+        assert!(!authorized(&event, "other"));
+        assert!(!authorized(&json!({}), ""));
+    }
+}
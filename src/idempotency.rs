@@ -0,0 +1,328 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Deduplication of messages delivered more than once.
+//!
+//! SNS delivers at least once, so the same message may reach the handler
+//! twice. Before the message is handled at all, its key is claimed with a
+//! conditional write to the `dedup_table` DynamoDB table; a message whose
+//! key is claimed or handled within the window is skipped, so none of its
+//! side effects are repeated. Failed messages give their claim up, and a
+//! claim left behind by a crashed invocation expires after
+//! [`CLAIM_LEASE_SECS`], so retries still go through. The `expires_at`
+//! attribute may serve as TTL of the table.
+//!
+//! The send itself moves the claim from `processing` to `sending` right
+//! before SES is called, and to `forwarded`, with `forwarded = true` and
+//! the SES message id, before the invocation succeeds. An invocation which
+//! crashed between the two leaves the message `sending`: its retry can't
+//! tell whether SES delivered it and reports [`Claim::Unconfirmed`]
+//! instead of sending it again.
+//!
+//! Every claim carries the random token of its [`Lease`], and only the
+//! holder of the current token moves or gives it up: an invocation which
+//! stalled past its lease and was taken over fails to begin its send, and
+//! can't delete the claim of the one which took over.
+use crate::aws::{self, AwsError};
+use lambda_runtime::Error;
+use mailparse::{MailHeader, MailHeaderMap};
+use rand::Rng;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde_json::{json, Value};
+
+/// Partition key attribute of the table.
+pub const KEY_ATTRIBUTE: &str = "message_id";
+
+/// Counter of messages whose earlier send was never confirmed.
+pub const UNCONFIRMED_COUNTER: &str = "unconfirmed_send";
+
+/// Seconds after which a claim of an unfinished invocation may be taken.
+pub const CLAIM_LEASE_SECS: i64 = 30;
+
+/// State of a claimed message whose forward is under way.
+const PROCESSING: &str = "processing";
+
+/// State of a claimed message handed to SES.
+const SENDING: &str = "sending";
+
+/// State of a message which was forwarded.
+const FORWARDED: &str = "forwarded";
+
+/// Result of claiming a message.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Claim {
+    /// The message is new, or its earlier claim lapsed
+    Claimed,
+
+    /// The message is being or was forwarded
+    Duplicate,
+
+    /// An earlier invocation stopped while sending the message, which may
+    /// or may not have been delivered
+    Unconfirmed,
+}
+
+/// Claim of a message by an invocation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Lease {
+    /// Table the claim is written to
+    pub table: String,
+
+    /// Deduplication key of the message, see [`key`]
+    pub key: String,
+
+    /// Token telling this claim from those of other invocations
+    pub token: String,
+}
+
+impl Lease {
+    /// Creates the lease of `key` in `table`, with a new random token.
+    pub fn new(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Lease {
+            table: table.into(),
+            key: key.into(),
+            token: format!("{:032x}", rand::thread_rng().gen::<u128>()),
+        }
+    }
+}
+
+/// Returns the deduplication key of a message: its `Message-ID` header
+/// when `by_header` is set and it has one, otherwise its SES message id.
+pub fn key(
+    headers: &[MailHeader],
+    ses_message_id: &str,
+    by_header: bool,
+) -> String {
+    headers
+        .get_first_value("Message-ID")
+        .map(|id| id.trim().to_owned())
+        .filter(|id| by_header && !id.is_empty())
+        .unwrap_or_else(|| ses_message_id.to_owned())
+}
+
+/// Claims the key of `lease` at `now` for `ttl` seconds.
+pub async fn claim(lease: &Lease, now: i64, ttl: i64) -> Result<Claim, Error> {
+    let result = call("PutItem", claim_request(lease, now, ttl)).await;
+    match result {
+        Ok(_) => Ok(Claim::Claimed),
+        Err(e) if is_condition_failure(&e) => Ok(held_claim(&e)),
+        Err(e) => Err(e),
+    }
+}
+
+fn claim_request(lease: &Lease, now: i64, ttl: i64) -> Value {
+    json!({
+        "TableName": lease.table,
+        "Item": {
+            KEY_ATTRIBUTE: { "S": lease.key },
+            "state": { "S": PROCESSING },
+            "claim_token": { "S": lease.token },
+            "claimed_at": { "N": now.to_string() },
+            "expires_at": { "N": (now + ttl).to_string() },
+        },
+        "ConditionExpression": "attribute_not_exists(#key) \
+            OR expires_at <= :now \
+            OR (#state = :processing AND claimed_at <= :stale)",
+        "ExpressionAttributeNames": {
+            "#key": KEY_ATTRIBUTE,
+            "#state": "state",
+        },
+        "ExpressionAttributeValues": {
+            ":now": { "N": now.to_string() },
+            ":stale": { "N": (now - CLAIM_LEASE_SECS).to_string() },
+            ":processing": { "S": PROCESSING },
+        },
+        "ReturnValuesOnConditionCheckFailure": "ALL_OLD",
+    })
+}
+
+/// Moves the claim of `lease` to sending at `now`, failing when it is no
+/// longer held, as after its lease was taken over.
+pub async fn begin_send(lease: &Lease, now: i64) -> Result<(), Error> {
+    call("UpdateItem", begin_send_request(lease, now))
+        .await
+        .map_err(|e| lost(lease, e, "sending"))?;
+    Ok(())
+}
+
+fn begin_send_request(lease: &Lease, now: i64) -> Value {
+    json!({
+        "TableName": lease.table,
+        "Key": { KEY_ATTRIBUTE: { "S": lease.key } },
+        "UpdateExpression": "SET #state = :sending, sending_at = :now",
+        "ConditionExpression": "#state = :processing AND claim_token = :token",
+        "ExpressionAttributeNames": { "#state": "state" },
+        "ExpressionAttributeValues": {
+            ":sending": { "S": SENDING },
+            ":processing": { "S": PROCESSING },
+            ":token": { "S": lease.token },
+            ":now": { "N": now.to_string() },
+        },
+    })
+}
+
+/// Marks the claim of `lease` as forwarded, recording the SES message id
+/// of the forward when it was sent, failing when it is no longer held.
+pub async fn complete(
+    lease: &Lease,
+    ses_message_id: Option<&str>,
+) -> Result<(), Error> {
+    call("UpdateItem", complete_request(lease, ses_message_id))
+        .await
+        .map_err(|e| lost(lease, e, "completing"))?;
+    Ok(())
+}
+
+fn complete_request(lease: &Lease, ses_message_id: Option<&str>) -> Value {
+    let mut update = "SET #state = :forwarded, forwarded = :true".to_owned();
+    let mut values = json!({
+        ":forwarded": { "S": FORWARDED },
+        ":true": { "BOOL": true },
+        ":token": { "S": lease.token },
+    });
+    if let Some(id) = ses_message_id {
+        update.push_str(", ses_message_id = :id");
+        values[":id"] = json!({ "S": id });
+    }
+    json!({
+        "TableName": lease.table,
+        "Key": { KEY_ATTRIBUTE: { "S": lease.key } },
+        "UpdateExpression": update,
+        "ConditionExpression": "claim_token = :token",
+        "ExpressionAttributeNames": { "#state": "state" },
+        "ExpressionAttributeValues": values,
+    })
+}
+
+/// Gives up the claim of `lease`, so a retry may forward it. A claim
+/// taken over by another invocation is left to it.
+pub async fn release(lease: &Lease) -> Result<(), Error> {
+    match call("DeleteItem", release_request(lease)).await {
+        Err(e) if !is_condition_failure(&e) => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn release_request(lease: &Lease) -> Value {
+    json!({
+        "TableName": lease.table,
+        "Key": { KEY_ATTRIBUTE: { "S": lease.key } },
+        "ConditionExpression": "claim_token = :token",
+        "ExpressionAttributeValues": { ":token": { "S": lease.token } },
+    })
+}
+
+/// Returns the error of a conditional write of `lease` made while
+/// `doing`, telling when the claim was taken over.
+fn lost(lease: &Lease, error: Error, doing: &str) -> Error {
+    match is_condition_failure(&error) {
+        true => {
+            format!("Claim of {} was lost before {}", lease.key, doing).into()
+        }
+        false => error,
+    }
+}
+
+/// Calls the DynamoDB `action` with `payload`.
+async fn call(action: &str, payload: Value) -> Result<Value, Error> {
+    let mut request =
+        SignedRequest::new("POST", "dynamodb", &Region::default(), "/");
+    request
+        .add_header("x-amz-target", &format!("DynamoDB_20120810.{}", action));
+    request.set_content_type("application/x-amz-json-1.0".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&payload)?));
+    let response = aws::dispatch(request).await?;
+    Ok(serde_json::from_slice(&response.body).unwrap_or_default())
+}
+
+/// Returns how the claim of the item in the conditional write failure
+/// `error` is held.
+fn held_claim(error: &Error) -> Claim {
+    let state = error
+        .downcast_ref::<AwsError>()
+        .and_then(|e| serde_json::from_str::<Value>(&e.body).ok())
+        .and_then(|body| {
+            body["Item"]["state"]["S"].as_str().map(str::to_owned)
+        });
+    match state.as_deref() {
+        Some(SENDING) => Claim::Unconfirmed,
+        _ => Claim::Duplicate,
+    }
+}
+
+/// Whether `error` is the failure of a conditional write.
+fn is_condition_failure(error: &Error) -> bool {
+    error
+        .downcast_ref::<AwsError>()
+        .is_some_and(|e| e.body.contains("ConditionalCheckFailedException"))
+}
+
+/** Test module for message deduplication */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key() {
+        let (headers, _) =
+            mailparse::parse_headers(b"Message-ID: <a@b.io> \r\n\r\n").unwrap();
+        assert_eq!(key(&headers, "0100abc", true), "<a@b.io>");
+        assert_eq!(key(&headers, "0100abc", false), "0100abc");
+        assert_eq!(key(&[], "0100abc", true), "0100abc");
+    }
+
+    #[test]
+    fn test_is_condition_failure() {
+        let error: Error = Box::new(AwsError {
+            status: 400,
+            body: r#"{"__type":"...#ConditionalCheckFailedException"}"#
+                .to_owned(),
+        });
+        assert!(is_condition_failure(&error));
+        assert!(!is_condition_failure(&"timeout".into()));
+        assert_eq!(held_claim(&error), Claim::Duplicate);
+
+        let error: Error = Box::new(AwsError {
+            status: 400,
+            body: json!({
+                "__type": "...#ConditionalCheckFailedException",
+                "Item": {
+                    KEY_ATTRIBUTE: { "S": "0100abc" },
+                    "state": { "S": SENDING },
+                },
+            })
+            .to_string(),
+        });
+        assert_eq!(held_claim(&error), Claim::Unconfirmed);
+    }
+
+    #[test]
+    fn test_lease_token() {
+        let (first, second) =
+            (Lease::new("dedup", "a"), Lease::new("dedup", "a"));
+        assert_ne!(first.token, second.token);
+        let claimed = claim_request(&first, 1_000, 60);
+        assert_eq!(claimed["Item"]["claim_token"]["S"], first.token.as_str());
+        for request in [
+            begin_send_request(&first, 1_000),
+            complete_request(&first, Some("0100abc")),
+            release_request(&first),
+        ] {
+            assert!(request["ConditionExpression"]
+                .as_str()
+                .unwrap()
+                .contains("claim_token = :token"));
+            assert_eq!(
+                request["ExpressionAttributeValues"][":token"]["S"],
+                first.token.as_str()
+            );
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Mail delivered by inbound parse webhooks rather than SES receipt rules.
+//!
+//! A `POST` to [`INBOUND_PATH`] through a Function URL or API Gateway,
+//! authorized by the `inbound_webhook_token` as a bearer token or `token`
+//! query parameter, is turned into the SNS notification SES would have
+//! published and forwarded through the same pipeline. The body may be:
+//!
+//! - the raw message, as `message/rfc822`, `text/plain` or
+//!   `application/octet-stream`
+//! - a `multipart/form-data` form with the raw message in `email`, as
+//!   posted by SendGrid, or `body-mime`, as posted by Mailgun
+//! - a JSON object with the raw message in `raw` and, optionally, the
+//!   envelope `sender` and `recipients`
+//!
+//! Recipients default to the `To` and `Cc` addresses of the message, and
+//! the sender to its `Return-Path` or `From` address. Webhook mail has no
+//! SES verdicts, so every verdict is `GRAY`.
+use crate::content;
+use crate::http;
+use crate::quiet;
+use mailparse::{MailAddr, MailHeaderMap};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// Path of inbound webhook requests.
+pub const INBOUND_PATH: &str = "/inbound";
+
+/// Status of the verdicts of webhook mail, which SES did not scan.
+pub const VERDICT: &str = "GRAY";
+
+/// Message delivered by a webhook, with its envelope.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct InboundMail {
+    /// Raw message
+    pub raw: String,
+
+    /// Envelope sender, read from the message when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+
+    /// Envelope recipients, read from the message when empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+}
+
+/// Returns the mail in the body of the HTTP request `event`.
+pub fn from_http(event: &Value) -> Result<InboundMail, String> {
+    let body = http::body(event)?;
+    let content_type =
+        http::header(event, "content-type").unwrap_or("message/rfc822");
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    match mime_type.as_str() {
+        "application/json" => serde_json::from_slice(&body)
+            .map_err(|e| format!("Invalid inbound mail: {}", e)),
+        "multipart/form-data" => from_form(content_type, &body),
+        _ => Ok(InboundMail {
+            raw: String::from_utf8_lossy(&body).into_owned(),
+            ..Default::default()
+        }),
+    }
+}
+
+/// Returns the mail posted in the `multipart/form-data` `body`.
+fn from_form(content_type: &str, body: &[u8]) -> Result<InboundMail, String> {
+    let mut form =
+        format!("Content-Type: {}\r\n\r\n", content_type).into_bytes();
+    form.extend_from_slice(body);
+    let form =
+        content::parse(&form).map_err(|e| format!("Invalid form: {}", e))?;
+    let mut mail = InboundMail::default();
+    for field in &form.subparts {
+        let disposition = field.get_content_disposition();
+        let value = field.get_body().unwrap_or_default();
+        match disposition.params.get("name").map(String::as_str) {
+            Some("email" | "body-mime") => mail.raw = value,
+            Some("sender") => mail.sender = Some(value.trim().to_owned()),
+            Some("recipient") => mail
+                .recipients
+                .extend(value.split(',').map(|r| r.trim().to_owned())),
+            Some("envelope") => {
+                let envelope: Value = serde_json::from_str(&value)
+                    .map_err(|e| format!("Invalid envelope: {}", e))?;
+                mail.sender = envelope["from"].as_str().map(str::to_owned);
+                mail.recipients.extend(
+                    envelope["to"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(Value::as_str)
+                        .map(str::to_owned),
+                );
+            }
+            _ => {}
+        }
+    }
+    if mail.raw.is_empty() {
+        return Err("No raw message in the form".to_owned());
+    }
+    Ok(mail)
+}
+
+impl InboundMail {
+    /// Returns the SNS event of the SES notification of the mail received
+    /// at `now`, which the forwarder handles like any other.
+    pub fn to_event(&self, now: i64) -> Result<Value, String> {
+        let message = content::parse(self.raw.as_bytes())
+            .map_err(|e| format!("Invalid message: {}", e))?;
+        let headers = &message.headers;
+        let recipients = if self.recipients.is_empty() {
+            addresses(headers, &["To", "Cc"])
+        } else {
+            self.recipients.clone()
+        };
+        if recipients.is_empty() {
+            return Err("No recipients".to_owned());
+        }
+        let sender = self
+            .sender
+            .clone()
+            .or_else(|| addresses(headers, &["Return-Path"]).pop())
+            .or_else(|| addresses(headers, &["From"]).pop())
+            .unwrap_or_default();
+        let notification = json!({
+            "notificationType": "Received",
+            "mail": {
+                "timestamp": quiet::timestamp(now),
+                "source": sender,
+                "messageId": hex::encode(Sha256::digest(&self.raw)),
+                "destination": recipients,
+                "commonHeaders": {
+                    "subject": headers
+                        .get_first_value("Subject")
+                        .unwrap_or_default(),
+                    "returnPath": sender,
+                },
+            },
+            "receipt": {
+                "spamVerdict": { "status": VERDICT },
+                "virusVerdict": { "status": VERDICT },
+                "spfVerdict": { "status": VERDICT },
+                "dkimVerdict": { "status": VERDICT },
+                "dmarcVerdict": { "status": VERDICT },
+            },
+            "content": self.raw,
+        });
+        Ok(json!({
+            "Records": [{
+                "EventSource": "privatemail:inbound",
+                "Sns": { "Message": notification.to_string() },
+            }]
+        }))
+    }
+}
+
+/// Returns the addresses in the headers `names` of a message.
+fn addresses(headers: &[mailparse::MailHeader], names: &[&str]) -> Vec<String> {
+    let mut addresses = Vec::new();
+    for header in headers.iter().filter(|h| {
+        names.iter().any(|name| h.get_key_ref().eq_ignore_ascii_case(name))
+    }) {
+        let Ok(list) = mailparse::addrparse_header(header) else {
+            continue;
+        };
+        for address in list.iter() {
+            match address {
+                MailAddr::Single(single) => addresses.push(single.addr.clone()),
+                MailAddr::Group(group) => addresses.extend(
+                    group.addrs.iter().map(|single| single.addr.clone()),
+                ),
+            }
+        }
+    }
+    addresses
+}
+
+/** Test module for inbound webhook mail */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: &str = "From: Eru <eru@fufu.soup>\r\n\
+                       To: hello@nyah.dev, Team: jobs@nyah.dev;\r\n\
+                       Subject: Fufu\r\n\r\nHello!\r\n";
+
+    fn request(content_type: &str, body: &str) -> Value {
+        json!({
+            "rawPath": INBOUND_PATH,
+            "requestContext": { "http": { "method": "POST" } },
+            "headers": { "content-type": content_type },
+            "body": body,
+        })
+    }
+
+    #[test]
+    fn test_raw() {
+        let mail = from_http(&request("message/rfc822", RAW)).unwrap();
+        assert_eq!(mail.raw, RAW);
+        let event = mail.to_event(1647679576).unwrap();
+        let message: Value = serde_json::from_str(
+            event["Records"][0]["Sns"]["Message"].as_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(message["mail"]["timestamp"], "2022-03-19T08:46:16.000Z");
+        assert_eq!(message["mail"]["source"], "eru@fufu.soup");
+        assert_eq!(
+            message["mail"]["destination"],
+            json!(["hello@nyah.dev", "jobs@nyah.dev"])
+        );
+        assert_eq!(message["mail"]["commonHeaders"]["subject"], "Fufu");
+        assert_eq!(message["receipt"]["spamVerdict"]["status"], VERDICT);
+        assert_eq!(message["content"], RAW);
+    }
+
+    #[test]
+    fn test_form() {
+        let body = format!(
+            "--xyz\r\n\
+             Content-Disposition: form-data; name=\"envelope\"\r\n\r\n\
+             {{\"to\":[\"hello@nyah.dev\"],\"from\":\"bounce@fufu.soup\"}}\r\n\
+             --xyz\r\n\
+             Content-Disposition: form-data; name=\"email\"\r\n\r\n\
+             {}\r\n\
+             --xyz--\r\n",
+            RAW
+        );
+        let event = request("multipart/form-data; boundary=xyz", &body);
+        let mail = from_http(&event).unwrap();
+        assert_eq!(mail.sender.as_deref(), Some("bounce@fufu.soup"));
+        assert_eq!(mail.recipients, ["hello@nyah.dev"]);
+        assert!(mail.raw.starts_with("From: Eru <eru@fufu.soup>\r\n"));
+
+        let event = request("multipart/form-data; boundary=xyz", "--xyz--");
+        assert!(from_http(&event).is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let body = json!({ "raw": RAW, "recipients": ["jobs@nyah.dev"] });
+        let event = request("application/json", &body.to_string());
+        let mail = from_http(&event).unwrap();
+        assert_eq!(mail.recipients, ["jobs@nyah.dev"]);
+        assert_eq!(mail.sender, None);
+
+        let mail =
+            InboundMail { raw: "Subject: Hi\r\n\r\n".to_owned(), ..mail };
+        assert!(InboundMail { recipients: vec![], ..mail }
+            .to_event(0)
+            .is_err());
+    }
+}
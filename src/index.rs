@@ -0,0 +1,275 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! DynamoDB metadata index of processed mail.
+//!
+//! With `index_table` set, a compact item describing every processed message
+//! is written to the table, keyed by the string attribute [`KEY_ATTRIBUTE`].
+//! Global secondary indexes make lookups by sender and date fast:
+//! [`SENDER_INDEX`], on `sender_domain` and `timestamp`, finds everything
+//! from `bank.com` last month, and [`DATE_INDEX`], on `day` and
+//! `timestamp`, everything received on a day.
+use crate::aws;
+use crate::store::MailAction;
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+/// Partition key attribute of the index table.
+pub const KEY_ATTRIBUTE: &str = "message_id";
+
+/// Global secondary index on `sender_domain` and `timestamp`.
+pub const SENDER_INDEX: &str = "sender_domain-timestamp";
+
+/// Global secondary index on `day` and `timestamp`.
+pub const DATE_INDEX: &str = "day-timestamp";
+
+/// Indexed metadata of a processed message.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexEntry {
+    /// SES message id
+    pub message_id: String,
+
+    /// RFC 3339 timestamp at which SES received the message
+    pub timestamp: String,
+
+    /// Envelope sender
+    pub sender: String,
+
+    /// Envelope recipients
+    pub recipients: Vec<String>,
+
+    /// Subject header
+    pub subject: String,
+
+    /// Verdict status by lowercase check name, e.g. `spam`
+    pub verdicts: BTreeMap<String, String>,
+
+    /// Outcome of processing the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<MailAction>,
+
+    /// Key of the message in the `archive_bucket`, if archived there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_key: Option<String>,
+
+    /// Key of the message in the store, if stored there
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_key: Option<String>,
+
+    /// Unix time the message was indexed at
+    pub indexed_at: i64,
+}
+
+impl IndexEntry {
+    /// Returns the lowercase domain of the sender.
+    pub fn sender_domain(&self) -> String {
+        let sender = self.sender.trim().trim_end_matches('>');
+        sender.rsplit('@').next().unwrap_or_default().to_lowercase()
+    }
+
+    /// Returns the `YYYY-MM-DD` day the message was received.
+    pub fn day(&self) -> &str {
+        self.timestamp.get(..10).unwrap_or(&self.timestamp)
+    }
+
+    /// Returns the DynamoDB item of the entry.
+    pub fn to_item(&self) -> Value {
+        let string = |value: &str| json!({ "S": value });
+        let recipients: Vec<Value> =
+            self.recipients.iter().map(|r| string(r)).collect();
+        let verdicts: Map<String, Value> = self
+            .verdicts
+            .iter()
+            .map(|(check, status)| (check.clone(), string(status)))
+            .collect();
+        let mut item = Map::new();
+        item.insert(KEY_ATTRIBUTE.to_owned(), string(&self.message_id));
+        item.insert("timestamp".to_owned(), string(&self.timestamp));
+        item.insert("day".to_owned(), string(self.day()));
+        item.insert("sender".to_owned(), string(&self.sender.to_lowercase()));
+        item.insert("sender_domain".to_owned(), string(&self.sender_domain()));
+        item.insert("recipients".to_owned(), json!({ "L": recipients }));
+        item.insert("subject".to_owned(), string(&self.subject));
+        item.insert("verdicts".to_owned(), json!({ "M": verdicts }));
+        if let Some(action) = self.action {
+            item.insert("action".to_owned(), string(action.as_str()));
+        }
+        if let Some(key) = &self.archive_key {
+            item.insert("archive_key".to_owned(), string(key));
+        }
+        if let Some(key) = &self.object_key {
+            item.insert("object_key".to_owned(), string(key));
+        }
+        item.insert(
+            "indexed_at".to_owned(),
+            json!({ "N": self.indexed_at.to_string() }),
+        );
+        Value::Object(item)
+    }
+
+    /// Reads an entry from the DynamoDB `item`, `None` when it has no
+    /// message id.
+    pub fn from_item(item: &Value) -> Option<Self> {
+        let string = |name: &str| item[name]["S"].as_str().map(str::to_owned);
+        Some(IndexEntry {
+            message_id: string(KEY_ATTRIBUTE)?,
+            timestamp: string("timestamp").unwrap_or_default(),
+            sender: string("sender").unwrap_or_default(),
+            recipients: item["recipients"]["L"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|r| r["S"].as_str().map(str::to_owned))
+                .collect(),
+            subject: string("subject").unwrap_or_default(),
+            verdicts: item["verdicts"]["M"]
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter_map(|(check, status)| {
+                    Some((check.clone(), status["S"].as_str()?.to_owned()))
+                })
+                .collect(),
+            action: string("action").and_then(|a| a.parse().ok()),
+            archive_key: string("archive_key"),
+            object_key: string("object_key"),
+            indexed_at: item["indexed_at"]["N"]
+                .as_str()
+                .and_then(|n| n.parse().ok())
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Writes `entry` to `table`, replacing any previous entry of the message.
+pub async fn put(table: &str, entry: &IndexEntry) -> Result<(), Error> {
+    call(
+        table,
+        "PutItem",
+        json!({ "TableName": table, "Item": entry.to_item() }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Deletes the entry of `message_id` from `table`, if any.
+pub async fn delete(table: &str, message_id: &str) -> Result<(), Error> {
+    call(
+        table,
+        "DeleteItem",
+        json!({
+            "TableName": table,
+            "Key": { KEY_ATTRIBUTE: { "S": message_id } },
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Returns the entries of the global secondary index `index` whose
+/// partition key `attribute` is `value`, received from `from` to `until`,
+/// RFC 3339 timestamps, oldest first. Stops once `limit` entries were read.
+pub async fn query(
+    table: &str,
+    index: &str,
+    attribute: &str,
+    value: &str,
+    (from, until): (&str, &str),
+    limit: usize,
+) -> Result<Vec<IndexEntry>, Error> {
+    let mut entries = Vec::new();
+    let mut start_key: Option<Value> = None;
+    loop {
+        let mut payload = json!({
+            "TableName": table,
+            "IndexName": index,
+            "KeyConditionExpression":
+                "#key = :key AND #timestamp BETWEEN :from AND :until",
+            "ExpressionAttributeNames": {
+                "#key": attribute,
+                "#timestamp": "timestamp",
+            },
+            "ExpressionAttributeValues": {
+                ":key": { "S": value },
+                ":from": { "S": from },
+                ":until": { "S": until },
+            },
+            "Limit": limit.saturating_sub(entries.len()),
+        });
+        if let Some(start_key) = start_key.take() {
+            payload["ExclusiveStartKey"] = start_key;
+        }
+        let response = call(table, "Query", payload).await?;
+        entries.extend(
+            response["Items"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(IndexEntry::from_item),
+        );
+        match response.get("LastEvaluatedKey") {
+            Some(key) if entries.len() < limit => start_key = Some(key.clone()),
+            _ => return Ok(entries),
+        }
+    }
+}
+
+/// Calls the DynamoDB `operation` on `table` with `payload`.
+async fn call(
+    table: &str,
+    operation: &str,
+    payload: Value,
+) -> Result<Value, Error> {
+    let mut request =
+        SignedRequest::new("POST", "dynamodb", &aws::arn_region(table), "/");
+    request.add_header(
+        "x-amz-target",
+        &format!("DynamoDB_20120810.{}", operation),
+    );
+    request.set_content_type("application/x-amz-json-1.0".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&payload)?));
+    let response = aws::dispatch(request).await?;
+    Ok(serde_json::from_slice(&response.body)?)
+}
+
+/** Test module for the metadata index */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_item() {
+        let entry = IndexEntry {
+            message_id: "abc".to_owned(),
+            timestamp: "2022-03-19T08:46:16.420Z".to_owned(),
+            sender: "Alerts@Bank.com".to_owned(),
+            recipients: vec!["hello@nyah.dev".to_owned()],
+            subject: "Statement".to_owned(),
+            verdicts: BTreeMap::from([("spam".to_owned(), "PASS".to_owned())]),
+            action: Some(MailAction::Forwarded),
+            archive_key: Some("2022/03/19/abc.eml".to_owned()),
+            object_key: None,
+            indexed_at: 1647679577,
+        };
+        let item = entry.to_item();
+        assert_eq!(item["day"]["S"], "2022-03-19");
+        assert_eq!(item["sender_domain"]["S"], "bank.com");
+        assert_eq!(item["verdicts"]["M"]["spam"]["S"], "PASS");
+        assert!(item.get("object_key").is_none());
+
+        let read = IndexEntry::from_item(&item).unwrap();
+        assert_eq!(read.sender, "alerts@bank.com");
+        assert_eq!(IndexEntry { sender: entry.sender.clone(), ..read }, entry);
+        assert_eq!(IndexEntry::from_item(&json!({})), None);
+    }
+}
@@ -13,31 +13,127 @@
 //!
 //! ```
 //! use crate::lib::config::PrivatEmailConfig;
-//! use serde::{Deserialize, Serialize};
 //!
 //! async fn privatemail_handler() {
 //!     // Initialize PrivatEmailConfig object.
-//!     let email_config = PrivatEmailConfig::default();
-//!
+//!     let email_config = PrivatEmailConfig::builder()
+//!         .from_email("hello@nyah.dev")
+//!         .to_email("me@example.com")
+//!         .black_list(["spam.example"])
+//!         .build();
 //! }
 //! ```
 
 #![forbid(unsafe_code)]
 #![allow(clippy::derive_partial_eq_without_eq)]
 
+pub mod alert;
+pub mod archive;
+pub mod attachments;
+pub mod auth;
+pub mod autoreply;
+pub mod aws;
+pub mod banner;
+pub mod breaker;
+pub mod classify;
 pub mod config;
+pub mod content;
+pub mod deadletter;
+pub mod digest;
+pub mod dkim;
+pub mod dnsbl;
+pub mod dryrun;
+pub mod encoding;
+pub mod erasure;
+pub mod events;
+pub mod failure;
+pub mod feedback;
+pub mod forward;
+pub mod html;
+pub mod http;
+pub mod idempotency;
+pub mod inbound;
+pub mod index;
+pub mod limits;
+pub mod local;
+pub mod logging;
+pub mod mbox;
+pub mod migration;
+pub mod mime;
+pub mod notify;
+pub mod outbound;
+pub mod pgp;
+pub mod preview;
+pub mod quiet;
+pub mod quota;
+pub mod received;
+pub mod relay;
+pub mod replay;
+pub mod retention;
+pub mod router;
+pub mod rules;
+pub mod search;
+pub mod sender;
+#[cfg(feature = "sesv2")]
+pub mod sesv2;
+pub mod smime;
+pub mod sns;
+pub mod srs;
+pub mod stats;
+pub mod store;
+pub mod summary;
+pub mod suppression;
+pub mod test_support;
+pub mod thread;
+pub mod tracking;
+pub mod xray;
 
+use attachments::AttachmentAction;
+use auth::{AuthCheck, VerdictPolicy};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
 use config::PrivatEmailConfig;
+use dkim::DkimSigner;
+use failure::{FailureClass, FailurePolicy};
+use forward::ForwardMode;
+use futures::stream::{self, StreamExt};
+use idempotency::Claim;
 use lambda_runtime::{Error, LambdaEvent};
-use mailparse::parse_mail;
+use mailparse::{MailHeader, MailHeaderMap, ParsedMail};
+use notify::Notifier;
+use preview::Renderer;
 use rusoto_core::Region;
 use rusoto_ses::{
-    Body, Content, Destination, Message, SendEmailRequest, Ses, SesClient,
+    Body, Content, Destination, Message, MessageTag, SendEmailRequest,
+    SesClient,
 };
+use sender::EmailSender;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{collections::HashMap, env, fmt::Debug};
-use tracing::{error, trace};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{env, fmt::Debug};
+use store::{MailAction, MailRecord, MailStore, ObjectStore};
+use tokio::sync::OnceCell;
+use tracing::{error, info, trace, warn};
+
+/// Status code of a message forwarded or sent, and of other events handled.
+pub const STATUS_OK: u32 = 200;
+
+/// Status code of a message intentionally not forwarded: dropped, blocked,
+/// quarantined, held back or a duplicate.
+pub const STATUS_SKIPPED: u32 = 202;
+
+/// Status code of an event or request which could not be parsed.
+pub const STATUS_MALFORMED: u32 = 400;
+
+/// Status code of a send SES failed, once the failure policy was applied.
+pub const STATUS_SES_FAILURE: u32 = 502;
+
+/// Encoding of the content set on the SNS action of a receipt rule which
+/// delivers it base64 encoded, keeping 8-bit messages intact.
+pub const BASE64_ENCODING: &str = "BASE64";
 
 /// LambdaResponse: The Outgoing response being passed by the Lambda
 #[derive(Debug, Default, Clone, Serialize)]
@@ -52,8 +148,44 @@ pub struct LambdaResponse {
     /// response headers for lambda response
     headers: HashMap<String, String>,
 
-    /// response body for LambdaResponse struct
+    /// response body for LambdaResponse struct, the JSON of `details`
     body: String,
+
+    /// Correlation data of the response, serialized as its body
+    #[serde(skip)]
+    details: ResponseBody,
+}
+
+/// Body of a [`LambdaResponse`], correlating it with the message handled.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResponseBody {
+    /// Outcome or error description
+    pub message: String,
+
+    /// SES message id of the received message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+
+    /// SES message id of the forward, reply or relayed message sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_message_id: Option<String>,
+
+    /// Action taken on the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<MailAction>,
+
+    /// Time spent handling the event, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processing_ms: Option<u64>,
+
+    /// Index entries matching a search
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<search::SearchResult>>,
+
+    /// Report of the erasure of the mail of a sender
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub erasure: Option<erasure::Erasure>,
 }
 
 impl LambdaResponse {
@@ -62,13 +194,103 @@ impl LambdaResponse {
     pub fn new(status_code: u32, body: &str) -> Self {
         let mut header = HashMap::new();
         header.insert("content-type".to_owned(), "application/json".to_owned());
+        let details =
+            ResponseBody { message: body.to_owned(), ..Default::default() };
         LambdaResponse {
             is_base_64_encoded: false,
             status_code,
             headers: header,
-            body: serde_json::to_string(&body).unwrap(),
+            body: serde_json::to_string(&details).unwrap(),
+            details,
         }
     }
+
+    /// Sets the SES message id of the received message.
+    pub fn with_message_id(mut self, message_id: &str) -> Self {
+        self.details.message_id = Some(message_id.to_owned());
+        self.encode()
+    }
+
+    /// Sets the SES message id of the message sent.
+    pub fn with_forwarded_message_id(mut self, message_id: &str) -> Self {
+        self.details.forwarded_message_id = Some(message_id.to_owned());
+        self.encode()
+    }
+
+    /// Sets the action taken on the message.
+    pub fn with_action(mut self, action: MailAction) -> Self {
+        self.details.action = Some(action);
+        self.encode()
+    }
+
+    /// Sets the time spent handling the event.
+    pub fn with_processing_time(mut self, elapsed: Duration) -> Self {
+        self.details.processing_ms = Some(elapsed.as_millis() as u64);
+        self.encode()
+    }
+
+    /// Sets the index entries matching a search.
+    pub fn with_results(mut self, results: Vec<search::SearchResult>) -> Self {
+        self.details.results = Some(results);
+        self.encode()
+    }
+
+    /// Sets the report of the erasure of the mail of a sender.
+    pub fn with_erasure(mut self, erasure: erasure::Erasure) -> Self {
+        self.details.erasure = Some(erasure);
+        self.encode()
+    }
+
+    /// Serializes the details as the body.
+    fn encode(mut self) -> Self {
+        self.body = serde_json::to_string(&self.details).unwrap();
+        self
+    }
+
+    /// Returns the status code of the response.
+    pub fn status_code(&self) -> u32 {
+        self.status_code
+    }
+
+    /// Returns the body of the response.
+    pub fn body(&self) -> &ResponseBody {
+        &self.details
+    }
+
+    /// Returns the outcome or error description.
+    pub fn message(&self) -> &str {
+        &self.details.message
+    }
+
+    /// Returns the SES message id of the received message.
+    pub fn message_id(&self) -> Option<&str> {
+        self.details.message_id.as_deref()
+    }
+
+    /// Returns the SES message id of the message sent.
+    pub fn forwarded_message_id(&self) -> Option<&str> {
+        self.details.forwarded_message_id.as_deref()
+    }
+
+    /// Returns the action taken on the message.
+    pub fn action(&self) -> Option<MailAction> {
+        self.details.action
+    }
+
+    /// Returns the time spent handling the event, in milliseconds.
+    pub fn processing_ms(&self) -> Option<u64> {
+        self.details.processing_ms
+    }
+
+    /// Returns the index entries matching a search.
+    pub fn results(&self) -> Option<&[search::SearchResult]> {
+        self.details.results.as_deref()
+    }
+
+    /// Returns the report of the erasure of the mail of a sender.
+    pub fn erasure(&self) -> Option<&erasure::Erasure> {
+        self.details.erasure.as_ref()
+    }
 }
 
 impl std::fmt::Display for LambdaResponse {
@@ -87,11 +309,39 @@ pub struct EmailReceiptNotification {
     notification_type: String,
     mail: Mail,
     receipt: Receipt,
-    content: String,
+    #[serde(with = "raw_content")]
+    content: Bytes,
+    /// Set by [`PrivatEmailService::handle_replay`] only, never by events
+    #[serde(skip)]
+    replay: Option<replay::Replay>,
     // #[serde(flatten)]
     // other: HashMap<String, Value>,
 }
 
+/// Serde of the raw content, kept in the buffer it was deserialized into
+/// and shared with the stores instead of copied.
+mod raw_content {
+    use bytes::Bytes;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(
+        content: &Bytes,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&String::from_utf8_lossy(content))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Bytes, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Bytes::from(String::deserialize(deserializer)?))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Mail {
     timestamp: String,
@@ -123,6 +373,12 @@ pub struct Receipt {
     spam_verdict: Verdict,
     #[serde(rename = "virusVerdict")]
     virus_verdict: Verdict,
+    #[serde(rename = "spfVerdict", default)]
+    spf_verdict: Verdict,
+    #[serde(rename = "dkimVerdict", default)]
+    dkim_verdict: Verdict,
+    #[serde(rename = "dmarcVerdict", default)]
+    dmarc_verdict: Verdict,
     #[serde(flatten)]
     other: HashMap<String, Value>,
 }
@@ -132,177 +388,3662 @@ pub struct Verdict {
     status: String,
 }
 
-/// PrivatEmail_Handler: processes incoming messages from SNS
-/// and forwards to the appropriate recipient email
-pub async fn privatemail_handler(
-    lambda_event: LambdaEvent<Value>,
-) -> Result<LambdaResponse, Error> {
-    let (event, ctx) = lambda_event.into_parts();
+impl Receipt {
+    /// Returns the sender authentication checks the message failed.
+    pub fn failed_checks(&self) -> Vec<AuthCheck> {
+        [
+            (AuthCheck::Spf, &self.spf_verdict),
+            (AuthCheck::Dkim, &self.dkim_verdict),
+            (AuthCheck::Dmarc, &self.dmarc_verdict),
+        ]
+        .into_iter()
+        .filter(|(_, verdict)| verdict.status == auth::FAIL)
+        .map(|(check, _)| check)
+        .collect()
+    }
 
-    // install global collector configured based on RUST_LOG env var
-    let xray_trace_id = &ctx.xray_trace_id.as_ref().unwrap();
-    env::set_var("_X_AMZN_TRACE_ID", xray_trace_id);
+    /// Whether the message passed `check`.
+    pub fn passed(&self, check: AuthCheck) -> bool {
+        let verdict = match check {
+            AuthCheck::Spf => &self.spf_verdict,
+            AuthCheck::Dkim => &self.dkim_verdict,
+            AuthCheck::Dmarc => &self.dmarc_verdict,
+        };
+        verdict.status == auth::PASS
+    }
 
-    // Enable Cloudwatch error logging at runtime
-    trace!("Event: {:#?}, Context: {:#?}", event, ctx);
+    /// Whether the content was delivered base64 encoded.
+    fn base64_content(&self) -> bool {
+        let action = self.other.get("action");
+        action.and_then(|action| action["encoding"].as_str())
+            == Some(BASE64_ENCODING)
+    }
 
-    // create ses client
-    let ses_client = SesClient::new(Region::default());
+    /// Returns the verdict status by lowercase check name, as matched by
+    /// filtering rules.
+    pub fn verdicts(&self) -> BTreeMap<&'static str, String> {
+        [
+            ("spam", &self.spam_verdict),
+            ("virus", &self.virus_verdict),
+            ("spf", &self.spf_verdict),
+            ("dkim", &self.dkim_verdict),
+            ("dmarc", &self.dmarc_verdict),
+        ]
+        .into_iter()
+        .filter(|(_, verdict)| !verdict.status.is_empty())
+        .map(|(check, verdict)| (check, verdict.status.clone()))
+        .collect()
+    }
+}
 
-    // Initialize the PrivatEmailConfig object
-    let email_config = PrivatEmailConfig::new_from_env();
+impl EmailReceiptNotification {
+    /// Returns a builder of notifications, for tests.
+    pub fn builder() -> test_support::EmailReceiptNotificationBuilder {
+        test_support::EmailReceiptNotificationBuilder::new()
+    }
 
-    // fetch sns payload
-    let sns_payload = event["Records"][0]["Sns"]
-        .as_object()
-        .unwrap_or_else(|| panic!("Missing sns payload"));
-    tracing::info!("Raw Email Info: {:?}", sns_payload);
+    /// Parses the SES notification of an SNS `event`, delivered in an
+    /// envelope or raw, or `event` itself when it has no records.
+    pub fn from_event(event: &Value) -> Result<Self, Error> {
+        let notification: Self = match event.get("Records") {
+            Some(_) => sns::notification(event)?,
+            None => Self::deserialize(event)?,
+        };
+        notification.decoded()
+    }
 
-    // Fetch request payload
-    let sns_payload = event["Records"][0]["Sns"]
-        .as_object()
-        .unwrap_or_else(|| panic!("Missing sns payload"));
-    tracing::info!("Raw Email Info: {:?}", sns_payload);
+    /// Returns the notification with its content decoded, if delivered
+    /// base64 encoded.
+    fn decoded(mut self) -> Result<Self, Error> {
+        if self.receipt.base64_content() {
+            self.content = STANDARD.decode(&self.content)?.into();
+        }
+        Ok(self)
+    }
 
-    // Fetch ses request payload from sns message
-    let ses_mail: EmailReceiptNotification = serde_json::from_str(
-        sns_payload["Message"]
-            .as_str()
-            .unwrap_or_else(|| panic!("Missing Message field")),
-    )?;
+    /// Raw content of the received message.
+    pub fn content(&self) -> &[u8] {
+        &self.content
+    }
 
-    // skip spam messages
-    let ses_receipt = &ses_mail.receipt;
-    if ses_receipt.spam_verdict.status == "FAIL"
-        || ses_receipt.virus_verdict.status == "FAIL"
-    {
-        let err_msg = "Message contains spam or virus, skipping!";
-        error!(err_msg);
-        return Ok(LambdaResponse::new(200, err_msg));
+    /// Returns the replay of an archived message this notification is, if
+    /// any.
+    pub fn replay(&self) -> Option<&replay::Replay> {
+        self.replay.as_ref()
     }
 
-    // Rewrite Email From header to contain sender's name with forwarder's email address
-    let original_sender: String =
-        ses_mail.mail.common_headers.return_path.to_string();
-    let subject: String = ses_mail.mail.common_headers.subject.to_string();
+    /// Returns the addresses of the `From` header and the envelope sender.
+    pub fn senders(&self) -> Vec<String> {
+        self.mail
+            .common_headers
+            .other
+            .get("from")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .map(str::to_owned)
+            .chain([self.mail.source.clone()])
+            .collect()
+    }
+
+    /// Returns what filtering rules are evaluated against, given the
+    /// parsed `mail` of the content and the UTC offset of rule schedules.
+    pub fn rule_message<'a>(
+        &self,
+        mail: &'a ParsedMail<'a>,
+        utc_offset: i32,
+    ) -> rules::Message<'a> {
+        rules::Message {
+            senders: self.senders(),
+            recipients: self.mail.destination.clone(),
+            subject: self.mail.common_headers.subject.clone(),
+            headers: &mail.headers,
+            body: rules::body_text(mail),
+            size: self.content.len(),
+            verdicts: self.receipt.verdicts(),
+            received: quiet::now(),
+            utc_offset,
+        }
+    }
+
+    /// Builds the metadata archived with this message.
+    pub fn to_archive_metadata(&self) -> archive::Metadata {
+        archive::Metadata {
+            message_id: self.mail.message_id.clone(),
+            timestamp: self.mail.timestamp.clone(),
+            source: self.mail.source.clone(),
+            destination: self.mail.destination.clone(),
+            subject: self.mail.common_headers.subject.clone(),
+            verdicts: self
+                .receipt
+                .verdicts()
+                .into_iter()
+                .map(|(check, status)| (check.to_owned(), status))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Builds the index entry of this message, handled with `action` and
+    /// stored under `object_key`, if any.
+    pub fn to_index_entry(
+        &self,
+        action: MailAction,
+        email_config: &PrivatEmailConfig,
+        object_key: Option<String>,
+    ) -> index::IndexEntry {
+        let metadata = self.to_archive_metadata();
+        index::IndexEntry {
+            archive_key: email_config.archive_bucket.as_ref().map(|_| {
+                format!(
+                    "{}.eml",
+                    archive::key(&metadata.message_id, &metadata.timestamp)
+                )
+            }),
+            message_id: metadata.message_id,
+            timestamp: metadata.timestamp,
+            sender: metadata.source,
+            recipients: metadata.destination,
+            subject: metadata.subject,
+            verdicts: metadata.verdicts,
+            action: Some(action),
+            object_key,
+            indexed_at: quiet::now(),
+        }
+    }
+
+    /// Builds the metadata record describing this message and `action`.
+    pub fn to_record(&self, action: MailAction) -> MailRecord {
+        MailRecord {
+            message_id: self.mail.message_id.clone(),
+            timestamp: self.mail.timestamp.clone(),
+            source: self.mail.source.clone(),
+            destination: self.mail.destination.clone(),
+            subject: self.mail.common_headers.subject.clone(),
+            action,
+            object_key: None,
+            thread_id: mailparse::parse_headers(&self.content)
+                .ok()
+                .and_then(|(headers, _)| thread::thread_id(&headers)),
+        }
+    }
+}
+
+/// Persists the raw message and its outcome in the configured store, if
+/// any, indexes it and posts the outcome to the configured webhook.
+/// Returns the key of the stored message, if it was kept.
+async fn track_outcome(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    ses_mail: &EmailReceiptNotification,
+    action: MailAction,
+) -> Result<Option<String>, Error> {
+    if let Some(webhook) = notify::WebhookNotifier::from_config(email_config) {
+        let notification = notify::Notification {
+            message_id: ses_mail.mail.message_id.clone(),
+            sender: ses_mail.mail.source.clone(),
+            recipients: ses_mail.mail.destination.clone(),
+            subject: ses_mail.mail.common_headers.subject.clone(),
+            action,
+            preview_url: None,
+            text: None,
+            thread_count: 1,
+        };
+        if let Err(e) = webhook.notify(&notification).await {
+            error!("Error posting to the webhook: {:?}", e);
+        }
+    }
+    put_event(email_config, ses_mail, action.as_str()).await;
+    let mut object_key = None;
+    if let Some(mail_store) = mail_store {
+        let record = mail_store
+            .track(ses_mail.to_record(action), ses_mail.content.clone())
+            .await?;
+        trace!("Tracked message: {:?}", record);
+        object_key = record.object_key;
+    }
+    if let Some(table) = &email_config.index_table {
+        let entry =
+            ses_mail.to_index_entry(action, email_config, object_key.clone());
+        if let Err(e) = index::put(table, &entry).await {
+            error!("Error indexing message in {}: {:?}", table, e);
+        }
+    }
+    Ok(object_key)
+}
 
-    // parse email content
-    let mail = parse_mail(ses_mail.content.as_bytes()).unwrap();
-    let content = mail.subparts[1].get_body_raw().unwrap();
-    let msg_body = charset::decode_latin1(&content).to_string();
-    trace!("HTML content: {:#?}", content);
+/// Puts the lifecycle event `name` of `ses_mail` on the configured bus,
+/// logging failures.
+async fn put_event(
+    email_config: &PrivatEmailConfig,
+    ses_mail: &EmailReceiptNotification,
+    name: &str,
+) {
+    let Some(bus) = &email_config.event_bus else {
+        return;
+    };
+    let event = events::MailEvent {
+        version: events::DETAIL_VERSION,
+        message_id: ses_mail.mail.message_id.clone(),
+        sender: ses_mail.mail.source.clone(),
+        recipients: ses_mail.mail.destination.clone(),
+        subject: ses_mail.mail.common_headers.subject.clone(),
+        received_at: ses_mail.mail.timestamp.clone(),
+    };
+    if let Err(e) = events::put(bus, name, &event).await {
+        error!("Error putting {} event: {:?}", name, e);
+    }
+}
 
-    // Skip mail if it's from blacklisted email
-    for email in
-        email_config.black_list.unwrap_or_else(|| panic!("Missing black list"))
+/// Like [`track_outcome`], but only logs failures so that a storage
+/// problem never turns an already handled message into a Lambda error.
+async fn track_outcome_logged(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    ses_mail: &EmailReceiptNotification,
+    action: MailAction,
+) {
+    if let Err(e) =
+        track_outcome(email_config, mail_store, ses_mail, action).await
     {
-        if !email.is_empty() && original_sender.contains(email.as_str()) {
-            let mut err_msg: String =
-                "Message is from blacklisted email: ".to_owned();
-            err_msg.push_str(email.as_str());
-            trace!("`{}`, skipping!", err_msg.as_str());
-            return Ok(LambdaResponse::new(200, err_msg.as_str()));
+        error!("Error tracking message outcome: {:?}", e);
+    }
+}
+
+/// Quarantines `ses_mail` for the malformed content `error`, counting it.
+async fn quarantine_malformed(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    ses_mail: &EmailReceiptNotification,
+    error: &content::ContentError,
+) -> Result<LambdaResponse, Error> {
+    let err_msg = format!("Malformed message quarantined: {}", error);
+    error!(
+        message_id = ses_mail.mail.message_id.as_str(),
+        metric = content::MALFORMED_COUNTER,
+        "{}, skipping!",
+        err_msg
+    );
+    if let Some(mail_store) = mail_store {
+        if let Err(e) =
+            mail_store.records.increment(content::MALFORMED_COUNTER, 1).await
+        {
+            error!("Error counting malformed message: {:?}", e);
         }
     }
+    track_outcome(email_config, mail_store, ses_mail, MailAction::Quarantined)
+        .await?;
+    Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+        .with_action(MailAction::Quarantined))
+}
 
-    let ses_email_message = SendEmailRequest {
-        configuration_set_name: Default::default(),
-        destination: Destination {
-            bcc_addresses: Default::default(),
-            cc_addresses: Default::default(),
-            to_addresses: Some(vec![email_config.to_email.to_string()]),
-        },
-        message: Message {
-            body: Body {
-                html: Some(Content {
-                    charset: Default::default(),
-                    data: msg_body,
-                }),
-                text: Default::default(),
-            },
-            subject: Content { charset: Default::default(), data: subject },
-        },
-        reply_to_addresses: Some(vec![original_sender]),
-        return_path: Default::default(),
-        return_path_arn: Default::default(),
-        source: email_config.from_email.to_string(),
-        source_arn: Default::default(),
-        tags: Default::default(),
+/// Quarantines `ses_mail`, whose content exceeds `max_content_size`, without
+/// parsing it, and notifies the destination with a link to the original
+/// when the store keeps it.
+async fn quarantine_oversized(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    ses_mail: &EmailReceiptNotification,
+) -> Result<LambdaResponse, Error> {
+    let size = ses_mail.content.len();
+    let err_msg = format!(
+        "Message of {} bytes exceeds the maximum content size of {}, \
+         quarantined",
+        size, email_config.max_content_size
+    );
+    warn!(message_id = ses_mail.mail.message_id.as_str(), "{}", err_msg);
+    let key = track_outcome(
+        email_config,
+        mail_store,
+        ses_mail,
+        MailAction::Quarantined,
+    )
+    .await?;
+    let link = match (mail_store, key) {
+        (Some(mail_store), Some(key)) => mail_store
+            .objects
+            .link(&key, limits::LINK_EXPIRY)
+            .await
+            .map_err(|e| error!("Error linking oversized message: {:?}", e))
+            .ok(),
+        _ => None,
+    };
+
+    let headers = &ses_mail.mail.common_headers;
+    let raw = mime::MimeMessage::new()
+        .header("From", &email_config.from_email)
+        .header("To", &email_config.to_email)
+        .header(
+            "Subject",
+            format!("{}{}", limits::OVERSIZE_SUBJECT_PREFIX, headers.subject),
+        )
+        .text(limits::oversize_notice(
+            &headers.subject,
+            &ses_mail.mail.source,
+            size,
+            link.as_deref(),
+        ))
+        .to_bytes();
+    if let Err(e) = forward::send_raw_email(
+        email_sender,
+        dkim,
+        &email_config.from_email,
+        vec![email_config.to_email.clone()],
+        raw.into(),
+        email_config.configuration_set.as_deref(),
+        &[],
+    )
+    .await
+    {
+        error!("Error sending the oversized message notice: {:?}", e);
+    }
+    Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+        .with_action(MailAction::Quarantined))
+}
+
+/// Stores an oversized original and returns a link to it, logging failures.
+async fn store_oversized(
+    mail_store: &MailStore,
+    ses_mail: &EmailReceiptNotification,
+) -> Option<String> {
+    let key =
+        format!("{}{}.eml", store::OVERSIZED_PREFIX, ses_mail.mail.message_id);
+    let stored = mail_store.objects.put(&key, ses_mail.content.clone()).await;
+    match stored {
+        Ok(()) => mail_store.objects.link(&key, limits::LINK_EXPIRY).await,
+        Err(e) => Err(e),
+    }
+    .map_err(|e| error!("Error storing oversized message: {:?}", e))
+    .ok()
+}
+
+/// Renders `html` with `renderer` and returns a link to the stored PNG
+/// preview, logging failures.
+async fn render_preview(
+    renderer: Option<&dyn Renderer>,
+    mail_store: Option<&MailStore>,
+    message_id: &str,
+    html: &str,
+) -> Option<String> {
+    let (renderer, mail_store) = (renderer?, mail_store?);
+    let key = format!("{}{}.png", preview::PREVIEW_PREFIX, message_id);
+    let link = async {
+        let png = renderer.render(html).await?;
+        mail_store.objects.put(&key, png.into()).await?;
+        mail_store.objects.link(&key, limits::LINK_EXPIRY).await
     };
+    link.await.map_err(|e| error!("Error rendering preview: {:?}", e)).ok()
+}
 
-    match ses_client.send_email(ses_email_message).await {
-        Ok(email_response) => {
-            trace!("Email forward success: {:?}", email_response);
-            Ok(LambdaResponse::new(200, &email_response.message_id))
+/// Sends `notification` through every configured notifier, logging failures.
+async fn send_notifications(
+    email_config: &PrivatEmailConfig,
+    notification: &notify::Notification,
+) {
+    for notifier in notify::from_config(email_config, &notification.recipients)
+    {
+        if let Err(e) = notifier.notify(notification).await {
+            error!("Error sending notification: {:?}", e);
         }
-        Err(error) => {
-            tracing::error!("Error forwarding email: {:?}", error);
-            Err(Box::new(error))
+    }
+}
+
+/// Moves the deduplication claim `lease` of a message to sending, right
+/// before SES is called.
+async fn begin_send(lease: Option<&idempotency::Lease>) -> Result<(), Error> {
+    match lease {
+        Some(lease) => idempotency::begin_send(lease, quiet::now()).await,
+        None => Ok(()),
+    }
+}
+
+/// Returns whether `address` is on the account suppression list, when
+/// `check_suppression` is set. Failed lookups are logged and never hold
+/// back a send.
+async fn suppressed(email_config: &PrivatEmailConfig, address: &str) -> bool {
+    if !email_config.check_suppression {
+        return false;
+    }
+    let region = email_config.sending_region();
+    match suppression::is_suppressed(&region, address).await {
+        Ok(true) => {
+            warn!(
+                metric = suppression::SUPPRESSED_COUNTER,
+                "{} is on the account suppression list", address
+            );
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            error!("Error checking suppression of {}: {:?}", address, e);
+            false
         }
     }
 }
 
-/// Test module for privatemail package
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use lambda_runtime::Context;
-    use std::fs;
-    use std::path::PathBuf;
+/// Returns the sender of `email_config`: SES in `ses_region`, failing over
+/// to `ses_failover_region` when set.
+fn config_sender(
+    email_config: &PrivatEmailConfig,
+) -> Result<Box<dyn EmailSender>, Error> {
+    let ses_client = region_sender(
+        email_config,
+        email_config.sending_region(),
+        email_config.ses_region.clone(),
+    );
+    Ok(match &email_config.ses_failover_region {
+        Some(region) => {
+            let secondary = region_sender(
+                email_config,
+                region.parse()?,
+                Some(region.clone()),
+            );
+            let sender =
+                sender::FailoverSender::new(ses_client, secondary, region);
+            Box::new(match &email_config.ses_failover_identity_arn {
+                Some(arn) => sender.with_identity_arn(arn),
+                None => sender,
+            })
+        }
+        None => ses_client,
+    })
+}
 
-    fn read_test_event(file_name: String) -> Value {
-        // Open the file in read-only mode with buffer.
+/// Whether [`config_sender`] builds the same sender from `a` and `b`.
+fn same_sender(a: &PrivatEmailConfig, b: &PrivatEmailConfig) -> bool {
+    a.ses_region == b.ses_region
+        && a.ses_failover_region == b.ses_failover_region
+        && a.ses_failover_identity_arn == b.ses_failover_identity_arn
+        && a.ses_v2 == b.ses_v2
+        && a.ses_primary == b.ses_primary
+        && a.ses_dual_write == b.ses_dual_write
+}
 
-        let mut srcdir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
-        let mut file_dir: String = "tests/payload/".to_owned();
-        file_dir.push_str(file_name.as_str());
-        srcdir.push(file_dir.as_str());
-        println!("Cur Dir: {}", srcdir.display());
+/// Returns the sender of `email_config` through SES in `region`, named
+/// `name` when set: the SES client, behind the backend selection of the
+/// migration and, with `ses_v2`, sending raw messages through SESv2.
+fn region_sender(
+    email_config: &PrivatEmailConfig,
+    region: Region,
+    name: Option<String>,
+) -> Box<dyn EmailSender> {
+    let client = SesClient::new(aws::service_region("ses", region.clone()));
+    let sender = migration::sender(
+        sender::SesSender::new(client, region.clone()),
+        email_config,
+        name,
+    );
+    #[cfg(feature = "sesv2")]
+    if email_config.ses_v2 {
+        return Box::new(sesv2::Sesv2Sender::new(sender, region));
+    }
+    sender
+}
 
-        // Read the JSON contents of the file as an instance of `String`.
-        let input_str = fs::read_to_string(srcdir.as_path()).unwrap();
-        trace!("Input str: {}", input_str);
+/// Sends the raw forward `raw` tagged with `tags` through the SES
+/// `configuration_set` as configured in `email_config`.
+async fn send_raw_forward(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    raw: Bytes,
+    configuration_set: Option<&str>,
+    tags: &[MessageTag],
+) -> Result<String, Error> {
+    let destinations = vec![email_config.to_email.to_string()];
+    forward::send_raw_email(
+        email_sender,
+        dkim,
+        &email_config.from_email,
+        destinations,
+        raw,
+        configuration_set,
+        tags,
+    )
+    .await
+}
 
-        // Return the `Value`.
-        return serde_json::from_str(input_str.as_str()).unwrap();
+/// Counts the failed send `error` towards opening the circuit breaker,
+/// unless it was caused by the message itself.
+fn record_send_failure(
+    error: &(dyn std::error::Error + 'static),
+    threshold: u32,
+) {
+    if failure::classify(error) == FailureClass::Size {
+        return;
     }
+    let tripped = breaker::shared()
+        .lock()
+        .unwrap()
+        .record_failure(quiet::now(), threshold);
+    if tripped {
+        error!(
+            metric = breaker::TRIPPED_METRIC,
+            "Circuit breaker opened after {} failed sends", threshold
+        );
+    }
+}
 
-    #[tokio::test]
-    #[ignore = "skipping integration because of IAM requirements"]
-    async fn handler_with_success() {
-        env::set_var("TO_EMAIL", "hello@nyah.dev");
-        env::set_var("FROM_EMAIL", "test@nyah.dev");
-        let test_event = read_test_event(String::from("test_event.json"));
+/// Counts a failed send towards the failure streak, triggering the
+/// configured alerters when it reaches `threshold`. Failures of oversized
+/// messages are not counted, as in [`record_send_failure`].
+async fn extend_failure_streak(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    ses_mail: &EmailReceiptNotification,
+    error: &(dyn std::error::Error + 'static),
+    threshold: u32,
+) {
+    if failure::classify(error) == FailureClass::Size {
+        return;
+    }
+    let records = match mail_store {
+        Some(mail_store) => &*mail_store.records,
+        None => alert::local_records() as &dyn store::RecordStore,
+    };
+    let failures = match alert::record_failure(records).await {
+        Ok(failures) => failures,
+        Err(e) => {
+            error!("Error counting failure streak: {:?}", e);
+            return;
+        }
+    };
+    if failures != u64::from(threshold) {
+        return;
+    }
+    let alert = alert::Alert {
+        failures,
+        message_id: ses_mail.mail.message_id.clone(),
+        error: error.to_string(),
+    };
+    error!("{}, alerting", alert.summary());
+    for alerter in alert::from_config(email_config) {
+        if let Err(e) = alerter.trigger(&alert).await {
+            error!("Error triggering alert: {:?}", e);
+        }
+    }
+}
 
-        assert_eq!(
-            privatemail_handler(LambdaEvent {
-                payload: test_event,
-                context: Context::default()
-            })
-            .await
-            .expect("expected Ok(_) response")
-            .status_code,
-            200
-        )
+/// Ends the failure streak after a successful send, resolving the alert
+/// when the streak had reached `threshold`.
+async fn end_failure_streak(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    threshold: u32,
+) {
+    let records = match mail_store {
+        Some(mail_store) => &*mail_store.records,
+        None => alert::local_records() as &dyn store::RecordStore,
+    };
+    match alert::record_success(records).await {
+        Ok(failures) if failures >= u64::from(threshold) => {
+            info!("Sends succeed again after {} failures", failures);
+            for alerter in alert::from_config(email_config) {
+                if let Err(e) = alerter.resolve().await {
+                    error!("Error resolving alert: {:?}", e);
+                }
+            }
+        }
+        Ok(_) => {}
+        Err(e) => error!("Error ending failure streak: {:?}", e),
     }
+}
 
-    #[tokio::test]
-    #[ignore = "skipping integration because of IAM requirements"]
-    async fn handler_with_black_listed_email() {
-        env::set_var("TO_EMAIL", "test@nyah.dev");
-        env::set_var("FROM_EMAIL", "fufu@achu.soup");
-        env::set_var("BLACK_LIST", "achu.soup");
-        let test_event = read_test_event(String::from("test_event.json"));
+/// Marks the deduplication claim `lease` of a message forwarded as
+/// `ses_message_id`, or gives it up when the forward failed, logging
+/// failures.
+async fn settle_claim(
+    lease: Option<&idempotency::Lease>,
+    forwarded: bool,
+    ses_message_id: Option<&str>,
+) {
+    let Some(lease) = lease else {
+        return;
+    };
+    let settled = if forwarded {
+        idempotency::complete(lease, ses_message_id).await
+    } else {
+        idempotency::release(lease).await
+    };
+    if let Err(e) = settled {
+        error!("Error settling claim of {}: {:?}", lease.key, e);
+    }
+}
 
-        assert_eq!(
-            privatemail_handler(LambdaEvent {
-                payload: test_event,
-                context: Context::default()
-            })
-            .await
-            .expect("expected Ok(_) response")
-            .status_code,
-            200
+/// Sends the acknowledgment of the route of `ses_mail`, or else the
+/// configured auto-reply, to its sender. Automated mail gets neither, and
+/// senders replied to recently no auto-reply. Failures are logged.
+async fn send_auto_reply(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    ses_mail: &EmailReceiptNotification,
+    headers: &[MailHeader<'_>],
+) {
+    let acknowledgment =
+        email_config.acknowledgment_for(&ses_mail.mail.destination);
+    let auto_reply = email_config
+        .auto_reply_text
+        .as_ref()
+        .zip(email_config.auto_reply_table.as_ref());
+    if acknowledgment.is_none() && auto_reply.is_none() {
+        return;
+    }
+    let sender = &ses_mail.mail.source;
+    if let Some(reason) = autoreply::suppressed(headers, sender) {
+        trace!("No auto-reply: {}", reason);
+        return;
+    }
+    if suppressed(email_config, sender).await {
+        return;
+    }
+    let context = banner::Context::new(
+        sender,
+        &ses_mail.mail.destination,
+        &ses_mail.mail.timestamp,
+    );
+    let subject = &ses_mail.mail.common_headers.subject;
+    let reply = async {
+        let message = match (acknowledgment, auto_reply) {
+            (Some(acknowledgment), _) => acknowledgment.build(
+                &email_config.from_email,
+                sender,
+                subject,
+                &ses_mail.mail.message_id,
+                &context,
+                headers,
+            ),
+            (None, Some((template, table))) => {
+                if !autoreply::claim(
+                    table,
+                    sender,
+                    quiet::now(),
+                    email_config.auto_reply_days,
+                )
+                .await?
+                {
+                    trace!("Auto-reply already sent to {}", sender);
+                    return Ok(());
+                }
+                autoreply::build(
+                    &email_config.from_email,
+                    sender,
+                    email_config.auto_reply_subject.as_deref(),
+                    subject,
+                    template,
+                    &context,
+                    headers,
+                )
+            }
+            (None, None) => return Ok(()),
+        };
+        let message_id = forward::send_raw_email(
+            email_sender,
+            dkim,
+            &email_config.from_email,
+            vec![sender.clone()],
+            message.to_bytes().into(),
+            email_config.configuration_set.as_deref(),
+            &[],
         )
+        .await?;
+        trace!("Auto-reply sent: {}", message_id);
+        Ok::<_, Error>(())
+    };
+    if let Err(e) = reply.await {
+        error!("Error sending auto-reply: {:?}", e);
+    }
+}
+
+/// Signing, encryption and verification keys of a configuration. Their
+/// settings are only read from the base configuration.
+#[derive(Debug, Default)]
+pub struct Keys {
+    /// DKIM key raw sends are signed with
+    pub dkim: Option<DkimSigner>,
+
+    /// PGP key forwards are encrypted to
+    pub pgp: Option<pgp::PgpRecipient>,
+
+    /// PGP keys inbound signatures are checked against
+    pub keyring: Option<pgp::verify::Keyring>,
+
+    /// S/MIME certificate forwards are signed with
+    pub smime: Option<smime::SmimeSigner>,
+
+    /// CAs inbound S/MIME signatures are checked against
+    pub trust_store: Option<smime::TrustStore>,
+}
+
+impl Keys {
+    /// Loads the keys configured in `email_config`.
+    pub async fn load(email_config: &PrivatEmailConfig) -> Result<Self, Error> {
+        Ok(Keys {
+            dkim: DkimSigner::from_config(email_config).await?,
+            pgp: pgp::PgpRecipient::from_config(email_config)?,
+            keyring: pgp::verify::Keyring::from_config(email_config)?,
+            smime: smime::SmimeSigner::from_config(email_config).await?,
+            trust_store: smime::TrustStore::from_config(email_config)?,
+        })
+    }
+}
+
+/// Classifier and preview renderer of a configuration, built with it
+/// rather than for every message.
+#[derive(Default)]
+pub struct Clients {
+    /// Bedrock client labeling mail with `classifier_model`
+    pub classifier: Option<Box<dyn classify::Classifier>>,
+
+    /// Lambda client rendering HTML previews with `preview_renderer`
+    pub renderer: Option<Box<dyn Renderer>>,
+}
+
+impl Clients {
+    /// Creates the clients configured in `email_config`.
+    pub fn from_config(email_config: &PrivatEmailConfig) -> Self {
+        Clients {
+            classifier: email_config.classifier_model.as_ref().map(|model| {
+                Box::new(classify::BedrockClassifier::new(
+                    model,
+                    Region::default(),
+                )) as Box<dyn classify::Classifier>
+            }),
+            renderer: email_config.preview_renderer.as_ref().map(|function| {
+                Box::new(preview::LambdaRenderer::new(
+                    function,
+                    Region::default(),
+                )) as Box<dyn Renderer>
+            }),
+        }
+    }
+}
+
+/// Clients and configuration shared by the invocations of a Lambda
+/// container, created once in `main()`.
+///
+/// The configuration is parsed once. When it is read from `CONFIG_S3_URI`
+/// it is loaded again, with its secrets, by the first invocation after
+/// `config_refresh_secs`; the SES sender is built again when its settings,
+/// such as `ses_region`, changed, the [`Clients`] with every load, while
+/// the store is kept as created.
+pub struct PrivatEmailService {
+    /// Sender of forwards and other outgoing mail, the SES client unless
+    /// replaced with [`PrivatEmailService::with_sender`]
+    email_sender: Mutex<Arc<dyn EmailSender>>,
+
+    /// Whether `email_sender` was replaced, and is kept across refreshes
+    custom_sender: bool,
+
+    /// Store configured in the configuration, if any
+    pub mail_store: Option<MailStore>,
+
+    /// Configuration, with its secrets resolved, and when it was loaded
+    config: Mutex<(Arc<PrivatEmailConfig>, Instant)>,
+
+    /// Classifier and renderer of the configuration
+    clients: Mutex<Arc<Clients>>,
+
+    /// Keys of the configuration, loaded by the first message needing them
+    keys: Mutex<Arc<OnceCell<Arc<Keys>>>>,
+
+    /// Whether the configuration comes from a remote source
+    remote: bool,
+
+    /// Sending quota last read from `email_sender`
+    quota: quota::Cache,
+
+    /// Resolver of DNS blocklist lookups, the system's unless replaced with
+    /// [`PrivatEmailService::with_resolver`]
+    resolver: Box<dyn dnsbl::Resolver>,
+}
+
+impl PrivatEmailService {
+    /// Loads the configuration, resolving its `secretsmanager://`
+    /// references, and creates the clients it needs.
+    pub async fn new() -> Result<Self, Error> {
+        let mut service = Self::from_config(Self::load_config().await?)?;
+        service.remote = env::var(config::remote::CONFIG_S3_URI).is_ok();
+        Ok(service)
+    }
+
+    /// Creates the clients needed by `email_config`.
+    pub fn from_config(email_config: PrivatEmailConfig) -> Result<Self, Error> {
+        let email_sender = config_sender(&email_config)?;
+        Ok(PrivatEmailService {
+            email_sender: Mutex::new(email_sender.into()),
+            custom_sender: false,
+            mail_store: MailStore::from_config(&email_config)?,
+            clients: Mutex::new(Arc::new(Clients::from_config(&email_config))),
+            config: Mutex::new((Arc::new(email_config), Instant::now())),
+            keys: Default::default(),
+            remote: false,
+            quota: Default::default(),
+            resolver: Box::new(dnsbl::SystemResolver),
+        })
+    }
+
+    /// Returns the service sending its mail through `email_sender`, e.g. a
+    /// [`sender::MockSender`] in tests.
+    pub fn with_sender(
+        mut self,
+        email_sender: impl EmailSender + 'static,
+    ) -> Self {
+        self.email_sender = Mutex::new(Arc::new(email_sender));
+        self.custom_sender = true;
+        self
+    }
+
+    /// Returns the sender of outgoing mail.
+    pub fn email_sender(&self) -> Arc<dyn EmailSender> {
+        self.email_sender.lock().unwrap().clone()
+    }
+
+    /// Returns the service looking up DNS blocklists through `resolver`,
+    /// e.g. a [`dnsbl::StaticResolver`] in tests.
+    pub fn with_resolver(
+        mut self,
+        resolver: impl dnsbl::Resolver + 'static,
+    ) -> Self {
+        self.resolver = Box::new(resolver);
+        self
+    }
+
+    /// Returns the current configuration.
+    pub fn config(&self) -> Arc<PrivatEmailConfig> {
+        self.config.lock().unwrap().0.clone()
+    }
+
+    /// Returns the classifier and renderer of the current configuration.
+    pub fn clients(&self) -> Arc<Clients> {
+        self.clients.lock().unwrap().clone()
+    }
+
+    /// Loads a remote configuration again once it is older than
+    /// `config_refresh_secs`, keeping the loaded one when that fails. The
+    /// SES sender is built again when the SES settings changed.
+    pub async fn refresh(&self) {
+        let refresh_secs = {
+            let (config, loaded_at) = &*self.config.lock().unwrap();
+            let refresh_secs = config.config_refresh_secs;
+            let due = loaded_at.elapsed().as_secs() >= refresh_secs;
+            if !self.remote || refresh_secs == 0 || !due {
+                return;
+            }
+            refresh_secs
+        };
+        let loaded = Self::load_config().await.and_then(|fresh| {
+            let current = self.config();
+            let rebuild = !self.custom_sender && !same_sender(&fresh, &current);
+            let sender = rebuild.then(|| config_sender(&fresh)).transpose()?;
+            Ok((fresh, sender))
+        });
+        let mut config = self.config.lock().unwrap();
+        match loaded {
+            Ok((fresh, sender)) => {
+                if let Some(sender) = sender {
+                    info!("SES settings changed, sending through new clients");
+                    *self.email_sender.lock().unwrap() = sender.into();
+                }
+                *self.clients.lock().unwrap() =
+                    Arc::new(Clients::from_config(&fresh));
+                *config = (Arc::new(fresh), Instant::now());
+                *self.keys.lock().unwrap() = Default::default();
+            }
+            Err(e) => {
+                warn!(
+                    "Error refreshing configuration, retrying in {}s: {:?}",
+                    refresh_secs, e
+                );
+                config.1 = Instant::now();
+            }
+        }
+    }
+
+    /// Returns the keys of the current configuration, loading them on
+    /// first use.
+    pub async fn keys(&self) -> Result<Arc<Keys>, Error> {
+        let keys = self.keys.lock().unwrap().clone();
+        let email_config = self.config();
+        keys.get_or_try_init(|| async {
+            Keys::load(&email_config).await.map(Arc::new)
+        })
+        .await
+        .cloned()
+    }
+
+    /// Loads the configuration, resolves its secrets and validates it.
+    async fn load_config() -> Result<PrivatEmailConfig, Error> {
+        PrivatEmailConfig::load()
+            .await?
+            .resolve(&config::secrets::SecretsManager)
+            .await
+    }
+
+    /// Handles an invocation, see [`privatemail_handler`].
+    pub async fn handle(
+        &self,
+        lambda_event: LambdaEvent<Value>,
+    ) -> Result<LambdaResponse, Error> {
+        self.refresh().await;
+        let (event, context) = lambda_event.into_parts();
+        let records = match event["Records"].as_array() {
+            Some(records) if records.len() > 1 => records.clone(),
+            _ => {
+                return handle_record(
+                    self,
+                    LambdaEvent::new(event, context),
+                    None,
+                )
+                .await
+            }
+        };
+        handle_records(self, records, context).await
+    }
+
+    /// Handles the notification `lambda_event` of an archived message as
+    /// its `replay`, which events themselves can't request.
+    pub async fn handle_replay(
+        &self,
+        lambda_event: LambdaEvent<Value>,
+        replay: &replay::Replay,
+    ) -> Result<LambdaResponse, Error> {
+        self.refresh().await;
+        handle_record(self, lambda_event, Some(replay)).await
+    }
+
+    /// Handles an HTTP request from a Function URL or API Gateway: send
+    /// requests posted to [`outbound::SEND_PATH`], mail posted by inbound
+    /// webhooks to [`inbound::INBOUND_PATH`] and searches of the mail index
+    /// at [`search::SEARCH_PATH`].
+    pub async fn handle_http(
+        &self,
+        lambda_event: LambdaEvent<Value>,
+    ) -> Result<LambdaResponse, Error> {
+        self.refresh().await;
+        let (event, context) = lambda_event.into_parts();
+        let email_config = self.config();
+        let token = match http::path(&event) {
+            outbound::SEND_PATH => &email_config.send_api_token,
+            inbound::INBOUND_PATH => &email_config.inbound_webhook_token,
+            search::SEARCH_PATH => &email_config.search_api_token,
+            _ => return Ok(LambdaResponse::new(404, "Not found")),
+        };
+        if !http::authorized(&event, token.as_deref().unwrap_or_default()) {
+            return Ok(LambdaResponse::new(401, "Unauthorized"));
+        }
+        if http::path(&event) == search::SEARCH_PATH {
+            return match search::from_http(&event) {
+                Ok(request) => search_index(&email_config, &request).await,
+                Err(e) => Ok(LambdaResponse::new(STATUS_MALFORMED, &e)),
+            };
+        }
+        if http::path(&event) == outbound::SEND_PATH {
+            let request = match outbound::from_http(&event) {
+                Ok(request) => request,
+                Err(e) => return Ok(LambdaResponse::new(STATUS_MALFORMED, &e)),
+            };
+            let keys = self.keys().await?;
+            return send_as_alias(
+                self.email_sender().as_ref(),
+                keys.dkim.as_ref(),
+                &email_config,
+                &request,
+            )
+            .await;
+        }
+        let records = inbound::from_http(&event)
+            .and_then(|mail| mail.to_event(quiet::now()));
+        match records {
+            Ok(records) => {
+                let event = LambdaEvent::new(records, context);
+                handle_record(self, event, None).await
+            }
+            Err(e) => {
+                warn!("Refusing inbound webhook mail: {}", e);
+                Ok(LambdaResponse::new(STATUS_MALFORMED, &e))
+            }
+        }
+    }
+}
+
+/// PrivatEmail_Handler: processes incoming messages from SNS
+/// and forwards to the appropriate recipient email
+///
+/// Events carrying several records are split into one event per record,
+/// handled `record_concurrency` at a time. The response is the highest
+/// status of the records, and the invocation fails when any record
+/// failed, once every record was handled. HTTP requests are routed to
+/// [`PrivatEmailService::handle_http`]. The Lambda binary keeps a
+/// [`PrivatEmailService`] across invocations instead.
+pub async fn privatemail_handler(
+    lambda_event: LambdaEvent<Value>,
+) -> Result<LambdaResponse, Error> {
+    let service = PrivatEmailService::new().await?;
+    match router::Route::of(&lambda_event.payload) {
+        router::Route::Http => service.handle_http(lambda_event).await,
+        _ => service.handle(lambda_event).await,
+    }
+}
+
+/// Handles each of `records` as an event of its own.
+async fn handle_records(
+    service: &PrivatEmailService,
+    records: Vec<Value>,
+    context: lambda_runtime::Context,
+) -> Result<LambdaResponse, Error> {
+    let count = records.len();
+    let results: Vec<_> = stream::iter(records)
+        .map(|record| {
+            let event = serde_json::json!({ "Records": [record] });
+            let event = LambdaEvent::new(event, context.clone());
+            handle_record(service, event, None)
+        })
+        .buffer_unordered(service.config().record_concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut status_code = STATUS_OK;
+    let mut first_error = None;
+    for result in results {
+        match result {
+            Ok(response) => {
+                info!("Record handled: {}", response);
+                status_code = status_code.max(response.status_code);
+            }
+            Err(e) => {
+                error!("Error handling record: {:?}", e);
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    match first_error {
+        Some(e) => Err(e),
+        None => Ok(LambdaResponse::new(
+            status_code,
+            &format!("Handled {} records", count),
+        )),
+    }
+}
+
+/// Handles an event of a single record, dead-lettering it on failure when
+/// configured, and adds the message id and processing time to the
+/// response.
+async fn handle_record(
+    service: &PrivatEmailService,
+    lambda_event: LambdaEvent<Value>,
+    replay: Option<&replay::Replay>,
+) -> Result<LambdaResponse, Error> {
+    let started = Instant::now();
+    let (event, context) = lambda_event.into_parts();
+    let response = match handle_event(service, &event, &context, replay).await {
+        Err(error) => {
+            dead_letter_error(service, &event, &context.request_id, error).await
+        }
+        handled => handled,
+    }?;
+    let message_id = message_id(&event);
+    let response = match message_id {
+        Some(message_id) => response.with_message_id(&message_id),
+        None => response,
+    };
+    Ok(response.with_processing_time(started.elapsed()))
+}
+
+/// Returns the SES message id of the notification of `event`, if any,
+/// without deserializing its content.
+fn message_id(event: &Value) -> Option<String> {
+    #[derive(Deserialize)]
+    struct Notification {
+        mail: Value,
+    }
+    let notification: Notification = match event.get("Records") {
+        Some(_) => sns::notification(event).ok()?,
+        None => Notification::deserialize(event).ok()?,
+    };
+    notification.mail["messageId"]
+        .as_str()
+        .filter(|message_id| !message_id.is_empty())
+        .map(str::to_owned)
+}
+
+/// Writes the event which failed with `error` to the dead letters when
+/// `dead_letter_errors` is set, answering it with a 500 response instead
+/// of failing the invocation.
+async fn dead_letter_error(
+    service: &PrivatEmailService,
+    event: &Value,
+    request_id: &str,
+    error: Error,
+) -> Result<LambdaResponse, Error> {
+    let email_config = service.config();
+    if !email_config.dead_letter_errors {
+        return Err(error);
+    }
+    let Some(mail_store) = &service.mail_store else {
+        return Err(error);
+    };
+    let ses_mail = EmailReceiptNotification::from_event(event).ok();
+    let letter = deadletter::DeadLetter {
+        message_id: ses_mail
+            .as_ref()
+            .map(|m| m.mail.message_id.clone())
+            .filter(|id| !id.is_empty())
+            .unwrap_or_else(|| request_id.to_owned()),
+        failure_class: None,
+        error: error.to_string(),
+        failed_at: quiet::now(),
+        event: event.clone(),
+    };
+    let content = ses_mail.as_ref().map(|m| m.content.clone());
+    match letter
+        .write(&*mail_store.objects, &email_config.failed_prefix, content)
+        .await
+    {
+        Ok(key) => {
+            error!(
+                message_id = letter.message_id.as_str(),
+                "Error handling event, dead-lettered to {}: {:?}", key, error
+            );
+            Ok(LambdaResponse::new(500, &key))
+        }
+        Err(e) => {
+            error!("Error writing dead letter: {:?}", e);
+            Err(error)
+        }
+    }
+}
+
+/// Handles an SNS notification of a received message, a scheduled
+/// release of deferred forwards, the daily digest, a statistics report or
+/// the deletion of expired mail.
+async fn handle_event(
+    service: &PrivatEmailService,
+    event: &Value,
+    ctx: &lambda_runtime::Context,
+    replay: Option<&replay::Replay>,
+) -> Result<LambdaResponse, Error> {
+    // install global collector configured based on RUST_LOG env var
+    if let Some(xray_trace_id) = &ctx.xray_trace_id {
+        env::set_var("_X_AMZN_TRACE_ID", xray_trace_id);
+    }
+
+    // Enable Cloudwatch error logging at runtime
+    trace!("Event: {:#?}, Context: {:#?}", event, ctx);
+
+    // Use the clients, configuration and store of the container
+    let email_sender = service.email_sender();
+    let email_sender = email_sender.as_ref();
+    let email_config = PrivatEmailConfig::clone(&service.config());
+    let mail_store = &service.mail_store;
+
+    // Use the DKIM, PGP and S/MIME keys, loaded by the first message
+    let keys = service.keys().await?;
+    let Keys { dkim, .. } = &*keys;
+
+    match router::Route::of(event) {
+        // The daily schedule sends the digest
+        router::Route::Digest => {
+            return send_digest(
+                email_sender,
+                dkim.as_ref(),
+                &email_config,
+                mail_store.as_ref(),
+            )
+            .await
+        }
+
+        // The cron schedule sends the statistics report
+        router::Route::Stats => {
+            return send_stats(
+                email_sender,
+                dkim.as_ref(),
+                &email_config,
+                mail_store.as_ref(),
+            )
+            .await
+        }
+
+        // The retention schedule deletes expired mail
+        router::Route::Retention => {
+            return purge_expired(&email_config, mail_store.as_ref()).await
+        }
+
+        // Scheduled invocations release forwards deferred by quiet hours,
+        // rules or the sending quota, once the quota has room
+        router::Route::Scheduled => {
+            if quota_exhausted(service, &email_config).await {
+                return Ok(LambdaResponse::new(
+                    STATUS_OK,
+                    "Sending quota exhausted, holding deferred messages",
+                ));
+            }
+            return release_deferred(
+                email_sender,
+                dkim.as_ref(),
+                &email_config,
+                mail_store.as_ref(),
+            )
+            .await;
+        }
+
+        // Erasure of the mail of a sender, invoked directly
+        router::Route::Erase => {
+            return match erasure::from_invocation(event) {
+                Ok(request) => {
+                    erase_sender(&email_config, mail_store.as_ref(), &request)
+                        .await
+                }
+                Err(e) => Ok(LambdaResponse::new(STATUS_MALFORMED, &e)),
+            }
+        }
+
+        // Searches of the mail index, invoked directly
+        router::Route::Search => {
+            return match search::from_invocation(event) {
+                Ok(request) => search_index(&email_config, &request).await,
+                Err(e) => Ok(LambdaResponse::new(STATUS_MALFORMED, &e)),
+            }
+        }
+
+        // Mail composed from an alias, invoked directly
+        router::Route::SendRequest => {
+            return match outbound::from_invocation(event) {
+                Ok(request) => {
+                    send_as_alias(
+                        email_sender,
+                        dkim.as_ref(),
+                        &email_config,
+                        &request,
+                    )
+                    .await
+                }
+                Err(e) => Ok(LambdaResponse::new(STATUS_MALFORMED, &e)),
+            }
+        }
+        router::Route::Records | router::Route::Http => {}
+    }
+
+    // Fetch ses request payload from the sns message, raw or enveloped
+    let message = match sns::message(event) {
+        Ok(message) => message,
+        Err(e) => return Ok(LambdaResponse::new(STATUS_MALFORMED, &e)),
+    };
+    trace!("Raw Email Info: {:?}", message);
+
+    // Feedback about sent mail is reported on the same topic
+    match feedback::notification_type(&message) {
+        feedback::BOUNCE => {
+            let notification = serde_json::from_value(message)?;
+            return handle_bounce(
+                email_sender,
+                dkim.as_ref(),
+                &email_config,
+                mail_store.as_ref(),
+                &notification,
+            )
+            .await;
+        }
+        feedback::DELIVERY => {
+            let notification = serde_json::from_value(message)?;
+            return Ok(handle_delivery(&notification));
+        }
+        feedback::DELIVERY_DELAY => {
+            let notification = serde_json::from_value(message)?;
+            return Ok(handle_delivery_delay(&notification));
+        }
+        _ => {}
+    }
+    let mut ses_mail: EmailReceiptNotification =
+        match serde_json::from_value::<EmailReceiptNotification>(message)
+            .map_err(Error::from)
+            .and_then(EmailReceiptNotification::decoded)
+        {
+            Ok(ses_mail) => ses_mail,
+            Err(e) => {
+                let err_msg = format!("Invalid SES notification: {}", e);
+                return Ok(LambdaResponse::new(STATUS_MALFORMED, &err_msg));
+            }
+        };
+
+    ses_mail.replay = replay.cloned();
+
+    // Use the settings of the tenant the mail was received for
+    let email_config = email_config.for_tenant(&ses_mail.mail.destination);
+
+    // Apply the settings stored for the recipient, if configured
+    let email_config = match email_config.config_table.clone() {
+        Some(table) => {
+            config::dynamo::apply(
+                &table,
+                email_config,
+                &ses_mail.mail.destination,
+            )
+            .await?
+        }
+        None => email_config,
+    };
+
+    // Replays may forward to another address
+    let mut email_config = email_config;
+    if let Some(to) = ses_mail.replay.as_ref().and_then(|r| r.to.clone()) {
+        email_config.to_email = to;
+    }
+
+    // Claim the message before anything is done with it, skipping it when
+    // it was already handled, unless replayed
+    let dedup_table =
+        email_config.dedup_table.as_ref().filter(|_| ses_mail.replay.is_none());
+    let dedup_key = dedup_table.map(|table| {
+        let headers = mailparse::parse_headers(&ses_mail.content)
+            .map(|(headers, _)| headers)
+            .unwrap_or_default();
+        let key = idempotency::key(
+            &headers,
+            &ses_mail.mail.message_id,
+            email_config.dedup_by_header,
+        );
+        idempotency::Lease::new(table, key)
+    });
+    if let Some(lease) = &dedup_key {
+        let key = &lease.key;
+        let ttl = email_config.dedup_ttl_hours as i64 * 3600;
+        match idempotency::claim(lease, quiet::now(), ttl).await? {
+            Claim::Claimed => {}
+            Claim::Duplicate => {
+                let err_msg = format!("Duplicate of {}, skipping!", key);
+                info!(
+                    message_id = ses_mail.mail.message_id.as_str(),
+                    "{}", err_msg
+                );
+                return Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+                    .with_action(MailAction::Duplicate));
+            }
+            Claim::Unconfirmed => {
+                let err_msg =
+                    format!("Unconfirmed send of {}, not resending!", key);
+                error!(
+                    message_id = ses_mail.mail.message_id.as_str(),
+                    "{}", err_msg
+                );
+                if let Some(mail_store) = &mail_store {
+                    if let Err(e) = mail_store
+                        .records
+                        .increment(idempotency::UNCONFIRMED_COUNTER, 1)
+                        .await
+                    {
+                        error!("Error counting unconfirmed send: {:?}", e);
+                    }
+                }
+                return Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+                    .with_action(MailAction::Duplicate));
+            }
+        }
+    }
+
+    // Settle the claim however the message ended: kept once handled, even
+    // when dropped or held back, and given up when it failed, so that a
+    // retry handles it again
+    let result = handle_mail(
+        service,
+        event,
+        email_config,
+        keys.as_ref(),
+        ses_mail,
+        dedup_key.as_ref(),
+    )
+    .await;
+    let handled = matches!(
+        &result,
+        Ok(response) if response.status_code() != STATUS_SES_FAILURE
+    );
+    let forwarded_message_id =
+        result.as_ref().ok().and_then(LambdaResponse::forwarded_message_id);
+    settle_claim(dedup_key.as_ref(), handled, forwarded_message_id).await;
+    result
+}
+
+/// Handles the received message `ses_mail`, claimed as `dedup_key`, with
+/// the configuration of its recipient.
+async fn handle_mail(
+    service: &PrivatEmailService,
+    event: &Value,
+    email_config: PrivatEmailConfig,
+    keys: &Keys,
+    ses_mail: EmailReceiptNotification,
+    dedup_key: Option<&idempotency::Lease>,
+) -> Result<LambdaResponse, Error> {
+    let email_sender = service.email_sender();
+    let email_sender = email_sender.as_ref();
+    let mail_store = &service.mail_store;
+    let clients = service.clients();
+    let Keys { dkim, pgp, keyring, smime, trust_store } = keys;
+
+    put_event(&email_config, &ses_mail, events::RECEIVED).await;
+
+    // Archive every received message before it is handled, replays already
+    // are
+    let objects = archive::object_store(&email_config)
+        .filter(|_| ses_mail.replay.is_none());
+    if let Some(objects) = objects {
+        let metadata = ses_mail.to_archive_metadata();
+        let bucket = objects.bucket();
+        match archive::write(&objects, metadata, ses_mail.content.clone()).await
+        {
+            Ok(key) => info!("Archived to s3://{}/{}", bucket, key),
+            Err(e) => error!("Error archiving to {}: {:?}", bucket, e),
+        }
+    }
+
+    // skip spam messages
+    let ses_receipt = &ses_mail.receipt;
+    if ses_receipt.spam_verdict.status == "FAIL"
+        || ses_receipt.virus_verdict.status == "FAIL"
+    {
+        let err_msg = "Message contains spam or virus, skipping!";
+        error!(err_msg);
+        if let Some(mail_store) = &mail_store {
+            if let Err(e) =
+                mail_store.records.increment(stats::SPAM_COUNTER, 1).await
+            {
+                error!("Error counting spam: {:?}", e);
+            }
+        }
+        track_outcome_logged(
+            &email_config,
+            mail_store.as_ref(),
+            &ses_mail,
+            MailAction::Blocked,
+        )
+        .await;
+        return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+            .with_action(MailAction::Blocked));
+    }
+
+    // Apply the SPF, DKIM and DMARC policies, collecting checks to tag
+    let mut failed_checks = Vec::new();
+    for check in ses_receipt.failed_checks() {
+        match email_config.verdict_policy(check) {
+            VerdictPolicy::Drop => {
+                let err_msg = format!("Message failed {}", check.as_str());
+                warn!("`{}`, skipping!", err_msg);
+                track_outcome_logged(
+                    &email_config,
+                    mail_store.as_ref(),
+                    &ses_mail,
+                    MailAction::Blocked,
+                )
+                .await;
+                return Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+                    .with_action(MailAction::Blocked));
+            }
+            VerdictPolicy::Tag => failed_checks.push(check),
+            VerdictPolicy::Forward => {}
+        }
+    }
+
+    // Keep messages too large to parse safely out of the pipeline
+    if limits::exceeds_limit(
+        ses_mail.content.len(),
+        email_config.max_content_size,
+    ) {
+        return quarantine_oversized(
+            email_sender,
+            dkim.as_ref(),
+            &email_config,
+            mail_store.as_ref(),
+            &ses_mail,
+        )
+        .await;
+    }
+
+    // Route bounces of rewritten return paths back to the original sender
+    let srs = srs::Srs::from_config(&email_config);
+    if let Some(original) = srs.as_ref().and_then(|srs| {
+        ses_mail
+            .mail
+            .destination
+            .iter()
+            .find_map(|d| srs.reverse(d, srs::today()).ok())
+    }) {
+        let headers = mailparse::parse_headers(&ses_mail.content)
+            .map(|(headers, _)| headers)
+            .unwrap_or_default();
+        if !srs::is_bounce(&headers, &ses_mail.mail.source) {
+            let err_msg = "Mail to a return path is not a bounce, skipping!";
+            warn!(source = ses_mail.mail.source.as_str(), "{}", err_msg);
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Blocked,
+            )
+            .await;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+                .with_action(MailAction::Blocked));
+        }
+        let raw = mime::MimeMessage::new()
+            .header("From", &email_config.from_email)
+            .header("To", &original)
+            .header(
+                "Subject",
+                format!(
+                    "Undeliverable: {}",
+                    ses_mail.mail.common_headers.subject
+                ),
+            )
+            .header("Auto-Submitted", "auto-replied")
+            .text(
+                "A message you sent could not be delivered. \
+                 The delivery report is attached.\r\n",
+            )
+            .attach(mime::Attachment::message("bounce.eml", &ses_mail.content))
+            .to_bytes();
+        let sent = forward::send_raw_email(
+            email_sender,
+            dkim.as_ref(),
+            &email_config.from_email,
+            vec![original],
+            raw.into(),
+            email_config.configuration_set.as_deref(),
+            &[],
+        )
+        .await;
+        let action = match &sent {
+            Ok(_) => MailAction::Relayed,
+            Err(_) => MailAction::Failed,
+        };
+        track_outcome_logged(
+            &email_config,
+            mail_store.as_ref(),
+            &ses_mail,
+            action,
+        )
+        .await;
+        return sent.map(|message_id| {
+            LambdaResponse::new(STATUS_OK, &message_id)
+                .with_forwarded_message_id(&message_id)
+                .with_action(action)
+        });
+    }
+
+    // Relay replies from the destination inbox back to the original sender
+    let relay = relay::Relay::from_config(&email_config);
+    let objects = mail_store.as_ref().map(|store| &*store.objects);
+    let mut relayed = None;
+    if let Some(relay) = &relay {
+        for destination in &ses_mail.mail.destination {
+            relayed = relay.decode(destination, objects).await?;
+            if relayed.is_some() {
+                break;
+            }
+        }
+    }
+    if let Some(recipient) = relayed {
+        // The envelope sender can be forged, so it must also pass SPF or
+        // carry a passing DKIM signature of its domain
+        let source = ses_mail.mail.source.as_str();
+        let signatures = mailparse::parse_headers(&ses_mail.content)
+            .map(|(headers, _)| headers.get_all_values("DKIM-Signature"))
+            .unwrap_or_default();
+        let authenticated = ses_receipt.passed(AuthCheck::Spf)
+            || ses_receipt.passed(AuthCheck::Dkim)
+                && auth::dkim_aligned(&signatures, source);
+        if !source.eq_ignore_ascii_case(&email_config.to_email)
+            || !authenticated
+        {
+            let err_msg = "Relay reply from unknown sender, skipping!";
+            warn!(source = ses_mail.mail.source.as_str(), "{}", err_msg);
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Blocked,
+            )
+            .await;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+                .with_action(MailAction::Blocked));
+        }
+
+        let reply = match content::parse(&ses_mail.content) {
+            Ok(reply) => reply,
+            Err(e) => {
+                return quarantine_malformed(
+                    &email_config,
+                    mail_store.as_ref(),
+                    &ses_mail,
+                    &e,
+                )
+                .await;
+            }
+        };
+        let raw =
+            relay::rebuild_reply(&reply, &email_config.from_email, &recipient)
+                .to_bytes();
+        return match forward::send_raw_email(
+            email_sender,
+            dkim.as_ref(),
+            &email_config.from_email,
+            vec![recipient],
+            raw.into(),
+            email_config.configuration_set.as_deref(),
+            &[],
+        )
+        .await
+        {
+            Ok(message_id) => {
+                trace!("Reply relay success: {}", message_id);
+                track_outcome_logged(
+                    &email_config,
+                    mail_store.as_ref(),
+                    &ses_mail,
+                    MailAction::Relayed,
+                )
+                .await;
+                Ok(LambdaResponse::new(STATUS_OK, &message_id)
+                    .with_forwarded_message_id(&message_id)
+                    .with_action(MailAction::Relayed))
+            }
+            Err(error) => {
+                error!("Error relaying reply: {:?}", error);
+                track_outcome_logged(
+                    &email_config,
+                    mail_store.as_ref(),
+                    &ses_mail,
+                    MailAction::Failed,
+                )
+                .await;
+                Err(error)
+            }
+        };
+    }
+
+    // Route mail from configured senders to their own destination
+    let email_config = email_config.for_sender(&ses_mail.senders());
+
+    // Rewrite Email From header to contain sender's name with forwarder's email address
+    let original_sender: String =
+        ses_mail.mail.common_headers.return_path.to_string();
+    let subject: String = ses_mail.mail.common_headers.subject.to_string();
+
+    // parse email content, quarantining what does not parse
+    let mail = match content::parse(&ses_mail.content) {
+        Ok(mail) => mail,
+        Err(e) => {
+            return quarantine_malformed(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                &e,
+            )
+            .await;
+        }
+    };
+
+    // Drop forwards which came back, alerting as they burn SES quota
+    if email_config.loop_detection
+        && forward::is_loop(&mail.headers, email_config.loop_identifier())
+    {
+        let err_msg = "Mail loop detected, dropping!";
+        error!(message_id = ses_mail.mail.message_id.as_str(), err_msg);
+        track_outcome_logged(
+            &email_config,
+            mail_store.as_ref(),
+            &ses_mail,
+            MailAction::Blocked,
+        )
+        .await;
+        send_notifications(
+            &email_config,
+            &notify::Notification {
+                message_id: ses_mail.mail.message_id.clone(),
+                sender: ses_mail.mail.source.clone(),
+                recipients: ses_mail.mail.destination.clone(),
+                subject,
+                action: MailAction::Blocked,
+                preview_url: None,
+                text: None,
+                thread_count: 1,
+            },
+        )
+        .await;
+        return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+            .with_action(MailAction::Blocked));
+    }
+
+    // Refuse messages relayed too often, as by a misconfigured chain
+    if let Some(limit) = email_config.hop_limit {
+        if let Err(e) = received::check_hops(
+            &mail.headers,
+            email_config.hop_header.as_deref(),
+            limit,
+        ) {
+            error!(
+                message_id = ses_mail.mail.message_id.as_str(),
+                hops = e.hops,
+                hop_limit = e.limit,
+                metric = received::HOP_LIMIT_COUNTER,
+                "{}, skipping!",
+                e
+            );
+            if let Some(mail_store) = &mail_store {
+                if let Err(e) = mail_store
+                    .records
+                    .increment(received::HOP_LIMIT_COUNTER, 1)
+                    .await
+                {
+                    error!("Error counting hop limit: {:?}", e);
+                }
+            }
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Blocked,
+            )
+            .await;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, &e.to_string())
+                .with_action(MailAction::Blocked));
+        }
+    }
+
+    let msg_body = match content::html_body(&mail) {
+        Ok(msg_body) => msg_body,
+        Err(e) => {
+            return quarantine_malformed(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                &e,
+            )
+            .await;
+        }
+    };
+    trace!("HTML content: {:#?}", msg_body);
+
+    // Verify PGP/MIME signatures against the configured keyring
+    let verification = keyring
+        .as_ref()
+        .and_then(|keyring| keyring.verify_mime(&mail, &ses_mail.content));
+    trace!("PGP signature: {:?}", verification);
+    let smime_verification = trust_store.as_ref().and_then(|trust_store| {
+        trust_store.verify_mime(&mail, &ses_mail.content)
+    });
+    trace!("S/MIME signature: {:?}", smime_verification);
+
+    // Flag anomalies of the Received chain for the classifier
+    let chain = received::chain(&mail.headers);
+    let anomalies = received::analyze(&chain);
+    for anomaly in &anomalies {
+        warn!(
+            message_id = ses_mail.mail.message_id.as_str(),
+            "Received chain anomaly: {}", anomaly
+        );
+    }
+
+    // Look the sending relay and domain up in the DNS blocklists
+    let dnsbl_verdict = dnsbl::screen(
+        service.resolver.as_ref(),
+        &email_config,
+        &chain,
+        &ses_mail.mail.source,
+        &ses_mail.mail.message_id,
+        mail_store.as_ref().map(|mail_store| &*mail_store.records),
+    )
+    .await;
+    if dnsbl_verdict == dnsbl::Verdict::Drop {
+        let err_msg = "Message listed in DNS blocklists";
+        warn!("`{}`, skipping!", err_msg);
+        track_outcome_logged(
+            &email_config,
+            mail_store.as_ref(),
+            &ses_mail,
+            MailAction::Blocked,
+        )
+        .await;
+        return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+            .with_action(MailAction::Blocked));
+    }
+
+    // Audit and act on attachments matching the blocklist
+    let mut stripped_attachments = false;
+    if let Some(blocklist) = &email_config.blocked_attachments {
+        let blocked = attachments::find_blocked(&mail, blocklist);
+        stripped_attachments = !blocked.is_empty();
+        for attachment in &blocked {
+            warn!(
+                message_id = ses_mail.mail.message_id.as_str(),
+                filename = ?attachment.filename,
+                mimetype = attachment.mimetype.as_str(),
+                rule = attachment.rule.as_str(),
+                action = ?email_config.attachment_action,
+                "Blocked attachment"
+            );
+        }
+        if !blocked.is_empty()
+            && email_config.attachment_action == AttachmentAction::Quarantine
+        {
+            let err_msg =
+                "Message quarantined for blocked attachment, skipping!";
+            error!(err_msg);
+            track_outcome(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Quarantined,
+            )
+            .await?;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+                .with_action(MailAction::Quarantined));
+        }
+    }
+
+    // Apply the filtering rules, led by the black list
+    let filter_rules = email_config.filter_rules();
+    let rule_message =
+        ses_mail.rule_message(&mail, email_config.rules_utc_offset);
+    let outcome = rules::evaluate(
+        &filter_rules,
+        email_config.default_rule_action.as_ref(),
+        &rule_message,
+    );
+    for &index in &outcome.matched {
+        let rule = &filter_rules[index];
+        info!(rule = rule.label(index), action = %rule.action, "Rule matched");
+    }
+    let rule = match outcome.matched.last() {
+        Some(&index) => filter_rules[index].label(index),
+        None => "default".to_owned(),
+    };
+    match outcome.disposition {
+        rules::Action::Drop => {
+            let err_msg = format!("Message dropped by rule {}", rule);
+            trace!("`{}`, skipping!", err_msg);
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Blocked,
+            )
+            .await;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+                .with_action(MailAction::Blocked));
+        }
+        rules::Action::Quarantine => {
+            let err_msg = format!("Message quarantined by rule {}", rule);
+            warn!("`{}`, skipping!", err_msg);
+            track_outcome(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Quarantined,
+            )
+            .await?;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+                .with_action(MailAction::Quarantined));
+        }
+        rules::Action::Digest => match &mail_store {
+            Some(mail_store) => {
+                track_outcome(
+                    &email_config,
+                    Some(mail_store),
+                    &ses_mail,
+                    MailAction::Digested,
+                )
+                .await?;
+                let summarizer = summary::from_config(&email_config);
+                let entry = digest::DigestEntry::new(
+                    &ses_mail.to_record(MailAction::Digested),
+                    &msg_body,
+                    email_config.digest_summaries,
+                    summarizer.as_deref(),
+                    digest::SUMMARY_TIMEOUT,
+                )
+                .await;
+                mail_store
+                    .objects
+                    .put(
+                        &digest::entry_key(&entry.message_id),
+                        serde_json::to_vec(&entry)?.into(),
+                    )
+                    .await?;
+                let msg =
+                    format!("Message held for the digest by rule {}", rule);
+                trace!("`{}`, skipping!", msg);
+                return Ok(LambdaResponse::new(STATUS_SKIPPED, &msg)
+                    .with_action(MailAction::Digested));
+            }
+            None => warn!("Digest rule needs a store, forwarding"),
+        },
+        _ => {}
+    }
+    let mut email_config = email_config;
+    if let Some(to_email) = &outcome.redirect {
+        email_config.to_email = to_email.clone();
+    }
+
+    // Label the message, if a classifier is configured
+    let label = match clients.classifier.as_deref() {
+        Some(classifier) => {
+            let budget = match (&mail_store, email_config.classifier_daily_cap)
+            {
+                (Some(mail_store), Some(cap)) => Some(classify::Budget {
+                    records: &*mail_store.records,
+                    day: ses_mail.mail.timestamp.get(..10).unwrap_or_default(),
+                    cap,
+                }),
+                _ => None,
+            };
+            classify::classify(
+                classifier,
+                &classify::ClassifierInput::new(
+                    &original_sender,
+                    &subject,
+                    &msg_body,
+                )
+                .with_anomalies(&anomalies)
+                .with_authenticated(ses_receipt.passed(AuthCheck::Spf)),
+                Duration::from_millis(email_config.classifier_timeout_ms),
+                budget,
+            )
+            .await
+        }
+        None => None,
+    };
+    trace!("Message label: {:?}", label);
+
+    // Skip mail carrying a blocked label
+    if let (Some(label), Some(blocked_labels)) =
+        (label, &email_config.blocked_labels)
+    {
+        if blocked_labels.contains(&label) {
+            let err_msg = format!("Message labelled {}", label.as_str());
+            trace!("`{}`, skipping!", err_msg);
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Blocked,
+            )
+            .await;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, &err_msg)
+                .with_action(MailAction::Blocked));
+        }
+    }
+
+    // Send mail of Telegram-only aliases to the chat instead of forwarding
+    if email_config.telegram_only(&ses_mail.mail.destination) {
+        if let Some(telegram) =
+            notify::TelegramNotifier::from_config(&email_config)
+        {
+            telegram
+                .notify(&notify::Notification {
+                    message_id: ses_mail.mail.message_id.clone(),
+                    sender: original_sender.clone(),
+                    recipients: ses_mail.mail.destination.clone(),
+                    subject: subject.clone(),
+                    action: MailAction::Notified,
+                    preview_url: None,
+                    text: Some(rules::body_text(&mail)),
+                    thread_count: 1,
+                })
+                .await?;
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Notified,
+            )
+            .await;
+            return Ok(LambdaResponse::new(
+                STATUS_SKIPPED,
+                "Message sent to Telegram",
+            )
+            .with_action(MailAction::Notified));
+        }
+    }
+
+    // Mark tagged authentication failures and blocklistings in the subject
+    let subject = auth::tag_subject(&subject, &failed_checks);
+    let subject = match dnsbl_verdict.header() {
+        Some(_) => forward::prefix_subject(dnsbl::SUBJECT_TAG, &subject),
+        None => subject,
+    };
+    let subject =
+        outcome.tags.iter().rev().fold(subject, |subject, tag| {
+            forward::prefix_subject(tag, &subject)
+        });
+
+    // Prefix the subject so the destination inbox can filter on it
+    let subject =
+        match email_config.subject_prefix_for(&ses_mail.mail.destination) {
+            Some(prefix) => forward::prefix_subject(prefix, &subject),
+            None => subject,
+        };
+
+    // The original can only be attached untouched when nothing was stripped
+    let mut forward_mode = email_config.forward_mode;
+    if forward_mode == ForwardMode::Attachment && stripped_attachments {
+        warn!("Attachments were stripped, forwarding inline instead");
+        forward_mode = ForwardMode::Inline;
+    }
+
+    // Keep the forward from reporting back to trackers, if enabled
+    let msg_body = if email_config.strip_trackers {
+        match &email_config.tracking_redirects {
+            Some(redirects) => tracking::strip(&msg_body, redirects),
+            None => tracking::strip(&msg_body, tracking::DEFAULT_REDIRECTS),
+        }
+    } else {
+        msg_body
+    };
+
+    // Strip active content from the forwarded body, if enabled
+    let msg_body = if email_config.sanitize_html {
+        html::sanitize(&msg_body)
+    } else {
+        msg_body
+    };
+
+    // Give inline forwards a plain text alternative, rendered from the
+    // cleaned HTML so removed trackers don't reappear as link targets
+    let text_alternative = (email_config.text_fallback
+        && forward_mode == ForwardMode::Inline)
+        .then(|| forward::text_alternative(&mail, &msg_body));
+
+    // Show the signature state above the forwarded body
+    let banner = verification
+        .as_ref()
+        .map(|verification| verification.banner())
+        .or_else(|| smime_verification.as_ref().map(|v| v.banner()));
+    let (msg_body, text_alternative) = match banner {
+        Some(banner) => (
+            format!("<p><b>{}</b></p>\r\n{}", banner, msg_body),
+            text_alternative.map(|text| format!("{}\r\n\r\n{}", banner, text)),
+        ),
+        None => (msg_body, text_alternative),
+    };
+
+    // Prepend the configured banner, above any signature state
+    let (msg_body, text_alternative) =
+        match banner::Banner::from_config(&email_config) {
+            Some(banner) => {
+                let context = banner::Context::new(
+                    mail.headers
+                        .get_first_value("From")
+                        .as_deref()
+                        .unwrap_or(&original_sender),
+                    &ses_mail.mail.destination,
+                    &ses_mail.mail.timestamp,
+                );
+                banner.prepend(&context, &msg_body, text_alternative.as_deref())
+            }
+            None => (msg_body, text_alternative),
+        };
+
+    // Replace messages exceeding the SES send limit with a notification
+    // linking to the stored original
+    let outbound_size = match forward_mode {
+        ForwardMode::Inline => {
+            subject.len()
+                + msg_body.len()
+                + text_alternative.as_ref().map_or(0, String::len)
+        }
+        ForwardMode::Attachment => ses_mail.content.len(),
+    };
+    let oversized =
+        limits::exceeds_limit(outbound_size, email_config.max_send_size);
+    let (subject, html_body, text_body) = if oversized {
+        warn!(
+            "Message {} exceeds the send limit ({} > {} bytes)",
+            ses_mail.mail.message_id, outbound_size, email_config.max_send_size
+        );
+        let link = match &mail_store {
+            Some(mail_store) => store_oversized(mail_store, &ses_mail).await,
+            None => None,
+        };
+        let notice = limits::oversize_notice(
+            &subject,
+            &original_sender,
+            outbound_size,
+            link.as_deref(),
+        );
+        (
+            format!("{}{}", limits::OVERSIZE_SUBJECT_PREFIX, subject),
+            None,
+            Some(notice),
+        )
+    } else {
+        (subject, Some(msg_body), text_alternative)
+    };
+
+    // Keep the HTML around for the notification preview
+    let preview_html =
+        email_config.preview_renderer.as_ref().and(html_body.clone());
+    let notification_subject = subject.clone();
+    let notification_sender = original_sender.clone();
+    let notification_text = (email_config.telegram_chat_id.is_some()
+        || email_config
+            .discord_webhook_for(&ses_mail.mail.destination)
+            .is_some())
+    .then(|| rules::body_text(&mail));
+
+    // Bounces go to a rewritten return path when SRS is configured
+    let return_path =
+        srs.as_ref().map(|srs| srs.forward(&original_sender, srs::today()));
+
+    // Replies go through the relay when it is configured
+    let reply_to = match &relay {
+        Some(relay) => relay
+            .reply_address(
+                &original_sender,
+                mail_store.as_ref().map(|store| &*store.objects),
+            )
+            .await?
+            .unwrap_or_else(|| {
+                warn!("Relay needs a store for long senders, not relaying");
+                original_sender
+            }),
+        None => original_sender,
+    };
+
+    // Quiet hours of the route hold back notifications, and the forward
+    // itself when deferring is enabled; rules may defer it until their
+    // schedule opens, and a nearly used sending quota for a while. Any
+    // needs a store to hold the forward
+    let quiet_until =
+        email_config.quiet_until(&ses_mail.mail.destination, quiet::now());
+    let quota_until = quota_exhausted(service, &email_config)
+        .await
+        .then(|| quiet::now() + email_config.quota_defer_secs as i64);
+    let defer_until = quiet_until
+        .filter(|_| email_config.quiet_hours_defer)
+        .max(outcome.defer_until)
+        .max(quota_until)
+        .filter(|_| mail_store.is_some() && !oversized);
+    if outcome.defer_until.is_some() && defer_until.is_none() {
+        warn!("Rule deferral needs a store and a sendable size, forwarding");
+    }
+    if quota_until.is_some() {
+        match (defer_until, &mail_store) {
+            (Some(_), Some(mail_store)) => {
+                warn!(
+                    message_id = ses_mail.mail.message_id.as_str(),
+                    metric = quota::DEFERRED_COUNTER,
+                    "Sending quota exhausted, deferring"
+                );
+                if let Err(e) = mail_store
+                    .records
+                    .increment(quota::DEFERRED_COUNTER, 1)
+                    .await
+                {
+                    error!("Error counting quota deferral: {:?}", e);
+                }
+            }
+            _ => warn!("Sending quota exhausted, forwarding"),
+        }
+    }
+
+    // Name the forwarded stream in the destination inbox
+    let to_address = email_config.to_address(&ses_mail.mail.destination);
+
+    // Tag the forward for slicing SES metrics and events
+    let mut tags = Vec::new();
+    if let Some(label) = label {
+        tags.push(MessageTag {
+            name: classify::LABEL_TAG.to_owned(),
+            value: label.as_str().to_owned(),
+        });
+    }
+    if email_config.message_tags {
+        if let Some(recipient) = ses_mail.mail.destination.first() {
+            tags.extend(forward::message_tags(
+                recipient,
+                &ses_mail.mail.source,
+                config::tenant::name(&email_config.tenants, recipient),
+            ));
+        }
+    }
+
+    // Tracked aliases go through the configuration set tracking opens and
+    // clicks, which SES only adds to HTML bodies
+    let configuration_set =
+        email_config.configuration_set_for(&ses_mail.mail.destination);
+    if html_body.is_none()
+        && configuration_set.is_some()
+        && configuration_set
+            == email_config.tracking_configuration_set.as_deref()
+    {
+        trace!("Tracked forward has no HTML body, SES can't track it");
+    }
+
+    let ses_email_message = SendEmailRequest {
+        configuration_set_name: configuration_set.map(str::to_owned),
+        destination: Destination {
+            bcc_addresses: Default::default(),
+            cc_addresses: Default::default(),
+            to_addresses: Some(vec![to_address.clone()]),
+        },
+        message: Message {
+            body: Body {
+                html: html_body
+                    .map(|data| Content { charset: Default::default(), data }),
+                text: text_body
+                    .map(|data| Content { charset: Default::default(), data }),
+            },
+            subject: Content { charset: Default::default(), data: subject },
+        },
+        reply_to_addresses: Some(vec![reply_to.clone()]),
+        return_path: return_path.clone(),
+        return_path_arn: Default::default(),
+        source: email_config.from_email.to_string(),
+        source_arn: Default::default(),
+        tags: Some(tags).filter(|tags| !tags.is_empty()),
+    };
+
+    // Raw forwards carry the original untouched or need extra headers,
+    // which SES can't add to the messages it builds: every header below,
+    // the label excepted, which formatted forwards carry as a message tag
+    let raw_message = if forward_mode == ForwardMode::Attachment && !oversized {
+        Some(forward::wrap_original(
+            &email_config.from_email,
+            &to_address,
+            &reply_to,
+            &notification_sender,
+            &notification_subject,
+            &ses_mail.content,
+        ))
+    } else if email_config.inject_headers
+        || email_config.loop_detection
+        || email_config.ses_v2
+        || pgp.is_some()
+        || defer_until.is_some()
+        || verification.is_some()
+        || smime.is_some()
+        || smime_verification.is_some()
+        || !failed_checks.is_empty()
+        || dnsbl_verdict.header().is_some()
+    {
+        Some(forward::from_request(&ses_email_message))
+    } else {
+        None
+    };
+
+    // Quarantine the message while SES keeps failing
+    if email_config.circuit_breaker_threshold.is_some() {
+        let allowed = breaker::shared().lock().unwrap().allow(
+            quiet::now(),
+            email_config.circuit_breaker_cooldown_secs as i64,
+        );
+        if !allowed {
+            let err_msg = "Circuit breaker open, message quarantined";
+            error!(
+                message_id = ses_mail.mail.message_id.as_str(),
+                metric = breaker::OPEN_COUNTER,
+                "{}",
+                err_msg
+            );
+            let Some(mail_store) = &mail_store else {
+                return Err(err_msg.into());
+            };
+            if let Err(e) =
+                mail_store.records.increment(breaker::OPEN_COUNTER, 1).await
+            {
+                error!("Error counting open circuit: {:?}", e);
+            }
+            track_outcome(
+                &email_config,
+                Some(mail_store),
+                &ses_mail,
+                MailAction::Quarantined,
+            )
+            .await?;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, err_msg)
+                .with_action(MailAction::Quarantined));
+        }
+    }
+
+    // SES drops forwards to a suppressed destination after accepting them
+    let send_result = if suppressed(&email_config, &email_config.to_email).await
+    {
+        Err(format!(
+            "Destination {} is on the account suppression list",
+            email_config.to_email
+        )
+        .into())
+    } else if let Some(mut message) = raw_message {
+        if let Some(label) = label {
+            message = message.header(classify::LABEL_HEADER, label.as_str());
+        }
+        if email_config.loop_detection {
+            message = message
+                .header(forward::LOOP_HEADER, email_config.loop_identifier());
+        }
+        if let Some(return_path) = &return_path {
+            message = message.header("Return-Path", return_path);
+        }
+        if !failed_checks.is_empty() {
+            message = message
+                .header(auth::AUTH_HEADER, auth::header_value(&failed_checks));
+        }
+        if let Some(listings) = dnsbl_verdict.header() {
+            message = message.header(dnsbl::DNSBL_HEADER, listings);
+        }
+        if let Some(verification) = &verification {
+            message = message.header(
+                pgp::verify::VERIFIED_HEADER,
+                verification.header_value(),
+            );
+        }
+        if let Some(verification) = &smime_verification {
+            message = message
+                .header(smime::VERIFIED_HEADER, verification.header_value());
+        }
+        if email_config.inject_headers {
+            for (name, value) in forward::forwarding_headers(
+                &ses_mail.mail.destination,
+                &ses_mail.mail.source,
+                &ses_mail.mail.message_id,
+            ) {
+                message = message.header(name, value);
+            }
+        }
+        let mut raw =
+            forward::with_threading(message, &mail.headers).to_bytes();
+        if let Some(smime) = &smime {
+            trace!("Signing forward as {}", smime.subject());
+            raw = smime.sign_message(&raw)?;
+        }
+        if let Some(pgp) = &pgp {
+            trace!("Encrypting forward to PGP key {}", pgp.key_id());
+            raw = pgp.encrypt_message(&raw)?;
+        }
+        // Shared by the store and every send attempt
+        let raw = Bytes::from(raw);
+        if let (Some(release_at), Some(mail_store)) = (defer_until, &mail_store)
+        {
+            let key =
+                quiet::deferred_key(release_at, &ses_mail.mail.message_id);
+            let tags = ses_email_message.tags.iter().flatten();
+            let release = quiet::Release {
+                from_email: email_config.from_email.clone(),
+                destinations: vec![email_config.to_email.clone()],
+                configuration_set: ses_email_message
+                    .configuration_set_name
+                    .clone(),
+                tags: tags.map(|t| (t.name.clone(), t.value.clone())).collect(),
+            };
+            let release = serde_json::to_vec(&release)?;
+            mail_store
+                .objects
+                .put(&quiet::release_key(&key), release.into())
+                .await?;
+            mail_store.objects.put(&key, raw.clone()).await?;
+            track_outcome_logged(
+                &email_config,
+                Some(mail_store),
+                &ses_mail,
+                MailAction::Deferred,
+            )
+            .await;
+            return Ok(LambdaResponse::new(STATUS_SKIPPED, &key)
+                .with_action(MailAction::Deferred));
+        }
+        begin_send(dedup_key).await?;
+        failure::retry_throttled(
+            email_config.send_retries,
+            email_config.send_retry_base_ms,
+            || {
+                send_raw_forward(
+                    email_sender,
+                    dkim.as_ref(),
+                    &email_config,
+                    raw.clone(),
+                    ses_email_message.configuration_set_name.as_deref(),
+                    ses_email_message.tags.as_deref().unwrap_or_default(),
+                )
+            },
+        )
+        .await
+    } else {
+        begin_send(dedup_key).await?;
+        failure::retry_throttled(
+            email_config.send_retries,
+            email_config.send_retry_base_ms,
+            || email_sender.send_email(ses_email_message.clone()),
+        )
+        .await
+    };
+
+    match send_result {
+        Ok(message_id) => {
+            trace!("Email forward success: {}", message_id);
+            if email_config.circuit_breaker_threshold.is_some() {
+                breaker::shared().lock().unwrap().record_success();
+            }
+            if let Some(threshold) = email_config.alert_failure_threshold {
+                end_failure_streak(
+                    &email_config,
+                    mail_store.as_ref(),
+                    threshold,
+                )
+                .await;
+            }
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Forwarded,
+            )
+            .await;
+            let thread_count =
+                match (&mail_store, thread::thread_id(&mail.headers)) {
+                    (Some(mail_store), Some(thread_id)) => mail_store
+                        .thread_count(&thread_id)
+                        .await
+                        .map_err(|e| {
+                            error!("Error reading thread count: {:?}", e)
+                        })
+                        .unwrap_or(1)
+                        .max(1),
+                    _ => 1,
+                };
+            let preview_url = match &preview_html {
+                Some(html) => {
+                    render_preview(
+                        clients.renderer.as_deref(),
+                        mail_store.as_ref(),
+                        &ses_mail.mail.message_id,
+                        html,
+                    )
+                    .await
+                }
+                None => None,
+            };
+            send_auto_reply(
+                email_sender,
+                dkim.as_ref(),
+                &email_config,
+                &ses_mail,
+                &mail.headers,
+            )
+            .await;
+            if quiet_until.is_some() {
+                trace!("Quiet hours, skipping notifications");
+            } else {
+                send_notifications(
+                    &email_config,
+                    &notify::Notification {
+                        message_id: ses_mail.mail.message_id.clone(),
+                        sender: notification_sender,
+                        recipients: ses_mail.mail.destination.clone(),
+                        subject: notification_subject,
+                        action: MailAction::Forwarded,
+                        preview_url,
+                        text: notification_text,
+                        thread_count,
+                    },
+                )
+                .await;
+            }
+            Ok(LambdaResponse::new(STATUS_OK, &message_id)
+                .with_forwarded_message_id(&message_id)
+                .with_action(MailAction::Forwarded))
+        }
+        Err(error) => {
+            if let Some(threshold) = email_config.circuit_breaker_threshold {
+                record_send_failure(error.as_ref(), threshold);
+            }
+            if let Some(threshold) = email_config.alert_failure_threshold {
+                extend_failure_streak(
+                    &email_config,
+                    mail_store.as_ref(),
+                    &ses_mail,
+                    error.as_ref(),
+                    threshold,
+                )
+                .await;
+            }
+            track_outcome_logged(
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                MailAction::Failed,
+            )
+            .await;
+            handle_send_failure(
+                email_sender,
+                dkim.as_ref(),
+                &email_config,
+                mail_store.as_ref(),
+                &ses_mail,
+                event,
+                error,
+            )
+            .await
+        }
+    }
+}
+
+/// Whether sends through `service` should wait for room in the sending
+/// quota, when `quota_defer_percent` is set.
+async fn quota_exhausted(
+    service: &PrivatEmailService,
+    email_config: &PrivatEmailConfig,
+) -> bool {
+    let Some(percent) = email_config.quota_defer_percent else {
+        return false;
+    };
+    service
+        .quota
+        .exhausted(service.email_sender().as_ref(), percent, quiet::now())
+        .await
+}
+
+/// Sends the deferred forwards whose release time has passed, as recorded
+/// in their release. Failed sends are logged and kept for the next run.
+async fn release_deferred(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+) -> Result<LambdaResponse, Error> {
+    let Some(mail_store) = mail_store else {
+        return Ok(LambdaResponse::new(
+            STATUS_OK,
+            "No store, nothing deferred",
+        ));
+    };
+    let now = quiet::now();
+    let mut released = 0;
+    for key in mail_store.objects.list(quiet::DEFERRED_PREFIX).await? {
+        if !key.ends_with(".eml")
+            || quiet::release_at(&key).is_none_or(|at| at > now)
+        {
+            continue;
+        }
+        let Some(raw) = mail_store.objects.get(&key).await? else {
+            continue;
+        };
+        let release_key = quiet::release_key(&key);
+        let release = match mail_store.objects.get(&release_key).await? {
+            Some(release) => serde_json::from_slice(&release)?,
+            // Held before releases were recorded
+            None => quiet::Release {
+                from_email: email_config.from_email.clone(),
+                destinations: vec![email_config.to_email.clone()],
+                configuration_set: email_config.configuration_set.clone(),
+                tags: Vec::new(),
+            },
+        };
+        let tags: Vec<MessageTag> = release
+            .tags
+            .into_iter()
+            .map(|(name, value)| MessageTag { name, value })
+            .collect();
+        match forward::send_raw_email(
+            email_sender,
+            dkim,
+            &release.from_email,
+            release.destinations,
+            raw.into(),
+            release.configuration_set.as_deref(),
+            &tags,
+        )
+        .await
+        {
+            Ok(message_id) => {
+                trace!("Released {}: {}", key, message_id);
+                mail_store.objects.delete(&key).await?;
+                mail_store.objects.delete(&release_key).await?;
+                released += 1;
+            }
+            Err(e) => error!("Error releasing {}: {:?}", key, e),
+        }
+    }
+    Ok(LambdaResponse::new(
+        STATUS_OK,
+        &format!("Released {} deferred messages", released),
+    ))
+}
+
+/// Sends the messages held for the digest as a single email to `to_email`
+/// and clears their entries. The raw messages are kept for the links.
+async fn send_digest(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+) -> Result<LambdaResponse, Error> {
+    let Some(mail_store) = mail_store else {
+        return Ok(LambdaResponse::new(
+            STATUS_OK,
+            "No store, nothing digested",
+        ));
+    };
+    let keys: Vec<String> = mail_store
+        .objects
+        .list(store::DIGEST_PREFIX)
+        .await?
+        .into_iter()
+        .filter(|key| digest::is_entry_key(key))
+        .collect();
+    let mut entries = Vec::new();
+    for key in &keys {
+        let Some(body) = mail_store.objects.get(key).await? else {
+            continue;
+        };
+        let mut entry: digest::DigestEntry = serde_json::from_slice(&body)?;
+        let message_key = digest::message_key(&entry.message_id);
+        match mail_store.objects.link(&message_key, digest::LINK_EXPIRY).await {
+            Ok(link) => entry.link = Some(link),
+            Err(e) => warn!("Error linking {}: {:?}", message_key, e),
+        }
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        return Ok(LambdaResponse::new(STATUS_OK, "Nothing digested"));
+    }
+    entries.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+    let raw = mime::MimeMessage::new()
+        .header("From", &email_config.from_email)
+        .header("To", &email_config.to_email)
+        .header("Subject", digest::subject(entries.len()))
+        .header("Auto-Submitted", "auto-generated")
+        .text(digest::render_text(&entries))
+        .to_bytes();
+    let message_id = forward::send_raw_email(
+        email_sender,
+        dkim,
+        &email_config.from_email,
+        vec![email_config.to_email.clone()],
+        raw.into(),
+        email_config.configuration_set.as_deref(),
+        &[],
+    )
+    .await?;
+    for key in &keys {
+        mail_store.objects.delete(key).await?;
+    }
+    info!(message_id = message_id.as_str(), "Sent digest");
+    Ok(LambdaResponse::new(
+        STATUS_OK,
+        &format!("Sent digest of {} messages", entries.len()),
+    ))
+}
+
+/// Sends `stats_email` the counters gained since the previous report, then
+/// keeps the current counters for the next one.
+async fn send_stats(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+) -> Result<LambdaResponse, Error> {
+    let (Some(mail_store), Some(stats_email)) =
+        (mail_store, &email_config.stats_email)
+    else {
+        return Ok(LambdaResponse::new(STATUS_OK, "No store or stats_email"));
+    };
+    let previous: stats::Snapshot =
+        match mail_store.objects.get(stats::SNAPSHOT_KEY).await? {
+            Some(body) => serde_json::from_slice(&body)?,
+            None => Default::default(),
+        };
+    let current = stats::Snapshot {
+        taken_at: quiet::now(),
+        counters: mail_store.records.counters().await?,
+    };
+    let raw = mime::MimeMessage::new()
+        .header("From", &email_config.from_email)
+        .header("To", stats_email)
+        .header("Subject", stats::subject(current.taken_at))
+        .header("Auto-Submitted", "auto-generated")
+        .text(stats::render_text(&previous, &current))
+        .to_bytes();
+    let message_id = forward::send_raw_email(
+        email_sender,
+        dkim,
+        &email_config.from_email,
+        vec![stats_email.to_owned()],
+        raw.into(),
+        email_config.configuration_set.as_deref(),
+        &[],
+    )
+    .await?;
+    mail_store
+        .objects
+        .put(stats::SNAPSHOT_KEY, serde_json::to_vec(&current)?.into())
+        .await?;
+    info!(message_id = message_id.as_str(), "Sent statistics");
+    Ok(LambdaResponse::new(STATUS_OK, &message_id))
+}
+
+/// Deletes the archived and quarantined messages older than
+/// `retention_days`, from the `archive_bucket` and the store.
+async fn purge_expired(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+) -> Result<LambdaResponse, Error> {
+    let Some(days) = email_config.retention_days else {
+        return Ok(LambdaResponse::new(STATUS_OK, "No retention_days"));
+    };
+    let cutoff = retention::cutoff(quiet::now(), days);
+    let mut purge = retention::Purge::default();
+    if let Some(objects) = archive::object_store(email_config) {
+        purge.archived = retention::purge_archive(&objects, cutoff).await?;
+    }
+    if let Some(mail_store) = mail_store {
+        purge.stored = retention::purge_store(mail_store, cutoff).await?;
+    }
+    if let Some(table) = &email_config.index_table {
+        for message_id in purge.message_ids() {
+            index::delete(table, message_id).await?;
+        }
+    }
+    let message = format!(
+        "Deleted {} archive objects and {} stored messages before {}",
+        purge.archived.len(),
+        purge.stored.len(),
+        quiet::timestamp(cutoff)
+    );
+    info!("{}", message);
+    Ok(LambdaResponse::new(STATUS_OK, &message))
+}
+
+/// Deletes the archived, stored and indexed mail of the sender of `request`,
+/// reporting what was deleted.
+async fn erase_sender(
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    request: &erasure::ErasureRequest,
+) -> Result<LambdaResponse, Error> {
+    let sender = request.sender.trim();
+    let mut report =
+        erasure::Erasure { sender: sender.to_owned(), ..Default::default() };
+    if let Some(objects) = archive::object_store(email_config) {
+        report.archived = erasure::erase_archive(&objects, sender).await?;
+    }
+    if let Some(mail_store) = mail_store {
+        report.stored = erasure::erase_store(mail_store, sender).await?;
+    }
+    if let Some(table) = &email_config.index_table {
+        let purge = retention::Purge {
+            archived: report.archived.clone(),
+            stored: report.stored.clone(),
+        };
+        let message_ids =
+            purge.message_ids().into_iter().map(str::to_owned).collect();
+        report.indexed =
+            erasure::erase_index(table, sender, &message_ids).await?;
+    }
+    let message = format!(
+        "Erased {} archive objects, {} stored and {} indexed messages",
+        report.archived.len(),
+        report.stored.len(),
+        report.indexed.len()
+    );
+    info!("{}", message);
+    Ok(LambdaResponse::new(STATUS_OK, &message).with_erasure(report))
+}
+
+/// Searches the `index_table` for `request`, linking each result to its
+/// archived message.
+async fn search_index(
+    email_config: &PrivatEmailConfig,
+    request: &search::SearchRequest,
+) -> Result<LambdaResponse, Error> {
+    let Some(table) = &email_config.index_table else {
+        return Ok(LambdaResponse::new(STATUS_MALFORMED, "No index_table"));
+    };
+    let now = quiet::now();
+    if let Err(e) = request.days(now) {
+        return Ok(LambdaResponse::new(STATUS_MALFORMED, &e));
+    }
+    let mut results = search::search(table, request, now).await?;
+    if let Some(objects) = archive::object_store(email_config) {
+        for result in &mut results {
+            if let Some(key) = &result.entry.archive_key {
+                let link = objects.link(key, search::LINK_EXPIRY).await?;
+                result.link = Some(link);
+            }
+        }
+    }
+    let message = format!("Found {} messages", results.len());
+    Ok(LambdaResponse::new(STATUS_OK, &message).with_results(results))
+}
+
+/// Applies the configured policy to a failed send of `ses_mail`, counting
+/// the failure by class. Only the retry policy fails the invocation.
+async fn handle_send_failure(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    ses_mail: &EmailReceiptNotification,
+    event: &Value,
+    error: Error,
+) -> Result<LambdaResponse, Error> {
+    let class = failure::classify(error.as_ref());
+    let policy = email_config.failure_policy(class);
+    error!(
+        message_id = ses_mail.mail.message_id.as_str(),
+        failure_class = class.as_str(),
+        failure_policy = policy.as_str(),
+        "Error forwarding email: {:?}",
+        error
+    );
+    if let Some(mail_store) = mail_store {
+        if let Err(e) =
+            mail_store.records.increment(&class.counter_key(), 1).await
+        {
+            error!("Error counting failure: {:?}", e);
+        }
+    }
+    if let Some(topic) =
+        email_config.alert_topic_arn.as_ref().filter(|_| class.is_permanent())
+    {
+        let event = alert::FailureEvent {
+            message_id: ses_mail.mail.message_id.clone(),
+            sender: ses_mail.mail.source.clone(),
+            recipients: ses_mail.mail.destination.clone(),
+            subject: ses_mail.mail.common_headers.subject.clone(),
+            failure_class: class,
+            error: error.to_string(),
+            failed_at: quiet::now(),
+        };
+        if let Err(e) = alert::publish(topic, &event).await {
+            error!("Error publishing failure alert: {:?}", e);
+        }
+    }
+
+    let message_id = &ses_mail.mail.message_id;
+    let handled: Result<(), Error> = match policy {
+        FailurePolicy::Retry => return Err(error),
+        FailurePolicy::DeadLetter => match mail_store {
+            Some(mail_store) => deadletter::DeadLetter {
+                message_id: message_id.clone(),
+                failure_class: Some(class),
+                error: error.to_string(),
+                failed_at: quiet::now(),
+                event: event.clone(),
+            }
+            .write(
+                &*mail_store.objects,
+                &email_config.failed_prefix,
+                Some(ses_mail.content.clone()),
+            )
+            .await
+            .map(|_| ()),
+            None => Err("No store configured for dead letters".into()),
+        },
+        FailurePolicy::Alert => {
+            send_notifications(
+                email_config,
+                &notify::Notification {
+                    message_id: message_id.clone(),
+                    sender: ses_mail.mail.source.clone(),
+                    recipients: ses_mail.mail.destination.clone(),
+                    subject: ses_mail.mail.common_headers.subject.clone(),
+                    action: MailAction::Failed,
+                    preview_url: None,
+                    text: None,
+                    thread_count: 1,
+                },
+            )
+            .await;
+            Ok(())
+        }
+        FailurePolicy::Bounce => {
+            bounce(email_sender, dkim, email_config, ses_mail, class).await
+        }
+    };
+
+    match handled {
+        Ok(()) => Ok(LambdaResponse::new(STATUS_SES_FAILURE, class.as_str())),
+        Err(e) => {
+            error!("Error applying {} policy: {:?}", policy.as_str(), e);
+            Err(error)
+        }
+    }
+}
+
+/// Tells the original sender of `ses_mail` that it was not delivered.
+async fn bounce(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    ses_mail: &EmailReceiptNotification,
+    class: failure::FailureClass,
+) -> Result<(), Error> {
+    let headers = &ses_mail.mail.common_headers;
+    let sender = &headers.return_path;
+    if sender.is_empty() || sender.to_lowercase().starts_with("mailer-daemon") {
+        return Err("Not bouncing a message without a sender".into());
+    }
+    if suppressed(email_config, sender).await {
+        return Err(format!("Not bouncing to suppressed {}", sender).into());
+    }
+    let raw = mime::MimeMessage::new()
+        .header("From", &email_config.from_email)
+        .header("To", sender)
+        .header("Subject", format!("Undeliverable: {}", headers.subject))
+        .header("Auto-Submitted", "auto-replied")
+        .text(format!(
+            "Your message \"{}\" could not be delivered ({} failure).\r\n",
+            headers.subject,
+            class.as_str()
+        ))
+        .to_bytes();
+    forward::send_raw_email(
+        email_sender,
+        dkim,
+        &email_config.from_email,
+        vec![sender.to_string()],
+        raw.into(),
+        email_config.configuration_set.as_deref(),
+        &[],
+    )
+    .await?;
+    Ok(())
+}
+
+/// Logs and records the bounce of a sent message, reporting it to
+/// `bounce_notify_email` when set.
+async fn handle_bounce(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    mail_store: Option<&MailStore>,
+    notification: &feedback::BounceNotification,
+) -> Result<LambdaResponse, Error> {
+    let message_id = notification.mail.message_id.as_str();
+    for address in notification.addresses() {
+        warn!(
+            message_id,
+            bounce_type = notification.bounce.bounce_type.as_str(),
+            metric = feedback::BOUNCE_COUNTER,
+            "Message bounced for {}",
+            address
+        );
+        if let Some(mail_store) = mail_store {
+            if let Err(e) =
+                mail_store.records.increment(feedback::BOUNCE_COUNTER, 1).await
+            {
+                error!("Error counting bounce: {:?}", e);
+            }
+        }
+    }
+    if let Some(table) = &email_config.bounce_table {
+        feedback::record(table, notification, quiet::now()).await?;
+    }
+
+    // Never report bounces of the reports themselves
+    let admin = email_config.bounce_notify_email.as_ref().filter(|admin| {
+        !notification.addresses().any(|a| a.eq_ignore_ascii_case(admin))
+    });
+    if let Some(admin) = admin {
+        let raw = mime::MimeMessage::new()
+            .header("From", &email_config.from_email)
+            .header("To", admin)
+            .header("Subject", format!("Bounce: {}", message_id))
+            .header("Auto-Submitted", "auto-generated")
+            .text(notification.report())
+            .to_bytes();
+        forward::send_raw_email(
+            email_sender,
+            dkim,
+            &email_config.from_email,
+            vec![admin.to_owned()],
+            raw.into(),
+            email_config.configuration_set.as_deref(),
+            &[],
+        )
+        .await?;
+    }
+    Ok(LambdaResponse::new(
+        STATUS_OK,
+        &format!("Bounce of {} handled", message_id),
+    ))
+}
+
+/// Sends the mail composed in `request` from one of the `send_as_domains`,
+/// answering with the SES message id.
+async fn send_as_alias(
+    email_sender: &dyn EmailSender,
+    dkim: Option<&DkimSigner>,
+    email_config: &PrivatEmailConfig,
+    request: &outbound::SendRequest,
+) -> Result<LambdaResponse, Error> {
+    let mut problems = request.problems(&email_config.send_as_domains);
+    for address in request.destinations() {
+        if suppressed(email_config, &address).await {
+            problems.push(format!("{} is suppressed", address));
+        }
+    }
+    if !problems.is_empty() {
+        warn!("Refusing send as {}: {:?}", request.from, problems);
+        return Ok(LambdaResponse::new(STATUS_MALFORMED, &problems.join("; ")));
+    }
+    let message_id = forward::send_raw_email(
+        email_sender,
+        dkim,
+        request.source(),
+        request.destinations(),
+        request.build().to_bytes().into(),
+        email_config.configuration_set.as_deref(),
+        &[],
+    )
+    .await;
+    let message_id = match message_id {
+        Ok(message_id) => message_id,
+        Err(e) => {
+            error!("Error sending as {}: {:?}", request.source(), e);
+            return Ok(LambdaResponse::new(STATUS_SES_FAILURE, &e.to_string()));
+        }
+    };
+    info!(message_id = message_id.as_str(), "Sent as {}", request.source());
+    Ok(LambdaResponse::new(STATUS_OK, &message_id)
+        .with_forwarded_message_id(&message_id))
+}
+
+/// Logs the delivery of a sent message with its latency.
+fn handle_delivery(
+    notification: &feedback::DeliveryNotification,
+) -> LambdaResponse {
+    let message_id = notification.mail.message_id.as_str();
+    let delivery = &notification.delivery;
+    info!(
+        message_id,
+        metric = feedback::DELIVERY_METRIC,
+        delivery_ms = delivery.processing_time_millis,
+        "Delivered to {} in {} ms: {}",
+        delivery.recipients.join(", "),
+        delivery.processing_time_millis,
+        delivery.smtp_response
+    );
+    LambdaResponse::new(
+        STATUS_OK,
+        &format!("Delivery of {} handled", message_id),
+    )
+}
+
+/// Warns of the delayed delivery of a sent message.
+fn handle_delivery_delay(
+    notification: &feedback::DeliveryDelayNotification,
+) -> LambdaResponse {
+    let message_id = notification.mail.message_id.as_str();
+    let delay = &notification.delivery_delay;
+    for recipient in &delay.delayed_recipients {
+        warn!(
+            message_id,
+            delay_type = delay.delay_type.as_str(),
+            metric = feedback::DELAY_COUNTER,
+            "Delivery to {} delayed until {} at most: {}",
+            recipient.email_address,
+            delay.expiration_time,
+            recipient.diagnostic_code.as_deref().unwrap_or_default()
+        );
+    }
+    LambdaResponse::new(STATUS_OK, &format!("Delay of {} handled", message_id))
+}
+
+/// Test module for privatemail package
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_runtime::Context;
+    use mailparse::parse_mail;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn read_test_event(file_name: String) -> Value {
+        // Open the file in read-only mode with buffer.
+
+        let mut srcdir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let mut file_dir: String = "tests/payload/".to_owned();
+        file_dir.push_str(file_name.as_str());
+        srcdir.push(file_dir.as_str());
+        println!("Cur Dir: {}", srcdir.display());
+
+        // Read the JSON contents of the file as an instance of `String`.
+        let input_str = fs::read_to_string(srcdir.as_path()).unwrap();
+        trace!("Input str: {}", input_str);
+
+        // Return the `Value`.
+        serde_json::from_str(input_str.as_str()).unwrap()
+    }
+
+    /// Returns a service sending through a [`sender::MockSender`], and the
+    /// sender.
+    fn mock_service(
+        email_config: PrivatEmailConfig,
+        email_sender: sender::MockSender,
+    ) -> (PrivatEmailService, Arc<sender::MockSender>) {
+        let email_sender = Arc::new(email_sender);
+        let service = PrivatEmailService::from_config(email_config)
+            .unwrap()
+            .with_sender(email_sender.clone());
+        (service, email_sender)
+    }
+
+    /// Returns the invocation of `payload`, with a trace id.
+    fn lambda_event(payload: Value) -> LambdaEvent<Value> {
+        let mut context = Context::default();
+        context.xray_trace_id = Some("Root=1-5759e988-bd862e3fe1be46a9".into());
+        LambdaEvent { payload, context }
+    }
+
+    #[tokio::test]
+    async fn handler_with_success() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .build();
+        let (service, email_sender) =
+            mock_service(email_config, sender::MockSender::new());
+        let test_event = read_test_event(String::from("test_event.json"));
+
+        let response = service.handle(lambda_event(test_event)).await.unwrap();
+        assert_eq!(response.status_code(), STATUS_OK);
+        assert_eq!(response.forwarded_message_id(), Some("mock-1"));
+        assert_eq!(response.action(), Some(MailAction::Forwarded));
+        let sent = email_sender.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].source(), Some("test@nyah.dev"));
+        assert_eq!(sent[0].destinations(), ["hello@nyah.dev"]);
+        let mail = parse_mail(sent[0].raw().unwrap()).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Subject").unwrap(),
+            "Testing new forward service"
+        );
+        assert_eq!(
+            mail.headers.get_first_value("Reply-To").unwrap(),
+            "fufu@achu.soup"
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_with_failed_send() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .failure_policy(FailureClass::Permanent, FailurePolicy::Alert)
+            .build();
+        let rejected = || -> Error {
+            Box::new(rusoto_core::RusotoError::Service(
+                rusoto_ses::SendRawEmailError::MessageRejected(
+                    "Email address is not verified.".to_owned(),
+                ),
+            ))
+        };
+        let (service, email_sender) =
+            mock_service(email_config, sender::MockSender::failing(rejected));
+        let event = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .html_body("<p>Hello</p>")
+            .to_sns_event();
+
+        let response = service.handle(lambda_event(event)).await.unwrap();
+        assert_eq!(response.status_code(), STATUS_SES_FAILURE);
+        assert_eq!(response.message(), FailureClass::Permanent.as_str());
+        assert_eq!(email_sender.sent().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn handler_tags_failed_checks() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .loop_detection(false)
+            .spf_policy(VerdictPolicy::Tag)
+            .build();
+        let (service, email_sender) =
+            mock_service(email_config, sender::MockSender::new());
+        let event = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .verdict("spf", "FAIL")
+            .to_sns_event();
+
+        // Formatted forwards can't carry the header, so it is sent raw
+        let response = service.handle(lambda_event(event)).await.unwrap();
+        assert_eq!(response.action(), Some(MailAction::Forwarded));
+        let sent = email_sender.sent();
+        let mail = parse_mail(sent[0].raw().unwrap()).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Subject").unwrap(),
+            "[SPF FAIL] Fufu"
+        );
+        assert_eq!(
+            mail.headers.get_first_value(auth::AUTH_HEADER).unwrap(),
+            "spf=fail"
+        );
+    }
+
+    #[tokio::test]
+    async fn handler_relays_authenticated_replies() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .loop_detection(false)
+            .relay("relay@nyah.dev", "s3cr3t")
+            .build();
+        let address = relay::Relay::from_config(&email_config)
+            .unwrap()
+            .encode("fufu@achu.soup")
+            .unwrap();
+        let reply = EmailReceiptNotification::builder()
+            .from("hello@nyah.dev")
+            .to(address.as_str())
+            .subject("Re: Fufu")
+            .text_body("Hello")
+            .verdict("spf", "FAIL");
+        let signed = reply
+            .clone()
+            .header("DKIM-Signature", "v=1; d=nyah.dev; s=mail; b=abc");
+        for (event, action) in [
+            (reply.clone().verdict("spf", "PASS"), MailAction::Relayed),
+            (reply, MailAction::Blocked),
+            (signed.clone(), MailAction::Relayed),
+            (signed.verdict("dkim", "FAIL"), MailAction::Blocked),
+        ] {
+            let (service, email_sender) =
+                mock_service(email_config.clone(), sender::MockSender::new());
+            let response =
+                service.handle(lambda_event(event.to_sns_event())).await;
+            assert_eq!(response.unwrap().action(), Some(action));
+            let sent = email_sender.sent();
+            assert_eq!(sent.len(), (action == MailAction::Relayed) as usize);
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_routes_only_bounces_to_return_paths() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .loop_detection(false)
+            .srs("nyah.dev", "s3cr3t")
+            .build();
+        let address = srs::Srs::from_config(&email_config)
+            .unwrap()
+            .forward("fufu@achu.soup", srs::today());
+        let report = "From: mailer-daemon@mx.test\r\n\
+                      Content-Type: multipart/report; \
+                      report-type=delivery-status; boundary=b\r\n\r\n\
+                      --b\r\n\r\nUndeliverable\r\n--b--\r\n";
+        for (event, action) in [
+            (
+                EmailReceiptNotification::builder()
+                    .from("mailer-daemon@mx.test")
+                    .to(address.as_str())
+                    .raw(report),
+                MailAction::Relayed,
+            ),
+            (
+                EmailReceiptNotification::builder()
+                    .from("<>")
+                    .to(address.as_str())
+                    .text_body("Undeliverable"),
+                MailAction::Relayed,
+            ),
+            (
+                EmailReceiptNotification::builder()
+                    .from("mallory@evil.test")
+                    .to(address.as_str())
+                    .text_body("Hello"),
+                MailAction::Blocked,
+            ),
+        ] {
+            let (service, email_sender) =
+                mock_service(email_config.clone(), sender::MockSender::new());
+            let response =
+                service.handle(lambda_event(event.to_sns_event())).await;
+            assert_eq!(response.unwrap().action(), Some(action));
+            let sent = email_sender.sent();
+            assert_eq!(sent.len(), (action == MailAction::Relayed) as usize);
+            if let Some(sent) = sent.first() {
+                assert_eq!(sent.destinations(), ["fufu@achu.soup"]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_replays_only_out_of_band() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .build();
+        let (service, email_sender) =
+            mock_service(email_config, sender::MockSender::new());
+        let mut notification = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .to_notification();
+        notification["replay"] = serde_json::json!({ "to": "eru@fufu.soup" });
+        let event = serde_json::json!({ "Records": [notification] });
+
+        service.handle(lambda_event(event.clone())).await.unwrap();
+        assert_eq!(email_sender.sent()[0].destinations(), ["hello@nyah.dev"]);
+        let replay = replay::Replay { to: Some("eru@fufu.soup".to_owned()) };
+        service.handle_replay(lambda_event(event), &replay).await.unwrap();
+        assert_eq!(email_sender.sent()[1].destinations(), ["eru@fufu.soup"]);
+    }
+
+    #[tokio::test]
+    async fn handler_with_dnsbl() {
+        let resolver = dnsbl::StaticResolver::new().with(
+            "achu.soup.dbl.spamhaus.org",
+            std::net::Ipv4Addr::new(127, 0, 1, 2),
+        );
+        let event = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .to_sns_event();
+        for (policy, action) in [
+            (VerdictPolicy::Tag, MailAction::Forwarded),
+            (VerdictPolicy::Drop, MailAction::Blocked),
+        ] {
+            let email_config = PrivatEmailConfig::builder()
+                .from_email("test@nyah.dev")
+                .to_email("hello@nyah.dev")
+                .loop_detection(false)
+                .dnsbl_list("dbl.spamhaus.org:domain".parse().unwrap())
+                .dnsbl_policy(1, policy)
+                .build();
+            let (service, email_sender) =
+                mock_service(email_config, sender::MockSender::new());
+            let service = service.with_resolver(resolver.clone());
+            let response =
+                service.handle(lambda_event(event.clone())).await.unwrap();
+            assert_eq!(response.action(), Some(action));
+            if policy == VerdictPolicy::Drop {
+                assert!(email_sender.sent().is_empty());
+                continue;
+            }
+            let sent = email_sender.sent();
+            let mail = parse_mail(sent[0].raw().unwrap()).unwrap();
+            assert_eq!(
+                mail.headers.get_first_value("Subject").unwrap(),
+                format!("{} Fufu", dnsbl::SUBJECT_TAG)
+            );
+            assert_eq!(
+                mail.headers.get_first_value(dnsbl::DNSBL_HEADER).unwrap(),
+                "dbl.spamhaus.org=achu.soup (127.0.1.2)"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn handler_sends_ses_v2_forwards_through_email_sender() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .ses_v2(limits::SESV2_MAX_SEND_SIZE)
+            .build();
+        let (service, email_sender) =
+            mock_service(email_config, sender::MockSender::new());
+        let event = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .to_sns_event();
+
+        let response = service.handle(lambda_event(event)).await.unwrap();
+        assert_eq!(response.action(), Some(MailAction::Forwarded));
+        let sent = email_sender.sent();
+        assert!(sent[0].raw().is_some());
+    }
+
+    #[tokio::test]
+    async fn handler_near_send_quota() {
+        let dir = env::temp_dir()
+            .join(format!("privatemail-quota-{}", std::process::id()));
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .local_store_dir(&dir)
+            .quota_deferral(90, 600)
+            .build();
+        let quota = quota::SendQuota {
+            max_24_hour_send: 200.0,
+            sent_last_24_hours: 190.0,
+            sending_enabled: true,
+        };
+        let (service, email_sender) = mock_service(
+            email_config,
+            sender::MockSender::new().with_quota(quota),
+        );
+        let event = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .to_sns_event();
+
+        let response = service.handle(lambda_event(event)).await.unwrap();
+        assert_eq!(response.status_code(), STATUS_SKIPPED);
+        assert_eq!(response.action(), Some(MailAction::Deferred));
+        assert!(response.message().starts_with(quiet::DEFERRED_PREFIX));
+        assert!(email_sender.sent().is_empty());
+
+        let scheduled = serde_json::json!({ "source": "aws.events" });
+        let response = service.handle(lambda_event(scheduled)).await.unwrap();
+        assert_eq!(response.status_code(), STATUS_OK);
+        assert!(email_sender.sent().is_empty());
+        let objects = &service.mail_store.as_ref().unwrap().objects;
+        assert_eq!(
+            objects.list(quiet::DEFERRED_PREFIX).await.unwrap().len(),
+            2
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn handler_releases_deferred_to_routed_destination() {
+        let dir = env::temp_dir()
+            .join(format!("privatemail-release-{}", std::process::id()));
+        let base_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .local_store_dir(&dir)
+            .build();
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .sender_route("@achu.soup", "fufu@nyah.dev")
+            .configuration_set("forwards")
+            .local_store_dir(&dir)
+            .quota_deferral(90, 0)
+            .build();
+        let quota = quota::SendQuota {
+            max_24_hour_send: 200.0,
+            sent_last_24_hours: 190.0,
+            sending_enabled: true,
+        };
+        let (service, _) = mock_service(
+            email_config,
+            sender::MockSender::new().with_quota(quota),
+        );
+        let event = EmailReceiptNotification::builder()
+            .from("fufu@achu.soup")
+            .subject("Fufu")
+            .text_body("Hello")
+            .to_sns_event();
+        let response = service.handle(lambda_event(event)).await.unwrap();
+        assert_eq!(response.action(), Some(MailAction::Deferred));
+
+        // Released with the routing resolved when held, not the base one
+        let (service, email_sender) =
+            mock_service(base_config, sender::MockSender::new());
+        let scheduled = serde_json::json!({ "source": "aws.events" });
+        let response = service.handle(lambda_event(scheduled)).await.unwrap();
+        assert_eq!(response.message(), "Released 1 deferred messages");
+        let sent = email_sender.sent();
+        assert_eq!(sent[0].destinations(), ["fufu@nyah.dev"]);
+        let sender::SentEmail::Raw(request) = &sent[0] else {
+            panic!("Deferred forwards are sent raw");
+        };
+        assert_eq!(request.configuration_set_name.as_deref(), Some("forwards"));
+        let objects = &service.mail_store.as_ref().unwrap().objects;
+        assert!(objects.list(quiet::DEFERRED_PREFIX).await.unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_service_config() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .build();
+        let service =
+            PrivatEmailService::from_config(email_config.clone()).unwrap();
+        service.refresh().await;
+        assert_eq!(*service.config(), email_config);
+        assert!(service.mail_store.is_none());
+        assert!(service.clients().classifier.is_none());
+
+        let email_config = PrivatEmailConfig {
+            classifier_model: Some("anthropic.claude-3-haiku".into()),
+            preview_renderer: Some("render-preview".into()),
+            ..email_config
+        };
+        let clients =
+            PrivatEmailService::from_config(email_config).unwrap().clients();
+        assert!(clients.classifier.is_some() && clients.renderer.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_event() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .build();
+        let service = PrivatEmailService::from_config(email_config).unwrap();
+        let mut context = Context::default();
+        context.xray_trace_id = Some("Root=1-5759e988-bd862e3fe1be46a9".into());
+        for payload in [
+            serde_json::json!({ "Records": [{}] }),
+            serde_json::json!({ "Records": [{ "Sns": { "Message": "{" } }] }),
+        ] {
+            let response = service
+                .handle(LambdaEvent { payload, context: context.clone() })
+                .await
+                .unwrap();
+            assert_eq!(response.status_code(), STATUS_MALFORMED);
+        }
+    }
+
+    #[test]
+    fn test_response_body() {
+        let response = LambdaResponse::new(200, "0100017fa1")
+            .with_message_id("0100017fa0")
+            .with_forwarded_message_id("0100017fa1")
+            .with_action(MailAction::Forwarded)
+            .with_processing_time(Duration::from_millis(42));
+        assert_eq!(response.message_id(), Some("0100017fa0"));
+        assert_eq!(response.forwarded_message_id(), Some("0100017fa1"));
+        assert_eq!(response.action(), Some(MailAction::Forwarded));
+        let body: ResponseBody = serde_json::from_str(&response.body).unwrap();
+        assert_eq!(&body, response.body());
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(json["body"].as_str().unwrap())
+                .unwrap(),
+            serde_json::json!({
+                "message": "0100017fa1",
+                "messageId": "0100017fa0",
+                "forwardedMessageId": "0100017fa1",
+                "action": "forwarded",
+                "processingMs": 42,
+            })
+        );
+        assert!(json.get("details").is_none());
+    }
+
+    #[test]
+    fn test_same_sender() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .build();
+        let mut fresh = email_config.clone();
+        fresh.to_email = "other@nyah.dev".to_owned();
+        assert!(same_sender(&email_config, &fresh));
+        fresh.ses_region = Some("eu-west-1".to_owned());
+        assert!(!same_sender(&email_config, &fresh));
+        let mut fresh = email_config.clone();
+        fresh.ses_failover_region = Some("eu-west-1".to_owned());
+        assert!(!same_sender(&email_config, &fresh));
+    }
+
+    #[test]
+    fn test_receipt_failed_checks() {
+        let receipt: Receipt = serde_json::from_value(serde_json::json!({
+            "spamVerdict": { "status": "PASS" },
+            "virusVerdict": { "status": "PASS" },
+            "spfVerdict": { "status": "FAIL" },
+            "dkimVerdict": { "status": "GRAY" },
+            "dmarcVerdict": { "status": "FAIL" },
+        }))
+        .unwrap();
+        assert_eq!(receipt.failed_checks(), [AuthCheck::Spf, AuthCheck::Dmarc]);
+
+        let receipt: Receipt = serde_json::from_value(serde_json::json!({
+            "spamVerdict": { "status": "PASS" },
+            "virusVerdict": { "status": "PASS" },
+        }))
+        .unwrap();
+        assert!(receipt.failed_checks().is_empty());
+    }
+
+    #[test]
+    fn test_rule_message() {
+        let test_event = read_test_event(String::from("test_event.json"));
+        let ses_mail =
+            EmailReceiptNotification::from_event(&test_event).unwrap();
+        let mail = parse_mail(ses_mail.content()).unwrap();
+        let message = ses_mail.rule_message(&mail, 0);
+        assert_eq!(
+            message.senders,
+            ["Mongo Beti <fufu@achu.soup>", "fufu@achu.soup"]
+        );
+        assert_eq!(message.recipients, ["samubu@user.earth"]);
+        assert_eq!(message.verdicts["spam"], "PASS");
+        assert_eq!(message.size, ses_mail.content().len());
+        assert!(message.body.contains("Test again"));
+        assert!(message.body.contains("rest the new email forwarder"));
+
+        let notification: Value = serde_json::from_str(
+            test_event["Records"][0]["Sns"]["Message"].as_str().unwrap(),
+        )
+        .unwrap();
+        let bare = EmailReceiptNotification::from_event(&notification).unwrap();
+        assert_eq!(bare.senders(), ses_mail.senders());
+    }
+
+    #[tokio::test]
+    async fn handler_with_black_listed_email() {
+        let email_config = PrivatEmailConfig::builder()
+            .from_email("test@nyah.dev")
+            .to_email("hello@nyah.dev")
+            .black_list(["achu.soup"])
+            .build();
+        let (service, email_sender) =
+            mock_service(email_config, sender::MockSender::new());
+        let test_event = read_test_event(String::from("test_event.json"));
+
+        let response = service.handle(lambda_event(test_event)).await.unwrap();
+        assert_eq!(response.status_code(), STATUS_SKIPPED);
+        assert_eq!(response.action(), Some(MailAction::Blocked));
+        assert!(email_sender.sent().is_empty());
     }
 }
@@ -0,0 +1,89 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! SES send limits, the maximum size of received content, and the fallback
+//! notice used for messages exceeding them.
+use std::time::Duration;
+
+/// Maximum size of a message accepted by the SES send APIs (10 MB).
+pub const SES_MAX_SEND_SIZE: usize = 10 * 1024 * 1024;
+
+/// Maximum size of a raw message accepted by SESv2 (40 MB).
+pub const SESV2_MAX_SEND_SIZE: usize = 40 * 1024 * 1024;
+
+/// Largest message SES receives (40 MB), the default maximum size of the
+/// content parsed.
+pub const SES_MAX_RECEIVE_SIZE: usize = 40 * 1024 * 1024;
+
+/// Validity of links to stored originals (7 days, the presigning maximum).
+pub const LINK_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Subject prefix of the notice sent instead of an oversized message.
+pub const OVERSIZE_SUBJECT_PREFIX: &str = "[Too large to forward] ";
+
+/// Returns true when a message of `size` bytes cannot be sent under `max`.
+pub fn exceeds_limit(size: usize, max: usize) -> bool {
+    size > max
+}
+
+/// Renders the plain text notice sent in place of an oversized message.
+pub fn oversize_notice(
+    subject: &str,
+    sender: &str,
+    size: usize,
+    link: Option<&str>,
+) -> String {
+    let mut notice = format!(
+        "A message was too large to be forwarded ({} bytes).\n\n\
+         From: {}\n\
+         Subject: {}\n",
+        size, sender, subject
+    );
+    match link {
+        Some(link) => notice.push_str(&format!(
+            "\nThe original message is available at:\n{}\n",
+            link
+        )),
+        None => notice.push_str(
+            "\nNo storage is configured, the original was not kept.\n",
+        ),
+    }
+    notice
+}
+
+/** Test module for SES send limits */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exceeds_limit() {
+        assert!(!exceeds_limit(SES_MAX_SEND_SIZE, SES_MAX_SEND_SIZE));
+        assert!(exceeds_limit(SES_MAX_SEND_SIZE + 1, SES_MAX_SEND_SIZE));
+    }
+
+    #[test]
+    fn test_oversize_notice() {
+        let notice = oversize_notice(
+            "Holiday pictures",
+            "fufu@achu.soup",
+            42,
+            Some("https://bucket.s3.amazonaws.com/oversized/a.eml"),
+        );
+        assert!(notice.contains("From: fufu@achu.soup"));
+        assert!(notice.contains("Subject: Holiday pictures"));
+        assert!(
+            notice.contains("https://bucket.s3.amazonaws.com/oversized/a.eml")
+        );
+
+        let notice =
+            oversize_notice("Holiday pictures", "fufu@achu.soup", 42, None);
+        assert!(notice.contains("original was not kept"));
+    }
+}
@@ -0,0 +1,216 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Local development server.
+//!
+//! `bootstrap --local [address]` serves the handler over HTTP instead of
+//! the Lambda runtime API, so changes are tried without deploying. A JSON
+//! body posted to `/` is handled as the invocation payload: SNS records,
+//! a bare SES notification, an SNS envelope as posted to HTTP
+//! subscriptions, or a scheduled or direct invocation. Requests to other
+//! paths, like `/send`, are handled as from a Function URL. The response
+//! carries the status code and body of the handler response. Point the AWS
+//! calls at local services with `AWS_ENDPOINT_URL`.
+use crate::router::Route;
+use crate::sns;
+use crate::PrivatEmailService;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use lambda_runtime::{Context, Error, LambdaEvent};
+use serde_json::{json, Map, Value};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::LocalSet;
+use tracing::{error, info};
+
+/// Address served when none is given.
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1:9000";
+
+/// Event source of the records of notifications posted to the server.
+pub const EVENT_SOURCE: &str = "privatemail:local";
+
+/// Serves the handler of `service` at `address` until the process ends.
+pub async fn serve(
+    service: PrivatEmailService,
+    address: SocketAddr,
+) -> Result<(), Error> {
+    let service = Arc::new(service);
+    let make_service = make_service_fn(move |_| {
+        let service = service.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let service = service.clone();
+                async move { Ok::<_, Infallible>(handle(&service, request).await) }
+            }))
+        }
+    });
+    info!("Serving the handler on http://{}", address);
+    // Handler futures aren't `Send`, so connections are served on this
+    // thread
+    let server = Server::bind(&address).executor(LocalExec).serve(make_service);
+    LocalSet::new().run_until(server).await?;
+    Ok(())
+}
+
+/// Executor spawning the connection tasks of the server on the local set.
+#[derive(Clone, Copy, Debug)]
+struct LocalExec;
+
+impl<F: Future + 'static> hyper::rt::Executor<F> for LocalExec {
+    fn execute(&self, future: F) {
+        tokio::task::spawn_local(future);
+    }
+}
+
+/// Handles the HTTP `request` with `service`.
+async fn handle(
+    service: &PrivatEmailService,
+    request: Request<Body>,
+) -> Response<Body> {
+    let (parts, body) = request.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return reply(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let path = parts.uri.path();
+    let headers: Map<String, Value> = parts
+        .headers
+        .iter()
+        .filter_map(|(name, value)| {
+            Some((name.to_string(), value.to_str().ok()?.into()))
+        })
+        .collect();
+    let event = match invocation(path, parts.uri.query(), headers, &body) {
+        Ok(event) => event,
+        Err(e) => return reply(StatusCode::BAD_REQUEST, e),
+    };
+
+    let mut context = Context::default();
+    context.request_id = format!("local-{}", crate::quiet::now());
+    context.xray_trace_id = Some(context.request_id.clone());
+    let lambda_event = LambdaEvent::new(event, context);
+    let response = match Route::of(&lambda_event.payload) {
+        Route::Http => service.handle_http(lambda_event).await,
+        _ => service.handle(lambda_event).await,
+    };
+    match response {
+        Ok(response) => {
+            let status = u16::try_from(response.status_code())
+                .ok()
+                .and_then(|status| StatusCode::from_u16(status).ok())
+                .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+            reply(status, serde_json::to_string(response.body()).unwrap())
+        }
+        Err(e) => {
+            error!("Error handling local request: {:?}", e);
+            reply(StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        }
+    }
+}
+
+/// Returns the invocation payload of a request to `path` with the `query`
+/// string, `headers` and `body`.
+pub fn invocation(
+    path: &str,
+    query: Option<&str>,
+    headers: Map<String, Value>,
+    body: &[u8],
+) -> Result<Value, String> {
+    if path != "/" {
+        let url = format!("http://localhost/?{}", query.unwrap_or_default());
+        let url = reqwest::Url::parse(&url).map_err(|e| e.to_string())?;
+        let params: Map<String, Value> = url
+            .query_pairs()
+            .map(|(name, value)| (name.into_owned(), value.into_owned().into()))
+            .collect();
+        return Ok(json!({
+            "rawPath": path,
+            "headers": headers,
+            "queryStringParameters": params,
+            "requestContext": { "http": { "path": path } },
+            // Encoded like the binary bodies of Function URLs, so that
+            // the bytes reach the handler as sent
+            "body": STANDARD.encode(body),
+            "isBase64Encoded": true,
+        }));
+    }
+    let payload: Value = serde_json::from_slice(body)
+        .map_err(|e| format!("Invalid JSON body: {}", e))?;
+    let message = match payload["Type"].as_str() {
+        Some("Notification") => payload["Message"].as_str().map(str::to_owned),
+        _ if sns::is_notification(&payload) => Some(payload.to_string()),
+        _ => None,
+    };
+    Ok(match message {
+        Some(message) => json!({
+            "Records": [{
+                "EventSource": EVENT_SOURCE,
+                "Sns": { "Message": message },
+            }]
+        }),
+        None => payload,
+    })
+}
+
+/// Returns a JSON response with `status` and `body`.
+fn reply(status: StatusCode, body: String) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("content-type", "application/json".parse().unwrap());
+    response
+}
+
+/** Test module for the local development server */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invocation() {
+        let notification = json!({
+            "notificationType": "Received",
+            "mail": { "messageId": "abc" },
+            "receipt": {},
+        });
+        let body = notification.to_string();
+        let event = invocation("/", None, Map::new(), body.as_bytes()).unwrap();
+        assert_eq!(event["Records"][0]["EventSource"], EVENT_SOURCE);
+        assert_eq!(sns::message(&event).unwrap(), notification);
+
+        let envelope = json!({ "Type": "Notification", "Message": body });
+        let envelope = envelope.to_string();
+        let event =
+            invocation("/", None, Map::new(), envelope.as_bytes()).unwrap();
+        assert_eq!(sns::message(&event).unwrap(), notification);
+
+        let digest = invocation("/", None, Map::new(), b"{\"digest\":true}");
+        assert_eq!(Route::of(&digest.unwrap()), Route::Digest);
+        assert!(invocation("/", None, Map::new(), b"mail").is_err());
+
+        let headers = Map::from_iter([("authorization".into(), "t".into())]);
+        let event =
+            invocation("/search", Some("sender=%40bank.com"), headers, b"")
+                .unwrap();
+        assert_eq!(Route::of(&event), Route::Http);
+        assert_eq!(crate::http::path(&event), "/search");
+        assert_eq!(
+            crate::http::query_param(&event, "sender"),
+            Some("@bank.com")
+        );
+
+        let body = b"Subject: Caf\xe9\r\n\r\n\xff";
+        let event = invocation("/inbound", None, Map::new(), body).unwrap();
+        assert_eq!(crate::http::body(&event).unwrap(), body);
+    }
+}
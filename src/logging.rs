@@ -0,0 +1,272 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Log output of the function.
+//!
+//! Logs are written at `AWS_LAMBDA_LOG_LEVEL` or `RUST_LOG`, as one JSON
+//! object per line when `AWS_LAMBDA_LOG_FORMAT` or `LOG_FORMAT` is `json`.
+//! With `REDACT_LOGS` set, every line is redacted before it is written:
+//! email addresses are replaced by a short hash, which still correlates
+//! the lines of a correspondent, and subjects, of `subject` fields and
+//! `Subject` header lines, by [`REDACTED`].
+//! These settings are read from the environment when the function starts,
+//! before the configuration is loaded.
+use lambda_runtime::tracing::subscriber::filter::{EnvFilter, LevelFilter};
+use lambda_runtime::tracing::subscriber::fmt::MakeWriter;
+use lambda_runtime::tracing::{subscriber, Level};
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::io::{self, Write};
+use std::sync::OnceLock;
+
+/// Replacement of redacted subjects.
+pub const REDACTED: &str = "[redacted]";
+
+/// Log settings read from the environment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct LogSettings {
+    /// Lowest level logged
+    pub level: Level,
+
+    /// Write one JSON object per line
+    pub json: bool,
+
+    /// Redact email addresses and subjects
+    pub redact: bool,
+}
+
+impl LogSettings {
+    /// Reads the settings from the environment.
+    pub fn from_env() -> Self {
+        let level = env::var("AWS_LAMBDA_LOG_LEVEL")
+            .or_else(|_| env::var("RUST_LOG"))
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(Level::INFO);
+        let json = ["AWS_LAMBDA_LOG_FORMAT", "LOG_FORMAT"].iter().any(|name| {
+            env::var(name)
+                .is_ok_and(|format| format.eq_ignore_ascii_case("json"))
+        });
+        let redact = env::var("REDACT_LOGS").is_ok_and(|redact| {
+            matches!(redact.to_lowercase().as_str(), "1" | "true" | "yes")
+        });
+        LogSettings { level, json, redact }
+    }
+}
+
+/// Installs the global subscriber writing logs as set in the environment.
+pub fn init() {
+    let settings = LogSettings::from_env();
+    let collector = subscriber::fmt()
+        .with_target(false)
+        .without_time()
+        .with_env_filter(
+            EnvFilter::builder()
+                .with_default_directive(
+                    LevelFilter::from_level(settings.level).into(),
+                )
+                .from_env_lossy(),
+        )
+        .with_writer(Stdout { redact: settings.redact });
+    if settings.json {
+        collector.json().init()
+    } else {
+        collector.init()
+    }
+}
+
+/// Returns `line` with its email addresses hashed and its subjects
+/// redacted, whether formatted as text, JSON or debug output, or found in
+/// the `Subject` header of a raw message.
+pub fn redact(line: &str) -> String {
+    static SUBJECT: OnceLock<Regex> = OnceLock::new();
+    static LINE_END: OnceLock<Regex> = OnceLock::new();
+    static ADDRESS: OnceLock<Regex> = OnceLock::new();
+    let subject = SUBJECT.get_or_init(|| {
+        Regex::new(concat!(
+            // Header lines, their line breaks possibly escaped
+            r#"(?i)(?P<header>(?:^|[\r\n"]|\\+[rn])subject:[ \t]*)"#,
+            // JSON, serde_json and SES header objects, Debug fields, text
+            r#"|(?:\\*"subject\\*"\s*:\s*(?:String\(\s*)?"#,
+            r#"|\\*"name\\*"\s*:\s*(?:String\()?\\*"subject\\*"\)?,\s*"#,
+            r#"\\*"value\\*"\s*:\s*(?:String\(\s*)?"#,
+            r#"|\bsubject:\s*(?:Some\(\s*)?"#,
+            r#"|\bsubject\s*=\s*)(?P<quote>\\*")?"#,
+        ))
+        .unwrap()
+    });
+    let line_end = LINE_END
+        .get_or_init(|| Regex::new(r"\r?\n|\r|\\+r\\+n|\\+[rn]|$").unwrap());
+    let address = ADDRESS.get_or_init(|| {
+        Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)+")
+            .unwrap()
+    });
+
+    let mut redacted = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(captures) = subject.captures(rest) {
+        let key = captures.get(0).unwrap();
+        redacted.push_str(&rest[..key.end()]);
+        let value = &rest[key.end()..];
+        let end = match (captures.name("header"), captures.name("quote")) {
+            // The value ends with the line, unless folded onto the next
+            (Some(_), _) => {
+                let mut from = 0;
+                loop {
+                    let end = line_end.find_at(value, from).unwrap();
+                    match value[end.end()..].starts_with([' ', '\t']) {
+                        true if !end.is_empty() => from = end.end(),
+                        _ => break end.start(),
+                    }
+                }
+            }
+            // The value ends at the same quote, not preceded by an escape
+            (None, Some(quote)) => {
+                let quote = quote.as_str();
+                let mut end = value.len();
+                let mut from = 0;
+                while let Some(at) = value[from..].find(quote) {
+                    let at = from + at;
+                    if !value[..at].ends_with('\\') {
+                        end = at;
+                        break;
+                    }
+                    from = at + quote.len();
+                }
+                end
+            }
+            (None, None) => {
+                value.find(char::is_whitespace).unwrap_or(value.len())
+            }
+        };
+        redacted.push_str(REDACTED);
+        rest = &value[end..];
+    }
+    redacted.push_str(rest);
+
+    address
+        .replace_all(&redacted, |captures: &regex::Captures| {
+            let hash = Sha256::digest(captures[0].to_lowercase());
+            format!("[email:{}]", &hex::encode(hash)[..8])
+        })
+        .into_owned()
+}
+
+/// Makes the writers of log lines to stdout.
+struct Stdout {
+    redact: bool,
+}
+
+impl<'a> MakeWriter<'a> for Stdout {
+    type Writer = LineWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LineWriter { redact: self.redact, line: Vec::new() }
+    }
+}
+
+/// Buffers a log line, written to stdout when dropped.
+struct LineWriter {
+    redact: bool,
+    line: Vec<u8>,
+}
+
+impl Write for LineWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LineWriter {
+    fn drop(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let line = match self.redact {
+            true => redact(&line),
+            false => line.into_owned(),
+        };
+        let _ = io::stdout().lock().write_all(line.as_bytes());
+    }
+}
+
+/** Test module for log output */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let hashed = redact("Forwarding for Hello@Nyah.dev");
+        assert!(hashed.starts_with("Forwarding for [email:"));
+        assert_eq!(hashed, redact("Forwarding for hello@nyah.dev"));
+        assert_eq!(
+            redact(
+                r#"{"fields":{"subject":"Re: \"Fufu\" and eru@fufu.soup"}}"#
+            ),
+            r#"{"fields":{"subject":"[redacted]"}}"#
+        );
+        assert_eq!(
+            redact(r#"Raw: {"subject": String("Fufu"), "id": "a"}"#),
+            r#"Raw: {"subject": String("[redacted]"), "id": "a"}"#
+        );
+        assert_eq!(
+            redact(r#"{"message":"{\"subject\":\"Fufu \\\"corn\\\"\"}"}"#),
+            r#"{"message":"{\"subject\":\"[redacted]\"}"}"#
+        );
+        assert_eq!(
+            redact("Blocked subject=\"Fufu corn\" rule=x"),
+            "Blocked subject=\"[redacted]\" rule=x"
+        );
+        assert_eq!(redact("subject=Fufu rule=x"), "subject=[redacted] rule=x");
+    }
+
+    #[test]
+    fn test_redact_debug() {
+        assert_eq!(
+            redact("Rule { subject: \"Fufu corn\", action: Drop }"),
+            "Rule { subject: \"[redacted]\", action: Drop }"
+        );
+        assert_eq!(
+            redact("CommonHeaders {\n    subject: Some(\n        \"Fufu\",\n"),
+            "CommonHeaders {\n    subject: Some(\n        \"[redacted]\",\n"
+        );
+        assert_eq!(
+            redact(r#"{"name": String("Subject"), "value": String("Fufu")}"#),
+            r#"{"name": String("Subject"), "value": String("[redacted]")}"#
+        );
+    }
+
+    #[test]
+    fn test_redact_header_lines() {
+        assert_eq!(
+            redact("From: a\r\nSubject: Fufu corn\r\nTo: b\r\n"),
+            "From: a\r\nSubject: [redacted]\r\nTo: b\r\n"
+        );
+        assert_eq!(
+            redact("subject: Fufu\n\tcorn\nTo: b"),
+            "subject: [redacted]\nTo: b"
+        );
+        // The raw message in the dump of an event, as text and as JSON
+        assert_eq!(
+            redact(r#""content": String("Subject: Fufu \"corn\"\r\nTo: b")"#),
+            r#""content": String("Subject: [redacted]\r\nTo: b")"#
+        );
+        assert_eq!(
+            redact(
+                r#"{"message":"Event: \"From: a\\r\\nSubject: Fufu\\r\\n\""}"#
+            ),
+            r#"{"message":"Event: \"From: a\\r\\nSubject: [redacted]\\r\\n\""}"#
+        );
+    }
+}
@@ -10,12 +10,43 @@
 //! Authors:
 //! - Nyah Check <hello@nyah.dev>
 
-use lambda_runtime::{service_fn, Error};
-use lib::privatemail_handler;
+use lambda_runtime::{service_fn, Error, LambdaEvent};
+use lib::router::Route;
+use lib::{local, PrivatEmailService};
+use serde_json::Value;
+use std::env;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    let privatemail_handler = service_fn(privatemail_handler);
+    // Log at AWS_LAMBDA_LOG_LEVEL or RUST_LOG, as JSON when the function
+    // is configured so, redacting addresses and subjects with REDACT_LOGS
+    lib::logging::init();
+
+    // Fail the init, rather than the first message, on a bad configuration
+    let service = PrivatEmailService::new().await?;
+    let email_config = service.config();
+    if email_config.validate_resources {
+        email_config.check_resources().await?;
+    }
+
+    // `--local [address]` serves the handler over HTTP for development
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--local") {
+        let address =
+            args.get(1).map_or(local::DEFAULT_ADDRESS, String::as_str);
+        return local::serve(service, address.parse()?).await;
+    }
+
+    // Share the clients and configuration across invocations, routing
+    // HTTP requests apart from SNS and scheduled events
+    let service = &service;
+    let privatemail_handler =
+        service_fn(move |event: LambdaEvent<Value>| async move {
+            match Route::of(&event.payload) {
+                Route::Http => service.handle_http(event).await,
+                _ => service.handle(event).await,
+            }
+        });
     lambda_runtime::run(privatemail_handler).await?;
     Ok(())
 }
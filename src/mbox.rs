@@ -0,0 +1,164 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Export of archived messages as an mbox file.
+//!
+//! Messages are written in the `mboxrd` format read by Thunderbird and most
+//! mail clients: each starts with a `From sender date` separator line, its
+//! lines ending in LF, and lines starting with any number of `>` followed
+//! by `From ` are quoted with one more `>`.
+use crate::archive::{self, Metadata};
+use crate::quiet;
+use crate::store::ObjectStore;
+use lambda_runtime::Error;
+use std::io::Write;
+
+/// Sender of the separator line of messages without envelope sender.
+const UNKNOWN_SENDER: &str = "MAILER-DAEMON";
+
+/// Writes the message `raw`, sent by `sender` at `secs` since the Unix
+/// epoch, to `out` in the `mboxrd` format.
+pub fn write_message(
+    out: &mut impl Write,
+    sender: &str,
+    secs: i64,
+    raw: &[u8],
+) -> Result<(), Error> {
+    let sender: String =
+        sender.chars().filter(|c| !c.is_whitespace()).collect();
+    let sender = match sender.is_empty() {
+        true => UNKNOWN_SENDER,
+        false => sender.as_str(),
+    };
+    writeln!(out, "From {} {}", sender, asctime(secs))?;
+    for line in raw.split_inclusive(|&b| b == b'\n') {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let unquoted = line.iter().position(|&b| b != b'>').unwrap_or(0);
+        if line[unquoted..].starts_with(b"From ") {
+            out.write_all(b">")?;
+        }
+        out.write_all(line)?;
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Writes the messages archived in `objects` on the days from `from` to
+/// `until`, in seconds since the Unix epoch, to `out`. Returns the number
+/// of messages written.
+pub async fn export(
+    objects: &dyn ObjectStore,
+    from: i64,
+    until: i64,
+    out: &mut impl Write,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    let mut day = from - from.rem_euclid(86_400);
+    while day <= until {
+        let prefix = archive::key("", &quiet::timestamp(day));
+        for key in objects.list(&prefix).await? {
+            let Some(stem) = key.strip_suffix(".eml") else {
+                continue;
+            };
+            let Some(raw) = objects.get(&key).await? else {
+                continue;
+            };
+            let metadata: Metadata =
+                match objects.get(&format!("{}.json", stem)).await? {
+                    Some(json) => serde_json::from_slice(&json)?,
+                    None => Metadata::default(),
+                };
+            let secs =
+                quiet::parse_timestamp(&metadata.timestamp).unwrap_or(day);
+            write_message(out, &metadata.source, secs, &raw)?;
+            count += 1;
+        }
+        day += 86_400;
+    }
+    Ok(count)
+}
+
+/// Returns `secs` since the Unix epoch in the format of C `asctime`, as in
+/// `Sat Mar 19 08:46:16 2022`.
+fn asctime(secs: i64) -> String {
+    const WEEKDAYS: [&str; 7] =
+        ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+        "Nov", "Dec",
+    ];
+    let timestamp = quiet::timestamp(secs);
+    let month: usize = timestamp[5..7].parse().unwrap_or(1);
+    // The epoch was a Thursday
+    let weekday = (secs.div_euclid(86_400) + 4).rem_euclid(7) as usize;
+    format!(
+        "{} {} {:>2} {} {}",
+        WEEKDAYS[weekday],
+        MONTHS[month - 1],
+        timestamp[8..10].trim_start_matches('0'),
+        &timestamp[11..19],
+        &timestamp[..4]
+    )
+}
+
+/** Test module for mbox export */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::fs::FsObjectStore;
+
+    #[test]
+    fn test_write_message() {
+        assert_eq!(asctime(1647679576), "Sat Mar 19 08:46:16 2022");
+        assert_eq!(asctime(0), "Thu Jan  1 00:00:00 1970");
+
+        let mut out = Vec::new();
+        let raw = b"Subject: Fufu\r\n\r\nFrom here\r\n>From there\r\nbye";
+        write_message(&mut out, "eru@fufu.soup", 1647679576, raw).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "From eru@fufu.soup Sat Mar 19 08:46:16 2022\n\
+             Subject: Fufu\n\n>From here\n>>From there\nbye\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export() {
+        let dir = std::env::temp_dir()
+            .join(format!("privatemail-mbox-{}", std::process::id()));
+        let objects = FsObjectStore::new(&dir).unwrap();
+        for (id, timestamp) in [
+            ("a", "2022-03-18T23:59:59.000Z"),
+            ("b", "2022-03-19T08:46:16.420Z"),
+            ("c", "2022-03-20T00:00:00.000Z"),
+        ] {
+            let metadata = Metadata {
+                message_id: id.to_owned(),
+                timestamp: timestamp.to_owned(),
+                source: "eru@fufu.soup".to_owned(),
+                ..Default::default()
+            };
+            archive::write(
+                &objects,
+                metadata,
+                "Subject: Fufu\r\n\r\nHi".into(),
+            )
+            .await
+            .unwrap();
+        }
+        let (from, until) = (1647648000, 1647648000 + 86_399);
+        let mut out = Vec::new();
+        assert_eq!(export(&objects, from, until, &mut out).await.unwrap(), 1);
+        let mbox = String::from_utf8(out).unwrap();
+        assert!(mbox.starts_with("From eru@fufu.soup Sat Mar 19 08:46:16"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
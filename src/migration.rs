@@ -0,0 +1,642 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Transitional dual-write mode for the rusoto to aws-sdk migration.
+//!
+//! Every outgoing `SendEmailRequest`, and `SendRawEmailRequest` of raw
+//! forwards, can also be built for the aws-sdk client (`aws-sdk`
+//! feature). Both requests are reduced to a [`SendShape`], or a
+//! [`RawShape`], and any structural difference is logged, while the mail
+//! is only sent through the configured primary backend. Once the logs show
+//! parity, `SES_PRIMARY=aws-sdk` flips the primary.
+//!
+//! The service sends through the [`EmailSender`] returned by [`sender`],
+//! which wraps the rusoto client in a `MigrationSender` when either mode
+//! is enabled.
+use crate::config::PrivatEmailConfig;
+#[cfg(feature = "aws-sdk")]
+use crate::quota::SendQuota;
+use crate::sender::EmailSender;
+#[cfg(feature = "aws-sdk")]
+use async_trait::async_trait;
+#[cfg(feature = "aws-sdk")]
+use lambda_runtime::Error;
+use rusoto_ses::{SendEmailRequest, SendRawEmailRequest};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+/// SES client library used to send mail.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum SesBackend {
+    /// `rusoto_ses`
+    #[default]
+    Rusoto,
+
+    /// `aws-sdk-ses`, requires the `aws-sdk` feature
+    AwsSdk,
+}
+
+impl std::str::FromStr for SesBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "rusoto" => Ok(SesBackend::Rusoto),
+            "aws-sdk" => Ok(SesBackend::AwsSdk),
+            other => Err(format!("Invalid SES backend: {}", other)),
+        }
+    }
+}
+
+/// Client independent view of a `SendEmail` request used for diffing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SendShape {
+    /// Envelope sender
+    pub source: String,
+
+    /// `To` recipients
+    pub to: Vec<String>,
+
+    /// `Cc` recipients
+    pub cc: Vec<String>,
+
+    /// `Bcc` recipients
+    pub bcc: Vec<String>,
+
+    /// `Reply-To` addresses
+    pub reply_to: Vec<String>,
+
+    /// Address bounces are returned to
+    pub return_path: Option<String>,
+
+    /// SES configuration set of the send
+    pub configuration_set: Option<String>,
+
+    /// Subject of the message
+    pub subject: String,
+
+    /// HTML body
+    pub html: Option<String>,
+
+    /// Plain text body
+    pub text: Option<String>,
+
+    /// SES message tags, as name and value
+    pub tags: Vec<(String, String)>,
+}
+
+impl SendShape {
+    /// Reduces a rusoto request to its shape.
+    pub fn from_rusoto(request: &SendEmailRequest) -> Self {
+        let destination = &request.destination;
+        let body = &request.message.body;
+        SendShape {
+            source: request.source.clone(),
+            to: destination.to_addresses.clone().unwrap_or_default(),
+            cc: destination.cc_addresses.clone().unwrap_or_default(),
+            bcc: destination.bcc_addresses.clone().unwrap_or_default(),
+            reply_to: request.reply_to_addresses.clone().unwrap_or_default(),
+            return_path: request.return_path.clone(),
+            configuration_set: request.configuration_set_name.clone(),
+            subject: request.message.subject.data.clone(),
+            html: body.html.as_ref().map(|c| c.data.clone()),
+            text: body.text.as_ref().map(|c| c.data.clone()),
+            tags: request
+                .tags
+                .iter()
+                .flatten()
+                .map(|t| (t.name.clone(), t.value.clone()))
+                .collect(),
+        }
+    }
+
+    /// Returns the names of the fields differing between both shapes.
+    pub fn diff(&self, other: &SendShape) -> Vec<&'static str> {
+        let fields = [
+            ("source", self.source == other.source),
+            ("to", self.to == other.to),
+            ("cc", self.cc == other.cc),
+            ("bcc", self.bcc == other.bcc),
+            ("reply_to", self.reply_to == other.reply_to),
+            ("return_path", self.return_path == other.return_path),
+            (
+                "configuration_set",
+                self.configuration_set == other.configuration_set,
+            ),
+            ("subject", self.subject == other.subject),
+            ("html", self.html == other.html),
+            ("text", self.text == other.text),
+            ("tags", self.tags == other.tags),
+        ];
+        fields.iter().filter(|(_, same)| !same).map(|(name, _)| *name).collect()
+    }
+}
+
+/// Client independent view of a `SendRawEmail` request used for diffing.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RawShape {
+    /// Envelope sender
+    pub source: Option<String>,
+
+    /// Envelope recipients
+    pub destinations: Vec<String>,
+
+    /// SHA-256 of the raw message, hex encoded
+    pub sha256: String,
+
+    /// SES configuration set of the send
+    pub configuration_set: Option<String>,
+
+    /// SES message tags, as name and value
+    pub tags: Vec<(String, String)>,
+}
+
+impl RawShape {
+    /// Returns the shape of a send of `data`, digested rather than copied.
+    pub fn new(
+        source: Option<String>,
+        destinations: Vec<String>,
+        data: &[u8],
+        configuration_set: Option<String>,
+        tags: Vec<(String, String)>,
+    ) -> Self {
+        RawShape {
+            source,
+            destinations,
+            sha256: hex::encode(Sha256::digest(data)),
+            configuration_set,
+            tags,
+        }
+    }
+
+    /// Reduces a rusoto request to its shape.
+    pub fn from_rusoto(request: &SendRawEmailRequest) -> Self {
+        RawShape::new(
+            request.source.clone(),
+            request.destinations.clone().unwrap_or_default(),
+            &request.raw_message.data,
+            request.configuration_set_name.clone(),
+            request
+                .tags
+                .iter()
+                .flatten()
+                .map(|t| (t.name.clone(), t.value.clone()))
+                .collect(),
+        )
+    }
+
+    /// Returns the names of the fields differing between both shapes.
+    pub fn diff(&self, other: &RawShape) -> Vec<&'static str> {
+        let fields = [
+            ("source", self.source == other.source),
+            ("destinations", self.destinations == other.destinations),
+            ("raw_message", self.sha256 == other.sha256),
+            (
+                "configuration_set",
+                self.configuration_set == other.configuration_set,
+            ),
+            ("tags", self.tags == other.tags),
+        ];
+        fields.iter().filter(|(_, same)| !same).map(|(name, _)| *name).collect()
+    }
+}
+
+/// Logs the fields differing between the rusoto and aws-sdk requests.
+#[cfg(feature = "aws-sdk")]
+fn log_diff(diff: &[&str]) {
+    if diff.is_empty() {
+        tracing::trace!("rusoto and aws-sdk requests match");
+    } else {
+        warn!(fields = ?diff, "rusoto and aws-sdk requests differ");
+    }
+}
+
+/// aws-sdk counterpart of the rusoto send path.
+#[cfg(feature = "aws-sdk")]
+pub mod sdk {
+    use super::{RawShape, SendShape};
+    use aws_sdk_ses::operation::send_email::SendEmailInput;
+    use aws_sdk_ses::operation::send_raw_email::SendRawEmailInput;
+    use aws_sdk_ses::primitives::Blob;
+    use aws_sdk_ses::types::{
+        Body, Content, Destination, Message, MessageTag, RawMessage,
+    };
+    use lambda_runtime::Error;
+    use rusoto_ses::{SendEmailRequest, SendRawEmailRequest};
+    use tokio::sync::OnceCell;
+
+    fn content(data: &str, charset: &Option<String>) -> Result<Content, Error> {
+        Ok(Content::builder()
+            .data(data)
+            .set_charset(charset.clone())
+            .build()?)
+    }
+
+    /// Builds the aws-sdk input equivalent to a rusoto request.
+    pub fn input(request: &SendEmailRequest) -> Result<SendEmailInput, Error> {
+        let destination = &request.destination;
+        let body = &request.message.body;
+        let subject = &request.message.subject;
+        let message = Message::builder()
+            .subject(content(&subject.data, &subject.charset)?)
+            .body(
+                Body::builder()
+                    .set_html(
+                        body.html
+                            .as_ref()
+                            .map(|c| content(&c.data, &c.charset))
+                            .transpose()?,
+                    )
+                    .set_text(
+                        body.text
+                            .as_ref()
+                            .map(|c| content(&c.data, &c.charset))
+                            .transpose()?,
+                    )
+                    .build(),
+            )
+            .build();
+        let tags = request
+            .tags
+            .iter()
+            .flatten()
+            .map(|t| {
+                MessageTag::builder().name(&t.name).value(&t.value).build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(SendEmailInput::builder()
+            .source(&request.source)
+            .destination(
+                Destination::builder()
+                    .set_to_addresses(destination.to_addresses.clone())
+                    .set_cc_addresses(destination.cc_addresses.clone())
+                    .set_bcc_addresses(destination.bcc_addresses.clone())
+                    .build(),
+            )
+            .message(message)
+            .set_reply_to_addresses(request.reply_to_addresses.clone())
+            .set_return_path(request.return_path.clone())
+            .set_source_arn(request.source_arn.clone())
+            .set_return_path_arn(request.return_path_arn.clone())
+            .set_configuration_set_name(request.configuration_set_name.clone())
+            .set_tags(if tags.is_empty() { None } else { Some(tags) })
+            .build()?)
+    }
+
+    /// Reduces an aws-sdk input to its shape.
+    pub fn shape(input: &SendEmailInput) -> SendShape {
+        let destination = input.destination();
+        let message = input.message();
+        SendShape {
+            source: input.source().unwrap_or_default().to_owned(),
+            to: destination
+                .map(|d| d.to_addresses().to_vec())
+                .unwrap_or_default(),
+            cc: destination
+                .map(|d| d.cc_addresses().to_vec())
+                .unwrap_or_default(),
+            bcc: destination
+                .map(|d| d.bcc_addresses().to_vec())
+                .unwrap_or_default(),
+            reply_to: input.reply_to_addresses().to_vec(),
+            return_path: input.return_path().map(|s| s.to_owned()),
+            configuration_set: input
+                .configuration_set_name()
+                .map(|s| s.to_owned()),
+            subject: message
+                .and_then(|m| m.subject())
+                .map(|c| c.data().to_owned())
+                .unwrap_or_default(),
+            html: message
+                .and_then(|m| m.body())
+                .and_then(|b| b.html())
+                .map(|c| c.data().to_owned()),
+            text: message
+                .and_then(|m| m.body())
+                .and_then(|b| b.text())
+                .map(|c| c.data().to_owned()),
+            tags: input
+                .tags()
+                .iter()
+                .map(|t| (t.name().to_owned(), t.value().to_owned()))
+                .collect(),
+        }
+    }
+
+    /// Builds the aws-sdk input equivalent to a rusoto raw request.
+    pub fn raw_input(
+        request: &SendRawEmailRequest,
+    ) -> Result<SendRawEmailInput, Error> {
+        let tags = request
+            .tags
+            .iter()
+            .flatten()
+            .map(|t| {
+                MessageTag::builder().name(&t.name).value(&t.value).build()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let message = RawMessage::builder()
+            .data(Blob::new(request.raw_message.data.to_vec()))
+            .build()?;
+        Ok(SendRawEmailInput::builder()
+            .set_source(request.source.clone())
+            .set_destinations(request.destinations.clone())
+            .raw_message(message)
+            .set_from_arn(request.from_arn.clone())
+            .set_source_arn(request.source_arn.clone())
+            .set_return_path_arn(request.return_path_arn.clone())
+            .set_configuration_set_name(request.configuration_set_name.clone())
+            .set_tags(if tags.is_empty() { None } else { Some(tags) })
+            .build()?)
+    }
+
+    /// Reduces an aws-sdk raw input to its shape.
+    pub fn raw_shape(input: &SendRawEmailInput) -> RawShape {
+        RawShape::new(
+            input.source().map(|s| s.to_owned()),
+            input.destinations().to_vec(),
+            input.raw_message().map(|m| m.data().as_ref()).unwrap_or_default(),
+            input.configuration_set_name().map(|s| s.to_owned()),
+            input
+                .tags()
+                .iter()
+                .map(|t| (t.name().to_owned(), t.value().to_owned()))
+                .collect(),
+        )
+    }
+
+    /// aws-sdk SES client of a region, kept for the life of the service.
+    #[derive(Debug)]
+    pub struct SdkClient {
+        region: Option<String>,
+        client: OnceCell<aws_sdk_ses::Client>,
+    }
+
+    impl SdkClient {
+        /// Creates the client of `region`, or of the environment. Loading
+        /// the configuration is asynchronous, so it happens on the first
+        /// send, once.
+        pub fn new(region: Option<String>) -> Self {
+            SdkClient { region, client: OnceCell::new() }
+        }
+
+        async fn client(&self) -> &aws_sdk_ses::Client {
+            self.client
+                .get_or_init(|| async {
+                    let mut loader = aws_config::from_env();
+                    if let Some(region) = &self.region {
+                        loader = loader
+                            .region(aws_config::Region::new(region.clone()));
+                    }
+                    aws_sdk_ses::Client::new(&loader.load().await)
+                })
+                .await
+        }
+
+        /// Sends `input`, returning the SES message id.
+        pub async fn send(
+            &self,
+            input: SendEmailInput,
+        ) -> Result<String, Error> {
+            let output = self
+                .client()
+                .await
+                .send_email()
+                .set_source(input.source)
+                .set_destination(input.destination)
+                .set_message(input.message)
+                .set_reply_to_addresses(input.reply_to_addresses)
+                .set_return_path(input.return_path)
+                .set_source_arn(input.source_arn)
+                .set_return_path_arn(input.return_path_arn)
+                .set_configuration_set_name(input.configuration_set_name)
+                .set_tags(input.tags)
+                .send()
+                .await?;
+            Ok(output.message_id().to_owned())
+        }
+
+        /// Sends the raw `input`, returning the SES message id.
+        pub async fn send_raw(
+            &self,
+            input: SendRawEmailInput,
+        ) -> Result<String, Error> {
+            let output = self
+                .client()
+                .await
+                .send_raw_email()
+                .set_source(input.source)
+                .set_destinations(input.destinations)
+                .set_raw_message(input.raw_message)
+                .set_from_arn(input.from_arn)
+                .set_source_arn(input.source_arn)
+                .set_return_path_arn(input.return_path_arn)
+                .set_configuration_set_name(input.configuration_set_name)
+                .set_tags(input.tags)
+                .send()
+                .await?;
+            Ok(output.message_id().to_owned())
+        }
+    }
+}
+
+/// [`EmailSender`] sending through the primary backend of the
+/// configuration, the rusoto one being `rusoto`. In dual-write mode the
+/// request for the other backend is built too and structural differences
+/// are logged.
+#[cfg(feature = "aws-sdk")]
+pub struct MigrationSender {
+    rusoto: Box<dyn EmailSender>,
+    sdk: sdk::SdkClient,
+    primary: SesBackend,
+}
+
+#[cfg(feature = "aws-sdk")]
+#[async_trait]
+impl EmailSender for MigrationSender {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        let input = sdk::raw_input(&request)?;
+        log_diff(
+            &RawShape::from_rusoto(&request).diff(&sdk::raw_shape(&input)),
+        );
+        match self.primary {
+            SesBackend::AwsSdk => self.sdk.send_raw(input).await,
+            SesBackend::Rusoto => self.rusoto.send_raw_email(request).await,
+        }
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        let input = sdk::input(&request)?;
+        log_diff(&SendShape::from_rusoto(&request).diff(&sdk::shape(&input)));
+        match self.primary {
+            SesBackend::AwsSdk => self.sdk.send(input).await,
+            SesBackend::Rusoto => self.rusoto.send_email(request).await,
+        }
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        self.rusoto.send_quota().await
+    }
+}
+
+/// Returns the sender of `config` sending through `rusoto`, the rusoto
+/// client of `region`, or through the aws-sdk client of that region when
+/// it is the primary backend.
+pub fn sender(
+    rusoto: impl EmailSender + 'static,
+    config: &PrivatEmailConfig,
+    region: Option<String>,
+) -> Box<dyn EmailSender> {
+    if !config.ses_dual_write && config.ses_primary == SesBackend::Rusoto {
+        return Box::new(rusoto);
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    {
+        Box::new(MigrationSender {
+            rusoto: Box::new(rusoto),
+            sdk: sdk::SdkClient::new(region),
+            primary: config.ses_primary,
+        })
+    }
+
+    #[cfg(not(feature = "aws-sdk"))]
+    {
+        let _ = region;
+        warn!("Built without the aws-sdk feature, sending through rusoto");
+        Box::new(rusoto)
+    }
+}
+
+/** Test module for the dual-write migration mode */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_ses::{
+        Body, Content, Destination, Message, MessageTag, RawMessage,
+    };
+
+    fn request() -> SendEmailRequest {
+        SendEmailRequest {
+            destination: Destination {
+                to_addresses: Some(vec!["hello@nyah.dev".to_owned()]),
+                ..Default::default()
+            },
+            message: Message {
+                body: Body {
+                    html: Some(Content {
+                        charset: None,
+                        data: "<p>hi</p>".to_owned(),
+                    }),
+                    text: None,
+                },
+                subject: Content { charset: None, data: "hi".to_owned() },
+            },
+            reply_to_addresses: Some(vec!["fufu@achu.soup".to_owned()]),
+            source: "test@nyah.dev".to_owned(),
+            tags: Some(vec![MessageTag {
+                name: "alias".to_owned(),
+                value: "hello".to_owned(),
+            }]),
+            ..Default::default()
+        }
+    }
+
+    fn raw_request() -> SendRawEmailRequest {
+        SendRawEmailRequest {
+            destinations: Some(vec!["hello@nyah.dev".to_owned()]),
+            raw_message: RawMessage {
+                data: "Subject: hi\r\n\r\nhi\r\n".into(),
+            },
+            source: Some("test@nyah.dev".to_owned()),
+            tags: request().tags,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_shape_diff() {
+        let shape = SendShape::from_rusoto(&request());
+        assert!(shape.diff(&shape.clone()).is_empty());
+
+        let mut other = shape.clone();
+        other.subject = "hello".to_owned();
+        other.reply_to.clear();
+        assert_eq!(shape.diff(&other), ["reply_to", "subject"]);
+    }
+
+    #[test]
+    fn test_raw_shape_diff() {
+        let shape = RawShape::from_rusoto(&raw_request());
+        assert!(shape.diff(&shape.clone()).is_empty());
+
+        let mut request = raw_request();
+        request.raw_message.data = "Subject: hello\r\n\r\nhi\r\n".into();
+        let other = RawShape::from_rusoto(&request);
+        assert_eq!(shape.diff(&other), ["raw_message"]);
+    }
+
+    #[test]
+    fn test_ses_backend_from_str() {
+        assert_eq!("aws-sdk".parse(), Ok(SesBackend::AwsSdk));
+        assert_eq!("Rusoto".parse(), Ok(SesBackend::Rusoto));
+        assert!("smtp".parse::<SesBackend>().is_err());
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    #[tokio::test]
+    async fn test_dual_write_sends_through_rusoto() {
+        let rusoto = std::sync::Arc::new(crate::sender::MockSender::new());
+        let config = PrivatEmailConfig::builder().ses_dual_write(true).build();
+        let email_sender = sender(rusoto.clone(), &config, None);
+        assert_eq!(email_sender.send_email(request()).await.unwrap(), "mock-1");
+        assert_eq!(rusoto.sent().len(), 1);
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    #[tokio::test]
+    async fn test_dual_write_sends_raw_through_rusoto() {
+        let rusoto = std::sync::Arc::new(crate::sender::MockSender::new());
+        let config = PrivatEmailConfig::builder().ses_dual_write(true).build();
+        let email_sender = sender(rusoto.clone(), &config, None);
+        let id = email_sender.send_raw_email(raw_request()).await.unwrap();
+        assert_eq!(id, "mock-1");
+        assert_eq!(rusoto.sent().len(), 1);
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    #[test]
+    fn test_sdk_raw_input_matches_rusoto() {
+        let request = raw_request();
+        let input = sdk::raw_input(&request).unwrap();
+        assert!(RawShape::from_rusoto(&request)
+            .diff(&sdk::raw_shape(&input))
+            .is_empty());
+    }
+
+    #[cfg(feature = "aws-sdk")]
+    #[test]
+    fn test_sdk_input_matches_rusoto() {
+        let request = request();
+        let input = sdk::input(&request).unwrap();
+        assert!(SendShape::from_rusoto(&request)
+            .diff(&sdk::shape(&input))
+            .is_empty());
+    }
+}
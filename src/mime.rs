@@ -0,0 +1,326 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Minimal MIME message builder for the raw send path.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Content-Transfer-Encoding of an attachment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Base64 encoded, wrapped at 76 columns
+    Base64,
+
+    /// Embedded verbatim, as required for `message/rfc822` parts
+    EightBit,
+}
+
+/// File attached to a [`MimeMessage`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Attachment {
+    /// File name shown by mail clients
+    pub filename: String,
+
+    /// MIME type of the attachment
+    pub content_type: String,
+
+    /// Raw attachment content
+    pub data: Vec<u8>,
+
+    /// Transfer encoding used for `data`
+    pub encoding: Encoding,
+}
+
+impl Attachment {
+    /// Creates a base64 encoded attachment.
+    pub fn new<F: ToString>(
+        filename: F,
+        content_type: &str,
+        data: Vec<u8>,
+    ) -> Self {
+        Attachment {
+            filename: filename.to_string(),
+            content_type: content_type.to_owned(),
+            data,
+            encoding: Encoding::Base64,
+        }
+    }
+
+    /// Wraps a complete message as a `message/rfc822` attachment.
+    pub fn message<F: ToString>(filename: F, raw: &[u8]) -> Self {
+        Attachment {
+            filename: filename.to_string(),
+            content_type: "message/rfc822".to_owned(),
+            data: raw.to_vec(),
+            encoding: Encoding::EightBit,
+        }
+    }
+}
+
+/// A MIME message with optional text and HTML bodies and attachments.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MimeMessage {
+    headers: Vec<(String, String)>,
+    text: Option<String>,
+    html: Option<String>,
+    attachments: Vec<Attachment>,
+}
+
+impl MimeMessage {
+    /// Creates an empty message.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a header; non-ASCII values are RFC 2047 encoded.
+    pub fn header<N: ToString, V: AsRef<str>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> Self {
+        self.headers
+            .push((name.to_string(), encode_header_value(value.as_ref())));
+        self
+    }
+
+    /// Sets the text/plain body.
+    pub fn text<T: ToString>(mut self, text: T) -> Self {
+        self.text = Some(text.to_string());
+        self
+    }
+
+    /// Sets the text/html body.
+    pub fn html<H: ToString>(mut self, html: H) -> Self {
+        self.html = Some(html.to_string());
+        self
+    }
+
+    /// Adds an attachment.
+    pub fn attach(mut self, attachment: Attachment) -> Self {
+        self.attachments.push(attachment);
+        self
+    }
+
+    /// Returns the value of the first header called `name`, if any.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Serializes the message with CRLF line endings.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (name, value) in &self.headers {
+            push_line(&mut out, &format!("{}: {}", name, value));
+        }
+        push_line(&mut out, "MIME-Version: 1.0");
+
+        // Neither boundary may prefix the other, or parsers split the
+        // alternative parts of the mixed message
+        let id = boundary_id(self);
+        let boundary = format!("=_privatemail_{}", id);
+        let body = self.body_part(&format!("=_privatemail_alt_{}", id));
+        if self.attachments.is_empty() {
+            out.extend(body);
+            return out;
+        }
+
+        push_line(
+            &mut out,
+            &format!(
+                "Content-Type: multipart/mixed; boundary=\"{}\"",
+                boundary
+            ),
+        );
+        push_line(&mut out, "");
+        push_line(&mut out, &format!("--{}", boundary));
+        out.extend(body);
+        for attachment in &self.attachments {
+            push_line(&mut out, &format!("--{}", boundary));
+            push_attachment(&mut out, attachment);
+        }
+        push_line(&mut out, &format!("--{}--", boundary));
+        out
+    }
+
+    fn hash_content<H: Hasher>(&self, hasher: &mut H) {
+        self.headers.hash(hasher);
+        self.text.hash(hasher);
+        self.html.hash(hasher);
+        for attachment in &self.attachments {
+            attachment.data.hash(hasher);
+        }
+    }
+
+    /// Serializes the body (headers included) as a single part: a plain
+    /// text or HTML leaf, or a multipart/alternative of both.
+    fn body_part(&self, boundary: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        match (&self.text, &self.html) {
+            (Some(text), Some(html)) => {
+                push_line(
+                    &mut out,
+                    &format!(
+                        "Content-Type: multipart/alternative; boundary=\"{}\"",
+                        boundary
+                    ),
+                );
+                push_line(&mut out, "");
+                push_line(&mut out, &format!("--{}", boundary));
+                push_text(&mut out, "text/plain", text);
+                push_line(&mut out, &format!("--{}", boundary));
+                push_text(&mut out, "text/html", html);
+                push_line(&mut out, &format!("--{}--", boundary));
+            }
+            (None, Some(html)) => push_text(&mut out, "text/html", html),
+            (text, None) => {
+                push_text(&mut out, "text/plain", text.as_deref().unwrap_or(""))
+            }
+        }
+        out
+    }
+}
+
+fn push_line(out: &mut Vec<u8>, line: &str) {
+    out.extend_from_slice(line.as_bytes());
+    out.extend_from_slice(b"\r\n");
+}
+
+fn push_base64(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend(base64_lines(data));
+}
+
+/// Base64 encodes `data` in CRLF terminated lines of 76 characters.
+pub fn base64_lines(data: &[u8]) -> Vec<u8> {
+    let encoded = STANDARD.encode(data);
+    let mut out = Vec::with_capacity(encoded.len() + encoded.len() / 38);
+    for chunk in encoded.as_bytes().chunks(76) {
+        out.extend_from_slice(chunk);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn push_text(out: &mut Vec<u8>, content_type: &str, text: &str) {
+    push_line(out, &format!("Content-Type: {}; charset=UTF-8", content_type));
+    push_line(out, "Content-Transfer-Encoding: base64");
+    push_line(out, "");
+    push_base64(out, text.as_bytes());
+}
+
+fn push_attachment(out: &mut Vec<u8>, attachment: &Attachment) {
+    let filename = encode_header_value(&attachment.filename.replace('"', ""));
+    push_line(
+        out,
+        &format!(
+            "Content-Type: {}; name=\"{}\"",
+            attachment.content_type, filename
+        ),
+    );
+    push_line(
+        out,
+        &format!("Content-Disposition: attachment; filename=\"{}\"", filename),
+    );
+    match attachment.encoding {
+        Encoding::Base64 => {
+            push_line(out, "Content-Transfer-Encoding: base64");
+            push_line(out, "");
+            push_base64(out, &attachment.data);
+        }
+        Encoding::EightBit => {
+            let encoding =
+                if attachment.data.is_ascii() { "7bit" } else { "8bit" };
+            push_line(out, &format!("Content-Transfer-Encoding: {}", encoding));
+            push_line(out, "");
+            out.extend_from_slice(&attachment.data);
+            if !attachment.data.ends_with(b"\n") {
+                out.extend_from_slice(b"\r\n");
+            }
+        }
+    }
+}
+
+/// Derives the id of the boundaries of `message`, which does not depend on
+/// any global state but is unique enough not to appear in its content.
+fn boundary_id(message: &MimeMessage) -> String {
+    let mut hasher = DefaultHasher::new();
+    message.hash_content(&mut hasher);
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// RFC 2047 encodes `value` when it contains non-ASCII characters.
+pub fn encode_header_value(value: &str) -> String {
+    let value = value.replace(['\r', '\n'], " ");
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value.as_bytes()))
+    }
+}
+
+/** Test module for the MIME builder */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::parse_mail;
+
+    #[test]
+    fn test_encode_header_value() {
+        assert_eq!(encode_header_value("Hello"), "Hello");
+        assert_eq!(encode_header_value("a\r\nBcc: x"), "a  Bcc: x");
+        assert_eq!(encode_header_value("Café"), "=?UTF-8?B?Q2Fmw6k=?=");
+    }
+
+    #[test]
+    fn test_alternative_body_round_trip() {
+        let raw = MimeMessage::new()
+            .header("Subject", "Café")
+            .text("plain")
+            .html("<p>html</p>")
+            .to_bytes();
+        let mail = parse_mail(&raw).unwrap();
+        assert_eq!(mail.ctype.mimetype, "multipart/alternative");
+        assert_eq!(mail.subparts[0].get_body().unwrap().trim(), "plain");
+        assert_eq!(mail.subparts[1].get_body().unwrap().trim(), "<p>html</p>");
+        assert_eq!(
+            mail.headers
+                .iter()
+                .find(|h| h.get_key() == "Subject")
+                .unwrap()
+                .get_value(),
+            "Café"
+        );
+    }
+
+    #[test]
+    fn test_rfc822_attachment_round_trip() {
+        let original = b"Subject: original\r\n\r\nhello\r\n";
+        let raw = MimeMessage::new()
+            .header("Subject", "Fwd: original")
+            .text("see attached")
+            .html("<p>see attached</p>")
+            .attach(Attachment::message("original.eml", original))
+            .to_bytes();
+        let mail = parse_mail(&raw).unwrap();
+        assert_eq!(mail.ctype.mimetype, "multipart/mixed");
+        assert_eq!(mail.subparts.len(), 2);
+        let attached = &mail.subparts[1];
+        assert_eq!(attached.ctype.mimetype, "message/rfc822");
+        assert_eq!(attached.get_body_raw().unwrap(), original);
+    }
+}
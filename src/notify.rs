@@ -0,0 +1,513 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Chat notifications sent after a message has been processed.
+use crate::config::PrivatEmailConfig;
+use crate::quiet;
+use crate::store::MailAction;
+use crate::thread;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use lambda_runtime::Error;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Header carrying the signature of webhook payloads, as
+/// `t=<unix time>,v1=<hex HMAC-SHA256 of "<unix time>.<body>">`.
+pub const SIGNATURE_HEADER: &str = "X-PrivatEmail-Signature";
+
+/// Summary of a processed message handed to notifiers.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Notification {
+    /// SES message id
+    pub message_id: String,
+
+    /// Original sender
+    pub sender: String,
+
+    /// Original recipients, selecting the notifiers of their route
+    pub recipients: Vec<String>,
+
+    /// Message subject
+    pub subject: String,
+
+    /// Action taken on the message
+    pub action: MailAction,
+
+    /// Link to a PNG preview of the message body, if rendered
+    pub preview_url: Option<String>,
+
+    /// Text body of the message, if extracted
+    pub text: Option<String>,
+
+    /// Messages forwarded so far in the conversation, `1` when unknown
+    pub thread_count: u64,
+}
+
+/// Delivers a [`Notification`] to an external channel.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    /// Sends `notification`.
+    async fn notify(&self, notification: &Notification) -> Result<(), Error>;
+}
+
+/// Longest text preview in Discord embeds, in characters.
+pub const DISCORD_PREVIEW_CHARS: usize = 280;
+
+/// Longest Discord embed title, in characters.
+const DISCORD_TITLE_CHARS: usize = 256;
+
+/// Base URL of the Telegram Bot API.
+pub const TELEGRAM_API: &str = "https://api.telegram.org";
+
+/// Default length of the text preview of Telegram messages, in characters.
+pub const DEFAULT_TELEGRAM_PREVIEW_CHARS: usize = 500;
+
+/// Longest text preview of Telegram messages, leaving room for the
+/// sender and subject within the 4096 characters of a message.
+const TELEGRAM_MAX_PREVIEW_CHARS: usize = 3_500;
+
+/// Creates the notifiers enabled in `config` for mail sent to
+/// `recipients`.
+pub fn from_config(
+    config: &PrivatEmailConfig,
+    recipients: &[String],
+) -> Vec<Box<dyn Notifier>> {
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    if let Some(url) = &config.slack_webhook_url {
+        notifiers.push(Box::new(SlackNotifier::new(url)));
+    }
+    if let Some(url) = config.discord_webhook_for(recipients) {
+        notifiers.push(Box::new(DiscordNotifier::new(url)));
+    }
+    if let Some(telegram) = TelegramNotifier::from_config(config) {
+        notifiers.push(Box::new(telegram));
+    }
+    notifiers
+}
+
+/// Parses a `route=url` pair of a Discord webhook, where the route is an
+/// original recipient, an `@domain` or `*`.
+pub fn parse_discord_webhook(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((route, url))
+            if !route.trim().is_empty()
+                && url.trim().starts_with("https://") =>
+        {
+            Ok((route.trim().to_lowercase(), url.trim().to_owned()))
+        }
+        _ => Err(format!("Invalid Discord webhook: {}", s)),
+    }
+}
+
+/// Returns at most `chars` characters of `text`, with its whitespace
+/// collapsed and an ellipsis when cut.
+pub fn truncate(text: &str, chars: usize) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(chars.saturating_sub(1)) {
+        Some((end, _)) if text.chars().count() > chars => {
+            format!("{}…", &text[..end])
+        }
+        _ => text,
+    }
+}
+
+/// Longest wait for the connection of a notification or alert post.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Longest wait for the response of a notification or alert post, so a
+/// slow endpoint does not hold the invocation until the Lambda timeout.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the HTTP client of the container, shared by the notifiers and
+/// alerters so their connections are reused.
+pub fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .expect("HTTP client of the default TLS backend")
+    })
+}
+
+/// Posts a JSON `payload` to `url` with the extra `headers`, failing on
+/// non-success responses.
+pub async fn post_json(
+    url: &str,
+    headers: &[(&str, &str)],
+    payload: &Value,
+) -> Result<(), Error> {
+    let mut request = client().post(url).json(payload);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    checked(request.send().await)
+}
+
+/// Returns the error of a post, if any, without its URL: bot and incoming
+/// webhook URLs hold their tokens, which must stay out of the logs.
+fn checked(response: reqwest::Result<reqwest::Response>) -> Result<(), Error> {
+    response.and_then(|r| r.error_for_status()).map_err(|e| e.without_url())?;
+    Ok(())
+}
+
+/// [`Notifier`] posting to a Slack incoming webhook.
+#[derive(Clone, Debug)]
+pub struct SlackNotifier {
+    webhook_url: String,
+}
+
+impl SlackNotifier {
+    /// Creates a notifier posting to `webhook_url`.
+    pub fn new<U: ToString>(webhook_url: U) -> Self {
+        SlackNotifier { webhook_url: webhook_url.to_string() }
+    }
+
+    /// Builds the Slack message payload for `notification`.
+    pub fn payload(notification: &Notification) -> Value {
+        let summary = if notification.thread_count > 1 {
+            format!(
+                "*{} messages* in thread from {}\n>{}",
+                notification.thread_count,
+                notification.sender,
+                thread::normalize_subject(&notification.subject)
+            )
+        } else {
+            format!(
+                "*{}* mail from {}\n>{}",
+                notification.action.as_str(),
+                notification.sender,
+                notification.subject
+            )
+        };
+        let mut blocks = vec![json!({
+            "type": "section",
+            "text": { "type": "mrkdwn", "text": summary },
+        })];
+        if let Some(url) = &notification.preview_url {
+            blocks.push(json!({
+                "type": "image",
+                "image_url": url,
+                "alt_text": notification.subject,
+            }));
+        }
+        json!({ "text": summary, "blocks": blocks })
+    }
+}
+
+#[async_trait]
+impl Notifier for SlackNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        post_json(&self.webhook_url, &[], &Self::payload(notification)).await
+    }
+}
+
+/// [`Notifier`] posting an embed to a Discord webhook.
+#[derive(Clone, Debug)]
+pub struct DiscordNotifier {
+    webhook_url: String,
+}
+
+impl DiscordNotifier {
+    /// Creates a notifier posting to `webhook_url`.
+    pub fn new<U: ToString>(webhook_url: U) -> Self {
+        DiscordNotifier { webhook_url: webhook_url.to_string() }
+    }
+
+    /// Builds the Discord message payload for `notification`: an embed
+    /// titled by the subject, with the sender and the start of the text.
+    pub fn payload(notification: &Notification) -> Value {
+        let mut fields = vec![
+            json!({
+                "name": "From",
+                "value": notification.sender,
+                "inline": true,
+            }),
+            json!({
+                "name": "Action",
+                "value": notification.action.as_str(),
+                "inline": true,
+            }),
+        ];
+        if notification.thread_count > 1 {
+            fields.push(json!({
+                "name": "Thread",
+                "value": format!("{} messages", notification.thread_count),
+                "inline": true,
+            }));
+        }
+        let mut embed = json!({
+            "title": truncate(&notification.subject, DISCORD_TITLE_CHARS),
+            "fields": fields,
+        });
+        if let Some(text) = &notification.text {
+            embed["description"] = truncate(text, DISCORD_PREVIEW_CHARS).into();
+        }
+        if let Some(url) = &notification.preview_url {
+            embed["image"] = json!({ "url": url });
+        }
+        json!({ "embeds": [embed] })
+    }
+}
+
+#[async_trait]
+impl Notifier for DiscordNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        post_json(&self.webhook_url, &[], &Self::payload(notification)).await
+    }
+}
+
+/// [`Notifier`] sending a message to a Telegram chat through a bot.
+#[derive(Clone, Debug)]
+pub struct TelegramNotifier {
+    bot_token: String,
+    chat_id: String,
+    preview_chars: usize,
+}
+
+impl TelegramNotifier {
+    /// Creates a notifier sending to `chat_id` as the bot of `bot_token`,
+    /// with up to `preview_chars` characters of the text body.
+    pub fn new<T: ToString, C: ToString>(
+        bot_token: T,
+        chat_id: C,
+        preview_chars: usize,
+    ) -> Self {
+        TelegramNotifier {
+            bot_token: bot_token.to_string(),
+            chat_id: chat_id.to_string(),
+            preview_chars: preview_chars.min(TELEGRAM_MAX_PREVIEW_CHARS),
+        }
+    }
+
+    /// Creates the notifier configured in `config`, if any.
+    pub fn from_config(config: &PrivatEmailConfig) -> Option<Self> {
+        Some(TelegramNotifier::new(
+            config.telegram_bot_token.as_ref()?,
+            config.telegram_chat_id.as_ref()?,
+            config.telegram_preview_chars,
+        ))
+    }
+
+    /// Returns the `sendMessage` URL of the bot.
+    pub fn url(&self) -> String {
+        format!("{}/bot{}/sendMessage", TELEGRAM_API, self.bot_token)
+    }
+
+    /// Builds the `sendMessage` payload for `notification`, in plain text.
+    pub fn payload(&self, notification: &Notification) -> Value {
+        let mut text = format!(
+            "{} mail from {}\n{}",
+            notification.action.as_str(),
+            notification.sender,
+            notification.subject
+        );
+        if let Some(body) = &notification.text {
+            let preview = truncate(body, self.preview_chars);
+            if !preview.is_empty() {
+                text.push_str("\n\n");
+                text.push_str(&preview);
+            }
+        }
+        json!({
+            "chat_id": self.chat_id,
+            "text": text,
+            "disable_web_page_preview": true,
+        })
+    }
+}
+
+#[async_trait]
+impl Notifier for TelegramNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        post_json(&self.url(), &[], &self.payload(notification)).await
+    }
+}
+
+/// [`Notifier`] posting a JSON summary of every handled message to an
+/// HTTPS endpoint, signed with a shared secret when one is set.
+#[derive(Clone, Debug)]
+pub struct WebhookNotifier {
+    url: String,
+    secret: Option<String>,
+}
+
+impl WebhookNotifier {
+    /// Creates a notifier posting to `url`, signing with `secret`.
+    pub fn new<U: ToString>(url: U, secret: Option<String>) -> Self {
+        WebhookNotifier { url: url.to_string(), secret }
+    }
+
+    /// Creates the notifier configured in `config`, if any.
+    pub fn from_config(config: &PrivatEmailConfig) -> Option<Self> {
+        let url = config.webhook_url.as_ref()?;
+        Some(WebhookNotifier::new(url, config.webhook_secret.clone()))
+    }
+
+    /// Builds the webhook payload for `notification`, sent at `timestamp`.
+    pub fn payload(notification: &Notification, timestamp: i64) -> Value {
+        json!({
+            "messageId": notification.message_id,
+            "sender": notification.sender,
+            "subject": notification.subject,
+            "action": notification.action.as_str(),
+            "timestamp": timestamp,
+        })
+    }
+
+    /// Returns the [`SIGNATURE_HEADER`] value of `body` sent at
+    /// `timestamp`.
+    pub fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hex::encode(mac.finalize().into_bytes())
+        )
+    }
+}
+
+#[async_trait]
+impl Notifier for WebhookNotifier {
+    async fn notify(&self, notification: &Notification) -> Result<(), Error> {
+        let timestamp = quiet::now();
+        let body = serde_json::to_vec(&Self::payload(notification, timestamp))?;
+        let mut request =
+            client().post(&self.url).header("Content-Type", "application/json");
+        if let Some(secret) = &self.secret {
+            request = request.header(
+                SIGNATURE_HEADER,
+                Self::signature(secret, timestamp, &body),
+            );
+        }
+        checked(request.body(body).send().await)
+    }
+}
+
+/** Test module for notifications */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn notification(preview_url: Option<&str>) -> Notification {
+        Notification {
+            message_id: "rjq1eo6jf3qqff76".to_owned(),
+            sender: "fufu@achu.soup".to_owned(),
+            recipients: vec!["hello@nyah.dev".to_owned()],
+            subject: "Testing new forward service".to_owned(),
+            action: MailAction::Forwarded,
+            preview_url: preview_url.map(|u| u.to_owned()),
+            text: None,
+            thread_count: 1,
+        }
+    }
+
+    #[test]
+    fn test_slack_payload() {
+        let payload = SlackNotifier::payload(&notification(None));
+        assert_eq!(payload["blocks"].as_array().unwrap().len(), 1);
+        assert!(payload["text"].as_str().unwrap().contains("fufu@achu.soup"));
+
+        let payload =
+            SlackNotifier::payload(&notification(Some("https://p/a.png")));
+        assert_eq!(payload["blocks"][1]["image_url"], "https://p/a.png");
+
+        let mut threaded = notification(None);
+        threaded.subject = "Re: Testing new forward service".to_owned();
+        threaded.thread_count = 5;
+        let payload = SlackNotifier::payload(&threaded);
+        assert!(payload["text"]
+            .as_str()
+            .unwrap()
+            .starts_with("*5 messages* in thread"));
+    }
+
+    #[test]
+    fn test_discord_payload() {
+        let payload = DiscordNotifier::payload(&notification(None));
+        let embed = &payload["embeds"][0];
+        assert_eq!(embed["title"], "Testing new forward service");
+        assert_eq!(embed["fields"][0]["value"], "fufu@achu.soup");
+        assert!(embed.get("description").is_none());
+
+        let mut long = notification(Some("https://p/a.png"));
+        long.text = Some("word ".repeat(100));
+        let payload = DiscordNotifier::payload(&long);
+        let description = payload["embeds"][0]["description"].as_str().unwrap();
+        assert_eq!(description.chars().count(), DISCORD_PREVIEW_CHARS);
+        assert!(description.ends_with('…'));
+        assert_eq!(payload["embeds"][0]["image"]["url"], "https://p/a.png");
+
+        assert_eq!(truncate(" Hello\n  there ", 20), "Hello there");
+        assert_eq!(
+            parse_discord_webhook(
+                "@Nyah.dev=https://discord.com/api/webhooks/1"
+            ),
+            Ok((
+                "@nyah.dev".to_owned(),
+                "https://discord.com/api/webhooks/1".to_owned()
+            ))
+        );
+        assert!(parse_discord_webhook("https://discord.com/x").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_post_error_hides_url() {
+        let url = "http://127.0.0.1:1/bot123:s3cr3t/sendMessage";
+        let error = post_json(url, &[], &json!({})).await.unwrap_err();
+        assert!(!format!("{:?}", error).contains("s3cr3t"));
+        assert!(!error.to_string().contains("s3cr3t"));
+    }
+
+    #[test]
+    fn test_telegram_payload() {
+        let telegram = TelegramNotifier::new("123:abc", "-10042", 10);
+        assert_eq!(
+            telegram.url(),
+            "https://api.telegram.org/bot123:abc/sendMessage"
+        );
+        let mut notification = notification(None);
+        notification.text = Some("Hello there, this is long".to_owned());
+        let payload = telegram.payload(&notification);
+        assert_eq!(payload["chat_id"], "-10042");
+        assert_eq!(
+            payload["text"],
+            "forwarded mail from fufu@achu.soup\n\
+             Testing new forward service\n\nHello the…"
+        );
+    }
+
+    #[test]
+    fn test_webhook_payload() {
+        let payload = WebhookNotifier::payload(&notification(None), 1647679576);
+        assert_eq!(payload["messageId"], "rjq1eo6jf3qqff76");
+        assert_eq!(payload["action"], "forwarded");
+        assert_eq!(payload["timestamp"], 1647679576);
+
+        let signature = WebhookNotifier::signature("s3cret", 1647679576, b"{}");
+        assert!(signature.starts_with("t=1647679576,v1="));
+        assert_eq!(signature.len(), "t=1647679576,v1=".len() + 64);
+        assert_ne!(
+            signature,
+            WebhookNotifier::signature("other", 1647679576, b"{}")
+        );
+    }
+
+    #[test]
+    fn test_client_is_shared() {
+        assert!(std::ptr::eq(client(), client()));
+    }
+}
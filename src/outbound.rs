@@ -0,0 +1,233 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! New mail composed from an alias and sent through the forwarder.
+//!
+//! A [`SendRequest`] is accepted from a direct invocation whose payload
+//! holds it under `sendRequest`, or as the JSON body of a `POST` to
+//! [`SEND_PATH`] through a Function URL or API Gateway, authorized by the
+//! `send_api_token` bearer token. The `From` address must be on one of
+//! the `send_as_domains`, which also need to be verified in SES:
+//!
+//! ```json
+//! {
+//!   "sendRequest": {
+//!     "from": "Nyah <jobs@nyah.dev>",
+//!     "to": ["recruiter@fufu.soup"],
+//!     "subject": "Re: Rust position",
+//!     "text": "Hello!"
+//!   }
+//! }
+//! ```
+use crate::config::validate::is_address;
+use crate::http;
+use crate::mime::MimeMessage;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Path of HTTP send requests.
+pub const SEND_PATH: &str = "/send";
+
+/// Mail to compose from an alias.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendRequest {
+    /// Alias sending the mail, optionally with a display name
+    pub from: String,
+
+    /// Recipients
+    pub to: Vec<String>,
+
+    /// Copied recipients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
+
+    /// Blind copied recipients
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bcc: Vec<String>,
+
+    /// Subject of the mail
+    pub subject: String,
+
+    /// Plain text body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+
+    /// HTML body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub html: Option<String>,
+
+    /// Address replies go to, the alias when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+
+    /// `Message-ID` of the message replied to, to thread the mail
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+}
+
+/// Returns the send request of a direct invocation `event`, routed as
+/// [`Route::SendRequest`](crate::router::Route::SendRequest).
+pub fn from_invocation(event: &Value) -> Result<SendRequest, String> {
+    serde_json::from_value(event["sendRequest"].clone())
+        .map_err(|e| format!("Invalid send request: {}", e))
+}
+
+/// Returns the send request in the body of the HTTP request `event`.
+pub fn from_http(event: &Value) -> Result<SendRequest, String> {
+    serde_json::from_slice(&http::body(event)?)
+        .map_err(|e| format!("Invalid send request: {}", e))
+}
+
+impl SendRequest {
+    /// Returns the problems preventing the request from being sent from
+    /// one of the `domains`.
+    pub fn problems<S: AsRef<str>>(&self, domains: &[S]) -> Vec<String> {
+        let mut problems = Vec::new();
+        let from = bare_address(&self.from);
+        let domain = from.rsplit_once('@').map_or("", |(_, d)| d);
+        if !domains.iter().any(|d| {
+            d.as_ref().trim_start_matches('@').eq_ignore_ascii_case(domain)
+        }) {
+            problems.push(format!("{} is not on a send-as domain", from));
+        }
+        for address in
+            self.recipients().chain([&self.from]).chain(self.reply_to.as_ref())
+        {
+            if !is_address(address.trim()) {
+                problems.push(format!("{} is not an address", address));
+            }
+        }
+        if self.to.is_empty() {
+            problems.push("No recipients".to_owned());
+        }
+        if self.text.is_none() && self.html.is_none() {
+            problems.push("No text or html body".to_owned());
+        }
+        problems
+    }
+
+    /// Returns the envelope source, the bare alias address.
+    pub fn source(&self) -> &str {
+        bare_address(&self.from)
+    }
+
+    /// Returns the bare addresses of every recipient, `bcc` included.
+    pub fn destinations(&self) -> Vec<String> {
+        self.recipients().map(|a| bare_address(a).to_owned()).collect()
+    }
+
+    /// Builds the message sent for the request.
+    pub fn build(&self) -> MimeMessage {
+        let mut message = MimeMessage::new()
+            .header("From", self.from.trim())
+            .header("To", self.to.join(", "));
+        if !self.cc.is_empty() {
+            message = message.header("Cc", self.cc.join(", "));
+        }
+        message = message
+            .header("Subject", &self.subject)
+            .header("Reply-To", self.reply_to.as_deref().unwrap_or(&self.from));
+        if let Some(message_id) = &self.in_reply_to {
+            message = message
+                .header("In-Reply-To", message_id.trim())
+                .header("References", message_id.trim());
+        }
+        if let Some(text) = &self.text {
+            message = message.text(text);
+        }
+        if let Some(html) = &self.html {
+            message = message.html(html);
+        }
+        message
+    }
+
+    fn recipients(&self) -> impl Iterator<Item = &String> {
+        self.to.iter().chain(&self.cc).chain(&self.bcc)
+    }
+}
+
+/// Returns the address of `value`, without its display name.
+fn bare_address(value: &str) -> &str {
+    match value.rsplit_once('<') {
+        Some((_, rest)) => rest.trim_end().trim_end_matches('>').trim(),
+        None => value.trim(),
+    }
+}
+
+/** Test module for outbound mail */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use serde_json::json;
+
+    fn request() -> SendRequest {
+        SendRequest {
+            from: "Nyah <jobs@nyah.dev>".to_owned(),
+            to: vec!["recruiter@fufu.soup".to_owned()],
+            bcc: vec!["me@nyah.dev".to_owned()],
+            subject: "Re: Rust position".to_owned(),
+            text: Some("Hello!".to_owned()),
+            in_reply_to: Some("<abc@fufu.soup>".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_problems() {
+        assert!(request().problems(&["@nyah.dev"]).is_empty());
+        let request = SendRequest {
+            from: "jobs@eru.soup".to_owned(),
+            cc: vec!["nobody".to_owned()],
+            text: None,
+            ..request()
+        };
+        assert_eq!(
+            request.problems(&["nyah.dev"]),
+            [
+                "jobs@eru.soup is not on a send-as domain",
+                "nobody is not an address",
+                "No text or html body",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build() {
+        let request = request();
+        assert_eq!(request.source(), "jobs@nyah.dev");
+        assert_eq!(
+            request.destinations(),
+            ["recruiter@fufu.soup", "me@nyah.dev"]
+        );
+        let raw = String::from_utf8(request.build().to_bytes()).unwrap();
+        assert!(raw.starts_with(
+            "From: Nyah <jobs@nyah.dev>\r\nTo: recruiter@fufu.soup\r\n"
+        ));
+        assert!(raw.contains("Reply-To: Nyah <jobs@nyah.dev>\r\n"));
+        assert!(raw.contains("In-Reply-To: <abc@fufu.soup>\r\n"));
+        assert!(!raw.contains("me@nyah.dev"));
+    }
+
+    #[test]
+    fn test_events() {
+        let event = json!({ "sendRequest": request() });
+        assert_eq!(from_invocation(&event), Ok(request()));
+
+        let event = json!({
+            "rawPath": "/send",
+            "requestContext": { "http": { "method": "POST" } },
+            "headers": { "authorization": "Bearer s3cret" },
+            "body": STANDARD.encode(serde_json::to_vec(&request()).unwrap()),
+            "isBase64Encoded": true,
+        });
+        assert_eq!(from_http(&event), Ok(request()));
+    }
+}
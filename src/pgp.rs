@@ -0,0 +1,742 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! OpenPGP encryption of forwarded mail.
+//!
+//! Messages are encrypted to a single RSA public key as PGP/MIME
+//! (RFC 3156): the body and attachments become an AES-256 encrypted,
+//! integrity protected OpenPGP message (RFC 4880), while the envelope
+//! headers, including the subject, stay readable. Keys using other
+//! algorithms are rejected, as are keys whose self-signatures revoke
+//! them, let them expire or don't allow encryption. The packets are
+//! checked against those of GnuPG, which decrypts the output in
+//! `tests/pgp.rs`.
+use crate::config::PrivatEmailConfig;
+use aes::Aes256;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use cfb_mode::cipher::{AsyncStreamCipher, KeyIvInit};
+use lambda_runtime::Error;
+use rand::{rngs::OsRng, RngCore};
+use rsa::{BigUint, Pkcs1v15Encrypt, RsaPublicKey};
+use sha1::{Digest, Sha1};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod verify;
+
+/// OpenPGP packet tags used here.
+const TAG_PKESK: u8 = 1;
+const TAG_SIGNATURE: u8 = 2;
+const TAG_PUBLIC_KEY: u8 = 6;
+const TAG_LITERAL: u8 = 11;
+const TAG_PUBLIC_SUBKEY: u8 = 14;
+const TAG_SEIPD: u8 = 18;
+const TAG_MDC: u8 = 19;
+
+/// OpenPGP algorithm ids: RSA (encrypt or sign), RSA encrypt-only and
+/// AES-256.
+const ALGO_RSA: u8 = 1;
+const ALGO_RSA_ENCRYPT: u8 = 2;
+const ALGO_AES256: u8 = 9;
+
+/// Signature subpackets read from self-signatures.
+const SUBPACKET_CREATED: u8 = 2;
+const SUBPACKET_KEY_EXPIRES: u8 = 9;
+const SUBPACKET_KEY_FLAGS: u8 = 27;
+
+/// Key flags allowing to encrypt communications or storage.
+const FLAGS_ENCRYPT: u8 = 0x04 | 0x08;
+
+/// An RSA key mail is encrypted to.
+#[derive(Clone, Debug)]
+pub struct PgpRecipient {
+    key_id: [u8; 8],
+    key: RsaPublicKey,
+}
+
+impl PgpRecipient {
+    /// Reads the encryption key of an ASCII armored public key block: the
+    /// newest valid RSA subkey allowed to encrypt, or else the primary key
+    /// if it is allowed to.
+    pub fn from_armored(armored: &str) -> Result<Self, Error> {
+        let data = dearmor(&armored.replace("\\n", "\n"))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        Self::select(&data, now)
+    }
+
+    /// Selects the encryption key of the public key packets `data` at
+    /// `now`, in seconds since the Unix epoch.
+    fn select(data: &[u8], now: u64) -> Result<Self, Error> {
+        let mut keys: Vec<KeyState> = Vec::new();
+        for (tag, body) in packets(data)? {
+            match tag {
+                // Only the first key of the block is read
+                TAG_PUBLIC_KEY if !keys.is_empty() => break,
+                TAG_PUBLIC_SUBKEY if keys.is_empty() => continue,
+                TAG_PUBLIC_KEY | TAG_PUBLIC_SUBKEY => {
+                    keys.push(KeyState::parse(tag == TAG_PUBLIC_KEY, body)?)
+                }
+                TAG_SIGNATURE => {
+                    let primary = keys.first().map(|k| k.key_id);
+                    if let (Some(key), Some(signature)) =
+                        (keys.last_mut(), SelfSignature::parse(body, primary))
+                    {
+                        key.apply(signature);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let (primary, subkeys) =
+            keys.split_first().ok_or("PGP public key has no primary key")?;
+        if primary.revoked {
+            return Err("PGP public key is revoked".into());
+        }
+        if !primary.valid(now) {
+            return Err("PGP public key has expired".into());
+        }
+        subkeys
+            .iter()
+            .filter(|k| k.encrypts(now))
+            .max_by_key(|k| k.created)
+            .or(Some(primary).filter(|k| k.encrypts(now)))
+            .and_then(|k| k.recipient.clone())
+            .ok_or_else(|| {
+                "PGP public key has no valid RSA encryption key".into()
+            })
+    }
+
+    /// Returns the recipient for the configured public key, if any.
+    pub fn from_config(
+        email_config: &PrivatEmailConfig,
+    ) -> Result<Option<Self>, Error> {
+        email_config
+            .pgp_public_key
+            .as_deref()
+            .map(Self::from_armored)
+            .transpose()
+    }
+
+    /// Returns the key id of the encryption key, in hex.
+    pub fn key_id(&self) -> String {
+        hex::encode_upper(self.key_id)
+    }
+
+    /// Encrypts `data` into a binary OpenPGP message.
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut session_key = [0u8; 32];
+        OsRng.fill_bytes(&mut session_key);
+
+        // Public-key encrypted session key
+        let checksum = session_key.iter().map(|&b| b as u32).sum::<u32>();
+        let mut key_material = vec![ALGO_AES256];
+        key_material.extend_from_slice(&session_key);
+        key_material.extend_from_slice(&(checksum as u16).to_be_bytes());
+        let encrypted_key =
+            self.key.encrypt(&mut OsRng, Pkcs1v15Encrypt, &key_material)?;
+        let mut pkesk = vec![3];
+        pkesk.extend_from_slice(&self.key_id);
+        pkesk.push(ALGO_RSA);
+        pkesk.extend(mpi(&encrypted_key));
+
+        // Literal data in a symmetrically encrypted, integrity protected
+        // packet, following the random prefix and closed by the MDC
+        let mut literal = vec![b'b', 0, 0, 0, 0, 0];
+        literal.extend_from_slice(data);
+        let mut plaintext = vec![0u8; 16];
+        OsRng.fill_bytes(&mut plaintext);
+        plaintext.extend_from_within(14..16);
+        plaintext.extend(packet(TAG_LITERAL, &literal));
+        plaintext.extend_from_slice(&[0xC0 | TAG_MDC, 20]);
+        let mdc = Sha1::digest(&plaintext);
+        plaintext.extend_from_slice(&mdc);
+        cfb_mode::Encryptor::<Aes256>::new(
+            &session_key.into(),
+            &[0; 16].into(),
+        )
+        .encrypt(&mut plaintext);
+        let mut seipd = vec![1];
+        seipd.extend(plaintext);
+
+        let mut message = packet(TAG_PKESK, &pkesk);
+        message.extend(packet(TAG_SEIPD, &seipd));
+        Ok(message)
+    }
+
+    /// Encrypts the raw MIME message `raw` as PGP/MIME, keeping every
+    /// header but the `Content-*` ones outside the encrypted part.
+    pub fn encrypt_message(&self, raw: &[u8]) -> Result<Vec<u8>, Error> {
+        let (headers, body) = split_headers(raw);
+        let mut outer = Vec::new();
+        let mut inner = Vec::new();
+        for field in headers {
+            let name = field.split(':').next().unwrap_or_default();
+            let name = name.trim().to_ascii_lowercase();
+            if name.starts_with("content-") {
+                inner.push(field);
+            } else if name != "mime-version" {
+                outer.push(field);
+            }
+        }
+        let mut entity = inner.join("\r\n").into_bytes();
+        entity.extend_from_slice(b"\r\n\r\n");
+        entity.extend_from_slice(body);
+        let armored = armor(&self.encrypt(&entity)?);
+
+        let boundary = format!("pgp-{:016x}", OsRng.next_u64());
+        let mut out = outer.join("\r\n");
+        out.push_str(&format!(
+            "\r\nMIME-Version: 1.0\r\n\
+             Content-Type: multipart/encrypted; \
+             protocol=\"application/pgp-encrypted\"; boundary=\"{b}\"\r\n\
+             \r\n\
+             This is an OpenPGP/MIME encrypted message.\r\n\
+             --{b}\r\n\
+             Content-Type: application/pgp-encrypted\r\n\
+             Content-Description: PGP/MIME version identification\r\n\
+             \r\n\
+             Version: 1\r\n\
+             --{b}\r\n\
+             Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\
+             Content-Description: OpenPGP encrypted message\r\n\
+             Content-Disposition: inline; filename=\"encrypted.asc\"\r\n\
+             \r\n\
+             {armored}\
+             --{b}--\r\n",
+            b = boundary,
+            armored = armored,
+        ));
+        Ok(out.into_bytes())
+    }
+}
+
+/// A key of a public key block and what its self-signatures say of it.
+struct KeyState {
+    primary: bool,
+    key_id: [u8; 8],
+    created: u64,
+    recipient: Option<PgpRecipient>,
+    binding: Option<SelfSignature>,
+    revoked: bool,
+}
+
+impl KeyState {
+    fn parse(primary: bool, body: &[u8]) -> Result<Self, Error> {
+        let created = be_length(body.get(1..5))? as u64;
+        Ok(KeyState {
+            primary,
+            key_id: key_id(body),
+            created,
+            recipient: parse_rsa_key(body)?,
+            binding: None,
+            revoked: false,
+        })
+    }
+
+    /// Applies the self-signature `signature` following the key, keeping
+    /// the newest binding.
+    fn apply(&mut self, signature: SelfSignature) {
+        match (self.primary, signature.class) {
+            (true, 0x20) | (false, 0x28) => self.revoked = true,
+            (true, 0x10..=0x13 | 0x1F) | (false, 0x18)
+                if self
+                    .binding
+                    .as_ref()
+                    .is_none_or(|b| b.created <= signature.created) =>
+            {
+                self.binding = Some(signature);
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the key is bound, unrevoked and unexpired at `now`.
+    fn valid(&self, now: u64) -> bool {
+        let Some(binding) = &self.binding else {
+            return false;
+        };
+        !self.revoked
+            && binding
+                .expires_after
+                .filter(|&secs| secs > 0)
+                .is_none_or(|secs| self.created + secs > now)
+    }
+
+    /// Whether the key is valid at `now` and allowed to encrypt. Keys
+    /// without flags may encrypt as their algorithm allows.
+    fn encrypts(&self, now: u64) -> bool {
+        self.valid(now)
+            && self.binding.as_ref().is_some_and(|b| {
+                b.key_flags.is_none_or(|flags| flags & FLAGS_ENCRYPT != 0)
+            })
+    }
+}
+
+/// The hashed subpackets of a self-signature read for key selection.
+struct SelfSignature {
+    class: u8,
+    created: u64,
+    key_flags: Option<u8>,
+    expires_after: Option<u64>,
+}
+
+impl SelfSignature {
+    /// Parses a version 4 signature packet body, returning `None` for
+    /// signatures of other issuers than the key `primary`.
+    fn parse(body: &[u8], primary: Option<[u8; 8]>) -> Option<Self> {
+        if body.first() != Some(&4) {
+            return None;
+        }
+        let hashed_end = 6 + be_length(body.get(4..6)).ok()?;
+        let hashed = body.get(6..hashed_end)?;
+        let unhashed_len = be_length(body.get(hashed_end..hashed_end + 2));
+        let unhashed = body.get(hashed_end + 2..)?.get(..unhashed_len.ok()?)?;
+        let issuer =
+            verify::issuer(hashed).or_else(|| verify::issuer(unhashed));
+        if issuer.is_some() && issuer != primary {
+            return None;
+        }
+        let mut signature = SelfSignature {
+            class: body[1],
+            created: 0,
+            key_flags: None,
+            expires_after: None,
+        };
+        for (kind, data) in subpackets(hashed)? {
+            match kind {
+                SUBPACKET_CREATED => {
+                    signature.created = be_length(Some(data)).ok()? as u64
+                }
+                SUBPACKET_KEY_EXPIRES => {
+                    signature.expires_after =
+                        Some(be_length(Some(data)).ok()? as u64)
+                }
+                SUBPACKET_KEY_FLAGS => {
+                    signature.key_flags = data.first().copied()
+                }
+                _ => {}
+            }
+        }
+        Some(signature)
+    }
+}
+
+/// Returns the `(type, data)` pairs of the signature `subpackets`, with
+/// the critical bit cleared from the types.
+fn subpackets(mut subpackets: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let mut out = Vec::new();
+    while let Some(&first) = subpackets.first() {
+        let (len, offset) = match first {
+            0..=191 => (first as usize, 1),
+            192..=254 => {
+                let second = *subpackets.get(1)? as usize;
+                (((first as usize - 192) << 8) + second + 192, 2)
+            }
+            255 => (be_length(subpackets.get(1..5)).ok()?, 5),
+        };
+        let packet = subpackets.get(offset..offset + len)?;
+        let (&kind, data) = packet.split_first()?;
+        out.push((kind & 0x7F, data));
+        subpackets = &subpackets[offset + len..];
+    }
+    Some(out)
+}
+
+/// Splits `raw` into its unfolded header fields and its body.
+pub(crate) fn split_headers(raw: &[u8]) -> (Vec<String>, &[u8]) {
+    let end = raw
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| (i, i + 4))
+        .or_else(|| {
+            raw.windows(2).position(|w| w == b"\n\n").map(|i| (i, i + 2))
+        })
+        .unwrap_or((raw.len(), raw.len()));
+    let mut fields: Vec<String> = Vec::new();
+    for line in String::from_utf8_lossy(&raw[..end.0]).lines() {
+        let line = line.trim_end_matches('\r');
+        match fields.last_mut() {
+            Some(field) if line.starts_with([' ', '\t']) => {
+                field.push_str("\r\n");
+                field.push_str(line);
+            }
+            _ => fields.push(line.to_owned()),
+        }
+    }
+    (fields, &raw[end.1..])
+}
+
+/// Returns the `(tag, body)` pairs of the OpenPGP packets in `data`.
+fn packets(mut data: &[u8]) -> Result<Vec<(u8, &[u8])>, Error> {
+    let mut packets = Vec::new();
+    while let Some(&header) = data.first() {
+        if header & 0x80 == 0 {
+            return Err("Invalid OpenPGP packet header".into());
+        }
+        let (tag, len, offset) = if header & 0x40 != 0 {
+            let tag = header & 0x3F;
+            match data.get(1).copied() {
+                Some(l @ 0..=191) => (tag, l as usize, 2),
+                Some(l @ 192..=223) => {
+                    let second =
+                        *data.get(2).ok_or("Truncated OpenPGP packet")?;
+                    (tag, ((l as usize - 192) << 8) + second as usize + 192, 3)
+                }
+                Some(255) => (tag, be_length(data.get(2..6))?, 6),
+                _ => return Err("Unsupported OpenPGP packet length".into()),
+            }
+        } else {
+            let tag = (header >> 2) & 0x0F;
+            match header & 0x03 {
+                0 => (tag, be_length(data.get(1..2))?, 2),
+                1 => (tag, be_length(data.get(1..3))?, 3),
+                2 => (tag, be_length(data.get(1..5))?, 5),
+                _ => return Err("Unsupported OpenPGP packet length".into()),
+            }
+        };
+        let body =
+            data.get(offset..offset + len).ok_or("Truncated OpenPGP packet")?;
+        packets.push((tag, body));
+        data = &data[offset + len..];
+    }
+    Ok(packets)
+}
+
+fn be_length(bytes: Option<&[u8]>) -> Result<usize, Error> {
+    let bytes = bytes.ok_or("Truncated OpenPGP packet")?;
+    Ok(bytes.iter().fold(0, |len, &b| (len << 8) | b as usize))
+}
+
+/// Parses a version 4 public key packet body, returning `None` for
+/// algorithms other than RSA.
+fn parse_rsa_key(body: &[u8]) -> Result<Option<PgpRecipient>, Error> {
+    if body.first() != Some(&4) {
+        return Err("Unsupported OpenPGP key version".into());
+    }
+    if !matches!(body.get(5), Some(&ALGO_RSA) | Some(&ALGO_RSA_ENCRYPT)) {
+        return Ok(None);
+    }
+    let (n, rest) = read_mpi(&body[6..])?;
+    let (e, _) = read_mpi(rest)?;
+    let key = RsaPublicKey::new(
+        BigUint::from_bytes_be(n),
+        BigUint::from_bytes_be(e),
+    )?;
+    Ok(Some(PgpRecipient { key_id: key_id(body), key }))
+}
+
+/// Returns the key id of a version 4 public key packet body, the low 64
+/// bits of its fingerprint.
+fn key_id(body: &[u8]) -> [u8; 8] {
+    let mut hasher = Sha1::new();
+    hasher.update([0x99]);
+    hasher.update((body.len() as u16).to_be_bytes());
+    hasher.update(body);
+    let fingerprint = hasher.finalize();
+    let mut key_id = [0u8; 8];
+    key_id.copy_from_slice(&fingerprint[12..]);
+    key_id
+}
+
+fn read_mpi(data: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let bits = be_length(data.get(..2))?;
+    let len = bits.div_ceil(8);
+    let value = data.get(2..2 + len).ok_or("Truncated OpenPGP key")?;
+    Ok((value, &data[2 + len..]))
+}
+
+/// Encodes `value` as an OpenPGP multiprecision integer.
+fn mpi(value: &[u8]) -> Vec<u8> {
+    let start = value.iter().position(|&b| b != 0).unwrap_or(value.len());
+    let value = &value[start..];
+    let bits = value
+        .first()
+        .map_or(0, |&b| value.len() * 8 - b.leading_zeros() as usize);
+    let mut out = (bits as u16).to_be_bytes().to_vec();
+    out.extend_from_slice(value);
+    out
+}
+
+/// Builds a new format packet with tag `tag`.
+fn packet(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![0xC0 | tag];
+    match body.len() {
+        len @ 0..=191 => out.push(len as u8),
+        len @ 192..=8383 => {
+            let len = len - 192;
+            out.extend_from_slice(&[(len >> 8) as u8 + 192, len as u8]);
+        }
+        len => {
+            out.push(255);
+            out.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+/// The CRC-24 checksum of ASCII armor.
+fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xB704CE;
+    for &byte in data {
+        crc ^= (byte as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= 0x1864CFB;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+/// ASCII armors the OpenPGP message `data`.
+fn armor(data: &[u8]) -> String {
+    let encoded = STANDARD.encode(data);
+    let mut out = String::from("-----BEGIN PGP MESSAGE-----\r\n\r\n");
+    for line in encoded.as_bytes().chunks(64) {
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push_str("\r\n");
+    }
+    let crc = crc24(data).to_be_bytes();
+    out.push_str(&format!("={}\r\n", STANDARD.encode(&crc[1..])));
+    out.push_str("-----END PGP MESSAGE-----\r\n");
+    out
+}
+
+/// Decodes an ASCII armored block, skipping armor headers and checksum.
+fn dearmor(armored: &str) -> Result<Vec<u8>, Error> {
+    let mut lines = armored
+        .lines()
+        .map(str::trim)
+        .skip_while(|l| !l.starts_with("-----BEGIN PGP"));
+    lines.next().ok_or("Missing PGP armor header")?;
+    let lines: Vec<&str> = lines
+        .skip_while(|l| l.contains(": "))
+        .take_while(|l| !l.starts_with("-----END PGP"))
+        .filter(|l| !l.is_empty() && !l.starts_with('='))
+        .collect();
+    Ok(STANDARD.decode(lines.concat())?)
+}
+
+/** Test module for PGP encryption */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::AsyncStreamCipher;
+    use rsa::pkcs8::DecodePrivateKey;
+    use rsa::traits::PublicKeyParts;
+    use rsa::RsaPrivateKey;
+
+    const PUBLIC_KEY: &str = include_str!("../tests/keys/pgp_test.asc");
+
+    /// Key generated with GnuPG, whose secret key is kept for decryption
+    /// by GnuPG in `tests/pgp.rs`.
+    const GNUPG_KEY: &str = include_str!("../tests/keys/pgp_gpg_public.asc");
+
+    /// Armors `key` as a v4 OpenPGP public key allowed to encrypt.
+    fn armored_public_key(key: &RsaPublicKey) -> String {
+        let mut data = key_packet(TAG_PUBLIC_KEY, key, 0);
+        data.extend(self_signature(
+            0x1F,
+            0,
+            &[(SUBPACKET_KEY_FLAGS, vec![0x0C])],
+        ));
+        armor(&data).replace("MESSAGE", "PUBLIC KEY BLOCK")
+    }
+
+    /// Builds a v4 key packet of `key` created at `created`.
+    fn key_packet(tag: u8, key: &RsaPublicKey, created: u32) -> Vec<u8> {
+        let mut body = vec![4];
+        body.extend_from_slice(&created.to_be_bytes());
+        body.push(ALGO_RSA);
+        body.extend(mpi(&key.n().to_bytes_be()));
+        body.extend(mpi(&key.e().to_bytes_be()));
+        packet(tag, &body)
+    }
+
+    /// Builds an unsigned v4 signature packet of `class` created at
+    /// `created`, with the hashed `subpackets`.
+    fn self_signature(
+        class: u8,
+        created: u32,
+        subpackets: &[(u8, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut hashed = vec![5, SUBPACKET_CREATED];
+        hashed.extend_from_slice(&created.to_be_bytes());
+        for (kind, data) in subpackets {
+            hashed.push(data.len() as u8 + 1);
+            hashed.push(*kind);
+            hashed.extend_from_slice(data);
+        }
+        let mut body = vec![4, class, ALGO_RSA, 8];
+        body.extend_from_slice(&(hashed.len() as u16).to_be_bytes());
+        body.extend(hashed);
+        body.extend_from_slice(&[0, 0, 0, 0]);
+        body.extend(mpi(&[1]));
+        packet(TAG_SIGNATURE, &body)
+    }
+
+    /// Decrypts the OpenPGP message `data` with `key`.
+    fn decrypt(key: &RsaPrivateKey, data: &[u8]) -> Vec<u8> {
+        let message = packets(data).unwrap();
+        let (TAG_PKESK, pkesk) = message[0] else { panic!("no PKESK") };
+        let (encrypted_key, _) = read_mpi(&pkesk[10..]).unwrap();
+        let session = key.decrypt(Pkcs1v15Encrypt, encrypted_key).unwrap();
+        assert_eq!(session[0], ALGO_AES256);
+        let (TAG_SEIPD, seipd) = message[1] else { panic!("no SEIPD") };
+        let mut plaintext = seipd[1..].to_vec();
+        cfb_mode::Decryptor::<Aes256>::new(
+            session[1..33].into(),
+            &[0; 16].into(),
+        )
+        .decrypt(&mut plaintext);
+        assert_eq!(plaintext[14..16], plaintext[16..18]);
+        let (protected, mdc) = plaintext.split_at(plaintext.len() - 20);
+        assert_eq!(Sha1::digest(protected).as_slice(), mdc);
+        let (tag, literal) =
+            packets(&protected[18..protected.len() - 2]).unwrap().remove(0);
+        assert_eq!(tag, TAG_LITERAL);
+        literal[6..].to_vec()
+    }
+
+    #[test]
+    fn test_from_armored() {
+        let recipient = PgpRecipient::from_armored(PUBLIC_KEY).unwrap();
+        assert_eq!(recipient.key_id(), "095C402950D97FDA");
+        assert!(PgpRecipient::from_armored("not a key").is_err());
+    }
+
+    #[test]
+    fn test_select_encryption_key() {
+        let key = RsaPrivateKey::from_pkcs8_pem(include_str!(
+            "../tests/keys/dkim_test.pem"
+        ))
+        .unwrap()
+        .to_public_key();
+        let flags = |flags: u8| (SUBPACKET_KEY_FLAGS, vec![flags]);
+        let expires =
+            |secs: u32| (SUBPACKET_KEY_EXPIRES, secs.to_be_bytes().to_vec());
+        let subkey = |created: u32, signatures: &[Vec<u8>]| {
+            let mut data = key_packet(TAG_PUBLIC_SUBKEY, &key, created);
+            data.extend(signatures.concat());
+            data
+        };
+        let primary = |key_flags: u8, revocation: &[Vec<u8>]| {
+            let mut data = key_packet(TAG_PUBLIC_KEY, &key, 1_000);
+            data.extend(self_signature(
+                0x13,
+                1_000,
+                &[flags(key_flags), expires(9_500)],
+            ));
+            data.extend(revocation.concat());
+            data
+        };
+        let select = |data: &[u8], now: u64| {
+            PgpRecipient::select(data, now).map(|r| r.key_id())
+        };
+
+        let valid =
+            subkey(2_000, &[self_signature(0x18, 2_000, &[flags(0x0C)])]);
+        let others = [
+            // Revoked, expired, signing only and unbound subkeys
+            subkey(
+                3_000,
+                &[
+                    self_signature(0x18, 3_000, &[flags(0x0C)]),
+                    self_signature(0x28, 4_000, &[]),
+                ],
+            ),
+            subkey(
+                4_000,
+                &[self_signature(0x18, 4_000, &[flags(0x0C), expires(100)])],
+            ),
+            subkey(5_000, &[self_signature(0x18, 5_000, &[flags(0x02)])]),
+            subkey(6_000, &[]),
+        ]
+        .concat();
+        let valid_id = hex::encode_upper(key_id_of(&valid));
+        assert_eq!(
+            select(
+                &[primary(0x03, &[]), valid.clone(), others.clone()].concat(),
+                10_000
+            )
+            .unwrap(),
+            valid_id
+        );
+        let unusable = [primary(0x03, &[]), others].concat();
+        assert!(select(&unusable, 10_000).is_err());
+        assert!(select(&valid, 10_000).is_err());
+        let revoked = primary(0x03, &[self_signature(0x20, 2_000, &[])]);
+        assert!(select(&[revoked, valid].concat(), 10_000).is_err());
+
+        // The primary key encrypts when allowed, until it expires
+        let alone = primary(0x0C, &[]);
+        let alone_id = hex::encode_upper(key_id_of(&alone));
+        assert_eq!(select(&alone, 10_000).unwrap(), alone_id);
+        assert!(select(&alone, 10_600).is_err());
+    }
+
+    /// Returns the key id of the first key packet of `data`.
+    fn key_id_of(data: &[u8]) -> [u8; 8] {
+        super::key_id(packets(data).unwrap()[0].1)
+    }
+
+    #[test]
+    fn test_gnupg_packet_layout() {
+        // Message GnuPG encrypted to its key, uncompressed with AES-256
+        let gnupg =
+            dearmor(include_str!("../tests/payload/pgp_gpg_encrypted.asc"))
+                .unwrap();
+        let recipient = PgpRecipient::from_armored(GNUPG_KEY).unwrap();
+        assert_eq!(recipient.key_id(), "A460AA0878470F93");
+        let ours = recipient.encrypt(b"secret body").unwrap();
+
+        // Same packets, session key encrypted to the same subkey
+        let layout = |data: &[u8]| -> Vec<(u8, Vec<u8>)> {
+            packets(data)
+                .unwrap()
+                .into_iter()
+                .map(|(tag, body)| match tag {
+                    TAG_PKESK => (tag, body[..10].to_vec()),
+                    _ => (tag, body[..1].to_vec()),
+                })
+                .collect()
+        };
+        assert_eq!(layout(&ours), layout(&gnupg));
+    }
+
+    #[test]
+    fn test_encrypt_message() {
+        let key = RsaPrivateKey::from_pkcs8_pem(include_str!(
+            "../tests/keys/dkim_test.pem"
+        ))
+        .unwrap();
+        let recipient = PgpRecipient::from_armored(&armored_public_key(
+            &key.to_public_key(),
+        ))
+        .unwrap();
+
+        let raw = b"From: test@nyah.dev\r\nSubject: Hi\r\n\
+                    MIME-Version: 1.0\r\n\
+                    Content-Type: text/plain;\r\n charset=utf-8\r\n\
+                    \r\nsecret body\r\n";
+        let encrypted = recipient.encrypt_message(raw).unwrap();
+        let mail = mailparse::parse_mail(&encrypted).unwrap();
+        assert_eq!(mail.ctype.mimetype, "multipart/encrypted");
+        assert_eq!(mail.headers[1].get_value(), "Hi");
+        assert_eq!(mail.subparts[0].get_body().unwrap().trim(), "Version: 1");
+
+        let armored = mail.subparts[1].get_body().unwrap();
+        assert_eq!(
+            decrypt(&key, &dearmor(&armored).unwrap()),
+            b"Content-Type: text/plain;\r\n charset=utf-8\r\n\r\n\
+              secret body\r\n"
+        );
+    }
+}
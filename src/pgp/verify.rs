@@ -0,0 +1,329 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Verification of PGP/MIME signed inbound mail.
+//!
+//! Only `multipart/signed` messages (RFC 3156) with version 4 RSA
+//! signatures over SHA-256, SHA-384 or SHA-512 are verified; anything
+//! else signed is reported as unknown.
+use super::{
+    dearmor, packets, parse_rsa_key, TAG_PUBLIC_KEY, TAG_PUBLIC_SUBKEY,
+    TAG_SIGNATURE,
+};
+use crate::config::PrivatEmailConfig;
+use lambda_runtime::Error;
+use mailparse::ParsedMail;
+use rsa::traits::PublicKeyParts;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::HashMap;
+
+/// Header carrying the signature state of forwarded mail.
+pub const VERIFIED_HEADER: &str = "X-PGP-Verified";
+
+const SUBPACKET_ISSUER: u8 = 16;
+const SUBPACKET_ISSUER_FINGERPRINT: u8 = 33;
+
+/// Signature state of a signed message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Verification {
+    /// Valid signature by a key of the keyring
+    Good(String),
+
+    /// Invalid signature claiming a key of the keyring
+    Bad(String),
+
+    /// Signature by a key outside the keyring, or one that can't be checked
+    Unknown(Option<String>),
+}
+
+impl Verification {
+    /// Returns the value of the [`VERIFIED_HEADER`], e.g. `good; key=...`.
+    pub fn header_value(&self) -> String {
+        match self {
+            Verification::Good(key) => format!("good; key={}", key),
+            Verification::Bad(key) => format!("bad; key={}", key),
+            Verification::Unknown(Some(key)) => format!("unknown; key={}", key),
+            Verification::Unknown(None) => "unknown".to_owned(),
+        }
+    }
+
+    /// Returns the banner shown above the forwarded body.
+    pub fn banner(&self) -> String {
+        match self {
+            Verification::Good(key) => {
+                format!("PGP signature: good, signed by key {}", key)
+            }
+            Verification::Bad(key) => format!(
+                "PGP signature: BAD, the message does not match the \
+                 signature of key {}",
+                key
+            ),
+            Verification::Unknown(Some(key)) => {
+                format!("PGP signature: unknown key {}", key)
+            }
+            Verification::Unknown(None) => {
+                "PGP signature: could not be checked".to_owned()
+            }
+        }
+    }
+}
+
+/// RSA public keys trusted for signatures, by key id.
+#[derive(Clone, Debug, Default)]
+pub struct Keyring {
+    keys: HashMap<[u8; 8], RsaPublicKey>,
+}
+
+impl Keyring {
+    /// Reads the RSA keys and subkeys of one or more ASCII armored public
+    /// key blocks.
+    pub fn from_armored(armored: &str) -> Result<Self, Error> {
+        let armored = armored.replace("\\n", "\n");
+        let mut keys = HashMap::new();
+        let marker = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+        for block in armored.split(marker).skip(1) {
+            let data = dearmor(&format!("{}{}", marker, block))?;
+            for (tag, body) in packets(&data)? {
+                if tag != TAG_PUBLIC_KEY && tag != TAG_PUBLIC_SUBKEY {
+                    continue;
+                }
+                if let Some(key) = parse_rsa_key(body)? {
+                    keys.insert(key.key_id, key.key);
+                }
+            }
+        }
+        if keys.is_empty() {
+            return Err("PGP keyring has no RSA keys".into());
+        }
+        Ok(Keyring { keys })
+    }
+
+    /// Returns the configured keyring, if any.
+    pub fn from_config(
+        email_config: &PrivatEmailConfig,
+    ) -> Result<Option<Self>, Error> {
+        email_config.pgp_keyring.as_deref().map(Self::from_armored).transpose()
+    }
+
+    /// Verifies the PGP/MIME signature of `mail`, parsed from `raw`.
+    /// Returns `None` when the message is not PGP/MIME signed.
+    pub fn verify_mime(
+        &self,
+        mail: &ParsedMail,
+        raw: &[u8],
+    ) -> Option<Verification> {
+        let protocol = mail.ctype.params.get("protocol")?;
+        if mail.ctype.mimetype != "multipart/signed"
+            || !protocol.eq_ignore_ascii_case("application/pgp-signature")
+        {
+            return None;
+        }
+        let signed = signed_part(raw, mail.ctype.params.get("boundary")?);
+        let signature = mail.subparts.get(1).and_then(|p| p.get_body().ok());
+        Some(match (signed, signature) {
+            (Some(signed), Some(signature)) => {
+                self.verify(&canonicalize(signed), &signature)
+            }
+            _ => Verification::Unknown(None),
+        })
+    }
+
+    /// Verifies the armored detached `signature` over `data`.
+    pub fn verify(&self, data: &[u8], signature: &str) -> Verification {
+        let Ok(packet) = dearmor(signature) else {
+            return Verification::Unknown(None);
+        };
+        let Some(signature) = packets(&packet)
+            .ok()
+            .and_then(|p| p.into_iter().find(|(tag, _)| *tag == TAG_SIGNATURE))
+            .and_then(|(_, body)| Signature::parse(body))
+        else {
+            return Verification::Unknown(None);
+        };
+        let key_id = signature.issuer.map(hex::encode_upper);
+        let Some(key) = signature.issuer.and_then(|id| self.keys.get(&id))
+        else {
+            return Verification::Unknown(key_id);
+        };
+        let key_id = key_id.unwrap_or_default();
+        match signature.verify(key, data) {
+            Some(true) => Verification::Good(key_id),
+            Some(false) => Verification::Bad(key_id),
+            None => Verification::Unknown(Some(key_id)),
+        }
+    }
+}
+
+/// The parts of a version 4 signature packet needed to verify it.
+struct Signature<'a> {
+    hash_algo: u8,
+    pubkey_algo: u8,
+    issuer: Option<[u8; 8]>,
+    hashed: &'a [u8],
+    value: &'a [u8],
+}
+
+impl<'a> Signature<'a> {
+    fn parse(body: &'a [u8]) -> Option<Self> {
+        if body.first() != Some(&4) {
+            return None;
+        }
+        let hashed_len = u16::from_be_bytes([*body.get(4)?, *body.get(5)?]);
+        let hashed_end = 6 + hashed_len as usize;
+        let unhashed_len = u16::from_be_bytes([
+            *body.get(hashed_end)?,
+            *body.get(hashed_end + 1)?,
+        ]);
+        let unhashed_start = hashed_end + 2;
+        let unhashed_end = unhashed_start + unhashed_len as usize;
+        let issuer = issuer(body.get(6..hashed_end)?)
+            .or_else(|| issuer(body.get(unhashed_start..unhashed_end)?));
+        // Two octets of the hash precede the signature MPI
+        let (value, _) = super::read_mpi(body.get(unhashed_end + 2..)?).ok()?;
+        Some(Signature {
+            hash_algo: body[3],
+            pubkey_algo: body[2],
+            issuer,
+            hashed: &body[..hashed_end],
+            value,
+        })
+    }
+
+    /// Checks the signature over `data` with `key`, returning `None` for
+    /// unsupported algorithms.
+    fn verify(&self, key: &RsaPublicKey, data: &[u8]) -> Option<bool> {
+        if self.pubkey_algo != super::ALGO_RSA {
+            return None;
+        }
+        let mut trailer = self.hashed.to_vec();
+        trailer.extend_from_slice(&[4, 0xFF]);
+        trailer.extend_from_slice(&(self.hashed.len() as u32).to_be_bytes());
+        let (scheme, digest) = match self.hash_algo {
+            8 => (
+                Pkcs1v15Sign::new::<Sha256>(),
+                digest::<Sha256>(data, &trailer),
+            ),
+            9 => (
+                Pkcs1v15Sign::new::<Sha384>(),
+                digest::<Sha384>(data, &trailer),
+            ),
+            10 => (
+                Pkcs1v15Sign::new::<Sha512>(),
+                digest::<Sha512>(data, &trailer),
+            ),
+            _ => return None,
+        };
+        // The MPI drops leading zeros the RSA check expects
+        let mut value = vec![0; key.size().saturating_sub(self.value.len())];
+        value.extend_from_slice(self.value);
+        Some(key.verify(scheme, &digest, &value).is_ok())
+    }
+}
+
+fn digest<D: Digest>(data: &[u8], trailer: &[u8]) -> Vec<u8> {
+    let mut hasher = D::new();
+    hasher.update(data);
+    hasher.update(trailer);
+    hasher.finalize().to_vec()
+}
+
+/// Returns the issuer key id in the signature `subpackets`.
+pub(super) fn issuer(subpackets: &[u8]) -> Option<[u8; 8]> {
+    super::subpackets(subpackets)?.into_iter().find_map(|(kind, data)| {
+        match kind {
+            SUBPACKET_ISSUER if data.len() == 8 => data.try_into().ok(),
+            SUBPACKET_ISSUER_FINGERPRINT if data.len() == 21 => {
+                data[13..].try_into().ok()
+            }
+            _ => None,
+        }
+    })
+}
+
+/// Returns the raw first part of the multipart body of `raw`, as signed:
+/// headers included, without the line break before the next boundary.
+pub(crate) fn signed_part<'a>(
+    raw: &'a [u8],
+    boundary: &str,
+) -> Option<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let find = |from: usize| {
+        raw[from..]
+            .windows(delimiter.len())
+            .position(|w| w == delimiter.as_bytes())
+            .map(|i| from + i)
+    };
+    let open = find(0)?;
+    let start = open
+        + delimiter.len()
+        + raw[open + delimiter.len()..].iter().position(|&b| b == b'\n')?
+        + 1;
+    let close = find(start)?;
+    let end =
+        if raw[..close].ends_with(b"\r\n") { close - 2 } else { close - 1 };
+    raw.get(start..end)
+}
+
+/// Converts bare line feeds to CRLF, as signatures are over canonical text.
+pub(crate) fn canonicalize(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &b) in data.iter().enumerate() {
+        if b == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(b);
+    }
+    out
+}
+
+/** Test module for PGP signature verification */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYRING: &str = include_str!("../../tests/keys/pgp_test.asc");
+    const SIGNED: &[u8] = include_bytes!("../../tests/payload/pgp_signed.eml");
+    const KEY_ID: &str = "6E4AC0B7C972D2CC";
+
+    #[test]
+    fn test_verify_mime() {
+        let keyring = Keyring::from_armored(KEYRING).unwrap();
+        let mail = mailparse::parse_mail(SIGNED).unwrap();
+        let verification = keyring.verify_mime(&mail, SIGNED).unwrap();
+        assert_eq!(verification, Verification::Good(KEY_ID.to_owned()));
+        assert_eq!(
+            verification.header_value(),
+            format!("good; key={}", KEY_ID)
+        );
+
+        let tampered = String::from_utf8_lossy(SIGNED)
+            .replace("hello from fufu", "hello from mallory");
+        let mail = mailparse::parse_mail(tampered.as_bytes()).unwrap();
+        assert_eq!(
+            keyring.verify_mime(&mail, tampered.as_bytes()),
+            Some(Verification::Bad(KEY_ID.to_owned()))
+        );
+
+        let unsigned = b"Content-Type: text/plain\r\n\r\nhello\r\n";
+        let mail = mailparse::parse_mail(unsigned).unwrap();
+        assert!(keyring.verify_mime(&mail, unsigned).is_none());
+    }
+
+    #[test]
+    fn test_unknown_key() {
+        let keyring = Keyring::default();
+        let mail = mailparse::parse_mail(SIGNED).unwrap();
+        assert_eq!(
+            keyring.verify_mime(&mail, SIGNED),
+            Some(Verification::Unknown(Some(KEY_ID.to_owned())))
+        );
+        assert!(Keyring::from_armored("no keys here").is_err());
+    }
+}
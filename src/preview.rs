@@ -0,0 +1,109 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! PNG previews of forwarded HTML bodies, attached to chat notifications.
+//!
+//! Rendering HTML needs a headless browser which does not fit in this
+//! Lambda, so it is delegated to a [`Renderer`]. [`LambdaRenderer`] invokes
+//! a separate rendering function which receives `{"html", "width"}` and
+//! answers `{"png": "<base64>"}`.
+use crate::aws;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+
+/// Width in pixels of rendered previews.
+pub const PREVIEW_WIDTH: u32 = 600;
+
+/// Key prefix for stored previews.
+pub const PREVIEW_PREFIX: &str = "previews/";
+
+/// Renders an HTML document to a PNG image.
+#[async_trait]
+pub trait Renderer: Send + Sync {
+    /// Returns the PNG encoded rendering of `html`.
+    async fn render(&self, html: &str) -> Result<Vec<u8>, Error>;
+}
+
+#[derive(Debug, Serialize)]
+struct RenderRequest<'a> {
+    html: &'a str,
+    width: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenderResponse {
+    png: String,
+}
+
+/// [`Renderer`] invoking a headless-rendering Lambda function.
+#[derive(Clone, Debug)]
+pub struct LambdaRenderer {
+    function_name: String,
+    region: Region,
+}
+
+impl LambdaRenderer {
+    /// Creates a renderer invoking `function_name` in `region`.
+    pub fn new<F: ToString>(function_name: F, region: Region) -> Self {
+        LambdaRenderer { function_name: function_name.to_string(), region }
+    }
+}
+
+#[async_trait]
+impl Renderer for LambdaRenderer {
+    async fn render(&self, html: &str) -> Result<Vec<u8>, Error> {
+        let mut request = SignedRequest::new(
+            "POST",
+            "lambda",
+            &self.region,
+            &format!(
+                "/2015-03-31/functions/{}/invocations",
+                self.function_name
+            ),
+        );
+        request.set_content_type("application/json".to_owned());
+        request.set_payload(Some(serde_json::to_vec(&RenderRequest {
+            html,
+            width: PREVIEW_WIDTH,
+        })?));
+        let response = aws::dispatch(request).await?;
+        decode_png(&response.body)
+    }
+}
+
+/// Decodes the renderer response and checks it holds a PNG image.
+fn decode_png(body: &[u8]) -> Result<Vec<u8>, Error> {
+    let response: RenderResponse = serde_json::from_slice(body)?;
+    let png = STANDARD.decode(response.png)?;
+    if !png.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Err("Renderer did not return a PNG image".into());
+    }
+    Ok(png)
+}
+
+/** Test module for preview rendering */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_png() {
+        let png = b"\x89PNG\r\n\x1a\nrest".to_vec();
+        let body = format!("{{\"png\": \"{}\"}}", STANDARD.encode(&png));
+        assert_eq!(decode_png(body.as_bytes()).unwrap(), png);
+
+        let body = format!("{{\"png\": \"{}\"}}", STANDARD.encode(b"GIF89a"));
+        assert!(decode_png(body.as_bytes()).is_err());
+        assert!(decode_png(b"{}").is_err());
+    }
+}
@@ -0,0 +1,444 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Quiet hours of the destination inbox.
+//!
+//! During quiet hours notifications are not sent. Forwards either go out
+//! immediately or, when deferring is enabled, are stored under
+//! [`DEFERRED_PREFIX`] and sent by a scheduled invocation once the window
+//! has ended, to the destinations recorded in their [`Release`]. Rules may
+//! defer forwards the same way until a weekly [`Schedule`], such as office
+//! hours, opens.
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix of deferred raw messages, keyed by release time and message id.
+pub const DEFERRED_PREFIX: &str = "deferred/";
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// A daily window, e.g. `22:00-07:00`, which may span midnight.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuietHours {
+    /// Start of the window, in minutes after midnight
+    start: u32,
+
+    /// End of the window, in minutes after midnight
+    end: u32,
+}
+
+impl QuietHours {
+    /// Whether `minute`, in minutes after midnight, is within the window.
+    pub fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Returns the time the window ends if `now` is within it, given the
+    /// UTC offset of the destination in minutes.
+    pub fn until(&self, now: i64, offset_minutes: i32) -> Option<i64> {
+        let local = now + offset_minutes as i64 * 60;
+        let minute = (local.rem_euclid(86_400) / 60) as u32;
+        if !self.contains(minute) {
+            return None;
+        }
+        let remaining = (self.end + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+        Some(now - now.rem_euclid(60) + remaining as i64 * 60)
+    }
+}
+
+impl std::str::FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quiet hours: {}", s);
+        let (start, end) = s.trim().split_once('-').ok_or_else(invalid)?;
+        let minutes = |t: &str| {
+            let (h, m) = t.trim().split_once(':')?;
+            let (h, m): (u32, u32) = (h.parse().ok()?, m.parse().ok()?);
+            (h < 24 && m < 60).then_some(h * 60 + m)
+        };
+        match (minutes(start), minutes(end)) {
+            (Some(start), Some(end)) if start != end => {
+                Ok(QuietHours { start, end })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl std::fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<QuietHours> for String {
+    fn from(hours: QuietHours) -> Self {
+        hours.to_string()
+    }
+}
+
+/// Days of the week, as written in schedules.
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// A daily window on some days of the week, e.g. `Mon-Fri 09:00-17:00`.
+/// A window spanning midnight belongs to the day it starts on.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Schedule {
+    /// Days of the window, bit 0 being Monday
+    days: u8,
+
+    /// Hours of the window on these days
+    hours: QuietHours,
+}
+
+impl Schedule {
+    /// Whether the time `now` is within the window, given the UTC offset
+    /// of the schedule in minutes.
+    pub fn contains(&self, now: i64, offset_minutes: i32) -> bool {
+        let local = now + offset_minutes as i64 * 60;
+        let weekday = weekday(local.div_euclid(86_400));
+        let minute = (local.rem_euclid(86_400) / 60) as u32;
+        let on = |day: usize| self.days & (1 << day) != 0;
+        if self.hours.start <= self.hours.end {
+            on(weekday) && self.hours.contains(minute)
+        } else {
+            (on(weekday) && minute >= self.hours.start)
+                || (on((weekday + 6) % 7) && minute < self.hours.end)
+        }
+    }
+
+    /// Returns the time the window next opens if `now` is outside of it,
+    /// given the UTC offset of the schedule in minutes.
+    pub fn opens(&self, now: i64, offset_minutes: i32) -> Option<i64> {
+        if self.contains(now, offset_minutes) {
+            return None;
+        }
+        let offset = offset_minutes as i64 * 60;
+        let today = (now + offset).div_euclid(86_400);
+        (today..today + 8)
+            .filter(|&day| self.days & (1 << weekday(day)) != 0)
+            .map(|day| day * 86_400 + self.hours.start as i64 * 60 - offset)
+            .find(|&start| start > now)
+    }
+}
+
+impl std::str::FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid schedule: {}", s);
+        let (days, hours) = match s.trim().split_once(' ') {
+            Some((days, hours)) => (days, hours),
+            None => ("mon-sun", s),
+        };
+        let day = |name: &str| {
+            let name = name.trim().to_lowercase();
+            DAYS.iter().position(|day| *day == name)
+        };
+        let mut mask = 0;
+        for range in days.split(',') {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let (first, last) =
+                day(first).zip(day(last)).ok_or_else(invalid)?;
+            let mut day = first;
+            loop {
+                mask |= 1 << day;
+                if day == last {
+                    break;
+                }
+                day = (day + 1) % 7;
+            }
+        }
+        let hours = hours.parse().map_err(|_| invalid())?;
+        Ok(Schedule { days: mask, hours })
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ranges = Vec::new();
+        let mut day = 0;
+        while day < 7 {
+            if self.days & (1 << day) == 0 {
+                day += 1;
+                continue;
+            }
+            let first = day;
+            while day < 6 && self.days & (1 << (day + 1)) != 0 {
+                day += 1;
+            }
+            let name = |day: usize| {
+                let mut name = DAYS[day].to_owned();
+                name[..1].make_ascii_uppercase();
+                name
+            };
+            ranges.push(match day - first {
+                0 => name(first),
+                _ => format!("{}-{}", name(first), name(day)),
+            });
+            day += 1;
+        }
+        write!(f, "{} {}", ranges.join(","), self.hours)
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Schedule> for String {
+    fn from(schedule: Schedule) -> Self {
+        schedule.to_string()
+    }
+}
+
+/// Returns the day of the week of `day` days after the Unix epoch, 0
+/// being Monday.
+fn weekday(day: i64) -> usize {
+    (day + 3).rem_euclid(7) as usize
+}
+
+/// Parses a `route=window` pair, e.g. `news@acme.com=22:00-07:00`.
+pub fn parse_route(s: &str) -> Result<(String, QuietHours), String> {
+    match s.split_once('=') {
+        Some((route, hours)) if !route.trim().is_empty() => {
+            Ok((route.trim().to_lowercase(), hours.parse()?))
+        }
+        _ => Err(format!("Invalid quiet hours: {}", s)),
+    }
+}
+
+/// Parses a UTC offset such as `+02:00`, `-0530` or `-5`, in minutes.
+pub fn parse_offset(s: &str) -> Result<i32, String> {
+    let invalid = || format!("Invalid UTC offset: {}", s);
+    let s = s.trim();
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(digits) => (-1, digits),
+        None => (1, s.trim_start_matches('+')),
+    };
+    let digits = digits.replace(':', "");
+    let (h, m) = match digits.len() {
+        1 | 2 => (digits.as_str(), "0"),
+        4 => digits.split_at(2),
+        _ => return Err(invalid()),
+    };
+    match (h.parse::<i32>(), m.parse::<i32>()) {
+        (Ok(h), Ok(m)) if h <= 14 && m < 60 => Ok(sign * (h * 60 + m)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Returns the current time in seconds since the Unix epoch.
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Returns the ISO 8601 UTC date of `secs` since the Unix epoch, in the
+/// format of SES timestamps.
+pub fn timestamp(secs: i64) -> String {
+    // Days to civil dates, after Howard Hinnant's `civil_from_days`
+    let days = secs.div_euclid(86_400) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1_460 + day_of_era / 36_524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let second = secs.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.000Z",
+        year,
+        month,
+        day,
+        second / 3_600,
+        second % 3_600 / 60,
+        second % 60
+    )
+}
+
+/// Returns the seconds since the Unix epoch of the ISO 8601 UTC date `s`,
+/// a `YYYY-MM-DD` day optionally followed by a `THH:MM:SS` time.
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let field = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = s.get(range)?;
+        digits.bytes().all(|b| b.is_ascii_digit()).then_some(())?;
+        digits.parse().ok()
+    };
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let second = match s.len() {
+        10 => 0,
+        _ => field(11..13)? * 3_600 + field(14..16)? * 60 + field(17..19)?,
+    };
+    // Civil dates to days, after Howard Hinnant's `days_from_civil`
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let mp = (month + 9) % 12;
+    let day_of_year = (153 * mp + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Some((era * 146_097 + day_of_era - 719_468) * 86_400 + second)
+}
+
+/// Returns the key of a message deferred until `release_at`.
+pub fn deferred_key(release_at: i64, message_id: &str) -> String {
+    format!("{}{}/{}.eml", DEFERRED_PREFIX, release_at, message_id)
+}
+
+/// Sending of a deferred forward, as resolved for the message when it was
+/// held, since routing may have sent it elsewhere than `to_email`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Release {
+    /// Address the forward is sent from
+    pub from_email: String,
+
+    /// Addresses the forward is sent to
+    pub destinations: Vec<String>,
+
+    /// SES configuration set of the send
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub configuration_set: Option<String>,
+
+    /// SES message tags of the send, as name and value
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<(String, String)>,
+}
+
+/// Returns the key of the [`Release`] of the deferred message at `key`.
+pub fn release_key(key: &str) -> String {
+    format!("{}.json", key.strip_suffix(".eml").unwrap_or(key))
+}
+
+/// Returns the release time of the deferred message stored at `key`.
+pub fn release_at(key: &str) -> Option<i64> {
+    key.strip_prefix(DEFERRED_PREFIX)?.split('/').next()?.parse().ok()
+}
+
+/** Test module for quiet hours */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours() {
+        let night: QuietHours = "22:00-07:30".parse().unwrap();
+        assert_eq!(night.to_string(), "22:00-07:30");
+        assert!(night.contains(23 * 60) && night.contains(7 * 60));
+        assert!(!night.contains(12 * 60));
+        assert!("22:00-22:00".parse::<QuietHours>().is_err());
+        assert!("25:00-07:00".parse::<QuietHours>().is_err());
+
+        // 2021-03-19 21:30 UTC is 23:30 at UTC+02:00
+        let now = 1616189400;
+        assert_eq!(night.until(now, 120), Some(now + 8 * 3600));
+        assert_eq!(night.until(now, 0), None);
+    }
+
+    #[test]
+    fn test_schedule() {
+        let office: Schedule = "mon-FRI 09:00-17:00".parse().unwrap();
+        assert_eq!(office.to_string(), "Mon-Fri 09:00-17:00");
+        assert_eq!(
+            "Sat,Sun,Mon 22:00-02:00".parse::<Schedule>().unwrap().to_string(),
+            "Mon,Sat-Sun 22:00-02:00"
+        );
+        assert_eq!(
+            "08:00-12:00".parse::<Schedule>().unwrap().to_string(),
+            "Mon-Sun 08:00-12:00"
+        );
+        assert!("Mon-Fry 09:00-17:00".parse::<Schedule>().is_err());
+        assert!("Mon-Fri 09:00".parse::<Schedule>().is_err());
+
+        // 2021-03-19 was a Friday; 21:30 UTC is 23:30 at UTC+02:00
+        let friday = 1616189400;
+        assert!(!office.contains(friday, 120));
+        assert!(office.contains(friday - 8 * 3600, 120));
+        assert_eq!(office.opens(friday - 8 * 3600, 120), None);
+        // Next opening is Monday 09:00 at UTC+02:00, i.e. 07:00 UTC
+        assert_eq!(office.opens(friday, 120), Some(1616396400));
+
+        let late: Schedule = "Fri 22:00-02:00".parse().unwrap();
+        assert!(late.contains(friday + 3 * 3600, 0));
+        assert!(!late.contains(friday + 6 * 3600, 0));
+        assert_eq!(late.opens(friday, 0), Some(friday + 1800));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse_route("News@Acme.com = 22:00-07:00").unwrap().0,
+            "news@acme.com"
+        );
+        assert!(parse_route("=22:00-07:00").is_err());
+        assert_eq!(parse_offset("+02:00"), Ok(120));
+        assert_eq!(parse_offset("-0530"), Ok(-330));
+        assert_eq!(parse_offset("-5"), Ok(-300));
+        assert!(parse_offset("+2:0").is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(timestamp(1647679576), "2022-03-19T08:46:16.000Z");
+        assert_eq!(timestamp(951_825_600), "2000-02-29T12:00:00.000Z");
+        for secs in [0, 1647679576, 951_825_600] {
+            assert_eq!(parse_timestamp(&timestamp(secs)), Some(secs));
+        }
+        assert_eq!(parse_timestamp("2022-03-19"), Some(1647648000));
+        assert_eq!(parse_timestamp("2022-13-19"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_deferred_key() {
+        let key = deferred_key(1616189400, "abc");
+        assert_eq!(key, "deferred/1616189400/abc.eml");
+        assert_eq!(release_at(&key), Some(1616189400));
+        assert_eq!(release_at("archive/abc.eml"), None);
+        assert_eq!(release_key(&key), "deferred/1616189400/abc.json");
+    }
+}
@@ -0,0 +1,133 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Awareness of the SES sending quota.
+//!
+//! With `quota_defer_percent` set, the 24-hour sending quota of the
+//! account and whether sending is enabled are read from the
+//! [`EmailSender`], at most once per [`CACHE_SECS`] in a warm container.
+//! Once that share of the quota is used, or while sending is paused,
+//! forwards are held in the store like those of quiet hours, for
+//! `quota_defer_secs`, instead of failing one after another; the scheduled
+//! release keeps holding them until the quota has room again.
+use crate::sender::EmailSender;
+use std::sync::Mutex;
+use tracing::error;
+
+/// Seconds a quota read from the mail service is used for.
+pub const CACHE_SECS: i64 = 60;
+
+/// Counter and metric name of forwards deferred near the quota.
+pub const DEFERRED_COUNTER: &str = "quota_deferred";
+
+/// Sending quota of the account, as told by the mail service.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SendQuota {
+    /// Messages which may be sent in 24 hours, negative when unlimited
+    pub max_24_hour_send: f64,
+
+    /// Messages sent in the last 24 hours
+    pub sent_last_24_hours: f64,
+
+    /// Whether sending is enabled for the account
+    pub sending_enabled: bool,
+}
+
+impl SendQuota {
+    /// Whether sends should wait: sending is paused, or at least `percent`
+    /// of a limited quota is used.
+    pub fn exhausted(&self, percent: u32) -> bool {
+        !self.sending_enabled
+            || (self.max_24_hour_send >= 0.0
+                && self.sent_last_24_hours
+                    >= self.max_24_hour_send * f64::from(percent) / 100.0)
+    }
+}
+
+/// Last quota read and when, kept by the service of a warm container.
+#[derive(Debug, Default)]
+pub struct Cache(Mutex<Option<(SendQuota, i64)>>);
+
+impl Cache {
+    /// Returns the quota of `email_sender` at `now`, read again once the
+    /// cached one is [`CACHE_SECS`] old, or None when the sender has none
+    /// or it could not be read.
+    pub async fn get(
+        &self,
+        email_sender: &dyn EmailSender,
+        now: i64,
+    ) -> Option<SendQuota> {
+        if let Some((quota, read_at)) = *self.0.lock().unwrap() {
+            if now - read_at < CACHE_SECS {
+                return Some(quota);
+            }
+        }
+        match email_sender.send_quota().await {
+            Ok(quota) => {
+                *self.0.lock().unwrap() = quota.map(|quota| (quota, now));
+                quota
+            }
+            Err(e) => {
+                error!("Error reading the sending quota: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Whether sends through `email_sender` should wait at `now`, once
+    /// `percent` of its quota is used.
+    pub async fn exhausted(
+        &self,
+        email_sender: &dyn EmailSender,
+        percent: u32,
+        now: i64,
+    ) -> bool {
+        self.get(email_sender, now)
+            .await
+            .is_some_and(|quota| quota.exhausted(percent))
+    }
+}
+
+/** Test module for the sending quota */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::MockSender;
+
+    fn quota(sent: f64) -> SendQuota {
+        SendQuota {
+            max_24_hour_send: 200.0,
+            sent_last_24_hours: sent,
+            sending_enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_exhausted() {
+        assert!(!quota(179.0).exhausted(90));
+        assert!(quota(180.0).exhausted(90));
+        assert!(quota(200.0).exhausted(100));
+        let paused = SendQuota { sending_enabled: false, ..quota(0.0) };
+        assert!(paused.exhausted(90));
+        let unlimited = SendQuota { max_24_hour_send: -1.0, ..quota(1e6) };
+        assert!(!unlimited.exhausted(90));
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache = Cache::default();
+        assert_eq!(cache.get(&MockSender::new(), 1_000).await, None);
+
+        let sender = MockSender::new().with_quota(quota(190.0));
+        assert!(cache.exhausted(&sender, 90, 1_000).await);
+        let sender = MockSender::new().with_quota(quota(10.0));
+        assert!(cache.exhausted(&sender, 90, 1_000 + CACHE_SECS - 1).await);
+        assert!(!cache.exhausted(&sender, 90, 1_000 + CACHE_SECS).await);
+    }
+}
@@ -0,0 +1,308 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Analysis of the `Received` header chain.
+//!
+//! Every relay prepends a `Received` header, so the chain is read bottom
+//! up from the originating host to SES. Forged chains tend to show hops
+//! going back in time, private addresses between public relays or an
+//! unusual number of relays. Counting the hops also bounds the depth of
+//! forwarding chains, see [`check_hops`].
+use mailparse::{MailHeader, MailHeaderMap};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Chains longer than this are flagged.
+pub const MAX_HOPS: usize = 15;
+
+/// Clock skew, in seconds, tolerated between consecutive hops.
+pub const MAX_SKEW_SECS: i64 = 15 * 60;
+
+/// A relay of the `Received` chain.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Hop {
+    /// Host the message was received from, as announced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+
+    /// Host that received the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+
+    /// Address of the sending host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+
+    /// When the hop received the message, in seconds since the epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<i64>,
+}
+
+impl Hop {
+    /// Parses the value of a `Received` header.
+    pub fn parse(value: &str) -> Self {
+        let (route, date) = match value.rsplit_once(';') {
+            Some((route, date)) => (route, Some(date)),
+            None => (value, None),
+        };
+        let words: Vec<&str> = route.split_whitespace().collect();
+        let after = |keyword: &str| {
+            words
+                .iter()
+                .position(|w| w.eq_ignore_ascii_case(keyword))
+                .and_then(|i| words.get(i + 1))
+                .map(|w| w.trim_matches(|c| c == '(' || c == ')').to_owned())
+        };
+        Hop {
+            from: after("from"),
+            by: after("by"),
+            ip: route
+                .split('[')
+                .skip(1)
+                .filter_map(|s| s.split(']').next())
+                .find_map(|s| s.trim_start_matches("IPv6:").parse().ok()),
+            timestamp: date.and_then(|d| mailparse::dateparse(d.trim()).ok()),
+        }
+    }
+}
+
+/// Anomaly found in a `Received` chain.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Anomaly {
+    /// A hop received the message before the hop that sent it to it
+    ImpossibleHop { hop: usize, behind_secs: i64 },
+
+    /// A private or loopback address appears after a public relay
+    PrivateIp { hop: usize, ip: IpAddr },
+
+    /// The chain has more than [`MAX_HOPS`] relays
+    ExcessiveRelays(usize),
+}
+
+impl std::fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anomaly::ImpossibleHop { hop, behind_secs } => write!(
+                f,
+                "hop {} is {}s earlier than the previous hop",
+                hop, behind_secs
+            ),
+            Anomaly::PrivateIp { hop, ip } => {
+                write!(f, "hop {} is from private address {}", hop, ip)
+            }
+            Anomaly::ExcessiveRelays(count) => {
+                write!(f, "{} relays exceed the maximum of {}", count, MAX_HOPS)
+            }
+        }
+    }
+}
+
+/// Counter of messages refused for exceeding the hop limit.
+pub const HOP_LIMIT_COUNTER: &str = "hop_limit_exceeded";
+
+/// A message relayed more often than the configured limit allows.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HopLimitExceeded {
+    /// Header the hops were counted with
+    pub header: String,
+
+    /// Hops of the message
+    pub hops: usize,
+
+    /// Most hops forwarded
+    pub limit: usize,
+}
+
+impl std::fmt::Display for HopLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} headers exceed the hop limit of {}",
+            self.hops, self.header, self.limit
+        )
+    }
+}
+
+impl std::error::Error for HopLimitExceeded {}
+
+/// Counts the `header` fields of `headers`, `Received` by default, failing
+/// when there are more than `limit`.
+pub fn check_hops(
+    headers: &[MailHeader],
+    header: Option<&str>,
+    limit: usize,
+) -> Result<usize, HopLimitExceeded> {
+    let header = header.unwrap_or("Received");
+    let hops = headers.get_all_headers(header).len();
+    if hops > limit {
+        return Err(HopLimitExceeded {
+            header: header.to_owned(),
+            hops,
+            limit,
+        });
+    }
+    Ok(hops)
+}
+
+/// Returns the `Received` chain in `headers`, originating hop first.
+pub fn chain(headers: &[MailHeader]) -> Vec<Hop> {
+    let mut hops: Vec<Hop> = headers
+        .get_all_values("Received")
+        .iter()
+        .map(|value| Hop::parse(value))
+        .collect();
+    hops.reverse();
+    hops
+}
+
+/// Returns the anomalies of `chain`, originating hop first. Hops are
+/// numbered from 1 at the originating host.
+pub fn analyze(chain: &[Hop]) -> Vec<Anomaly> {
+    let mut anomalies = Vec::new();
+    if chain.len() > MAX_HOPS {
+        anomalies.push(Anomaly::ExcessiveRelays(chain.len()));
+    }
+    let mut seen_public = false;
+    let mut latest = None;
+    for (i, hop) in chain.iter().enumerate() {
+        if let Some(ip) = hop.ip {
+            if is_private(&ip) {
+                if seen_public {
+                    anomalies.push(Anomaly::PrivateIp { hop: i + 1, ip });
+                }
+            } else {
+                seen_public = true;
+            }
+        }
+        if let Some(timestamp) = hop.timestamp {
+            match latest {
+                Some(latest) if latest - timestamp > MAX_SKEW_SECS => {
+                    anomalies.push(Anomaly::ImpossibleHop {
+                        hop: i + 1,
+                        behind_secs: latest - timestamp,
+                    });
+                }
+                _ => latest = latest.max(Some(timestamp)),
+            }
+        }
+    }
+    anomalies
+}
+
+/// Returns the last hop of `chain` from a public address, the relay which
+/// handed the message to SES.
+pub fn relay(chain: &[Hop]) -> Option<&Hop> {
+    chain.iter().rev().find(|hop| hop.ip.is_some_and(|ip| !is_private(&ip)))
+}
+
+/// Whether `ip` belongs to a private, loopback or link-local range.
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // 100.64.0.0/10, carrier-grade NAT
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xC0 == 64)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xFE00 == 0xFC00
+                || first & 0xFFC0 == 0xFE80
+        }
+    }
+}
+
+/** Test module for Received chain analysis */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(from: &str, ip: &str, time: &str) -> String {
+        format!(
+            "Received: from {f} ({f} [{ip}])\r\n \
+             by mx.test with SMTP id x;\r\n \
+             Fri, 19 Mar 2021 {t} +0000\r\n",
+            f = from,
+            ip = ip,
+            t = time
+        )
+    }
+
+    #[test]
+    fn test_hop_parse() {
+        let hop = Hop::parse(
+            "from mail-wm1-f51.google.com (mail-wm1-f51.google.com \
+             [209.85.128.51]) by inbound-smtp.us-east-1.amazonaws.com with \
+             SMTP id abc; Fri, 19 Mar 2021 08:46:16 +0000 (UTC)",
+        );
+        assert_eq!(hop.from.as_deref(), Some("mail-wm1-f51.google.com"));
+        assert_eq!(
+            hop.by.as_deref(),
+            Some("inbound-smtp.us-east-1.amazonaws.com")
+        );
+        assert_eq!(hop.ip, Some("209.85.128.51".parse().unwrap()));
+        assert_eq!(hop.timestamp, Some(1616143576));
+
+        let hop = Hop::parse("by 2002:a05:600c::1 with SMTP id f8 [IPv6:::1]");
+        assert_eq!(hop.ip, Some("::1".parse().unwrap()));
+        assert!(hop.timestamp.is_none());
+    }
+
+    #[test]
+    fn test_analyze() {
+        // Topmost header first, as relays prepend them
+        let raw = [
+            received("relay.test", "10.0.0.7", "08:40:00"),
+            received("mx.achu.soup", "203.0.113.9", "09:30:00"),
+            received("laptop", "192.168.1.2", "09:29:00"),
+            "\r\n".to_owned(),
+        ]
+        .concat();
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        let chain = chain(&headers);
+        assert_eq!(chain[0].from.as_deref(), Some("laptop"));
+        assert_eq!(
+            analyze(&chain),
+            [
+                Anomaly::PrivateIp { hop: 3, ip: "10.0.0.7".parse().unwrap() },
+                Anomaly::ImpossibleHop { hop: 3, behind_secs: 3000 },
+            ]
+        );
+        assert!(analyze(&chain[..2]).is_empty());
+        let relay = relay(&chain).and_then(|hop| hop.from.as_deref());
+        assert_eq!(relay, Some("mx.achu.soup"));
+        assert_eq!(super::relay(&chain[..1]), None);
+
+        let long = vec![Hop::default(); MAX_HOPS + 1];
+        assert_eq!(analyze(&long), [Anomaly::ExcessiveRelays(MAX_HOPS + 1)]);
+    }
+
+    #[test]
+    fn test_check_hops() {
+        let raw = [
+            received("relay.test", "10.0.0.7", "08:40:00"),
+            received("mx.achu.soup", "203.0.113.9", "09:30:00"),
+            "X-Hop: 1\r\n\r\n".to_owned(),
+        ]
+        .concat();
+        let (headers, _) = mailparse::parse_headers(raw.as_bytes()).unwrap();
+        assert_eq!(check_hops(&headers, None, 2), Ok(2));
+        assert_eq!(check_hops(&headers, Some("x-hop"), 1), Ok(1));
+        let error = check_hops(&headers, None, 1).unwrap_err();
+        assert_eq!(error.hops, 2);
+        assert_eq!(
+            error.to_string(),
+            "2 Received headers exceed the hop limit of 1"
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Two-way relay of replies back to the original sender.
+//!
+//! Forwards carry a `Reply-To` of the form `relay+<sender>.<sig>@domain`,
+//! where `<sender>` is the base32 encoded original sender and `<sig>` an
+//! HMAC over it, so that only addresses handed out by this forwarder are
+//! relayed. Replies arriving at such an address from the destination inbox,
+//! which must pass SPF or carry a passing DKIM signature of its domain, are
+//! rebuilt without forwarding artifacts and sent to the original sender
+//! from the forwarding address.
+//!
+//! Local parts are limited to 64 octets (RFC 5321 4.5.3.1.1), so senders
+//! too long to encode get `relay+<key>@domain` instead: `<key>` is an HMAC
+//! of the sender, which is stored under [`SENDER_PREFIX`] in the store.
+use crate::config::PrivatEmailConfig;
+use crate::encoding::{base32_decode, base32_encode};
+use crate::http::constant_time_eq;
+use crate::limits::OVERSIZE_SUBJECT_PREFIX;
+use crate::mime::{Attachment, MimeMessage};
+use crate::store::ObjectStore;
+use hmac::{Hmac, Mac};
+use lambda_runtime::Error;
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use sha2::Sha256;
+
+/// Length of the address signature, in base32 characters.
+const SIGNATURE_LEN: usize = 8;
+
+/// Length of the key of stored senders, in base32 characters.
+const KEY_LEN: usize = 26;
+
+/// Longest local part of an address, RFC 5321 4.5.3.1.1.
+pub const MAX_LOCAL_LEN: usize = 64;
+
+/// Key prefix of the senders of keyed reply addresses in the store.
+pub const SENDER_PREFIX: &str = "relay/";
+
+/// Relay alias and the secret signing its reply addresses.
+#[derive(Clone, Debug)]
+pub struct Relay {
+    local: String,
+    domain: String,
+    secret: Vec<u8>,
+}
+
+impl Relay {
+    /// Creates a relay for `alias`, signing addresses with `secret`.
+    pub fn new(alias: &str, secret: &[u8]) -> Option<Self> {
+        let (local, domain) = alias.trim().rsplit_once('@')?;
+        Some(Relay {
+            local: local.to_lowercase(),
+            domain: domain.to_lowercase(),
+            secret: secret.to_vec(),
+        })
+    }
+
+    /// Returns the relay configured in `config`, if both the alias and
+    /// the secret are set.
+    pub fn from_config(config: &PrivatEmailConfig) -> Option<Self> {
+        let alias = config.relay_alias.as_ref()?;
+        let secret = config.relay_secret.as_ref()?;
+        Relay::new(alias, secret.as_bytes())
+    }
+
+    /// Returns the reply address encoding `sender`, or `None` when its
+    /// local part would be longer than [`MAX_LOCAL_LEN`].
+    pub fn encode(&self, sender: &str) -> Option<String> {
+        let local = format!(
+            "{}+{}.{}",
+            self.local,
+            base32_encode(sender.as_bytes()),
+            self.signature(sender)
+        );
+        (local.len() <= MAX_LOCAL_LEN)
+            .then(|| format!("{}@{}", local, self.domain))
+    }
+
+    /// Returns the reply address of `sender`, storing it in `objects`
+    /// under a key when it is too long to encode. Without a store, such
+    /// senders get no reply address.
+    pub async fn reply_address(
+        &self,
+        sender: &str,
+        objects: Option<&dyn ObjectStore>,
+    ) -> Result<Option<String>, Error> {
+        if let Some(address) = self.encode(sender) {
+            return Ok(Some(address));
+        }
+        let Some(objects) = objects else {
+            return Ok(None);
+        };
+        let key = self.key(sender);
+        let object_key = format!("{}{}", SENDER_PREFIX, key);
+        objects.put(&object_key, sender.as_bytes().to_vec().into()).await?;
+        Ok(Some(format!("{}+{}@{}", self.local, key, self.domain)))
+    }
+
+    /// Returns the original sender encoded in `address`, or `None` when it
+    /// is not a reply address of this relay or its signature is invalid.
+    /// Keyed addresses are read from `objects`.
+    pub async fn decode(
+        &self,
+        address: &str,
+        objects: Option<&dyn ObjectStore>,
+    ) -> Result<Option<String>, Error> {
+        let Some(token) = self.token(address) else {
+            return Ok(None);
+        };
+        if let Some((encoded, signature)) = token.rsplit_once('.') {
+            let sender = base32_decode(encoded)
+                .and_then(|sender| String::from_utf8(sender).ok());
+            return Ok(sender.filter(|sender| {
+                constant_time_eq(&self.signature(sender), signature)
+            }));
+        }
+        let (Some(objects), KEY_LEN) = (objects, token.len()) else {
+            return Ok(None);
+        };
+        let stored =
+            objects.get(&format!("{}{}", SENDER_PREFIX, token)).await?;
+        Ok(stored
+            .and_then(|sender| String::from_utf8(sender).ok())
+            .filter(|sender| constant_time_eq(&self.key(sender), &token)))
+    }
+
+    /// Returns the lowercased part of `address` after the `+` of the alias,
+    /// if it is an address of this relay.
+    fn token(&self, address: &str) -> Option<String> {
+        let (local, domain) = address.trim().rsplit_once('@')?;
+        if !domain.eq_ignore_ascii_case(&self.domain) {
+            return None;
+        }
+        let local = local.to_lowercase();
+        let token = local.strip_prefix(&self.local)?.strip_prefix('+')?;
+        Some(token.to_owned())
+    }
+
+    fn signature(&self, sender: &str) -> String {
+        self.mac(&[sender.as_bytes()], SIGNATURE_LEN)
+    }
+
+    /// Returns the store key of `sender`, hashed apart from its signature.
+    fn key(&self, sender: &str) -> String {
+        self.mac(&[b"key\0", sender.as_bytes()], KEY_LEN)
+    }
+
+    /// Returns the first `len` base32 characters of the HMAC of `parts`.
+    fn mac(&self, parts: &[&[u8]], len: usize) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        for part in parts {
+            mac.update(part);
+        }
+        let mut hash = base32_encode(&mac.finalize().into_bytes());
+        hash.truncate(len);
+        hash
+    }
+}
+
+/// Removes the prefixes added when forwarding from a reply subject, e.g.
+/// `Re: Fwd: Lunch` becomes `Re: Lunch`.
+pub fn clean_subject(subject: &str) -> String {
+    let subject = subject.trim();
+    let (reply, rest) = match subject.get(..3) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("re:") => {
+            ("Re: ", subject[3..].trim_start())
+        }
+        _ => ("", subject),
+    };
+    let rest = rest.strip_prefix("Fwd: ").unwrap_or(rest);
+    let rest = rest.strip_prefix(OVERSIZE_SUBJECT_PREFIX).unwrap_or(rest);
+    format!("{}{}", reply, rest)
+}
+
+/// Collects the bodies and attachments of `part` and its subparts.
+fn collect(
+    part: &ParsedMail,
+    text: &mut Option<String>,
+    html: &mut Option<String>,
+    attachments: &mut Vec<Attachment>,
+) {
+    if !part.subparts.is_empty() {
+        for subpart in &part.subparts {
+            collect(subpart, text, html, attachments);
+        }
+        return;
+    }
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"));
+    let body = match &disposition.disposition {
+        DispositionType::Attachment => None,
+        _ if filename.is_some() => None,
+        _ => match part.ctype.mimetype.as_str() {
+            "text/plain" if text.is_none() => Some(text),
+            "text/html" if html.is_none() => Some(html),
+            _ => None,
+        },
+    };
+    match body {
+        Some(slot) => *slot = part.get_body().ok(),
+        None => {
+            if let Ok(data) = part.get_body_raw() {
+                attachments.push(Attachment::new(
+                    filename.cloned().unwrap_or_else(|| "attachment".into()),
+                    &part.ctype.mimetype,
+                    data,
+                ));
+            }
+        }
+    }
+}
+
+/// Rebuilds `reply` as a new message from `from` to `to`, keeping its
+/// subject, bodies, attachments and threading headers but none of the
+/// headers added by the destination provider.
+pub fn rebuild_reply(reply: &ParsedMail, from: &str, to: &str) -> MimeMessage {
+    let subject = reply.headers.get_first_value("Subject").unwrap_or_default();
+    let mut message = MimeMessage::new()
+        .header("From", from)
+        .header("To", to)
+        .header("Subject", clean_subject(&subject));
+    for name in ["In-Reply-To", "References"] {
+        if let Some(value) = reply.headers.get_first_value(name) {
+            message = message.header(name, value);
+        }
+    }
+
+    let (mut text, mut html, mut attachments) = (None, None, Vec::new());
+    collect(reply, &mut text, &mut html, &mut attachments);
+    if let Some(text) = text {
+        message = message.text(text);
+    }
+    if let Some(html) = html {
+        message = message.html(html);
+    }
+    for attachment in attachments {
+        message = message.attach(attachment);
+    }
+    message
+}
+
+/** Test module for the reply relay */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::fs::FsObjectStore;
+    use mailparse::parse_mail;
+
+    #[tokio::test]
+    async fn test_relay_address() {
+        let relay = Relay::new("relay@nyah.dev", b"secret").unwrap();
+        let address = relay.encode("fufu@achu.soup").unwrap();
+        assert!(address.starts_with("relay+"));
+        assert!(address.ends_with("@nyah.dev"));
+        assert_eq!(
+            relay.decode(&address.to_uppercase(), None).await.unwrap(),
+            Some("fufu@achu.soup".to_owned())
+        );
+
+        // Forged or foreign addresses are not relayed
+        let (token, _) = address.rsplit_once('.').unwrap();
+        let (token, _) = token.rsplit_once('.').unwrap();
+        let forged = format!("{}.aaaaaaaa@nyah.dev", token);
+        assert_eq!(relay.decode(&forged, None).await.unwrap(), None);
+        assert_eq!(relay.decode("relay@nyah.dev", None).await.unwrap(), None);
+        let other = Relay::new("relay@nyah.dev", b"other").unwrap();
+        assert_eq!(other.decode(&address, None).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_long_sender_address() {
+        let dir = std::env::temp_dir()
+            .join(format!("privatemail-relay-{}", std::process::id()));
+        let objects = FsObjectStore::new(&dir).unwrap();
+        let relay = Relay::new("relay@nyah.dev", b"secret").unwrap();
+        let sender = "notifications+a1b2c3d4e5@mail.long-newsletter.achu.soup";
+        assert!(relay.encode(sender).is_none());
+        assert_eq!(relay.reply_address(sender, None).await.unwrap(), None);
+
+        let address =
+            relay.reply_address(sender, Some(&objects)).await.unwrap().unwrap();
+        let (local, _) = address.rsplit_once('@').unwrap();
+        assert!(local.len() <= MAX_LOCAL_LEN);
+        assert_eq!(
+            relay.decode(&address, Some(&objects)).await.unwrap().as_deref(),
+            Some(sender)
+        );
+        assert_eq!(relay.decode(&address, None).await.unwrap(), None);
+
+        // Keys are only valid for their own sender
+        let other = Relay::new("relay@nyah.dev", b"other").unwrap();
+        assert_eq!(other.decode(&address, Some(&objects)).await.unwrap(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_reply() {
+        let raw = b"From: me@gmail.test\r\n\
+            To: relay+abc.def@nyah.dev\r\n\
+            Subject: Re: Fwd: Lunch\r\n\
+            In-Reply-To: <a@achu.soup>\r\n\
+            X-Gm-Message-State: noise\r\n\
+            Content-Type: text/plain\r\n\r\n\
+            Sounds good\r\n";
+        let reply = parse_mail(raw).unwrap();
+        let message = rebuild_reply(&reply, "hello@nyah.dev", "fufu@achu.soup");
+        assert_eq!(message.get_header("Subject"), Some("Re: Lunch"));
+        assert_eq!(message.get_header("In-Reply-To"), Some("<a@achu.soup>"));
+        assert!(message.get_header("X-Gm-Message-State").is_none());
+
+        let rebuilt = message.to_bytes();
+        let rebuilt = parse_mail(&rebuilt).unwrap();
+        assert_eq!(rebuilt.get_body().unwrap().trim(), "Sounds good");
+    }
+}
@@ -0,0 +1,195 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Replay of archived messages.
+//!
+//! A message of the `archive_bucket`, found by its key or SES message id,
+//! is handled again by the whole forwarding pipeline, as rebuilt from its
+//! raw message and metadata, e.g. once a routing bug is fixed or after an
+//! SES outage. Replayed messages are not archived again nor skipped as
+//! duplicates, and may be forwarded to another address than `to_email`.
+//! The [`Replay`] is passed to [`PrivatEmailService::handle_replay`] next to
+//! the event, so that published notifications can't claim to be replays.
+use crate::archive::{self, Metadata};
+use crate::store::ObjectStore;
+use crate::{inbound, quiet};
+use crate::{LambdaResponse, PrivatEmailService};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_runtime::{Context, Error, LambdaEvent};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Event source of the records of replayed messages.
+pub const EVENT_SOURCE: &str = "privatemail:replay";
+
+/// Marks a notification as the replay of an archived message.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Replay {
+    /// Address the message is forwarded to instead of `to_email`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+/// Days back from today searched for a message id given without the day
+/// it was received.
+pub const SEARCH_DAYS: i64 = 31;
+
+/// Returns the key of the raw archived message `target`, a key or an SES
+/// message id, if archived in `objects`. Message ids are looked up under
+/// `day`, e.g. `2022-03-19`, or the last [`SEARCH_DAYS`] days.
+pub async fn locate(
+    objects: &dyn ObjectStore,
+    target: &str,
+    day: Option<&str>,
+) -> Result<Option<String>, Error> {
+    let bases = match (target.contains('/'), day) {
+        (true, _) => {
+            let base = target.strip_suffix(".json").unwrap_or(target);
+            vec![base.strip_suffix(".eml").unwrap_or(base).to_owned()]
+        }
+        (false, Some(day)) => vec![archive::key(target, day)],
+        (false, None) => (0..SEARCH_DAYS)
+            .map(|days| quiet::timestamp(quiet::now() - days * 86400))
+            .map(|day| archive::key(target, &day))
+            .collect(),
+    };
+    for base in bases {
+        let key = format!("{}.eml", base);
+        if objects.list(&key).await?.contains(&key) {
+            return Ok(Some(key));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the SNS event of the SES notification of the archived message
+/// `raw`, described by `metadata`.
+pub fn to_event(metadata: &Metadata, raw: &[u8]) -> Value {
+    // The content is sent base64 encoded, as by SES, to keep 8-bit
+    // messages intact
+    let mut receipt = Map::new();
+    receipt.insert(
+        "action".into(),
+        json!({ "type": "SNS", "encoding": crate::BASE64_ENCODING }),
+    );
+    for check in ["spam", "virus", "spf", "dkim", "dmarc"] {
+        let status = metadata.verdicts.get(check);
+        let status = status.map_or(inbound::VERDICT, String::as_str);
+        receipt
+            .insert(format!("{}Verdict", check), json!({ "status": status }));
+    }
+    let notification = json!({
+        "notificationType": "Received",
+        "mail": {
+            "timestamp": metadata.timestamp,
+            "source": metadata.source,
+            "messageId": metadata.message_id,
+            "destination": metadata.destination,
+            "commonHeaders": {
+                "subject": metadata.subject,
+                "returnPath": metadata.source,
+            },
+        },
+        "receipt": receipt,
+        "content": STANDARD.encode(raw),
+    });
+    json!({
+        "Records": [{
+            "EventSource": EVENT_SOURCE,
+            "Sns": { "Message": notification.to_string() },
+        }]
+    })
+}
+
+/// Handles the archived message `target`, a key or an SES message id
+/// received on `day`, again with `service`, forwarding it to `to` when
+/// given.
+pub async fn replay(
+    service: &PrivatEmailService,
+    objects: &dyn ObjectStore,
+    target: &str,
+    day: Option<&str>,
+    to: Option<&str>,
+) -> Result<LambdaResponse, Error> {
+    let Some(key) = locate(objects, target, day).await? else {
+        return Err(format!("{} is not archived", target).into());
+    };
+    let base = key.trim_end_matches(".eml");
+    let raw = objects.get(&key).await?.ok_or("Missing archived message")?;
+    let metadata = objects
+        .get(&format!("{}.json", base))
+        .await?
+        .ok_or("Missing archived metadata")?;
+    let metadata: Metadata = serde_json::from_slice(&metadata)?;
+    let replay = Replay { to: to.map(str::to_owned) };
+
+    let mut context = Context::default();
+    context.request_id = format!("replay-{}", metadata.message_id);
+    context.xray_trace_id = Some(context.request_id.clone());
+    let event = to_event(&metadata, &raw);
+    service.handle_replay(LambdaEvent::new(event, context), &replay).await
+}
+
+/** Test module for the replay of archived messages */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::fs::FsObjectStore;
+    use crate::EmailReceiptNotification;
+    use bytes::Bytes;
+    use std::collections::BTreeMap;
+
+    #[tokio::test]
+    async fn test_replay_event() {
+        let dir = std::env::temp_dir()
+            .join(format!("privatemail-replay-{}", std::process::id()));
+        let objects = FsObjectStore::new(&dir).unwrap();
+        let metadata = Metadata {
+            message_id: "abc".to_owned(),
+            timestamp: "2022-03-19T08:46:16.420Z".to_owned(),
+            source: "fufu@achu.soup".to_owned(),
+            destination: vec!["hello@nyah.dev".to_owned()],
+            subject: "Fufu".to_owned(),
+            verdicts: BTreeMap::from([("spam".to_owned(), "PASS".to_owned())]),
+            ..Default::default()
+        };
+        let raw = Bytes::from_static(b"Subject: Fufu\r\n\r\nH\xe9llo");
+        archive::write(&objects, metadata.clone(), raw.clone()).await.unwrap();
+        let key = "2022/03/19/abc.eml";
+        for target in [key, "2022/03/19/abc.json", "2022/03/19/abc"] {
+            let located = locate(&objects, target, None).await.unwrap();
+            assert_eq!(located.as_deref(), Some(key));
+        }
+        let day = Some("2022-03-19");
+        let located = locate(&objects, "abc", day).await.unwrap();
+        assert_eq!(located.as_deref(), Some(key));
+        assert_eq!(locate(&objects, "ab", day).await.unwrap(), None);
+        assert_eq!(locate(&objects, "abc", None).await.unwrap(), None);
+
+        // Message ids received lately are found without their day
+        let today = Metadata {
+            message_id: "def".to_owned(),
+            timestamp: quiet::timestamp(quiet::now()),
+            ..Default::default()
+        };
+        let key = archive::write(&objects, today, raw.clone()).await.unwrap();
+        let located = locate(&objects, "def", None).await.unwrap();
+        assert_eq!(located, Some(key));
+
+        let event = to_event(&metadata, &raw);
+        assert_eq!(event["Records"][0]["EventSource"], EVENT_SOURCE);
+        let ses_mail = EmailReceiptNotification::from_event(&event).unwrap();
+        assert_eq!(ses_mail.content(), &raw[..]);
+        let verdicts = ses_mail.to_archive_metadata().verdicts;
+        assert_eq!(verdicts["spam"], "PASS");
+        assert_eq!(verdicts["virus"], inbound::VERDICT);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,192 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Retention of archived and quarantined mail.
+//!
+//! An EventBridge schedule invoking the function with `{"retention": true}`,
+//! e.g. daily, deletes the messages received more than `retention_days`
+//! ago: those of the `archive_bucket`, by the day of their key, and the
+//! archived and quarantined messages of the store, by their records, which
+//! are deleted with them, as are their entries of the `index_table`. Bucket
+//! lifecycle rules can't do the latter, as they would leave records and
+//! index entries pointing to deleted messages.
+use crate::quiet;
+use crate::store::dedup::{self, Manifest};
+use crate::store::{
+    MailRecord, MailStore, ObjectStore, ARCHIVE_PREFIX, QUARANTINE_PREFIX,
+};
+use lambda_runtime::Error;
+use std::collections::BTreeSet;
+
+/// Messages deleted by a run.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Purge {
+    /// Keys deleted from the `archive_bucket`
+    pub archived: Vec<String>,
+
+    /// Ids of the messages deleted from the store, with their records
+    pub stored: Vec<String>,
+}
+
+impl Purge {
+    /// Returns the ids of the messages deleted, from the archive or the
+    /// store.
+    pub fn message_ids(&self) -> BTreeSet<&str> {
+        self.archived
+            .iter()
+            .filter_map(|key| key.strip_suffix(".eml")?.rsplit('/').next())
+            .chain(self.stored.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+/// Returns the time before which messages are deleted at `now`, keeping
+/// `days` of mail.
+pub fn cutoff(now: i64, days: u32) -> i64 {
+    now - i64::from(days) * 86_400
+}
+
+/// Deletes the objects of `objects`, keyed as by [`crate::archive::key`], of
+/// the days ending before `cutoff`.
+pub async fn purge_archive(
+    objects: &dyn ObjectStore,
+    cutoff: i64,
+) -> Result<Vec<String>, Error> {
+    let mut deleted = Vec::new();
+    for key in objects.list("").await? {
+        let day = key.get(..10).map(|day| day.replace('/', "-"));
+        let Some(day) = day.as_deref().and_then(quiet::parse_timestamp) else {
+            continue;
+        };
+        if day + 86_400 > cutoff {
+            // Keys are listed in order, so the days after are kept too
+            break;
+        }
+        objects.delete(&key).await?;
+        deleted.push(key);
+    }
+    Ok(deleted)
+}
+
+/// Deletes the archived and quarantined messages of `mail_store` received
+/// before `cutoff`, and their records. Returns their message ids.
+pub async fn purge_store(
+    mail_store: &MailStore,
+    cutoff: i64,
+) -> Result<Vec<String>, Error> {
+    let records =
+        mail_store.records.list_records("", &quiet::timestamp(cutoff)).await?;
+    let mut deleted = Vec::new();
+    for record in records {
+        let Some(key) = &record.object_key else {
+            continue;
+        };
+        if !key.starts_with(ARCHIVE_PREFIX)
+            && !key.starts_with(QUARANTINE_PREFIX)
+        {
+            continue;
+        }
+        delete_message(mail_store, &record).await?;
+        deleted.push(record.message_id);
+    }
+    Ok(deleted)
+}
+
+/// Deletes the stored message of `record`, if any, releasing its
+/// deduplicated attachments, and the record.
+pub async fn delete_message(
+    mail_store: &MailStore,
+    record: &MailRecord,
+) -> Result<(), Error> {
+    let objects = &*mail_store.objects;
+    let manifest = match mail_store.dedup_attachments {
+        true => objects.get(&Manifest::key(&record.message_id)).await?,
+        false => None,
+    };
+    match (manifest, &record.object_key) {
+        (Some(manifest), _) => {
+            let manifest: Manifest = serde_json::from_slice(&manifest)?;
+            dedup::release(objects, &*mail_store.records, &manifest).await?;
+        }
+        (None, Some(key)) => objects.delete(key).await?,
+        (None, None) => {}
+    }
+    mail_store.records.delete_record(&record.message_id).await
+}
+
+/** Test module for mail retention */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::fs::FsObjectStore;
+    use crate::store::{MailAction, MemoryRecordStore};
+
+    fn record(message_id: &str, timestamp: &str) -> MailRecord {
+        MailRecord {
+            message_id: message_id.to_owned(),
+            timestamp: timestamp.to_owned(),
+            source: "fufu@achu.soup".to_owned(),
+            destination: vec!["samubu@user.earth".to_owned()],
+            subject: "Testing".to_owned(),
+            action: MailAction::Quarantined,
+            object_key: None,
+            thread_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge() {
+        let root = std::env::temp_dir()
+            .join(format!("privatemail-retention-{}", std::process::id()));
+        let now = quiet::parse_timestamp("2022-03-19T08:46:16").unwrap();
+        let cutoff = cutoff(now, 30);
+        assert_eq!(quiet::timestamp(cutoff), "2022-02-17T08:46:16.000Z");
+
+        let objects = FsObjectStore::new(root.join("archive")).unwrap();
+        for key in ["2022/02/16/a.eml", "2022/02/17/b.eml", "2022/03/19/c.eml"]
+        {
+            objects.put(key, "Hi".into()).await.unwrap();
+        }
+        let deleted = purge_archive(&objects, cutoff).await.unwrap();
+        assert_eq!(deleted, ["2022/02/16/a.eml"]);
+        assert_eq!(objects.list("").await.unwrap().len(), 2);
+
+        let mail_store = MailStore::new(
+            Box::new(FsObjectStore::new(root.join("store")).unwrap()),
+            Box::new(MemoryRecordStore::default()),
+        );
+        for (id, timestamp) in [
+            ("old", "2022-01-02T00:00:00.000Z"),
+            ("new", "2022-03-18T00:00:00.000Z"),
+        ] {
+            mail_store
+                .track(record(id, timestamp), "Subject: Hi\r\n\r\nHi".into())
+                .await
+                .unwrap();
+        }
+        let deleted = purge_store(&mail_store, cutoff).await.unwrap();
+        assert_eq!(deleted, ["old"]);
+        assert!(mail_store.records.get_record("old").await.unwrap().is_none());
+        let keys = mail_store.objects.list(QUARANTINE_PREFIX).await.unwrap();
+        assert_eq!(keys, ["quarantine/new.eml"]);
+
+        let purge = Purge {
+            archived: vec![
+                "2022/02/16/a.eml".into(),
+                "2022/02/16/a.json".into(),
+            ],
+            stored: deleted,
+        };
+        assert_eq!(
+            purge.message_ids().into_iter().collect::<Vec<_>>(),
+            ["a", "old"]
+        );
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Routing of invocations by the shape of their payload.
+use crate::http;
+use serde_json::Value;
+
+/// Kind of invocation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Route {
+    /// SNS records of received mail or SES feedback
+    Records,
+
+    /// EventBridge schedule releasing deferred forwards
+    Scheduled,
+
+    /// Direct invocation holding a `sendRequest`
+    SendRequest,
+
+    /// Direct invocation searching the mail index, holding a `search`
+    Search,
+
+    /// HTTP request from a Function URL or API Gateway
+    Http,
+
+    /// Daily schedule sending the digest, invoked with `{"digest": true}`
+    Digest,
+
+    /// Cron schedule sending statistics, invoked with `{"stats": true}`
+    Stats,
+
+    /// Schedule deleting expired mail, invoked with `{"retention": true}`
+    Retention,
+
+    /// Direct invocation erasing the mail of a sender, holding an `erase`
+    Erase,
+}
+
+impl Route {
+    /// Returns the route of the invocation payload `event`.
+    pub fn of(event: &Value) -> Route {
+        if event.get("digest").is_some() {
+            Route::Digest
+        } else if event.get("stats").is_some() {
+            Route::Stats
+        } else if event.get("retention").is_some() {
+            Route::Retention
+        } else if event["source"] == "aws.events" {
+            Route::Scheduled
+        } else if event.get("sendRequest").is_some() {
+            Route::SendRequest
+        } else if event.get("erase").is_some() {
+            Route::Erase
+        } else if event.get("search").is_some() {
+            Route::Search
+        } else if http::is_http(event) {
+            Route::Http
+        } else {
+            Route::Records
+        }
+    }
+}
+
+/** Test module for invocation routing */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_route() {
+        let records = json!({ "Records": [{ "Sns": { "Message": "{}" } }] });
+        assert_eq!(Route::of(&records), Route::Records);
+        let scheduled = json!({ "source": "aws.events", "detail": {} });
+        assert_eq!(Route::of(&scheduled), Route::Scheduled);
+        let send = json!({ "sendRequest": { "from": "jobs@nyah.dev" } });
+        assert_eq!(Route::of(&send), Route::SendRequest);
+        let search = json!({ "search": { "sender": "bank.com" } });
+        assert_eq!(Route::of(&search), Route::Search);
+        let erase = json!({ "erase": { "sender": "fufu@achu.soup" } });
+        assert_eq!(Route::of(&erase), Route::Erase);
+        let http = json!({ "requestContext": {}, "body": "" });
+        assert_eq!(Route::of(&http), Route::Http);
+        let digest = json!({ "digest": true });
+        assert_eq!(Route::of(&digest), Route::Digest);
+        let stats = json!({ "stats": true });
+        assert_eq!(Route::of(&stats), Route::Stats);
+        let retention = json!({ "retention": true });
+        assert_eq!(Route::of(&retention), Route::Retention);
+    }
+}
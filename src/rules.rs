@@ -0,0 +1,671 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Filtering rules evaluated on every message, in order.
+//!
+//! A rule matches when all of its conditions hold. Rules run by ascending
+//! `priority`, in configured order for equal priorities, and every
+//! matching rule acts: tags add up, the last redirect wins, and `drop`,
+//! `quarantine` or `digest` end the evaluation, as does a matching rule
+//! with `stop`.
+//! The default action applies when no rule matched. In YAML:
+//!
+//! ```yaml
+//! rules:
+//!   - name: known
+//!     sender: ["@bank.example", "boss@work.example"]
+//!     stop: true
+//!     action: forward
+//!   - name: newsletters
+//!     headers: { list-unsubscribe: "" }
+//!     stop: true
+//!     action: { tag: "[News]" }
+//!   - name: scams
+//!     body: ["unclaimed inheritance"]
+//!     body_regex: "wire \\$?[0-9,]+ to"
+//!     action: drop
+//! default_rule_action: quarantine
+//! ```
+//!
+//! Sender and recipient patterns starting with `@` match a domain and its
+//! subdomains, other patterns any address containing them. Text matches
+//! are case-insensitive; body conditions match the decoded text of the
+//! inline parts, HTML rendered as text.
+use crate::html;
+use crate::quiet::Schedule;
+use mailparse::{DispositionType, MailHeader, MailHeaderMap, ParsedMail};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, OnceLock};
+
+/// What happens to a message matched by a rule.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Forward the message as configured
+    #[default]
+    Forward,
+
+    /// Do not forward the message
+    Drop,
+
+    /// Forward the message with the subject prefixed, e.g. `[News]`
+    Tag(String),
+
+    /// Hold the message back in quarantine
+    Quarantine,
+
+    /// Forward the message to another address
+    Redirect(String),
+
+    /// Hold the forward back until the schedule opens, e.g.
+    /// `Mon-Fri 09:00-17:00`; within it, forward at once
+    Defer(Schedule),
+
+    /// List the message in the daily digest instead of forwarding it
+    Digest,
+}
+
+impl Action {
+    /// Name of the action, as in the configuration.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Action::Forward => "forward",
+            Action::Drop => "drop",
+            Action::Tag(_) => "tag",
+            Action::Quarantine => "quarantine",
+            Action::Redirect(_) => "redirect",
+            Action::Defer(_) => "defer",
+            Action::Digest => "digest",
+        }
+    }
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Tag(value) | Action::Redirect(value) => {
+                write!(f, "{} {}", self.as_str(), value)
+            }
+            Action::Defer(schedule) => {
+                write!(f, "{} {}", self.as_str(), schedule)
+            }
+            _ => f.write_str(self.as_str()),
+        }
+    }
+}
+
+impl std::str::FromStr for Action {
+    type Err = String;
+
+    /// Parses `forward`, `drop`, `quarantine`, `digest`, `tag=[News]`,
+    /// `redirect=finance@nyah.dev` or `defer=Mon-Fri 09:00-17:00`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.split_once('=') {
+            Some((name, value)) => (name, Some(value.trim())),
+            None => (s, None),
+        };
+        match (name.trim().to_lowercase().as_str(), value) {
+            ("forward", None) => Ok(Action::Forward),
+            ("drop", None) => Ok(Action::Drop),
+            ("quarantine", None) => Ok(Action::Quarantine),
+            ("digest", None) => Ok(Action::Digest),
+            ("tag", Some(tag)) if !tag.is_empty() => {
+                Ok(Action::Tag(tag.to_owned()))
+            }
+            ("redirect", Some(to)) if to.contains('@') => {
+                Ok(Action::Redirect(to.to_owned()))
+            }
+            ("defer", Some(schedule)) => Ok(Action::Defer(schedule.parse()?)),
+            _ => Err(format!("Invalid rule action: {}", s)),
+        }
+    }
+}
+
+/// A set of conditions and the action taken when all of them hold.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Rule {
+    /// Name shown in logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// Rules run by ascending priority
+    #[serde(default)]
+    pub priority: i32,
+
+    /// End the evaluation when the rule matches
+    #[serde(default)]
+    pub stop: bool,
+
+    /// Sender patterns, matched against the `From` header and envelope
+    /// sender; any of them may match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sender: Vec<String>,
+
+    /// Recipient patterns, matched against the original recipients; any
+    /// of them may match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipient: Vec<String>,
+
+    /// Text the subject contains
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    /// Text each named header contains; an empty text only requires the
+    /// header to be present
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+
+    /// Keywords the decoded body contains; any of them may match
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub body: Vec<String>,
+
+    /// Case-insensitive regular expression matching the decoded body
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_regex: Option<String>,
+
+    /// Weekly window the message must arrive in, e.g. `Mon-Fri
+    /// 09:00-17:00`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hours: Option<Schedule>,
+
+    /// Weekly window the message must arrive outside of
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub outside_hours: Option<Schedule>,
+
+    /// Smallest matching message size, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_size: Option<usize>,
+
+    /// Largest matching message size, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<usize>,
+
+    /// SES verdict status by check, e.g. `{spf: FAIL}`, out of `spam`,
+    /// `virus`, `spf`, `dkim` and `dmarc`
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub verdicts: BTreeMap<String, String>,
+
+    /// Action taken on matching messages, written as a single key map
+    /// such as `{tag: "[News]"}` in every format
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: Action,
+}
+
+impl Rule {
+    /// Returns a rule dropping mail from senders containing `entry`, as
+    /// the black list did, ahead of all other rules.
+    pub fn blocked_sender(entry: &str) -> Self {
+        Rule {
+            name: Some(format!("black_list {}", entry)),
+            priority: i32::MIN,
+            stop: true,
+            sender: vec![entry.to_owned()],
+            recipient: Vec::new(),
+            subject: None,
+            headers: BTreeMap::new(),
+            body: Vec::new(),
+            body_regex: None,
+            hours: None,
+            outside_hours: None,
+            min_size: None,
+            max_size: None,
+            verdicts: BTreeMap::new(),
+            action: Action::Drop,
+        }
+    }
+
+    /// Returns the name of the rule, or else its position in `rules`.
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("#{}", index + 1),
+        }
+    }
+
+    /// Returns true when all conditions hold for `message`.
+    pub fn matches(&self, message: &Message) -> bool {
+        let any = |patterns: &[String], addresses: &[String]| {
+            patterns.is_empty()
+                || patterns.iter().any(|pattern| {
+                    addresses.iter().any(|a| address_matches(pattern, a))
+                })
+        };
+        any(&self.sender, &message.senders)
+            && any(&self.recipient, &message.recipients)
+            && self
+                .subject
+                .as_ref()
+                .is_none_or(|text| contains(&message.subject, text))
+            && self.headers.iter().all(|(name, text)| {
+                message
+                    .headers
+                    .get_all_values(name)
+                    .iter()
+                    .any(|value| contains(value, text))
+            })
+            && (self.body.is_empty() || {
+                let body = message.body.to_lowercase();
+                self.body.iter().any(|keyword| {
+                    body.contains(&keyword.trim().to_lowercase())
+                })
+            })
+            && self.body_regex.as_ref().is_none_or(|pattern| {
+                compiled(pattern)
+                    .is_some_and(|regex| regex.is_match(&message.body))
+            })
+            && self.hours.is_none_or(|hours| {
+                hours.contains(message.received, message.utc_offset)
+            })
+            && self.outside_hours.is_none_or(|hours| {
+                !hours.contains(message.received, message.utc_offset)
+            })
+            && self.min_size.is_none_or(|min| message.size >= min)
+            && self.max_size.is_none_or(|max| message.size <= max)
+            && self.verdicts.iter().all(|(check, status)| {
+                message
+                    .verdicts
+                    .get(check.to_lowercase().as_str())
+                    .is_some_and(|s| s.eq_ignore_ascii_case(status.trim()))
+            })
+    }
+}
+
+/// What rules are evaluated against.
+#[derive(Clone, Debug, Default)]
+pub struct Message<'a> {
+    /// Addresses of the `From` header and the envelope sender
+    pub senders: Vec<String>,
+
+    /// Original recipients
+    pub recipients: Vec<String>,
+
+    /// Original subject
+    pub subject: String,
+
+    /// Headers of the original message
+    pub headers: &'a [MailHeader<'a>],
+
+    /// Decoded text of the body, HTML parts rendered as text
+    pub body: String,
+
+    /// Size of the original message, in bytes
+    pub size: usize,
+
+    /// SES verdict status by lowercase check name
+    pub verdicts: BTreeMap<&'static str, String>,
+
+    /// Time the message arrived, in seconds since the Unix epoch
+    pub received: i64,
+
+    /// UTC offset of rule schedules, in minutes
+    pub utc_offset: i32,
+}
+
+/// What the rules decided for a message.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Outcome {
+    /// Indices of the matching rules, in evaluation order
+    pub matched: Vec<usize>,
+
+    /// Whether the default action was taken, as no rule matched
+    pub by_default: bool,
+
+    /// `Forward`, or the `Drop`, `Quarantine` or `Digest` which ended the
+    /// evaluation
+    pub disposition: Action,
+
+    /// Tags of the subject, in evaluation order
+    pub tags: Vec<String>,
+
+    /// Destination of the last matching redirect
+    pub redirect: Option<String>,
+
+    /// Time a matching deferral holds the forward back until
+    pub defer_until: Option<i64>,
+}
+
+impl Outcome {
+    /// Records `action` on `message`, returning true when it ends the
+    /// evaluation.
+    fn apply(&mut self, action: &Action, message: &Message) -> bool {
+        match action {
+            Action::Forward => false,
+            Action::Drop | Action::Quarantine | Action::Digest => {
+                self.disposition = action.clone();
+                true
+            }
+            Action::Tag(tag) => {
+                self.tags.push(tag.clone());
+                false
+            }
+            Action::Redirect(to) => {
+                self.redirect = Some(to.clone());
+                false
+            }
+            Action::Defer(schedule) => {
+                let opens =
+                    schedule.opens(message.received, message.utc_offset);
+                self.defer_until = self.defer_until.max(opens);
+                false
+            }
+        }
+    }
+}
+
+/// Evaluates `rules` against `message`, taking the `default` action when
+/// no rule matches.
+pub fn evaluate(
+    rules: &[Rule],
+    default: Option<&Action>,
+    message: &Message,
+) -> Outcome {
+    let mut order: Vec<usize> = (0..rules.len()).collect();
+    order.sort_by_key(|&index| rules[index].priority);
+
+    let mut outcome = Outcome::default();
+    for index in order {
+        let rule = &rules[index];
+        if !rule.matches(message) {
+            continue;
+        }
+        outcome.matched.push(index);
+        if outcome.apply(&rule.action, message) || rule.stop {
+            break;
+        }
+    }
+    if let (true, Some(default)) = (outcome.matched.is_empty(), default) {
+        outcome.by_default = true;
+        outcome.apply(default, message);
+    }
+    outcome
+}
+
+/// Compiles the `body_regex` of a rule, ignoring case.
+pub fn body_regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+/// Returns the compiled `body_regex` `pattern`, compiling it on first use
+/// and keeping it across invocations of a warm Lambda container.
+fn compiled(pattern: &str) -> Option<Regex> {
+    static CACHE: OnceLock<Mutex<HashMap<String, Option<Regex>>>> =
+        OnceLock::new();
+    let mut cache = CACHE.get_or_init(Default::default).lock().unwrap();
+    cache
+        .entry(pattern.to_owned())
+        .or_insert_with(|| body_regex(pattern).ok())
+        .clone()
+}
+
+/// Returns the decoded text of the inline text parts of `mail`, for
+/// matching its body.
+pub fn body_text(mail: &ParsedMail) -> String {
+    let mut text = String::new();
+    for part in mail.parts() {
+        if part.get_content_disposition().disposition != DispositionType::Inline
+        {
+            continue;
+        }
+        let Ok(body) = part.get_body() else { continue };
+        match part.ctype.mimetype.as_str() {
+            "text/plain" => text.push_str(&body),
+            "text/html" => text.push_str(&html::to_text(&body)),
+            _ => continue,
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Returns true when `address`, possibly with a display name, matches the
+/// sender or recipient `pattern`.
+fn address_matches(pattern: &str, address: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let address = address.trim().to_lowercase();
+    let address = match address.rsplit_once('<') {
+        Some((_, rest)) => rest.trim_end_matches('>'),
+        None => &address,
+    };
+    match pattern.strip_prefix('@') {
+        Some(domain) => address.rsplit_once('@').is_some_and(|(_, d)| {
+            d == domain || d.ends_with(&format!(".{}", domain))
+        }),
+        None => !pattern.is_empty() && address.contains(&pattern),
+    }
+}
+
+fn contains(haystack: &str, needle: &str) -> bool {
+    haystack.to_lowercase().contains(&needle.trim().to_lowercase())
+}
+
+/** Test module for filtering rules */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message<'a>(headers: &'a [MailHeader<'a>]) -> Message<'a> {
+        Message {
+            senders: vec![
+                "Bank <Alerts@mail.bank.example>".to_owned(),
+                "bounce@esp.example".to_owned(),
+            ],
+            recipients: vec!["jobs@fufu.soup".to_owned()],
+            subject: "Your Weekly Statement".to_owned(),
+            headers,
+            body: "Your statement is ready.\n".to_owned(),
+            size: 2048,
+            verdicts: [("spam", "PASS".to_owned()), ("spf", "FAIL".to_owned())]
+                .into_iter()
+                .collect(),
+            // Friday 2021-03-19 23:30 at UTC+02:00
+            received: 1616189400,
+            utc_offset: 120,
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            r#"
+            - name: spoofed
+              sender: ["@bank.example"]
+              verdicts: { spf: pass }
+              action: drop
+            - name: bank
+              sender: ["@bank.example"]
+              subject: statement
+              max_size: 4096
+              action: { redirect: finance@nyah.dev }
+            - headers: { list-unsubscribe: "" }
+              action: { tag: "[News]" }
+            "#,
+        )
+        .unwrap();
+
+        let (headers, _) = mailparse::parse_headers(b"\r\n").unwrap();
+        let outcome = evaluate(&rules, None, &message(&headers));
+        assert_eq!(outcome.matched, [1]);
+        assert_eq!(outcome.redirect.as_deref(), Some("finance@nyah.dev"));
+        assert_eq!(outcome.disposition, Action::Forward);
+
+        let (list_headers, _) = mailparse::parse_headers(
+            b"List-Unsubscribe: <mailto:u@x.io>\r\n\r\n",
+        )
+        .unwrap();
+        let outcome = evaluate(&rules, None, &message(&list_headers));
+        assert_eq!(outcome.matched, [1, 2]);
+        assert_eq!(outcome.tags, ["[News]"]);
+        assert_eq!(rules[2].label(2), "#3");
+
+        let mut other = message(&headers);
+        other.senders = vec!["news@fufu.soup".to_owned()];
+        assert_eq!(evaluate(&rules, None, &other), Outcome::default());
+
+        let rule: Rule =
+            serde_json::from_str(r#"{"action": {"redirect": "a@b.io"}}"#)
+                .unwrap();
+        assert_eq!(rule.action, Action::Redirect("a@b.io".to_owned()));
+        let rule: Rule = serde_json::from_str(r#"{"action": "drop"}"#).unwrap();
+        assert_eq!(rule.action, Action::Drop);
+    }
+
+    #[test]
+    fn test_priority_stop_default() {
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            r#"
+            - name: newsletters
+              headers: { list-unsubscribe: "" }
+              stop: true
+              action: { tag: "[News]" }
+            - name: known
+              priority: -1
+              sender: ["@bank.example"]
+              stop: true
+              action: forward
+            - name: big
+              min_size: 1024
+              action: quarantine
+            - name: never
+              action: { tag: "[Big]" }
+            "#,
+        )
+        .unwrap();
+        let default = Action::Quarantine;
+
+        let (list_headers, _) = mailparse::parse_headers(
+            b"List-Unsubscribe: <mailto:u@x.io>\r\n\r\n",
+        )
+        .unwrap();
+        let mut message = message(&list_headers);
+        let outcome = evaluate(&rules, Some(&default), &message);
+        assert_eq!(outcome.matched, [1]);
+        assert_eq!(outcome.disposition, Action::Forward);
+        assert!(outcome.tags.is_empty());
+
+        message.senders = vec!["news@fufu.soup".to_owned()];
+        let outcome = evaluate(&rules, Some(&default), &message);
+        assert_eq!(outcome.matched, [0]);
+        assert_eq!(outcome.tags, ["[News]"]);
+
+        message.headers = &[];
+        let outcome = evaluate(&rules, Some(&default), &message);
+        assert_eq!(outcome.matched, [2]);
+        assert_eq!(outcome.disposition, Action::Quarantine);
+        assert!(!outcome.by_default);
+
+        message.size = 10;
+        let outcome = evaluate(&rules[..2], Some(&default), &message);
+        assert!(outcome.matched.is_empty() && outcome.by_default);
+        assert_eq!(outcome.disposition, Action::Quarantine);
+    }
+
+    #[test]
+    fn test_body() {
+        let mail = mailparse::parse_mail(
+            b"Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+            --b\r\n\
+            Content-Type: text/html\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+            <p>Your <b>UNCLAIMED</b> inheritance=3A wire $1,500 to us</p>\r\n\
+            --b\r\n\
+            Content-Type: text/plain\r\n\
+            Content-Disposition: attachment; filename=notes.txt\r\n\r\n\
+            lottery\r\n\
+            --b--\r\n",
+        )
+        .unwrap();
+        let body = body_text(&mail);
+        assert!(body.contains("UNCLAIMED inheritance: wire $1,500 to"));
+        assert!(!body.contains("lottery"));
+
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            r#"
+            - body: ["lottery", "unclaimed inheritance"]
+              action: drop
+            - body_regex: "wire \\$?[0-9,]+ to"
+              action: quarantine
+            "#,
+        )
+        .unwrap();
+        let mut message = message(&[]);
+        assert!(!rules[0].matches(&message));
+        message.body = body;
+        assert!(rules[0].matches(&message) && rules[1].matches(&message));
+        message.body = "WIRE 2,000 TO".to_owned();
+        assert!(!rules[0].matches(&message) && rules[1].matches(&message));
+        assert!(body_regex("wire (").is_err());
+    }
+
+    #[test]
+    fn test_hours() {
+        let rules: Vec<Rule> = serde_yaml::from_str(
+            r#"
+            - recipient: ["urgent@fufu.soup"]
+              action: { defer: "Mon-Fri 09:00-17:00" }
+            - recipient: ["jobs@fufu.soup"]
+              outside_hours: "Mon-Fri 08:00-18:00"
+              action: { redirect: on-call@fufu.soup }
+            - hours: "Fri 22:00-00:30"
+              action: { tag: "[Late]" }
+            "#,
+        )
+        .unwrap();
+        let mut message = message(&[]);
+        let outcome = evaluate(&rules, None, &message);
+        assert_eq!(outcome.matched, [1, 2]);
+        assert_eq!(outcome.redirect.as_deref(), Some("on-call@fufu.soup"));
+        assert_eq!(outcome.defer_until, None);
+
+        message.recipients = vec!["urgent@fufu.soup".to_owned()];
+        let outcome = evaluate(&rules, None, &message);
+        assert_eq!(outcome.matched, [0, 2]);
+        // Monday 09:00 at UTC+02:00
+        assert_eq!(outcome.defer_until, Some(1616396400));
+
+        // Friday 10:00 at UTC+02:00
+        message.received -= 13 * 3600 + 1800;
+        let outcome = evaluate(&rules, None, &message);
+        assert_eq!(outcome.matched, [0]);
+        assert_eq!(outcome.defer_until, None);
+    }
+
+    #[test]
+    fn test_action_from_str() {
+        assert_eq!("Drop".parse(), Ok(Action::Drop));
+        assert_eq!("digest".parse(), Ok(Action::Digest));
+        assert_eq!("tag = [News]".parse(), Ok(Action::Tag("[News]".into())));
+        assert_eq!(
+            "redirect=finance@nyah.dev".parse::<Action>().unwrap().to_string(),
+            "redirect finance@nyah.dev"
+        );
+        assert!("tag".parse::<Action>().is_err());
+        assert_eq!(
+            "defer=mon-fri 09:00-17:00".parse::<Action>().unwrap().to_string(),
+            "defer Mon-Fri 09:00-17:00"
+        );
+        assert!("redirect=finance".parse::<Action>().is_err());
+    }
+
+    #[test]
+    fn test_address_matches() {
+        assert!(address_matches("@bank.example", "a@mail.Bank.example"));
+        assert!(!address_matches("@bank.example", "a@notbank.example"));
+        assert!(address_matches("spam.example", "x@spam.example"));
+        assert!(!address_matches("", "x@spam.example"));
+
+        let rule = Rule::blocked_sender("esp.example");
+        assert!(rule.matches(&Message {
+            senders: vec!["bounce@esp.example".to_owned()],
+            ..Message::default()
+        }));
+        assert_eq!(rule.action, Action::Drop);
+    }
+}
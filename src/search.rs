@@ -0,0 +1,303 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Search over the mail index.
+//!
+//! A [`SearchRequest`] is accepted from a direct invocation whose payload
+//! holds it under `search`, or from a `GET` to [`SEARCH_PATH`] through a
+//! Function URL or API Gateway, with its fields as query parameters,
+//! authorized by the `search_api_token` bearer token:
+//!
+//! ```json
+//! { "search": { "sender": "bank.com", "from": "2022-02-01", "until": "2022-02-28" } }
+//! ```
+//!
+//! Searches by sender query the `sender_domain-timestamp` index of the
+//! `index_table`, others the `day-timestamp` index day by day. Subject and
+//! alias filters apply to the entries read. Each result links to the
+//! archived message, when there is one.
+use crate::http;
+use crate::index::{self, IndexEntry};
+use crate::quiet;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::time::Duration;
+
+/// Path of HTTP search requests.
+pub const SEARCH_PATH: &str = "/search";
+
+/// Results returned when the request sets no limit.
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// Most results returned.
+pub const MAX_LIMIT: usize = 1_000;
+
+/// Validity of the links to archived messages.
+pub const LINK_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// Most days searched by a request without sender.
+pub const MAX_DAYS: i64 = 366;
+
+/// Days searched when the request sets no date range.
+const DEFAULT_DAYS: i64 = 30;
+
+/// Most index entries read for a request, before filtering.
+const SCAN_LIMIT: usize = 5_000;
+
+/// Query of the mail index.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchRequest {
+    /// Sender address, or domain as `bank.com` or `@bank.com`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+
+    /// First `YYYY-MM-DD` day received, 30 days before `until` when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+
+    /// Last `YYYY-MM-DD` day received, today when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+
+    /// Text the subject contains, regardless of case
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+
+    /// Alias among the recipients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+
+    /// Most results returned, 100 by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+/// Index entry matching a search.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    /// Indexed metadata of the message
+    #[serde(flatten)]
+    pub entry: IndexEntry,
+
+    /// Presigned link to the archived message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Returns the search request of a direct invocation `event`, routed as
+/// [`Route::Search`](crate::router::Route::Search).
+pub fn from_invocation(event: &Value) -> Result<SearchRequest, String> {
+    serde_json::from_value(event["search"].clone())
+        .map_err(|e| format!("Invalid search request: {}", e))
+}
+
+/// Returns the search request of the query parameters of the HTTP request
+/// `event`.
+pub fn from_http(event: &Value) -> Result<SearchRequest, String> {
+    let param = |name| http::query_param(event, name).map(str::to_owned);
+    Ok(SearchRequest {
+        sender: param("sender"),
+        from: param("from"),
+        until: param("until"),
+        subject: param("subject"),
+        alias: param("alias"),
+        limit: param("limit")
+            .map(|limit| limit.parse())
+            .transpose()
+            .map_err(|_| "Invalid limit".to_owned())?,
+    })
+}
+
+impl SearchRequest {
+    /// Returns the first and last day searched at `now`, as Unix times of
+    /// their midnight.
+    pub fn days(&self, now: i64) -> Result<(i64, i64), String> {
+        let day = |value: &Option<String>, name| match value {
+            Some(value) => quiet::parse_timestamp(value)
+                .filter(|_| value.len() == 10)
+                .map(Some)
+                .ok_or_else(|| format!("{} is not a YYYY-MM-DD day", name)),
+            None => Ok(None),
+        };
+        let until = day(&self.until, "until")?
+            .unwrap_or_else(|| now - now.rem_euclid(86_400));
+        let from = day(&self.from, "from")?
+            .unwrap_or(until - (DEFAULT_DAYS - 1) * 86_400);
+        if from > until {
+            return Err("from is after until".to_owned());
+        }
+        if self.sender.is_none() && until - from >= MAX_DAYS * 86_400 {
+            return Err(format!(
+                "Searches without sender span at most {} days",
+                MAX_DAYS
+            ));
+        }
+        Ok((from, until))
+    }
+
+    /// Returns the number of results returned.
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    /// Returns whether `entry` matches the sender, subject and alias of the
+    /// request.
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        let contains = |text: &str, part: &str| {
+            text.to_lowercase().contains(&part.to_lowercase())
+        };
+        let sender = self.sender.as_deref().map(str::trim);
+        sender.is_none_or(|sender| match sender.split_once('@') {
+            Some((local, _)) if !local.is_empty() => {
+                entry.sender.eq_ignore_ascii_case(sender)
+            }
+            _ => entry
+                .sender_domain()
+                .eq_ignore_ascii_case(sender.trim_start_matches('@')),
+        }) && self
+            .subject
+            .as_deref()
+            .is_none_or(|subject| contains(&entry.subject, subject))
+            && self.alias.as_deref().is_none_or(|alias| {
+                entry
+                    .recipients
+                    .iter()
+                    .any(|r| r.eq_ignore_ascii_case(alias.trim()))
+            })
+    }
+}
+
+/// Returns the entries of `table` matching `request` at `now`, newest
+/// first, without links.
+pub async fn search(
+    table: &str,
+    request: &SearchRequest,
+    now: i64,
+) -> Result<Vec<SearchResult>, lambda_runtime::Error> {
+    let (from, until) = request.days(now)?;
+    let range = (
+        quiet::timestamp(from),
+        quiet::timestamp(until).replace("T00:00:00.000Z", "T23:59:59.999Z"),
+    );
+    let range = (range.0.as_str(), range.1.as_str());
+    let mut entries = match &request.sender {
+        Some(sender) => {
+            let domain = sender.trim().rsplit('@').next().unwrap_or_default();
+            index::query(
+                table,
+                index::SENDER_INDEX,
+                "sender_domain",
+                &domain.to_lowercase(),
+                range,
+                SCAN_LIMIT,
+            )
+            .await?
+        }
+        None => {
+            let mut entries = Vec::new();
+            let mut day = until;
+            while day >= from && entries.len() < SCAN_LIMIT {
+                let name = &quiet::timestamp(day)[..10];
+                let scanned = SCAN_LIMIT - entries.len();
+                entries.extend(
+                    index::query(
+                        table,
+                        index::DATE_INDEX,
+                        "day",
+                        name,
+                        range,
+                        scanned,
+                    )
+                    .await?,
+                );
+                day -= 86_400;
+            }
+            entries
+        }
+    };
+    entries.retain(|entry| request.matches(entry));
+    entries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    entries.truncate(request.limit());
+    Ok(entries
+        .into_iter()
+        .map(|entry| SearchResult { entry, link: None })
+        .collect())
+}
+
+/** Test module for index searches */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request() {
+        let now = quiet::parse_timestamp("2022-03-19T08:46:16").unwrap();
+        let request = SearchRequest::default();
+        let (from, until) = request.days(now).unwrap();
+        assert_eq!(quiet::timestamp(until), "2022-03-19T00:00:00.000Z");
+        assert_eq!(quiet::timestamp(from), "2022-02-18T00:00:00.000Z");
+        assert_eq!(request.limit(), DEFAULT_LIMIT);
+
+        let request = SearchRequest {
+            from: Some("2020-01-01".into()),
+            ..Default::default()
+        };
+        assert!(request.days(now).is_err());
+        let request =
+            SearchRequest { sender: Some("bank.com".into()), ..request };
+        assert!(request.days(now).is_ok());
+        let request = SearchRequest {
+            until: Some("2022-03".into()),
+            ..Default::default()
+        };
+        assert!(request.days(now).is_err());
+
+        let event = json!({
+            "requestContext": {},
+            "body": "",
+            "queryStringParameters": { "sender": "bank.com", "limit": "5" },
+        });
+        let request = from_http(&event).unwrap();
+        assert_eq!(request.sender.as_deref(), Some("bank.com"));
+        assert_eq!(request.limit(), 5);
+        let search = json!({ "search": { "subject": "statement" } });
+        let request = from_invocation(&search).unwrap();
+        assert_eq!(request.subject.as_deref(), Some("statement"));
+    }
+
+    #[test]
+    fn test_matches() {
+        let entry = IndexEntry {
+            sender: "alerts@bank.com".into(),
+            recipients: vec!["money@nyah.dev".into()],
+            subject: "Your Statement".into(),
+            ..Default::default()
+        };
+        let request = |sender: &str, subject: &str, alias: &str| {
+            let value = |s: &str| (!s.is_empty()).then(|| s.to_owned());
+            SearchRequest {
+                sender: value(sender),
+                subject: value(subject),
+                alias: value(alias),
+                ..Default::default()
+            }
+        };
+        assert!(request("", "", "").matches(&entry));
+        assert!(request("bank.com", "statement", "").matches(&entry));
+        assert!(request("@Bank.com", "", "Money@nyah.dev").matches(&entry));
+        assert!(request("alerts@bank.com", "", "").matches(&entry));
+        assert!(!request("other@bank.com", "", "").matches(&entry));
+        assert!(!request("fufu.soup", "", "").matches(&entry));
+        assert!(!request("", "invoice", "").matches(&entry));
+        assert!(!request("", "", "jobs@nyah.dev").matches(&entry));
+    }
+}
@@ -0,0 +1,522 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Senders of outgoing mail.
+//!
+//! Every message the handler sends, forwards, replies, digests and bounces
+//! alike, goes through the [`EmailSender`] of the service: the SES client
+//! in production, or a [`MockSender`] recording the messages, so the whole
+//! pipeline is tested without AWS credentials:
+//!
+//! ```
+//! use lib::config::PrivatEmailConfig;
+//! use lib::sender::MockSender;
+//! use lib::PrivatEmailService;
+//! use std::sync::Arc;
+//!
+//! let email_config = PrivatEmailConfig::builder()
+//!     .from_email("fwd@nyah.dev")
+//!     .to_email("me@nyah.dev")
+//!     .build();
+//! let sender = Arc::new(MockSender::new());
+//! let service = PrivatEmailService::from_config(email_config)
+//!     .unwrap()
+//!     .with_sender(sender.clone());
+//! assert!(sender.sent().is_empty());
+//! ```
+//!
+//! With `ses_failover_region` set, the SES client is wrapped in a
+//! [`FailoverSender`], which sends through SES in the secondary region
+//! when the primary one is unavailable or paused.
+//!
+//! The SES client of each region is wrapped in turn for the aws-sdk
+//! migration, see [`crate::migration::sender`], and with `ses_v2` in a
+//! `sesv2::Sesv2Sender` sending raw messages through SESv2.
+use crate::quota::SendQuota;
+use crate::{failure, xray};
+use async_trait::async_trait;
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use rusoto_ses::{SendEmailRequest, SendRawEmailRequest, Ses, SesClient};
+use std::sync::{Arc, Mutex};
+use tracing::{trace, warn};
+
+/// Metric logged for every send retried in the secondary region.
+pub const FAILOVER_METRIC: &str = "ses_failover";
+
+/// Sends mail, returning the message id given by the mail service.
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    /// Sends the raw MIME message of `request`.
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error>;
+
+    /// Sends the message built by the mail service from `request`.
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error>;
+
+    /// Returns the sending quota of the account, when the mail service has
+    /// one.
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        Ok(None)
+    }
+}
+
+/// SES client of `region`, the region its sends are traced in.
+#[derive(Clone)]
+pub struct SesSender {
+    client: SesClient,
+    region: Region,
+}
+
+impl SesSender {
+    /// Creates the sender of `client`, which sends in `region`.
+    pub fn new(client: SesClient, region: Region) -> Self {
+        SesSender { client, region }
+    }
+}
+
+#[async_trait]
+impl EmailSender for SesSender {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        let region = self.region.name();
+        let response = xray::traced("ses", "SendRawEmail", region, |_| async {
+            Ok(Ses::send_raw_email(&self.client, request).await?)
+        })
+        .await?;
+        trace!("Raw send response: {:?}", response);
+        Ok(response.message_id)
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        let region = self.region.name();
+        let response = xray::traced("ses", "SendEmail", region, |_| async {
+            Ok(Ses::send_email(&self.client, request).await?)
+        })
+        .await?;
+        trace!("rusoto send response: {:?}", response);
+        Ok(response.message_id)
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        let region = self.region.name();
+        let quota = xray::traced("ses", "GetSendQuota", region, |_| async {
+            Ok(Ses::get_send_quota(&self.client).await?)
+        })
+        .await?;
+        let enabled = xray::traced(
+            "ses",
+            "GetAccountSendingEnabled",
+            region,
+            |_| async {
+                Ok(Ses::get_account_sending_enabled(&self.client).await?)
+            },
+        )
+        .await?;
+        Ok(Some(SendQuota {
+            max_24_hour_send: quota.max_24_hour_send.unwrap_or(-1.0),
+            sent_last_24_hours: quota.sent_last_24_hours.unwrap_or_default(),
+            sending_enabled: enabled.enabled.unwrap_or(true),
+        }))
+    }
+}
+
+/// Sends through the client as a [`SesSender`] of the default region.
+#[async_trait]
+impl EmailSender for SesClient {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        SesSender::new(self.clone(), Region::default())
+            .send_raw_email(request)
+            .await
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        SesSender::new(self.clone(), Region::default())
+            .send_email(request)
+            .await
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        SesSender::new(self.clone(), Region::default()).send_quota().await
+    }
+}
+
+#[async_trait]
+impl<S: EmailSender + ?Sized> EmailSender for Box<S> {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        (**self).send_raw_email(request).await
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        (**self).send_email(request).await
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        (**self).send_quota().await
+    }
+}
+
+#[async_trait]
+impl<S: EmailSender + ?Sized> EmailSender for Arc<S> {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        (**self).send_raw_email(request).await
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        (**self).send_email(request).await
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        (**self).send_quota().await
+    }
+}
+
+/// [`EmailSender`] sending through `secondary`, in another region, when
+/// `primary` fails with a regional outage or a paused account, see
+/// [`failure::is_regional`].
+pub struct FailoverSender {
+    primary: Box<dyn EmailSender>,
+    secondary: Box<dyn EmailSender>,
+    region: String,
+    identity_arn: Option<String>,
+}
+
+impl FailoverSender {
+    /// Creates a sender failing over from `primary` to `secondary`, which
+    /// sends in `region`.
+    pub fn new(
+        primary: impl EmailSender + 'static,
+        secondary: impl EmailSender + 'static,
+        region: impl Into<String>,
+    ) -> Self {
+        FailoverSender {
+            primary: Box::new(primary),
+            secondary: Box::new(secondary),
+            region: region.into(),
+            identity_arn: None,
+        }
+    }
+
+    /// Sends failed over as the identity `arn` of the secondary region,
+    /// authorized to send for the addresses of the primary one.
+    pub fn with_identity_arn(mut self, arn: impl Into<String>) -> Self {
+        self.identity_arn = Some(arn.into());
+        self
+    }
+
+    /// Logs the failover of a send which failed with `error`.
+    fn failing_over(&self, error: &Error) {
+        warn!(
+            metric = FAILOVER_METRIC,
+            region = self.region.as_str(),
+            "Send failed, failing over: {}",
+            error
+        );
+    }
+}
+
+#[async_trait]
+impl EmailSender for FailoverSender {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        match self.primary.send_raw_email(request.clone()).await {
+            Err(e) if failure::is_regional(e.as_ref()) => {
+                self.failing_over(&e);
+                let request = match &self.identity_arn {
+                    Some(arn) => SendRawEmailRequest {
+                        source_arn: Some(arn.clone()),
+                        from_arn: Some(arn.clone()),
+                        return_path_arn: Some(arn.clone()),
+                        ..request
+                    },
+                    None => request,
+                };
+                self.secondary.send_raw_email(request).await
+            }
+            sent => sent,
+        }
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        match self.primary.send_email(request.clone()).await {
+            Err(e) if failure::is_regional(e.as_ref()) => {
+                self.failing_over(&e);
+                let request = match &self.identity_arn {
+                    Some(arn) => SendEmailRequest {
+                        source_arn: Some(arn.clone()),
+                        return_path_arn: Some(arn.clone()),
+                        ..request
+                    },
+                    None => request,
+                };
+                self.secondary.send_email(request).await
+            }
+            sent => sent,
+        }
+    }
+
+    /// Returns the quota of the primary region, or of the secondary one
+    /// while sending is paused in the primary.
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        match self.primary.send_quota().await? {
+            Some(quota) if !quota.sending_enabled => {
+                self.secondary.send_quota().await
+            }
+            quota => Ok(quota),
+        }
+    }
+}
+
+/// Message sent through a [`MockSender`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum SentEmail {
+    /// Raw MIME message
+    Raw(SendRawEmailRequest),
+
+    /// Message built by the mail service
+    Email(SendEmailRequest),
+}
+
+impl SentEmail {
+    /// Returns the envelope sender.
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            SentEmail::Raw(request) => request.source.as_deref(),
+            SentEmail::Email(request) => Some(&request.source),
+        }
+    }
+
+    /// Returns the recipients.
+    pub fn destinations(&self) -> Vec<String> {
+        match self {
+            SentEmail::Raw(request) => {
+                request.destinations.clone().unwrap_or_default()
+            }
+            SentEmail::Email(request) => {
+                let destination = &request.destination;
+                [
+                    &destination.to_addresses,
+                    &destination.cc_addresses,
+                    &destination.bcc_addresses,
+                ]
+                .into_iter()
+                .flatten()
+                .flatten()
+                .cloned()
+                .collect()
+            }
+        }
+    }
+
+    /// Returns the raw MIME message, when sent raw.
+    pub fn raw(&self) -> Option<&[u8]> {
+        match self {
+            SentEmail::Raw(request) => Some(&request.raw_message.data),
+            SentEmail::Email(_) => None,
+        }
+    }
+}
+
+/// [`EmailSender`] recording the messages instead of sending them, for
+/// tests.
+#[derive(Default)]
+pub struct MockSender {
+    sent: Mutex<Vec<SentEmail>>,
+    error: Option<Box<dyn Fn() -> Error + Send + Sync>>,
+    quota: Option<SendQuota>,
+}
+
+impl std::fmt::Debug for MockSender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MockSender")
+            .field("sent", &self.sent)
+            .field("failing", &self.error.is_some())
+            .field("quota", &self.quota)
+            .finish()
+    }
+}
+
+impl MockSender {
+    /// Creates a sender accepting every message, with the message ids
+    /// `mock-1`, `mock-2`, etc.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a sender failing every message with the error made by
+    /// `error`, e.g. a [`rusoto_core::RusotoError`] classified as the
+    /// errors of SES.
+    pub fn failing<F>(error: F) -> Self
+    where
+        F: Fn() -> Error + Send + Sync + 'static,
+    {
+        MockSender {
+            sent: Default::default(),
+            error: Some(Box::new(error)),
+            quota: None,
+        }
+    }
+
+    /// Returns the sender telling `quota` as the sending quota.
+    pub fn with_quota(mut self, quota: SendQuota) -> Self {
+        self.quota = Some(quota);
+        self
+    }
+
+    /// Returns the messages sent so far, in order, failed ones included.
+    pub fn sent(&self) -> Vec<SentEmail> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// Records `email`, returning its message id.
+    fn record(&self, email: SentEmail) -> Result<String, Error> {
+        let mut sent = self.sent.lock().unwrap();
+        sent.push(email);
+        match &self.error {
+            Some(error) => Err(error()),
+            None => Ok(format!("mock-{}", sent.len())),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailSender for MockSender {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        self.record(SentEmail::Raw(request))
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        self.record(SentEmail::Email(request))
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        Ok(self.quota)
+    }
+}
+
+/** Test module for senders of outgoing mail */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::RusotoError;
+    use rusoto_ses::{RawMessage, SendRawEmailError};
+
+    fn request() -> SendRawEmailRequest {
+        SendRawEmailRequest {
+            raw_message: RawMessage { data: "Subject: Hi\r\n\r\nHi".into() },
+            source: Some("fwd@nyah.dev".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_sender() {
+        let paused = || -> Error {
+            Box::new(RusotoError::Service(
+                SendRawEmailError::AccountSendingPaused(
+                    "Sending paused for this account.".to_owned(),
+                ),
+            ))
+        };
+        let primary = Arc::new(MockSender::failing(paused));
+        let secondary = Arc::new(MockSender::new());
+        let sender = FailoverSender::new(
+            primary.clone(),
+            secondary.clone(),
+            "eu-west-1",
+        )
+        .with_identity_arn("arn:aws:ses:eu-west-1:1:identity/nyah.dev");
+        assert_eq!(sender.send_raw_email(request()).await.unwrap(), "mock-1");
+        assert_eq!(primary.sent().len(), 1);
+        let SentEmail::Raw(failed_over) = &secondary.sent()[0] else {
+            panic!("{:?}", secondary.sent());
+        };
+        assert_eq!(
+            failed_over.from_arn.as_deref(),
+            Some("arn:aws:ses:eu-west-1:1:identity/nyah.dev")
+        );
+        assert_eq!(failed_over.raw_message, request().raw_message);
+
+        let rejected = || -> Error {
+            Box::new(RusotoError::Service(SendRawEmailError::MessageRejected(
+                "Email address is not verified.".to_owned(),
+            )))
+        };
+        let primary = Arc::new(MockSender::failing(rejected));
+        let secondary = Arc::new(MockSender::new());
+        let sender = FailoverSender::new(
+            primary.clone(),
+            secondary.clone(),
+            "eu-west-1",
+        );
+        assert!(sender.send_raw_email(request()).await.is_err());
+        assert!(secondary.sent().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failover_quota() {
+        let quota = SendQuota {
+            max_24_hour_send: 200.0,
+            sent_last_24_hours: 10.0,
+            sending_enabled: true,
+        };
+        let paused = SendQuota { sending_enabled: false, ..quota };
+        let sender = FailoverSender::new(
+            MockSender::new().with_quota(quota),
+            MockSender::new().with_quota(paused),
+            "eu-west-1",
+        );
+        assert_eq!(sender.send_quota().await.unwrap(), Some(quota));
+        let sender = FailoverSender::new(
+            MockSender::new().with_quota(paused),
+            MockSender::new().with_quota(quota),
+            "eu-west-1",
+        );
+        assert_eq!(sender.send_quota().await.unwrap(), Some(quota));
+    }
+}
@@ -0,0 +1,171 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Raw sends through the SESv2 API, built with the `sesv2` feature.
+//!
+//! SESv2 `SendEmail` accepts raw messages of up to 40 MB, where
+//! `SendRawEmail` stops at 10 MB, so larger forwards keep their
+//! attachments instead of falling back to a link to the stored original.
+//! Accounts need the higher limit granted by AWS for messages over 10 MB.
+//!
+//! With `ses_v2` set, the sender of each region is wrapped in a
+//! [`Sesv2Sender`], so raw sends keep the failover and quota handling of
+//! the service.
+use crate::aws;
+use crate::quota::SendQuota;
+use crate::sender::EmailSender;
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use rusoto_ses::{MessageTag, SendEmailRequest, SendRawEmailRequest};
+use serde_json::{json, Value};
+use tracing::trace;
+
+/// Returns the payload of a SESv2 `SendEmail` of the `raw` message, sent
+/// as the identity `identity_arn` if any.
+pub fn payload(
+    source: &str,
+    identity_arn: Option<&str>,
+    destinations: &[String],
+    raw: &[u8],
+    configuration_set: Option<&str>,
+    tags: &[MessageTag],
+) -> Value {
+    let mut payload = json!({
+        "FromEmailAddress": source,
+        "Destination": { "ToAddresses": destinations },
+        "Content": { "Raw": { "Data": STANDARD.encode(raw) } },
+    });
+    if let Some(arn) = identity_arn {
+        payload["FromEmailAddressIdentityArn"] = json!(arn);
+    }
+    if let Some(name) = configuration_set {
+        payload["ConfigurationSetName"] = json!(name);
+    }
+    if !tags.is_empty() {
+        payload["EmailTags"] = tags
+            .iter()
+            .map(|tag| json!({ "Name": tag.name, "Value": tag.value }))
+            .collect();
+    }
+    payload
+}
+
+/// Sends the raw message of `request` through the SESv2 API of `region`,
+/// returning the SES message id. The message is sent as is, already DKIM
+/// signed when a signer is configured.
+pub async fn send_raw_email(
+    region: &Region,
+    request: SendRawEmailRequest,
+) -> Result<String, Error> {
+    let source = request.source.ok_or("No source for SESv2 send")?;
+    let mut signed =
+        aws::sesv2_request(region, "POST", "/v2/email/outbound-emails");
+    signed.set_content_type("application/json".to_owned());
+    signed.set_payload(Some(serde_json::to_vec(&payload(
+        &source,
+        request.from_arn.as_deref(),
+        &request.destinations.unwrap_or_default(),
+        &request.raw_message.data,
+        request.configuration_set_name.as_deref(),
+        &request.tags.unwrap_or_default(),
+    ))?));
+    let response = aws::dispatch(signed).await?;
+    let body: Value = serde_json::from_slice(&response.body)?;
+    trace!("SESv2 send response: {:?}", body);
+    body["MessageId"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| "No MessageId in SESv2 response".into())
+}
+
+/// [`EmailSender`] sending raw messages through the SESv2 API of a region,
+/// and everything else through the sender it wraps.
+pub struct Sesv2Sender {
+    inner: Box<dyn EmailSender>,
+    region: Region,
+}
+
+impl Sesv2Sender {
+    /// Creates the sender of `region`, wrapping `inner` of the same
+    /// region.
+    pub fn new(inner: impl EmailSender + 'static, region: Region) -> Self {
+        Sesv2Sender { inner: Box::new(inner), region }
+    }
+}
+
+#[async_trait]
+impl EmailSender for Sesv2Sender {
+    async fn send_raw_email(
+        &self,
+        request: SendRawEmailRequest,
+    ) -> Result<String, Error> {
+        send_raw_email(&self.region, request).await
+    }
+
+    async fn send_email(
+        &self,
+        request: SendEmailRequest,
+    ) -> Result<String, Error> {
+        self.inner.send_email(request).await
+    }
+
+    async fn send_quota(&self) -> Result<Option<SendQuota>, Error> {
+        self.inner.send_quota().await
+    }
+}
+
+/** Test module for SESv2 sends */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sender::MockSender;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_payload() {
+        let payload = payload(
+            "test@nyah.dev",
+            None,
+            &["hello@nyah.dev".to_owned()],
+            b"Subject: Hi\r\n\r\nhello",
+            Some("forwards"),
+            &[MessageTag {
+                name: "alias".to_owned(),
+                value: "jobs".to_owned(),
+            }],
+        );
+        assert_eq!(payload["FromEmailAddress"], "test@nyah.dev");
+        assert!(payload.get("FromEmailAddressIdentityArn").is_none());
+        assert_eq!(payload["ConfigurationSetName"], "forwards");
+        assert_eq!(payload["EmailTags"][0]["Name"], "alias");
+        assert_eq!(payload["EmailTags"][0]["Value"], "jobs");
+        assert_eq!(payload["Destination"]["ToAddresses"][0], "hello@nyah.dev");
+        assert_eq!(
+            STANDARD
+                .decode(payload["Content"]["Raw"]["Data"].as_str().unwrap())
+                .unwrap(),
+            b"Subject: Hi\r\n\r\nhello"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sender_wraps_inner() {
+        let inner = Arc::new(MockSender::new().with_quota(SendQuota {
+            max_24_hour_send: 200.0,
+            sent_last_24_hours: 0.0,
+            sending_enabled: true,
+        }));
+        let sender = Sesv2Sender::new(inner.clone(), Region::UsEast1);
+        sender.send_email(SendEmailRequest::default()).await.unwrap();
+        assert_eq!(inner.sent().len(), 1);
+        assert!(sender.send_quota().await.unwrap().is_some());
+    }
+}
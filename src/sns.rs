@@ -0,0 +1,126 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! SES notifications delivered by SNS.
+//!
+//! SNS usually wraps a notification in an envelope, with the notification
+//! as the JSON string `Message`. With `RawMessageDelivery` enabled on the
+//! subscription, e.g. of an SQS queue in front of the function, the record
+//! body holds the notification itself. [`message`] detects either form.
+//!
+//! [`notification`] deserializes the notification straight from the JSON
+//! text, so reading a few of its fields, e.g. the message id, skips the
+//! raw content of the received message instead of copying it.
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// `Type` of SNS envelopes carrying a notification.
+const NOTIFICATION: &str = "Notification";
+
+/// SNS envelope, without the fields of no use to the handler.
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(rename = "Type", default)]
+    kind: Option<String>,
+
+    #[serde(rename = "Message", default)]
+    message: Option<String>,
+}
+
+/// Returns the SES notification of the first record of `event`, whether
+/// delivered in an SNS envelope or raw, or why there is none.
+pub fn message(event: &Value) -> Result<Value, String> {
+    notification(event)
+}
+
+/// Deserializes the SES notification of the first record of `event` as a
+/// `T`, whether delivered in an SNS envelope or raw, or returns why there
+/// is none.
+pub fn notification<T: DeserializeOwned>(event: &Value) -> Result<T, String> {
+    let record = &event["Records"][0];
+    if let Some(sns) = record.get("Sns") {
+        return match sns["Message"].as_str() {
+            Some(message) => parse(message),
+            None => Err("Missing SNS message".to_owned()),
+        };
+    }
+    // An SQS body holds the notification raw, or an envelope without raw
+    // delivery
+    if let Some(body) = record["body"].as_str() {
+        let envelope: Envelope = parse(body)?;
+        return match envelope {
+            Envelope { kind: Some(kind), message: Some(message) }
+                if kind == NOTIFICATION =>
+            {
+                parse(&message)
+            }
+            _ => parse(body),
+        };
+    }
+    if is_notification(record) {
+        return T::deserialize(record)
+            .map_err(|e| format!("Invalid SNS message: {}", e));
+    }
+    Err("Missing SNS payload".to_owned())
+}
+
+/// Whether `value` is an SES notification, of received mail or feedback.
+pub fn is_notification(value: &Value) -> bool {
+    value["mail"].is_object()
+        && (value.get("receipt").is_some()
+            || value.get("notificationType").is_some()
+            || value.get("eventType").is_some())
+}
+
+/// Parses the JSON notification `message`.
+fn parse<T: DeserializeOwned>(message: &str) -> Result<T, String> {
+    serde_json::from_str(message)
+        .map_err(|e| format!("Invalid SNS message: {}", e))
+}
+
+/** Test module for SNS notifications */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_message() {
+        let notification = json!({
+            "notificationType": "Received",
+            "mail": { "messageId": "abc" },
+            "receipt": {},
+        });
+        let enveloped = json!({
+            "Records": [{ "Sns": { "Message": notification.to_string() } }]
+        });
+        assert_eq!(message(&enveloped).unwrap(), notification);
+        let raw = json!({ "Records": [{ "body": notification.to_string() }] });
+        assert_eq!(message(&raw).unwrap(), notification);
+        let text = notification.to_string();
+        let envelope = json!({ "Type": "Notification", "Message": text });
+        let queued = json!({ "Records": [{ "body": envelope.to_string() }] });
+        assert_eq!(message(&queued).unwrap(), notification);
+        let direct = json!({ "Records": [notification.clone()] });
+        assert_eq!(message(&direct).unwrap(), notification);
+
+        assert_eq!(
+            message(&json!({ "Records": [{ "Sns": {} }] })),
+            Err("Missing SNS message".to_owned())
+        );
+        assert_eq!(
+            message(&json!({ "Records": [{}] })),
+            Err("Missing SNS payload".to_owned())
+        );
+        assert!(message(&json!({ "Records": [{ "body": "{" }] }))
+            .unwrap_err()
+            .starts_with("Invalid SNS message"));
+    }
+}
@@ -0,0 +1,233 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Sender Rewriting Scheme for the return path of forwards.
+//!
+//! Forwards carry a return path of the form
+//! `SRS0=<hash>=<ts>=<domain>=<user>@<srs domain>` so that the envelope
+//! sender belongs to the forwarding domain while bounces can still be
+//! routed back to the original sender. The hash is an HMAC over the
+//! timestamp, domain and user of the original address, each ended by a
+//! NUL byte, the timestamp a day count modulo 1024; both are base32 so
+//! that they survive case changes. Only bounces are routed back, so that
+//! the rewritten addresses can't be used to relay mail to the senders.
+use crate::config::PrivatEmailConfig;
+use crate::encoding::{base32_encode, BASE32};
+use crate::http::constant_time_eq;
+use hmac::{Hmac, Mac};
+use mailparse::{MailHeader, MailHeaderMap};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Local part prefix of rewritten addresses.
+pub const SRS0_PREFIX: &str = "SRS0=";
+
+/// Length of the address hash, in base32 characters.
+const HASH_LEN: usize = 4;
+
+/// Days after which a rewritten address is no longer accepted.
+pub const MAX_AGE_DAYS: u64 = 21;
+
+/// Timestamps wrap around after this many days.
+const TIMESTAMP_PERIOD: u64 = 1024;
+
+/// Error decoding a rewritten address.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SrsError {
+    /// The address was not rewritten by SRS
+    NotSrs,
+
+    /// The address does not have the SRS0 layout
+    Malformed,
+
+    /// The hash does not match, the address was forged
+    BadHash,
+
+    /// The address is older than [`MAX_AGE_DAYS`]
+    Expired,
+}
+
+impl std::fmt::Display for SrsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            SrsError::NotSrs => "not an SRS address",
+            SrsError::Malformed => "malformed SRS address",
+            SrsError::BadHash => "invalid SRS hash",
+            SrsError::Expired => "expired SRS address",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for SrsError {}
+
+/// Rewrites and restores envelope senders for a forwarding domain.
+#[derive(Clone, Debug)]
+pub struct Srs {
+    domain: String,
+    secret: Vec<u8>,
+}
+
+/// Returns the number of days since the Unix epoch.
+pub fn today() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or_default()
+}
+
+impl Srs {
+    /// Creates a rewriter for `domain`, hashing with `secret`.
+    pub fn new<D: ToString>(domain: D, secret: &[u8]) -> Self {
+        Srs {
+            domain: domain.to_string().to_lowercase(),
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// Returns the rewriter configured in `config`, if both the domain
+    /// and the secret are set.
+    pub fn from_config(config: &PrivatEmailConfig) -> Option<Self> {
+        let domain = config.srs_domain.as_ref()?;
+        let secret = config.srs_secret.as_ref()?;
+        Some(Srs::new(domain, secret.as_bytes()))
+    }
+
+    /// Rewrites `sender` as an address of the forwarding domain. Senders
+    /// without a domain are returned unchanged.
+    pub fn forward(&self, sender: &str, day: u64) -> String {
+        let Some((user, domain)) = sender.trim().rsplit_once('@') else {
+            return sender.to_owned();
+        };
+        let timestamp = timestamp(day);
+        format!(
+            "{}{}={}={}={}@{}",
+            SRS0_PREFIX,
+            self.hash(&timestamp, domain, user),
+            timestamp,
+            domain,
+            user,
+            self.domain
+        )
+    }
+
+    /// Restores the original sender from a rewritten `address`.
+    pub fn reverse(&self, address: &str, day: u64) -> Result<String, SrsError> {
+        let (local, domain) =
+            address.trim().rsplit_once('@').ok_or(SrsError::NotSrs)?;
+        let prefix = local.get(..SRS0_PREFIX.len()).ok_or(SrsError::NotSrs)?;
+        if !prefix.eq_ignore_ascii_case(SRS0_PREFIX)
+            || !domain.eq_ignore_ascii_case(&self.domain)
+        {
+            return Err(SrsError::NotSrs);
+        }
+
+        let mut parts = local[SRS0_PREFIX.len()..].splitn(4, '=');
+        let (Some(hash), Some(timestamp), Some(domain), Some(user)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(SrsError::Malformed);
+        };
+        let timestamp = timestamp.to_lowercase();
+        let expected = self.hash(&timestamp, domain, user);
+        if !constant_time_eq(&hash.to_lowercase(), &expected.to_lowercase()) {
+            return Err(SrsError::BadHash);
+        }
+        let stamped =
+            decode_timestamp(&timestamp).ok_or(SrsError::Malformed)?;
+        let age = (day % TIMESTAMP_PERIOD + TIMESTAMP_PERIOD - stamped)
+            % TIMESTAMP_PERIOD;
+        if age > MAX_AGE_DAYS {
+            return Err(SrsError::Expired);
+        }
+        Ok(format!("{}@{}", user, domain))
+    }
+
+    fn hash(&self, timestamp: &str, domain: &str, user: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any length");
+        // Separated so that no two splits of an address hash alike
+        for field in [timestamp, &domain.to_lowercase(), &user.to_lowercase()] {
+            mac.update(field.as_bytes());
+            mac.update(b"\0");
+        }
+        let mut hash = base32_encode(&mac.finalize().into_bytes());
+        hash.truncate(HASH_LEN);
+        hash
+    }
+}
+
+/// Whether the message with `headers` from the envelope sender `source` is
+/// a bounce: a delivery report, or mail with a null return path.
+pub fn is_bounce(headers: &[MailHeader], source: &str) -> bool {
+    matches!(source.trim(), "" | "<>")
+        || headers.get_first_value("Content-Type").is_some_and(|t| {
+            t.trim().to_lowercase().starts_with("multipart/report")
+        })
+}
+
+/// Encodes `day` as two base32 characters.
+fn timestamp(day: u64) -> String {
+    let day = day % TIMESTAMP_PERIOD;
+    [day >> 5, day & 31].iter().map(|v| BASE32[*v as usize] as char).collect()
+}
+
+fn decode_timestamp(timestamp: &str) -> Option<u64> {
+    let mut day = 0;
+    for c in timestamp.bytes() {
+        day = (day << 5) | BASE32.iter().position(|a| *a == c)? as u64;
+    }
+    (timestamp.len() == 2).then_some(day)
+}
+
+/** Test module for the Sender Rewriting Scheme */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srs_round_trip() {
+        let srs = Srs::new("nyah.dev", b"secret");
+        let rewritten = srs.forward("fufu@achu.soup", 19_000);
+        assert!(rewritten.starts_with("SRS0="));
+        assert!(rewritten.ends_with("=achu.soup=fufu@nyah.dev"));
+        assert_eq!(
+            srs.reverse(&rewritten.to_lowercase(), 19_001).unwrap(),
+            "fufu@achu.soup"
+        );
+        assert_eq!(srs.reverse(&rewritten, 19_030), Err(SrsError::Expired));
+    }
+
+    #[test]
+    fn test_srs_rejects_forgeries() {
+        let srs = Srs::new("nyah.dev", b"secret");
+        let rewritten = srs.forward("fufu@achu.soup", 19_000);
+        let forged = rewritten.replace("fufu@", "mallory@");
+        assert_eq!(srs.reverse(&forged, 19_000), Err(SrsError::BadHash));
+        assert_eq!(srs.reverse("fufu@nyah.dev", 19_000), Err(SrsError::NotSrs));
+        assert_eq!(
+            srs.reverse("SRS0=abcd@nyah.dev", 19_000),
+            Err(SrsError::Malformed)
+        );
+        assert_ne!(srs.hash("aa", "b.io", "ab"), srs.hash("aa", "b.ioa", "b"));
+    }
+
+    #[test]
+    fn test_is_bounce() {
+        let (report, _) = mailparse::parse_headers(
+            b"Content-Type: multipart/report; \
+              report-type=delivery-status\r\n\r\n",
+        )
+        .unwrap();
+        assert!(is_bounce(&report, "mailer-daemon@achu.soup"));
+        assert!(is_bounce(&[], ""));
+        assert!(is_bounce(&[], "<>"));
+        assert!(!is_bounce(&[], "fufu@achu.soup"));
+    }
+}
@@ -0,0 +1,149 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Periodic statistics reports.
+//!
+//! An EventBridge cron schedule invoking the function with
+//! `{"stats": true}`, e.g. weekly, emails `stats_email` what the counters
+//! of the store gained since the previous report: messages by outcome,
+//! spam, bounces and the volume of every alias. The counters at each
+//! report are kept under [`SNAPSHOT_KEY`].
+use crate::feedback;
+use crate::quiet;
+use crate::store::MailAction;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Key of the counters at the previous report.
+pub const SNAPSHOT_KEY: &str = "stats/snapshot.json";
+
+/// Counter of messages blocked by the SES spam or virus verdict.
+pub const SPAM_COUNTER: &str = "spam";
+
+/// Prefix of the counters of messages received per alias.
+pub const ALIAS_PREFIX: &str = "alias/";
+
+/// Returns the counter name of messages received for `alias`.
+pub fn alias_counter(alias: &str) -> String {
+    format!("{}{}", ALIAS_PREFIX, alias.to_lowercase())
+}
+
+/// Counters at the time of a report.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    /// Unix time of the report
+    pub taken_at: i64,
+
+    /// Every counter and its value
+    pub counters: BTreeMap<String, u64>,
+}
+
+impl Snapshot {
+    /// Returns what each counter gained since `previous`.
+    pub fn since(&self, previous: &Snapshot) -> BTreeMap<String, u64> {
+        self.counters
+            .iter()
+            .map(|(name, &value)| {
+                let before = previous.counters.get(name).copied();
+                (name.clone(), value.saturating_sub(before.unwrap_or(0)))
+            })
+            .collect()
+    }
+}
+
+/// Returns the subject of the report of the period ending at `now`.
+pub fn subject(now: i64) -> String {
+    format!("privatemail statistics to {}", day(now))
+}
+
+/// Renders the counters gained between `previous` and `current` as the
+/// plain text body of a report.
+pub fn render_text(previous: &Snapshot, current: &Snapshot) -> String {
+    let gained = current.since(previous);
+    let count = |name: &str| gained.get(name).copied().unwrap_or(0);
+    let mut text = match previous.taken_at {
+        0 => format!("Until {}\r\n\r\n", day(current.taken_at)),
+        since => {
+            format!("From {} to {}\r\n\r\n", day(since), day(current.taken_at))
+        }
+    };
+    for (label, name) in [
+        ("Forwarded", MailAction::Forwarded.as_str()),
+        ("Blocked", MailAction::Blocked.as_str()),
+        ("Spam", SPAM_COUNTER),
+        ("Quarantined", MailAction::Quarantined.as_str()),
+        ("Digested", MailAction::Digested.as_str()),
+        ("Failed", MailAction::Failed.as_str()),
+        ("Bounces", feedback::BOUNCE_COUNTER),
+    ] {
+        text.push_str(&format!("{:<12} {:>8}\r\n", label, count(name)));
+    }
+    let mut aliases: Vec<(&str, u64)> = gained
+        .iter()
+        .filter_map(|(name, &value)| {
+            Some((name.strip_prefix(ALIAS_PREFIX)?, value))
+        })
+        .filter(|&(_, value)| value > 0)
+        .collect();
+    if !aliases.is_empty() {
+        aliases.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        text.push_str("\r\nPer alias\r\n");
+        for (alias, value) in aliases {
+            text.push_str(&format!("  {:<32} {:>8}\r\n", alias, value));
+        }
+    }
+    text
+}
+
+/// Returns the `YYYY-MM-DD` day of the Unix time `secs`.
+fn day(secs: i64) -> String {
+    quiet::timestamp(secs)[..10].to_owned()
+}
+
+/** Test module for statistics reports */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_text() {
+        let snapshot = |taken_at, counters: &[(&str, u64)]| Snapshot {
+            taken_at,
+            counters: counters
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value))
+                .collect(),
+        };
+        let previous = snapshot(1647079576, &[("forwarded", 10), ("spam", 1)]);
+        let current = snapshot(
+            1647684376,
+            &[
+                ("forwarded", 15),
+                ("spam", 3),
+                ("bounce", 1),
+                ("alias/hello@nyah.dev", 4),
+                ("alias/jobs@nyah.dev", 9),
+            ],
+        );
+        assert_eq!(current.since(&previous)["forwarded"], 5);
+        let text = render_text(&previous, &current);
+        assert!(text.starts_with("From 2022-03-12 to 2022-03-19\r\n"));
+        assert!(text.contains("Forwarded           5\r\n"));
+        assert!(text.contains("Spam                2\r\n"));
+        assert!(text.contains("Bounces             1\r\n"));
+        let jobs = text.find("jobs@nyah.dev").unwrap();
+        assert!(jobs < text.find("hello@nyah.dev").unwrap());
+
+        let text = render_text(&Snapshot::default(), &current);
+        assert!(text.starts_with("Until 2022-03-19\r\n"));
+        assert_eq!(alias_counter("Hello@Nyah.dev"), "alias/hello@nyah.dev");
+        assert_eq!(subject(1647684376), "privatemail statistics to 2022-03-19");
+    }
+}
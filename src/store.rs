@@ -0,0 +1,450 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Persistence layer for `PrivatEmail`.
+//!
+//! Two traits abstract over the storage backends used by the pipeline:
+//! - [`ObjectStore`]: raw message blobs (archive, quarantine).
+//! - [`RecordStore`]: per-message metadata records and counters.
+//!
+//! [`MailStore`] bundles one of each and is what the handler talks to.
+use crate::config::PrivatEmailConfig;
+use crate::{stats, thread};
+use async_trait::async_trait;
+use bytes::Bytes;
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub mod dedup;
+pub mod envelope;
+pub mod fs;
+pub mod s3;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+/// Key prefix for archived messages.
+pub const ARCHIVE_PREFIX: &str = "archive/";
+
+/// Key prefix for quarantined messages.
+pub const QUARANTINE_PREFIX: &str = "quarantine/";
+
+/// Key prefix for originals which exceeded the SES send limit.
+pub const OVERSIZED_PREFIX: &str = "oversized/";
+
+/// Key prefix for messages which could not be sent.
+pub const FAILED_PREFIX: &str = "failed/";
+
+/// Key prefix for messages waiting for the next digest.
+pub const DIGEST_PREFIX: &str = "digest/";
+
+/// Outcome of processing a single message.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailAction {
+    /// Message was forwarded to the destination inbox
+    Forwarded,
+
+    /// Message was dropped by a verdict or blocklist
+    Blocked,
+
+    /// Message was held back in quarantine
+    Quarantined,
+
+    /// Forwarding the message failed
+    Failed,
+
+    /// Reply from the destination inbox was relayed to the original sender
+    Relayed,
+
+    /// Message was held back until the quiet hours of its route end
+    Deferred,
+
+    /// Message was sent to a chat instead of being forwarded
+    Notified,
+
+    /// Message was held back for the daily digest
+    Digested,
+
+    /// Message was a redelivery of one already handled
+    Duplicate,
+}
+
+impl MailAction {
+    /// Name of the action, also used as counter name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MailAction::Forwarded => "forwarded",
+            MailAction::Blocked => "blocked",
+            MailAction::Quarantined => "quarantined",
+            MailAction::Failed => "failed",
+            MailAction::Relayed => "relayed",
+            MailAction::Deferred => "deferred",
+            MailAction::Notified => "notified",
+            MailAction::Digested => "digested",
+            MailAction::Duplicate => "duplicate",
+        }
+    }
+}
+
+impl std::str::FromStr for MailAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forwarded" => Ok(MailAction::Forwarded),
+            "blocked" => Ok(MailAction::Blocked),
+            "quarantined" => Ok(MailAction::Quarantined),
+            "failed" => Ok(MailAction::Failed),
+            "relayed" => Ok(MailAction::Relayed),
+            "deferred" => Ok(MailAction::Deferred),
+            "notified" => Ok(MailAction::Notified),
+            "digested" => Ok(MailAction::Digested),
+            "duplicate" => Ok(MailAction::Duplicate),
+            other => Err(format!("Invalid mail action: {}", other)),
+        }
+    }
+}
+
+/// Metadata record kept for every processed message.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MailRecord {
+    /// SES message id
+    pub message_id: String,
+
+    /// RFC 3339 timestamp at which SES received the message
+    pub timestamp: String,
+
+    /// Envelope sender
+    pub source: String,
+
+    /// Envelope recipients
+    pub destination: Vec<String>,
+
+    /// Message subject
+    pub subject: String,
+
+    /// Action taken on the message
+    pub action: MailAction,
+
+    /// Key of the raw message in the object store, if stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object_key: Option<String>,
+
+    /// Conversation the message belongs to, see [`crate::thread`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+}
+
+/// Blob storage for raw messages.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    /// Writes `body` under `key`, replacing any previous object. Stores
+    /// sending it elsewhere share the buffer instead of copying it.
+    async fn put(&self, key: &str, body: Bytes) -> Result<(), Error>;
+
+    /// Reads the object stored under `key`.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Deletes the object stored under `key`, if any.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    /// Lists the keys starting with `prefix`, in lexicographic order.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+
+    /// Returns a URL from which the object under `key` can be fetched
+    /// for `expires_in`.
+    async fn link(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, Error>;
+}
+
+/// Metadata storage for processed messages and counters.
+#[async_trait]
+pub trait RecordStore: Send + Sync {
+    /// Inserts or replaces the record for `record.message_id`.
+    async fn put_record(&self, record: &MailRecord) -> Result<(), Error>;
+
+    /// Fetches the record of `message_id`.
+    async fn get_record(
+        &self,
+        message_id: &str,
+    ) -> Result<Option<MailRecord>, Error>;
+
+    /// Deletes the record of `message_id`, if any.
+    async fn delete_record(&self, message_id: &str) -> Result<(), Error>;
+
+    /// Lists records with `from <= timestamp < to`, oldest first.
+    async fn list_records(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<MailRecord>, Error>;
+
+    /// Adds `by` to the counter `name`, returning the new value.
+    async fn increment(&self, name: &str, by: u64) -> Result<u64, Error>;
+
+    /// Subtracts `by` from the counter `name`, stopping at zero, and
+    /// returns the new value.
+    async fn decrement(&self, name: &str, by: u64) -> Result<u64, Error>;
+
+    /// Returns every counter and its value.
+    async fn counters(&self) -> Result<BTreeMap<String, u64>, Error>;
+}
+
+/// In-memory [`RecordStore`], used when no database is available.
+#[derive(Debug, Default)]
+pub struct MemoryRecordStore {
+    records: Mutex<BTreeMap<String, MailRecord>>,
+    counters: Mutex<BTreeMap<String, u64>>,
+}
+
+#[async_trait]
+impl RecordStore for MemoryRecordStore {
+    async fn put_record(&self, record: &MailRecord) -> Result<(), Error> {
+        self.records
+            .lock()
+            .unwrap()
+            .insert(record.message_id.clone(), record.clone());
+        Ok(())
+    }
+
+    async fn get_record(
+        &self,
+        message_id: &str,
+    ) -> Result<Option<MailRecord>, Error> {
+        Ok(self.records.lock().unwrap().get(message_id).cloned())
+    }
+
+    async fn delete_record(&self, message_id: &str) -> Result<(), Error> {
+        self.records.lock().unwrap().remove(message_id);
+        Ok(())
+    }
+
+    async fn list_records(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<MailRecord>, Error> {
+        let mut records: Vec<MailRecord> = self
+            .records
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|r| {
+                r.timestamp.as_str() >= from && r.timestamp.as_str() < to
+            })
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        Ok(records)
+    }
+
+    async fn increment(&self, name: &str, by: u64) -> Result<u64, Error> {
+        let mut counters = self.counters.lock().unwrap();
+        let value = counters.entry(name.to_owned()).or_insert(0);
+        *value += by;
+        Ok(*value)
+    }
+
+    async fn decrement(&self, name: &str, by: u64) -> Result<u64, Error> {
+        let mut counters = self.counters.lock().unwrap();
+        let value = counters.entry(name.to_owned()).or_insert(0);
+        *value = value.saturating_sub(by);
+        Ok(*value)
+    }
+
+    async fn counters(&self) -> Result<BTreeMap<String, u64>, Error> {
+        Ok(self.counters.lock().unwrap().clone())
+    }
+}
+
+/// Object and record stores used by the handler.
+pub struct MailStore {
+    /// Raw message storage
+    pub objects: Box<dyn ObjectStore>,
+
+    /// Metadata and counter storage
+    pub records: Box<dyn RecordStore>,
+
+    /// Whether every processed message is archived, not only quarantined ones
+    pub archive: bool,
+
+    /// Whether archived attachments are stored once, see [`dedup`]
+    pub dedup_attachments: bool,
+}
+
+impl MailStore {
+    /// Creates a `MailStore` from its parts.
+    pub fn new(
+        objects: Box<dyn ObjectStore>,
+        records: Box<dyn RecordStore>,
+    ) -> Self {
+        MailStore { objects, records, archive: false, dedup_attachments: false }
+    }
+
+    /// Creates the store configured in `config`, if any.
+    ///
+    /// With `local_store_dir` set, raw messages are kept on the filesystem
+    /// and records in a SQLite database inside the same directory (or in
+    /// memory when the crate is built without the `sqlite` feature), and
+    /// every message is archived. Otherwise `s3_bucket` holds quarantined
+    /// and oversized messages.
+    pub fn from_config(
+        config: &PrivatEmailConfig,
+    ) -> Result<Option<Self>, Error> {
+        if let Some(dir) = &config.local_store_dir {
+            let objects = Box::new(fs::FsObjectStore::new(dir)?);
+
+            #[cfg(feature = "sqlite")]
+            let records: Box<dyn RecordStore> = Box::new(
+                sqlite::SqliteRecordStore::open(dir.join("privatemail.db"))?,
+            );
+            #[cfg(not(feature = "sqlite"))]
+            let records: Box<dyn RecordStore> =
+                Box::new(MemoryRecordStore::default());
+
+            let mut store = MailStore::new(objects, records);
+            store.archive = true;
+            store.dedup_attachments = config.dedup_attachments;
+            return Ok(Some(store));
+        }
+
+        Ok(config.s3_bucket.as_ref().map(|bucket| {
+            MailStore::new(
+                Box::new(
+                    s3::S3ObjectStore::new(bucket, Region::default())
+                        .with_sse_kms(config.sse_kms_key_id.clone()),
+                ),
+                Box::new(MemoryRecordStore::default()),
+            )
+        }))
+    }
+
+    /// Records the outcome of a message, bumping the counters of
+    /// `record.action` and of its recipients and, for forwarded messages,
+    /// of its thread. The raw
+    /// message is stored when it is quarantined or when the store archives
+    /// every message.
+    pub async fn track(
+        &self,
+        mut record: MailRecord,
+        raw: Bytes,
+    ) -> Result<MailRecord, Error> {
+        let prefix = match record.action {
+            MailAction::Quarantined => QUARANTINE_PREFIX,
+            MailAction::Digested => DIGEST_PREFIX,
+            _ if self.archive => ARCHIVE_PREFIX,
+            _ => "",
+        };
+        if !prefix.is_empty() {
+            let key = format!("{}{}.eml", prefix, record.message_id);
+            if prefix == ARCHIVE_PREFIX && self.dedup_attachments {
+                dedup::archive(
+                    &*self.objects,
+                    &*self.records,
+                    &record.message_id,
+                    &key,
+                    &raw,
+                )
+                .await?;
+            } else {
+                self.objects.put(&key, raw).await?;
+            }
+            record.object_key = Some(key);
+        }
+        self.records.put_record(&record).await?;
+        self.records.increment(record.action.as_str(), 1).await?;
+        for recipient in &record.destination {
+            self.records.increment(&stats::alias_counter(recipient), 1).await?;
+        }
+        if let (MailAction::Forwarded, Some(thread_id)) =
+            (record.action, &record.thread_id)
+        {
+            self.records.increment(&thread::counter_key(thread_id), 1).await?;
+        }
+        Ok(record)
+    }
+
+    /// Returns the number of messages forwarded in `thread_id`.
+    pub async fn thread_count(&self, thread_id: &str) -> Result<u64, Error> {
+        self.records.increment(&thread::counter_key(thread_id), 0).await
+    }
+}
+
+/** Test module for the persistence layer */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(message_id: &str, timestamp: &str) -> MailRecord {
+        MailRecord {
+            message_id: message_id.to_owned(),
+            timestamp: timestamp.to_owned(),
+            source: "fufu@achu.soup".to_owned(),
+            destination: vec!["samubu@user.earth".to_owned()],
+            subject: "Testing".to_owned(),
+            action: MailAction::Forwarded,
+            object_key: None,
+            thread_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_record_store() {
+        let store = MemoryRecordStore::default();
+        store
+            .put_record(&record("b", "2021-03-19T08:46:16.420Z"))
+            .await
+            .unwrap();
+        store
+            .put_record(&record("a", "2021-03-18T08:46:16.420Z"))
+            .await
+            .unwrap();
+        store
+            .put_record(&record("c", "2021-03-20T08:46:16.420Z"))
+            .await
+            .unwrap();
+
+        let listed = store
+            .list_records("2021-03-18T00:00:00Z", "2021-03-20T00:00:00Z")
+            .await
+            .unwrap();
+        let ids: Vec<&str> =
+            listed.iter().map(|r| r.message_id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+
+        store.delete_record("a").await.unwrap();
+        assert!(store.get_record("a").await.unwrap().is_none());
+
+        assert_eq!(store.increment("forwarded", 2).await.unwrap(), 2);
+        assert_eq!(store.increment("forwarded", 1).await.unwrap(), 3);
+        assert_eq!(store.counters().await.unwrap()["forwarded"], 3);
+    }
+
+    #[test]
+    fn test_mail_action_round_trip() {
+        for action in [
+            MailAction::Forwarded,
+            MailAction::Blocked,
+            MailAction::Quarantined,
+            MailAction::Failed,
+            MailAction::Digested,
+            MailAction::Duplicate,
+        ] {
+            assert_eq!(action.as_str().parse(), Ok(action));
+        }
+    }
+}
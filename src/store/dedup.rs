@@ -0,0 +1,275 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Content-addressed attachment storage for the archive.
+//!
+//! Base64 attachments are stored once under `attachments/<sha256>` with a
+//! reference count kept as a counter in the [`RecordStore`]. The archived
+//! message keeps its structure but each attachment body is replaced by a
+//! `sha256:<hash>` line; a [`Manifest`] lists the shared objects and
+//! [`restore`] puts the bodies back.
+use super::{ObjectStore, RecordStore};
+use crate::received::{self, Hop};
+use crate::{content, mime};
+use lambda_runtime::Error;
+use mailparse::{parse_headers, DispositionType, MailHeaderMap, ParsedMail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Key prefix for content-addressed attachments.
+pub const ATTACHMENT_PREFIX: &str = "attachments/";
+
+/// Key prefix for archive manifests.
+pub const MANIFEST_PREFIX: &str = "manifests/";
+
+/// Marker replacing attachment bodies in archived messages.
+const REFERENCE_MARKER: &str = "sha256:";
+
+/// Shared attachment referenced by an archived message.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttachmentRef {
+    /// File name of the attachment, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+
+    /// MIME type of the attachment
+    pub content_type: String,
+
+    /// Hex encoded SHA-256 of the decoded content
+    pub sha256: String,
+
+    /// Decoded size in bytes
+    pub size: usize,
+}
+
+/// Describes an archived message and the attachments it references.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    /// SES message id
+    pub message_id: String,
+
+    /// Key of the archived message with attachment bodies removed
+    pub message_key: String,
+
+    /// Attachments in the order they appear in the message
+    pub attachments: Vec<AttachmentRef>,
+
+    /// `Received` chain of the message, originating hop first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub received: Vec<Hop>,
+}
+
+impl Manifest {
+    /// Returns the key under which the manifest of `message_id` is kept.
+    pub fn key(message_id: &str) -> String {
+        format!("{}{}.json", MANIFEST_PREFIX, message_id)
+    }
+}
+
+/// Returns the object key of the attachment hashed `sha256`.
+pub fn attachment_key(sha256: &str) -> String {
+    format!("{}{}", ATTACHMENT_PREFIX, sha256)
+}
+
+/// Returns the byte range of the body of `part` within `raw`.
+fn body_range(raw: &[u8], part: &ParsedMail) -> Option<(usize, usize)> {
+    let start =
+        (part.raw_bytes.as_ptr() as usize).checked_sub(raw.as_ptr() as usize)?;
+    let (_, body) = parse_headers(part.raw_bytes).ok()?;
+    Some((start + body, start + part.raw_bytes.len()))
+}
+
+fn is_base64(part: &ParsedMail) -> bool {
+    part.headers
+        .get_first_value("Content-Transfer-Encoding")
+        .is_some_and(|e| e.trim().eq_ignore_ascii_case("base64"))
+}
+
+/// Collects the base64 attachment leaves of `part`.
+fn leaves<'a>(part: &'a ParsedMail<'a>, out: &mut Vec<&'a ParsedMail<'a>>) {
+    if !part.subparts.is_empty() {
+        for subpart in &part.subparts {
+            leaves(subpart, out);
+        }
+        return;
+    }
+    let disposition = part.get_content_disposition();
+    if disposition.disposition == DispositionType::Attachment && is_base64(part)
+    {
+        out.push(part);
+    }
+}
+
+/// Replaces the byte ranges in `edits` (sorted, non-overlapping) of `raw`.
+fn splice(raw: &[u8], edits: Vec<((usize, usize), Vec<u8>)>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(raw.len());
+    let mut at = 0;
+    for ((start, end), replacement) in edits {
+        out.extend_from_slice(&raw[at..start]);
+        out.extend(replacement);
+        at = end;
+    }
+    out.extend_from_slice(&raw[at..]);
+    out
+}
+
+/// Archives `raw` under `message_key`, storing its attachments once and
+/// writing the manifest of `message_id`.
+pub async fn archive(
+    objects: &dyn ObjectStore,
+    records: &dyn RecordStore,
+    message_id: &str,
+    message_key: &str,
+    raw: &[u8],
+) -> Result<Manifest, Error> {
+    let mail = content::parse(raw)?;
+    let mut parts = Vec::new();
+    leaves(&mail, &mut parts);
+
+    let mut attachments = Vec::new();
+    let mut edits = Vec::new();
+    for part in parts {
+        let Some(range) = body_range(raw, part) else {
+            continue;
+        };
+        let data = part.get_body_raw()?;
+        let size = data.len();
+        let sha256 = hex::encode(Sha256::digest(&data));
+        let key = attachment_key(&sha256);
+        if records.increment(&key, 1).await? == 1 {
+            objects.put(&key, data.into()).await?;
+        }
+        edits.push((
+            range,
+            format!("{}{}\r\n", REFERENCE_MARKER, sha256).into(),
+        ));
+        attachments.push(AttachmentRef {
+            filename: part
+                .get_content_disposition()
+                .params
+                .get("filename")
+                .cloned(),
+            content_type: part.ctype.mimetype.clone(),
+            sha256,
+            size,
+        });
+    }
+
+    objects.put(message_key, splice(raw, edits).into()).await?;
+    let manifest = Manifest {
+        message_id: message_id.to_owned(),
+        message_key: message_key.to_owned(),
+        attachments,
+        received: received::chain(&mail.headers),
+    };
+    objects
+        .put(&Manifest::key(message_id), serde_json::to_vec(&manifest)?.into())
+        .await?;
+    Ok(manifest)
+}
+
+/// Rebuilds the archived message of `manifest`, re-encoding the shared
+/// attachments in base64.
+pub async fn restore(
+    objects: &dyn ObjectStore,
+    manifest: &Manifest,
+) -> Result<Vec<u8>, Error> {
+    let stored =
+        objects.get(&manifest.message_key).await?.ok_or_else(|| {
+            format!("Missing archived message {}", manifest.message_key)
+        })?;
+    let mail = content::parse(&stored)?;
+    let mut parts = Vec::new();
+    leaves(&mail, &mut parts);
+
+    let mut edits = Vec::new();
+    for part in parts {
+        let Some(range) = body_range(&stored, part) else {
+            continue;
+        };
+        // The body holds the reference marker, which is not valid base64
+        let body = String::from_utf8_lossy(&stored[range.0..range.1]);
+        let Some(sha256) = body.trim().strip_prefix(REFERENCE_MARKER) else {
+            continue;
+        };
+        let data = objects
+            .get(&attachment_key(sha256))
+            .await?
+            .ok_or_else(|| format!("Missing attachment {}", sha256))?;
+        edits.push((range, mime::base64_lines(&data)));
+    }
+    Ok(splice(&stored, edits))
+}
+
+/// Drops the references of `manifest`, deleting attachments no longer
+/// referenced by any message, then the message and manifest themselves.
+pub async fn release(
+    objects: &dyn ObjectStore,
+    records: &dyn RecordStore,
+    manifest: &Manifest,
+) -> Result<(), Error> {
+    for attachment in &manifest.attachments {
+        let key = attachment_key(&attachment.sha256);
+        if records.decrement(&key, 1).await? == 0 {
+            objects.delete(&key).await?;
+        }
+    }
+    objects.delete(&manifest.message_key).await?;
+    objects.delete(&Manifest::key(&manifest.message_id)).await
+}
+
+/** Test module for attachment deduplication */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mime::{Attachment, MimeMessage};
+    use crate::store::fs::FsObjectStore;
+    use crate::store::MemoryRecordStore;
+
+    fn message(text: &str) -> Vec<u8> {
+        MimeMessage::new()
+            .header("Subject", text)
+            .text(text)
+            .attach(Attachment::new("logo.png", "image/png", vec![7; 300]))
+            .to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_dedup_archive_round_trip() {
+        let root = std::env::temp_dir()
+            .join(format!("privatemail-dedup-{}", std::process::id()));
+        let objects = FsObjectStore::new(&root).unwrap();
+        let records = MemoryRecordStore::default();
+
+        let (first, second) = (message("first"), message("second"));
+        let a = archive(&objects, &records, "a", "archive/a.eml", &first)
+            .await
+            .unwrap();
+        let b = archive(&objects, &records, "b", "archive/b.eml", &second)
+            .await
+            .unwrap();
+        assert_eq!(a.attachments[0].sha256, b.attachments[0].sha256);
+        assert_eq!(objects.list(ATTACHMENT_PREFIX).await.unwrap().len(), 1);
+
+        let stored = objects.get("archive/a.eml").await.unwrap().unwrap();
+        assert!(stored.len() < first.len());
+        let restored = restore(&objects, &a).await.unwrap();
+        let restored = mailparse::parse_mail(&restored).unwrap();
+        assert_eq!(restored.subparts[1].get_body_raw().unwrap(), vec![7; 300]);
+
+        release(&objects, &records, &a).await.unwrap();
+        assert_eq!(objects.list(ATTACHMENT_PREFIX).await.unwrap().len(), 1);
+        release(&objects, &records, &b).await.unwrap();
+        assert!(objects.list(ATTACHMENT_PREFIX).await.unwrap().is_empty());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,202 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Client-side envelope encryption of S3 objects with KMS.
+//!
+//! Each object is encrypted with AES-256-GCM under a data key of its own,
+//! generated by KMS under the configured key. The data key is only stored
+//! encrypted by KMS, in the object metadata, so reading the object needs
+//! `kms:Decrypt` on the key as well as access to the bucket. The metadata
+//! follows version 2 of the AWS S3 Encryption Client with the
+//! `kms+context` wrapping, so AWS SDK encryption clients read the objects
+//! too.
+use crate::aws;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lambda_runtime::Error;
+use rand::Rng;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// Metadata holding the data key encrypted by KMS.
+pub const KEY_HEADER: &str = "x-amz-meta-x-amz-key-v2";
+
+/// Metadata holding the initialization vector.
+pub const IV_HEADER: &str = "x-amz-meta-x-amz-iv";
+
+/// Metadata holding the content encryption algorithm.
+pub const CEK_ALG_HEADER: &str = "x-amz-meta-x-amz-cek-alg";
+
+/// Metadata holding the key wrapping algorithm.
+pub const WRAP_ALG_HEADER: &str = "x-amz-meta-x-amz-wrap-alg";
+
+/// Metadata holding the length of the authentication tag, in bits.
+pub const TAG_LEN_HEADER: &str = "x-amz-meta-x-amz-tag-len";
+
+/// Metadata holding the encryption context of the data key.
+pub const MATDESC_HEADER: &str = "x-amz-meta-x-amz-matdesc";
+
+/// Content encryption algorithm.
+const CEK_ALG: &str = "AES/GCM/NoPadding";
+
+/// Key wrapping algorithm.
+const WRAP_ALG: &str = "kms+context";
+
+/// Returns the KMS encryption context of data keys.
+fn context() -> Value {
+    json!({ "aws:x-amz-cek-alg": CEK_ALG })
+}
+
+/// Encrypts `body` under a new data key of the KMS key `key_id`. Returns
+/// the ciphertext and the metadata headers to store it with.
+pub async fn encrypt(
+    key_id: &str,
+    body: &[u8],
+) -> Result<(Vec<u8>, Vec<(&'static str, String)>), Error> {
+    let response = kms(
+        &aws::arn_region(key_id),
+        "GenerateDataKey",
+        json!({
+            "KeyId": key_id,
+            "KeySpec": "AES_256",
+            "EncryptionContext": context(),
+        }),
+    )
+    .await?;
+    let key = blob(&response, "Plaintext")?;
+    let encrypted_key = blob(&response, "CiphertextBlob")?;
+    let iv: [u8; 12] = rand::thread_rng().gen();
+    let ciphertext = seal(&key, &iv, body)?;
+    let headers = vec![
+        (KEY_HEADER, STANDARD.encode(encrypted_key)),
+        (IV_HEADER, STANDARD.encode(iv)),
+        (CEK_ALG_HEADER, CEK_ALG.to_owned()),
+        (WRAP_ALG_HEADER, WRAP_ALG.to_owned()),
+        (TAG_LEN_HEADER, "128".to_owned()),
+        (MATDESC_HEADER, context().to_string()),
+    ];
+    Ok((ciphertext, headers))
+}
+
+/// Decrypts `body` stored with the metadata `headers`, as written by
+/// [`encrypt`], with KMS in the region of `key_id`, if given. Returns
+/// `None` when `body` is not envelope encrypted.
+pub async fn decrypt(
+    key_id: Option<&str>,
+    headers: &BTreeMap<String, String>,
+    body: &[u8],
+) -> Result<Option<Vec<u8>>, Error> {
+    let Some(encrypted_key) = headers.get(KEY_HEADER) else {
+        return Ok(None);
+    };
+    let algorithms = (
+        headers.get(CEK_ALG_HEADER).map(String::as_str),
+        headers.get(WRAP_ALG_HEADER).map(String::as_str),
+    );
+    if algorithms != (Some(CEK_ALG), Some(WRAP_ALG)) {
+        return Err(format!("Unsupported envelope {:?}", algorithms).into());
+    }
+    let iv = STANDARD.decode(headers.get(IV_HEADER).ok_or("Missing IV")?)?;
+    let context: Value = match headers.get(MATDESC_HEADER) {
+        Some(matdesc) => serde_json::from_str(matdesc)?,
+        None => context(),
+    };
+    let region = key_id.map(aws::arn_region).unwrap_or_default();
+    let response = kms(
+        &region,
+        "Decrypt",
+        json!({
+            "CiphertextBlob": encrypted_key,
+            "EncryptionContext": context,
+        }),
+    )
+    .await?;
+    Ok(Some(open(&blob(&response, "Plaintext")?, &iv, body)?))
+}
+
+/// Encrypts `body` with AES-256-GCM, appending the authentication tag.
+fn seal(key: &[u8], iv: &[u8], body: &[u8]) -> Result<Vec<u8>, Error> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| "Invalid data key length")?;
+    cipher
+        .encrypt(Nonce::from_slice(iv), body)
+        .map_err(|_| "Error encrypting object".into())
+}
+
+/// Decrypts and authenticates `body` sealed by [`seal`].
+fn open(key: &[u8], iv: &[u8], body: &[u8]) -> Result<Vec<u8>, Error> {
+    if iv.len() != 12 {
+        return Err("Invalid IV length".into());
+    }
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| "Invalid data key length")?;
+    cipher
+        .decrypt(Nonce::from_slice(iv), body)
+        .map_err(|_| "Error decrypting object, authentication failed".into())
+}
+
+/// Calls the KMS `operation` in `region` with `payload`.
+async fn kms(
+    region: &Region,
+    operation: &str,
+    payload: Value,
+) -> Result<Value, Error> {
+    let mut request = SignedRequest::new("POST", "kms", region, "/");
+    request.add_header("x-amz-target", &format!("TrentService.{}", operation));
+    request.set_content_type("application/x-amz-json-1.1".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&payload)?));
+    let response = aws::dispatch(request).await?;
+    Ok(serde_json::from_slice(&response.body)?)
+}
+
+/// Decodes the base64 blob `name` of a KMS response.
+fn blob(response: &Value, name: &str) -> Result<Vec<u8>, Error> {
+    let value = response[name]
+        .as_str()
+        .ok_or_else(|| format!("KMS response has no {}", name))?;
+    Ok(STANDARD.decode(value)?)
+}
+
+/** Test module for envelope encryption */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal() {
+        let key = [7u8; 32];
+        let iv = [1u8; 12];
+        let body = b"Subject: Fufu\r\n\r\nHello";
+        let sealed = seal(&key, &iv, body).unwrap();
+        assert_eq!(sealed.len(), body.len() + 16);
+        assert_eq!(open(&key, &iv, &sealed).unwrap(), body);
+
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(open(&key, &iv, &tampered).is_err());
+        assert!(open(&[8u8; 32], &iv, &sealed).is_err());
+        assert!(open(&key, &iv[..8], &sealed).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_decrypt_unencrypted() {
+        let headers = BTreeMap::new();
+        let body = b"plain";
+        assert_eq!(decrypt(None, &headers, body).await.unwrap(), None);
+
+        let headers = BTreeMap::from([
+            (KEY_HEADER.to_owned(), "a2V5".to_owned()),
+            (CEK_ALG_HEADER.to_owned(), "AES/CBC/PKCS5Padding".to_owned()),
+        ]);
+        assert!(decrypt(None, &headers, body).await.is_err());
+    }
+}
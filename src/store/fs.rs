@@ -0,0 +1,139 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Filesystem backed [`ObjectStore`] for local runs.
+use super::ObjectStore;
+use async_trait::async_trait;
+use bytes::Bytes;
+use lambda_runtime::Error;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+use tokio::{fs, task};
+
+/// Stores objects as files below a root directory, keys being
+/// `/`-separated relative paths.
+#[derive(Clone, Debug)]
+pub struct FsObjectStore {
+    root: PathBuf,
+}
+
+impl FsObjectStore {
+    /// Creates a store rooted at `root`, creating the directory if needed.
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<Self, Error> {
+        std::fs::create_dir_all(root.as_ref())?;
+        Ok(FsObjectStore { root: root.as_ref().to_path_buf() })
+    }
+
+    /// Maps `key` to a path below the root, rejecting keys escaping it.
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        let relative = Path::new(key);
+        if key.is_empty()
+            || !relative.components().all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("Invalid object key: {}", key).into());
+        }
+        Ok(self.root.join(relative))
+    }
+
+    /// Recursively collects the keys of every file below `dir`.
+    fn walk(
+        &self,
+        dir: &Path,
+        keys: &mut Vec<String>,
+    ) -> Result<(), std::io::Error> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.walk(&path, keys)?;
+            } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                let parts: Vec<String> = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy().into_owned())
+                    .collect();
+                keys.push(parts.join("/"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectStore for FsObjectStore {
+    async fn put(&self, key: &str, body: Bytes) -> Result<(), Error> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Unlike `fs::write`, writes the shared buffer without copying it
+        task::spawn_blocking(move || std::fs::write(path, body)).await??;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(self.path(key)?).await {
+            Ok(body) => Ok(Some(body)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        self.walk(&self.root, &mut keys)?;
+        keys.retain(|k| k.starts_with(prefix));
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn link(
+        &self,
+        key: &str,
+        _expires_in: Duration,
+    ) -> Result<String, Error> {
+        Ok(format!("file://{}", self.path(key)?.display()))
+    }
+}
+
+/** Test module for FsObjectStore */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fs_object_store() {
+        let root = std::env::temp_dir()
+            .join(format!("privatemail-fs-{}", std::process::id()));
+        let store = FsObjectStore::new(&root).unwrap();
+
+        store.put("archive/2021/a.eml", "first".into()).await.unwrap();
+        store.put("quarantine/b.eml", "second".into()).await.unwrap();
+        assert_eq!(
+            store.get("archive/2021/a.eml").await.unwrap().unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            store.list("archive/").await.unwrap(),
+            ["archive/2021/a.eml"]
+        );
+
+        store.delete("archive/2021/a.eml").await.unwrap();
+        assert!(store.get("archive/2021/a.eml").await.unwrap().is_none());
+        assert!(store.put("../escape", "nope".into()).await.is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
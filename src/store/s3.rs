@@ -0,0 +1,253 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! S3 backed [`ObjectStore`].
+//!
+//! Objects may be written compressed with zstd, marked by their
+//! `Content-Encoding`, encrypted by S3 with a KMS key (SSE-KMS) and
+//! encrypted before upload with [`envelope`] encryption, in this order.
+//! Compressed and envelope encrypted objects are decoded when read, whether
+//! the store encodes its writes or not.
+use super::{envelope, ObjectStore};
+use crate::aws::{self, AwsError};
+use async_trait::async_trait;
+use bytes::Bytes;
+use lambda_runtime::Error;
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// `Content-Encoding` of objects compressed with zstd.
+pub const ZSTD_ENCODING: &str = "zstd";
+
+/// zstd compression level of written objects.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Stores objects in a single S3 bucket, keys being object keys.
+#[derive(Clone, Debug)]
+pub struct S3ObjectStore {
+    bucket: String,
+    region: Region,
+    compress: bool,
+    sse_kms_key_id: Option<String>,
+    envelope_key_id: Option<String>,
+}
+
+impl S3ObjectStore {
+    /// Creates a store writing to `bucket` in `region`.
+    pub fn new<B: ToString>(bucket: B, region: Region) -> Self {
+        S3ObjectStore {
+            bucket: bucket.to_string(),
+            region,
+            compress: false,
+            sse_kms_key_id: None,
+            envelope_key_id: None,
+        }
+    }
+
+    /// Sets whether objects are written compressed with zstd.
+    pub fn with_compression(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    /// Sets the KMS key S3 encrypts written objects with, if any.
+    pub fn with_sse_kms(mut self, key_id: Option<String>) -> Self {
+        self.sse_kms_key_id = key_id;
+        self
+    }
+
+    /// Sets the KMS key written objects are envelope encrypted under, if
+    /// any.
+    pub fn with_envelope_encryption(mut self, key_id: Option<String>) -> Self {
+        self.envelope_key_id = key_id;
+        self
+    }
+
+    /// Name of the bucket backing the store.
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    fn request(&self, method: &str, key: &str) -> SignedRequest {
+        SignedRequest::new(
+            method,
+            "s3",
+            &self.region,
+            &format!("/{}/{}", self.bucket, key),
+        )
+    }
+}
+
+/// Returns the unescaped text of every `<tag>` element in `xml`.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|chunk| chunk.split(close.as_str()).next())
+        .map(|value| {
+            value
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+/// Returns `body` as stored with the `Content-Encoding` `encoding`.
+fn decode(body: &[u8], encoding: Option<&str>) -> Result<Vec<u8>, Error> {
+    match encoding {
+        Some(ZSTD_ENCODING) => Ok(zstd::stream::decode_all(body)?),
+        _ => Ok(body.to_vec()),
+    }
+}
+
+/// Returns true when `error` is an S3 "not found" response.
+fn is_not_found(error: &Error) -> bool {
+    error.downcast_ref::<AwsError>().is_some_and(|e| e.status == 404)
+}
+
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn put(&self, key: &str, body: Bytes) -> Result<(), Error> {
+        let mut request = self.request("PUT", key);
+        request.set_content_type("application/octet-stream".to_owned());
+        let body = match self.compress {
+            true => {
+                request.add_header("Content-Encoding", ZSTD_ENCODING);
+                zstd::bulk::compress(&body, ZSTD_LEVEL)?.into()
+            }
+            false => body,
+        };
+        if let Some(key_id) = &self.sse_kms_key_id {
+            request.add_header("x-amz-server-side-encryption", "aws:kms");
+            request.add_header(
+                "x-amz-server-side-encryption-aws-kms-key-id",
+                key_id,
+            );
+        }
+        let body = match &self.envelope_key_id {
+            Some(key_id) => {
+                let (body, headers) = envelope::encrypt(key_id, &body).await?;
+                for (name, value) in headers {
+                    request.add_header(name, &value);
+                }
+                body.into()
+            }
+            None => body,
+        };
+        request.set_payload(Some(body));
+        aws::dispatch(request).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match aws::dispatch(self.request("GET", key)).await {
+            Ok(response) => {
+                let headers: BTreeMap<String, String> = response
+                    .headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_owned()))
+                    .collect();
+                let key_id = self.envelope_key_id.as_deref();
+                let body =
+                    envelope::decrypt(key_id, &headers, &response.body).await?;
+                let body = body.as_deref().unwrap_or(&response.body);
+                let encoding = headers.get("content-encoding");
+                Ok(Some(decode(body, encoding.map(String::as_str))?))
+            }
+            Err(e) if is_not_found(&e) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        aws::dispatch(self.request("DELETE", key)).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut request = SignedRequest::new(
+                "GET",
+                "s3",
+                &self.region,
+                &format!("/{}", self.bucket),
+            );
+            request.add_param("list-type", "2");
+            request.add_param("prefix", prefix);
+            if let Some(token) = &token {
+                request.add_param("continuation-token", token);
+            }
+            let response = aws::dispatch(request).await?;
+            let body = response.body_as_str();
+            keys.extend(xml_values(body, "Key"));
+            token = xml_values(body, "NextContinuationToken").pop();
+            if token.is_none() {
+                break;
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn link(
+        &self,
+        key: &str,
+        expires_in: Duration,
+    ) -> Result<String, Error> {
+        aws::presign(self.request("GET", key), expires_in).await
+    }
+}
+
+/** Test module for S3ObjectStore */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xml_values() {
+        let xml = "<ListBucketResult><Contents><Key>archive/a.eml</Key>\
+            </Contents><Contents><Key>archive/b&amp;c.eml</Key></Contents>\
+            <NextContinuationToken>abc</NextContinuationToken>\
+            </ListBucketResult>";
+        assert_eq!(
+            xml_values(xml, "Key"),
+            ["archive/a.eml", "archive/b&c.eml"]
+        );
+        assert_eq!(xml_values(xml, "NextContinuationToken"), ["abc"]);
+        assert!(xml_values(xml, "Missing").is_empty());
+    }
+
+    #[test]
+    fn test_decode() {
+        let raw = b"Subject: Fufu\r\n\r\nHello Hello Hello Hello";
+        let compressed = zstd::bulk::compress(raw, ZSTD_LEVEL).unwrap();
+        assert_ne!(compressed, raw);
+        assert_eq!(decode(&compressed, Some(ZSTD_ENCODING)).unwrap(), raw);
+        assert_eq!(decode(raw, None).unwrap(), raw);
+        assert!(decode(raw, Some(ZSTD_ENCODING)).is_err());
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        let error: Error =
+            Box::new(AwsError { status: 404, body: String::new() });
+        assert!(is_not_found(&error));
+        let error: Error =
+            Box::new(AwsError { status: 500, body: String::new() });
+        assert!(!is_not_found(&error));
+    }
+}
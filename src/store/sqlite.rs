@@ -0,0 +1,225 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! SQLite backed [`RecordStore`] for local runs (`sqlite` feature).
+use super::{MailRecord, RecordStore};
+use async_trait::async_trait;
+use lambda_runtime::Error;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS records (
+        message_id  TEXT PRIMARY KEY,
+        timestamp   TEXT NOT NULL,
+        source      TEXT NOT NULL,
+        destination TEXT NOT NULL,
+        subject     TEXT NOT NULL,
+        action      TEXT NOT NULL,
+        object_key  TEXT,
+        thread_id   TEXT
+    );
+    CREATE INDEX IF NOT EXISTS records_timestamp ON records (timestamp);
+    CREATE TABLE IF NOT EXISTS counters (
+        name  TEXT PRIMARY KEY,
+        value INTEGER NOT NULL
+    );
+";
+
+/// Keeps records and counters in a single SQLite database file.
+#[derive(Debug)]
+pub struct SqliteRecordStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteRecordStore {
+    /// Opens (or creates) the database at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Opens a private in-memory database.
+    pub fn open_in_memory() -> Result<Self, Error> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(conn: Connection) -> Result<Self, Error> {
+        conn.execute_batch(SCHEMA)?;
+
+        // Databases created before threading lack the thread_id column
+        let has_thread_id = conn
+            .prepare(
+                "SELECT 1 FROM pragma_table_info('records')
+                 WHERE name = 'thread_id'",
+            )?
+            .exists([])?;
+        if !has_thread_id {
+            conn.execute("ALTER TABLE records ADD COLUMN thread_id TEXT", [])?;
+        }
+        Ok(SqliteRecordStore { conn: Mutex::new(conn) })
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<(MailRecord, String, String)> {
+        Ok((
+            MailRecord {
+                message_id: row.get(0)?,
+                timestamp: row.get(1)?,
+                source: row.get(2)?,
+                destination: Vec::new(),
+                subject: row.get(4)?,
+                action: super::MailAction::Forwarded,
+                object_key: row.get(6)?,
+                thread_id: row.get(7)?,
+            },
+            row.get(3)?,
+            row.get(5)?,
+        ))
+    }
+
+    fn decode(
+        (mut record, destination, action): (MailRecord, String, String),
+    ) -> Result<MailRecord, Error> {
+        record.destination = serde_json::from_str(&destination)?;
+        record.action = action.parse()?;
+        Ok(record)
+    }
+}
+
+#[async_trait]
+impl RecordStore for SqliteRecordStore {
+    async fn put_record(&self, record: &MailRecord) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO records VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                record.message_id,
+                record.timestamp,
+                record.source,
+                serde_json::to_string(&record.destination)?,
+                record.subject,
+                record.action.as_str(),
+                record.object_key,
+                record.thread_id,
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn get_record(
+        &self,
+        message_id: &str,
+    ) -> Result<Option<MailRecord>, Error> {
+        let row = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT * FROM records WHERE message_id = ?1",
+                params![message_id],
+                Self::from_row,
+            )
+            .optional()?;
+        row.map(Self::decode).transpose()
+    }
+
+    async fn delete_record(&self, message_id: &str) -> Result<(), Error> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM records WHERE message_id = ?1",
+            params![message_id],
+        )?;
+        Ok(())
+    }
+
+    async fn list_records(
+        &self,
+        from: &str,
+        to: &str,
+    ) -> Result<Vec<MailRecord>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT * FROM records WHERE timestamp >= ?1 AND timestamp < ?2
+             ORDER BY timestamp",
+        )?;
+        let rows = stmt
+            .query_map(params![from, to], Self::from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter().map(Self::decode).collect()
+    }
+
+    async fn increment(&self, name: &str, by: u64) -> Result<u64, Error> {
+        let value: i64 = self.conn.lock().unwrap().query_row(
+            "INSERT INTO counters VALUES (?1, ?2)
+             ON CONFLICT(name) DO UPDATE SET value = value + excluded.value
+             RETURNING value",
+            params![name, by as i64],
+            |row| row.get(0),
+        )?;
+        Ok(value as u64)
+    }
+
+    async fn decrement(&self, name: &str, by: u64) -> Result<u64, Error> {
+        let value: i64 = self.conn.lock().unwrap().query_row(
+            "INSERT INTO counters VALUES (?1, 0)
+             ON CONFLICT(name) DO UPDATE SET value = max(value - ?2, 0)
+             RETURNING value",
+            params![name, by as i64],
+            |row| row.get(0),
+        )?;
+        Ok(value as u64)
+    }
+
+    async fn counters(&self) -> Result<BTreeMap<String, u64>, Error> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT name, value FROM counters")?;
+        let counters = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64))
+            })?
+            .collect::<Result<BTreeMap<_, _>, _>>()?;
+        Ok(counters)
+    }
+}
+
+/** Test module for SqliteRecordStore */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MailAction;
+
+    #[tokio::test]
+    async fn test_sqlite_record_store() {
+        let store = SqliteRecordStore::open_in_memory().unwrap();
+        let record = MailRecord {
+            message_id: "a".to_owned(),
+            timestamp: "2021-03-19T08:46:16.420Z".to_owned(),
+            source: "fufu@achu.soup".to_owned(),
+            destination: vec!["samubu@user.earth".to_owned()],
+            subject: "Testing".to_owned(),
+            action: MailAction::Quarantined,
+            object_key: Some("quarantine/a.eml".to_owned()),
+            thread_id: Some("<a@achu.soup>".to_owned()),
+        };
+        store.put_record(&record).await.unwrap();
+        assert_eq!(store.get_record("a").await.unwrap(), Some(record.clone()));
+        assert_eq!(
+            store.list_records("2021-03-19", "2021-03-20").await.unwrap(),
+            [record]
+        );
+
+        store.delete_record("a").await.unwrap();
+        assert!(store.get_record("a").await.unwrap().is_none());
+
+        assert_eq!(store.increment("blocked", 1).await.unwrap(), 1);
+        assert_eq!(store.increment("blocked", 4).await.unwrap(), 5);
+        assert_eq!(store.counters().await.unwrap()["blocked"], 5);
+        assert_eq!(store.decrement("blocked", 2).await.unwrap(), 3);
+        assert_eq!(store.decrement("blocked", 9).await.unwrap(), 0);
+    }
+}
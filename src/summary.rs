@@ -0,0 +1,261 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Short summaries of long messages for digests.
+//!
+//! A [`Summarizer`] condenses a message body to a couple of sentences.
+//! [`BedrockSummarizer`] asks a Bedrock model; [`ExtractiveSummarizer`]
+//! needs no service and picks the most representative sentences, and is
+//! also the fallback whenever the model fails or is too slow.
+use crate::classify;
+use crate::config::PrivatEmailConfig;
+use crate::html::decode_entities;
+use async_trait::async_trait;
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::warn;
+
+/// Bodies shorter than this, in characters, are shown by their first line.
+pub const SUMMARY_THRESHOLD: usize = 1000;
+
+/// Sentences kept by the extractive summarizer.
+pub const SUMMARY_SENTENCES: usize = 2;
+
+/// Characters kept from the first line of short bodies.
+pub const FIRST_LINE_CHARS: usize = 120;
+
+/// Produces a short summary of a plain text message body.
+#[async_trait]
+pub trait Summarizer: Send + Sync {
+    /// Returns a summary of `text`.
+    async fn summarize(&self, text: &str) -> Result<String, Error>;
+}
+
+/// [`Summarizer`] keeping the sentences with the most frequent words.
+#[derive(Clone, Copy, Debug)]
+pub struct ExtractiveSummarizer {
+    sentences: usize,
+}
+
+impl Default for ExtractiveSummarizer {
+    fn default() -> Self {
+        ExtractiveSummarizer { sentences: SUMMARY_SENTENCES }
+    }
+}
+
+impl ExtractiveSummarizer {
+    /// Creates a summarizer keeping `sentences` sentences.
+    pub fn new(sentences: usize) -> Self {
+        ExtractiveSummarizer { sentences }
+    }
+
+    /// Summarizes `text` without any service call.
+    pub fn extract(&self, text: &str) -> String {
+        let sentences = sentences(text);
+        let words = |s: &str| -> Vec<String> {
+            s.split(|c: char| !c.is_alphanumeric())
+                .filter(|w| w.chars().count() > 3)
+                .map(str::to_lowercase)
+                .collect()
+        };
+        let mut frequency: HashMap<String, usize> = HashMap::new();
+        for word in sentences.iter().flat_map(|s| words(s)) {
+            *frequency.entry(word).or_default() += 1;
+        }
+
+        // Average word frequency, so long sentences are not favoured
+        let mut scored: Vec<(usize, f64)> = sentences
+            .iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let words = words(s);
+                let total: usize = words.iter().map(|w| frequency[w]).sum();
+                (i, total as f64 / words.len().max(1) as f64)
+            })
+            .collect();
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut keep: Vec<usize> =
+            scored.iter().take(self.sentences).map(|(i, _)| *i).collect();
+        keep.sort_unstable();
+        keep.iter().map(|i| sentences[*i]).collect::<Vec<_>>().join(" ")
+    }
+}
+
+#[async_trait]
+impl Summarizer for ExtractiveSummarizer {
+    async fn summarize(&self, text: &str) -> Result<String, Error> {
+        Ok(self.extract(text))
+    }
+}
+
+/// [`Summarizer`] asking an Amazon Bedrock model.
+#[derive(Clone, Debug)]
+pub struct BedrockSummarizer {
+    model_id: String,
+    region: Region,
+}
+
+impl BedrockSummarizer {
+    /// Creates a summarizer using `model_id` in `region`.
+    pub fn new<M: ToString>(model_id: M, region: Region) -> Self {
+        BedrockSummarizer { model_id: model_id.to_string(), region }
+    }
+}
+
+#[async_trait]
+impl Summarizer for BedrockSummarizer {
+    async fn summarize(&self, text: &str) -> Result<String, Error> {
+        let text: String =
+            text.chars().take(classify::MAX_INPUT_CHARS).collect();
+        let prompt = format!(
+            "Summarize the email below in at most two short sentences. \
+             Answer with the summary only.\n\n{}",
+            text
+        );
+        let summary =
+            classify::converse(&self.model_id, &self.region, &prompt, 100)
+                .await?;
+        Ok(summary.trim().to_owned())
+    }
+}
+
+/// Returns the configured model summarizer, if any.
+pub fn from_config(
+    email_config: &PrivatEmailConfig,
+) -> Option<Box<dyn Summarizer>> {
+    let model = email_config.summary_model.as_ref()?;
+    Some(Box::new(BedrockSummarizer::new(model, Region::default())))
+}
+
+/// Splits `text` into trimmed, non-empty sentences.
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        if matches!(c, '.' | '!' | '?' | '\n') {
+            let end = i + c.len_utf8();
+            sentences.push(text[start..end].trim());
+            start = end;
+        }
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|s| s.chars().any(char::is_alphanumeric));
+    sentences
+}
+
+/// Returns the first non-empty line of `text`, truncated.
+pub fn first_line(text: &str) -> String {
+    let line = text.lines().map(str::trim).find(|l| !l.is_empty());
+    let line = line.unwrap_or_default();
+    if line.chars().count() > FIRST_LINE_CHARS {
+        let truncated: String = line.chars().take(FIRST_LINE_CHARS).collect();
+        format!("{}…", truncated.trim_end())
+    } else {
+        line.to_owned()
+    }
+}
+
+/// Converts an HTML body to plain text, dropping tags, scripts and styles.
+pub fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        text.push_str(&rest[..open]);
+        let tag = &rest[open..];
+        let lower = tag.get(..7).unwrap_or(tag).to_lowercase();
+        let skip_to = if lower.starts_with("<script") {
+            tag.to_lowercase().find("</script>").map(|e| e + 9)
+        } else if lower.starts_with("<style") {
+            tag.to_lowercase().find("</style>").map(|e| e + 8)
+        } else {
+            tag.find('>').map(|e| e + 1)
+        };
+        match skip_to {
+            Some(end) => {
+                let name = tag[1..].trim_start_matches('/').to_lowercase();
+                if ["p", "br", "div", "tr", "li", "h"]
+                    .iter()
+                    .any(|block| name.starts_with(block))
+                {
+                    text.push('\n');
+                }
+                rest = &tag[end..];
+            }
+            None => {
+                rest = "";
+            }
+        }
+    }
+    text.push_str(rest);
+    let text = decode_entities(&text);
+    text.lines()
+        .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the digest line for `text`: the first line of short bodies, a
+/// summary of long ones. The `summarizer` is bounded by `timeout`, falling
+/// back to an extractive summary.
+pub async fn summarize(
+    summarizer: Option<&dyn Summarizer>,
+    text: &str,
+    timeout: Duration,
+) -> String {
+    if text.chars().count() < SUMMARY_THRESHOLD {
+        return first_line(text);
+    }
+    if let Some(summarizer) = summarizer {
+        match tokio::time::timeout(timeout, summarizer.summarize(text)).await {
+            Ok(Ok(summary)) if !summary.is_empty() => return summary,
+            Ok(Ok(_)) => warn!("Summarizer returned an empty summary"),
+            Ok(Err(e)) => warn!("Error summarizing message: {:?}", e),
+            Err(_) => warn!("Summarizing timed out after {:?}", timeout),
+        }
+    }
+    ExtractiveSummarizer::default().extract(text)
+}
+
+/** Test module for message summaries */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_text() {
+        let html = "<html><style>p { color: red; }</style><body>\
+                    <p>Hello&nbsp;there,</p><p>Your order &amp; receipt\
+                    </p><script>alert(1)</script></body></html>";
+        assert_eq!(plain_text(html), "Hello there,\nYour order & receipt");
+    }
+
+    #[test]
+    fn test_extractive_summary() {
+        let text = "The invoice for March is attached. \
+                    We hope you enjoyed the weather. \
+                    Please pay the invoice for March before Friday. \
+                    Thanks!";
+        assert_eq!(
+            ExtractiveSummarizer::default().extract(text),
+            "The invoice for March is attached. \
+             Please pay the invoice for March before Friday."
+        );
+    }
+
+    #[tokio::test]
+    async fn test_summarize_short_body() {
+        let summary =
+            summarize(None, "\n  Lunch tomorrow?\nSee you", Duration::ZERO)
+                .await;
+        assert_eq!(summary, "Lunch tomorrow?");
+    }
+}
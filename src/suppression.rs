@@ -0,0 +1,188 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! The SES account suppression list, read through the SESv2 API.
+//!
+//! SES accepts sends to suppressed addresses and drops them afterwards,
+//! counting them as bounces. With `check_suppression` set, forwards to a
+//! suppressed destination fail as permanent send failures instead, and
+//! auto-replies and bounces to suppressed senders are skipped.
+//! `privatemail-suppression` lists, adds and removes addresses.
+use crate::aws::{self, AwsError};
+use lambda_runtime::Error;
+use rusoto_core::Region;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Counter and metric name of sends skipped for a suppressed address.
+pub const SUPPRESSED_COUNTER: &str = "suppressed";
+
+/// Path of the suppressed destinations of the account.
+const ADDRESSES_PATH: &str = "/v2/email/suppression/addresses";
+
+/// Why an address is suppressed.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Reason {
+    /// Mail to the address bounced
+    Bounce,
+
+    /// The recipient marked mail as spam
+    Complaint,
+}
+
+/// Address on the suppression list.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct SuppressedDestination {
+    /// Suppressed address
+    pub email_address: String,
+
+    /// Why it is suppressed
+    pub reason: Reason,
+
+    /// Unix time it was last added
+    #[serde(default)]
+    pub last_update_time: f64,
+}
+
+impl Reason {
+    /// Name of the reason, as used by SES.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Reason::Bounce => "BOUNCE",
+            Reason::Complaint => "COMPLAINT",
+        }
+    }
+}
+
+impl std::str::FromStr for Reason {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "bounce" => Ok(Reason::Bounce),
+            "complaint" => Ok(Reason::Complaint),
+            other => Err(format!("Invalid suppression reason: {}", other)),
+        }
+    }
+}
+
+/// Returns the suppression of `address` in `region`, if it is suppressed.
+pub async fn get(
+    region: &Region,
+    address: &str,
+) -> Result<Option<SuppressedDestination>, Error> {
+    let request = aws::sesv2_request(region, "GET", &address_path(address));
+    match aws::dispatch(request).await {
+        Ok(response) => {
+            let body: Value = serde_json::from_slice(&response.body)?;
+            Ok(Some(serde_json::from_value(
+                body["SuppressedDestination"].clone(),
+            )?))
+        }
+        Err(e) if e.downcast_ref::<AwsError>().is_some_and(is_not_found) => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns whether `address` is on the suppression list of `region`.
+pub async fn is_suppressed(
+    region: &Region,
+    address: &str,
+) -> Result<bool, Error> {
+    Ok(get(region, address).await?.is_some())
+}
+
+/// Adds `address` to the suppression list of `region` for `reason`.
+pub async fn add(
+    region: &Region,
+    address: &str,
+    reason: Reason,
+) -> Result<(), Error> {
+    let mut request = aws::sesv2_request(region, "PUT", ADDRESSES_PATH);
+    request.set_content_type("application/json".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&json!({
+        "EmailAddress": address.trim(),
+        "Reason": reason.as_str(),
+    }))?));
+    aws::dispatch(request).await?;
+    Ok(())
+}
+
+/// Removes `address` from the suppression list of `region`.
+pub async fn remove(region: &Region, address: &str) -> Result<(), Error> {
+    aws::dispatch(aws::sesv2_request(region, "DELETE", &address_path(address)))
+        .await?;
+    Ok(())
+}
+
+/// Returns every address on the suppression list of `region`.
+pub async fn list(
+    region: &Region,
+) -> Result<Vec<SuppressedDestination>, Error> {
+    let mut destinations = Vec::new();
+    let mut next_token: Option<String> = None;
+    loop {
+        let mut request = aws::sesv2_request(region, "GET", ADDRESSES_PATH);
+        request.add_param("PageSize", "1000");
+        if let Some(token) = &next_token {
+            request.add_param("NextToken", token);
+        }
+        let response = aws::dispatch(request).await?;
+        let body: Value = serde_json::from_slice(&response.body)?;
+        for summary in body["SuppressedDestinationSummaries"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            destinations.push(serde_json::from_value(summary.clone())?);
+        }
+        next_token = body["NextToken"].as_str().map(str::to_owned);
+        if next_token.is_none() {
+            return Ok(destinations);
+        }
+    }
+}
+
+fn address_path(address: &str) -> String {
+    format!("{}/{}", ADDRESSES_PATH, address.trim())
+}
+
+fn is_not_found(error: &AwsError) -> bool {
+    error.status == 404 || error.body.contains("NotFoundException")
+}
+
+/** Test module for the suppression list */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suppressed_destination() {
+        let destination: SuppressedDestination = serde_json::from_str(
+            r#"{
+                "EmailAddress": "gone@suya.io",
+                "Reason": "BOUNCE",
+                "LastUpdateTime": 1647679576.5
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(destination.email_address, "gone@suya.io");
+        assert_eq!(destination.reason, Reason::Bounce);
+        assert_eq!("Complaint".parse(), Ok(Reason::Complaint));
+        assert!("spam".parse::<Reason>().is_err());
+        assert_eq!(
+            address_path(" gone@suya.io "),
+            "/v2/email/suppression/addresses/gone@suya.io"
+        );
+    }
+}
@@ -0,0 +1,358 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Builders of test events.
+//!
+//! Builds the SES receipt notifications the handler is invoked with, bare
+//! or in an SNS event, from a few fields instead of JSON fixtures, so
+//! configurations and rules are unit tested easily:
+//!
+//! ```
+//! use lib::test_support::EmailReceiptNotificationBuilder;
+//!
+//! let event = EmailReceiptNotificationBuilder::new()
+//!     .from("Fufu <fufu@achu.soup>")
+//!     .subject("hi")
+//!     .html_body("<p>Hello</p>")
+//!     .to_sns_event();
+//! assert_eq!(event["Records"][0]["EventSource"], "aws:sns");
+//! ```
+use crate::mime::{Attachment, MimeMessage};
+use crate::EmailReceiptNotification;
+use lambda_runtime::{Context, LambdaEvent};
+use serde_json::{json, Map, Value};
+
+/// Sender of built notifications when none is set.
+pub const DEFAULT_FROM: &str = "sender@example.com";
+
+/// Recipient of built notifications when none is set.
+pub const DEFAULT_TO: &str = "me@example.com";
+
+/// Timestamp of built notifications when none is set.
+pub const DEFAULT_TIMESTAMP: &str = "2022-03-19T08:46:16.420Z";
+
+/// Builds an SES receipt notification, see
+/// [`EmailReceiptNotification::builder`].
+#[derive(Clone, Debug)]
+pub struct EmailReceiptNotificationBuilder {
+    from: String,
+    to: Vec<String>,
+    subject: String,
+    message_id: String,
+    timestamp: String,
+    headers: Vec<(String, String)>,
+    text: Option<String>,
+    html: Option<String>,
+    attachments: Vec<Attachment>,
+    verdicts: Vec<(String, String)>,
+    raw: Option<String>,
+}
+
+impl Default for EmailReceiptNotificationBuilder {
+    fn default() -> Self {
+        EmailReceiptNotificationBuilder {
+            from: DEFAULT_FROM.to_owned(),
+            to: Vec::new(),
+            subject: String::new(),
+            message_id: "test-message".to_owned(),
+            timestamp: DEFAULT_TIMESTAMP.to_owned(),
+            headers: Vec::new(),
+            text: None,
+            html: None,
+            attachments: Vec::new(),
+            verdicts: ["spam", "virus", "spf", "dkim", "dmarc"]
+                .map(|check| (check.to_owned(), "PASS".to_owned()))
+                .into(),
+            raw: None,
+        }
+    }
+}
+
+/// Returns the bare address of the mailbox `mailbox`, e.g. of
+/// `Name <local@domain>`.
+fn address(mailbox: &str) -> &str {
+    match mailbox.rsplit_once('<') {
+        Some((_, address)) => address.trim_end_matches('>').trim(),
+        None => mailbox.trim(),
+    }
+}
+
+impl EmailReceiptNotificationBuilder {
+    /// Creates a builder of a notification of an empty message, passing
+    /// every check.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the `From` header, whose address is also the envelope sender.
+    pub fn from(mut self, mailbox: impl Into<String>) -> Self {
+        self.from = mailbox.into();
+        self
+    }
+
+    /// Adds a recipient, to the `To` header and the envelope.
+    pub fn to(mut self, mailbox: impl Into<String>) -> Self {
+        self.to.push(mailbox.into());
+        self
+    }
+
+    /// Sets the subject.
+    pub fn subject(mut self, subject: impl Into<String>) -> Self {
+        self.subject = subject.into();
+        self
+    }
+
+    /// Sets the SES message id.
+    pub fn message_id(mut self, message_id: impl Into<String>) -> Self {
+        self.message_id = message_id.into();
+        self
+    }
+
+    /// Sets when SES received the message, as an RFC 3339 timestamp.
+    pub fn timestamp(mut self, timestamp: impl Into<String>) -> Self {
+        self.timestamp = timestamp.into();
+        self
+    }
+
+    /// Appends a header to the message.
+    pub fn header(
+        mut self,
+        name: impl Into<String>,
+        value: impl Into<String>,
+    ) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Sets the text/plain body.
+    pub fn text_body(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+
+    /// Sets the text/html body.
+    pub fn html_body(mut self, html: impl Into<String>) -> Self {
+        self.html = Some(html.into());
+        self
+    }
+
+    /// Attaches a file.
+    pub fn attachment(
+        mut self,
+        filename: impl Into<String>,
+        content_type: &str,
+        data: impl Into<Vec<u8>>,
+    ) -> Self {
+        let filename = filename.into();
+        self.attachments.push(Attachment::new(
+            filename,
+            content_type,
+            data.into(),
+        ));
+        self
+    }
+
+    /// Sets the status of the SES `check`, `spam`, `virus`, `spf`, `dkim`
+    /// or `dmarc`, e.g. `FAIL`.
+    pub fn verdict(
+        mut self,
+        check: impl Into<String>,
+        status: impl Into<String>,
+    ) -> Self {
+        let (check, status) = (check.into(), status.into());
+        match self.verdicts.iter_mut().find(|(name, _)| *name == check) {
+            Some(verdict) => verdict.1 = status,
+            None => self.verdicts.push((check, status)),
+        }
+        self
+    }
+
+    /// Sets the raw message, replacing the one built from the headers and
+    /// bodies. The subject and addresses of the notification are still
+    /// those set on the builder.
+    pub fn raw(mut self, raw: impl Into<String>) -> Self {
+        self.raw = Some(raw.into());
+        self
+    }
+
+    /// Returns the recipients, or [`DEFAULT_TO`] when none was added.
+    fn recipients(&self) -> Vec<String> {
+        match self.to.is_empty() {
+            true => vec![DEFAULT_TO.to_owned()],
+            false => self.to.clone(),
+        }
+    }
+
+    /// Returns the headers of the message, in order.
+    fn message_headers(&self) -> Vec<(String, String)> {
+        let mut headers = vec![
+            ("From".to_owned(), self.from.clone()),
+            ("To".to_owned(), self.recipients().join(", ")),
+            ("Subject".to_owned(), self.subject.clone()),
+            ("Message-ID".to_owned(), format!("<{}@test>", self.message_id)),
+        ];
+        headers.extend(self.headers.iter().cloned());
+        headers
+    }
+
+    /// Returns the raw message.
+    pub fn to_raw(&self) -> String {
+        if let Some(raw) = &self.raw {
+            return raw.clone();
+        }
+        let mut message = MimeMessage::new();
+        for (name, value) in self.message_headers() {
+            message = message.header(name, value);
+        }
+        // A message without body still gets an empty text part
+        if self.text.is_some() || self.html.is_none() {
+            message = message.text(self.text.clone().unwrap_or_default());
+        }
+        if let Some(html) = &self.html {
+            message = message.html(html);
+        }
+        for attachment in &self.attachments {
+            message = message.attach(attachment.clone());
+        }
+        String::from_utf8_lossy(&message.to_bytes()).into_owned()
+    }
+
+    /// Returns the bare SES notification, as delivered by raw SNS
+    /// subscriptions.
+    pub fn to_notification(&self) -> Value {
+        let headers: Vec<Value> = self
+            .message_headers()
+            .into_iter()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        let mut receipt = Map::new();
+        receipt.insert("timestamp".into(), self.timestamp.clone().into());
+        receipt.insert("recipients".into(), self.recipients().into());
+        for (check, status) in &self.verdicts {
+            receipt.insert(
+                format!("{}Verdict", check),
+                json!({ "status": status }),
+            );
+        }
+        receipt.insert(
+            "action".into(),
+            json!({ "type": "SNS", "encoding": "UTF8" }),
+        );
+        let source = address(&self.from);
+        let destination: Vec<&str> =
+            self.to.iter().map(|to| address(to)).collect();
+        let destination = match destination.is_empty() {
+            true => vec![DEFAULT_TO],
+            false => destination,
+        };
+        json!({
+            "notificationType": "Received",
+            "mail": {
+                "timestamp": self.timestamp,
+                "source": source,
+                "messageId": self.message_id,
+                "destination": destination,
+                "headersTruncated": false,
+                "headers": headers,
+                "commonHeaders": {
+                    "returnPath": source,
+                    "from": [self.from],
+                    "to": self.recipients(),
+                    "messageId": format!("<{}@test>", self.message_id),
+                    "subject": self.subject,
+                },
+            },
+            "receipt": receipt,
+            "content": self.to_raw(),
+        })
+    }
+
+    /// Returns the SNS event of the notification, as the Lambda receives
+    /// it.
+    pub fn to_sns_event(&self) -> Value {
+        json!({
+            "Records": [{
+                "EventSource": "aws:sns",
+                "EventVersion": "1.0",
+                "Sns": {
+                    "Type": "Notification",
+                    "MessageId": format!("sns-{}", self.message_id),
+                    "Subject": "Amazon SES Email Receipt Notification",
+                    "Message": self.to_notification().to_string(),
+                },
+            }]
+        })
+    }
+
+    /// Returns the invocation of the handler with the SNS event, with a
+    /// request id and trace id.
+    pub fn to_lambda_event(&self) -> LambdaEvent<Value> {
+        let mut context = Context::default();
+        context.request_id = format!("test-{}", self.message_id);
+        context.xray_trace_id = Some(context.request_id.clone());
+        LambdaEvent::new(self.to_sns_event(), context)
+    }
+
+    /// Builds the notification.
+    pub fn build(&self) -> EmailReceiptNotification {
+        serde_json::from_value(self.to_notification())
+            .expect("built notifications are valid")
+    }
+}
+
+/** Test module for the builders of test events */
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mailparse::MailHeaderMap;
+
+    #[test]
+    fn test_notification_builder() {
+        let builder = EmailReceiptNotificationBuilder::new()
+            .from("Fufu <fufu@achu.soup>")
+            .to("eru@nyah.dev")
+            .subject("Fufu & soup")
+            .text_body("Hello")
+            .html_body("<p>Hello</p>")
+            .attachment("menu.txt", "text/plain", "Fufu")
+            .header("List-Id", "<soup.achu.soup>")
+            .verdict("spf", "FAIL");
+        let event = builder.to_sns_event();
+        let ses_mail = EmailReceiptNotification::from_event(&event).unwrap();
+        assert_eq!(
+            ses_mail.senders(),
+            ["Fufu <fufu@achu.soup>", "fufu@achu.soup"]
+        );
+
+        let mail = mailparse::parse_mail(ses_mail.content()).unwrap();
+        assert_eq!(
+            mail.headers.get_first_value("Subject").unwrap(),
+            "Fufu & soup"
+        );
+        assert_eq!(mail.headers.get_first_value("To").unwrap(), "eru@nyah.dev");
+        let message = ses_mail.rule_message(&mail, 0);
+        assert_eq!(message.recipients, ["eru@nyah.dev"]);
+        assert_eq!(message.verdicts["spf"], "FAIL");
+        assert_eq!(message.verdicts["dkim"], "PASS");
+        assert!(message.body.contains("Hello"));
+        assert!(message.headers.get_first_value("List-Id").is_some());
+        assert_eq!(mail.subparts.len(), 2);
+        assert_eq!(mail.subparts[0].subparts.len(), 2);
+
+        let bare =
+            EmailReceiptNotificationBuilder::new().raw("Subject: Hi\r\n\r\nHi");
+        assert_eq!(bare.build().content(), b"Subject: Hi\r\n\r\nHi");
+        let lambda_event = bare.to_lambda_event();
+        assert!(lambda_event.context.xray_trace_id.is_some());
+        assert_eq!(
+            bare.build().to_archive_metadata().destination,
+            [DEFAULT_TO]
+        );
+    }
+}
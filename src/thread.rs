@@ -0,0 +1,84 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Conversation threading from `References` / `In-Reply-To` chains.
+use mailparse::{MailHeader, MailHeaderMap};
+
+/// Counter prefix tracking the number of messages forwarded per thread.
+pub const THREAD_PREFIX: &str = "threads/";
+
+/// Returns the `<...>` message ids found in a header value.
+pub fn message_ids(value: &str) -> Vec<String> {
+    value
+        .split('<')
+        .skip(1)
+        .filter_map(|id| id.split_once('>'))
+        .map(|(id, _)| format!("<{}>", id.trim()))
+        .collect()
+}
+
+/// Returns the id of the conversation a message belongs to: the root of
+/// its `References` chain, its `In-Reply-To` parent or its own
+/// `Message-ID` when it starts a conversation.
+pub fn thread_id(headers: &[MailHeader]) -> Option<String> {
+    ["References", "In-Reply-To", "Message-ID"].iter().find_map(|name| {
+        headers
+            .get_first_value(name)
+            .and_then(|value| message_ids(&value).into_iter().next())
+    })
+}
+
+/// Returns the counter name tracking `thread_id`.
+pub fn counter_key(thread_id: &str) -> String {
+    format!("{}{}", THREAD_PREFIX, thread_id)
+}
+
+/// Strips reply and forward prefixes from `subject`.
+pub fn normalize_subject(subject: &str) -> &str {
+    let mut subject = subject.trim();
+    loop {
+        let lower = subject.to_lowercase();
+        let prefix = ["re:", "fwd:", "fw:", "aw:"]
+            .iter()
+            .find(|prefix| lower.starts_with(*prefix));
+        match prefix {
+            Some(prefix) => subject = subject[prefix.len()..].trim_start(),
+            None => return subject,
+        }
+    }
+}
+
+/** Test module for conversation threading */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(raw: &[u8]) -> Vec<MailHeader<'_>> {
+        mailparse::parse_headers(raw).unwrap().0
+    }
+
+    #[test]
+    fn test_thread_id() {
+        let reply = headers(
+            b"Message-ID: <c@a.test>\r\nIn-Reply-To: <b@a.test>\r\n\
+              References: <a@a.test> <b@a.test>\r\n\r\n",
+        );
+        assert_eq!(thread_id(&reply).as_deref(), Some("<a@a.test>"));
+
+        let first = headers(b"Message-ID: <a@a.test>\r\n\r\n");
+        assert_eq!(thread_id(&first).as_deref(), Some("<a@a.test>"));
+        assert!(thread_id(&headers(b"Subject: hi\r\n\r\n")).is_none());
+    }
+
+    #[test]
+    fn test_normalize_subject() {
+        assert_eq!(normalize_subject("Re: RE: Fwd: Lunch"), "Lunch");
+        assert_eq!(normalize_subject("Lunch"), "Lunch");
+    }
+}
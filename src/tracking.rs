@@ -0,0 +1,226 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Removal of tracking pixels and tracking redirects from HTML bodies.
+//!
+//! Opening a forward should not tell the sender it was read, so images of
+//! at most 1x1 pixels or hidden with `display: none` are dropped. Links
+//! through known redirectors are rewritten to their destination.
+use crate::html::{attribute, decode_entities, tag_name};
+use reqwest::Url;
+
+/// Redirectors carrying their destination in a query parameter, as
+/// `host/path?param`. Subdomains of the host match too.
+pub const DEFAULT_REDIRECTS: &[&str] = &[
+    "www.google.com/url?q",
+    "www.google.com/url?url",
+    "l.facebook.com/l.php?u",
+    "lm.facebook.com/l.php?u",
+    "safelinks.protection.outlook.com/?url",
+    "www.linkedin.com/redir/redirect?url",
+    "out.reddit.com/?url",
+    "www.youtube.com/redirect?q",
+    "click.linksynergy.com/deeplink?murl",
+    "slack-redir.net/link?url",
+    "t.umblr.com/redirect?z",
+];
+
+/// Redirects nested deeper than this are left alone.
+const MAX_REDIRECTS: usize = 4;
+
+/// Returns `html` without tracking pixels and with links through the
+/// `redirects` rewritten to their destination.
+pub fn strip<S: AsRef<str>>(html: &str, redirects: &[S]) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(open) = rest.find('<') {
+        out.push_str(&rest[..open]);
+        let tag = &rest[open..];
+        let Some(end) = tag.find('>') else {
+            break;
+        };
+        let inner = &tag[1..end];
+        rest = &tag[end + 1..];
+        match tag_name(inner) {
+            (name, false) if name == "img" && is_pixel(inner) => {}
+            (name, false) if name == "a" => {
+                out.push_str(&rewrite_href(&tag[..=end], redirects));
+            }
+            _ => out.push_str(&tag[..=end]),
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses a tracked alias route, e.g. `jobs@fufu.soup` or `@eru.soup=false`
+/// to leave an alias of a tracked domain alone.
+pub fn parse_tracked_alias(s: &str) -> Result<(String, bool), String> {
+    let (route, tracked) = match s.split_once('=') {
+        Some((route, tracked)) => (route, tracked.trim().parse().ok()),
+        None => (s, Some(true)),
+    };
+    match (route.trim(), tracked) {
+        ("", _) | (_, None) => Err(format!("Invalid tracked alias: {}", s)),
+        (route, Some(tracked)) => Ok((route.to_lowercase(), tracked)),
+    }
+}
+
+/// Returns the destination of `url` if it goes through one of the
+/// `redirects`, following nested redirects.
+pub fn unwrap_redirect<S: AsRef<str>>(
+    url: &str,
+    redirects: &[S],
+) -> Option<Url> {
+    let mut url = Url::parse(url).ok()?;
+    let mut unwrapped = false;
+    for _ in 0..MAX_REDIRECTS {
+        let Some(target) = redirects
+            .iter()
+            .find_map(|pattern| destination(&url, pattern.as_ref()))
+        else {
+            break;
+        };
+        url = target;
+        unwrapped = true;
+    }
+    unwrapped.then_some(url)
+}
+
+/// Returns the destination of `url` if it matches the redirect `pattern`.
+fn destination(url: &Url, pattern: &str) -> Option<Url> {
+    let (prefix, param) = pattern.split_once('?')?;
+    let (host, path) = match prefix.find('/') {
+        Some(i) => prefix.split_at(i),
+        None => (prefix, "/"),
+    };
+    let url_host = url.host_str()?.to_ascii_lowercase();
+    let host = host.to_ascii_lowercase();
+    if url_host != host && !url_host.ends_with(&format!(".{}", host)) {
+        return None;
+    }
+    if !url.path().starts_with(path) {
+        return None;
+    }
+    let (_, target) = url.query_pairs().find(|(key, _)| key == param)?;
+    Url::parse(&target)
+        .ok()
+        .filter(|target| matches!(target.scheme(), "http" | "https"))
+}
+
+/// Returns the `<a>` tag with its `href` through one of the `redirects`
+/// replaced by the destination.
+fn rewrite_href<S: AsRef<str>>(tag: &str, redirects: &[S]) -> String {
+    let inner = &tag[1..tag.len() - 1];
+    let Some(href) = attribute(inner, "href") else {
+        return tag.to_owned();
+    };
+    let decoded = decode_entities(&href);
+    match unwrap_redirect(&decoded, redirects) {
+        Some(target) => {
+            let escaped =
+                target.as_str().replace('&', "&amp;").replace('"', "&quot;");
+            tag.replacen(&href, &escaped, 1)
+        }
+        None => tag.to_owned(),
+    }
+}
+
+/// Whether the `<img>` tag `inner` is a tracking pixel.
+fn is_pixel(inner: &str) -> bool {
+    let style = attribute(inner, "style").unwrap_or_default();
+    let hidden = style.split(';').any(|declaration| {
+        declaration.split_once(':').is_some_and(|(property, value)| {
+            property.trim().eq_ignore_ascii_case("display")
+                && value.trim().eq_ignore_ascii_case("none")
+        })
+    });
+    let size = |name: &str| {
+        attribute(inner, name)
+            .and_then(|value| pixels(&value))
+            .or_else(|| style_pixels(&style, name))
+    };
+    let tiny = |size: Option<u32>| size.is_some_and(|size| size <= 1);
+    hidden || (tiny(size("width")) && tiny(size("height")))
+}
+
+/// Returns the length of the inline style `property`, in pixels.
+fn style_pixels(style: &str, property: &str) -> Option<u32> {
+    style.split(';').find_map(|declaration| {
+        let (name, value) = declaration.split_once(':')?;
+        name.trim().eq_ignore_ascii_case(property).then(|| pixels(value))?
+    })
+}
+
+fn pixels(value: &str) -> Option<u32> {
+    value.trim().trim_end_matches("px").trim().parse().ok()
+}
+
+/** Test module for tracker removal */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tracked_alias() {
+        assert_eq!(
+            parse_tracked_alias("Jobs@fufu.soup"),
+            Ok(("jobs@fufu.soup".to_owned(), true))
+        );
+        assert_eq!(
+            parse_tracked_alias("@eru.soup=false"),
+            Ok(("@eru.soup".to_owned(), false))
+        );
+        assert!(parse_tracked_alias("*=maybe").is_err());
+        assert!(parse_tracked_alias("=true").is_err());
+    }
+
+    #[test]
+    fn test_strip_pixels() {
+        let html = "<p>Hi</p><img src=\"https://t.example/o.gif\" \
+                    width=\"1\" height=\"1\">\
+                    <img src=\"https://t.example/p.gif\" \
+                    style=\"width:0px; height: 0px\">\
+                    <img src=\"https://t.example/q.gif\" \
+                    style=\"display:none\">\
+                    <img src=\"cid:logo\" width=\"120\" height=\"1\">";
+        assert_eq!(
+            strip(html, DEFAULT_REDIRECTS),
+            "<p>Hi</p><img src=\"cid:logo\" width=\"120\" height=\"1\">"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_links() {
+        let html = "<a class=\"btn\" href=\"https://www.google.com/url?\
+                    q=https%3A%2F%2Fshop.example%2Fsale%3Fa%3D1%26b%3D2\
+                    &amp;sa=D\">Sale</a> \
+                    <a href=\"https://nam12.safelinks.protection.outlook.com/\
+                    ?url=https%3A%2F%2Fl.facebook.com%2Fl.php%3Fu%3D\
+                    https%253A%252F%252Fachu.soup%252F\">Nested</a> \
+                    <a href=\"https://www.google.com/url?q=javascript:x\">\
+                    Bad</a>";
+        assert_eq!(
+            strip(html, DEFAULT_REDIRECTS),
+            "<a class=\"btn\" href=\"https://shop.example/sale?a=1&amp;b=2\">\
+             Sale</a> <a href=\"https://achu.soup/\">Nested</a> \
+             <a href=\"https://www.google.com/url?q=javascript:x\">Bad</a>"
+        );
+        assert!(unwrap_redirect(
+            "https://track.example/c?u=https://achu.soup",
+            &["track.example/c?u"]
+        )
+        .is_some());
+        assert!(unwrap_redirect(
+            "https://achu.soup/?u=x",
+            &["track.example?u"]
+        )
+        .is_none());
+    }
+}
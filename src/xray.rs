@@ -0,0 +1,277 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! X-Ray subsegments of AWS calls.
+//!
+//! With active tracing, Lambda passes the trace of every invocation in
+//! [`TRACE_ENV`] and runs an X-Ray daemon at [`DAEMON_ENV`]. [`traced`]
+//! times an AWS call as a subsegment of the function segment and sends it
+//! to the daemon, so traces show the latency of SES, S3 and the other
+//! services called. Signed requests also carry the [`TRACE_HEADER`], with
+//! the subsegment as parent. Calls outside a sampled trace are not timed.
+use crate::aws::AwsError;
+use lambda_runtime::Error;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::env;
+use std::future::Future;
+use std::net::UdpSocket;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::trace;
+
+/// Environment variable holding the trace header of the invocation.
+pub const TRACE_ENV: &str = "_X_AMZN_TRACE_ID";
+
+/// Environment variable holding the address of the X-Ray daemon.
+pub const DAEMON_ENV: &str = "AWS_XRAY_DAEMON_ADDRESS";
+
+/// Header propagating the trace to AWS services.
+pub const TRACE_HEADER: &str = "X-Amzn-Trace-Id";
+
+/// Header line of every document sent to the daemon.
+const DAEMON_HEADER: &str = "{\"format\": \"json\", \"version\": 1}\n";
+
+/// Trace header, as in `Root=1-5759e988-bd862e3fe1be46a994272793;
+/// Parent=53995c3f42cd8ad8;Sampled=1`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TraceHeader {
+    /// Trace id
+    pub root: String,
+
+    /// Id of the parent segment
+    pub parent: Option<String>,
+
+    /// Whether the trace is recorded
+    pub sampled: bool,
+}
+
+impl TraceHeader {
+    /// Parses a trace header, `None` when it has no root.
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut header =
+            TraceHeader { root: String::new(), parent: None, sampled: false };
+        for field in value.split(';') {
+            match field.trim().split_once('=') {
+                Some(("Root", root)) => header.root = root.to_owned(),
+                Some(("Parent", parent)) => {
+                    header.parent = Some(parent.to_owned())
+                }
+                Some(("Sampled", sampled)) => header.sampled = sampled == "1",
+                _ => {}
+            }
+        }
+        (!header.root.is_empty()).then_some(header)
+    }
+
+    /// Returns the trace header of the current invocation.
+    pub fn current() -> Option<Self> {
+        Self::parse(&env::var(TRACE_ENV).ok()?)
+    }
+
+    /// Returns the header of calls made within the segment `parent`.
+    pub fn with_parent(&self, parent: &str) -> String {
+        format!(
+            "Root={};Parent={};Sampled={}",
+            self.root,
+            parent,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+/// Timed AWS call, sent to the daemon as a subsegment document.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Subsegment {
+    /// Random 64-bit id, in hexadecimal
+    pub id: String,
+
+    /// Id of the trace
+    pub trace_id: String,
+
+    /// Id of the function segment
+    pub parent_id: String,
+
+    /// Always `subsegment`
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+
+    /// Service called, e.g. `S3`
+    pub name: String,
+
+    /// Always `aws`, for calls to AWS services
+    pub namespace: &'static str,
+
+    /// Unix time the call started, in fractional seconds
+    pub start_time: f64,
+
+    /// Unix time the call ended, in fractional seconds
+    pub end_time: f64,
+
+    /// Operation and region of the call
+    pub aws: Value,
+
+    /// Response status, when the service answered with an error
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<Value>,
+
+    /// Whether the call failed with a client error
+    pub error: bool,
+
+    /// Whether the call failed with a service or network error
+    pub fault: bool,
+}
+
+impl Subsegment {
+    /// Returns the document of the subsegment, as sent to the daemon.
+    pub fn to_document(&self) -> Result<Vec<u8>, Error> {
+        let mut document = DAEMON_HEADER.as_bytes().to_vec();
+        document.extend(serde_json::to_vec(self)?);
+        Ok(document)
+    }
+}
+
+/// Returns the name X-Ray shows for the signing name `service`.
+pub fn service_name(service: &str) -> &str {
+    match service {
+        "s3" => "S3",
+        "ses" => "SES",
+        "sns" => "SNS",
+        "dynamodb" => "DynamoDB",
+        "events" => "EventBridge",
+        "secretsmanager" => "SecretsManager",
+        "bedrock" => "Bedrock",
+        other => other,
+    }
+}
+
+/// Runs `call` as the subsegment `operation` of `service` in `region`.
+/// Within a sampled trace, `call` is given the trace header to send.
+pub async fn traced<T, F, Fut>(
+    service: &str,
+    operation: &str,
+    region: &str,
+    call: F,
+) -> Result<T, Error>
+where
+    F: FnOnce(Option<String>) -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let trace = TraceHeader::current().filter(|trace| trace.sampled);
+    let (Some(trace), Some(daemon)) = (trace, daemon_address()) else {
+        return call(None).await;
+    };
+    let Some(parent_id) = trace.parent.clone() else {
+        return call(None).await;
+    };
+    let id = format!("{:016x}", rand::thread_rng().gen::<u64>());
+    let start_time = now();
+    let result = call(Some(trace.with_parent(&id))).await;
+    let status = match &result {
+        Ok(_) => None,
+        Err(e) => Some(e.downcast_ref::<AwsError>().map(|e| e.status)),
+    };
+    let subsegment = Subsegment {
+        id,
+        trace_id: trace.root,
+        parent_id,
+        kind: "subsegment",
+        name: service_name(service).to_owned(),
+        namespace: "aws",
+        start_time,
+        end_time: now(),
+        aws: json!({ "operation": operation, "region": region }),
+        http: status
+            .flatten()
+            .map(|status| json!({ "response": { "status": status } })),
+        error: matches!(status, Some(Some(400..=499))),
+        fault: matches!(status, Some(None | Some(500..))),
+    };
+    if let Err(e) = send(&daemon, &subsegment) {
+        trace!("Error sending subsegment to {}: {:?}", daemon, e);
+    }
+    result
+}
+
+/// Returns the UDP address of the daemon, which may be given as
+/// `tcp:127.0.0.1:2000 udp:127.0.0.1:2000`.
+fn daemon_address() -> Option<String> {
+    let value = env::var(DAEMON_ENV).ok()?;
+    let address = value
+        .split_whitespace()
+        .find_map(|part| part.strip_prefix("udp:"))
+        .unwrap_or(&value);
+    Some(address.to_owned())
+}
+
+/// Sends `subsegment` to the daemon at `address`.
+fn send(address: &str, subsegment: &Subsegment) -> Result<(), Error> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.send_to(&subsegment.to_document()?, address)?;
+    Ok(())
+}
+
+/// Returns the Unix time, in fractional seconds.
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or_default()
+}
+
+/** Test module for X-Ray subsegments */
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trace_header() {
+        let header = TraceHeader::parse(
+            "Root=1-5759e988-bd862e3fe1be46a994272793;\
+             Parent=53995c3f42cd8ad8;Sampled=1",
+        )
+        .unwrap();
+        assert_eq!(header.root, "1-5759e988-bd862e3fe1be46a994272793");
+        assert_eq!(header.parent.as_deref(), Some("53995c3f42cd8ad8"));
+        assert!(header.sampled);
+        assert_eq!(
+            header.with_parent("70de5b6f19ff9a0a"),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;\
+             Parent=70de5b6f19ff9a0a;Sampled=1"
+        );
+        assert_eq!(TraceHeader::parse("Sampled=0"), None);
+    }
+
+    #[test]
+    fn test_subsegment_document() {
+        let subsegment = Subsegment {
+            id: "70de5b6f19ff9a0a".to_owned(),
+            trace_id: "1-5759e988-bd862e3fe1be46a994272793".to_owned(),
+            parent_id: "53995c3f42cd8ad8".to_owned(),
+            kind: "subsegment",
+            name: service_name("s3").to_owned(),
+            namespace: "aws",
+            start_time: 1647679576.5,
+            end_time: 1647679576.75,
+            aws: json!({ "operation": "GetObject", "region": "eu-west-1" }),
+            http: None,
+            error: false,
+            fault: false,
+        };
+        let document = subsegment.to_document().unwrap();
+        let (header, body) =
+            std::str::from_utf8(&document).unwrap().split_once('\n').unwrap();
+        assert_eq!(header, r#"{"format": "json", "version": 1}"#);
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["type"], "subsegment");
+        assert_eq!(body["name"], "S3");
+        assert_eq!(body["aws"]["operation"], "GetObject");
+        assert!(body.get("http").is_none());
+    }
+}
@@ -61,6 +61,9 @@ data "aws_iam_policy_document" "ses_email_forward_policy_document" {
     actions = [
       "ses:SendEmail",
       "ses:SendRawEmail",
+      "ses:GetSuppressedDestination",
+      "ses:GetSendQuota",
+      "ses:GetAccountSendingEnabled",
     ]
 
     resources = [
@@ -68,6 +71,22 @@ data "aws_iam_policy_document" "ses_email_forward_policy_document" {
     ]
   }
 
+  statement {
+    sid = "4"
+
+    actions = [
+      "s3:GetObject",
+      "s3:PutObject",
+      "s3:DeleteObject",
+      "s3:ListBucket",
+    ]
+
+    resources = [
+      aws_s3_bucket.ses-bucket.arn,
+      "${aws_s3_bucket.ses-bucket.arn}/*",
+    ]
+  }
+
   statement {
     sid = "2"
 
@@ -81,6 +100,158 @@ data "aws_iam_policy_document" "ses_email_forward_policy_document" {
       "arn:aws:logs:*:*:*",
     ]
   }
+
+  statement {
+    sid = "5"
+
+    actions = [
+      "secretsmanager:GetSecretValue",
+    ]
+
+    resources = [
+      var.secrets_arn,
+    ]
+  }
+
+  statement {
+    sid = "6"
+
+    actions = [
+      "dynamodb:BatchGetItem",
+      "dynamodb:DescribeTable",
+    ]
+
+    resources = [
+      var.config_table_arn,
+    ]
+  }
+
+  statement {
+    sid = "7"
+
+    actions = [
+      "dynamodb:PutItem",
+      "dynamodb:DescribeTable",
+    ]
+
+    resources = [
+      var.auto_reply_table_arn,
+    ]
+  }
+
+  statement {
+    sid = "8"
+
+    actions = [
+      "dynamodb:PutItem",
+      "dynamodb:UpdateItem",
+      "dynamodb:DeleteItem",
+      "dynamodb:DescribeTable",
+    ]
+
+    resources = [
+      var.dedup_table_arn,
+    ]
+  }
+
+  statement {
+    sid = "9"
+
+    actions = [
+      "dynamodb:UpdateItem",
+      "dynamodb:DescribeTable",
+    ]
+
+    resources = [
+      var.bounce_table_arn,
+    ]
+  }
+
+  statement {
+    sid = "10"
+
+    actions = [
+      "sns:Publish",
+    ]
+
+    resources = [
+      var.alert_topic_arn,
+    ]
+  }
+
+  statement {
+    sid = "11"
+
+    actions = [
+      "events:PutEvents",
+    ]
+
+    resources = [
+      var.event_bus_arn,
+    ]
+  }
+
+  statement {
+    sid = "12"
+
+    actions = [
+      "xray:PutTraceSegments",
+      "xray:PutTelemetryRecords",
+    ]
+
+    resources = [
+      "*",
+    ]
+  }
+
+  statement {
+    sid = "13"
+
+    actions = [
+      "dynamodb:PutItem",
+      "dynamodb:DeleteItem",
+      "dynamodb:Query",
+      "dynamodb:DescribeTable",
+    ]
+
+    resources = [
+      var.index_table_arn,
+      "${var.index_table_arn}/index/*",
+    ]
+  }
+
+  dynamic "statement" {
+    for_each = var.kms_key_arn == "" ? [] : [var.kms_key_arn]
+
+    content {
+      sid = "14"
+
+      actions = [
+        "kms:GenerateDataKey",
+        "kms:Decrypt",
+      ]
+
+      resources = [
+        statement.value,
+      ]
+    }
+  }
+
+  dynamic "statement" {
+    for_each = var.bedrock_model_arn == "" ? [] : [var.bedrock_model_arn]
+
+    content {
+      sid = "15"
+
+      actions = [
+        "bedrock:InvokeModel",
+      ]
+
+      resources = [
+        statement.value,
+      ]
+    }
+  }
 }
 
 resource "aws_iam_policy" "ses-email-policy" {
@@ -170,6 +341,11 @@ resource "aws_lambda_function" "ses-email-forward-lambda" {
   source_code_hash = filebase64sha256("lambda.zip")
   runtime          = "provided"
 
+  # X-Ray subsegments of SES, S3 and other AWS calls
+  tracing_config {
+    mode = "Active"
+  }
+
   # cloudwatch logging
   depends_on = [
     aws_iam_role_policy_attachment.lambda_logs_policy,
@@ -182,6 +358,7 @@ resource "aws_lambda_function" "ses-email-forward-lambda" {
       FROM_EMAIL     = var.from_email,
       TO_EMAIL       = var.to_email,
       BLACK_LIST     = var.black_list
+      S3_BUCKET      = var.bucket
     }
   }
 }
@@ -61,3 +61,53 @@ variable "domain_name" {
   default     = "nyah.dev"
   description = "verified AWS domain"
 }
+
+variable "secrets_arn" {
+  default     = "arn:aws:secretsmanager:*:*:secret:privatemail*"
+  description = "Secrets Manager secrets the function may read"
+}
+
+variable "config_table_arn" {
+  default     = "arn:aws:dynamodb:*:*:table/privatemail*"
+  description = "DynamoDB tables of per-recipient settings the function may read"
+}
+
+variable "auto_reply_table_arn" {
+  default     = "arn:aws:dynamodb:*:*:table/privatemail*"
+  description = "DynamoDB tables of auto-reply senders the function may write"
+}
+
+variable "dedup_table_arn" {
+  default     = "arn:aws:dynamodb:*:*:table/privatemail*"
+  description = "DynamoDB tables of forwarded messages the function may write"
+}
+
+variable "bounce_table_arn" {
+  default     = "arn:aws:dynamodb:*:*:table/privatemail*"
+  description = "DynamoDB tables of bounced addresses the function may write"
+}
+
+variable "index_table_arn" {
+  default     = "arn:aws:dynamodb:*:*:table/privatemail*"
+  description = "DynamoDB tables of the mail index the function may write"
+}
+
+variable "alert_topic_arn" {
+  default     = "arn:aws:sns:*:*:privatemail*"
+  description = "SNS topics of failure alerts the function may publish to"
+}
+
+variable "event_bus_arn" {
+  default     = "arn:aws:events:*:*:event-bus/privatemail*"
+  description = "EventBridge buses the function may put lifecycle events on"
+}
+
+variable "kms_key_arn" {
+  default     = ""
+  description = "KMS key stored messages are encrypted under, unset when none"
+}
+
+variable "bedrock_model_arn" {
+  default     = ""
+  description = "Bedrock model labelling messages and summarizing digests, unset when none"
+}
@@ -0,0 +1,288 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Integration tests against LocalStack.
+//!
+//! ```text
+//! docker run --rm -d -p 4566:4566 localstack/localstack
+//! cargo test --features localstack-tests --test localstack
+//! ```
+//!
+//! The handler runs end to end through the real rusoto clients and signed
+//! requests, pointed at `AWS_ENDPOINT_URL`, `http://localhost:4566` by
+//! default. Every test creates its own buckets and tables, so they run in
+//! parallel and against a LocalStack kept running between runs.
+use lambda_runtime::Error;
+use lib::config::PrivatEmailConfig;
+use lib::store::{MailAction, ObjectStore};
+use lib::test_support::EmailReceiptNotificationBuilder;
+use lib::{archive, aws, idempotency, index, PrivatEmailService};
+use lib::{STATUS_OK, STATUS_SKIPPED};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::Region;
+use rusoto_ses::{Ses, SesClient, VerifyEmailIdentityRequest};
+use serde_json::{json, Value};
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Endpoint of LocalStack when `AWS_ENDPOINT_URL` is not set.
+const LOCALSTACK_URL: &str = "http://localhost:4566";
+
+/// Verified address forwards are sent from.
+const FROM_EMAIL: &str = "fwd@privatemail.test";
+
+/// Address receiving forwards.
+const TO_EMAIL: &str = "me@privatemail.test";
+
+/// Points the AWS calls at LocalStack, with its dummy credentials, unless
+/// configured otherwise.
+fn setup() {
+    for (name, value) in [
+        (aws::ENDPOINT_URL, LOCALSTACK_URL),
+        ("AWS_ACCESS_KEY_ID", "test"),
+        ("AWS_SECRET_ACCESS_KEY", "test"),
+        ("AWS_DEFAULT_REGION", "us-east-1"),
+    ] {
+        if env::var(name).is_err() {
+            env::set_var(name, value);
+        }
+    }
+}
+
+/// Returns a name unique to this run of `test`, for its resources.
+fn unique(test: &str) -> String {
+    let nanos =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+    format!("privatemail-{}-{}-{}", test, std::process::id(), nanos)
+}
+
+/// Returns the configuration forwarding from [`FROM_EMAIL`] to
+/// [`TO_EMAIL`], once SES verified the former.
+async fn email_config() -> PrivatEmailConfig {
+    let ses_client =
+        SesClient::new(aws::service_region("ses", Region::default()));
+    ses_client
+        .verify_email_identity(VerifyEmailIdentityRequest {
+            email_address: FROM_EMAIL.to_owned(),
+        })
+        .await
+        .expect("LocalStack should be running, see the module docs");
+    PrivatEmailConfig::builder()
+        .from_email(FROM_EMAIL)
+        .to_email(TO_EMAIL)
+        .build()
+}
+
+/// Returns a notification of a message unique to `test`.
+fn notification(test: &str) -> EmailReceiptNotificationBuilder {
+    EmailReceiptNotificationBuilder::new()
+        .message_id(unique(test))
+        .from("Fufu <fufu@achu.soup>")
+        .to("hello@nyah.dev")
+        .subject("Fufu & soup")
+        .text_body("Hello")
+        .html_body("<p>Hello</p>")
+}
+
+/// Creates the S3 bucket `bucket`.
+async fn create_bucket(bucket: &str) -> Result<(), Error> {
+    let request = SignedRequest::new(
+        "PUT",
+        "s3",
+        &Region::default(),
+        &format!("/{}", bucket),
+    );
+    aws::dispatch(request).await?;
+    Ok(())
+}
+
+/// Creates the DynamoDB table `table` keyed by `key`, with the global
+/// secondary `indexes`, each on a partition and a sort key.
+async fn create_table(
+    table: &str,
+    key: &str,
+    indexes: &[(&str, &str, &str)],
+) -> Result<(), Error> {
+    let mut attributes = vec![key];
+    for (_, partition, sort) in indexes {
+        attributes.extend([*partition, *sort]);
+    }
+    attributes.sort_unstable();
+    attributes.dedup();
+    let attributes: Vec<Value> = attributes
+        .into_iter()
+        .map(|name| json!({ "AttributeName": name, "AttributeType": "S" }))
+        .collect();
+    let indexes: Vec<Value> = indexes
+        .iter()
+        .map(|(name, partition, sort)| {
+            json!({
+                "IndexName": name,
+                "KeySchema": [
+                    { "AttributeName": partition, "KeyType": "HASH" },
+                    { "AttributeName": sort, "KeyType": "RANGE" },
+                ],
+                "Projection": { "ProjectionType": "ALL" },
+            })
+        })
+        .collect();
+    let mut payload = json!({
+        "TableName": table,
+        "BillingMode": "PAY_PER_REQUEST",
+        "AttributeDefinitions": attributes,
+        "KeySchema": [{ "AttributeName": key, "KeyType": "HASH" }],
+    });
+    if !indexes.is_empty() {
+        payload["GlobalSecondaryIndexes"] = indexes.into();
+    }
+    let mut request =
+        SignedRequest::new("POST", "dynamodb", &Region::default(), "/");
+    request.add_header("x-amz-target", "DynamoDB_20120810.CreateTable");
+    request.set_content_type("application/x-amz-json-1.0".to_owned());
+    request.set_payload(Some(serde_json::to_vec(&payload)?));
+    aws::dispatch(request).await?;
+    Ok(())
+}
+
+/// Returns the raw messages LocalStack accepted from `source`.
+async fn sent_messages(source: &str) -> Result<Vec<Value>, Error> {
+    let endpoint = aws::endpoint("ses").unwrap_or(LOCALSTACK_URL.to_owned());
+    let url = format!("{}/_aws/ses?email={}", endpoint, source);
+    let sent: Value = reqwest::get(url).await?.json().await?;
+    Ok(sent["messages"].as_array().cloned().unwrap_or_default())
+}
+
+#[tokio::test]
+async fn forwards_through_ses() {
+    setup();
+    let service =
+        PrivatEmailService::from_config(email_config().await).unwrap();
+
+    let response =
+        service.handle(notification("ses").to_lambda_event()).await.unwrap();
+    assert_eq!(response.status_code(), STATUS_OK, "{}", response.message());
+    assert_eq!(response.action(), Some(MailAction::Forwarded));
+    let message_id = response.forwarded_message_id().unwrap();
+
+    let sent = sent_messages(FROM_EMAIL).await.unwrap();
+    let message = sent
+        .iter()
+        .find(|message| message["Id"] == message_id)
+        .expect("LocalStack should have accepted the forward");
+    let raw = message["RawData"].as_str().unwrap_or_default();
+    assert!(raw.contains("Subject: Fufu & soup"), "{}", raw);
+    assert!(raw.contains("Reply-To: fufu@achu.soup"), "{}", raw);
+}
+
+#[tokio::test]
+async fn archives_to_s3() {
+    setup();
+    let bucket = unique("archive");
+    create_bucket(&bucket).await.unwrap();
+    let mut email_config = email_config().await;
+    email_config.archive_bucket = Some(bucket);
+    let objects = archive::object_store(&email_config).unwrap();
+    let service = PrivatEmailService::from_config(email_config).unwrap();
+
+    let builder = notification("archive");
+    let response = service.handle(builder.to_lambda_event()).await.unwrap();
+    assert_eq!(response.status_code(), STATUS_OK, "{}", response.message());
+
+    let keys = objects.list("").await.unwrap();
+    assert_eq!(keys.len(), 2, "{:?}", keys);
+    let raw_key = keys.iter().find(|key| key.ends_with(".eml")).unwrap();
+    let raw = objects.get(raw_key).await.unwrap().unwrap();
+    assert_eq!(raw, builder.to_raw().as_bytes());
+    let expiry = Duration::from_secs(300);
+    let link = objects.link(raw_key, expiry).await.unwrap();
+    assert_eq!(reqwest::get(link).await.unwrap().bytes().await.unwrap(), raw);
+}
+
+#[tokio::test]
+async fn deduplicates_dropped_messages_in_dynamodb() {
+    setup();
+    let dedup_table = unique("dedup-dropped");
+    create_table(&dedup_table, idempotency::KEY_ATTRIBUTE, &[]).await.unwrap();
+    let mut email_config = email_config().await;
+    email_config.dedup_table = Some(dedup_table);
+    let service = PrivatEmailService::from_config(email_config).unwrap();
+
+    // Messages not forwarded are claimed too, so nothing is done twice
+    let builder = notification("dropped").verdict("spam", "FAIL");
+    let response = service.handle(builder.to_lambda_event()).await.unwrap();
+    assert_eq!(response.action(), Some(MailAction::Blocked));
+    let again = service.handle(builder.to_lambda_event()).await.unwrap();
+    assert_eq!(again.action(), Some(MailAction::Duplicate));
+}
+
+#[tokio::test]
+async fn indexes_and_deduplicates_in_dynamodb() {
+    setup();
+    let (dedup_table, index_table) = (unique("dedup"), unique("index"));
+    create_table(&dedup_table, idempotency::KEY_ATTRIBUTE, &[]).await.unwrap();
+    create_table(
+        &index_table,
+        index::KEY_ATTRIBUTE,
+        &[
+            (index::SENDER_INDEX, "sender_domain", "timestamp"),
+            (index::DATE_INDEX, "day", "timestamp"),
+        ],
+    )
+    .await
+    .unwrap();
+    let mut email_config = email_config().await;
+    email_config.dedup_table = Some(dedup_table);
+    email_config.index_table = Some(index_table.clone());
+    let service = PrivatEmailService::from_config(email_config).unwrap();
+
+    let builder = notification("dynamodb");
+    let response = service.handle(builder.to_lambda_event()).await.unwrap();
+    assert_eq!(response.status_code(), STATUS_OK, "{}", response.message());
+    let again = service.handle(builder.to_lambda_event()).await.unwrap();
+    assert_eq!(again.status_code(), STATUS_SKIPPED, "{}", again.message());
+    assert_eq!(again.action(), Some(MailAction::Duplicate));
+
+    let entries = index::query(
+        &index_table,
+        index::SENDER_INDEX,
+        "sender_domain",
+        "achu.soup",
+        ("0000", "9999"),
+        10,
+    )
+    .await
+    .unwrap();
+    assert_eq!(entries.len(), 1, "{:?}", entries);
+    assert_eq!(entries[0].subject, "Fufu & soup");
+    assert_eq!(entries[0].action, Some(MailAction::Forwarded));
+}
+
+#[tokio::test]
+async fn claim_takeover_in_dynamodb() {
+    setup();
+    let table = unique("takeover");
+    create_table(&table, idempotency::KEY_ATTRIBUTE, &[]).await.unwrap();
+    let now =
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let stalled = idempotency::Lease::new(table.as_str(), "0100abc");
+    let stale = now - idempotency::CLAIM_LEASE_SECS - 1;
+    let claim = idempotency::claim(&stalled, stale, 3600).await.unwrap();
+    assert_eq!(claim, idempotency::Claim::Claimed);
+
+    // A retry takes the lapsed claim over, and the stalled invocation can
+    // neither send nor give up the claim of the retry
+    let retry = idempotency::Lease::new(table.as_str(), "0100abc");
+    let claim = idempotency::claim(&retry, now, 3600).await.unwrap();
+    assert_eq!(claim, idempotency::Claim::Claimed);
+    assert!(idempotency::begin_send(&stalled, now).await.is_err());
+    idempotency::release(&stalled).await.unwrap();
+    idempotency::begin_send(&retry, now).await.unwrap();
+    assert!(idempotency::complete(&stalled, None).await.is_err());
+    idempotency::complete(&retry, Some("0100def")).await.unwrap();
+}
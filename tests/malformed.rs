@@ -0,0 +1,153 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Hostile and malformed mail.
+//!
+//! Every fixture of `tests/payload/malformed`, and messages too large to
+//! keep as fixtures, are handled end to end: each is either forwarded or
+//! quarantined, never panicking the handler. Content above the maximum
+//! size is quarantined without being parsed at all.
+use lib::config::PrivatEmailConfig;
+use lib::content::{MAX_HEADERS, MAX_MULTIPARTS};
+use lib::limits::OVERSIZE_SUBJECT_PREFIX;
+use lib::sender::MockSender;
+use lib::store::fs::FsObjectStore;
+use lib::store::{MailAction, ObjectStore, QUARANTINE_PREFIX};
+use lib::test_support::EmailReceiptNotificationBuilder;
+use lib::{LambdaResponse, PrivatEmailService, STATUS_OK, STATUS_SKIPPED};
+use mailparse::MailHeaderMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::{env, fs, process};
+
+/// Returns the response to the receipt of the raw message `raw`, tracked
+/// in the local store `store_dir` if any.
+async fn handle(raw: &str, store_dir: Option<&Path>) -> LambdaResponse {
+    let mut builder = PrivatEmailConfig::builder()
+        .from_email("fwd@nyah.dev")
+        .to_email("me@nyah.dev");
+    if let Some(store_dir) = store_dir {
+        builder = builder.local_store_dir(store_dir);
+    }
+    let email_config = builder.build();
+    let service = PrivatEmailService::from_config(email_config)
+        .unwrap()
+        .with_sender(Arc::new(MockSender::new()));
+    let builder = EmailReceiptNotificationBuilder::new()
+        .from("Mongo Beti <fufu@achu.soup>")
+        .subject("Malformed")
+        .raw(raw);
+    service.handle(builder.to_lambda_event()).await.unwrap()
+}
+
+/// Asserts the message `raw` is quarantined, and kept in the local store.
+async fn assert_quarantined(test: &str, raw: &str) {
+    let dir = env::temp_dir().join(format!(
+        "privatemail-malformed-{}-{}",
+        test,
+        process::id()
+    ));
+    let response = handle(raw, Some(&dir)).await;
+    assert_eq!(
+        response.status_code(),
+        STATUS_SKIPPED,
+        "{}",
+        response.message()
+    );
+    assert_eq!(response.action(), Some(MailAction::Quarantined));
+
+    let objects = FsObjectStore::new(&dir).unwrap();
+    let quarantined = objects.list(QUARANTINE_PREFIX).await.unwrap();
+    assert_eq!(quarantined.len(), 1, "{:?}", quarantined);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn corpus() {
+    for (name, action) in [
+        ("truncated_boundary.eml", MailAction::Quarantined),
+        ("unclosed_boundary.eml", MailAction::Forwarded),
+        ("missing_boundary.eml", MailAction::Quarantined),
+        ("invalid_base64.eml", MailAction::Quarantined),
+        ("bad_headers.eml", MailAction::Forwarded),
+        ("empty.eml", MailAction::Forwarded),
+    ] {
+        let path = format!("tests/payload/malformed/{}", name);
+        let raw = fs::read_to_string(&path).unwrap();
+        let response = handle(&raw, None).await;
+        assert_eq!(response.action(), Some(action), "{}", name);
+        let status = match action {
+            MailAction::Forwarded => STATUS_OK,
+            _ => STATUS_SKIPPED,
+        };
+        assert_eq!(response.status_code(), status, "{}", name);
+    }
+}
+
+#[tokio::test]
+async fn enormous_header_count() {
+    let headers: String = (0..MAX_HEADERS * 10)
+        .map(|i| format!("X-Header-{}: value\r\n", i))
+        .collect();
+    assert_quarantined("headers", &format!("{}\r\nHello", headers)).await;
+}
+
+#[tokio::test]
+async fn deep_nesting() {
+    // Deep enough to overflow the stack of the parser without the bound
+    let depth = MAX_MULTIPARTS * 400;
+    let mut raw = String::new();
+    for i in 0..depth {
+        raw.push_str(&format!(
+            "Content-Type: multipart/mixed; boundary=\"b{}\"\r\n\r\n--b{}\r\n",
+            i, i
+        ));
+    }
+    raw.push_str("Content-Type: text/html\r\n\r\n<p>Hello</p>\r\n");
+    assert_quarantined("nesting", &raw).await;
+}
+
+#[tokio::test]
+async fn oversized_content() {
+    let dir = env::temp_dir()
+        .join(format!("privatemail-malformed-oversized-{}", process::id()));
+    let email_config = PrivatEmailConfig::builder()
+        .from_email("fwd@nyah.dev")
+        .to_email("me@nyah.dev")
+        .local_store_dir(&dir)
+        .max_content_size(1024)
+        .build();
+    let sender = Arc::new(MockSender::new());
+    let service = PrivatEmailService::from_config(email_config)
+        .unwrap()
+        .with_sender(sender.clone());
+    let raw = format!("Subject: Fufu\r\n\r\n{}", "Eru ".repeat(1024));
+    let builder = EmailReceiptNotificationBuilder::new()
+        .from("Mongo Beti <fufu@achu.soup>")
+        .subject("Fufu")
+        .raw(raw.as_str());
+    let response = service.handle(builder.to_lambda_event()).await.unwrap();
+    assert_eq!(response.status_code(), STATUS_SKIPPED);
+    assert_eq!(response.action(), Some(MailAction::Quarantined));
+
+    let objects = FsObjectStore::new(&dir).unwrap();
+    let quarantined = objects.list(QUARANTINE_PREFIX).await.unwrap();
+    assert_eq!(quarantined.len(), 1, "{:?}", quarantined);
+    let sent = sender.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].destinations(), ["me@nyah.dev"]);
+    let notice = mailparse::parse_mail(sent[0].raw().unwrap()).unwrap();
+    assert_eq!(
+        notice.headers.get_first_value("Subject").unwrap(),
+        format!("{}Fufu", OVERSIZE_SUBJECT_PREFIX)
+    );
+    let text = notice.get_body().unwrap();
+    assert!(text.contains(&quarantined[0]), "{}", text);
+    fs::remove_dir_all(&dir).unwrap();
+}
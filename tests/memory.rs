@@ -0,0 +1,117 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Peak memory of large messages.
+//!
+//! Handles a 30MB message and fails when the allocations meanwhile peak at
+//! more than 2.5 times its size, about the JSON decoder's own copies of it.
+//! It is the only test of its binary, as the allocator counts every thread.
+use lib::config::PrivatEmailConfig;
+use lib::sender::MockSender;
+use lib::store::MailAction;
+use lib::test_support::EmailReceiptNotificationBuilder;
+use lib::{PrivatEmailService, STATUS_OK};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::{env, fs, process};
+
+/// Allocator recording the peak of the allocated bytes.
+struct PeakAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+impl PeakAllocator {
+    fn grow(size: usize) {
+        let allocated = ALLOCATED.fetch_add(size, Ordering::SeqCst) + size;
+        PEAK.fetch_max(allocated, Ordering::SeqCst);
+    }
+
+    fn shrink(size: usize) {
+        ALLOCATED.fetch_sub(size, Ordering::SeqCst);
+    }
+}
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::grow(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        Self::shrink(layout.size());
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            Self::grow(new_size);
+            Self::shrink(layout.size());
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static GLOBAL: PeakAllocator = PeakAllocator;
+
+/// Size of the attachment, 30MB once base64 encoded.
+const ATTACHMENT_SIZE: usize = 22 * 1024 * 1024;
+
+#[tokio::test]
+async fn large_message_peak_memory() {
+    let dir =
+        env::temp_dir().join(format!("privatemail-memory-{}", process::id()));
+    let email_config = PrivatEmailConfig::builder()
+        .from_email("fwd@nyah.dev")
+        .to_email("me@nyah.dev")
+        .local_store_dir(&dir)
+        .build();
+    let sender = Arc::new(MockSender::new());
+    let service = PrivatEmailService::from_config(email_config)
+        .unwrap()
+        .with_sender(sender.clone());
+
+    let data: Vec<u8> = (0..ATTACHMENT_SIZE).map(|i| (i % 251) as u8).collect();
+    let lambda_event = EmailReceiptNotificationBuilder::new()
+        .from("Mongo Beti <fufu@achu.soup>")
+        .subject("Holiday pictures")
+        .html_body("<p>The pictures of the holidays</p>")
+        .attachment("pictures.zip", "application/zip", data)
+        .to_lambda_event();
+    let raw_size = lambda_event.payload["Records"][0]["Sns"]["Message"]
+        .as_str()
+        .unwrap()
+        .len();
+
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    let response = service.handle(lambda_event).await.unwrap();
+    let peak = PEAK.load(Ordering::SeqCst) - before;
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(response.status_code(), STATUS_OK, "{}", response.message());
+    assert_eq!(response.action(), Some(MailAction::Forwarded));
+    assert!(
+        peak < raw_size * 5 / 2,
+        "Peak of {} bytes handling {} bytes",
+        peak,
+        raw_size
+    );
+}
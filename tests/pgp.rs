@@ -0,0 +1,80 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Decryption of PGP/MIME forwards by GnuPG.
+//!
+//! Messages encrypted to the GnuPG key of `tests/keys` are decrypted by
+//! `gpg`, an OpenPGP implementation independent of the encoder. Skipped
+//! where `gpg` is not installed.
+use lib::pgp::PgpRecipient;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::{env, fs, process};
+
+const PUBLIC_KEY: &str = include_str!("keys/pgp_gpg_public.asc");
+
+/// Runs `gpg` with `args` in the keyring `home`.
+fn gpg(home: &Path, args: &[&str]) -> Output {
+    Command::new("gpg")
+        .env("GNUPGHOME", home)
+        .args(["--batch", "--status-fd", "2"])
+        .args(args)
+        .output()
+        .expect("gpg runs")
+}
+
+/// Returns a keyring holding the secret key of `tests/keys`.
+fn keyring() -> PathBuf {
+    let home =
+        env::temp_dir().join(format!("privatemail-gpg-{}", process::id()));
+    fs::create_dir_all(&home).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&home, fs::Permissions::from_mode(0o700)).unwrap();
+    }
+    let secret = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/keys/pgp_gpg_secret.asc");
+    let output = gpg(&home, &["--import", secret.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+    home
+}
+
+#[test]
+fn gnupg_decrypts_forwards() {
+    if Command::new("gpg").arg("--version").output().is_err() {
+        eprintln!("gpg is not installed, skipping");
+        return;
+    }
+    let home = keyring();
+    let recipient = PgpRecipient::from_armored(PUBLIC_KEY).unwrap();
+    let raw = b"From: fufu@achu.soup\r\nSubject: Hi\r\n\
+                MIME-Version: 1.0\r\n\
+                Content-Type: text/plain; charset=utf-8\r\n\
+                \r\nsecret body\r\n";
+    let encrypted = recipient.encrypt_message(raw).unwrap();
+    let mail = mailparse::parse_mail(&encrypted).unwrap();
+    let armored = home.join("forward.asc");
+    fs::write(&armored, mail.subparts[1].get_body().unwrap()).unwrap();
+
+    let output = gpg(&home, &["--decrypt", armored.to_str().unwrap()]);
+    let status = String::from_utf8_lossy(&output.stderr);
+    let _ = Command::new("gpgconf")
+        .env("GNUPGHOME", &home)
+        .args(["--kill", "gpg-agent"])
+        .output();
+    fs::remove_dir_all(&home).unwrap();
+    assert!(output.status.success(), "{}", status);
+    assert!(status.contains("[GNUPG:] DECRYPTION_OKAY"), "{}", status);
+    assert!(status.contains("[GNUPG:] GOODMDC"), "{}", status);
+    assert_eq!(
+        output.stdout,
+        b"Content-Type: text/plain; charset=utf-8\r\n\r\nsecret body\r\n"
+    );
+}
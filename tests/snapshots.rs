@@ -0,0 +1,117 @@
+//! -*- mode: rust; -*-
+//!
+//! This file is part of privatemail crate.
+//! Copyright (c) 2022 Nyah Check
+//! See LICENSE for licensing information.
+//!
+//! Authors:
+//! - Nyah Check <hello@nyah.dev>
+//!
+//! Golden snapshots of forwarded messages.
+//!
+//! The SES event of `tests/payload/test_event.json` and every fixture of
+//! `tests/payload/corpus` are forwarded through the handler, and the exact
+//! messages handed to SES compared with their snapshots under
+//! `tests/snapshots`, so changes to the MIME pipeline show up as diffs:
+//!
+//! ```text
+//! cargo test --test snapshots
+//! cargo insta review
+//! ```
+//!
+//! Snapshots are updated on purpose only, with `cargo insta review` or
+//! `INSTA_UPDATE=always`. The random MIME boundaries are replaced by
+//! `BOUNDARY` and line endings by `\n` to keep them stable and readable.
+use lambda_runtime::{Context, LambdaEvent};
+use lib::config::PrivatEmailConfig;
+use lib::sender::{MockSender, SentEmail};
+use lib::test_support::EmailReceiptNotificationBuilder;
+use lib::{PrivatEmailService, STATUS_OK};
+use mailparse::MailHeaderMap;
+use regex::Regex;
+use serde_json::Value;
+use std::fs;
+use std::sync::Arc;
+
+/// Returns the messages sent when handling `lambda_event`.
+async fn handle(lambda_event: LambdaEvent<Value>) -> Vec<SentEmail> {
+    let email_config = PrivatEmailConfig::builder()
+        .from_email("fwd@nyah.dev")
+        .to_email("me@nyah.dev")
+        .build();
+    let sender = Arc::new(MockSender::new());
+    let service = PrivatEmailService::from_config(email_config)
+        .unwrap()
+        .with_sender(sender.clone());
+    let response = service.handle(lambda_event).await.unwrap();
+    assert_eq!(response.status_code(), STATUS_OK, "{}", response.message());
+    sender.sent()
+}
+
+/// Returns the messages sent when forwarding the corpus fixture `name`.
+async fn forward(name: &str) -> Vec<SentEmail> {
+    let path = format!("tests/payload/corpus/{}", name);
+    let raw = fs::read_to_string(&path).expect("fixtures are readable");
+    let mail = mailparse::parse_mail(raw.as_bytes()).unwrap();
+    let header = |name| mail.headers.get_first_value(name).unwrap_or_default();
+    let builder = EmailReceiptNotificationBuilder::new()
+        .message_id(name.trim_end_matches(".eml"))
+        .from(header("From"))
+        .to(header("To"))
+        .subject(header("Subject"))
+        .raw(raw.as_str());
+    handle(builder.to_lambda_event()).await
+}
+
+/// Renders the envelope and raw message of `sent`, with stable boundaries.
+fn render(sent: &[SentEmail]) -> String {
+    let boundary = Regex::new(r"=_privatemail_(alt_)?[0-9a-f]+").unwrap();
+    let mut rendered = String::new();
+    for email in sent {
+        rendered.push_str(&format!(
+            "Source: {}\nDestinations: {}\n\n",
+            email.source().unwrap_or_default(),
+            email.destinations().join(", ")
+        ));
+        let raw = String::from_utf8_lossy(email.raw().unwrap_or_default());
+        let raw = boundary.replace_all(&raw, "=_privatemail_${1}BOUNDARY");
+        rendered.push_str(&raw.replace("\r\n", "\n"));
+    }
+    rendered
+}
+
+#[tokio::test]
+async fn ses_event() {
+    let payload = fs::read_to_string("tests/payload/test_event.json").unwrap();
+    let mut context = Context::default();
+    context.request_id = "test-ses-event".to_owned();
+    context.xray_trace_id = Some(context.request_id.clone());
+    let event = serde_json::from_str(&payload).unwrap();
+    let sent = handle(LambdaEvent::new(event, context)).await;
+    insta::assert_snapshot!(render(&sent));
+}
+
+#[tokio::test]
+async fn quoted_printable() {
+    insta::assert_snapshot!(render(&forward("quoted_printable.eml").await));
+}
+
+#[tokio::test]
+async fn base64() {
+    insta::assert_snapshot!(render(&forward("base64.eml").await));
+}
+
+#[tokio::test]
+async fn attachment() {
+    insta::assert_snapshot!(render(&forward("attachment.eml").await));
+}
+
+#[tokio::test]
+async fn text_plain() {
+    insta::assert_snapshot!(render(&forward("text_plain.eml").await));
+}
+
+#[tokio::test]
+async fn latin1() {
+    insta::assert_snapshot!(render(&forward("latin1.eml").await));
+}